@@ -0,0 +1,80 @@
+use std::path::Path;
+use std::process::Command;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Builds do not depend on protoc being installed.
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    tonic_build::compile_protos("proto/chipmunk.proto")?;
+    build_info()?;
+    Ok(())
+}
+
+/// Describe the build through `CHIPMUNK_*` environment variables, read by
+/// `chipmunk::build_info`.
+fn build_info() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed=CHIPMUNK_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Builds from a source archive have no repository to ask, so the commit
+    // may be given instead.
+    let git_sha = match std::env::var("CHIPMUNK_GIT_SHA") {
+        Ok(sha) => sha,
+        Err(_) => git_sha().unwrap_or_else(|| "unknown".to_string()),
+    };
+    println!("cargo:rustc-env=CHIPMUNK_GIT_SHA={git_sha}");
+
+    // Reproducible builds pin the timestamp, see
+    // https://reproducible-builds.org/specs/source-date-epoch/.
+    let built_at = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            DateTime::from_timestamp(epoch.parse()?, 0).ok_or("invalid SOURCE_DATE_EPOCH")?
+        }
+        Err(_) => Utc::now(),
+    };
+    println!(
+        "cargo:rustc-env=CHIPMUNK_BUILD_TIMESTAMP={}",
+        built_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+
+    let mut features: Vec<_> = std::env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=CHIPMUNK_FEATURES={}", features.join(","));
+    Ok(())
+}
+
+/// The commit checked out, or `None` when not built from a git repository.
+fn git_sha() -> Option<String> {
+    // Rebuild as commits are made or checked out.
+    let head = Path::new(".git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = std::fs::read_to_string(head)
+            .ok()?
+            .strip_prefix("ref: ")
+            .map(str::trim)
+        {
+            let reference = Path::new(".git").join(reference);
+            if reference.exists() {
+                println!("cargo:rerun-if-changed={}", reference.display());
+            }
+        }
+    }
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
@@ -1,54 +1,414 @@
+use axum::http::{HeaderName, HeaderValue, Method};
 use chipmunk::{
-    config::{ChipmunkConfig, MemtableConfig, WalConfig},
+    build_info,
+    config::{
+        AckMode, ChipmunkConfig, CompactionConfig, Compression, CorsConfig, HttpConfig, InMemory,
+        MemtableConfig, ReplicationConfig, SstableConfig, TlsConfig, WalConfig, WriteStallConfig,
+    },
+    debug::{self, DumpFormat},
+    replication,
     server::Chipmunk,
+    units::{parse_millis, parse_secs, parse_size},
 };
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity::InfoLevel;
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_log::AsTrace;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload};
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
+#[command(version, long_version = build_info::LONG_VERSION)]
 struct Cli {
     /// Address to bind to for listening on incoming connections.
     #[arg(long, default_value = "127.0.0.1:5000")]
     bind_address: String,
 
+    /// Address to serve the Redis protocol on, so that Redis clients can be
+    /// used with the store. Not served when unset.
+    #[arg(long)]
+    resp_bind_address: Option<String>,
+
     #[command(flatten)]
     log_level: clap_verbosity::Verbosity<InfoLevel>,
 
+    /// Format logs are written in.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// JSON file of settings which can be changed while the store runs, such
+    /// as the log level and compaction triggers, read at startup and again on
+    /// SIGHUP or `POST /admin/config/reload`. Its settings override those of
+    /// the matching flags.
+    #[arg(long)]
+    config_file: Option<PathBuf>,
+
     /// Directory that WAL segments should be written to.
     ///
     /// Defaults to the current directory.
     #[arg(long, default_value = "./")]
     wal_directory: PathBuf,
 
-    /// Maximium size, in bytes, of the WAL before rotation should occur.
-    ///
-    /// Default to 8 MiB.
-    #[arg(long, default_value = "8388608")]
+    /// Maximium size of the WAL before rotation should occur.
+    #[arg(long, visible_alias = "wal-max-size", default_value = "8MiB", value_parser = parse_size::<u64>)]
     wal_max_size_bytes: u64,
 
-    /// Size, in bytes, of the internal WAL buffer.
-    #[arg(long)]
+    /// Size of the internal WAL buffer.
+    #[arg(long, visible_alias = "wal-buffer-size", value_parser = parse_size::<usize>)]
     wal_buffer_size_bytes: Option<usize>,
 
-    /// Maximium size, in bytes, of the memtable before it is flushed to disk.
-    ///
-    /// Defaults to 8 MiB.
-    #[arg(long, default_value = "8388608")]
+    /// Time for which WAL segments are archived once flushed, so that the
+    /// changefeed and replicas can read their changes for longer. Segments
+    /// are removed once flushed when unset.
+    #[arg(long, visible_alias = "wal-retention", value_parser = parse_millis)]
+    wal_retention_ms: Option<Duration>,
+
+    /// Maximium size of the memtable before it is flushed to disk.
+    #[arg(long, visible_alias = "memtable-max-size", default_value = "8MiB", value_parser = parse_size::<u64>)]
     memtable_max_size_bytes: u64,
+
+    /// Maximum number of rotated memtables awaiting the background flush
+    /// before writes flush the oldest to disk themselves.
+    #[arg(long, default_value = "1")]
+    memtable_max_immutable: usize,
+
+    /// Keep every write in memory only, without a WAL or SSTables, either
+    /// retaining or evicting the rotated memtables which would otherwise be
+    /// flushed.
+    #[arg(long)]
+    in_memory: Option<InMemory>,
+
+    /// Age at which the memtable is flushed even though it is not full.
+    /// Memtables are only flushed once full when unset.
+    #[arg(long, visible_alias = "memtable-flush-interval", value_parser = parse_millis)]
+    memtable_flush_interval_ms: Option<Duration>,
+
+    /// Size of the largest value which is accepted. Larger requests are
+    /// rejected with 413 Payload Too Large.
+    #[arg(long, visible_alias = "max-value-size", default_value = "2MiB", value_parser = parse_size::<usize>)]
+    max_value_size_bytes: usize,
+
+    /// Size at which SSTable data blocks are closed.
+    #[arg(long, visible_alias = "sstable-block-size", default_value = "4KiB", value_parser = parse_size::<usize>)]
+    sstable_block_size_bytes: usize,
+
+    /// Compression applied to SSTable data blocks.
+    #[arg(long, value_enum, default_value_t = Compression::None)]
+    sstable_compression: Compression,
+
+    /// Capacity of the cache of SSTable data blocks. Zero disables the cache.
+    #[arg(long, visible_alias = "sstable-block-cache", default_value = "8MiB", value_parser = parse_size::<usize>)]
+    sstable_block_cache_bytes: usize,
+
+    /// Maximum number of SSTable files which are held open at once.
+    #[arg(long, default_value = "1000")]
+    sstable_max_open_files: usize,
+
+    /// Size beyond which SSTable indexes are split into partitions. Indexes
+    /// are never partitioned when unset.
+    #[arg(long, visible_alias = "sstable-index-partition-size", value_parser = parse_size::<usize>)]
+    sstable_index_partition_size_bytes: Option<usize>,
+
+    /// Bloom filter bits stored for each key of an SSTable. Zero writes
+    /// tables without a filter.
+    #[arg(long, default_value = "10")]
+    sstable_bloom_bits_per_key: usize,
+
+    /// Size from which values are stored in a separate value log rather than
+    /// within SSTables, so compaction does not rewrite them. Values are never
+    /// separated when unset.
+    #[arg(long, visible_alias = "value-log-min-value-size", value_parser = parse_size::<usize>)]
+    value_log_min_value_size_bytes: Option<usize>,
+
+    /// Proportion of a value log file which must be garbage before its live
+    /// values are moved elsewhere and the file removed.
+    #[arg(long, default_value = "0.5")]
+    value_log_gc_ratio: f64,
+
+    /// Number of L1 files, those flushed from memtables, at which they are
+    /// compacted into L2.
+    #[arg(long, default_value = "4")]
+    compaction_l1_file_trigger: usize,
+
+    /// Total size of the L1 files at which they are compacted.
+    #[arg(long, visible_alias = "compaction-l1-size-trigger", value_parser = parse_size::<u64>)]
+    compaction_l1_size_trigger_bytes: Option<u64>,
+
+    /// Size of L1 relative to L2 at which L1 is compacted, e.g. 0.1 compacts
+    /// once L1 holds a tenth as many bytes as L2.
+    #[arg(long)]
+    compaction_level_size_ratio: Option<f64>,
+
+    /// Number of L2 files beyond which they are merged together with L1.
+    #[arg(long, default_value = "3")]
+    compaction_max_l2_files: usize,
+
+    /// Rate, in bytes per second, which compaction writes are limited to.
+    /// Compaction runs as fast as it can when unset.
+    #[arg(long, value_parser = parse_size::<u64>)]
+    compaction_max_bytes_per_sec: Option<u64>,
+
+    /// Number of L1 files from which writes are slowed down.
+    #[arg(long)]
+    write_stall_l1_slowdown_files: Option<usize>,
+
+    /// Number of L1 files from which writes are stopped until flushes and
+    /// compaction catch up.
+    #[arg(long)]
+    write_stall_l1_stop_files: Option<usize>,
+
+    /// Number of immutable memtables from which writes are slowed down.
+    #[arg(long)]
+    write_stall_immutable_slowdown: Option<usize>,
+
+    /// Number of immutable memtables from which writes are stopped until
+    /// they have been flushed.
+    #[arg(long)]
+    write_stall_immutable_stop: Option<usize>,
+
+    /// Delay applied to each write while writes are slowed down.
+    #[arg(long, visible_alias = "write-stall-slowdown-delay", default_value = "1ms", value_parser = parse_millis)]
+    write_stall_slowdown_delay_ms: Duration,
+
+    /// PEM file holding the certificate chain to serve over TLS with. Plain
+    /// HTTP is served when unset.
+    #[arg(long, requires = "tls_key_path")]
+    tls_cert_path: Option<PathBuf>,
+
+    /// PEM file holding the private key of the TLS certificate.
+    #[arg(long, requires = "tls_cert_path")]
+    tls_key_path: Option<PathBuf>,
+
+    /// Interval at which the TLS certificate and key are checked for changes
+    /// and reloaded.
+    #[arg(long, visible_alias = "tls-reload-interval", default_value = "60s", value_parser = parse_secs)]
+    tls_reload_interval_secs: Duration,
+
+    /// Origins allowed to call the API from a browser, separated by commas,
+    /// or `*` for any origin. Cross-origin requests are blocked when unset.
+    #[arg(long, value_delimiter = ',')]
+    cors_allowed_origins: Vec<HeaderValue>,
+
+    /// Methods allowed in cross-origin requests, separated by commas.
+    ///
+    /// Defaults to every method the API serves.
+    #[arg(long, value_delimiter = ',')]
+    cors_allowed_methods: Vec<Method>,
+
+    /// Request headers allowed in cross-origin requests, separated by commas.
+    ///
+    /// Defaults to every header the API reads.
+    #[arg(long, value_delimiter = ',')]
+    cors_allowed_headers: Vec<HeaderName>,
+
+    /// Time for which browsers may cache preflight responses.
+    #[arg(long, visible_alias = "cors-max-age", default_value = "1h", value_parser = parse_secs)]
+    cors_max_age_secs: Duration,
+
+    /// Time after which requests which have not been answered are answered
+    /// with 503 Service Unavailable. Requests are never timed out when unset.
+    #[arg(long, visible_alias = "request-timeout", value_parser = parse_millis)]
+    request_timeout_ms: Option<Duration>,
+
+    /// Close HTTP/1.1 connections after each request rather than keeping
+    /// them open for the next.
+    #[arg(long)]
+    disable_keep_alive: bool,
+
+    /// Interval at which HTTP/2 connections are pinged, closing those which
+    /// stop answering. Connections are never pinged when unset.
+    #[arg(long, visible_alias = "keep-alive-interval", value_parser = parse_secs)]
+    keep_alive_interval_secs: Option<Duration>,
+
+    /// Maximum number of HTTP connections served at once. Further connections
+    /// wait to be accepted until others close.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Bearer token required by /admin/shutdown, /admin/reopen, /admin/flush,
+    /// /admin/compact, /admin/config/reload and changes through
+    /// PUT /admin/config, which are refused when unset.
+    #[arg(long, env = "CHIPMUNK_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Follow the primary at this address, such as `primary:5000`, as a
+    /// read-only replica: its writes are applied as they are made, reads are
+    /// served, and writes of clients are refused. An empty WAL directory is
+    /// first filled with a checkpoint of the primary.
+    #[arg(long)]
+    replica_of: Option<String>,
+
+    /// When writes are acknowledged while replicas follow the store: once
+    /// applied, or once enough replicas have applied them too.
+    #[arg(long, value_enum, default_value_t = AckMode::Async)]
+    replication_ack_mode: AckMode,
+
+    /// Number of replicas which must apply each write before it is
+    /// acknowledged with `--replication-ack-mode semi-sync`.
+    #[arg(long, default_value = "1")]
+    replication_min_replicas: usize,
+
+    /// Time a write waits for replicas before the store stops waiting for
+    /// them, until enough have caught up.
+    #[arg(long, visible_alias = "replication-ack-timeout", default_value = "1s", value_parser = parse_millis)]
+    replication_ack_timeout_ms: Duration,
+
+    /// Number of shards the keys are split between, each with a WAL and
+    /// memtable of its own so that writes to different shards do not wait on
+    /// one another. Cannot change once the store has been written to, and
+    /// more than one rules out watching keys, the changefeed and replication.
+    #[arg(long, default_value = "1", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    shards: usize,
+
+    /// Run a tool rather than serving the store.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Inspect the files of a store which is not running.
+    #[command(subcommand)]
+    Debug(DebugCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum DebugCommand {
+    /// Print each record of a WAL segment: its offset, sequence number, type,
+    /// key, value size and checksum status. Exits with a failure if any
+    /// record is corrupt.
+    WalDump {
+        /// Path of the segment file, such as `0.wal`.
+        segment: PathBuf,
+
+        /// Format records are printed in.
+        #[arg(long, value_enum, default_value_t = DumpFormat::Text)]
+        format: DumpFormat,
+    },
+    /// Print the footer, metadata, index and bloom filter of an SSTable, and
+    /// optionally its entries. Exits with a failure if any block is corrupt.
+    SstDump {
+        /// Path of the SSTable file, such as `0.sst`.
+        file: PathBuf,
+
+        /// Also print every entry, without its value.
+        #[arg(long)]
+        entries: bool,
+
+        /// Format the table is printed in.
+        #[arg(long, value_enum, default_value_t = DumpFormat::Text)]
+        format: DumpFormat,
+    },
+}
+
+/// How logs, including the access log of every request, are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, with the fields of the event at the top
+    /// level and those of the request it belongs to, such as its ID, key size
+    /// and body size, under `span`.
+    Json,
+}
+
+/// Reload the config file of the store on each SIGHUP, keeping the settings
+/// in place if it is invalid.
+#[cfg(unix)]
+async fn reload_on_hangup(store: Chipmunk) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Can listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config");
+        if let Err(e) = store.reload() {
+            warn!("Cannot reload config: {e}");
+        }
+    }
+}
+
+/// Resolve once the process is asked to stop, by SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Can listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Can listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("Received SIGINT, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     let cli = Cli::parse();
 
-    tracing_subscriber::fmt()
-        .with_max_level(cli.log_level.log_level_filter().as_trace())
-        .init();
+    // The level sits behind a handle, so that it can be changed by reloading
+    // the config file.
+    let (level, level_handle) = reload::Layer::new(cli.log_level.log_level_filter().as_trace());
+    let logs = tracing_subscriber::registry().with(level);
+    match cli.log_format {
+        LogFormat::Text => logs.with(fmt::layer()).init(),
+        LogFormat::Json => logs
+            .with(
+                fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_span_list(false),
+            )
+            .init(),
+    }
+
+    if let Some(Command::Debug(command)) = cli.command {
+        let summary = match command {
+            DebugCommand::WalDump { segment, format } => {
+                debug::dump_wal(&segment, format, &mut std::io::stdout().lock())?
+            }
+            DebugCommand::SstDump {
+                file,
+                entries,
+                format,
+            } => debug::dump_sstable(&file, entries, format, &mut std::io::stdout().lock())?,
+        };
+        if !summary.is_clean() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let cors = match cli.cors_allowed_origins.as_slice() {
+        [] => None,
+        origins if origins.iter().any(|origin| origin == "*") => Some(CorsConfig::any_origin()),
+        origins => Some(CorsConfig::new(origins.to_vec())),
+    }
+    .map(|cors| {
+        let cors = cors.with_max_age(cli.cors_max_age_secs);
+        let cors = if cli.cors_allowed_methods.is_empty() {
+            cors
+        } else {
+            cors.with_methods(cli.cors_allowed_methods)
+        };
+        if cli.cors_allowed_headers.is_empty() {
+            cors
+        } else {
+            cors.with_headers(cli.cors_allowed_headers)
+        }
+    });
 
     let config = ChipmunkConfig {
         wal: WalConfig {
@@ -56,18 +416,146 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
             max_size: cli.wal_max_size_bytes,
             log_directory: cli.wal_directory,
             buffer_size: cli.wal_buffer_size_bytes,
+            retention: cli.wal_retention_ms,
         },
         memtable: MemtableConfig {
             id: 0,
             max_size: cli.memtable_max_size_bytes,
+            max_immutable_memtables: cli.memtable_max_immutable,
+            in_memory: cli.in_memory,
+            max_value_size: Some(cli.max_value_size_bytes),
+            flush_interval: cli.memtable_flush_interval_ms,
+        },
+        sstable: SstableConfig {
+            block_size: cli.sstable_block_size_bytes,
+            compression: cli.sstable_compression,
+            block_cache_capacity: cli.sstable_block_cache_bytes,
+            max_open_files: cli.sstable_max_open_files,
+            index_partition_size: cli.sstable_index_partition_size_bytes,
+            bloom_bits_per_key: cli.sstable_bloom_bits_per_key,
+            min_separated_value_size: cli.value_log_min_value_size_bytes,
+            value_log_gc_ratio: cli.value_log_gc_ratio,
+        },
+        compaction: CompactionConfig {
+            l1_file_trigger: Some(cli.compaction_l1_file_trigger),
+            l1_size_trigger: cli.compaction_l1_size_trigger_bytes,
+            level_size_ratio: cli.compaction_level_size_ratio,
+            max_l2_files: Some(cli.compaction_max_l2_files),
+            max_bytes_per_sec: cli.compaction_max_bytes_per_sec,
         },
+        write_stall: WriteStallConfig {
+            l1_slowdown_trigger: cli.write_stall_l1_slowdown_files,
+            l1_stop_trigger: cli.write_stall_l1_stop_files,
+            immutable_slowdown_trigger: cli.write_stall_immutable_slowdown,
+            immutable_stop_trigger: cli.write_stall_immutable_stop,
+            slowdown_delay: cli.write_stall_slowdown_delay_ms,
+        },
+        tls: cli.tls_cert_path.zip(cli.tls_key_path).map(|(cert, key)| {
+            TlsConfig::new(cert, key).with_reload_interval(cli.tls_reload_interval_secs)
+        }),
+        cors,
+        http: HttpConfig {
+            request_timeout: cli.request_timeout_ms,
+            keep_alive: !cli.disable_keep_alive,
+            keep_alive_interval: cli.keep_alive_interval_secs,
+            max_connections: cli.max_connections,
+            admin_token: cli.admin_token,
+        },
+        replica_of: cli.replica_of,
+        replication: ReplicationConfig {
+            ack_mode: cli.replication_ack_mode,
+            min_replicas: cli.replication_min_replicas,
+            ack_timeout: cli.replication_ack_timeout_ms,
+        },
+        shards: cli.shards,
     };
+    if config.shards > 1
+        && (config.replica_of.is_some() || config.replication.ack_mode == AckMode::SemiSync)
+    {
+        return Err("replication is not supported with more than one shard".into());
+    }
 
-    let c = Chipmunk::new(config);
+    let scheme = if config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    // A new replica starts from a checkpoint of the primary, as the WAL of
+    // the primary may no longer hold every change, before the store is
+    // opened within the directory.
+    if let (Some(primary), None) = (&config.replica_of, &config.memtable.in_memory) {
+        replication::bootstrap(&config.wal.log_directory, primary).await?;
+    }
+    let c = Chipmunk::new(config)?.with_log_level(level_handle);
+    let c = match cli.config_file {
+        Some(path) => {
+            let c = c.with_config_file(path);
+            c.reload()?;
+            #[cfg(unix)]
+            tokio::spawn(reload_on_hangup(c.clone()));
+            c
+        }
+        None => c,
+    };
+    let listener = TcpListener::bind(&cli.bind_address).await?;
+    info!("Listening on {scheme}://{}", cli.bind_address);
+    let resp_listener = match &cli.resp_bind_address {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            info!("Serving the Redis protocol on {address}");
+            Some(listener)
+        }
+        None => None,
+    };
+
+    // Every listener stops on the same signal.
+    let (stop, stopping) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = stop.send(());
+    });
+    let stopped = move || {
+        let mut stopping = stopping.clone();
+        async move {
+            let _ = stopping.changed().await;
+        }
+    };
+
+    // Requests are accepted while the store is restored, so that probes can
+    // report on it, though only /readyz reports it as able to serve.
+    let server = {
+        let c = c.clone();
+        let stopped = stopped();
+        tokio::spawn(async move { c.serve(listener, stopped).await })
+    };
+    let resp_server = resp_listener.map(|listener| {
+        let c = c.clone();
+        let stopped = stopped();
+        tokio::spawn(async move { c.serve_resp(listener, stopped).await })
+    });
     c.restore().await?;
-    info!("Listening on http://{}", cli.bind_address);
-    let app = chipmunk::server::new_app(c);
-    let listener = TcpListener::bind(cli.bind_address).await?;
-    axum::serve(listener, app).await?;
+    info!("Restored, ready to serve");
+    // Replication continues from the most recent write restored.
+    let follower = c.replica_of().map(|primary| {
+        info!("Following the primary at {primary}");
+        let c = c.clone();
+        let primary = primary.to_string();
+        tokio::spawn(async move { c.follow(&primary).await })
+    });
+    // In-flight requests have drained once the servers return, after which
+    // the store is closed whether or not serving failed, so that no
+    // acknowledged write is left in the WAL buffer.
+    let served = server.await;
+    let resp_served = match resp_server {
+        Some(resp_server) => resp_server.await,
+        None => Ok(Ok(())),
+    };
+    if let Some(follower) = follower {
+        follower.abort();
+    }
+    c.close().await?;
+    info!("Shut down cleanly");
+    served??;
+    resp_served??;
     Ok(())
 }
@@ -1,11 +1,66 @@
-use chipmunk::client::ChipmunkClient;
-use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chipmunk::client::{
+    check_import, ChipmunkClient, ClientError, ImportProgress, ReadPreference, RetryPolicy, Scheme,
+};
+use chipmunk::server::BulkEntry;
+use chipmunk::units::parse_millis;
+use clap::{Parser, Subcommand, ValueEnum};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_stream::StreamExt;
+
+/// Size of the chunks an export is searched in, from its end, when resuming
+/// it.
+const RESUME_CHUNK: u64 = 64 * 1024;
 
 #[derive(Debug, Clone, Parser)]
 struct Cli {
-    /// Host address of the remote chipmunk store.
-    #[arg(long, default_value = "127.0.0.1:5000")]
-    host: String,
+    /// Host of the remote chipmunk store, as a hostname or IP address with an
+    /// optional port. Given more than once, requests fail over to the later
+    /// hosts when the first cannot serve them.
+    #[arg(long = "host", default_value = "127.0.0.1:5000")]
+    hosts: Vec<String>,
+
+    /// Spread reads over every healthy host, rather than reading from the
+    /// host which writes are sent to.
+    #[arg(long)]
+    round_robin_reads: bool,
+
+    /// Spread reads over the hosts after the first, such as replicas which
+    /// follow it, rather than reading from the first.
+    #[arg(long, conflicts_with = "round_robin_reads")]
+    follower_reads: bool,
+
+    /// Connect to the store over TLS.
+    #[arg(long)]
+    tls: bool,
+
+    /// PEM file of a certificate to trust for TLS, such as that of a private
+    /// CA, alongside those of the system.
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// Bearer token sent with every request.
+    #[arg(long, env = "CHIPMUNK_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Time allowed for connecting to the store, such as `500ms` or `2s`.
+    #[arg(long, visible_alias = "connect-timeout", value_parser = parse_millis)]
+    connect_timeout_ms: Option<Duration>,
+
+    /// Time allowed for each attempt of a request.
+    #[arg(long, visible_alias = "request-timeout", value_parser = parse_millis)]
+    request_timeout_ms: Option<Duration>,
+
+    /// Number of times each operation is attempted, retrying transient
+    /// failures such as stalled writes with exponential backoff.
+    #[arg(long, default_value = "3")]
+    max_attempts: u32,
 
     #[clap(subcommand)]
     commands: Commands,
@@ -13,7 +68,9 @@ struct Cli {
 
 #[derive(Debug, Clone, Subcommand)]
 enum Commands {
-    /// Perform a health check against the store.
+    /// Describe the host reads are sent to.
+    Status,
+    /// Perform a health check against every host of the store.
     Health,
     /// Get a value from the store, addressed by key.
     Get { key: String },
@@ -21,27 +78,308 @@ enum Commands {
     Insert { key: String, value: String },
     /// Delete a pre-existing key-value pair from the store, addressed by key.
     Delete { key: String },
+    /// List the key-value pairs from a start key, inclusive, up to an end
+    /// key, exclusive, in key order.
+    Scan {
+        #[arg(long)]
+        start: Option<String>,
+        #[arg(long)]
+        end: Option<String>,
+    },
+    /// Print changes to the keys starting with a prefix as they are written.
+    Watch {
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+    /// Print the changes to the keys starting with a prefix after a sequence
+    /// number, in the order they were written, up to the most recent, then
+    /// the sequence number to carry on after.
+    Changes {
+        #[arg(long, default_value = "0")]
+        since: u64,
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+    /// Write the keys of a file of newline-delimited JSON, as exported.
+    Import {
+        path: PathBuf,
+
+        /// Number of lines at the start of the file to skip, to carry on an
+        /// import which stopped part way from where it says.
+        #[arg(long, default_value = "0")]
+        skip_lines: u64,
+
+        /// Check every line of the file without writing any keys.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Write the keys starting with a prefix, in key order, to a file or to
+    /// standard output.
+    Export {
+        /// File to write the keys to, rather than standard output.
+        path: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = BulkFormat::Ndjson)]
+        format: BulkFormat,
+
+        #[arg(long, default_value = "")]
+        prefix: String,
+
+        /// Carry on an export to the file which stopped part way, from the
+        /// key after the last one it holds.
+        #[arg(long, requires = "path")]
+        resume: bool,
+
+        /// Count the keys which would be exported without writing them.
+        #[arg(long, conflicts_with_all = ["path", "resume"])]
+        dry_run: bool,
+    },
+    /// Replace the keys starting with a prefix with those of a file exported
+    /// with it, leaving every other key as it is.
+    Restore {
+        path: PathBuf,
+
+        #[arg(long)]
+        prefix: String,
+    },
+}
+
+/// How exported keys are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BulkFormat {
+    /// A line of JSON for each key, holding it and its value as base64, as
+    /// read by `import`.
+    Ndjson,
+}
+
+/// Prepare an export which stopped part way to be carried on, dropping any
+/// line it left incomplete and returning the key of its last line, if any.
+///
+/// The file is left positioned at its end.
+fn resume_point(file: &mut std::fs::File) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let len = file.metadata()?.len();
+    let end = rfind_newline(file, len)?.map_or(0, |newline| newline + 1);
+    file.set_len(end)?;
+    if end == 0 {
+        return Ok(None);
+    }
+    let start = rfind_newline(file, end - 1)?.map_or(0, |newline| newline + 1);
+    let mut line = vec![0; (end - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut line)?;
+    let (key, _) = BulkEntry::parse(&line)
+        .and_then(|entry| entry.ok_or_else(|| "Line is blank".to_string()))
+        .and_then(|entry| entry.decode())
+        .map_err(|reason| format!("Cannot resume export from its last line: {reason}"))?;
+    Ok(Some(key))
+}
+
+/// Offset of the last newline of the file before the given offset.
+fn rfind_newline(file: &mut std::fs::File, before: u64) -> std::io::Result<Option<u64>> {
+    let mut end = before;
+    let mut chunk = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(RESUME_CHUNK);
+        chunk.resize((end - start) as usize, 0);
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|&b| b == b'\n') {
+            return Ok(Some(start + newline as u64));
+        }
+        end = start;
+    }
+    Ok(None)
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+async fn main() -> Result<(), Box<dyn Error + 'static>> {
     let cli = Cli::parse();
 
-    let client = ChipmunkClient::try_new(cli.host.clone())?;
+    let mut builder = ChipmunkClient::builder(cli.hosts[0].clone())
+        .with_retry(RetryPolicy::default().with_max_attempts(cli.max_attempts));
+    for host in &cli.hosts[1..] {
+        builder = builder.with_host(host.clone());
+    }
+    if cli.round_robin_reads {
+        builder = builder.with_read_preference(ReadPreference::RoundRobin);
+    }
+    if cli.follower_reads {
+        builder = builder.with_read_preference(ReadPreference::Followers);
+    }
+    if cli.tls {
+        builder = builder.with_scheme(Scheme::Https);
+    }
+    if let Some(path) = cli.ca_cert {
+        builder = builder.with_root_certificate(path);
+    }
+    if let Some(token) = cli.token {
+        builder = builder.with_bearer_token(token);
+    }
+    if let Some(timeout) = cli.connect_timeout_ms {
+        builder = builder.with_connect_timeout(timeout);
+    }
+    if let Some(timeout) = cli.request_timeout_ms {
+        builder = builder.with_request_timeout(timeout);
+    }
+    let client = builder.build()?;
 
     match cli.commands {
         Commands::Get { key } => {
             match client.get(&key).await? {
-                Some(value) => println!("{value}"),
+                Some(value) => println!("{}", String::from_utf8_lossy(&value)),
                 None => println!("'{key}' does not exist"),
             };
         }
-        Commands::Insert { key, value } => client.insert(&key, &value).await?,
+        Commands::Insert { key, value } => client.insert(&key, value).await?,
         Commands::Delete { key } => client.delete(&key).await?,
-        Commands::Health => match client.ping().await {
-            Some(_) => println!("{} is healthy", cli.host),
-            None => println!("{} is unhealthy", cli.host),
+        Commands::Scan { start, end } => {
+            let start = start.as_deref().map_or(Bound::Unbounded, Bound::Included);
+            let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+            let mut entries = std::pin::pin!(client.scan((start, end)));
+            while let Some(entry) = entries.next().await {
+                let (key, value) = entry?;
+                println!(
+                    "{}={}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+        }
+        Commands::Watch { prefix } => {
+            let mut changes = std::pin::pin!(client.watch(&prefix));
+            while let Some(change) = changes.next().await {
+                println!("{:?}", change?);
+            }
+        }
+        Commands::Changes { mut since, prefix } => loop {
+            let page = client.changes(since, &prefix).await?;
+            for change in page.changes {
+                println!("{change:?}");
+            }
+            since = page.next;
+            if since >= page.latest {
+                eprintln!("Carry on with --since {since}");
+                break;
+            }
         },
+        Commands::Import {
+            path,
+            skip_lines,
+            dry_run,
+        } => {
+            let mut file = BufReader::new(File::open(path).await?);
+            let mut line = Vec::new();
+            for _ in 0..skip_lines {
+                line.clear();
+                if file.read_until(b'\n', &mut line).await? == 0 {
+                    break;
+                }
+            }
+            if dry_run {
+                let checked = check_import(file).await.map_err(|e| match e {
+                    ClientError::InvalidBulkEntry { line, reason } => {
+                        ClientError::InvalidBulkEntry {
+                            line: line + skip_lines,
+                            reason,
+                        }
+                    }
+                    e => e,
+                })?;
+                println!(
+                    "Would import {} keys from {} lines",
+                    checked.imported, checked.lines
+                );
+                return Ok(());
+            }
+            let mut done = ImportProgress {
+                lines: 0,
+                imported: 0,
+            };
+            let imported = client
+                .import(file, |progress| {
+                    done = progress;
+                    eprintln!("Imported {} keys", progress.imported);
+                })
+                .await;
+            match imported {
+                Ok(imported) => println!("Imported {imported} keys"),
+                Err(e) => {
+                    eprintln!(
+                        "Import stopped after {} keys, carry on with --skip-lines {}",
+                        done.imported,
+                        skip_lines + done.lines
+                    );
+                    return Err(e.into());
+                }
+            }
+        }
+        Commands::Export {
+            path,
+            format: BulkFormat::Ndjson,
+            prefix,
+            resume,
+            dry_run,
+        } => {
+            if dry_run {
+                let exported = client.export(&prefix, tokio::io::sink(), |_| {}).await?;
+                println!("Would export {exported} keys");
+                return Ok(());
+            }
+            let exported = match path {
+                Some(path) if resume => {
+                    let mut file = std::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(path)?;
+                    let last_key = resume_point(&mut file)?;
+                    let file = File::from_std(file);
+                    match last_key {
+                        Some(key) => client.resume_export(&prefix, &key, file, |_| {}).await?,
+                        None => client.export(&prefix, file, |_| {}).await?,
+                    }
+                }
+                Some(path) => {
+                    let file = File::create(path).await?;
+                    client.export(&prefix, file, |_| {}).await?
+                }
+                None => client.export(&prefix, tokio::io::stdout(), |_| {}).await?,
+            };
+            // Standard output may be holding the export itself.
+            eprintln!("Exported {exported} keys");
+        }
+        Commands::Restore { path, prefix } => {
+            let file = BufReader::new(File::open(path).await?);
+            let restored = client
+                .restore(&prefix, file, |progress| {
+                    eprintln!("Restored {} keys", progress.imported);
+                })
+                .await?;
+            println!("Restored {restored} keys under '{prefix}'");
+        }
+        Commands::Status => {
+            let status = client.status().await?;
+            match &status.not_ready_reason {
+                None => println!("{} is ready", status.host),
+                Some(reason) => println!("{} is not ready: {reason}", status.host),
+            }
+            println!("version: {} ({})", status.version, status.git_sha);
+            println!("uptime: {}s", status.uptime.as_secs());
+            println!(
+                "keys: ~{} ({} bytes)",
+                status.tree.approximate_keys, status.tree.approximate_bytes
+            );
+        }
+        Commands::Health => {
+            let ready = client.check_health().await;
+            if ready.is_empty() {
+                println!("No host is healthy");
+            }
+            for host in ready {
+                println!("{host} is healthy");
+            }
+        }
     }
     Ok(())
 }
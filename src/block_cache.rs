@@ -0,0 +1,235 @@
+//! A least recently used (LRU) cache of decoded SSTable data blocks.
+//!
+//! A single cache is shared by every SSTable reader, so that repeated lookups
+//! of hot keys are served from memory rather than re-reading and
+//! re-decompressing the same block from disk.
+
+// TODO: remove once used in other components
+#![allow(dead_code)]
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::memtable::Entry;
+
+/// The decoded entries of a single data block.
+pub type Block = Arc<Vec<(Bytes, Entry)>>;
+
+/// Identifies a block by the ID of the file it belongs to and its offset
+/// within that file.
+type BlockKey = (u64, u64);
+
+/// Shared cache of decoded data blocks, bounded by the total size, in bytes,
+/// of the blocks it holds.
+pub struct BlockCache {
+    capacity: AtomicUsize,
+    /// Number of owners handed out, see [`BlockCache::new_owner`].
+    owners: AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    blocks: LruCache<BlockKey, (Block, usize)>,
+    size: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    /// Create a cache which holds up to `capacity` bytes of blocks. A capacity
+    /// of zero disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: AtomicUsize::new(capacity),
+            owners: AtomicU64::new(0),
+            inner: Mutex::new(Inner {
+                blocks: LruCache::unbounded(),
+                size: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// A new ID for a set of files sharing the cache, such as those of one
+    /// shard, to tell them apart from others whose file IDs are the same.
+    pub fn new_owner(&self) -> u64 {
+        self.owners.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Fetch a block, marking it as the most recently used.
+    pub fn get(&self, file_id: u64, offset: u64) -> Option<Block> {
+        let mut inner = self.inner.lock();
+        match inner.blocks.get(&(file_id, offset)).map(|(b, _)| b.clone()) {
+            Some(block) => {
+                inner.hits += 1;
+                Some(block)
+            }
+            None => {
+                inner.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert a block of the given size, in bytes, evicting the least recently
+    /// used blocks until the cache is back within its capacity.
+    ///
+    /// Blocks which are larger than the entire cache are not held.
+    pub fn insert(&self, file_id: u64, offset: u64, block: Block, size: usize) {
+        let capacity = self.capacity();
+        if size > capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+        if let Some((_, previous)) = inner.blocks.put((file_id, offset), (block, size)) {
+            inner.size -= previous;
+        }
+        inner.size += size;
+        inner.evict_to(capacity);
+    }
+
+    /// Drop every block belonging to the given file, used once a file has
+    /// been removed so its blocks do not linger.
+    pub fn evict_file(&self, file_id: u64) {
+        let mut inner = self.inner.lock();
+        let keys: Vec<_> = inner
+            .blocks
+            .iter()
+            .filter(|((id, _), _)| *id == file_id)
+            .map(|(k, _)| *k)
+            .collect();
+        for key in keys {
+            if let Some((_, size)) = inner.blocks.pop(&key) {
+                inner.size -= size;
+            }
+        }
+    }
+
+    /// Maximum size, in bytes, of the blocks held.
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Change the maximum size, in bytes, of the blocks held, evicting the
+    /// least recently used blocks until the cache is within it.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock();
+        self.capacity.store(capacity, Ordering::Relaxed);
+        inner.evict_to(capacity);
+    }
+
+    /// Total size, in bytes, of the blocks currently held.
+    pub fn size(&self) -> usize {
+        self.inner.lock().size
+    }
+
+    /// Number of lookups which were, and were not, served from the cache.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let inner = self.inner.lock();
+        (inner.hits, inner.misses)
+    }
+
+    /// Set the number of hits and misses back to zero.
+    pub fn reset_hits_and_misses(&self) {
+        let mut inner = self.inner.lock();
+        inner.hits = 0;
+        inner.misses = 0;
+    }
+}
+
+impl Inner {
+    /// Evict the least recently used blocks until those left total no more
+    /// than `capacity` bytes.
+    fn evict_to(&mut self, capacity: usize) {
+        while self.size > capacity {
+            match self.blocks.pop_lru() {
+                Some((_, (_, evicted))) => self.size -= evicted,
+                None => break,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity())
+            .field("size", &inner.size)
+            .field("blocks", &inner.blocks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(key: &'static str) -> Block {
+        Arc::new(vec![(
+            Bytes::from_static(key.as_bytes()),
+            Entry {
+                value: None,
+                expires_at: None,
+                separated: false,
+                stamp: None,
+            },
+        )])
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = BlockCache::new(100);
+        cache.insert(0, 0, block("a"), 40);
+        cache.insert(0, 40, block("b"), 40);
+        assert_eq!(cache.size(), 80);
+
+        // Touch the first block so that the second is least recently used
+        assert!(cache.get(0, 0).is_some());
+        cache.insert(1, 0, block("c"), 40);
+        assert_eq!(cache.size(), 80);
+        assert!(cache.get(0, 40).is_none(), "Least recently used is evicted");
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(1, 0).is_some());
+        assert_eq!(cache.hits_and_misses(), (3, 1));
+
+        // Larger than the entire cache, so never held
+        cache.insert(2, 0, block("d"), 101);
+        assert!(cache.get(2, 0).is_none());
+
+        cache.evict_file(0);
+        assert!(cache.get(0, 0).is_none());
+        assert_eq!(cache.size(), 40);
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = BlockCache::new(0);
+        cache.insert(0, 0, block("a"), 1);
+        assert!(cache.get(0, 0).is_none());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn resize() {
+        let cache = BlockCache::new(100);
+        cache.insert(0, 0, block("a"), 40);
+        cache.insert(0, 40, block("b"), 40);
+
+        cache.set_capacity(50);
+        assert_eq!(cache.size(), 40, "Shrinking evicts down to the capacity");
+        assert!(cache.get(0, 0).is_none());
+        assert!(cache.get(0, 40).is_some());
+
+        cache.set_capacity(200);
+        cache.insert(1, 0, block("c"), 150);
+        assert!(cache.get(1, 0).is_some(), "Blocks fit the grown cache");
+    }
+}
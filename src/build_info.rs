@@ -0,0 +1,60 @@
+//! What is running, see [`BuildInfo`].
+//!
+//! Everything is recorded by the build script, so that tooling can tell apart
+//! binaries built from the same version.
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Version of the crate the binary was built from.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the binary was built from, or `unknown` when built outside of a
+/// git repository without `CHIPMUNK_GIT_SHA` set.
+pub const GIT_SHA: &str = env!("CHIPMUNK_GIT_SHA");
+
+/// When the binary was built, as an RFC 3339 timestamp in UTC. Taken from
+/// `SOURCE_DATE_EPOCH` when set, for reproducible builds.
+pub const BUILD_TIMESTAMP: &str = env!("CHIPMUNK_BUILD_TIMESTAMP");
+
+/// Comma separated Cargo features the binary was built with.
+const FEATURES: &str = env!("CHIPMUNK_FEATURES");
+
+/// Everything known about the build, as printed by `chipmunk --version`.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("CHIPMUNK_GIT_SHA"),
+    "\nbuilt: ",
+    env!("CHIPMUNK_BUILD_TIMESTAMP"),
+    "\nfeatures: ",
+    env!("CHIPMUNK_FEATURES"),
+);
+
+/// The build of Chipmunk that is running, served at `/version`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct BuildInfo {
+    /// Version of the crate, such as `0.1.0`.
+    pub version: &'static str,
+    /// Commit the build was made from.
+    pub git_sha: &'static str,
+    /// When the build was made, as an RFC 3339 timestamp.
+    pub build_timestamp: &'static str,
+    /// Cargo features enabled, in alphabetical order.
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// The build of the running binary.
+    pub fn current() -> Self {
+        Self {
+            version: VERSION,
+            git_sha: GIT_SHA,
+            build_timestamp: BUILD_TIMESTAMP,
+            features: FEATURES
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+        }
+    }
+}
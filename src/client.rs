@@ -1,6 +1,44 @@
-use std::net::SocketAddr;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use reqwest::StatusCode;
+use base64::Engine;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Certificate, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tracing::{debug, warn};
+
+use crate::server::{
+    BulkEntry, ChangesResponse, ErrorResponse, ImportResponse, KeyEncoding, ListedKey, WatchEvent,
+    CONTINUATION_HEADER, KEY_BASE64, LAST_EVENT_ID_HEADER, NDJSON_CONTENT_TYPE,
+};
+
+use crate::statistics::{Statistics, TreeStats};
+
+/// Number of scanned entries buffered ahead of the caller reading them.
+const SCAN_BUFFER: usize = 64;
+
+/// Maximum number of lines sent by each request of an import, see
+/// [`ChipmunkClient::import`].
+const IMPORT_BATCH_LINES: usize = 1000;
+
+/// Size, in bytes, from which the lines read so far are sent as a request of
+/// an import rather than waiting for more.
+const IMPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Default time after which a host which failed is tried again, see
+/// [`ChipmunkClientBuilder::with_health_check_interval`].
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 /// Errors that originate from interacting with a remote chipmunk store.
 #[derive(Debug, thiserror::Error)]
@@ -17,53 +55,984 @@ pub enum ClientError {
         source: reqwest::Error,
     },
 
+    #[error("unable to convert the value of key '{key_name}' to or from JSON: {source}")]
+    JsonOp {
+        key_name: String,
+        source: serde_json::Error,
+    },
+
     #[error("unable to delete key '{key_name}': {source}")]
     DeleteOp {
         key_name: String,
         source: reqwest::Error,
     },
 
-    #[error("unable to parse given host '{host}': {source}")]
-    InvalidHost {
-        host: String,
-        source: std::net::AddrParseError,
+    #[error("unable to {op}, not found: {message}")]
+    NotFound { op: ClientOp, message: String },
+
+    #[error("unable to {op}, conflict: {message}")]
+    Conflict { op: ClientOp, message: String },
+
+    #[error("unable to {op}, precondition failed: {message}")]
+    PreconditionFailed { op: ClientOp, message: String },
+
+    /// Writes were stalled while the store caught up, and should be retried
+    /// after `retry_after` if given.
+    #[error("unable to {op}, writes are stalled: {message}")]
+    WriteStalled {
+        op: ClientOp,
+        retry_after: Option<Duration>,
+        message: String,
+    },
+
+    #[error("unable to {op}, server error {status}: {message}")]
+    Server {
+        op: ClientOp,
+        status: StatusCode,
+        message: String,
+    },
+
+    /// Any other status which is not a success, such as an invalid request.
+    #[error("unable to {op}, rejected with {status}: {message}")]
+    Rejected {
+        op: ClientOp,
+        status: StatusCode,
+        message: String,
+    },
+
+    #[error("unable to scan keys: {0}")]
+    ScanOp(reqwest::Error),
+
+    #[error("unable to read scanned keys: {reason}")]
+    InvalidScan { reason: String },
+
+    #[error("unable to get the status of the store: {0}")]
+    StatusOp(reqwest::Error),
+
+    #[error("unable to read the status of the store: {reason}")]
+    InvalidStatus { reason: String },
+
+    #[error("unable to watch keys: {0}")]
+    WatchOp(reqwest::Error),
+
+    #[error("unable to read watched changes: {reason}")]
+    InvalidWatch { reason: String },
+
+    #[error("unable to read changes: {0}")]
+    ChangesOp(reqwest::Error),
+
+    #[error("unable to read the changefeed: {reason}")]
+    InvalidChanges { reason: String },
+
+    #[error("unable to import keys: {0}")]
+    ImportOp(reqwest::Error),
+
+    #[error("import rejected after {imported} keys were imported: {reason}")]
+    ImportRejected { imported: u64, reason: String },
+
+    #[error("unable to read the result of an import: {reason}")]
+    InvalidImport { reason: String },
+
+    #[error("unable to export keys: {0}")]
+    ExportOp(reqwest::Error),
+
+    #[error("unable to delete the keys being restored: {0}")]
+    RestoreOp(reqwest::Error),
+
+    #[error("invalid entry on line {line}: {reason}")]
+    InvalidBulkEntry { line: u64, reason: String },
+
+    #[error("unable to read or write bulk entries: {0}")]
+    BulkIo(std::io::Error),
+
+    #[error("unable to parse given host '{host}': {reason}")]
+    InvalidHost { host: String, reason: String },
+
+    #[error("unable to load root certificate '{path}': {reason}")]
+    InvalidCertificate { path: PathBuf, reason: String },
+
+    #[error("invalid bearer token: {0}")]
+    InvalidToken(header::InvalidHeaderValue),
+
+    #[error("unable to build client: {0}")]
+    Build(reqwest::Error),
+}
+
+/// Failures which a [`RetryPolicy`] retries, as they may pass by themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryOn {
+    /// The store could not be connected to or, for reads, the connection was
+    /// lost. A write is only retried when it was never sent.
+    pub connection: bool,
+    /// The request timed out.
+    pub timeout: bool,
+    /// Writes were refused with `429 Too Many Requests` while the store
+    /// caught up on flushes and compaction.
+    pub write_stall: bool,
+    /// The store answered `503 Service Unavailable`, such as while restoring
+    /// or when a request timed out on the server.
+    pub unavailable: bool,
+}
+
+impl RetryOn {
+    /// The failures after which a write cannot have been applied, which
+    /// writes are retried on by default.
+    pub fn unapplied() -> Self {
+        Self {
+            connection: true,
+            timeout: false,
+            write_stall: true,
+            unavailable: false,
+        }
+    }
+
+    fn error(&self, e: &reqwest::Error, operation: Operation) -> bool {
+        let lost = match operation {
+            Operation::Read => e.is_connect() || e.is_request(),
+            Operation::Write => e.is_connect(),
+        };
+        (self.connection && lost) || (self.timeout && e.is_timeout())
+    }
+
+    fn status(&self, status: StatusCode) -> bool {
+        (self.write_stall && status == StatusCode::TOO_MANY_REQUESTS)
+            || (self.unavailable && status == StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+impl Default for RetryOn {
+    fn default() -> Self {
+        Self {
+            connection: true,
+            timeout: true,
+            write_stall: true,
+            unavailable: true,
+        }
+    }
+}
+
+/// How a [`ChipmunkClient`] retries failed operations, backing off
+/// exponentially between attempts.
+///
+/// A write which timed out, or was answered with `503 Service Unavailable`,
+/// may still have been applied, and retrying it could then overwrite a later
+/// write from another client. Writes are therefore only retried on the
+/// failures of [`RetryOn::unapplied`] unless others are opted into through
+/// [`RetryPolicy::with_retry_writes_on`].
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of times an operation is attempted, including the first.
+    pub max_attempts: u32,
+    /// Time waited before the first retry, doubling with each retry after.
+    pub initial_backoff: Duration,
+    /// Longest time waited between attempts.
+    pub max_backoff: Duration,
+    /// Whether to wait a random time up to the backoff instead, so that
+    /// clients which failed together do not retry together.
+    pub jitter: bool,
+    /// Failures which reads are retried on, any other being returned at once.
+    pub retry_on: RetryOn,
+    /// Failures which writes are retried on, any other being returned at
+    /// once.
+    pub retry_writes_on: RetryOn,
+}
+
+impl RetryPolicy {
+    /// Attempt every operation once.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_retry_on(mut self, retry_on: RetryOn) -> Self {
+        self.retry_on = retry_on;
+        self
+    }
+
+    pub fn with_retry_writes_on(mut self, retry_writes_on: RetryOn) -> Self {
+        self.retry_writes_on = retry_writes_on;
+        self
+    }
+
+    /// Time to wait after the given attempt failed, at least as long as the
+    /// server asked for through `Retry-After`, within the maximum backoff.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let exponential = 2u32
+            .checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .unwrap_or(self.max_backoff);
+        let backoff = match self.jitter {
+            true => exponential.mul_f64(fastrand::f64()),
+            false => exponential,
+        };
+        backoff
+            .max(retry_after.unwrap_or_default())
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter: true,
+            retry_on: RetryOn::default(),
+            retry_writes_on: RetryOn::unapplied(),
+        }
+    }
+}
+
+/// A change to the watched keys, see [`ChipmunkClient::watch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchChange {
+    Put {
+        key: Bytes,
+        value: Bytes,
+        sequence: u64,
+        /// Unix timestamp, in milliseconds, from which the value expires.
+        expires_at: Option<u64>,
+    },
+    Delete {
+        key: Bytes,
+        sequence: u64,
+    },
+    /// Every key from `start`, inclusive, up to `end`, exclusive, was deleted.
+    DeleteRange {
+        start: Bytes,
+        end: Bytes,
+        sequence: u64,
+    },
+    /// This many changes were missed, which should be caught up on by reading
+    /// the keys again.
+    Lagged {
+        missed: u64,
     },
 }
 
+impl WatchChange {
+    /// The change sent as an event with base64 keys and values, failing
+    /// with the error given the reason otherwise.
+    fn decode(event: WatchEvent, invalid: fn(String) -> ClientError) -> Result<Self, ClientError> {
+        let decode = |encoded: String| {
+            KEY_BASE64
+                .decode(encoded)
+                .map(Bytes::from)
+                .map_err(|e| invalid(e.to_string()))
+        };
+        Ok(match event {
+            WatchEvent::Put {
+                key,
+                value,
+                sequence,
+                expires_at,
+            } => WatchChange::Put {
+                key: decode(key)?,
+                value: decode(value)?,
+                sequence,
+                expires_at,
+            },
+            WatchEvent::Delete { key, sequence } => WatchChange::Delete {
+                key: decode(key)?,
+                sequence,
+            },
+            WatchEvent::DeleteRange {
+                start,
+                end,
+                sequence,
+            } => WatchChange::DeleteRange {
+                start: decode(start)?,
+                end: decode(end)?,
+                sequence,
+            },
+            WatchEvent::Lagged { missed } => WatchChange::Lagged { missed },
+        })
+    }
+}
+
+/// A page of the changefeed, see [`ChipmunkClient::changes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangePage {
+    /// Changes in the order they were written.
+    pub changes: Vec<WatchChange>,
+    /// Sequence number to read the next page after.
+    pub next: u64,
+    /// Sequence number of the most recent write, which `next` reaches once
+    /// every change has been read.
+    pub latest: u64,
+}
+
+/// Splits a stream of server-sent events into the data and ID of each.
+#[derive(Debug, Default)]
+struct EventParser {
+    /// Bytes received after the last complete line.
+    partial: Vec<u8>,
+    data: String,
+    id: Option<String>,
+}
+
+impl EventParser {
+    /// Parse the next chunk of the stream, returning the events it completes.
+    fn feed(&mut self, chunk: &[u8]) -> Vec<(String, Option<String>)> {
+        self.partial.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                // Events with no data, such as keep-alive comments, are
+                // ignored.
+                if !self.data.is_empty() {
+                    events.push((std::mem::take(&mut self.data), self.id.take()));
+                }
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "data" => {
+                    if !self.data.is_empty() {
+                        self.data.push('\n');
+                    }
+                    self.data.push_str(value);
+                }
+                "id" => self.id = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+/// The state of a host of the store, see [`ChipmunkClient::status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    /// Scheme and authority of the host, such as `http://localhost:5000`.
+    pub host: String,
+    /// Whether the host is ready to serve reads and writes.
+    pub ready: bool,
+    /// Why the host is not ready, such as while it restores.
+    pub not_ready_reason: Option<String>,
+    /// Version of Chipmunk which the host runs.
+    pub version: String,
+    /// Commit which the host was built from.
+    pub git_sha: String,
+    /// Time since the store was started.
+    pub uptime: Duration,
+    /// The shape of the tree.
+    pub tree: TreeStats,
+    /// Work performed by the engine.
+    pub statistics: Statistics,
+}
+
+/// How far an import has got, see [`ChipmunkClient::import`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportProgress {
+    /// Number of lines read and written, including blank lines.
+    pub lines: u64,
+    /// Number of keys written.
+    pub imported: u64,
+}
+
+/// Check newline-delimited JSON, as read by [`ChipmunkClient::import`],
+/// without writing it anywhere, returning the lines read and keys it holds.
+///
+/// Nothing is sent to the store, so values larger than it accepts are only
+/// found by importing them.
+pub async fn check_import<R>(mut reader: R) -> Result<ImportProgress, ClientError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut checked = ImportProgress {
+        lines: 0,
+        imported: 0,
+    };
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(ClientError::BulkIo)?;
+        if read == 0 {
+            return Ok(checked);
+        }
+        checked.lines += 1;
+        let entry = BulkEntry::parse(&line).and_then(|entry| entry.map(|e| e.decode()).transpose());
+        match entry {
+            Ok(Some(_)) => checked.imported += 1,
+            Ok(None) => {}
+            Err(reason) => {
+                return Err(ClientError::InvalidBulkEntry {
+                    line: checked.lines,
+                    reason,
+                })
+            }
+        }
+    }
+}
+
+/// The fields of `/version` read by [`ChipmunkClient::status`].
+#[derive(Debug, Deserialize)]
+struct VersionBody {
+    version: String,
+    git_sha: String,
+}
+
+/// The fields of `/admin/stats` read by [`ChipmunkClient::status`].
+#[derive(Debug, Deserialize)]
+struct StatsBody {
+    uptime_seconds: u64,
+    #[serde(flatten)]
+    tree: TreeStats,
+    statistics: Statistics,
+}
+
+/// The response, if its status is a success, or the error matching its
+/// status otherwise, with the message of its [`ErrorResponse`].
+async fn check_status(op: ClientOp, resp: Response) -> Result<Response, ClientError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|seconds| seconds.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs);
+    // The body is only read to describe the failure, which its status
+    // already does should it be unreadable.
+    let body = resp.text().await.unwrap_or_default();
+    let message = match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(error) => error.message,
+        Err(_) if !body.is_empty() => body,
+        Err(_) => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+    Err(match status {
+        StatusCode::NOT_FOUND => ClientError::NotFound { op, message },
+        StatusCode::CONFLICT => ClientError::Conflict { op, message },
+        StatusCode::PRECONDITION_FAILED => ClientError::PreconditionFailed { op, message },
+        StatusCode::TOO_MANY_REQUESTS => ClientError::WriteStalled {
+            op,
+            retry_after,
+            message,
+        },
+        status if status.is_server_error() => ClientError::Server {
+            op,
+            status,
+            message,
+        },
+        status => ClientError::Rejected {
+            op,
+            status,
+            message,
+        },
+    })
+}
+
+/// The JSON body of a successful response to a status endpoint.
+async fn read_status<T: DeserializeOwned>(
+    resp: Result<Response, reqwest::Error>,
+) -> Result<T, ClientError> {
+    let resp = resp.map_err(ClientError::StatusOp)?;
+    let body = check_status(ClientOp::Status, resp)
+        .await?
+        .bytes()
+        .await
+        .map_err(ClientError::StatusOp)?;
+    serde_json::from_slice(&body).map_err(|e| ClientError::InvalidStatus {
+        reason: e.to_string(),
+    })
+}
+
+/// Scheme the store is reached through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scheme {
+    #[default]
+    Http,
+    /// Connections are made over TLS, see [`ChipmunkClientBuilder::with_root_certificate`].
+    Https,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheme::Http => f.write_str("http"),
+            Scheme::Https => f.write_str("https"),
+        }
+    }
+}
+
+/// Which of the healthy hosts reads are sent to. Writes are always sent to
+/// the first healthy host, in the order the hosts were configured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadPreference {
+    /// Read from the same host as writes, so that every write is seen.
+    #[default]
+    Primary,
+    /// Spread reads over every healthy host in turn.
+    RoundRobin,
+    /// Spread reads over the healthy hosts after the first, such as replicas
+    /// following it with `--replica-of`, reading from the first only when
+    /// none of them are healthy. Writes may take a moment to be seen.
+    Followers,
+}
+
+/// Whether an operation reads or writes, deciding the hosts it may be sent
+/// to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Read,
+    Write,
+}
+
+/// The operations of a [`ChipmunkClient`], as reported to a
+/// [`RequestObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientOp {
+    Get,
+    Insert,
+    Delete,
+    Scan,
+    Watch,
+    Changes,
+    Import,
+    Export,
+    Restore,
+    Status,
+    Health,
+}
+
+impl ClientOp {
+    fn operation(self) -> Operation {
+        match self {
+            ClientOp::Insert | ClientOp::Delete | ClientOp::Import | ClientOp::Restore => {
+                Operation::Write
+            }
+            _ => Operation::Read,
+        }
+    }
+}
+
+impl fmt::Display for ClientOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ClientOp::Get => "get",
+            ClientOp::Insert => "insert",
+            ClientOp::Delete => "delete",
+            ClientOp::Scan => "scan",
+            ClientOp::Watch => "watch",
+            ClientOp::Changes => "changes",
+            ClientOp::Import => "import",
+            ClientOp::Export => "export",
+            ClientOp::Restore => "restore",
+            ClientOp::Status => "status",
+            ClientOp::Health => "health",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Told of every request a [`ChipmunkClient`] sends, such as to record
+/// metrics, see [`ChipmunkClientBuilder::with_observer`].
+pub trait RequestObserver: Send + Sync {
+    /// A request of the operation was answered with the status, or failed
+    /// without an answer when there is none, after the duration. Each
+    /// attempt of a retried operation is a request of its own. The duration
+    /// is until the response began, so excludes reading a streamed body.
+    fn on_request(&self, op: ClientOp, duration: Duration, status: Option<StatusCode>);
+}
+
+impl fmt::Debug for dyn RequestObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestObserver")
+    }
+}
+
+/// A host of the store, as known to a [`ChipmunkClient`].
+#[derive(Debug)]
+struct Node {
+    /// Scheme and authority of the host, such as `http://localhost:5000`.
+    base_url: String,
+    /// When requests to the host last failed, or `None` while they succeed.
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl Node {
+    /// Whether requests may be sent to the host, which is the case once the
+    /// interval has passed since it last failed.
+    fn is_healthy(&self, interval: Duration) -> bool {
+        self.failed_at
+            .lock()
+            .is_none_or(|failed_at| failed_at.elapsed() >= interval)
+    }
+
+    fn mark(&self, healthy: bool) {
+        let mut failed_at = self.failed_at.lock();
+        if healthy {
+            if failed_at.take().is_some() {
+                debug!(host = self.base_url, "Host recovered");
+            }
+        } else {
+            if failed_at.is_none() {
+                warn!(host = self.base_url, "Host failed, failing over");
+            }
+            *failed_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Configure how a [`ChipmunkClient`] reaches the store, see
+/// [`ChipmunkClient::builder`].
+#[derive(Debug, Clone)]
+pub struct ChipmunkClientBuilder {
+    hosts: Vec<String>,
+    scheme: Scheme,
+    read_preference: ReadPreference,
+    health_check_interval: Duration,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    bearer_token: Option<String>,
+    root_certificates: Vec<PathBuf>,
+    retry: RetryPolicy,
+    observer: Option<Arc<dyn RequestObserver>>,
+}
+
+impl ChipmunkClientBuilder {
+    pub fn with_scheme(mut self, scheme: Scheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    /// Add a host to fail over to, after those already given, which is given
+    /// as for [`ChipmunkClient::builder`].
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.push(host.into());
+        self
+    }
+
+    pub fn with_read_preference(mut self, read_preference: ReadPreference) -> Self {
+        self.read_preference = read_preference;
+        self
+    }
+
+    /// Time after which a host which failed is tried again, without which it
+    /// is only sent requests when every host has failed.
+    pub fn with_health_check_interval(mut self, health_check_interval: Duration) -> Self {
+        self.health_check_interval = health_check_interval;
+        self
+    }
+
+    /// Time allowed for connecting to the store, by default as long as the
+    /// operating system allows.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Time allowed for each attempt of a request, from connecting until the
+    /// response body has been read. Requests never time out by default.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Token sent as `Authorization: Bearer <token>` with every request, as
+    /// required by the admin routes.
+    pub fn with_bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    /// PEM file of a certificate to trust, alongside those of the system,
+    /// such as that of a private CA or a self-signed server.
+    pub fn with_root_certificate(mut self, path: impl Into<PathBuf>) -> Self {
+        self.root_certificates.push(path.into());
+        self
+    }
+
+    /// Retry failed operations as the policy describes, rather than with the
+    /// default [`RetryPolicy`].
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Tell the observer of every request sent, with its duration and
+    /// status.
+    pub fn with_observer(mut self, observer: Arc<dyn RequestObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Attempt to create the [`ChipmunkClient`].
+    pub fn build(self) -> Result<ChipmunkClient, ClientError> {
+        let mut nodes = Vec::with_capacity(self.hosts.len());
+        for host in &self.hosts {
+            let invalid_host = |reason: &str| ClientError::InvalidHost {
+                host: host.clone(),
+                reason: reason.to_string(),
+            };
+            let url = Url::parse(&format!("{}://{host}", self.scheme))
+                .map_err(|e| invalid_host(&e.to_string()))?;
+            if url.path() != "/" || url.query().is_some() || !url.username().is_empty() {
+                return Err(invalid_host("expected a host and optional port"));
+            }
+            nodes.push(Node {
+                base_url: url.as_str().trim_end_matches('/').to_string(),
+                failed_at: Mutex::new(None),
+            });
+        }
+
+        let mut client = reqwest::Client::builder();
+        if let Some(connect_timeout) = self.connect_timeout {
+            client = client.connect_timeout(connect_timeout);
+        }
+        if let Some(request_timeout) = self.request_timeout {
+            client = client.timeout(request_timeout);
+        }
+        if let Some(token) = &self.bearer_token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .map_err(ClientError::InvalidToken)?;
+            value.set_sensitive(true);
+            client = client.default_headers(HeaderMap::from_iter([(header::AUTHORIZATION, value)]));
+        }
+        for path in &self.root_certificates {
+            let invalid = |reason: String| ClientError::InvalidCertificate {
+                path: path.clone(),
+                reason,
+            };
+            let pem = std::fs::read(path).map_err(|e| invalid(e.to_string()))?;
+            let certificate = Certificate::from_pem(&pem).map_err(|e| invalid(e.to_string()))?;
+            client = client.add_root_certificate(certificate);
+        }
+
+        Ok(ChipmunkClient {
+            nodes: nodes.into(),
+            next_read: Arc::new(AtomicUsize::new(0)),
+            read_preference: self.read_preference,
+            health_check_interval: self.health_check_interval,
+            client: client.build().map_err(ClientError::Build)?,
+            retry: self.retry,
+            observer: self.observer,
+        })
+    }
+}
+
 /// Interact with a remote chipmunk store.
+///
+/// Requests are sent to one of the configured hosts, the first being the
+/// primary. A host which cannot be connected to, or answers
+/// `503 Service Unavailable`, is passed over until the health check interval
+/// has passed, with retries of the failed request sent to the next host.
+#[derive(Clone)]
 pub struct ChipmunkClient {
-    host: SocketAddr,
+    nodes: Arc<[Node]>,
+    /// Counter choosing the host of each read, see [`ReadPreference`].
+    next_read: Arc<AtomicUsize>,
+    read_preference: ReadPreference,
+    health_check_interval: Duration,
     client: reqwest::Client,
+    retry: RetryPolicy,
+    observer: Option<Arc<dyn RequestObserver>>,
 }
 
 impl ChipmunkClient {
-    /// Attempt to create a new [`ChipmunkClient`].
-    pub fn try_new(host: String) -> Result<Self, ClientError> {
-        Ok(Self {
-            host: host
-                .parse()
-                .map_err(|e| ClientError::InvalidHost { host, source: e })?,
-            client: reqwest::Client::new(),
+    /// Start configuring a [`ChipmunkClient`] for the store at the host,
+    /// given as a hostname or IP address and an optional port, such as
+    /// `localhost:5000`. The port otherwise defaults to that of the
+    /// [`Scheme`].
+    pub fn builder(host: impl Into<String>) -> ChipmunkClientBuilder {
+        ChipmunkClientBuilder {
+            hosts: vec![host.into()],
+            scheme: Scheme::default(),
+            read_preference: ReadPreference::default(),
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            connect_timeout: None,
+            request_timeout: None,
+            bearer_token: None,
+            root_certificates: Vec::new(),
+            retry: RetryPolicy::default(),
+            observer: None,
+        }
+    }
+
+    /// The host the next attempt of an operation is sent to, passing over
+    /// those which failed unless every host has.
+    fn select(&self, operation: Operation) -> usize {
+        let mut healthy: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].is_healthy(self.health_check_interval))
+            .collect();
+        if healthy.is_empty() {
+            healthy = (0..self.nodes.len()).collect();
+        }
+        match (operation, self.read_preference) {
+            (Operation::Write, _) | (Operation::Read, ReadPreference::Primary) => healthy[0],
+            (Operation::Read, ReadPreference::RoundRobin) => {
+                healthy[self.next_read.fetch_add(1, Ordering::Relaxed) % healthy.len()]
+            }
+            (Operation::Read, ReadPreference::Followers) => {
+                // The healthy hosts are in the order they were configured.
+                let followers = match healthy.iter().position(|&i| i > 0) {
+                    Some(start) => &healthy[start..],
+                    None => &healthy[..],
+                };
+                followers[self.next_read.fetch_add(1, Ordering::Relaxed) % followers.len()]
+            }
+        }
+    }
+
+    /// Send the request built for a host, again for as long as it fails in a
+    /// way the [`RetryPolicy`] retries. Retries are sent to another host
+    /// straight away if the one which failed is passed over, and after the
+    /// backoff otherwise. The last response or error is returned once attempts
+    /// run out.
+    async fn send(
+        &self,
+        op: ClientOp,
+        request: impl Fn(&reqwest::Client, &str) -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let operation = op.operation();
+        let mut attempt = 1;
+        let mut node = self.select(operation);
+        loop {
+            let result = self
+                .observe(op, request(&self.client, &self.nodes[node].base_url))
+                .await;
+            self.nodes[node].mark(match &result {
+                Ok(resp) => resp.status() != StatusCode::SERVICE_UNAVAILABLE,
+                Err(e) => !(e.is_connect() || e.is_request() || e.is_timeout()),
+            });
+            let retry_on = match operation {
+                Operation::Read => &self.retry.retry_on,
+                Operation::Write => &self.retry.retry_writes_on,
+            };
+            let (retry, retry_after) = match &result {
+                Ok(resp) => (
+                    retry_on.status(resp.status()),
+                    resp.headers()
+                        .get(header::RETRY_AFTER)
+                        .and_then(|seconds| seconds.to_str().ok()?.parse().ok())
+                        .map(Duration::from_secs),
+                ),
+                Err(e) => (retry_on.error(e, operation), None),
+            };
+            if !retry || attempt >= self.retry.max_attempts {
+                return result;
+            }
+            let failed = node;
+            node = self.select(operation);
+            if node == failed {
+                let backoff = self.retry.backoff(attempt, retry_after);
+                debug!(attempt, ?backoff, "Retrying request");
+                tokio::time::sleep(backoff).await;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Send a single request, telling the [`RequestObserver`] of it.
+    async fn observe(
+        &self,
+        op: ClientOp,
+        request: RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let started = Instant::now();
+        let result = request.send().await;
+        if let Some(observer) = &self.observer {
+            let status = result.as_ref().ok().map(Response::status);
+            observer.on_request(op, started.elapsed(), status);
+        }
+        result
+    }
+
+    /// Path addressing a key, escaped so that any key can be used.
+    fn key_path(key: &str) -> String {
+        format!("/api/v1/{}", KeyEncoding::Raw.encode(key.as_bytes()))
+    }
+
+    /// Describe the host which reads are sent to, from its `/readyz`,
+    /// `/version` and `/admin/stats`. A host which is up but not ready to
+    /// serve is reported rather than returned as an error.
+    pub async fn status(&self) -> Result<ServerStatus, ClientError> {
+        let node = &self.nodes[self.select(ClientOp::Status.operation())];
+        let get = |path: &str| {
+            let request = self.client.get(format!("{}{path}", node.base_url));
+            self.observe(ClientOp::Status, request)
+        };
+        let (ready, version, stats) =
+            tokio::join!(get("/readyz"), get("/version"), get("/admin/stats"));
+
+        let ready = ready.map_err(ClientError::StatusOp)?;
+        let not_ready = match ready.status().is_success() {
+            true => None,
+            false => Some(match ready.text().await {
+                Ok(body) => serde_json::from_str::<ErrorResponse>(&body)
+                    .map(|e| e.message)
+                    .unwrap_or(body),
+                Err(e) => e.to_string(),
+            }),
+        };
+        let version: VersionBody = read_status(version).await?;
+        let stats: StatsBody = read_status(stats).await?;
+        Ok(ServerStatus {
+            host: node.base_url.clone(),
+            ready: not_ready.is_none(),
+            not_ready_reason: not_ready,
+            version: version.version,
+            git_sha: version.git_sha,
+            uptime: Duration::from_secs(stats.uptime_seconds),
+            tree: stats.tree,
+            statistics: stats.statistics,
         })
     }
 
-    /// Check the remote server is available to accept connections, returning
-    /// [`Some`] if available and [`None`] otherwise.
-    pub async fn ping(&self) -> Option<()> {
-        self.client
-            .get(format!("http://{}/health", self.host))
-            .send()
-            .await
-            .ok()
-            .map(|_| ())
+    /// Check whether each host is ready to serve, as by its `/readyz`, so
+    /// that those which have recovered are used again before the health
+    /// check interval has passed. Returns the hosts which are ready.
+    pub async fn check_health(&self) -> Vec<String> {
+        let mut ready = Vec::new();
+        for node in self.nodes.iter() {
+            let request = self.client.get(format!("{}/readyz", node.base_url));
+            let healthy = match self.observe(ClientOp::Health, request).await {
+                Ok(resp) => resp.status().is_success(),
+                Err(_) => false,
+            };
+            node.mark(healthy);
+            if healthy {
+                ready.push(node.base_url.clone());
+            }
+        }
+        ready
     }
 
     /// Get a value from the remote store, addressed by its key.
-    pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>, ClientError> {
+        let path = Self::key_path(key);
         let resp = self
-            .client
-            .get(format!("http://{}/api/v1/{}", self.host, key))
-            .send()
+            .send(ClientOp::Get, |client, base| {
+                client.get(format!("{base}{path}"))
+            })
             .await
             .map_err(|e| ClientError::GetOp {
                 key_name: key.to_string(),
@@ -74,44 +1043,1398 @@ impl ChipmunkClient {
             return Ok(None);
         }
 
+        let resp = check_status(ClientOp::Get, resp).await?;
         let body = resp.bytes().await.map_err(|e| ClientError::GetOp {
             key_name: key.to_string(),
             source: e,
         })?;
-        Ok(Some(String::from_utf8_lossy(&body).to_string()))
+        Ok(Some(body))
     }
 
-    /// Insert a new key-value pair.
-    pub async fn insert(&self, key: &str, value: &str) -> Result<(), ClientError> {
-        let req = self
-            .client
-            .post(format!("http://{}/api/v1", self.host))
-            .body(format!("{key}={value}"));
+    /// Get a value from the remote store, addressed by its key, and
+    /// deserialize it from JSON, as written by [`ChipmunkClient::put_json`].
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, ClientError> {
+        let Some(value) = self.get(key).await? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&value)
+            .map(Some)
+            .map_err(|e| ClientError::JsonOp {
+                key_name: key.to_string(),
+                source: e,
+            })
+    }
+
+    /// Insert a new key-value pair, the value being sent as raw bytes.
+    pub async fn insert(&self, key: &str, value: impl Into<Bytes>) -> Result<(), ClientError> {
+        self.put(key, value.into(), "application/octet-stream")
+            .await
+    }
 
-        let resp = req.send().await.map_err(|e| ClientError::InsertOp {
+    /// Insert a new key-value pair, the value being serialized to JSON.
+    pub async fn put_json<T: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), ClientError> {
+        let value = serde_json::to_vec(value).map_err(|e| ClientError::JsonOp {
             key_name: key.to_string(),
             source: e,
         })?;
+        self.put(key, value.into(), "application/json").await
+    }
 
-        match resp.error_for_status() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(ClientError::InsertOp {
+    /// Write the value of a key, sent with the given content type.
+    async fn put(&self, key: &str, value: Bytes, content_type: &str) -> Result<(), ClientError> {
+        let path = Self::key_path(key);
+        let resp = self
+            .send(ClientOp::Insert, |client, base| {
+                client
+                    .put(format!("{base}{path}"))
+                    .header(header::CONTENT_TYPE, content_type)
+                    .body(value.clone())
+            })
+            .await
+            .map_err(|e| ClientError::InsertOp {
                 key_name: key.to_string(),
                 source: e,
-            }),
-        }
+            })?;
+
+        check_status(ClientOp::Insert, resp).await?;
+        Ok(())
     }
 
     /// Delete a key from the remote store.
     pub async fn delete(&self, key: &str) -> Result<(), ClientError> {
-        self.client
-            .delete(format!("http://{}/api/v1/{}", self.host, key))
-            .send()
+        let path = Self::key_path(key);
+        let resp = self
+            .send(ClientOp::Delete, |client, base| {
+                client.delete(format!("{base}{path}"))
+            })
             .await
-            .map(|_| Ok(()))
             .map_err(|e| ClientError::DeleteOp {
                 key_name: key.to_string(),
                 source: e,
-            })?
+            })?;
+        check_status(ClientOp::Delete, resp).await?;
+        Ok(())
+    }
+
+    /// Iterate over the key-value pairs within a range, in key order.
+    ///
+    /// Keys are listed a page at a time, following the continuation of each
+    /// page until the range is exhausted. The next page is requested while
+    /// the current one is read, and no more once the stream is dropped. An
+    /// error ends the stream.
+    pub fn scan<'a>(
+        &self,
+        range: impl RangeBounds<&'a str>,
+    ) -> impl Stream<Item = Result<(Bytes, Bytes), ClientError>> {
+        let mut query = vec![
+            ("key_encoding", "base64".to_string()),
+            ("values", "true".to_string()),
+        ];
+        match range.start_bound() {
+            Bound::Included(start) => query.push(("start", KEY_BASE64.encode(start))),
+            // Listing after a key is how pages are continued.
+            Bound::Excluded(start) => query.push(("cursor", KEY_BASE64.encode(start))),
+            Bound::Unbounded => {}
+        }
+        match range.end_bound() {
+            Bound::Included(end) => {
+                query.push(("end", KEY_BASE64.encode(end)));
+                query.push(("end_inclusive", "true".to_string()));
+            }
+            Bound::Excluded(end) => query.push(("end", KEY_BASE64.encode(end))),
+            Bound::Unbounded => {}
+        }
+
+        let client = self.clone();
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let page = client.scan_page(&query).await;
+                let (entries, cursor) = match page {
+                    Ok(page) => page,
+                    Err(e) => {
+                        let _ = sender.send(Err(e)).await;
+                        return;
+                    }
+                };
+                for entry in entries {
+                    if sender.send(Ok(entry)).await.is_err() {
+                        return;
+                    }
+                }
+                let Some(cursor) = cursor else {
+                    return;
+                };
+                query.retain(|(name, _)| *name != "start" && *name != "cursor");
+                query.push(("cursor", cursor));
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+
+    /// Follow the changes to keys starting with a prefix, in the order they
+    /// are written, from when the watch starts.
+    ///
+    /// A watch which is disconnected reconnects, to another host if need be,
+    /// and resumes after the last change it received. Changes which the store
+    /// no longer retains are reported by a [`WatchChange::Lagged`]. An error
+    /// ends the stream, once the [`RetryPolicy`] gives up on reconnecting.
+    pub fn watch(&self, prefix: &str) -> impl Stream<Item = Result<WatchChange, ClientError>> {
+        let client = self.clone();
+        let prefix = KEY_BASE64.encode(prefix);
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::spawn(async move {
+            let mut last_id: Option<String> = None;
+            let mut connections = 0u32;
+            loop {
+                if connections > 0 {
+                    // Reconnecting is paced, in case connections keep ending
+                    // as soon as they are made.
+                    tokio::time::sleep(client.retry.backoff(1, None)).await;
+                }
+                connections += 1;
+                let resp = client
+                    .send(ClientOp::Watch, |client, base| {
+                        let request = client
+                            .get(format!("{base}/api/v1/ops/watch"))
+                            .query(&[("prefix", prefix.as_str()), ("key_encoding", "base64")]);
+                        match &last_id {
+                            Some(id) => request.header(LAST_EVENT_ID_HEADER, id),
+                            None => request,
+                        }
+                    })
+                    .await;
+                let resp = match resp {
+                    Ok(resp) => check_status(ClientOp::Watch, resp).await,
+                    Err(e) => Err(ClientError::WatchOp(e)),
+                };
+                let mut resp = match resp {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        let _ = sender.send(Err(e)).await;
+                        return;
+                    }
+                };
+
+                let mut parser = EventParser::default();
+                loop {
+                    let chunk = tokio::select! {
+                        // Nothing is reading the watch any more.
+                        _ = sender.closed() => return,
+                        chunk = resp.chunk() => chunk,
+                    };
+                    let Ok(Some(chunk)) = chunk else {
+                        break;
+                    };
+                    for (data, id) in parser.feed(&chunk) {
+                        let change = serde_json::from_str(&data)
+                            .map_err(|e| ClientError::InvalidWatch {
+                                reason: e.to_string(),
+                            })
+                            .and_then(|event| {
+                                WatchChange::decode(event, |reason| ClientError::InvalidWatch {
+                                    reason,
+                                })
+                            });
+                        let failed = change.is_err();
+                        if sender.send(change).await.is_err() || failed {
+                            return;
+                        }
+                        if id.is_some() {
+                            last_id = id;
+                        }
+                    }
+                }
+                debug!(?last_id, "Watch disconnected, reconnecting");
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+
+    /// Read the changes to keys starting with a prefix after a sequence
+    /// number, in the order they were written, a page at a time.
+    ///
+    /// Unlike [`ChipmunkClient::watch`], no change is missed: passing the
+    /// `next` of each page as `since` reads every change, from zero for the
+    /// first. Changes the store no longer retains fail with a
+    /// [`ClientError::Rejected`] of `410 Gone`, after which the keys should be
+    /// read again.
+    pub async fn changes(&self, since: u64, prefix: &str) -> Result<ChangePage, ClientError> {
+        let prefix = KEY_BASE64.encode(prefix);
+        let since = since.to_string();
+        let resp = self
+            .send(ClientOp::Changes, |client, base| {
+                client.get(format!("{base}/api/v1/ops/changes")).query(&[
+                    ("since", since.as_str()),
+                    ("prefix", prefix.as_str()),
+                    ("key_encoding", "base64"),
+                ])
+            })
+            .await
+            .map_err(ClientError::ChangesOp)?;
+        let resp = check_status(ClientOp::Changes, resp).await?;
+        let body = resp.bytes().await.map_err(ClientError::ChangesOp)?;
+
+        let invalid = |reason| ClientError::InvalidChanges { reason };
+        let page: ChangesResponse =
+            serde_json::from_slice(&body).map_err(|e| invalid(e.to_string()))?;
+        Ok(ChangePage {
+            changes: page
+                .changes
+                .into_iter()
+                .map(|event| WatchChange::decode(event, invalid))
+                .collect::<Result<_, _>>()?,
+            next: page.next,
+            latest: page.latest,
+        })
+    }
+
+    /// Write the keys of newline-delimited JSON, as written by
+    /// [`ChipmunkClient::export`], returning the number of keys imported.
+    ///
+    /// Each line is a [`crate::server::BulkEntry`]. Lines are sent in batches
+    /// as they are read, `progress` being called after each with the lines
+    /// read and keys imported so far. Imports are not atomic: a line which is
+    /// rejected leaves the batches before it written, the error giving how
+    /// many keys were, and the last progress how many lines to skip to carry
+    /// on from where it stopped.
+    pub async fn import<R>(
+        &self,
+        reader: R,
+        progress: impl FnMut(ImportProgress),
+    ) -> Result<u64, ClientError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        self.import_into("", reader, progress).await
+    }
+
+    /// Replace every key starting with a prefix with those of an
+    /// [`ChipmunkClient::export`] of it, such as to restore a single
+    /// namespace, returning the number of keys imported.
+    ///
+    /// The keys starting with the prefix are deleted first, so those written
+    /// since the export are not kept, while keys outside it are left alone: a
+    /// line holding one is rejected as for [`ChipmunkClient::import`]. A
+    /// restore is not atomic, so one which fails part way should be run
+    /// again from the start.
+    pub async fn restore<R>(
+        &self,
+        prefix: &str,
+        reader: R,
+        progress: impl FnMut(ImportProgress),
+    ) -> Result<u64, ClientError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let query = [
+            ("prefix", KEY_BASE64.encode(prefix)),
+            ("key_encoding", "base64".to_string()),
+        ];
+        let resp = self
+            .send(ClientOp::Restore, |client, base| {
+                client
+                    .delete(format!("{base}/api/v1/ops/keys"))
+                    .query(&query)
+            })
+            .await
+            .map_err(ClientError::RestoreOp)?;
+        check_status(ClientOp::Restore, resp).await?;
+        self.import_into(prefix, reader, progress).await
+    }
+
+    /// Import the lines of the reader, each of whose keys must start with the
+    /// prefix.
+    async fn import_into<R>(
+        &self,
+        prefix: &str,
+        mut reader: R,
+        mut progress: impl FnMut(ImportProgress),
+    ) -> Result<u64, ClientError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let query = [
+            ("prefix", KEY_BASE64.encode(prefix)),
+            ("key_encoding", "base64".to_string()),
+        ];
+        let mut imported = 0;
+        let mut read = 0;
+        let mut finished = false;
+        while !finished {
+            let mut batch = Vec::new();
+            let mut lines = 0;
+            while lines < IMPORT_BATCH_LINES && batch.len() < IMPORT_BATCH_BYTES {
+                let read = reader
+                    .read_until(b'\n', &mut batch)
+                    .await
+                    .map_err(ClientError::BulkIo)?;
+                if read == 0 {
+                    finished = true;
+                    break;
+                }
+                if !batch.ends_with(b"\n") {
+                    batch.push(b'\n');
+                }
+                lines += 1;
+            }
+            if lines == 0 {
+                break;
+            }
+            let lines = lines as u64;
+
+            let batch = Bytes::from(batch);
+            let resp = self
+                .send(ClientOp::Import, |client, base| {
+                    client
+                        .post(format!("{base}/api/v1/ops/import"))
+                        .query(&query)
+                        .header(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+                        .body(batch.clone())
+                })
+                .await
+                .map_err(ClientError::ImportOp)?;
+            let resp = match check_status(ClientOp::Import, resp).await {
+                Ok(resp) => resp,
+                Err(ClientError::Rejected { message, .. }) => {
+                    return Err(ClientError::ImportRejected {
+                        imported,
+                        reason: message,
+                    })
+                }
+                Err(e) => return Err(e),
+            };
+            let body = resp.bytes().await.map_err(ClientError::ImportOp)?;
+            let response: ImportResponse =
+                serde_json::from_slice(&body).map_err(|e| ClientError::InvalidImport {
+                    reason: e.to_string(),
+                })?;
+            imported += response.imported;
+            read += lines;
+            progress(ImportProgress {
+                lines: read,
+                imported,
+            });
+        }
+        debug!(imported, "Imported keys");
+        Ok(imported)
+    }
+
+    /// Write every key starting with a prefix, in key order, as
+    /// newline-delimited JSON which [`ChipmunkClient::import`] reads back,
+    /// returning the number of keys exported.
+    ///
+    /// The keys are those of the store when the export starts, streamed to
+    /// the writer as they arrive, `progress` being called with the number of
+    /// keys written so far after each part of the response. Time-to-live is
+    /// not exported.
+    pub async fn export<W>(
+        &self,
+        prefix: &str,
+        writer: W,
+        progress: impl FnMut(u64),
+    ) -> Result<u64, ClientError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.export_from(prefix, None, writer, progress).await
+    }
+
+    /// Carry on an [`ChipmunkClient::export`] which stopped after writing a
+    /// key, writing the keys starting with the prefix which come after it.
+    pub async fn resume_export<W>(
+        &self,
+        prefix: &str,
+        after: &[u8],
+        writer: W,
+        progress: impl FnMut(u64),
+    ) -> Result<u64, ClientError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.export_from(prefix, Some(after), writer, progress)
+            .await
+    }
+
+    async fn export_from<W>(
+        &self,
+        prefix: &str,
+        after: Option<&[u8]>,
+        mut writer: W,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64, ClientError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut query = vec![
+            ("prefix", KEY_BASE64.encode(prefix)),
+            ("key_encoding", "base64".to_string()),
+        ];
+        if let Some(after) = after {
+            query.push(("after", KEY_BASE64.encode(after)));
+        }
+        let resp = self
+            .send(ClientOp::Export, |client, base| {
+                client
+                    .get(format!("{base}/api/v1/ops/export"))
+                    .query(&query)
+            })
+            .await
+            .map_err(ClientError::ExportOp)?;
+        let mut resp = check_status(ClientOp::Export, resp).await?;
+
+        let mut exported = 0;
+        while let Some(chunk) = resp.chunk().await.map_err(ClientError::ExportOp)? {
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(ClientError::BulkIo)?;
+            // Every line is a key, ending with a newline.
+            exported += chunk.iter().filter(|&&b| b == b'\n').count() as u64;
+            progress(exported);
+        }
+        writer.flush().await.map_err(ClientError::BulkIo)?;
+        debug!(exported, "Exported keys");
+        Ok(exported)
+    }
+
+    /// List a single page of a [`ChipmunkClient::scan`], returning its entries
+    /// and the cursor of the next page, if any.
+    async fn scan_page(
+        &self,
+        query: &[(&str, String)],
+    ) -> Result<(Vec<(Bytes, Bytes)>, Option<String>), ClientError> {
+        let resp = self
+            .send(ClientOp::Scan, |client, base| {
+                client.get(format!("{base}/api/v1/ops/keys")).query(query)
+            })
+            .await
+            .map_err(ClientError::ScanOp)?;
+        let resp = check_status(ClientOp::Scan, resp).await?;
+        let cursor = resp
+            .headers()
+            .get(CONTINUATION_HEADER)
+            .map(|cursor| {
+                cursor
+                    .to_str()
+                    .map(str::to_string)
+                    .map_err(|e| ClientError::InvalidScan {
+                        reason: e.to_string(),
+                    })
+            })
+            .transpose()?;
+        let body = resp.bytes().await.map_err(ClientError::ScanOp)?;
+
+        let invalid = |reason: String| ClientError::InvalidScan { reason };
+        let listed: Vec<ListedKey> =
+            serde_json::from_slice(&body).map_err(|e| invalid(e.to_string()))?;
+        let mut entries = Vec::with_capacity(listed.len());
+        for ListedKey { key, value } in listed {
+            let key = KEY_BASE64.decode(key).map_err(|e| invalid(e.to_string()))?;
+            let value = KEY_BASE64
+                .decode(value.unwrap_or_default())
+                .map_err(|e| invalid(e.to_string()))?;
+            entries.push((key.into(), value.into()));
+        }
+        Ok((entries, cursor))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use axum::extract::Path;
+    use axum::http::HeaderMap;
+    use axum::routing::{get, put};
+    use axum::Router;
+    use tempdir::TempDir;
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::config::{
+        ChipmunkConfig, CompactionConfig, HttpConfig, MemtableConfig, ReplicationConfig,
+        SstableConfig, TlsConfig, WalConfig, WriteStallConfig,
+    };
+    use crate::server::{new_app, Chipmunk, ErrorCode, DEFAULT_LIST_LIMIT};
+    use crate::tls::serve_tls;
+
+    #[test]
+    fn builder_hosts() {
+        let base_url =
+            |builder: ChipmunkClientBuilder| builder.build().unwrap().nodes[0].base_url.clone();
+        assert_eq!(
+            base_url(ChipmunkClient::builder("localhost:5000")),
+            "http://localhost:5000"
+        );
+        assert_eq!(
+            base_url(ChipmunkClient::builder("127.0.0.1:5000").with_scheme(Scheme::Https)),
+            "https://127.0.0.1:5000"
+        );
+        assert_eq!(
+            base_url(ChipmunkClient::builder("chipmunk.internal")),
+            "http://chipmunk.internal"
+        );
+        for host in ["", "localhost:port", "localhost:5000/api", "user@localhost"] {
+            assert!(
+                matches!(
+                    ChipmunkClient::builder(host).build(),
+                    Err(ClientError::InvalidHost { .. })
+                ),
+                "{host} is not a host"
+            );
+        }
+        let missing = ChipmunkClient::builder("localhost")
+            .with_root_certificate("missing.pem")
+            .build();
+        assert!(matches!(
+            missing,
+            Err(ClientError::InvalidCertificate { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn builder_tls_and_auth() {
+        // Values are the authorization each request was sent with, and the
+        // key `slow` is never answered in time.
+        let app = Router::new().route(
+            "/api/v1/:key",
+            get(|Path(key): Path<String>, headers: HeaderMap| async move {
+                if key == "slow" {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+                headers[header::AUTHORIZATION].to_str().unwrap().to_string()
+            }),
+        );
+        let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let config = TlsConfig::new(testdata.join("cert1.pem"), testdata.join("key1.pem"));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_tls(
+            listener,
+            app,
+            config,
+            HttpConfig::default(),
+            std::future::pending(),
+        ));
+
+        let client = ChipmunkClient::builder(format!("localhost:{port}"))
+            .with_scheme(Scheme::Https)
+            .with_root_certificate(testdata.join("cert1.pem"))
+            .with_bearer_token("secret")
+            .with_request_timeout(Duration::from_millis(200))
+            .with_retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+        assert_eq!(client.get("a").await.unwrap().unwrap(), "Bearer secret");
+        let slow = client.get("slow").await.unwrap_err();
+        assert!(
+            matches!(slow, ClientError::GetOp { source, .. } if source.is_timeout()),
+            "Requests time out"
+        );
+
+        let untrusted = ChipmunkClient::builder(format!("localhost:{port}"))
+            .with_scheme(Scheme::Https)
+            .with_retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+        assert!(
+            untrusted.get("a").await.is_err(),
+            "The server is only trusted through its root certificate"
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_pages() {
+        let dir = TempDir::new("scan_pages").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024 * 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024 * 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        let count = DEFAULT_LIST_LIMIT * 2 + 500;
+        for i in 0..count {
+            store
+                .store
+                .insert(format!("key{i:05}").into_bytes(), vec![0xff, i as u8])
+                .unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string()).build().unwrap();
+
+        let scanned: Vec<_> = client.scan(..).map(|entry| entry.unwrap()).collect().await;
+        assert_eq!(scanned.len(), count, "Every page is followed");
+        assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(
+            scanned[1234],
+            (
+                Bytes::from("key01234"),
+                Bytes::from(vec![0xff, 1234u16 as u8])
+            )
+        );
+
+        let count = |range: (Bound<&'static str>, Bound<&'static str>)| {
+            let client = client.clone();
+            async move { client.scan(range).collect::<Vec<_>>().await.len() }
+        };
+        use Bound::*;
+        assert_eq!(
+            count((Included("key00999"), Excluded("key02001"))).await,
+            1002
+        );
+        assert_eq!(
+            count((Excluded("key00999"), Included("key02001"))).await,
+            1002
+        );
+        assert_eq!(count((Included("key02400"), Unbounded)).await, 100);
+        assert_eq!(count((Unbounded, Excluded("key00000"))).await, 0);
+
+        let mut stream = Box::pin(client.scan("key00010".."key00012"));
+        assert_eq!(stream.next().await.unwrap().unwrap().0, "key00010");
+        assert_eq!(stream.next().await.unwrap().unwrap().0, "key00011");
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(500))
+            .with_jitter(false);
+        let backoff = |attempt| policy.backoff(attempt, None);
+        assert_eq!(backoff(1), Duration::from_millis(100));
+        assert_eq!(backoff(2), Duration::from_millis(200));
+        assert_eq!(backoff(3), Duration::from_millis(400));
+        assert_eq!(
+            backoff(4),
+            Duration::from_millis(500),
+            "Capped at the maximum"
+        );
+        assert_eq!(backoff(u32::MAX), Duration::from_millis(500));
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_millis(300))),
+            Duration::from_millis(300),
+            "Retry-After is waited for"
+        );
+
+        let jittered = policy.with_jitter(true);
+        for attempt in 1..5 {
+            assert!(jittered.backoff(attempt, None) <= Duration::from_millis(500));
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        // Writes are refused as stalled twice, then accepted.
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&attempts);
+        let app = Router::new().route(
+            "/api/v1/:key",
+            put(move || async move {
+                match counted.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => StatusCode::TOO_MANY_REQUESTS,
+                    _ => StatusCode::NO_CONTENT,
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let fast = RetryPolicy::default().with_backoff(Duration::ZERO, Duration::from_millis(10));
+
+        let client = |retry| {
+            ChipmunkClient::builder(addr.to_string())
+                .with_retry(retry)
+                .build()
+                .unwrap()
+        };
+        let once = client(RetryPolicy::none());
+        assert!(once.insert("a", "1").await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        client(fast.clone()).insert("a", "1").await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3, "Retried until accepted");
+
+        let refused = client(fast.clone().with_retry_writes_on(RetryOn {
+            write_stall: false,
+            ..RetryOn::unapplied()
+        }));
+        attempts.store(0, Ordering::SeqCst);
+        assert!(refused.insert("a", "1").await.is_err());
+        assert_eq!(
+            attempts.load(Ordering::SeqCst),
+            1,
+            "Only chosen failures are retried"
+        );
+    }
+
+    #[tokio::test]
+    async fn unavailable_writes_are_not_retried() {
+        // Writes are answered as unavailable once, then accepted.
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&attempts);
+        let app = Router::new().route(
+            "/api/v1/:key",
+            put(move || async move {
+                match counted.fetch_add(1, Ordering::SeqCst) {
+                    0 => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::NO_CONTENT,
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let fast = RetryPolicy::default().with_backoff(Duration::ZERO, Duration::from_millis(10));
+        let client = |retry| {
+            ChipmunkClient::builder(addr.to_string())
+                .with_retry(retry)
+                .build()
+                .unwrap()
+        };
+
+        assert!(
+            client(fast.clone()).insert("a", "1").await.is_err(),
+            "The write may have been applied"
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        attempts.store(0, Ordering::SeqCst);
+        client(fast.with_retry_writes_on(RetryOn::default()))
+            .insert("a", "1")
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2, "Retried once opted in");
+    }
+
+    #[tokio::test]
+    async fn error_statuses() {
+        // Every request fails as its key describes.
+        let app = Router::new().route(
+            "/api/v1/:key",
+            get(|Path(key): Path<String>| async move {
+                let code = match key.as_str() {
+                    "missing" => ErrorCode::NotFound,
+                    "conflict" => ErrorCode::Conflict,
+                    "stale" => ErrorCode::PreconditionFailed,
+                    "stalled" => ErrorCode::WriteStall,
+                    "broken" => ErrorCode::Internal,
+                    _ => ErrorCode::InvalidRequest,
+                };
+                ErrorResponse::new(code, format!("{key} failed"))
+            })
+            .put(|| async { (StatusCode::BAD_GATEWAY, "no upstream") })
+            .delete(|| async { ErrorResponse::new(ErrorCode::NotFound, "Key not found") }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string())
+            .with_retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+
+        assert_eq!(client.get("missing").await.unwrap(), None);
+        assert!(matches!(
+            client.delete("missing").await,
+            Err(ClientError::NotFound { op: ClientOp::Delete, message }) if message == "Key not found"
+        ));
+        assert!(matches!(
+            client.get("conflict").await,
+            Err(ClientError::Conflict { op: ClientOp::Get, message }) if message == "conflict failed"
+        ));
+        assert!(matches!(
+            client.get("stale").await,
+            Err(ClientError::PreconditionFailed { .. })
+        ));
+        assert!(matches!(
+            client.get("stalled").await,
+            Err(ClientError::WriteStalled {
+                retry_after: Some(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.get("broken").await,
+            Err(ClientError::Server {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.get("invalid").await,
+            Err(ClientError::Rejected {
+                status: StatusCode::BAD_REQUEST,
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.insert("a", "1").await,
+            Err(ClientError::Server { op: ClientOp::Insert, status: StatusCode::BAD_GATEWAY, message })
+                if message == "no upstream"
+        ));
+    }
+
+    #[tokio::test]
+    async fn observer() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(ClientOp, Option<StatusCode>)>>);
+
+        impl RequestObserver for Recorder {
+            fn on_request(&self, op: ClientOp, _: Duration, status: Option<StatusCode>) {
+                self.0.lock().push((op, status));
+            }
+        }
+
+        let dir = TempDir::new("observer").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        store.restore().await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+        let primary = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        let client = ChipmunkClient::builder(primary.to_string())
+            .with_host(replica.to_string())
+            .with_retry(RetryPolicy::default().with_max_attempts(2))
+            .with_observer(recorder.clone())
+            .build()
+            .unwrap();
+        client.insert("a", "1").await.unwrap();
+        client.get("missing").await.unwrap();
+        client.delete("a").await.unwrap();
+        client.check_health().await;
+        assert_eq!(
+            *recorder.0.lock(),
+            vec![
+                (ClientOp::Insert, None),
+                (ClientOp::Insert, Some(StatusCode::NO_CONTENT)),
+                (ClientOp::Get, Some(StatusCode::NOT_FOUND)),
+                (ClientOp::Delete, Some(StatusCode::NO_CONTENT)),
+                (ClientOp::Health, None),
+                (ClientOp::Health, Some(StatusCode::OK)),
+            ],
+            "Every attempt is observed"
+        );
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let dir = TempDir::new("failover").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        store.restore().await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+        // Nothing listens on the primary once its listener is dropped.
+        let primary = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let client = ChipmunkClient::builder(primary.to_string())
+            .with_host(replica.to_string())
+            .with_retry(RetryPolicy::default().with_max_attempts(2))
+            .build()
+            .unwrap();
+        client.insert("a", "1").await.unwrap();
+        assert_eq!(client.get("a").await.unwrap().unwrap(), "1");
+        assert!(!client.nodes[0].is_healthy(DEFAULT_HEALTH_CHECK_INTERVAL));
+        assert_eq!(
+            client.check_health().await,
+            vec![format!("http://{replica}")]
+        );
+
+        let single = ChipmunkClient::builder(primary.to_string())
+            .with_retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+        assert!(single.get("a").await.is_err(), "No host to fail over to");
+    }
+
+    #[tokio::test]
+    async fn round_robin_reads() {
+        // Reads are answered with the name of the host, which writes record.
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut hosts = Vec::new();
+        for name in ["primary", "replica"] {
+            let recorded = Arc::clone(&written);
+            let app = Router::new().route(
+                "/api/v1/:key",
+                get(move || async move { name }).put(move || async move {
+                    recorded.lock().push(name);
+                    StatusCode::NO_CONTENT
+                }),
+            );
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            hosts.push(listener.local_addr().unwrap().to_string());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        }
+        let client = |read_preference| {
+            ChipmunkClient::builder(&hosts[0])
+                .with_host(&hosts[1])
+                .with_read_preference(read_preference)
+                .build()
+                .unwrap()
+        };
+
+        let round_robin = client(ReadPreference::RoundRobin);
+        let mut read = Vec::new();
+        for _ in 0..4 {
+            read.push(round_robin.get("a").await.unwrap().unwrap());
+        }
+        assert_eq!(read, ["primary", "replica", "primary", "replica"]);
+        round_robin.insert("a", "1").await.unwrap();
+        round_robin.insert("b", "2").await.unwrap();
+        assert_eq!(
+            *written.lock(),
+            ["primary", "primary"],
+            "Writes go to the primary"
+        );
+        let primary = client(ReadPreference::Primary);
+        assert_eq!(primary.get("a").await.unwrap().unwrap(), "primary");
+
+        let followers = client(ReadPreference::Followers);
+        for _ in 0..2 {
+            assert_eq!(followers.get("a").await.unwrap().unwrap(), "replica");
+        }
+        followers.insert("c", "3").await.unwrap();
+        assert_eq!(written.lock().last(), Some(&"primary"));
+    }
+
+    #[test]
+    fn parse_events() {
+        let mut parser = EventParser::default();
+        assert!(
+            parser.feed(b"event: put\nid: 1\ndata: o").is_empty(),
+            "Events are only complete once followed by a blank line"
+        );
+        assert_eq!(
+            parser.feed(b"ne\r\n\r\n:\n\ndata:two\ndata: lines\n\n"),
+            vec![
+                ("one".to_string(), Some("1".to_string())),
+                ("two\nlines".to_string(), None)
+            ],
+            "Comments are not events"
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_reconnects() {
+        // The first connection ends after two events, and the second resumes
+        // after the last of them.
+        let event = |id: u64, key: &str| {
+            let key = KEY_BASE64.encode(key);
+            format!(
+                "event: delete\nid: {id}\ndata: {{\"type\":\"delete\",\"key\":\"{key}\",\"sequence\":{id}}}\n\n"
+            )
+        };
+        let resumed_from = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&resumed_from);
+        let app = Router::new().route(
+            "/api/v1/ops/watch",
+            get(move |headers: HeaderMap| async move {
+                let last_id = headers
+                    .get(LAST_EVENT_ID_HEADER)
+                    .map(|id| id.to_str().unwrap().to_string());
+                recorded.lock().push(last_id.clone());
+                let body = match last_id {
+                    None => event(1, "a") + &event(2, "b"),
+                    Some(_) => event(3, "c"),
+                };
+                ([(header::CONTENT_TYPE, "text/event-stream")], body)
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string()).build().unwrap();
+
+        let watched: Vec<WatchChange> = client
+            .watch("")
+            .take(3)
+            .map(|change| change.unwrap())
+            .collect()
+            .await;
+        let delete = |key: &'static str, sequence| WatchChange::Delete {
+            key: Bytes::from(key),
+            sequence,
+        };
+        assert_eq!(
+            watched,
+            vec![delete("a", 1), delete("b", 2), delete("c", 3)]
+        );
+        assert_eq!(resumed_from.lock()[..2], [None, Some("2".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn watch_store() {
+        let dir = TempDir::new("watch_store").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        store.restore().await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string()).build().unwrap();
+
+        let mut watch = Box::pin(client.watch("app/"));
+        // The watch has subscribed once it sees a change written after it.
+        loop {
+            client.insert("app/ready", "").await.unwrap();
+            let next = tokio::time::timeout(Duration::from_millis(100), watch.next()).await;
+            if let Ok(Some(change)) = next {
+                assert!(matches!(change.unwrap(), WatchChange::Put { .. }));
+                break;
+            }
+        }
+        client.insert("other", "1").await.unwrap();
+        client.insert("app/a", vec![0xff, 0x00]).await.unwrap();
+        let Some(Ok(WatchChange::Put { key, value, .. })) = watch.next().await else {
+            panic!("Expected a put");
+        };
+        assert_eq!(
+            (key, value),
+            (Bytes::from("app/a"), Bytes::from(vec![0xff, 0x00]))
+        );
+
+        // The changefeed reads the same writes, from the first.
+        let page = client.changes(0, "app/").await.unwrap();
+        assert_eq!(page.next, page.latest);
+        assert!(matches!(
+            page.changes.first(),
+            Some(WatchChange::Put { sequence: 1, .. })
+        ));
+        assert!(matches!(
+            page.changes.last(),
+            Some(WatchChange::Put { key, .. }) if key == "app/a"
+        ));
+        assert!(page
+            .changes
+            .iter()
+            .all(|change| !matches!(change, WatchChange::Put { key, .. } if key == "other")));
+        let page = client.changes(page.next, "app/").await.unwrap();
+        assert!(page.changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn import_export() {
+        let serve = |dir: &TempDir| {
+            let conf = ChipmunkConfig {
+                wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+                memtable: MemtableConfig::new(0, 1024).with_max_value_size(16),
+                sstable: SstableConfig::default(),
+                compaction: CompactionConfig::default(),
+                write_stall: WriteStallConfig::default(),
+                tls: None,
+                cors: None,
+                http: HttpConfig::default(),
+                replica_of: None,
+                replication: ReplicationConfig::default(),
+                shards: 1,
+            };
+            async move {
+                let store = Chipmunk::new(conf).unwrap();
+                store.restore().await.unwrap();
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+                ChipmunkClient::builder(addr.to_string()).build().unwrap()
+            }
+        };
+        let source_dir = TempDir::new("export_source").unwrap();
+        let target_dir = TempDir::new("import_target").unwrap();
+        let source = serve(&source_dir).await;
+        let target = serve(&target_dir).await;
+
+        for i in 0..1500 {
+            source
+                .insert(&format!("user/{i:04}"), i.to_string())
+                .await
+                .unwrap();
+        }
+        source.insert("other", "value").await.unwrap();
+
+        let mut exported = Vec::new();
+        let mut progress = Vec::new();
+        let count = source
+            .export("user/", &mut exported, |n| progress.push(n))
+            .await
+            .unwrap();
+        assert_eq!(count, 1500);
+        assert_eq!(progress.last(), Some(&1500));
+        assert!(progress.windows(2).all(|n| n[0] <= n[1]));
+
+        let mut progress = Vec::new();
+        let count = target
+            .import(&exported[..], |n| progress.push(n.imported))
+            .await
+            .unwrap();
+        assert_eq!(count, 1500);
+        assert_eq!(progress, vec![1000, 1500], "Lines are sent in batches");
+        let checked = check_import(&exported[..]).await.unwrap();
+        assert_eq!(checked.lines, 1500);
+        assert_eq!(checked.imported, 1500);
+
+        // An export which stopped part way carries on after its last key
+        let last = exported[..exported.len() - 1]
+            .rsplit(|&b| b == b'\n')
+            .next()
+            .unwrap();
+        let (last_key, _) = BulkEntry::parse(last).unwrap().unwrap().decode().unwrap();
+        assert_eq!(last_key, b"user/1499");
+        let mut partial = Vec::new();
+        source.export("user/0", &mut partial, |_| {}).await.unwrap();
+        let count = source
+            .resume_export("user/", b"user/0999", &mut partial, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(count, 500);
+        assert_eq!(partial, exported);
+        assert_eq!(target.get("user/0042").await.unwrap().unwrap(), "42");
+        assert_eq!(target.get("other").await.unwrap(), None);
+
+        let invalid = format!(
+            "{}\nnot json\n",
+            String::from_utf8_lossy(exported.split(|&b| b == b'\n').next().unwrap())
+        );
+        let rejected = target.import(invalid.as_bytes(), |_| {}).await;
+        assert!(matches!(
+            rejected,
+            Err(ClientError::ImportRejected { imported: 0, reason }) if reason.starts_with("Line 2:")
+        ));
+        assert!(matches!(
+            check_import(invalid.as_bytes()).await,
+            Err(ClientError::InvalidBulkEntry { line: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn restore_namespace() {
+        let dir = TempDir::new("restore_namespace").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 2,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        store.restore().await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string()).build().unwrap();
+
+        for tenant in ["a", "b"] {
+            for i in 0..3 {
+                client
+                    .insert(&format!("tenant-{tenant}/{i}"), tenant)
+                    .await
+                    .unwrap();
+            }
+        }
+        let mut backup = Vec::new();
+        client
+            .export("tenant-a/", &mut backup, |_| {})
+            .await
+            .unwrap();
+
+        client.insert("tenant-a/0", "changed").await.unwrap();
+        client.insert("tenant-a/new", "new").await.unwrap();
+        client.delete("tenant-a/1").await.unwrap();
+        client.insert("tenant-b/0", "changed").await.unwrap();
+        client.insert("tenant-b/new", "new").await.unwrap();
+
+        let restored = client
+            .restore("tenant-a/", &backup[..], |_| {})
+            .await
+            .unwrap();
+        assert_eq!(restored, 3);
+        for i in 0..3 {
+            assert_eq!(
+                client.get(&format!("tenant-a/{i}")).await.unwrap().unwrap(),
+                "a"
+            );
+        }
+        assert_eq!(client.get("tenant-a/new").await.unwrap(), None);
+
+        // The other namespace keeps the writes made since the backup
+        assert_eq!(client.get("tenant-b/0").await.unwrap().unwrap(), "changed");
+        assert_eq!(client.get("tenant-b/1").await.unwrap().unwrap(), "b");
+        assert_eq!(client.get("tenant-b/new").await.unwrap().unwrap(), "new");
+
+        // A backup cannot be restored into another namespace
+        let rejected = client.restore("tenant-c/", &backup[..], |_| {}).await;
+        assert!(matches!(
+            rejected,
+            Err(ClientError::ImportRejected { imported: 0, reason }) if reason.starts_with("Line 1:")
+        ));
+        assert_eq!(client.get("tenant-c/0").await.unwrap(), None);
+        assert_eq!(client.get("tenant-a/0").await.unwrap().unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn json_values() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct User {
+            name: String,
+            admin: bool,
+        }
+
+        let dir = TempDir::new("json_values").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        store.restore().await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(store)).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string()).build().unwrap();
+
+        let user = User {
+            name: "chip".to_string(),
+            admin: true,
+        };
+        client.put_json("user", &user).await.unwrap();
+        assert_eq!(client.get_json::<User>("user").await.unwrap(), Some(user));
+        assert_eq!(
+            client.get("user").await.unwrap().unwrap(),
+            r#"{"name":"chip","admin":true}"#
+        );
+        assert_eq!(client.get_json::<User>("missing").await.unwrap(), None);
+
+        client.insert("raw", "not json").await.unwrap();
+        assert!(matches!(
+            client.get_json::<User>("raw").await,
+            Err(ClientError::JsonOp { key_name, .. }) if key_name == "raw"
+        ));
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let dir = TempDir::new("status").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let store = Chipmunk::new(conf).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = store.clone();
+        tokio::spawn(async move { axum::serve(listener, new_app(served)).await.unwrap() });
+        let client = ChipmunkClient::builder(addr.to_string()).build().unwrap();
+
+        let status = client.status().await.unwrap();
+        assert!(!status.ready);
+        assert_eq!(status.not_ready_reason.as_deref(), Some("Restoring"));
+        assert_eq!(status.host, format!("http://{addr}"));
+        assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+
+        store.restore().await.unwrap();
+        client.insert("a", "1").await.unwrap();
+        let status = client.status().await.unwrap();
+        assert!(status.ready && status.not_ready_reason.is_none());
+        assert_eq!(status.statistics.keys_written, 1);
+        assert_eq!(status.tree.approximate_keys, 1);
+
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = ChipmunkClient::builder(unreachable.to_string())
+            .build()
+            .unwrap();
+        assert!(matches!(
+            client.status().await,
+            Err(ClientError::StatusOp(_))
+        ));
     }
 }
@@ -0,0 +1,287 @@
+//! Compaction of L1, the SSTables flushed from memtables, into L2.
+//!
+//! Input files are merged by streaming over their entries in key order, see
+//! [`MergingIter`], so only a single data block of each input is held in
+//! memory at once regardless of how much data is being compacted.
+//!
+//! Applications can inspect every live entry as it is compacted through a
+//! [`CompactionFilter`], to remove or rewrite data in the background rather
+//! than doing so through the write path.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{comparator::Comparator, memtable::Entry, ChipmunkError};
+
+/// Work performed by compaction, either by a single cycle or summed over
+/// every cycle since the engine started.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct CompactionStats {
+    /// Number of compaction cycles which have completed.
+    pub compactions: u64,
+    /// Size, in bytes, of the input files.
+    pub bytes_read: u64,
+    /// Size, in bytes, of the output files.
+    pub bytes_written: u64,
+    pub input_files: u64,
+    pub output_files: u64,
+    /// Tombstones and expired entries which were discarded.
+    pub dropped_tombstones: u64,
+    /// Entries which a [`CompactionFilter`] removed or changed.
+    pub filtered_entries: u64,
+    /// Time spent compacting.
+    #[schema(value_type = Object)]
+    pub duration: Duration,
+    /// Time, within the duration, spent waiting to keep within the
+    /// configured rate limit, see [`CompactionConfig::max_bytes_per_sec`](crate::config::CompactionConfig::max_bytes_per_sec).
+    #[schema(value_type = Object)]
+    pub throttled: Duration,
+}
+
+impl CompactionStats {
+    /// Add the work of another cycle to these totals.
+    pub fn merge(&mut self, other: &CompactionStats) {
+        self.compactions += other.compactions;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.input_files += other.input_files;
+        self.output_files += other.output_files;
+        self.dropped_tombstones += other.dropped_tombstones;
+        self.filtered_entries += other.filtered_entries;
+        self.duration += other.duration;
+        self.throttled += other.throttled;
+    }
+}
+
+/// Shortest wait taken to slow compaction down, so that a slight lead over
+/// the rate limit does not cost a sleep for every entry.
+const MIN_THROTTLE: Duration = Duration::from_millis(10);
+
+/// Limits the rate at which a compaction cycle writes, by sleeping whenever
+/// it gets ahead of the given number of bytes per second.
+pub(crate) struct Throttle {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    /// Start limiting writes to the given rate, or not at all when `None`.
+    pub(crate) fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.filter(|rate| *rate > 0),
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account for the given number of bytes written, sleeping until they
+    /// are within the rate limit. Returns the time slept.
+    pub(crate) fn consume(&mut self, bytes: u64) -> Duration {
+        let Some(rate) = self.bytes_per_sec else {
+            return Duration::ZERO;
+        };
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        let ahead = due.saturating_sub(self.start.elapsed());
+        if ahead < MIN_THROTTLE {
+            return Duration::ZERO;
+        }
+        std::thread::sleep(ahead);
+        ahead
+    }
+}
+
+/// What a [`CompactionFilter`] decided should happen to an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// Carry the entry over unchanged.
+    Keep,
+    /// Remove the entry, as if the key had been deleted.
+    Remove,
+    /// Carry the entry over with a new value, its expiry is unchanged.
+    ChangeValue(Vec<u8>),
+}
+
+/// A hook which decides the fate of every live entry as it is compacted.
+///
+/// Values are given in the form they were written, after any
+/// [`ValueTransform`](crate::transform::ValueTransform) has been reversed,
+/// and changed values are transformed again before being persisted.
+/// Tombstones and expired entries are never passed to the filter.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8]) -> CompactionDecision;
+}
+
+/// The next entry of one of the sources of a [`MergingIter`].
+struct Head {
+    key: Bytes,
+    entry: Entry,
+    source: usize,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Ord for Head {
+    /// Heads are popped from a max-heap, so the smallest key is the greatest
+    /// and, for equal keys, the newest source is.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator
+            .compare(&other.key, &self.key)
+            .then(self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.source == other.source
+    }
+}
+
+impl Eq for Head {}
+
+/// A k-way merge of several sources of entries, each sorted by key, into a
+/// single sorted stream.
+///
+/// Sources are given oldest first, each sorted by the given [`Comparator`].
+/// Where several hold the same key, only the entry from the newest source is
+/// yielded as it shadows the others.
+pub(crate) struct MergingIter<I> {
+    sources: Vec<I>,
+    heap: BinaryHeap<Head>,
+    comparator: Arc<dyn Comparator>,
+    done: bool,
+}
+
+impl<I> MergingIter<I>
+where
+    I: Iterator<Item = Result<(Bytes, Entry), ChipmunkError>>,
+{
+    pub fn new(sources: Vec<I>, comparator: Arc<dyn Comparator>) -> Result<Self, ChipmunkError> {
+        let mut merge = Self {
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            comparator,
+            done: false,
+        };
+        for source in 0..merge.sources.len() {
+            merge.advance(source)?;
+        }
+        Ok(merge)
+    }
+
+    /// Pull the next entry of a source onto the heap.
+    fn advance(&mut self, source: usize) -> Result<(), ChipmunkError> {
+        if let Some((key, entry)) = self.sources[source].next().transpose()? {
+            self.heap.push(Head {
+                key,
+                entry,
+                source,
+                comparator: Arc::clone(&self.comparator),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<I> Iterator for MergingIter<I>
+where
+    I: Iterator<Item = Result<(Bytes, Entry), ChipmunkError>>,
+{
+    type Item = Result<(Bytes, Entry), ChipmunkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let head = self.heap.pop()?;
+        let mut advanced = self.advance(head.source);
+        // Drop the shadowed entries of older sources.
+        while advanced.is_ok() && self.heap.peek().is_some_and(|next| next.key == head.key) {
+            let shadowed = self.heap.pop().expect("Peeked entry exists");
+            advanced = self.advance(shadowed.source);
+        }
+        match advanced {
+            Ok(()) => Some(Ok((head.key, head.entry))),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::comparator;
+
+    fn source(
+        entries: &[(&'static str, Option<&'static str>)],
+    ) -> std::vec::IntoIter<Result<(Bytes, Entry), ChipmunkError>> {
+        entries
+            .iter()
+            .map(|(key, value)| {
+                Ok((
+                    Bytes::from_static(key.as_bytes()),
+                    Entry {
+                        value: value.map(|v| Bytes::from_static(v.as_bytes())),
+                        expires_at: None,
+                        separated: false,
+                        stamp: None,
+                    },
+                ))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn throttle() {
+        let mut unlimited = Throttle::new(None);
+        assert_eq!(unlimited.consume(u64::MAX / 2), Duration::ZERO);
+
+        let mut throttle = Throttle::new(Some(1000));
+        assert_eq!(throttle.consume(5), Duration::ZERO, "Within the limit");
+        let start = Instant::now();
+        let slept = throttle.consume(100);
+        assert!(slept > Duration::from_millis(50));
+        assert!(start.elapsed() >= slept);
+    }
+
+    #[test]
+    fn merge() {
+        let oldest = source(&[("a", Some("1")), ("c", Some("1")), ("e", Some("1"))]);
+        let middle = source(&[("b", Some("2")), ("c", None)]);
+        let newest = source(&[("a", Some("3")), ("d", Some("3")), ("e", Some("3"))]);
+
+        let merged: Vec<_> = MergingIter::new(vec![oldest, middle, newest], comparator::bytewise())
+            .unwrap()
+            .map(|r| {
+                let (key, entry) = r.unwrap();
+                (key, entry.value)
+            })
+            .collect();
+        assert_eq!(
+            merged,
+            vec![
+                (Bytes::from("a"), Some(Bytes::from("3"))),
+                (Bytes::from("b"), Some(Bytes::from("2"))),
+                (Bytes::from("c"), None),
+                (Bytes::from("d"), Some(Bytes::from("3"))),
+                (Bytes::from("e"), Some(Bytes::from("3"))),
+            ]
+        );
+    }
+}
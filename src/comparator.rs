@@ -0,0 +1,185 @@
+//! Orderings of keys, see [`Comparator`].
+//!
+//! Every part of the tree orders keys the same way: memtables, the blocks and
+//! indexes of SSTables, merges during scans and compaction, and the bounds of
+//! range deletions and scans. Keys are ordered by their bytes unless a
+//! [`Comparator`] is set through
+//! [`Lsm::set_comparator`](crate::lsm::Lsm::set_comparator).
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::lsm::prefix_range;
+
+/// A total order over keys.
+///
+/// The order is persisted through the layout of every SSTable, so a store
+/// must always be opened with the comparator it was written with. Keys which
+/// compare as equal must hold the same bytes.
+pub trait Comparator: Send + Sync {
+    /// Name of the ordering, used when describing it.
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// The range holding every key which starts with the prefix, or `None`
+    /// if such keys are not contiguous within the ordering, in which case
+    /// prefix scans read every key.
+    fn prefix_range(&self, _prefix: &[u8]) -> Option<(Bound<Bytes>, Bound<Bytes>)> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The default comparator, shared by every part of the tree.
+pub fn bytewise() -> Arc<dyn Comparator> {
+    Arc::new(Bytewise)
+}
+
+/// Orders keys lexicographically by their bytes, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn prefix_range(&self, prefix: &[u8]) -> Option<(Bound<Bytes>, Bound<Bytes>)> {
+        Some(prefix_range(prefix))
+    }
+}
+
+/// Orders keys in the reverse of [`Bytewise`], so that scans run from the
+/// largest key to the smallest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reverse;
+
+impl Comparator for Reverse {
+    fn name(&self) -> &str {
+        "reverse"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn prefix_range(&self, prefix: &[u8]) -> Option<(Bound<Bytes>, Bound<Bytes>)> {
+        // The keys run from just before the prefix's successor, down to the
+        // prefix itself.
+        let (start, end) = prefix_range(prefix);
+        Some((end, start))
+    }
+}
+
+/// Orders time series keys, made up of a fixed length series prefix followed
+/// by a timestamp, so that each series is read newest first.
+///
+/// Prefixes are ordered by their bytes, then the remainder of each key in
+/// descending order. A key holding only the prefix comes first in its series,
+/// so it can bound a scan of the whole series. Timestamps should be big
+/// endian so that their bytes order the same way as their values.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSeries {
+    /// Length, in bytes, of the series prefix of every key.
+    pub prefix_len: usize,
+}
+
+impl TimeSeries {
+    fn split<'a>(&self, key: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        key.split_at(key.len().min(self.prefix_len))
+    }
+}
+
+impl Comparator for TimeSeries {
+    fn name(&self) -> &str {
+        "time-series"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a_series, a_time) = self.split(a);
+        let (b_series, b_time) = self.split(b);
+        a_series
+            .cmp(b_series)
+            .then_with(|| b_time.is_empty().cmp(&a_time.is_empty()))
+            .then_with(|| b_time.cmp(a_time))
+    }
+
+    fn prefix_range(&self, prefix: &[u8]) -> Option<(Bound<Bytes>, Bound<Bytes>)> {
+        // Keys sharing part of a series prefix are ordered by their bytes.
+        (prefix.len() <= self.prefix_len).then(|| prefix_range(prefix))
+    }
+}
+
+/// Whether the key is after the start of the range.
+pub(crate) fn after_start(comparator: &dyn Comparator, key: &[u8], start: Bound<&Bytes>) -> bool {
+    match start {
+        Bound::Included(start) => comparator.compare(key, start).is_ge(),
+        Bound::Excluded(start) => comparator.compare(key, start).is_gt(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Whether the key is before the end of the range.
+pub(crate) fn before_end(comparator: &dyn Comparator, key: &[u8], end: Bound<&Bytes>) -> bool {
+    match end {
+        Bound::Included(end) => comparator.compare(key, end).is_le(),
+        Bound::Excluded(end) => comparator.compare(key, end).is_lt(),
+        Bound::Unbounded => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sorted(comparator: &dyn Comparator, mut keys: Vec<&'static [u8]>) -> Vec<&'static [u8]> {
+        keys.sort_by(|a, b| comparator.compare(a, b));
+        keys
+    }
+
+    #[test]
+    fn orderings() {
+        let keys: Vec<&[u8]> = vec![b"b", b"a", b"ab", b"c"];
+        assert_eq!(
+            sorted(&Bytewise, keys.clone()),
+            vec![b"a" as &[u8], b"ab", b"b", b"c"]
+        );
+        assert_eq!(
+            sorted(&Reverse, keys),
+            vec![b"c" as &[u8], b"b", b"ab", b"a"]
+        );
+
+        let series = TimeSeries { prefix_len: 2 };
+        assert_eq!(
+            sorted(
+                &series,
+                vec![b"s1\x01", b"s2\x01", b"s1\x03", b"s1", b"s2\x02"]
+            ),
+            vec![b"s1" as &[u8], b"s1\x03", b"s1\x01", b"s2\x02", b"s2\x01"],
+            "Series ascend, each newest first after the bare prefix"
+        );
+
+        let (start, end) = Reverse.prefix_range(b"ab").unwrap();
+        for key in [b"ab" as &[u8], b"abc", b"ab\xff"] {
+            assert!(after_start(&Reverse, key, start.as_ref()));
+            assert!(before_end(&Reverse, key, end.as_ref()));
+        }
+        assert!(!before_end(&Reverse, b"aa", end.as_ref()));
+        assert!(!after_start(&Reverse, b"ac", start.as_ref()));
+        assert!(series.prefix_range(b"s1\x01").is_none());
+    }
+}
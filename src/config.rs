@@ -1,4 +1,63 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::level_filters::LevelFilter;
+use utoipa::ToSchema;
+
+use crate::comparator::{self, Comparator};
+use crate::memtable::MEMTABLE_MAX_SIZE_BYTES;
+use crate::units::{ByteSize, Millis};
+use crate::wal::WAL_MAX_SEGMENT_SIZE_BYTES;
+use crate::ChipmunkError;
+
+/// Default size, in bytes, at which SSTable data blocks are closed.
+pub const DEFAULT_SSTABLE_BLOCK_SIZE: usize = 4 * 1024; // 4 KiB
+
+/// Default number of bloom filter bits stored for each key of an SSTable,
+/// giving a false positive rate of roughly 1%.
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
+/// Default proportion of a value log file which must be garbage before its
+/// live values are moved elsewhere and the file removed.
+pub const DEFAULT_VALUE_LOG_GC_RATIO: f64 = 0.5;
+
+/// Default capacity, in bytes, of the cache of SSTable data blocks.
+pub const DEFAULT_BLOCK_CACHE_CAPACITY: usize = 8 * 1024 * 1024; // 8 MiB
+
+/// Default maximum number of SSTable readers which are held open at once.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 1000;
+
+/// Default number of rotated memtables which may be held in memory before the
+/// oldest is flushed. One, so that a full memtable can await the background
+/// flush rather than the write which filled it flushing it, while a setting
+/// of zero flushes on every rotation as before the limit existed.
+pub const DEFAULT_MAX_IMMUTABLE_MEMTABLES: usize = 1;
+
+/// Default number of L1 files at which they are compacted into L2.
+pub const DEFAULT_L1_FILE_TRIGGER: usize = 4;
+
+/// Default number of L2 files beyond which they are merged together.
+pub const DEFAULT_MAX_L2_FILES: usize = 3;
+
+/// Default delay applied to each write while writes are slowed down.
+pub const DEFAULT_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Default size, in bytes, of the largest request body the server accepts
+/// when no maximum value size is configured.
+pub const DEFAULT_MAX_REQUEST_BODY: usize = 2 * 1024 * 1024; // 2 MiB
+
+/// Default interval at which TLS certificate files are checked for changes.
+pub const DEFAULT_TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default time for which browsers may cache the answer to a CORS preflight.
+pub const DEFAULT_CORS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Default directory which the WAL and SSTables are written to.
+pub const DEFAULT_LOG_DIRECTORY: &str = "./";
 
 #[derive(Debug, Clone)]
 pub struct WalConfig {
@@ -6,6 +65,11 @@ pub struct WalConfig {
     pub max_size: u64,
     pub log_directory: PathBuf,
     pub buffer_size: Option<usize>,
+    /// Time for which the segments of flushed memtables are archived rather
+    /// than removed, so that the changefeed and replicas can still read
+    /// their changes. Changes are only retained until they are flushed
+    /// when unset.
+    pub retention: Option<Duration>,
 }
 
 impl WalConfig {
@@ -15,23 +79,884 @@ impl WalConfig {
             max_size,
             log_directory,
             buffer_size,
+            retention: None,
         }
     }
+
+    /// Archive flushed segments for the given time.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self::new(
+            0,
+            WAL_MAX_SEGMENT_SIZE_BYTES,
+            PathBuf::from(DEFAULT_LOG_DIRECTORY),
+            None,
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct MemtableConfig {
     pub id: u64,
     pub max_size: u64,
+    /// Maximum number of rotated, immutable, memtables which can be held in
+    /// memory before writes stall to flush the oldest of them. When flushes
+    /// run in the background, this many may await the worker before writes
+    /// flush them instead.
+    pub max_immutable_memtables: usize,
+    /// Keep every write in memory only, without a WAL or SSTables, handling
+    /// rotated memtables as given rather than flushing them. Writes are
+    /// persisted when unset.
+    pub in_memory: Option<InMemory>,
+    /// Size, in bytes, beyond which values are rejected rather than
+    /// written. Values of any size are accepted when unset.
+    pub max_value_size: Option<usize>,
+    /// Age at which the active memtable is flushed even though it is not
+    /// full, bounding how long writes are held only in memory and the WAL.
+    /// Only applies while flushes run in the background. Memtables are only
+    /// flushed once full when unset.
+    pub flush_interval: Option<Duration>,
 }
 
 impl MemtableConfig {
     pub fn new(id: u64, max_size: u64) -> Self {
-        Self { id, max_size }
+        Self {
+            id,
+            max_size,
+            max_immutable_memtables: DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+            in_memory: None,
+            max_value_size: None,
+            flush_interval: None,
+        }
+    }
+
+    /// Set the maximum number of immutable memtables held in memory.
+    pub fn with_max_immutable_memtables(mut self, max_immutable_memtables: usize) -> Self {
+        self.max_immutable_memtables = max_immutable_memtables;
+        self
+    }
+
+    /// Keep every write in memory only, nothing is written to the log
+    /// directory.
+    pub fn with_in_memory(mut self, in_memory: InMemory) -> Self {
+        self.in_memory = Some(in_memory);
+        self
+    }
+
+    /// Reject values larger than the given size, in bytes.
+    pub fn with_max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// Flush the active memtable once it is as old as the given interval.
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = Some(flush_interval);
+        self
+    }
+}
+
+impl Default for MemtableConfig {
+    fn default() -> Self {
+        Self::new(0, MEMTABLE_MAX_SIZE_BYTES)
+    }
+}
+
+/// What happens to rotated memtables when the engine runs in memory only, see
+/// [`MemtableConfig::in_memory`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum InMemory {
+    /// Every rotated memtable is kept, so memory grows with the data written.
+    #[default]
+    Retain,
+    /// The oldest rotated memtables are discarded once more than
+    /// `max_immutable_memtables` are held, bounding memory as a cache would.
+    Evict,
+}
+
+/// Compression applied to SSTable data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+#[derive(Debug, Clone)]
+pub struct SstableConfig {
+    /// Size, in bytes, at which data blocks are closed and a new one started.
+    pub block_size: usize,
+    /// Compression applied to each data block as it is written.
+    pub compression: Compression,
+    /// Capacity, in bytes, of the cache of decoded data blocks which is shared
+    /// by all SSTables. Zero disables the cache.
+    pub block_cache_capacity: usize,
+    /// Maximum number of SSTable readers held open at once, the least recently
+    /// used are closed and reopened on demand beyond this.
+    pub max_open_files: usize,
+    /// Size, in bytes, beyond which an SSTable's index is split into
+    /// partitions so that lookups only need to read a small part of it. The
+    /// index is never partitioned when unset.
+    pub index_partition_size: Option<usize>,
+    /// Bloom filter bits stored for each key, more bits giving fewer false
+    /// positives. Zero writes tables without a filter.
+    pub bloom_bits_per_key: usize,
+    /// Size, in bytes, from which values are written to a separate value log
+    /// as they are flushed, leaving only a pointer to them within the SSTable.
+    /// This keeps compaction from rewriting large values. Values are never
+    /// separated when unset.
+    pub min_separated_value_size: Option<usize>,
+    /// Proportion of a value log file which must be garbage, from values
+    /// which have since been overwritten or deleted, before the live values
+    /// are moved elsewhere and the file removed.
+    pub value_log_gc_ratio: f64,
+}
+
+impl SstableConfig {
+    pub fn new(block_size: usize, compression: Compression) -> Self {
+        Self {
+            block_size,
+            compression,
+            block_cache_capacity: DEFAULT_BLOCK_CACHE_CAPACITY,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            index_partition_size: None,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            min_separated_value_size: None,
+            value_log_gc_ratio: DEFAULT_VALUE_LOG_GC_RATIO,
+        }
+    }
+
+    /// Set the capacity, in bytes, of the shared block cache.
+    pub fn with_block_cache_capacity(mut self, block_cache_capacity: usize) -> Self {
+        self.block_cache_capacity = block_cache_capacity;
+        self
+    }
+
+    /// Set the maximum number of SSTable readers held open at once.
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Partition SSTable indexes which grow beyond the given size, in bytes.
+    pub fn with_index_partition_size(mut self, index_partition_size: usize) -> Self {
+        self.index_partition_size = Some(index_partition_size);
+        self
+    }
+
+    /// Set the number of bloom filter bits stored for each key.
+    pub fn with_bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    /// Separate values of at least the given size, in bytes, into the value
+    /// log.
+    pub fn with_min_separated_value_size(mut self, min_separated_value_size: usize) -> Self {
+        self.min_separated_value_size = Some(min_separated_value_size);
+        self
+    }
+
+    /// Set the proportion of a value log file which must be garbage before it
+    /// is collected.
+    pub fn with_value_log_gc_ratio(mut self, value_log_gc_ratio: f64) -> Self {
+        self.value_log_gc_ratio = value_log_gc_ratio;
+        self
     }
 }
 
+impl Default for SstableConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_SSTABLE_BLOCK_SIZE, Compression::None)
+    }
+}
+
+/// Thresholds at which L1, the files flushed from memtables, is compacted
+/// into L2. Compaction is triggered as soon as any one of them is exceeded,
+/// thresholds which are unset never trigger it.
+#[derive(Debug, Clone)]
+pub struct CompactionConfig {
+    /// Number of L1 files at which compaction is triggered.
+    pub l1_file_trigger: Option<usize>,
+    /// Total size, in bytes, of the L1 files at which compaction is triggered.
+    pub l1_size_trigger: Option<u64>,
+    /// Size of L1 relative to L2 at which compaction is triggered, e.g. `0.1`
+    /// compacts once L1 holds a tenth as many bytes as L2. This only applies
+    /// once L2 holds data.
+    pub level_size_ratio: Option<f64>,
+    /// Number of L2 files beyond which compaction is triggered, merging the
+    /// L2 files along with L1 into one.
+    pub max_l2_files: Option<usize>,
+    /// Rate, in bytes per second, which compaction writes are limited to, so
+    /// that it leaves disk bandwidth to reads and flushes. Compaction runs as
+    /// fast as it can when unset.
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl CompactionConfig {
+    /// Compact once L1 holds the given number of files.
+    pub fn with_l1_file_trigger(mut self, l1_file_trigger: usize) -> Self {
+        self.l1_file_trigger = Some(l1_file_trigger);
+        self
+    }
+
+    /// Compact once the L1 files total the given size, in bytes.
+    pub fn with_l1_size_trigger(mut self, l1_size_trigger: u64) -> Self {
+        self.l1_size_trigger = Some(l1_size_trigger);
+        self
+    }
+
+    /// Compact once L1 reaches the given size relative to L2.
+    pub fn with_level_size_ratio(mut self, level_size_ratio: f64) -> Self {
+        self.level_size_ratio = Some(level_size_ratio);
+        self
+    }
+
+    /// Compact once L2 holds more than the given number of files, merging
+    /// them into one.
+    pub fn with_max_l2_files(mut self, max_l2_files: usize) -> Self {
+        self.max_l2_files = Some(max_l2_files);
+        self
+    }
+
+    /// Limit compaction to writing the given number of bytes per second.
+    pub fn with_max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            l1_file_trigger: Some(DEFAULT_L1_FILE_TRIGGER),
+            l1_size_trigger: None,
+            level_size_ratio: None,
+            max_l2_files: Some(DEFAULT_MAX_L2_FILES),
+            max_bytes_per_sec: None,
+        }
+    }
+}
+
+/// Limits on the data awaiting a flush or compaction, beyond which writes are
+/// first slowed down and then stopped so that the tree cannot degrade without
+/// bound. Limits which are unset never stall writes.
+#[derive(Debug, Clone)]
+pub struct WriteStallConfig {
+    /// Number of L1 files from which writes are slowed down.
+    pub l1_slowdown_trigger: Option<usize>,
+    /// Number of L1 files from which writes are stopped.
+    pub l1_stop_trigger: Option<usize>,
+    /// Number of immutable memtables from which writes are slowed down.
+    pub immutable_slowdown_trigger: Option<usize>,
+    /// Number of immutable memtables from which writes are stopped.
+    pub immutable_stop_trigger: Option<usize>,
+    /// Delay applied to each write while writes are slowed down.
+    pub slowdown_delay: Duration,
+}
+
+impl WriteStallConfig {
+    /// Slow down and stop writes at the given number of L1 files.
+    pub fn with_l1_triggers(mut self, slowdown: usize, stop: usize) -> Self {
+        self.l1_slowdown_trigger = Some(slowdown);
+        self.l1_stop_trigger = Some(stop);
+        self
+    }
+
+    /// Slow down and stop writes at the given number of immutable memtables.
+    pub fn with_immutable_triggers(mut self, slowdown: usize, stop: usize) -> Self {
+        self.immutable_slowdown_trigger = Some(slowdown);
+        self.immutable_stop_trigger = Some(stop);
+        self
+    }
+
+    /// Set the delay applied to each write while writes are slowed down.
+    pub fn with_slowdown_delay(mut self, slowdown_delay: Duration) -> Self {
+        self.slowdown_delay = slowdown_delay;
+        self
+    }
+}
+
+impl Default for WriteStallConfig {
+    fn default() -> Self {
+        Self {
+            l1_slowdown_trigger: None,
+            l1_stop_trigger: None,
+            immutable_slowdown_trigger: None,
+            immutable_stop_trigger: None,
+            slowdown_delay: DEFAULT_SLOWDOWN_DELAY,
+        }
+    }
+}
+
+/// Certificate and private key which the server terminates TLS with, see
+/// [`crate::tls`].
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file holding the private key of the leaf certificate.
+    pub key_path: PathBuf,
+    /// Interval at which the files are checked for changes, so that renewed
+    /// certificates are used without a restart.
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+        Self {
+            cert_path,
+            key_path,
+            reload_interval: DEFAULT_TLS_RELOAD_INTERVAL,
+        }
+    }
+
+    pub fn with_reload_interval(mut self, reload_interval: Duration) -> Self {
+        self.reload_interval = reload_interval;
+        self
+    }
+}
+
+/// Cross-origin requests which the HTTP API allows, so that browser-based
+/// clients on other origins can call it.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins, such as `https://dashboard.example.com`, allowed to call the
+    /// API, or every origin when `None`.
+    pub allowed_origins: Option<Vec<HeaderValue>>,
+    pub allowed_methods: Vec<Method>,
+    /// Request headers which may be sent beyond those always allowed.
+    pub allowed_headers: Vec<HeaderName>,
+    /// Time for which browsers may cache the answer to a preflight.
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    /// Allow the given origins to use every route of the API with the
+    /// headers it understands. `*` is not an origin, see
+    /// [`CorsConfig::any_origin`].
+    pub fn new(allowed_origins: Vec<HeaderValue>) -> Self {
+        Self {
+            allowed_origins: Some(allowed_origins),
+            allowed_methods: vec![Method::GET, Method::PUT, Method::DELETE, Method::POST],
+            allowed_headers: vec![
+                header::CONTENT_TYPE,
+                header::IF_MATCH,
+                header::IF_NONE_MATCH,
+                HeaderName::from_static(crate::server::KEY_ENCODING_HEADER),
+                HeaderName::from_static(crate::server::TTL_HEADER),
+            ],
+            max_age: DEFAULT_CORS_MAX_AGE,
+        }
+    }
+
+    /// Allow every origin, which suits APIs without credentials.
+    pub fn any_origin() -> Self {
+        Self {
+            allowed_origins: None,
+            ..Self::new(Vec::new())
+        }
+    }
+
+    pub fn with_methods(mut self, allowed_methods: Vec<Method>) -> Self {
+        self.allowed_methods = allowed_methods;
+        self
+    }
+
+    pub fn with_headers(mut self, allowed_headers: Vec<HeaderName>) -> Self {
+        self.allowed_headers = allowed_headers;
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+}
+
+/// Limits on the connections and requests of the HTTP API, which keep a
+/// stalled engine or misbehaving clients from exhausting the server.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    /// Time after which requests which have not been answered are answered
+    /// with `503 Service Unavailable`. Requests are never timed out when
+    /// unset.
+    pub request_timeout: Option<Duration>,
+    /// Whether HTTP/1.1 connections are kept open between requests.
+    pub keep_alive: bool,
+    /// Interval at which HTTP/2 connections are pinged, closing those which
+    /// stop answering. Connections are never pinged when unset.
+    pub keep_alive_interval: Option<Duration>,
+    /// Maximum number of connections served at once. Further connections wait
+    /// to be accepted until others close.
+    pub max_connections: Option<usize>,
+    /// Bearer token required by the admin routes which take the store out of
+    /// service, flush or compact it, or change its settings, which are
+    /// refused when unset.
+    pub admin_token: Option<String>,
+}
+
+impl HttpConfig {
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_keep_alive_interval(mut self, keep_alive_interval: Duration) -> Self {
+        self.keep_alive_interval = Some(keep_alive_interval);
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn with_admin_token(mut self, admin_token: impl Into<String>) -> Self {
+        self.admin_token = Some(admin_token.into());
+        self
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: None,
+            keep_alive: true,
+            keep_alive_interval: None,
+            max_connections: None,
+            admin_token: None,
+        }
+    }
+}
+
+/// Time a write waits for replicas to apply it in [`AckMode::SemiSync`],
+/// see [`ReplicationConfig::ack_timeout`].
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// When writes are acknowledged to clients while replicas follow the store,
+/// see [`crate::replication`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AckMode {
+    /// Writes are acknowledged once applied, leaving replicas to catch up.
+    #[default]
+    Async,
+    /// Writes are acknowledged once enough replicas have applied them, or
+    /// the timeout has passed, after which writes are acknowledged
+    /// asynchronously until enough replicas have caught up.
+    SemiSync,
+}
+
+/// How the store acknowledges writes to the replicas which follow it.
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    pub ack_mode: AckMode,
+    /// Number of replicas which must apply a write before it is acknowledged
+    /// in [`AckMode::SemiSync`].
+    pub min_replicas: usize,
+    /// Time a write waits for replicas before the store falls back to
+    /// acknowledging writes asynchronously.
+    pub ack_timeout: Duration,
+}
+
+impl ReplicationConfig {
+    /// Acknowledge writes once the given number of replicas have applied
+    /// them, waiting no longer than the timeout.
+    pub fn semi_sync(min_replicas: usize, ack_timeout: Duration) -> Self {
+        Self {
+            ack_mode: AckMode::SemiSync,
+            min_replicas,
+            ack_timeout,
+        }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            ack_mode: AckMode::Async,
+            min_replicas: 1,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+        }
+    }
+}
+
+/// Everything the store is created with, see
+/// [`Chipmunk::new`](crate::server::Chipmunk::new).
+///
+/// The defaults write to the current directory, so embedding the store
+/// usually only needs the directory and sizes changing through
+/// [`ChipmunkConfig::builder`]:
+///
+/// ```
+/// use chipmunk::config::ChipmunkConfig;
+///
+/// let config = ChipmunkConfig::builder()
+///     .wal_dir("/var/lib/chipmunk")
+///     .memtable_max(64 * 1024 * 1024)
+///     .build();
+/// assert_eq!(config.memtable.max_size, 64 * 1024 * 1024);
+/// ```
+#[derive(Debug, Clone)]
 pub struct ChipmunkConfig {
     pub wal: WalConfig,
     pub memtable: MemtableConfig,
+    pub sstable: SstableConfig,
+    pub compaction: CompactionConfig,
+    pub write_stall: WriteStallConfig,
+    /// Serve over TLS rather than plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+    /// Allow cross-origin requests from browsers when set, which are
+    /// otherwise blocked.
+    pub cors: Option<CorsConfig>,
+    pub http: HttpConfig,
+    /// Address of the primary which the store follows as a read-only
+    /// replica when set, such as `primary:5000` or `https://primary:5000`,
+    /// see [`crate::replication`].
+    pub replica_of: Option<String>,
+    /// How writes are acknowledged to the replicas following the store.
+    pub replication: ReplicationConfig,
+    /// Number of shards the keys are split between, which cannot change
+    /// once the store has been written to.
+    ///
+    /// With more than one, a store cannot follow a primary or have writes
+    /// wait on replicas, which [`Chipmunk::new`](crate::server::Chipmunk::new)
+    /// refuses. Watching keys, the changefeed, serving replicas and
+    /// checkpoints are refused too, and a batch is only applied atomically
+    /// within each shard, so one which fails may be partly applied.
+    pub shards: usize,
+}
+
+impl Default for ChipmunkConfig {
+    fn default() -> Self {
+        Self {
+            wal: WalConfig::default(),
+            memtable: MemtableConfig::default(),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        }
+    }
+}
+
+impl ChipmunkConfig {
+    /// Start from the defaults, changing only the settings given.
+    pub fn builder() -> ChipmunkConfigBuilder {
+        ChipmunkConfigBuilder::default()
+    }
+}
+
+/// Builds a [`ChipmunkConfig`], see [`ChipmunkConfig::builder`]. Settings
+/// which are not given keep their defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ChipmunkConfigBuilder {
+    config: ChipmunkConfig,
+}
+
+impl ChipmunkConfigBuilder {
+    /// Directory which the WAL and SSTables are written to.
+    pub fn wal_dir(mut self, log_directory: impl Into<PathBuf>) -> Self {
+        self.config.wal.log_directory = log_directory.into();
+        self
+    }
+
+    /// Size, in bytes, at which WAL segments are rotated.
+    pub fn wal_max(mut self, max_size: u64) -> Self {
+        self.config.wal.max_size = max_size;
+        self
+    }
+
+    /// Size, in bytes, of the buffer WAL appends are held in before being
+    /// written out.
+    pub fn wal_buffer(mut self, buffer_size: usize) -> Self {
+        self.config.wal.buffer_size = Some(buffer_size);
+        self
+    }
+
+    /// See [`WalConfig::with_retention`].
+    pub fn wal_retention(mut self, retention: Duration) -> Self {
+        self.config.wal.retention = Some(retention);
+        self
+    }
+
+    /// Size, in bytes, at which the active memtable is rotated.
+    pub fn memtable_max(mut self, max_size: u64) -> Self {
+        self.config.memtable.max_size = max_size;
+        self
+    }
+
+    /// See [`MemtableConfig::with_max_immutable_memtables`].
+    pub fn max_immutable_memtables(mut self, max_immutable_memtables: usize) -> Self {
+        self.config.memtable.max_immutable_memtables = max_immutable_memtables;
+        self
+    }
+
+    /// See [`MemtableConfig::with_flush_interval`].
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.config.memtable.flush_interval = Some(flush_interval);
+        self
+    }
+
+    /// See [`MemtableConfig::with_in_memory`].
+    pub fn in_memory(mut self, in_memory: InMemory) -> Self {
+        self.config.memtable.in_memory = Some(in_memory);
+        self
+    }
+
+    /// See [`MemtableConfig::with_max_value_size`].
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.config.memtable.max_value_size = Some(max_value_size);
+        self
+    }
+
+    pub fn sstable(mut self, sstable: SstableConfig) -> Self {
+        self.config.sstable = sstable;
+        self
+    }
+
+    pub fn compaction(mut self, compaction: CompactionConfig) -> Self {
+        self.config.compaction = compaction;
+        self
+    }
+
+    pub fn write_stall(mut self, write_stall: WriteStallConfig) -> Self {
+        self.config.write_stall = write_stall;
+        self
+    }
+
+    /// Serve over TLS rather than plain HTTP.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Allow cross-origin requests from browsers.
+    pub fn cors(mut self, cors: CorsConfig) -> Self {
+        self.config.cors = Some(cors);
+        self
+    }
+
+    pub fn http(mut self, http: HttpConfig) -> Self {
+        self.config.http = http;
+        self
+    }
+
+    /// Follow the primary at the address as a read-only replica.
+    pub fn replica_of(mut self, primary: impl Into<String>) -> Self {
+        self.config.replica_of = Some(primary.into());
+        self
+    }
+
+    pub fn replication(mut self, replication: ReplicationConfig) -> Self {
+        self.config.replication = replication;
+        self
+    }
+
+    /// Split the keys between the given number of shards.
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.config.shards = shards;
+        self
+    }
+
+    pub fn build(self) -> ChipmunkConfig {
+        self.config
+    }
+}
+
+/// Settings which can be changed while the store runs, read from a JSON
+/// config file at startup and again on each
+/// [`Chipmunk::reload`](crate::server::Chipmunk::reload).
+///
+/// Fields are named as the command line flags they override. Those missing
+/// from the file keep the values the store was started with, so removing a
+/// setting from the file and reloading restores its original value. Sizes
+/// and durations are numbers in the unit the field is named after, or
+/// strings such as `"64MiB"` or `"500ms"`, see [`crate::units`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tunables {
+    /// Maximum level of the logs written: `off`, `error`, `warn`, `info`,
+    /// `debug` or `trace`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub log_level: Option<LogLevel>,
+    /// Size, in bytes, of the buffer WAL appends are held in before being
+    /// written out, which trades the durability of the latest writes for
+    /// fewer writes to disk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub wal_buffer_size_bytes: Option<ByteSize>,
+    /// Age, in milliseconds, at which the active memtable is flushed even
+    /// though it is not full.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub memtable_flush_interval_ms: Option<Millis>,
+    /// Capacity, in bytes, of the cache of SSTable data blocks. Shrinking it
+    /// evicts the least recently used blocks straight away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub sstable_block_cache_bytes: Option<ByteSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compaction_l1_file_trigger: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub compaction_l1_size_trigger_bytes: Option<ByteSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compaction_level_size_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compaction_max_l2_files: Option<usize>,
+    /// Rate, in bytes per second, which compaction writes are limited to.
+    /// Applies from the next compaction cycle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub compaction_max_bytes_per_sec: Option<ByteSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_stall_l1_slowdown_files: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_stall_l1_stop_files: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_stall_immutable_slowdown: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_stall_immutable_stop: Option<usize>,
+    /// Delay, in milliseconds, applied to each write while writes are slowed
+    /// down, which limits the rate of writes the tree cannot keep up with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub write_stall_slowdown_delay_ms: Option<Millis>,
+}
+
+impl Tunables {
+    /// Read the settings from a JSON config file.
+    pub fn load(path: &Path) -> Result<Self, ChipmunkError> {
+        let contents = std::fs::read(path).map_err(|source| ChipmunkError::ConfigRead {
+            source,
+            path: path.to_path_buf(),
+        })?;
+        serde_json::from_slice(&contents).map_err(|e| ChipmunkError::InvalidConfig {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })
+    }
+
+    /// The compaction thresholds, with those which are not set taken from
+    /// the given config.
+    pub fn compaction(&self, base: &CompactionConfig) -> CompactionConfig {
+        CompactionConfig {
+            l1_file_trigger: self.compaction_l1_file_trigger.or(base.l1_file_trigger),
+            l1_size_trigger: self
+                .compaction_l1_size_trigger_bytes
+                .map(|size| size.0)
+                .or(base.l1_size_trigger),
+            level_size_ratio: self.compaction_level_size_ratio.or(base.level_size_ratio),
+            max_l2_files: self.compaction_max_l2_files.or(base.max_l2_files),
+            max_bytes_per_sec: self
+                .compaction_max_bytes_per_sec
+                .map(|rate| rate.0)
+                .or(base.max_bytes_per_sec),
+        }
+    }
+
+    /// The write stall limits, with those which are not set taken from the
+    /// given config.
+    pub fn write_stall(&self, base: &WriteStallConfig) -> WriteStallConfig {
+        WriteStallConfig {
+            l1_slowdown_trigger: self
+                .write_stall_l1_slowdown_files
+                .or(base.l1_slowdown_trigger),
+            l1_stop_trigger: self.write_stall_l1_stop_files.or(base.l1_stop_trigger),
+            immutable_slowdown_trigger: self
+                .write_stall_immutable_slowdown
+                .or(base.immutable_slowdown_trigger),
+            immutable_stop_trigger: self
+                .write_stall_immutable_stop
+                .or(base.immutable_stop_trigger),
+            slowdown_delay: self
+                .write_stall_slowdown_delay_ms
+                .map_or(base.slowdown_delay, |delay| delay.0),
+        }
+    }
+}
+
+/// Maximum level of the logs written, given by its name such as `debug`,
+/// see [`Tunables::log_level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLevel(pub LevelFilter);
+
+impl Serialize for LogLevel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string().to_lowercase())
+    }
+}
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let level = String::deserialize(deserializer)?;
+        level.parse().map(LogLevel).map_err(D::Error::custom)
+    }
+}
+
+/// Options for opening an existing directory through
+/// [`Lsm::open`](crate::lsm::Lsm::open), which discovers the IDs and
+/// directory that a [`WalConfig`] and [`MemtableConfig`] would otherwise
+/// have to be given.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Size, in bytes, at which WAL segments are rotated.
+    pub wal_max_size: u64,
+    /// Size, in bytes, of the buffer WAL appends are held in before being
+    /// written out. Appends are written immediately when unset.
+    pub wal_buffer_size: Option<usize>,
+    /// Time for which flushed WAL segments are archived, see
+    /// [`WalConfig::retention`].
+    pub wal_retention: Option<Duration>,
+    /// Size, in bytes, at which the active memtable is rotated.
+    pub memtable_max_size: u64,
+    /// Maximum number of immutable memtables held in memory, see
+    /// [`MemtableConfig::max_immutable_memtables`].
+    pub max_immutable_memtables: usize,
+    /// Size, in bytes, beyond which values are rejected, see
+    /// [`MemtableConfig::max_value_size`].
+    pub max_value_size: Option<usize>,
+    pub sstable: SstableConfig,
+    pub compaction: CompactionConfig,
+    pub write_stall: WriteStallConfig,
+    /// Ordering of keys, which must match the one the directory was written
+    /// with.
+    pub comparator: Arc<dyn Comparator>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            wal_max_size: WAL_MAX_SEGMENT_SIZE_BYTES,
+            wal_buffer_size: None,
+            wal_retention: None,
+            memtable_max_size: MEMTABLE_MAX_SIZE_BYTES,
+            max_immutable_memtables: DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+            max_value_size: None,
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            comparator: comparator::bytewise(),
+        }
+    }
 }
@@ -0,0 +1,599 @@
+//! Offline inspection of the files of a store, for debugging corruption and
+//! replay. These read files directly, so should be pointed at a store which
+//! is not running or at copies of its files.
+
+use std::io::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::sstable::{Entry, IndexKind, Sstable};
+use crate::wal::{Checksum, SegmentReader, WalEntry, WalRecord};
+use crate::ChipmunkError;
+
+/// How records are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// A human readable line for each record.
+    #[default]
+    Text,
+    /// A JSON object on each line for each record.
+    Json,
+}
+
+/// Outcome of a dump, telling whether the file was read cleanly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpSummary {
+    /// Number of records, or entries, read.
+    pub records: u64,
+    /// Number of records which failed their checksum.
+    pub checksum_mismatches: u64,
+    /// Why reading stopped before the end of the file, if it did.
+    pub error: Option<String>,
+}
+
+impl DumpSummary {
+    /// Whether every record was read and matched its checksum.
+    pub fn is_clean(&self) -> bool {
+        self.checksum_mismatches == 0 && self.error.is_none()
+    }
+}
+
+/// A record of a WAL segment, as printed by [`dump_wal`]. Keys are printed
+/// with non-printable bytes escaped.
+#[derive(Debug, Serialize)]
+struct WalDumpRecord {
+    offset: u64,
+    sequence: Option<u64>,
+    /// Unix timestamp, in milliseconds, of the write.
+    written_at: Option<u64>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    key: String,
+    /// End of the range of a range deletion, exclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// `ok`, `mismatch` or `none` for segments written without checksums.
+    crc: &'static str,
+}
+
+impl WalDumpRecord {
+    fn new(record: WalRecord) -> Self {
+        let (stamp, entry) = match record.entry {
+            WalEntry::Stamped { stamp, entry } => (Some(stamp), *entry),
+            entry => (None, entry),
+        };
+        let (kind, key, end, value_size, expires_at) = match &entry {
+            WalEntry::Put { key, value } => ("put", escape(key), None, Some(value.len()), None),
+            WalEntry::PutWithExpiry {
+                key,
+                value,
+                expires_at,
+            } => (
+                "put",
+                escape(key),
+                None,
+                Some(value.len()),
+                Some(*expires_at),
+            ),
+            WalEntry::Delete { key } => ("delete", escape(key), None, None, None),
+            WalEntry::DeleteRange { start, end } => {
+                ("delete_range", escape(start), Some(escape(end)), None, None)
+            }
+            WalEntry::Stamped { .. } => unreachable!("stamps are never nested"),
+        };
+        Self {
+            offset: record.offset,
+            sequence: stamp.map(|stamp| stamp.sequence),
+            written_at: stamp.map(|stamp| stamp.written_at),
+            kind,
+            key,
+            end,
+            value_size,
+            expires_at,
+            crc: match record.checksum {
+                Checksum::Valid => "ok",
+                Checksum::Mismatch { .. } => "mismatch",
+                Checksum::Absent => "none",
+            },
+        }
+    }
+
+    fn write_text(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let sequence = self
+            .sequence
+            .map_or_else(|| "-".to_string(), |sequence| sequence.to_string());
+        write!(
+            out,
+            "{:>10} seq={sequence} {} key={}",
+            self.offset, self.kind, self.key
+        )?;
+        if let Some(end) = &self.end {
+            write!(out, " end={end}")?;
+        }
+        if let Some(value_size) = self.value_size {
+            write!(out, " value_size={value_size}")?;
+        }
+        if let Some(expires_at) = self.expires_at {
+            write!(out, " expires_at={expires_at}")?;
+        }
+        writeln!(out, " crc={}", self.crc)
+    }
+}
+
+/// Bytes as text, with non-printable bytes escaped.
+fn escape(bytes: &[u8]) -> String {
+    bytes.escape_ascii().to_string()
+}
+
+/// The tables of an SSTable, as printed by [`dump_sstable`].
+#[derive(Debug, Serialize)]
+struct SstableDump {
+    path: String,
+    version: u32,
+    /// Absent for legacy tables, which have no footer.
+    footer: Option<FooterDump>,
+    min_key: String,
+    max_key: String,
+    entries: u64,
+    tombstones: u64,
+    /// Unix timestamp, in milliseconds, at which the table was written.
+    created_at: u64,
+    /// Deleted ranges, each as its start, inclusive, and end, exclusive.
+    range_tombstones: Vec<(String, String)>,
+    /// `flat`, `partitioned` or `legacy`.
+    index: &'static str,
+    index_partitions: Option<usize>,
+    blocks: Vec<BlockDump>,
+    bloom: Option<BloomDump>,
+}
+
+#[derive(Debug, Serialize)]
+struct FooterDump {
+    index_offset: u64,
+    index_size: u64,
+    meta_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct BlockDump {
+    offset: u64,
+    size: u64,
+    last_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partition: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct BloomDump {
+    probes: u8,
+    bits: usize,
+    /// Size, in bytes, of the encoded filter.
+    size: usize,
+    /// Expected rate of false positives over the keys the table holds.
+    false_positive_rate: f64,
+}
+
+/// An entry of an SSTable, as printed by [`dump_sstable`].
+#[derive(Debug, Serialize)]
+struct SstableEntryDump {
+    key: String,
+    sequence: Option<u64>,
+    /// `put` or `delete`.
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// Whether the value is held in the value log, the size being that of
+    /// the pointer to it.
+    separated: bool,
+}
+
+impl SstableEntryDump {
+    fn new(key: &[u8], entry: &Entry) -> Self {
+        Self {
+            key: escape(key),
+            sequence: entry.stamp.map(|stamp| stamp.sequence),
+            kind: match entry.value {
+                Some(_) => "put",
+                None => "delete",
+            },
+            value_size: entry.value.as_ref().map(|value| value.len()),
+            expires_at: entry.expires_at,
+            separated: entry.separated,
+        }
+    }
+
+    fn write_text(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let sequence = self
+            .sequence
+            .map_or_else(|| "-".to_string(), |sequence| sequence.to_string());
+        write!(out, "seq={sequence} {} key={}", self.kind, self.key)?;
+        if let Some(value_size) = self.value_size {
+            write!(out, " value_size={value_size}")?;
+        }
+        if let Some(expires_at) = self.expires_at {
+            write!(out, " expires_at={expires_at}")?;
+        }
+        if self.separated {
+            write!(out, " separated")?;
+        }
+        writeln!(out)
+    }
+}
+
+impl SstableDump {
+    fn write_text(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "{}: format version {}", self.path, self.version)?;
+        if let Some(footer) = &self.footer {
+            writeln!(
+                out,
+                "footer: index offset={} size={}, meta size={}",
+                footer.index_offset, footer.index_size, footer.meta_size
+            )?;
+        }
+        writeln!(
+            out,
+            "metadata: entries={} tombstones={} created_at={} min_key={} max_key={}",
+            self.entries, self.tombstones, self.created_at, self.min_key, self.max_key
+        )?;
+        for (start, end) in &self.range_tombstones {
+            writeln!(out, "range tombstone: {start}..{end}")?;
+        }
+        match self.index_partitions {
+            Some(partitions) => writeln!(
+                out,
+                "index: {} with {partitions} partitions, {} data blocks",
+                self.index,
+                self.blocks.len()
+            )?,
+            None => writeln!(
+                out,
+                "index: {}, {} data blocks",
+                self.index,
+                self.blocks.len()
+            )?,
+        }
+        for block in &self.blocks {
+            write!(
+                out,
+                "{:>10} size={} last_key={}",
+                block.offset, block.size, block.last_key
+            )?;
+            if let Some(partition) = block.partition {
+                write!(out, " partition={partition}")?;
+            }
+            writeln!(out)?;
+        }
+        match &self.bloom {
+            Some(bloom) => writeln!(
+                out,
+                "bloom: probes={} bits={} size={} false_positive_rate={:.4}",
+                bloom.probes, bloom.bits, bloom.size, bloom.false_positive_rate
+            ),
+            None => writeln!(out, "bloom: none"),
+        }
+    }
+}
+
+/// Print the footer, metadata, index and bloom filter of an SSTable, and
+/// optionally every entry it holds.
+///
+/// Entries are printed with their key, sequence number, type and value size
+/// rather than their value. Reading stops at a data block which cannot be
+/// read or fails its checksum, which is reported last. A table which cannot
+/// be opened at all is an error.
+pub fn dump_sstable(
+    path: &Path,
+    entries: bool,
+    format: DumpFormat,
+    out: &mut impl Write,
+) -> Result<DumpSummary, ChipmunkError> {
+    let sstable = Sstable::open(path)?;
+    let metadata = sstable.metadata();
+    let (index, index_partitions) = match sstable.index_kind() {
+        IndexKind::Flat => ("flat", None),
+        IndexKind::Partitioned { partitions } => ("partitioned", Some(partitions)),
+        IndexKind::Legacy => ("legacy", None),
+    };
+    let mut summary = DumpSummary {
+        records: 0,
+        checksum_mismatches: 0,
+        error: None,
+    };
+    let blocks = match sstable.data_blocks() {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            summary.error = Some(e.to_string());
+            Vec::new()
+        }
+    };
+    let dump = SstableDump {
+        path: path.display().to_string(),
+        version: sstable.format_version(),
+        footer: sstable.footer().map(|footer| FooterDump {
+            index_offset: footer.index_offset,
+            index_size: footer.index_size,
+            meta_size: footer.meta_size,
+        }),
+        min_key: escape(&metadata.min_key),
+        max_key: escape(&metadata.max_key),
+        entries: metadata.entries,
+        tombstones: metadata.tombstones,
+        created_at: metadata.created_at,
+        range_tombstones: metadata
+            .range_tombstones
+            .iter()
+            .map(|tombstone| (escape(&tombstone.start), escape(&tombstone.end)))
+            .collect(),
+        index,
+        index_partitions,
+        blocks: blocks
+            .into_iter()
+            .map(|block| BlockDump {
+                offset: block.offset,
+                size: block.size,
+                last_key: escape(&block.last_key),
+                partition: block.partition,
+            })
+            .collect(),
+        bloom: metadata.filter.as_ref().map(|filter| BloomDump {
+            probes: filter.probes(),
+            bits: filter.bits(),
+            size: filter.size(),
+            false_positive_rate: filter.false_positive_rate(metadata.entries),
+        }),
+    };
+
+    let written = (|| {
+        match format {
+            DumpFormat::Text => dump.write_text(out)?,
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut *out, &dump)?;
+                writeln!(out)?;
+            }
+        }
+        if entries && summary.error.is_none() {
+            if format == DumpFormat::Text {
+                writeln!(out, "entries:")?;
+            }
+            for entry in sstable.iter() {
+                let (key, entry) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        summary.error = Some(e.to_string());
+                        break;
+                    }
+                };
+                summary.records += 1;
+                let entry = SstableEntryDump::new(&key, &entry);
+                match format {
+                    DumpFormat::Text => entry.write_text(out)?,
+                    DumpFormat::Json => {
+                        serde_json::to_writer(&mut *out, &entry)?;
+                        writeln!(out)?;
+                    }
+                }
+            }
+        }
+        write_error(&summary, format, out)?;
+        out.flush()
+    })();
+    written.map_err(ChipmunkError::DumpWrite)?;
+    Ok(summary)
+}
+
+/// Report why reading stopped early, if it did, as the last line of a dump.
+fn write_error(
+    summary: &DumpSummary,
+    format: DumpFormat,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    let Some(error) = &summary.error else {
+        return Ok(());
+    };
+    match format {
+        DumpFormat::Text => writeln!(out, "unreadable: {error}"),
+        DumpFormat::Json => writeln!(out, "{}", serde_json::json!({ "error": error })),
+    }
+}
+
+/// Print each record of a WAL segment, with its offset, the sequence number
+/// of its write, its type, key, value size and whether it matched its
+/// checksum.
+///
+/// Records which fail their checksum are printed and reading carries on, as
+/// replay would have stopped at them. Reading stops at a record which cannot
+/// be decoded, which is reported last.
+pub fn dump_wal(
+    segment: &Path,
+    format: DumpFormat,
+    out: &mut impl Write,
+) -> Result<DumpSummary, ChipmunkError> {
+    let mut reader = SegmentReader::open(segment)?;
+    let mut summary = DumpSummary {
+        records: 0,
+        checksum_mismatches: 0,
+        error: None,
+    };
+    let written = (|| {
+        if format == DumpFormat::Text {
+            writeln!(
+                out,
+                "{}: format version {}",
+                segment.display(),
+                reader.version()
+            )?;
+        }
+        while let Some(record) = reader.next_record() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    summary.error = Some(e.to_string());
+                    break;
+                }
+            };
+            summary.records += 1;
+            if matches!(record.checksum, Checksum::Mismatch { .. }) {
+                summary.checksum_mismatches += 1;
+            }
+            let record = WalDumpRecord::new(record);
+            match format {
+                DumpFormat::Text => record.write_text(out)?,
+                DumpFormat::Json => {
+                    serde_json::to_writer(&mut *out, &record)?;
+                    writeln!(out)?;
+                }
+            }
+        }
+        write_error(&summary, format, out)?;
+        out.flush()
+    })();
+    written.map_err(ChipmunkError::DumpWrite)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::memtable::WriteStamp;
+    use crate::sstable::SstableBuilder;
+    use crate::wal::{Wal, WAL_MAX_SEGMENT_SIZE_BYTES};
+
+    #[test]
+    fn wal_dump() {
+        let dir = TempDir::new("wal_dump").unwrap();
+        let mut wal = Wal::new(0, dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, None);
+        let stamp = |sequence| WriteStamp {
+            sequence,
+            written_at: 1_700_000_000_000,
+        };
+        let entries = [
+            WalEntry::Put {
+                key: b"a\n".to_vec(),
+                value: b"1".to_vec(),
+            },
+            WalEntry::Stamped {
+                stamp: stamp(2),
+                entry: Box::new(WalEntry::PutWithExpiry {
+                    key: b"b".to_vec(),
+                    value: b"22".to_vec(),
+                    expires_at: 1_800_000_000_000,
+                }),
+            },
+            WalEntry::Stamped {
+                stamp: stamp(3),
+                entry: Box::new(WalEntry::DeleteRange {
+                    start: b"c".to_vec(),
+                    end: b"d".to_vec(),
+                }),
+            },
+        ];
+        for entry in entries {
+            wal.append(entry).unwrap();
+        }
+        wal.sync().unwrap();
+
+        let mut out = Vec::new();
+        let summary = dump_wal(&wal.path(), DumpFormat::Text, &mut out).unwrap();
+        assert!(summary.is_clean());
+        assert_eq!(summary.records, 3);
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().map(str::trim_start).collect();
+        assert!(lines[0].ends_with("0.wal: format version 4"));
+        assert_eq!(lines[1], "4 seq=- put key=a\\n value_size=1 crc=ok");
+        assert!(lines[2].ends_with("seq=2 put key=b value_size=2 expires_at=1800000000000 crc=ok"));
+        assert!(lines[3].ends_with("seq=3 delete_range key=c end=d crc=ok"));
+
+        // Corrupt the last byte of the final key, and leave a partial record
+        let mut bytes = std::fs::read(wal.path()).unwrap();
+        let last = bytes.len() - 5;
+        bytes[last] ^= 0xff;
+        bytes.push(0);
+        std::fs::write(wal.path(), &bytes).unwrap();
+
+        let mut out = Vec::new();
+        let summary = dump_wal(&wal.path(), DumpFormat::Json, &mut out).unwrap();
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.checksum_mismatches, 1);
+        assert!(summary.error.is_some());
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1]["sequence"], 2);
+        assert_eq!(lines[1]["value_size"], 2);
+        assert_eq!(lines[2]["crc"], "mismatch");
+        assert!(lines[3]["error"].is_string());
+    }
+
+    #[test]
+    fn sstable_dump() {
+        let dir = TempDir::new("sstable_dump").unwrap();
+        let path = dir.path().join("0.sst");
+        let mut builder = SstableBuilder::new(&path).unwrap().with_block_size(64);
+        for i in 0..100u32 {
+            let key = format!("key{i:03}");
+            builder.put(key.as_bytes(), &[0; 16]).unwrap();
+        }
+        builder
+            .add(
+                b"key100",
+                &Entry {
+                    value: None,
+                    expires_at: None,
+                    separated: false,
+                    stamp: Some(WriteStamp {
+                        sequence: 7,
+                        written_at: 1_700_000_000_000,
+                    }),
+                },
+            )
+            .unwrap();
+        builder.delete_range(b"x", b"z");
+        builder.finish().unwrap();
+
+        let mut out = Vec::new();
+        let summary = dump_sstable(&path, false, DumpFormat::Text, &mut out).unwrap();
+        assert!(summary.is_clean());
+        assert_eq!(summary.records, 0, "Entries are only read when asked for");
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("metadata: entries=101 tombstones=1"));
+        assert!(text.contains("min_key=key000 max_key=key100"));
+        assert!(text.contains("range tombstone: x..z"));
+        assert!(text.contains("bloom: probes="));
+        assert!(!text.contains("entries:"));
+
+        let mut out = Vec::new();
+        let summary = dump_sstable(&path, true, DumpFormat::Json, &mut out).unwrap();
+        assert!(summary.is_clean());
+        assert_eq!(summary.records, 101);
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 102);
+        let table = &lines[0];
+        assert_eq!(table["entries"], 101);
+        let blocks = table["blocks"].as_array().unwrap();
+        assert!(blocks.len() > 1, "Small blocks split the table");
+        assert_eq!(blocks.last().unwrap()["last_key"], "key100");
+        assert!(table["bloom"]["false_positive_rate"].as_f64().unwrap() < 0.05);
+        assert_eq!(lines[1]["key"], "key000");
+        assert_eq!(lines[1]["type"], "put");
+        assert_eq!(lines[1]["value_size"], 16);
+        assert_eq!(lines[101]["type"], "delete");
+        assert_eq!(lines[101]["sequence"], 7);
+    }
+}
@@ -0,0 +1,158 @@
+//! Bloom filters which are persisted alongside each SSTable, see
+//! [`BloomFilter`].
+//!
+//! A filter is built from every key a table holds, tombstones included, as
+//! the table is written. As tables are immutable their filters never need to
+//! handle deletion: data which is deleted or compacted away simply drops out
+//! of the filters of the tables which replace it.
+//!
+//! An encoded filter is the number of probes made for each key followed by
+//! the bit array:
+//!
+//! ```text
+//! | probes (u8) | bits |
+//! ```
+
+use bytes::Bytes;
+
+/// A bloom filter over the keys of a single SSTable.
+///
+/// Checks can return false positives but never false negatives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    probes: u8,
+    bits: Bytes,
+}
+
+impl BloomFilter {
+    /// Build a filter from the [`hash`] of every key, using the given number
+    /// of bits for each.
+    pub(crate) fn build(hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits per key minimises the false positive rate.
+        let probes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        // Small filters would otherwise have a high false positive rate.
+        let len = (hashes.len() * bits_per_key).max(64).div_ceil(8);
+        let mut bits = vec![0u8; len];
+        let bit_count = (len * 8) as u64;
+        for hash in hashes {
+            for bit in probe_bits(*hash, probes, bit_count) {
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Self {
+            probes,
+            bits: bits.into(),
+        }
+    }
+
+    /// Whether the key may have been added to the filter.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let bit_count = (self.bits.len() * 8) as u64;
+        probe_bits(hash(key), self.probes, bit_count)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Number of bits set for each key.
+    pub fn probes(&self) -> u8 {
+        self.probes
+    }
+
+    /// Number of bits within the filter.
+    pub fn bits(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Expected rate of false positives once the given number of keys have
+    /// been added.
+    pub fn false_positive_rate(&self, keys: u64) -> f64 {
+        let probes = f64::from(self.probes);
+        let bits_per_key = self.bits() as f64 / keys.max(1) as f64;
+        (1.0 - (-probes / bits_per_key).exp()).powf(probes)
+    }
+
+    /// Size, in bytes, of the encoded filter.
+    pub fn size(&self) -> usize {
+        1 + self.bits.len()
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.probes);
+        buf.extend_from_slice(&self.bits);
+    }
+
+    pub(crate) fn decode(mut data: Bytes) -> Result<Self, &'static str> {
+        if data.len() < 2 {
+            return Err("bloom filter is truncated");
+        }
+        let probes = data[0];
+        if probes == 0 {
+            return Err("bloom filter makes no probes");
+        }
+        Ok(Self {
+            probes,
+            bits: data.split_off(1),
+        })
+    }
+}
+
+/// The bits which are set for a key, derived from two halves of its hash by
+/// double hashing.
+fn probe_bits(hash: u64, probes: u8, bit_count: u64) -> impl Iterator<Item = usize> {
+    let h1 = hash & 0xffff_ffff;
+    let h2 = hash >> 32;
+    (0..probes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+}
+
+/// Hash of a key, as stored in a filter. This is persisted so must never
+/// change.
+pub(crate) fn hash(key: &[u8]) -> u64 {
+    // FNV-1a, followed by a finalizer so that every bit of the result depends
+    // on every bit of the key.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::DEFAULT_BLOOM_BITS_PER_KEY;
+
+    #[test]
+    fn bloom_filter() {
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key{i}").into_bytes()).collect();
+        let hashes: Vec<u64> = keys.iter().map(|key| hash(key)).collect();
+        let filter = BloomFilter::build(&hashes, DEFAULT_BLOOM_BITS_PER_KEY);
+        for key in &keys {
+            assert!(filter.may_contain(key), "No false negatives");
+        }
+        let false_positives = (0..1000)
+            .filter(|i| filter.may_contain(format!("missing{i}").as_bytes()))
+            .count();
+        assert!(
+            false_positives < 50,
+            "Expected a false positive rate of roughly 1%, got {false_positives} in 1000"
+        );
+        let expected = filter.false_positive_rate(keys.len() as u64);
+        assert!(
+            (0.005..0.02).contains(&expected),
+            "Expected an estimate of roughly 1%, got {expected}"
+        );
+
+        let mut buf = Vec::new();
+        filter.encode(&mut buf);
+        assert_eq!(buf.len(), filter.size());
+        assert_eq!(BloomFilter::decode(buf.into()).unwrap(), filter);
+        assert!(BloomFilter::decode(Bytes::from_static(&[0, 1])).is_err());
+
+        let empty = BloomFilter::build(&[], DEFAULT_BLOOM_BITS_PER_KEY);
+        assert!(!empty.may_contain(b"key0"));
+    }
+}
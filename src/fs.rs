@@ -0,0 +1,77 @@
+//! Helpers for making changes to the working directory durable.
+//!
+//! Syncing a file only persists its contents. Creating, removing or renaming a
+//! file changes the directory holding it, which must be synced as well for the
+//! change to survive a crash.
+//!
+//! Files which must never be seen part way through being written are written
+//! to a [`temp_path`] first, then renamed into place once complete.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Suffix of files which are still being written, see [`temp_path`].
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Sync a directory, persisting the files which have been created, removed
+/// or renamed within it.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Sync the directory holding a file, see [`sync_dir`].
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+        _ => sync_dir(Path::new(".")),
+    }
+}
+
+/// Path which a file is written to before being renamed to `path`, so that
+/// a crash part way through never leaves an incomplete file at `path`.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(TEMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Move a completely written, and synced, temporary file to `path`, replacing
+/// any existing file, and sync the directory so that the rename is durable.
+pub(crate) fn publish(temp: &Path, path: &Path) -> io::Result<()> {
+    std::fs::rename(temp, path)?;
+    sync_parent(path)
+}
+
+/// Total size of the files within a directory and its subdirectories.
+pub(crate) fn directory_size(directory: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Remove the temporary files left within a directory by writes which were
+/// interrupted, returning how many were removed.
+pub(crate) fn remove_temp_files(directory: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        sync_dir(directory)?;
+    }
+    Ok(removed)
+}
@@ -0,0 +1,393 @@
+//! The gRPC API, served on the same port as the HTTP API, see [`routes`].
+//!
+//! The service is generated from `proto/chipmunk.proto` and shares the
+//! [`Chipmunk`] instance of the HTTP API, so writes through either are seen
+//! by both. Requests are told apart by their path, which for gRPC is always
+//! the name of the service followed by that of the method. Errors carry the
+//! same messages as the [`ErrorResponse`] of the HTTP API, with the status
+//! code closest to its [`ErrorCode`].
+
+use std::ops::Bound;
+use std::pin::Pin;
+use std::time::Duration;
+
+use axum::Router;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::server::NamedService;
+use tonic::{Code, Request, Response, Status};
+use tracing::warn;
+
+use crate::lsm::BatchWrite;
+use crate::memtable::unix_millis;
+use crate::server::{Chipmunk, ErrorCode, ErrorResponse};
+use crate::ChipmunkError;
+
+pub mod proto {
+    tonic::include_proto!("chipmunk.v1");
+}
+
+use proto::chipmunk_server::{self, ChipmunkServer};
+use proto::{
+    batch_write, scan_request, BatchRequest, DeleteRequest, GetRequest, GetResponse, KeyValue,
+    PutRequest, ScanRequest, WriteResponse,
+};
+
+/// Number of scanned entries buffered ahead of the client reading them.
+const SCAN_BUFFER: usize = 64;
+
+/// Routes serving the gRPC API, to be merged into the HTTP app.
+pub fn routes<S>(store: Chipmunk) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let path = format!("/{}/*method", ChipmunkServer::<GrpcService>::NAME);
+    Router::new().route_service(&path, ChipmunkServer::new(GrpcService { store }))
+}
+
+impl From<ErrorResponse> for Status {
+    fn from(e: ErrorResponse) -> Self {
+        let code = match e.code {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidKey => Code::InvalidArgument,
+            ErrorCode::Unauthorized => Code::Unauthenticated,
+            ErrorCode::NotFound => Code::NotFound,
+            ErrorCode::Conflict | ErrorCode::PreconditionFailed | ErrorCode::ReadOnly => {
+                Code::FailedPrecondition
+            }
+            ErrorCode::PayloadTooLarge | ErrorCode::WriteStall => Code::ResourceExhausted,
+            ErrorCode::Unavailable => Code::Unavailable,
+            ErrorCode::Timeout => Code::DeadlineExceeded,
+            ErrorCode::Gone => Code::OutOfRange,
+            ErrorCode::Internal => Code::Internal,
+        };
+        Status::new(code, e.message)
+    }
+}
+
+/// Implementation of the generated [`chipmunk_server::Chipmunk`] service.
+#[derive(Clone)]
+pub struct GrpcService {
+    store: Chipmunk,
+}
+
+#[tonic::async_trait]
+impl chipmunk_server::Chipmunk for GrpcService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let lookup = key.clone();
+        let stamped = self
+            .store
+            .blocking(move |store| store.get_stamped(lookup))
+            .await
+            .map_err(|e| {
+                warn!(key = ?String::from_utf8_lossy(&key), "Cannot get: {e}");
+                ErrorResponse::from(e)
+            })?;
+        let Some(stamped) = stamped else {
+            return Err(Status::not_found("Key not found"));
+        };
+        let now = unix_millis();
+        Ok(Response::new(GetResponse {
+            value: stamped.value,
+            ttl_seconds: stamped
+                .expires_at
+                .map(|expires_at| expires_at.saturating_sub(now).div_ceil(1000)),
+            sequence: stamped.stamp.map(|stamp| stamp.sequence),
+            created_at: stamped.stamp.map(|stamp| stamp.written_at),
+        }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<WriteResponse>, Status> {
+        let PutRequest {
+            key,
+            value,
+            ttl_seconds,
+        } = request.into_inner();
+        self.store.require_primary().map_err(ErrorResponse::from)?;
+        let writing = key.clone();
+        let written = self
+            .store
+            .blocking(move |store| match ttl_seconds {
+                Some(ttl) => store.insert_with_ttl(writing, value, Duration::from_secs(ttl)),
+                None => store.insert(writing, value),
+            })
+            .await;
+        match written {
+            Ok(sequence) => {
+                self.store.replicated(sequence).await;
+                Ok(Response::new(WriteResponse { sequence }))
+            }
+            Err(e) => {
+                warn!(key = ?String::from_utf8_lossy(&key), "Cannot insert: {e}");
+                Err(ErrorResponse::from(e).into())
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let key = request.into_inner().key;
+        self.store.require_primary().map_err(ErrorResponse::from)?;
+        let deleting = key.clone();
+        match self
+            .store
+            .blocking(move |store| store.delete(deleting))
+            .await
+        {
+            Ok(sequence) => {
+                self.store.replicated(sequence).await;
+                Ok(Response::new(WriteResponse { sequence }))
+            }
+            Err(e) => {
+                warn!(key = ?String::from_utf8_lossy(&key), "Cannot delete: {e}");
+                Err(ErrorResponse::from(e).into())
+            }
+        }
+    }
+
+    type ScanStream = Pin<Box<dyn Stream<Item = Result<KeyValue, Status>> + Send>>;
+
+    /// Entries are read from a snapshot on a blocking thread, which stops once
+    /// the limit is reached or the client goes away.
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest {
+            bounds,
+            limit,
+            keys_only,
+        } = request.into_inner();
+        let limit = match limit {
+            0 => usize::MAX,
+            limit => usize::try_from(limit).unwrap_or(usize::MAX),
+        };
+        let snapshot = self
+            .store
+            .blocking(|store| store.snapshot())
+            .await
+            .map_err(|e| {
+                warn!("Cannot scan: {e}");
+                ErrorResponse::from(e)
+            })?;
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let entries: Box<dyn Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>>> =
+                match bounds {
+                    Some(scan_request::Bounds::Prefix(prefix)) => {
+                        Box::new(snapshot.scan_prefix(&prefix))
+                    }
+                    Some(scan_request::Bounds::Range(range)) => {
+                        let start = if range.start.is_empty() {
+                            Bound::Unbounded
+                        } else {
+                            Bound::Included(Bytes::from(range.start))
+                        };
+                        let end = if range.end.is_empty() {
+                            Bound::Unbounded
+                        } else {
+                            Bound::Excluded(Bytes::from(range.end))
+                        };
+                        Box::new(snapshot.scan((start, end)))
+                    }
+                    None => Box::new(snapshot.scan(..)),
+                };
+            for entry in entries.take(limit) {
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("Cannot scan: {e}");
+                        let _ = sender.blocking_send(Err(ErrorResponse::from(e).into()));
+                        return;
+                    }
+                };
+                let entry = KeyValue {
+                    key: key.to_vec(),
+                    value: if keys_only {
+                        Vec::new()
+                    } else {
+                        value.to_vec()
+                    },
+                };
+                if sender.blocking_send(Ok(entry)).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    async fn batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        self.store.require_primary().map_err(ErrorResponse::from)?;
+        let mut writes = Vec::new();
+        for write in request.into_inner().writes {
+            writes.push(match write.write {
+                Some(batch_write::Write::Put(put)) => BatchWrite::Put {
+                    key: put.key,
+                    value: put.value,
+                    ttl: put.ttl_seconds.map(Duration::from_secs),
+                },
+                Some(batch_write::Write::Delete(delete)) => BatchWrite::Delete { key: delete.key },
+                None => return Err(Status::invalid_argument("Batch write has no operation")),
+            });
+        }
+        match self.store.blocking(|store| store.write_batch(writes)).await {
+            Ok(sequence) => {
+                self.store.replicated(sequence).await;
+                Ok(Response::new(WriteResponse { sequence }))
+            }
+            Err(e) => {
+                warn!("Cannot write batch: {e}");
+                Err(ErrorResponse::from(e).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    use super::proto::chipmunk_client::ChipmunkClient;
+    use super::proto::{BatchWrite, KeyRange};
+    use super::*;
+    use crate::config::{
+        ChipmunkConfig, CompactionConfig, HttpConfig, MemtableConfig, ReplicationConfig,
+        SstableConfig, WalConfig, WriteStallConfig,
+    };
+    use crate::server::new_app;
+
+    fn put(key: &[u8], value: &[u8]) -> PutRequest {
+        PutRequest {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl_seconds: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_alongside_http() {
+        let dir = TempDir::new("grpc_alongside_http").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024).with_max_value_size(16),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, new_app(Chipmunk::new(conf).unwrap()))
+                .await
+                .unwrap();
+        });
+        let mut client = ChipmunkClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+
+        let written = client.put(put(b"a", b"1")).await.unwrap().into_inner();
+        assert_eq!(written.sequence, 1);
+        let got = client
+            .get(GetRequest { key: b"a".to_vec() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(got.value, b"1");
+        assert_eq!(got.sequence, Some(1));
+        assert_eq!(got.ttl_seconds, None);
+        let http = reqwest::get(format!("http://{addr}/api/v1/a"))
+            .await
+            .unwrap();
+        assert_eq!(http.bytes().await.unwrap(), "1", "Writes are shared");
+
+        let missing = client
+            .get(GetRequest { key: b"b".to_vec() })
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), Code::NotFound);
+        let too_large = client.put(put(b"b", &[0; 17])).await.unwrap_err();
+        assert_eq!(too_large.code(), Code::ResourceExhausted);
+
+        let write = |write| BatchWrite { write: Some(write) };
+        let batch = BatchRequest {
+            writes: vec![
+                write(batch_write::Write::Put(put(b"b", b"2"))),
+                write(batch_write::Write::Put(put(b"c", b"3"))),
+                write(batch_write::Write::Put(put(b"d", b"4"))),
+                write(batch_write::Write::Delete(DeleteRequest {
+                    key: b"a".to_vec(),
+                })),
+            ],
+        };
+        assert_eq!(client.batch(batch).await.unwrap().into_inner().sequence, 5);
+        let empty = BatchRequest {
+            writes: vec![BatchWrite { write: None }],
+        };
+        assert_eq!(
+            client.batch(empty).await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+
+        let scanner = client.clone();
+        let scan = |bounds, limit, keys_only| {
+            let mut client = scanner.clone();
+            async move {
+                client
+                    .scan(ScanRequest {
+                        bounds,
+                        limit,
+                        keys_only,
+                    })
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .map(|entry| {
+                        let entry = entry.unwrap();
+                        (entry.key, entry.value)
+                    })
+                    .collect::<Vec<_>>()
+                    .await
+            }
+        };
+        let kv = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+        assert_eq!(
+            scan(None, 0, false).await,
+            vec![kv(b"b", b"2"), kv(b"c", b"3"), kv(b"d", b"4")]
+        );
+        let range = scan_request::Bounds::Range(KeyRange {
+            start: b"c".to_vec(),
+            end: Vec::new(),
+        });
+        assert_eq!(
+            scan(Some(range), 1, true).await,
+            vec![kv(b"c", b"")],
+            "Scans start from the range and stop at the limit"
+        );
+        let prefix = scan_request::Bounds::Prefix(b"d".to_vec());
+        assert_eq!(scan(Some(prefix), 0, false).await, vec![kv(b"d", b"4")]);
+
+        let deleted = client
+            .delete(DeleteRequest { key: b"b".to_vec() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(deleted.sequence, 6);
+        assert_eq!(scan(None, 0, true).await.len(), 2);
+    }
+}
@@ -1,14 +1,36 @@
 use std::io;
 use std::path::PathBuf;
 
-use axum::http::StatusCode;
+use crate::server::ErrorCode;
 
+pub mod build_info;
 pub mod client;
+pub mod compaction;
+pub mod comparator;
 pub mod config;
+pub mod debug;
+pub mod grpc;
+pub mod metrics;
+pub mod replication;
+pub mod resp;
 pub mod server;
+pub mod sstable;
+pub mod statistics;
+pub mod tls;
+pub mod transform;
+pub mod units;
 
+mod block_cache;
+mod filter;
+mod fs;
 mod lsm;
+mod manifest;
 mod memtable;
+mod sessions;
+mod shard;
+mod snapshot;
+mod table_cache;
+mod value_log;
 mod wal;
 
 #[derive(Debug, thiserror::Error)]
@@ -30,18 +52,125 @@ pub enum ChipmunkError {
 
     #[error("unable to open directory to restore: {0}")]
     WalRestoreDirectory(io::Error),
+
+    #[error("unable to write sstable: {0}")]
+    SstableWrite(io::Error),
+
+    #[error("unable to read sstable: {0}")]
+    SstableRead(io::Error),
+
+    #[error("sstable '{path}' is corrupt: {reason}")]
+    SstableCorrupt { path: PathBuf, reason: String },
+
+    #[error("keys must be added to sstable '{path}' in strictly ascending order")]
+    SstableUnsortedKey { path: PathBuf },
+
+    #[error("unable to write value log: {0}")]
+    ValueLogWrite(io::Error),
+
+    #[error("unable to read value log: {0}")]
+    ValueLogRead(io::Error),
+
+    #[error("value log '{path}' is corrupt: {reason}")]
+    ValueLogCorrupt { path: PathBuf, reason: String },
+
+    #[error("unable to write manifest: {0}")]
+    ManifestWrite(io::Error),
+
+    #[error("unable to read manifest: {0}")]
+    ManifestRead(io::Error),
+
+    #[error("'{path}' was written in unsupported format version {version}")]
+    UnsupportedFormatVersion { path: PathBuf, version: u32 },
+
+    #[error("writes are stopped until flushes and compaction catch up")]
+    WriteStall,
+
+    #[error("value of {size} bytes exceeds the maximum of {max} bytes")]
+    ValueTooLarge { size: usize, max: usize },
+
+    #[error("'{path}' is already open by another instance")]
+    DirectoryLocked { path: PathBuf },
+
+    #[error("{0} is not supported while running in memory only")]
+    InMemory(&'static str),
+
+    #[error("unable to create checkpoint at '{path}': {source}")]
+    Checkpoint { source: io::Error, path: PathBuf },
+
+    #[error("unable to load TLS certificate or key from '{path}': {reason}")]
+    TlsLoad { path: PathBuf, reason: String },
+
+    #[error("unable to serve: {0}")]
+    Serve(io::Error),
+
+    #[error("unable to write dump: {0}")]
+    DumpWrite(io::Error),
+
+    #[error("unable to read config file '{path}': {source}")]
+    ConfigRead { source: io::Error, path: PathBuf },
+
+    #[error("config file '{path}' is invalid: {reason}")]
+    InvalidConfig { path: PathBuf, reason: String },
+
+    #[error("no config file was given to reload settings from")]
+    NoConfigFile,
+
+    #[error("changes after sequence number {after} are no longer retained")]
+    ChangesNotRetained { after: u64 },
+
+    #[error("expected the change with sequence number {expected}, received {found}")]
+    ReplicationGap { expected: u64, found: u64 },
+
+    #[error("unable to replicate from the primary: {0}")]
+    Replication(String),
+
+    #[error("this store is a read-only replica, writes are sent to the primary at {primary}")]
+    ReadOnlyReplica { primary: String },
+
+    #[error("{0} is not supported while the store is split into shards")]
+    Sharded(&'static str),
+
+    #[error("'{path}' was written with {recorded} shard(s), not the {configured} configured")]
+    ShardCountMismatch {
+        path: PathBuf,
+        recorded: usize,
+        configured: usize,
+    },
+
+    #[error("the store can only be restored while its WAL segment and memtable are empty")]
+    RestoreNotEmpty,
+
+    #[error("unable to start background worker: {0}")]
+    BackgroundWorker(io::Error),
+
+    #[error("unable to decode value: {0}")]
+    ValueDecode(String),
+
+    #[error("unable to generate a session id: {0}")]
+    SessionId(getrandom::Error),
 }
 
 impl ChipmunkError {
-    fn as_status_code(&self) -> StatusCode {
-        // NOTE: new errors may not always be surfaced as an internal server
-        // error so we can allow this for now.
-        #[allow(clippy::match_single_binding)]
+    /// The [`ErrorCode`] reported to clients, which decides the status of the
+    /// response.
+    pub fn error_code(&self) -> ErrorCode {
         match self {
-            // The internal error should be masked. We do not want to leak
-            // errors relating to underlying k-v operations over the outward
-            // facing HTTP API.
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+            // Writes may succeed once the engine has caught up.
+            ChipmunkError::WriteStall => ErrorCode::WriteStall,
+            ChipmunkError::ValueTooLarge { .. } => ErrorCode::PayloadTooLarge,
+            // The operation does not apply to how the engine is running.
+            ChipmunkError::InMemory(_) | ChipmunkError::Sharded(_) => ErrorCode::Conflict,
+            // The settings in place are kept, and the file needs fixing.
+            ChipmunkError::ConfigRead { .. }
+            | ChipmunkError::InvalidConfig { .. }
+            | ChipmunkError::NoConfigFile => ErrorCode::Conflict,
+            // Only a copy of the whole store can catch the reader up.
+            ChipmunkError::ChangesNotRetained { .. } => ErrorCode::Gone,
+            ChipmunkError::ReadOnlyReplica { .. } => ErrorCode::ReadOnly,
+            // Restoring would replay the WAL over the writes already held.
+            ChipmunkError::RestoreNotEmpty => ErrorCode::Conflict,
+            _ => ErrorCode::Internal,
         }
     }
 }
@@ -1,449 +1,5129 @@
 #![allow(dead_code)]
 
+use std::collections::VecDeque;
+use std::fs::{File, TryLockError};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use bloomfx::BloomFilter;
 use bytes::Bytes;
-use fxhash::FxHashMap;
-use parking_lot::Mutex;
-use tracing::{debug, error, info};
+use parking_lot::{Condvar, Mutex, RwLock};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::{MemtableConfig, WalConfig},
-    memtable::Memtable,
-    wal::{Wal, WalEntry},
+    block_cache::BlockCache,
+    compaction::{CompactionDecision, CompactionFilter, CompactionStats, MergingIter, Throttle},
+    comparator::{self, Comparator},
+    config::{
+        CompactionConfig, InMemory, MemtableConfig, Options, SstableConfig, WalConfig,
+        WriteStallConfig,
+    },
+    fs,
+    manifest::{self, Manifest, Version, VersionEdit, LEVEL_1, LEVEL_2},
+    memtable::{expiry_after, unix_millis, Entry, Memtable, RangeTombstone, WriteStamp},
+    metrics::{Exposition, Histogram, Metrics},
+    snapshot::Snapshot,
+    sstable::{self, Sstable, SstableBuilder},
+    statistics::{LevelStats, Statistics, TreeStats},
+    table_cache::TableCache,
+    transform::ValueTransform,
+    value_log::{self, ValueLog, ValueLogReader, ValuePointer},
+    wal::{self, Checksum, SegmentReader, Wal, WalEntry, WalRecord},
     ChipmunkError,
 };
 
+/// Name of the file which is locked while a directory is open, see
+/// [`Lsm::open`].
+pub const LOCK_FILE_NAME: &str = "LOCK";
+
+/// A source of entries, sorted by key, which is merged into a scan.
+pub(crate) type ScanSource<'a> =
+    Box<dyn Iterator<Item = Result<(Bytes, Entry), ChipmunkError>> + 'a>;
+
+/// A value read through [`Lsm::get_stamped`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StampedValue {
+    pub value: Vec<u8>,
+    /// Unix timestamp, in milliseconds, from which the value expires.
+    pub expires_at: Option<u64>,
+    /// The write which produced the value, `None` for values written before
+    /// stamps were recorded.
+    pub stamp: Option<WriteStamp>,
+}
+
+/// A single write within [`Lsm::write_batch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchWrite {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+}
+
+/// Number of changes held for each subscriber which has yet to receive them,
+/// see [`Lsm::subscribe`], and of the most recent changes retained for
+/// subscribers to resume from, see [`Lsm::subscribe_after`].
+pub const CHANGE_BUFFER: usize = 1024;
+
+/// Widen the span of keys, as returned by
+/// [`SstableMetadata::key_span`](crate::sstable::SstableMetadata::key_span),
+/// to cover another.
+fn widen_span(
+    span: Option<(Bytes, Bytes)>,
+    other: Option<(Bytes, Bytes)>,
+    comparator: &dyn Comparator,
+) -> Option<(Bytes, Bytes)> {
+    match (span, other) {
+        (Some((min, max)), Some((start, end))) => Some((
+            if comparator.compare(&start, &min).is_lt() {
+                start
+            } else {
+                min
+            },
+            if comparator.compare(&end, &max).is_gt() {
+                end
+            } else {
+                max
+            },
+        )),
+        (span, other) => span.or(other),
+    }
+}
+
+/// Compaction trigger reported when L2 holds more than the configured
+/// `max_l2_files`, which merges them together.
+const L2_FILE_COUNT_TRIGGER: &str = "l2 file count";
+
+/// A write applied to the tree, see [`Lsm::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub stamp: WriteStamp,
+    pub kind: ChangeKind,
+}
+
+/// A subscription resuming after a sequence number, see
+/// [`Lsm::subscribe_after`].
+#[derive(Debug)]
+pub struct Resumed {
+    /// Number of writes after the sequence number which are no longer
+    /// retained, so cannot be replayed.
+    pub missed: u64,
+    /// Retained changes after the sequence number, in order.
+    pub replayed: Vec<Change>,
+    /// Every change following those replayed.
+    pub changes: broadcast::Receiver<Change>,
+}
+
+/// The changes which the WAL holds after a sequence number, followed by
+/// every change made from then on, see [`Lsm::wal_changes_after`].
+pub struct WalChanges {
+    /// Changes read back from the WAL, in order.
+    pub backlog: WalBacklog,
+    /// Sequence number of the most recent write when the WAL was read.
+    pub latest: u64,
+    /// Every change following those of the backlog.
+    pub changes: broadcast::Receiver<Change>,
+}
+
+impl WalChanges {
+    /// Take the first change of the backlog, after the given sequence
+    /// number, failing with [`ChipmunkError::ChangesNotRetained`] when the
+    /// WAL no longer holds every change after it.
+    pub fn first_after(&mut self, sequence: u64) -> Result<Option<Change>, ChipmunkError> {
+        let first = self.backlog.next().transpose()?;
+        let retained = match &first {
+            Some(change) => change.stamp.sequence == sequence + 1,
+            None => self.latest <= sequence,
+        };
+        if !retained {
+            return Err(ChipmunkError::ChangesNotRetained { after: sequence });
+        }
+        Ok(first)
+    }
+}
+
+/// Reads the changes after a sequence number back from the segments of the
+/// WAL, see [`WalChanges`].
+///
+/// Changes held by segments which were removed before the read began, once
+/// their memtables were flushed, are not seen, so the first change may not
+/// follow the sequence number asked for.
+pub struct WalBacklog {
+    /// Segments holding the changes, oldest first. Each was opened while the
+    /// WAL was held, so removing it once flushed does not affect the read.
+    segments: VecDeque<SegmentReader>,
+    /// Length of the last segment when the WAL was held. Changes appended
+    /// past it are received by the subscription instead.
+    end: u64,
+    /// Sequence number of the last change returned, before which changes
+    /// are skipped, as restoring the WAL copies them to the active segment.
+    last: u64,
+    value_transform: Option<Arc<dyn ValueTransform>>,
+}
+
+impl Iterator for WalBacklog {
+    type Item = Result<Change, ChipmunkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let last_segment = self.segments.len() == 1;
+            let record = self.segments.front_mut()?.next_record();
+            let entry = match record {
+                Some(Ok(WalRecord {
+                    offset,
+                    entry,
+                    checksum,
+                })) if !matches!(checksum, Checksum::Mismatch { .. })
+                    && (!last_segment || offset < self.end) =>
+                {
+                    entry
+                }
+                // Whatever follows a damaged record is unreadable, leaving a
+                // gap in the sequence numbers which the reader can detect.
+                _ => {
+                    self.segments.pop_front();
+                    continue;
+                }
+            };
+            // Entries written before writes were stamped cannot be placed.
+            let WalEntry::Stamped { stamp, entry } = entry else {
+                continue;
+            };
+            if stamp.sequence <= self.last {
+                continue;
+            }
+            let Some(kind) = ChangeKind::from_entry(*entry) else {
+                continue;
+            };
+            self.last = stamp.sequence;
+            let kind = match (kind, &self.value_transform) {
+                (
+                    ChangeKind::Put {
+                        key,
+                        value,
+                        expires_at,
+                    },
+                    Some(transform),
+                ) => match transform.decode(value.to_vec()) {
+                    Ok(value) => ChangeKind::Put {
+                        key,
+                        value: value.into(),
+                        expires_at,
+                    },
+                    Err(e) => return Some(Err(e)),
+                },
+                (kind, _) => kind,
+            };
+            return Some(Ok(Change { stamp, kind }));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Put {
+        key: Bytes,
+        value: Bytes,
+        /// Unix timestamp, in milliseconds, from which the value expires.
+        expires_at: Option<u64>,
+    },
+    Delete {
+        key: Bytes,
+    },
+    /// Every key from `start`, inclusive, up to `end`, exclusive, was deleted.
+    DeleteRange {
+        start: Bytes,
+        end: Bytes,
+    },
+}
+
+impl ChangeKind {
+    /// The change recorded by an entry of the WAL, `None` for a stamped
+    /// entry as stamps are kept apart from the change.
+    pub(crate) fn from_entry(entry: WalEntry) -> Option<Self> {
+        Some(match entry {
+            WalEntry::Put { key, value } => ChangeKind::Put {
+                key: key.into(),
+                value: value.into(),
+                expires_at: None,
+            },
+            WalEntry::PutWithExpiry {
+                key,
+                value,
+                expires_at,
+            } => ChangeKind::Put {
+                key: key.into(),
+                value: value.into(),
+                expires_at: Some(expires_at),
+            },
+            WalEntry::Delete { key } => ChangeKind::Delete { key: key.into() },
+            WalEntry::DeleteRange { start, end } => ChangeKind::DeleteRange {
+                start: start.into(),
+                end: end.into(),
+            },
+            WalEntry::Stamped { .. } => return None,
+        })
+    }
+
+    /// The entry of the WAL recording the change.
+    pub(crate) fn into_entry(self) -> WalEntry {
+        match self {
+            ChangeKind::Put {
+                key,
+                value,
+                expires_at: Some(expires_at),
+            } => WalEntry::PutWithExpiry {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                expires_at,
+            },
+            ChangeKind::Put { key, value, .. } => WalEntry::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            ChangeKind::Delete { key } => WalEntry::Delete { key: key.to_vec() },
+            ChangeKind::DeleteRange { start, end } => WalEntry::DeleteRange {
+                start: start.to_vec(),
+                end: end.to_vec(),
+            },
+        }
+    }
+}
+
+/// A [`Memtable`] which has been rotated out and is awaiting a flush.
+struct FrozenMemtable {
+    memtable: Arc<Memtable>,
+    /// ID of the WAL segment which was active when the memtable was frozen.
+    /// Segments before this only contain data held by this memtable, or by
+    /// those which were flushed before it.
+    wal_segment: u64,
+}
+
+/// Flushes and compaction which have been handed off by the write path,
+/// see [`Lsm::enable_background_work`].
+#[derive(Default)]
+pub struct BackgroundWork {
+    pending: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl BackgroundWork {
+    /// Block until work is pending, or the timeout elapses, returning whether
+    /// there is work to do. The pending work is taken, so it should be
+    /// followed by [`Lsm::run_background_work`].
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let mut pending = self.pending.lock();
+        if !*pending {
+            self.wake.wait_for(&mut pending, timeout);
+        }
+        std::mem::take(&mut *pending)
+    }
+
+    fn notify(&self) {
+        *self.pending.lock() = true;
+        self.wake.notify_one();
+    }
+}
+
+/// Pressure on the write path from data which is awaiting a flush or
+/// compaction, see [`WriteStallConfig`]. Ordered from the least pressure to
+/// the most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WriteStall {
+    Normal,
+    /// Each write is delayed to let flushes and compaction catch up.
+    Slowdown,
+    /// Writes wait for flushes and compaction to run, and are rejected if
+    /// that does not relieve the pressure.
+    Stop,
+}
+
 pub struct Lsm {
+    /// Sequence number of the most recent write. Writes hold the lock from
+    /// their WAL append until they are visible within the memtable, so that
+    /// a [`Snapshot`] sees every write up to its sequence number.
+    sequence: Mutex<u64>,
+
     /// Write-ahead Log (WAL) which backs the operations performed on the LSM
-    /// storage engine.
-    wal: Mutex<Wal>,
+    /// storage engine, `None` when running in memory only.
+    wal: Option<Mutex<Wal>>,
     /// The configuration which was used to initialise the [`Wal`].
     wal_config: WalConfig,
 
     /// Currently active [`Memtable`]
-    memtable: Memtable,
+    memtable: RwLock<Arc<Memtable>>,
+    /// Memtables which have been rotated out and are awaiting a flush, oldest
+    /// first. These remain readable until their SSTable has been registered.
+    immutable_memtables: RwLock<Vec<FrozenMemtable>>,
     /// The configuration which was used to initialise the [`Memtable`].
     memtable_config: MemtableConfig,
+    /// The configuration used when writing SSTables.
+    sstable_config: SstableConfig,
+    /// Thresholds at which compaction is triggered, which may be changed
+    /// while the tree runs.
+    compaction_config: RwLock<CompactionConfig>,
+    /// Limits beyond which writes are slowed down or stopped, which may be
+    /// changed while the tree runs.
+    write_stall_config: RwLock<WriteStallConfig>,
+    /// Age at which the active memtable is flushed by
+    /// [`Lsm::flush_if_due`], which may be changed while the tree runs.
+    flush_interval: RwLock<Option<Duration>>,
+    /// When the active memtable was created, from which its age is measured.
+    memtable_created: Mutex<Instant>,
 
+    /// Held while memtables are flushed, so that no memtable is flushed twice
+    /// by concurrent callers. Taken before any of the other locks.
+    flush_lock: Mutex<()>,
+    /// Set once flushes are handed off to a background worker rather than
+    /// run by the write path.
+    background_work: Option<Arc<BackgroundWork>>,
+
+    /// Log of the SSTables which make up each level, replayed on restore.
+    manifest: Mutex<Manifest>,
     /// IDs of the now immutable memtables
     sstables: Mutex<Vec<u64>>,
-
-    bloom: Mutex<BloomFilter<Vec<u8>>>,
+    /// Decoded SSTable blocks, shared by all reads.
+    block_cache: Arc<BlockCache>,
+    /// Open SSTable readers, bounded by the configured maximum open files.
+    table_cache: TableCache,
+    /// Large values which have been separated from their SSTables.
+    value_log: ValueLog,
 
     l2_id: AtomicU64,
     l2_files: Mutex<Vec<u64>>,
+    /// Work performed by every compaction cycle so far.
+    compaction_stats: Mutex<CompactionStats>,
+    /// Metrics shared with the WAL, behind [`Lsm::statistics`].
+    metrics: Arc<Metrics>,
 
     working_directory: PathBuf,
+
+    /// Optional transformation applied to values on write and reversed on
+    /// read.
+    value_transform: RwLock<Option<Arc<dyn ValueTransform>>>,
+    /// Optional hook deciding the fate of entries as they are compacted.
+    compaction_filter: RwLock<Option<Arc<dyn CompactionFilter>>>,
+    /// Ordering of keys throughout the tree.
+    comparator: RwLock<Arc<dyn Comparator>>,
+    /// Every applied write is sent to the subscribers, see [`Lsm::subscribe`].
+    changes: broadcast::Sender<Change>,
+    /// The most recent changes, for subscribers to resume from. Changes are
+    /// sent while this is held, so that it is consistent with subscribing.
+    recent_changes: Mutex<VecDeque<Change>>,
+    /// Whether anything has subscribed, from when changes are built, even
+    /// while nothing is subscribed, so that they can be resumed from.
+    watching: AtomicBool,
+    /// Held for as long as the tree is open when it was opened through
+    /// [`Lsm::open`], keeping other instances out of the directory.
+    directory_lock: Option<File>,
 }
 
 impl Lsm {
-    pub fn new(wal_config: WalConfig, memtable_config: MemtableConfig) -> Self {
-        Self {
-            wal: Wal::new(
-                wal_config.id,
+    pub fn new(
+        wal_config: WalConfig,
+        memtable_config: MemtableConfig,
+        sstable_config: SstableConfig,
+        compaction_config: CompactionConfig,
+        write_stall_config: WriteStallConfig,
+    ) -> Result<Self, ChipmunkError> {
+        let block_cache = Arc::new(BlockCache::new(sstable_config.block_cache_capacity));
+        Self::with_shared(
+            wal_config,
+            memtable_config,
+            sstable_config,
+            compaction_config,
+            write_stall_config,
+            Arc::new(Metrics::default()),
+            block_cache,
+        )
+    }
+
+    /// Create a tree which counts its work within the given [`Metrics`] and
+    /// caches data blocks within the given [`BlockCache`], so that they can
+    /// be shared by every shard of a
+    /// [`ShardedLsm`](crate::shard::ShardedLsm).
+    pub(crate) fn with_shared(
+        wal_config: WalConfig,
+        memtable_config: MemtableConfig,
+        sstable_config: SstableConfig,
+        compaction_config: CompactionConfig,
+        write_stall_config: WriteStallConfig,
+        metrics: Arc<Metrics>,
+        block_cache: Arc<BlockCache>,
+    ) -> Result<Self, ChipmunkError> {
+        let (manifest, wal, value_log) = if memtable_config.in_memory.is_some() {
+            info!("Keeping every write in memory only");
+            (Manifest::in_memory(), None, ValueLog::in_memory())
+        } else {
+            let manifest = Manifest::open(&wal_config.log_directory)?;
+            // The active segment must follow the checkpoint, otherwise it would
+            // be removed rather than replayed by the next restore, and every
+            // segment awaiting replay.
+            let wal_id = wal::next_segment_id(
+                &wal_config.log_directory,
+                wal_config.id.max(manifest.version().wal_checkpoint()),
+            );
+            let wal = Wal::try_new(
+                wal_id,
                 &wal_config.log_directory,
                 wal_config.max_size,
                 wal_config.buffer_size,
-            )
-            .into(),
-            memtable: Memtable::new(memtable_config.id, memtable_config.max_size),
+            )?
+            .with_metrics(Arc::clone(&metrics))
+            .with_retention(wal_config.retention);
+            let value_log = ValueLog::open(&wal_config.log_directory)?;
+            (manifest, Some(wal.into()), value_log)
+        };
+        Ok(Self {
+            sequence: Mutex::new(0),
+            wal,
+            memtable: RwLock::new(Arc::new(Memtable::new(
+                memtable_config.id,
+                memtable_config.max_size,
+            ))),
+            immutable_memtables: Vec::new().into(),
+            flush_lock: Mutex::new(()),
+            background_work: None,
+            manifest: manifest.into(),
             sstables: Vec::new().into(),
+            block_cache: Arc::clone(&block_cache),
+            table_cache: TableCache::new(
+                &wal_config.log_directory,
+                sstable_config.max_open_files,
+                block_cache,
+            ),
+            value_log,
             l2_id: AtomicU64::new(0),
             l2_files: Vec::new().into(),
+            compaction_stats: Mutex::default(),
+            metrics,
             working_directory: wal_config.log_directory.clone(),
+            flush_interval: RwLock::new(memtable_config.flush_interval),
+            memtable_created: Mutex::new(Instant::now()),
             memtable_config,
+            sstable_config,
+            compaction_config: RwLock::new(compaction_config),
+            write_stall_config: RwLock::new(write_stall_config),
+            wal_config,
+            value_transform: RwLock::new(None),
+            compaction_filter: RwLock::new(None),
+            comparator: RwLock::new(comparator::bytewise()),
+            changes: broadcast::channel(CHANGE_BUFFER).0,
+            recent_changes: Mutex::new(VecDeque::new()),
+            watching: AtomicBool::new(false),
+            directory_lock: None,
+        })
+    }
+
+    /// Open the tree held within `directory`, creating the directory if it
+    /// does not yet exist, and restore everything it holds.
+    ///
+    /// The directory is locked for as long as the [`Lsm`] is open, so a second
+    /// instance cannot open it at the same time. Rather than being configured,
+    /// the IDs of the next WAL segment and memtable are chosen to follow every
+    /// segment, SSTable and value log file already within the directory, so
+    /// reopening never collides with the files of a previous run.
+    pub fn open(directory: &Path, options: Options) -> Result<Self, ChipmunkError> {
+        let directory_err = |source| ChipmunkError::WalDirectoryOpen {
+            source,
+            path: directory.to_path_buf(),
+        };
+        std::fs::create_dir_all(directory)
+            .and_then(|_| fs::sync_parent(directory))
+            .map_err(directory_err)?;
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(directory.join(LOCK_FILE_NAME))
+            .map_err(directory_err)?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(ChipmunkError::DirectoryLocked {
+                    path: directory.to_path_buf(),
+                })
+            }
+            Err(TryLockError::Error(source)) => return Err(directory_err(source)),
+        }
+
+        let wal_config = WalConfig {
+            retention: options.wal_retention,
+            ..WalConfig::new(
+                0,
+                options.wal_max_size,
+                directory.to_path_buf(),
+                options.wal_buffer_size,
+            )
+        };
+        let memtable_config = MemtableConfig {
+            max_value_size: options.max_value_size,
+            ..MemtableConfig::new(0, options.memtable_max_size)
+                .with_max_immutable_memtables(options.max_immutable_memtables)
+        };
+        let mut lsm = Self::new(
             wal_config,
-            bloom: BloomFilter::new(10000, 2).into(),
+            memtable_config,
+            options.sstable,
+            options.compaction,
+            options.write_stall,
+        )?;
+        lsm.directory_lock = Some(lock);
+        lsm.set_comparator(options.comparator);
+        lsm.restore()?;
+        info!(
+            directory = %directory.display(),
+            wal_segment = lsm.wal_segment(),
+            memtable_id = lsm.memtable_id(),
+            "Opened LSM-tree"
+        );
+        Ok(lsm)
+    }
+
+    /// Record the SSTables within the working directory in the manifest when it
+    /// has never recorded anything, as is the case for directories written
+    /// before it existed.
+    ///
+    /// Once the manifest is in use it is the only record of the live files, so
+    /// any others are left behind by an interrupted flush or compaction and
+    /// are not read.
+    fn record_unmanaged_sstables(&self) -> Result<(), ChipmunkError> {
+        let mut manifest = self.manifest.lock();
+        let mut edits = Vec::new();
+        for level in [LEVEL_1, LEVEL_2] {
+            let mut ids: Vec<u64> = std::fs::read_dir(&self.working_directory)
+                .map_err(ChipmunkError::WalRestoreDirectory)?
+                .flatten()
+                .filter_map(|entry| manifest::file_id(level, &entry.file_name().to_string_lossy()))
+                .filter(|id| manifest.version().metadata(level, *id).is_none())
+                .collect();
+            ids.sort_unstable();
+            for id in ids {
+                let path = self.working_directory.join(manifest::file_name(level, id));
+                if *manifest.version() != Version::default() {
+                    warn!(file = %path.display(), "Ignoring SSTable missing from the manifest");
+                    continue;
+                }
+                let table = Sstable::open(&path)?.with_comparator(self.comparator());
+                edits.push(VersionEdit::AddFile {
+                    level,
+                    id,
+                    metadata: table.metadata().clone(),
+                });
+            }
+        }
+        if !edits.is_empty() {
+            info!(files = edits.len(), "Recording SSTables in the manifest");
+            manifest.apply(&edits)?;
+        }
+        Ok(())
+    }
+
+    /// The lowest memtable ID which follows every L1 SSTable and value log
+    /// file, including any left behind on disk without being recorded by the
+    /// manifest, so that flushing the memtable overwrites neither.
+    fn next_memtable_id(&self) -> u64 {
+        let on_disk = std::fs::read_dir(&self.working_directory)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| manifest::file_id(LEVEL_1, &entry.file_name().to_string_lossy()));
+        self.manifest
+            .lock()
+            .version()
+            .files(LEVEL_1)
+            .into_iter()
+            .chain(on_disk)
+            .chain(self.value_log.file_ids())
+            .map(|id| id + 1)
+            .fold(0, u64::max)
+    }
+
+    /// Set the [`ValueTransform`] used for values passing through the [`Lsm`].
+    ///
+    /// This should be set before any data is written, values which were
+    /// persisted under a different transform will not be decoded correctly.
+    pub fn set_value_transform(&self, transform: Arc<dyn ValueTransform>) {
+        *self.value_transform.write() = Some(transform);
+    }
+
+    /// Set the [`Comparator`] which orders keys throughout the [`Lsm`].
+    ///
+    /// This must be set before any data is written or restored, SSTables which
+    /// were written under a different ordering will not be read correctly.
+    pub fn set_comparator(&self, comparator: Arc<dyn Comparator>) {
+        let mut active = self.memtable.write();
+        *active = Arc::new(Memtable::with_comparator(
+            active.id(),
+            self.memtable_config.max_size,
+            Arc::clone(&comparator),
+        ));
+        self.table_cache.set_comparator(Arc::clone(&comparator));
+        *self.comparator.write() = comparator;
+    }
+
+    /// Set the [`CompactionFilter`] which every live entry is passed through
+    /// as it is compacted.
+    pub fn set_compaction_filter(&self, filter: Arc<dyn CompactionFilter>) {
+        *self.compaction_filter.write() = Some(filter);
+    }
+
+    /// The thresholds at which compaction is triggered.
+    pub fn compaction_config(&self) -> CompactionConfig {
+        self.compaction_config.read().clone()
+    }
+
+    /// Change the thresholds at which compaction is triggered, which apply
+    /// from the next time they are checked.
+    pub fn set_compaction_config(&self, config: CompactionConfig) {
+        *self.compaction_config.write() = config;
+    }
+
+    /// The limits beyond which writes are slowed down or stopped.
+    pub fn write_stall_config(&self) -> WriteStallConfig {
+        self.write_stall_config.read().clone()
+    }
+
+    /// Change the limits beyond which writes are slowed down or stopped,
+    /// which apply from the next write.
+    pub fn set_write_stall_config(&self, config: WriteStallConfig) {
+        *self.write_stall_config.write() = config;
+    }
+
+    /// The age at which the active memtable is flushed by
+    /// [`Lsm::flush_if_due`].
+    pub fn flush_interval(&self) -> Option<Duration> {
+        *self.flush_interval.read()
+    }
+
+    /// Change the age at which the active memtable is flushed, never when
+    /// `None`.
+    pub fn set_flush_interval(&self, flush_interval: Option<Duration>) {
+        *self.flush_interval.write() = flush_interval;
+    }
+
+    /// Capacity, in bytes, of the cache of SSTable data blocks.
+    pub fn block_cache_capacity(&self) -> usize {
+        self.block_cache.capacity()
+    }
+
+    /// Change the capacity, in bytes, of the cache of SSTable data blocks,
+    /// evicting blocks straight away if it shrinks.
+    pub fn set_block_cache_capacity(&self, capacity: usize) {
+        self.block_cache.set_capacity(capacity);
+    }
+
+    /// Size, in bytes, of the buffer WAL appends are held in, `None` when
+    /// there is no WAL.
+    pub fn wal_buffer_size(&self) -> Option<usize> {
+        self.wal.as_ref().map(|wal| wal.lock().buffer_size())
+    }
+
+    /// Change the size, in bytes, of the buffer WAL appends are held in, the
+    /// default when `None`. Appends already buffered beyond the new size are
+    /// written out.
+    pub fn set_wal_buffer_size(&self, buffer_size: Option<usize>) -> Result<(), ChipmunkError> {
+        match &self.wal {
+            Some(wal) => wal.lock().set_buffer_size(buffer_size),
+            None => Ok(()),
         }
     }
 
-    /// Insert an item into the [`Lsm`] tree.
+    /// The [`ValueTransform`] in use, if any.
+    fn value_transform(&self) -> Option<Arc<dyn ValueTransform>> {
+        self.value_transform.read().clone()
+    }
+
+    /// The [`Comparator`] ordering keys throughout the tree.
+    pub(crate) fn comparator(&self) -> Arc<dyn Comparator> {
+        Arc::clone(&self.comparator.read())
+    }
+
+    /// Hand flushes and compaction off to a background worker, so that writes
+    /// return once they are in the WAL and memtable.
+    ///
+    /// The write path then only freezes a full memtable and signals the
+    /// returned [`BackgroundWork`], which the worker waits on before calling
+    /// [`Lsm::run_background_work`]. Writes fall back to flushing themselves
+    /// if more than `max_immutable_memtables` are left waiting, so a worker
+    /// which falls behind cannot grow memory without bound.
+    pub fn enable_background_work(&mut self) -> Arc<BackgroundWork> {
+        Arc::clone(
+            self.background_work
+                .get_or_insert_with(|| Arc::new(BackgroundWork::default())),
+        )
+    }
+
+    /// Flush every frozen memtable, removing the WAL segments they held, then
+    /// compact if any of the thresholds in the [`CompactionConfig`] have been
+    /// exceeded and collect value log garbage, see
+    /// [`Lsm::collect_value_log_garbage`]. This is the work handed off by
+    /// [`Lsm::enable_background_work`].
+    pub fn run_background_work(&self) -> Result<(), ChipmunkError> {
+        self.flush_immutable_memtables(0)?;
+        self.maybe_compact()?;
+        self.collect_value_log_garbage()?;
+        Ok(())
+    }
+
+    /// Insert an item into the [`Lsm`] tree, returning the sequence number
+    /// assigned to the write.
     ///
     /// A [`WalEntry`] is appended into the WAL before proceeding to insert the
     /// key-value pair into an in-memory index, the L0 [`Memtable`].
-    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), ChipmunkError> {
-        let entry = WalEntry::Put {
-            key: key.to_vec(),
-            value: value.to_vec(),
+    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64, ChipmunkError> {
+        self.put(key, value, None)
+    }
+
+    /// Insert an item into the [`Lsm`] tree which expires once the given
+    /// time-to-live has elapsed.
+    ///
+    /// Expired entries are treated as absent on reads and are dropped when they
+    /// are flushed or compacted.
+    pub fn insert_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<u64, ChipmunkError> {
+        let expires_at = expiry_after(ttl);
+        self.put(key, value, Some(expires_at))
+    }
+
+    /// Shared write path for insertions, with an optional expiry as a unix
+    /// timestamp in milliseconds.
+    fn put(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<u64, ChipmunkError> {
+        self.metrics.put_latency.time(|| {
+            self.check_value_size(&value)?;
+            self.throttle_write()?;
+            let value = self.encode_value(value);
+            let stamp = self.apply_put(&mut self.sequence.lock(), key, value, expires_at)?;
+            self.maybe_rotate_memtable()?;
+
+            Ok(stamp.sequence)
+        })
+    }
+
+    /// Reject a value beyond the configured `max_value_size`, before it is
+    /// transformed.
+    pub(crate) fn check_value_size(&self, value: &[u8]) -> Result<(), ChipmunkError> {
+        match self.memtable_config.max_value_size {
+            Some(max) if value.len() > max => Err(ChipmunkError::ValueTooLarge {
+                size: value.len(),
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the [`ValueTransform`], if there is one, to a value being written.
+    fn encode_value(&self, value: Vec<u8>) -> Vec<u8> {
+        match self.value_transform() {
+            Some(transform) => transform.encode(value),
+            None => value,
+        }
+    }
+
+    /// Reverse the [`ValueTransform`], if there is one, of a stored value.
+    fn decode_value(&self, value: Vec<u8>) -> Result<Vec<u8>, ChipmunkError> {
+        match self.value_transform() {
+            Some(transform) => transform.decode(value),
+            None => Ok(value),
+        }
+    }
+
+    /// Subscribe to every write applied from now on, in the order of their
+    /// sequence numbers.
+    ///
+    /// A change is sent once its write is in the WAL and visible to reads.
+    /// Values relocated by value log garbage collection are sent as puts of
+    /// the same value. A subscriber more than [`CHANGE_BUFFER`] changes
+    /// behind misses the oldest of them, and is told how many it missed.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.watching.store(true, Ordering::Relaxed);
+        self.changes.subscribe()
+    }
+
+    /// Subscribe to every write after the given sequence number, replaying
+    /// those which are still retained, such as for a subscriber reconnecting
+    /// after it last saw that sequence number.
+    ///
+    /// The [`CHANGE_BUFFER`] most recent changes are retained once anything
+    /// has subscribed, so those made before then, or before the tree was
+    /// opened, are counted as missed.
+    pub fn subscribe_after(&self, sequence: u64) -> Resumed {
+        self.watching.store(true, Ordering::Relaxed);
+        // Held throughout, so that no write is applied in between.
+        let latest = self.sequence.lock();
+        let recent = self.recent_changes.lock();
+        let replayed: Vec<Change> = recent
+            .iter()
+            .filter(|change| change.stamp.sequence > sequence)
+            .cloned()
+            .collect();
+        Resumed {
+            missed: latest
+                .saturating_sub(sequence)
+                .saturating_sub(replayed.len() as u64),
+            replayed,
+            changes: self.changes.subscribe(),
+        }
+    }
+
+    /// Read back the changes after a sequence number which the WAL still
+    /// holds, and subscribe to those made from now on, see [`WalChanges`].
+    ///
+    /// Unlike [`Lsm::subscribe_after`], changes are retained for as long as
+    /// their WAL segments are: until the memtables holding them are flushed,
+    /// then for the configured [`WalConfig::retention`] while archived.
+    pub fn wal_changes_after(&self, sequence: u64) -> Result<WalChanges, ChipmunkError> {
+        let Some(wal) = &self.wal else {
+            return Err(ChipmunkError::InMemory("reading changes from the WAL"));
         };
+        self.watching.store(true, Ordering::Relaxed);
+        // Held throughout, so that every write is either within the segments
+        // or sent to the subscription.
+        let latest = self.sequence.lock();
+        let mut wal = wal.lock();
+        wal.flush_buffer()?;
+        let segments = wal
+            .archived_paths()?
+            .iter()
+            .chain(&wal.segment_paths())
+            .map(|path| SegmentReader::open(path))
+            .collect::<Result<_, _>>()?;
+        let end = std::fs::metadata(wal.path())
+            .map_err(ChipmunkError::SegmentOpen)?
+            .len();
+        Ok(WalChanges {
+            backlog: WalBacklog {
+                segments,
+                end,
+                last: sequence,
+                value_transform: self.value_transform(),
+            },
+            latest: *latest,
+            changes: self.changes.subscribe(),
+        })
+    }
 
+    /// Apply a change made to another tree, such as the primary which this
+    /// tree replicates, under the same stamp so that the trees stay in step.
+    ///
+    /// A change which was already applied is skipped, returning false. One
+    /// which does not follow the most recent write is refused, as the changes
+    /// in between would be missed.
+    pub fn apply_replicated(&self, change: Change) -> Result<bool, ChipmunkError> {
+        self.throttle_write()?;
         {
-            let mut wal = self.wal.lock();
-            wal.append(entry)?;
+            let mut sequence = self.sequence.lock();
+            let expected = *sequence + 1;
+            if change.stamp.sequence < expected {
+                return Ok(false);
+            }
+            if change.stamp.sequence > expected {
+                return Err(ChipmunkError::ReplicationGap {
+                    expected,
+                    found: change.stamp.sequence,
+                });
+            }
+            let kind = match change.kind {
+                ChangeKind::Put {
+                    key,
+                    value,
+                    expires_at,
+                } => ChangeKind::Put {
+                    key,
+                    value: self.encode_value(value.to_vec()).into(),
+                    expires_at,
+                },
+                kind => kind,
+            };
+            self.apply_stamped(&mut sequence, change.stamp, kind.into_entry())?;
+        }
+        self.maybe_rotate_memtable()?;
+        Ok(true)
+    }
+
+    /// Whether changes need to be built, as something has subscribed to them.
+    fn watched(&self) -> bool {
+        self.watching.load(Ordering::Relaxed)
+    }
+
+    fn publish(&self, stamp: WriteStamp, kind: Option<ChangeKind>) {
+        if let Some(kind) = kind {
+            let change = Change { stamp, kind };
+            let mut recent = self.recent_changes.lock();
+            if recent.len() == CHANGE_BUFFER {
+                recent.pop_front();
+            }
+            recent.push_back(change.clone());
+            // Subscribers may have gone away since the change was built.
+            let _ = self.changes.send(change);
+        }
+    }
+
+    /// Persist an insertion, whose value has already been transformed, to the
+    /// WAL and apply it to the active memtable. The caller holds the sequence
+    /// lock for the write, which is assigned the next sequence number and
+    /// returned in its [`WriteStamp`].
+    fn apply_put(
+        &self,
+        sequence: &mut u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<WriteStamp, ChipmunkError> {
+        let entry = match expires_at {
+            Some(expires_at) => WalEntry::PutWithExpiry {
+                key,
+                value,
+                expires_at,
+            },
+            None => WalEntry::Put { key, value },
+        };
+        let stamp = next_stamp(*sequence);
+        self.apply_stamped(sequence, stamp, entry)?;
+        Ok(stamp)
+    }
+
+    /// Persist a deletion to the WAL and apply it to the active memtable, see
+    /// [`Lsm::apply_put`].
+    fn apply_delete(&self, sequence: &mut u64, key: Vec<u8>) -> Result<WriteStamp, ChipmunkError> {
+        let stamp = next_stamp(*sequence);
+        self.apply_stamped(sequence, stamp, WalEntry::Delete { key })?;
+        Ok(stamp)
+    }
+
+    /// Persist a range deletion to the WAL and apply it to the active
+    /// memtable, see [`Lsm::apply_put`].
+    fn apply_delete_range(
+        &self,
+        sequence: &mut u64,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    ) -> Result<WriteStamp, ChipmunkError> {
+        let stamp = next_stamp(*sequence);
+        self.apply_stamped(sequence, stamp, WalEntry::DeleteRange { start, end })?;
+        Ok(stamp)
+    }
+
+    /// Persist a write to the WAL under the given stamp and apply it to the
+    /// active memtable, publishing it to subscribers. The caller holds the
+    /// sequence lock, which is moved on to the sequence number of the stamp.
+    fn apply_stamped(
+        &self,
+        sequence: &mut u64,
+        stamp: WriteStamp,
+        entry: WalEntry,
+    ) -> Result<(), ChipmunkError> {
+        // Values are published as they were written, which is checked before
+        // the write is persisted so that one which cannot be is refused.
+        let published = match &entry {
+            WalEntry::Put { value, .. } | WalEntry::PutWithExpiry { value, .. }
+                if self.watched() =>
+            {
+                Some(self.decode_value(value.clone())?)
+            }
+            _ => None,
+        };
+        // The memtable is held from before the write reaches the WAL, so that
+        // it cannot be frozen in between. Were it frozen, it would be recorded
+        // as flushing the WAL segment of a write which it does not hold, and
+        // the segment removed once it was flushed.
+        let memtable = self.memtable.read();
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock();
+            wal.append(WalEntry::Stamped {
+                stamp,
+                entry: Box::new(entry.clone()),
+            })?;
             if wal.size() >= self.wal_config.max_size {
                 wal.rotate()?;
             }
         }
 
-        // Populate the internal bloom filter
-        self.bloom_insert(key.clone());
+        self.metrics.keys_written.add(1);
+        let change = match entry {
+            WalEntry::Put { key, value } => {
+                self.apply_put_entry(&memtable, stamp, key, value, None, published)
+            }
+            WalEntry::PutWithExpiry {
+                key,
+                value,
+                expires_at,
+            } => self.apply_put_entry(&memtable, stamp, key, value, Some(expires_at), published),
+            WalEntry::Delete { key } => {
+                self.metrics.bytes_written.add(key.len() as u64);
+                let change = self.watched().then(|| ChangeKind::Delete {
+                    key: Bytes::copy_from_slice(&key),
+                });
+                memtable.delete(key);
+                change
+            }
+            WalEntry::DeleteRange { start, end } => {
+                self.metrics
+                    .bytes_written
+                    .add((start.len() + end.len()) as u64);
+                let change = self.watched().then(|| ChangeKind::DeleteRange {
+                    start: Bytes::copy_from_slice(&start),
+                    end: Bytes::copy_from_slice(&end),
+                });
+                memtable.delete_range(start, end);
+                change
+            }
+            WalEntry::Stamped { .. } => unreachable!("Writes are stamped once"),
+        };
+        memtable.record_sequence(stamp.sequence);
+        *sequence = stamp.sequence;
+        self.publish(stamp, change);
+        Ok(())
+    }
+
+    /// Insert into the memtable for [`Lsm::apply_stamped`], returning the
+    /// change to publish when given the decoded value to publish it with.
+    fn apply_put_entry(
+        &self,
+        memtable: &Memtable,
+        stamp: WriteStamp,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        published: Option<Vec<u8>>,
+    ) -> Option<ChangeKind> {
+        self.metrics
+            .bytes_written
+            .add((key.len() + value.len()) as u64);
+        let change = published.map(|published| ChangeKind::Put {
+            key: Bytes::copy_from_slice(&key),
+            value: published.into(),
+            expires_at,
+        });
+        memtable.insert_with_stamp(key, value, expires_at, Some(stamp));
+        change
+    }
 
-        self.memtable.insert(key, value);
-        if self.memtable.size() > self.memtable_config.max_size {
-            info!("Memtable rotation");
-            self.rotate_memtable();
+    /// The current pressure on the write path, as judged against the
+    /// [`WriteStallConfig`].
+    pub fn write_stall(&self) -> WriteStall {
+        let config = self.write_stall_config();
+        let l1_files = self.sstables.lock().len();
+        let immutable = self.immutable_memtables.read().len();
+        let exceeds = |count: usize, trigger: Option<usize>| trigger.is_some_and(|t| count >= t);
 
-            // Remove the closed WAL segments after the Memtable has been flushed
-            // to disk, these are no longer required as the memtable has been
-            // persisted already.
-            self.remove_closed_segments()?;
+        if exceeds(l1_files, config.l1_stop_trigger)
+            || exceeds(immutable, config.immutable_stop_trigger)
+        {
+            WriteStall::Stop
+        } else if exceeds(l1_files, config.l1_slowdown_trigger)
+            || exceeds(immutable, config.immutable_slowdown_trigger)
+        {
+            WriteStall::Slowdown
+        } else {
+            WriteStall::Normal
         }
+    }
 
-        // This compaction trigger is not very scientific at the moment.
-        if self.l2_files.lock().len() > 3 {
-            self.force_compaction();
+    /// Apply backpressure to a write according to the current
+    /// [`WriteStall`].
+    ///
+    /// Stopped writes block while every immutable memtable is flushed and L1
+    /// is compacted, and are rejected if the tree is still over its limits
+    /// afterwards.
+    fn throttle_write(&self) -> Result<(), ChipmunkError> {
+        match self.write_stall() {
+            WriteStall::Normal => Ok(()),
+            WriteStall::Slowdown => {
+                let delay = self.write_stall_config.read().slowdown_delay;
+                debug!(?delay, "Slowing down write");
+                std::thread::sleep(delay);
+                Ok(())
+            }
+            WriteStall::Stop => {
+                warn!("Writes stopped, waiting on flush and compaction");
+                self.flush_immutable_memtables(0)?;
+                if !self.sstables.lock().is_empty() {
+                    self.compact(false)?;
+                }
+                if self.write_stall() == WriteStall::Stop {
+                    return Err(ChipmunkError::WriteStall);
+                }
+                Ok(())
+            }
         }
+    }
 
-        Ok(())
+    /// Rotate the current [`Memtable`] if it has grown beyond its configured
+    /// maximum size.
+    ///
+    /// With background work enabled, the memtable is only frozen and the
+    /// flush left to the worker unless it has fallen behind.
+    fn maybe_rotate_memtable(&self) -> Result<(), ChipmunkError> {
+        if self.memtable.read().size() <= self.memtable_config.max_size {
+            return Ok(());
+        }
+        info!("Memtable rotation");
+        match &self.background_work {
+            Some(work) => {
+                self.freeze_memtable();
+                work.notify();
+                let waiting = self.immutable_memtables.read().len();
+                if waiting > self.memtable_config.max_immutable_memtables {
+                    warn!(waiting, "Background flush is behind, flushing on write");
+                    self.flush_immutable_memtables(self.memtable_config.max_immutable_memtables)?;
+                }
+                Ok(())
+            }
+            None => {
+                // Without a worker, value log garbage is only looked for once
+                // compaction has run, rather than on every rotation.
+                self.freeze_memtable();
+                self.flush_immutable_memtables(self.memtable_config.max_immutable_memtables)?;
+                if self.maybe_compact()? {
+                    self.collect_value_log_garbage()?;
+                }
+                Ok(())
+            }
+        }
     }
 
     /// Force a rotation of the current [`Memtable`].
-    pub fn rotate_memtable(&self) {
-        self.sstables.lock().push(self.memtable.id());
-        self.memtable.flush(self.working_directory.clone());
+    ///
+    /// The active memtable is frozen and replaced with an empty one. Frozen
+    /// memtables are held in memory, up to the configured
+    /// `max_immutable_memtables`, before the oldest are flushed to make room.
+    /// A frozen memtable stays readable until it has been flushed and its
+    /// SSTable has been registered, so there is no point at which its data is
+    /// unreachable. Compaction runs afterwards if the flushed files exceed
+    /// any of the thresholds in the [`CompactionConfig`].
+    pub fn rotate_memtable(&self) -> Result<(), ChipmunkError> {
+        self.freeze_memtable();
+        self.flush_immutable_memtables(self.memtable_config.max_immutable_memtables)?;
+        self.maybe_compact()?;
+        Ok(())
     }
 
-    /// Remove closed [`Segment`] files. This should only be called when the [`Memtable`]
-    /// has been flushed to an [`SSTable`].
-    pub fn remove_closed_segments(&self) -> Result<(), ChipmunkError> {
-        info!("Removing closed segments");
-        let mut wal = self.wal.lock();
-        for segment_id in wal.closed_segments().iter() {
-            let path = format!("{}/{segment_id}.wal", self.working_directory.display());
-            debug!(path, "Removing segment");
-            std::fs::remove_file(&path).map_err(ChipmunkError::SegmentDelete)?;
+    /// Flush the active memtable, along with every frozen memtable, to
+    /// SSTables.
+    ///
+    /// Unlike [`Lsm::rotate_memtable`], no memtable is retained in memory and
+    /// compaction is left to its usual triggers.
+    pub fn flush(&self) -> Result<(), ChipmunkError> {
+        if self.memtable_config.in_memory.is_some() {
+            return Err(ChipmunkError::InMemory("flushing"));
         }
-        wal.clear_segments();
-        Ok(())
+        if !self.memtable.read().is_empty() {
+            self.freeze_memtable();
+        }
+        self.flush_immutable_memtables(0)
     }
 
-    /// Force a compaction cycle to occur.
-    ///
-    /// This operates as a full compaction. Taking all data from various sstables
-    /// on disk and merging them into new files, removing any tombstones values
-    /// to ensure only the most recent data is kept.
-    pub fn force_compaction(&self) {
-        let mut l2_tree: FxHashMap<Bytes, Bytes> = FxHashMap::default();
-        let mut insert_count = 0;
-        let mut skip_count = 0;
+    /// Flush the active memtable if it holds data and is at least as old as
+    /// the flush interval, then compact if any of the thresholds have been
+    /// exceeded. Returns whether it was flushed.
+    pub fn flush_if_due(&self) -> Result<bool, ChipmunkError> {
+        let Some(interval) = self.flush_interval() else {
+            return Ok(false);
+        };
+        if self.memtable_config.in_memory.is_some()
+            || self.memtable.read().is_empty()
+            || self.memtable_created.lock().elapsed() < interval
         {
-            let mut sstables = self.sstables.lock();
-            info!(sstable_count = sstables.len(), "Running compaction cycle");
-            for l1_file_id in &*sstables {
-                let l1_file = self.working_directory.join(format!("sstable-{l1_file_id}"));
-                info!(file = %l1_file.display(), "Compacting L1 file");
-                let tree: FxHashMap<Bytes, Option<Bytes>> = Memtable::load(l1_file.clone());
-
-                for (k, v) in tree {
-                    if let Some(v) = v {
-                        insert_count += 1;
-                        debug!(key = %String::from_utf8_lossy(&k), "Inserting for L2");
-                        // Only insert values which are NOT tombstones
-                        l2_tree.insert(k, v);
-                    } else {
-                        skip_count += 1;
-                    }
-                }
-                info!(file = %l1_file.display(), "Deleting L1 file");
-                std::fs::remove_file(l1_file)
-                    .expect("Can always remove existing SSTable after compaction");
-                info!(insert_count, skip_count, "Compaction progress");
-            }
-            sstables.clear();
+            return Ok(false);
         }
-        info!(insert_count, skip_count, "Compaction complete");
+        debug!(?interval, "Flushing memtable on its interval");
+        self.flush()?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
 
-        let l2_id = self
-            .l2_id
-            .fetch_add(1, std::sync::atomic::Ordering::Acquire);
-        let flush_path = self.working_directory.join(format!("l2-{l2_id}"));
-        let l2_data = bincode::serialize(&l2_tree).unwrap();
-        std::fs::write(flush_path, l2_data).unwrap();
-        self.l2_files.lock().push(l2_id);
+    /// Replace the active memtable with an empty one, queueing it to be
+    /// flushed.
+    fn freeze_memtable(&self) {
+        let mut active = self.memtable.write();
+        *self.memtable_created.lock() = Instant::now();
+        self.metrics.memtable_rotations.add(1);
+        let next = Arc::new(Memtable::with_comparator(
+            active.id() + 1,
+            self.memtable_config.max_size,
+            self.comparator(),
+        ));
+        let frozen = std::mem::replace(&mut *active, next);
+        let wal_segment = self.wal_segment();
+        // Registered while the active memtable is still locked so that
+        // readers see the frozen data in one of the two places.
+        self.immutable_memtables.write().push(FrozenMemtable {
+            memtable: frozen,
+            wal_segment,
+        });
     }
 
-    /// Get a value from the LSM-tree.
-    ///
-    /// This first checks whether the value has passed through the internal
-    /// [`BloomFilter`] first.
-    /// Afterwards [`Memtable`] is then consulted to search through persisted SSTables
-    /// if it exists.
-    pub fn get(&self, key: Vec<u8>) -> Option<Vec<u8>> {
-        debug!(key=?String::from_utf8_lossy(&key), "Getting key");
-        match self.check(key.clone()) {
-            // We can return instantly if the value has not passed through the
-            // filter.
-            false => None,
-            true => match self.memtable.get(&key) {
-                Some(v) => Some(v.to_vec()),
-                None => {
-                    debug!("Searching immutable memtables");
-                    for memtable_id in self.sstables.lock().iter().rev() {
-                        let memtable = Memtable::load(
-                            self.working_directory
-                                .join(format!("sstable-{memtable_id}")),
-                        );
-                        match memtable.get(key.as_slice()) {
-                            Some(Some(v)) => return Some(v.to_vec()),
-                            None | Some(None) => continue,
-                        };
-                    }
-                    // Exhausted search of entire structure did not find the key, so
-                    // it does not exist.
-                    None
-                }
-            },
+    /// ID of the active WAL segment, zero when there is no WAL.
+    fn wal_segment(&self) -> u64 {
+        self.wal.as_ref().map_or(0, |wal| wal.lock().id())
+    }
+
+    /// Run a compaction cycle if any of the configured thresholds have been
+    /// exceeded, returning whether it ran.
+    fn maybe_compact(&self) -> Result<bool, ChipmunkError> {
+        let Some(trigger) = self.compaction_trigger()? else {
+            return Ok(false);
+        };
+        info!(trigger, "Compaction triggered");
+        // Merging L1 into a file of its own would only add to the L2 files
+        // whose number triggered the compaction.
+        self.compact(trigger == L2_FILE_COUNT_TRIGGER)?;
+        Ok(true)
+    }
+
+    /// The first threshold of the [`CompactionConfig`] which has been
+    /// exceeded, if any. Only too many L2 files trigger compaction while L1 is
+    /// empty.
+    fn compaction_trigger(&self) -> Result<Option<&'static str>, ChipmunkError> {
+        let config = self.compaction_config();
+        if config
+            .max_l2_files
+            .is_some_and(|max| self.l2_files.lock().len() > max)
+        {
+            return Ok(Some(L2_FILE_COUNT_TRIGGER));
+        }
+        let l1_files = self.sstables.lock().clone();
+        if l1_files.is_empty() {
+            return Ok(None);
+        }
+        if config
+            .l1_file_trigger
+            .is_some_and(|trigger| l1_files.len() >= trigger)
+        {
+            return Ok(Some("l1 file count"));
+        }
+        if config.l1_size_trigger.is_none() && config.level_size_ratio.is_none() {
+            return Ok(None);
+        }
+
+        let l1_size = self.level_size(LEVEL_1, &l1_files)?;
+        if config
+            .l1_size_trigger
+            .is_some_and(|trigger| l1_size >= trigger)
+        {
+            return Ok(Some("l1 size"));
+        }
+        if let Some(ratio) = config.level_size_ratio {
+            let l2_files = self.l2_files.lock().clone();
+            let l2_size = self.level_size(LEVEL_2, &l2_files)?;
+            if l2_size > 0 && l1_size as f64 >= l2_size as f64 * ratio {
+                return Ok(Some("level size ratio"));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The L2 files which a compaction of the given L1 files merges: those
+    /// which overlap the keys of the L1 files, then those overlapping the
+    /// files chosen in turn, as L2 files written before they were merged this
+    /// way may overlap one another. Every L2 file is merged if the metadata of
+    /// any file is missing.
+    fn overlapping_l2_files(&self, l1_files: &[u64], comparator: &dyn Comparator) -> Vec<u64> {
+        let l2_files = self.l2_files.lock().clone();
+        let manifest = self.manifest.lock();
+        let version = manifest.version();
+        let mut span = None;
+        for id in l1_files {
+            let Some(metadata) = version.metadata(LEVEL_1, *id) else {
+                return l2_files;
+            };
+            span = widen_span(span, metadata.key_span(comparator), comparator);
+        }
+
+        let mut chosen = Vec::new();
+        let mut remaining = l2_files.clone();
+        while let Some((min, max)) = span.clone() {
+            let bounds = (Bound::Included(min), Bound::Included(max));
+            let mut overlapping = Vec::new();
+            for id in std::mem::take(&mut remaining) {
+                let Some(metadata) = version.metadata(LEVEL_2, id) else {
+                    return l2_files;
+                };
+                if metadata.overlaps(&bounds, comparator) {
+                    span = widen_span(span, metadata.key_span(comparator), comparator);
+                    overlapping.push(id);
+                } else {
+                    remaining.push(id);
+                }
+            }
+            if overlapping.is_empty() {
+                break;
+            }
+            chosen.extend(overlapping);
+        }
+        chosen.sort_unstable();
+        chosen
+    }
+
+    /// Total size, in bytes, of the given files within a level.
+    fn level_size(&self, level: u8, ids: &[u64]) -> Result<u64, ChipmunkError> {
+        let mut size = 0;
+        for id in ids {
+            let path = self.working_directory.join(manifest::file_name(level, *id));
+            size += std::fs::metadata(path)
+                .map_err(ChipmunkError::SstableRead)?
+                .len();
+        }
+        Ok(size)
+    }
+
+    /// Flush the oldest frozen memtables to SSTables until no more than
+    /// `retain` remain in memory.
+    ///
+    /// Closed WAL segments are removed once the memtables holding their data
+    /// have been flushed, as the data has been persisted already.
+    ///
+    /// When running in memory only there is nowhere to flush to, so `retain`
+    /// is ignored and the oldest are instead evicted beyond the configured
+    /// `max_immutable_memtables` if [`InMemory::Evict`] is set.
+    pub fn flush_immutable_memtables(&self, retain: usize) -> Result<(), ChipmunkError> {
+        if let Some(in_memory) = self.memtable_config.in_memory {
+            self.evict_immutable_memtables(in_memory);
+            return Ok(());
+        }
+        let _flushing = self.flush_lock.lock();
+        loop {
+            let (oldest, wal_segment) = {
+                let immutable = self.immutable_memtables.read();
+                if immutable.len() <= retain {
+                    return Ok(());
+                }
+                (Arc::clone(&immutable[0].memtable), immutable[0].wal_segment)
+            };
+
+            let start = Instant::now();
+            let metadata = oldest.flush(
+                self.working_directory.clone(),
+                &self.sstable_config,
+                &self.value_log,
+            )?;
+            // The segments before the one the memtable was frozen in no longer
+            // need to be replayed once the SSTable is registered.
+            self.manifest.lock().apply(&[
+                VersionEdit::AddFile {
+                    level: LEVEL_1,
+                    id: oldest.id(),
+                    metadata,
+                },
+                VersionEdit::Checkpoint { wal_segment },
+                VersionEdit::Sequence {
+                    sequence: oldest.max_sequence(),
+                },
+            ])?;
+            self.register_sstable(oldest.id());
+            self.metrics.flushes.record(start.elapsed());
+            if let Ok(file) = std::fs::metadata(
+                self.working_directory
+                    .join(manifest::file_name(LEVEL_1, oldest.id())),
+            ) {
+                self.metrics.bytes_flushed.add(file.len());
+            }
+            self.immutable_memtables
+                .write()
+                .retain(|m| !Arc::ptr_eq(&m.memtable, &oldest));
+
+            self.remove_closed_segments_before(wal_segment)?;
+        }
+    }
+
+    /// Discard the oldest frozen memtables beyond the configured maximum, when
+    /// running in memory with [`InMemory::Evict`].
+    fn evict_immutable_memtables(&self, in_memory: InMemory) {
+        if in_memory == InMemory::Retain {
+            return;
+        }
+        let mut immutable = self.immutable_memtables.write();
+        let excess = immutable
+            .len()
+            .saturating_sub(self.memtable_config.max_immutable_memtables);
+        if excess > 0 {
+            debug!(evicted = excess, "Evicting frozen memtables");
+            immutable.drain(..excess);
+        }
+    }
+
+    /// Cleanly shut down the [`Lsm`], so that the next [`Lsm::restore`] does
+    /// not need to replay any of the WAL.
+    ///
+    /// The WAL is synced, then every memtable is flushed to an SSTable, after
+    /// which the WAL is rotated and a checkpoint recorded in the manifest past
+    /// every existing segment, which are then removed. The checkpoint marks
+    /// the shutdown as clean. Writes are held off until this completes. The
+    /// engine remains usable afterwards, with later writes going to a fresh
+    /// segment.
+    pub fn close(&self) -> Result<(), ChipmunkError> {
+        // Nothing is persisted when running in memory only.
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        info!("Closing LSM-tree");
+        let _sequence = self.sequence.lock();
+        // Acknowledged writes are made durable first, so that they survive
+        // should flushing the memtables fail.
+        wal.lock().sync()?;
+        if !self.memtable.read().is_empty() {
+            self.rotate_memtable()?;
+        }
+        self.flush_immutable_memtables(0)?;
+
+        let checkpoint = {
+            let mut wal = wal.lock();
+            wal.flush_buffer()?;
+            wal.rotate()?;
+            wal.id()
+        };
+        self.manifest.lock().apply(&[VersionEdit::Checkpoint {
+            wal_segment: checkpoint,
+        }])?;
+        self.remove_closed_segments_before(checkpoint)?;
+        info!(checkpoint, "Closed LSM-tree");
+        Ok(())
+    }
+
+    /// Create a consistent copy of the tree within `target_dir`, which must not
+    /// already exist, that can be opened as a store of its own.
+    ///
+    /// Writes are held off while every memtable is flushed, so the SSTables
+    /// hold everything written before the call and no WAL needs to be carried
+    /// over. The SSTables are hard linked into the target where possible,
+    /// falling back to copying them, and a manifest listing them is written
+    /// alongside.
+    pub fn checkpoint(&self, target_dir: &Path) -> Result<(), ChipmunkError> {
+        if self.wal.is_none() {
+            return Err(ChipmunkError::InMemory("checkpoint"));
+        }
+        info!(target = %target_dir.display(), "Creating checkpoint");
+        let checkpoint_err = |source| ChipmunkError::Checkpoint {
+            source,
+            path: target_dir.to_path_buf(),
+        };
+
+        let sequence = self.sequence.lock();
+        if !self.memtable.read().is_empty() {
+            self.rotate_memtable()?;
+        }
+        self.flush_immutable_memtables(0)?;
+
+        std::fs::create_dir(target_dir).map_err(checkpoint_err)?;
+        // Compaction removes files while holding the L1 lock, so holding it
+        // keeps every listed file in place until it has been linked.
+        let l1_files = self.sstables.lock();
+        let l2_files = self.l2_files.lock().clone();
+        let mut edits = Vec::new();
+        for (level, ids) in [(LEVEL_1, &*l1_files), (LEVEL_2, &l2_files)] {
+            for id in ids {
+                let name = manifest::file_name(level, *id);
+                let source = self.working_directory.join(&name);
+                let destination = target_dir.join(&name);
+                if std::fs::hard_link(&source, &destination).is_err() {
+                    std::fs::copy(&source, &destination).map_err(checkpoint_err)?;
+                }
+                let metadata = match self.manifest.lock().version().metadata(level, *id) {
+                    Some(metadata) => metadata.clone(),
+                    None => Sstable::open(&source)?.metadata().clone(),
+                };
+                edits.push(VersionEdit::AddFile {
+                    level,
+                    id: *id,
+                    metadata,
+                });
+            }
+        }
+        // Value log files are only removed while holding the sequence lock.
+        for id in self.value_log.file_ids() {
+            let name = value_log::file_name(id);
+            let source = self.working_directory.join(&name);
+            let destination = target_dir.join(&name);
+            if std::fs::hard_link(&source, &destination).is_err() {
+                std::fs::copy(&source, &destination).map_err(checkpoint_err)?;
+            }
+        }
+        let files = edits.len();
+        // Sequence numbers carry on from where this tree had reached.
+        edits.push(VersionEdit::Sequence {
+            sequence: *sequence,
+        });
+        Manifest::open(target_dir)?.apply(&edits)?;
+        fs::sync_dir(target_dir)
+            .and_then(|_| fs::sync_parent(target_dir))
+            .map_err(checkpoint_err)?;
+        info!(
+            target = %target_dir.display(),
+            files,
+            "Created checkpoint"
+        );
+        Ok(())
+    }
+
+    /// Remove closed [`Segment`] files. This should only be called when the [`Memtable`]
+    /// has been flushed to an [`SSTable`].
+    pub fn remove_closed_segments(&self) -> Result<(), ChipmunkError> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        info!("Removing closed segments");
+        let removed = wal.lock().remove_closed_segments()?;
+        debug!(removed, "Removed closed segments");
+        Ok(())
+    }
+
+    /// Remove closed [`Segment`] files with an ID lower than the one given.
+    fn remove_closed_segments_before(&self, segment_id: u64) -> Result<(), ChipmunkError> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let removed = wal.lock().remove_closed_segments_before(segment_id)?;
+        debug!(removed, segment_id, "Removed closed segments");
+        Ok(())
+    }
+
+    /// Ingest an externally built SSTable, bulk loading its entries without
+    /// passing them through the WAL and memtable.
+    ///
+    /// The file is verified before anything else happens and is rejected if
+    /// any problem is found. Ingested entries are newer than everything which
+    /// was written before the call, so the active memtable is frozen and
+    /// flushed first, and are shadowed by anything written afterwards. The
+    /// file is copied into the working directory and registered through the
+    /// manifest, at which point it becomes visible to reads.
+    pub fn ingest_sstable(&self, path: &Path) -> Result<(), ChipmunkError> {
+        if self.wal.is_none() {
+            return Err(ChipmunkError::InMemory("SSTable ingestion"));
+        }
+        let report = sstable::verify_with_comparator(path, self.comparator());
+        if let Some(problem) = report.problems.first() {
+            return Err(ChipmunkError::SstableCorrupt {
+                path: path.to_path_buf(),
+                reason: format!(
+                    "refusing to ingest, {} problem(s) found: {problem:?}",
+                    report.problems.len()
+                ),
+            });
+        }
+
+        // Reserve the ID which follows the active memtable, so the ingested
+        // file sits between the data written before and after this call.
+        let file_id = {
+            let mut active = self.memtable.write();
+            let file_id = active.id() + 1;
+            let next = Arc::new(Memtable::with_comparator(
+                file_id + 1,
+                self.memtable_config.max_size,
+                self.comparator(),
+            ));
+            let frozen = std::mem::replace(&mut *active, next);
+            if !frozen.is_empty() {
+                let wal_segment = self.wal_segment();
+                self.immutable_memtables.write().push(FrozenMemtable {
+                    memtable: frozen,
+                    wal_segment,
+                });
+            }
+            file_id
+        };
+        self.flush_immutable_memtables(0)?;
+
+        let destination = self
+            .working_directory
+            .join(manifest::file_name(LEVEL_1, file_id));
+        let temp = fs::temp_path(&destination);
+        std::fs::copy(path, &temp)
+            .and_then(|_| File::open(&temp)?.sync_all())
+            .and_then(|_| fs::publish(&temp, &destination))
+            .map_err(ChipmunkError::SstableWrite)?;
+        let sstable = Sstable::open(&destination)?;
+
+        self.manifest.lock().apply(&[VersionEdit::AddFile {
+            level: LEVEL_1,
+            id: file_id,
+            metadata: sstable.metadata().clone(),
+        }])?;
+        self.register_sstable(file_id);
+        info!(
+            source = %path.display(),
+            file = %destination.display(),
+            entries = report.entries,
+            "Ingested SSTable"
+        );
+        Ok(())
+    }
+
+    /// Make an L1 SSTable visible to reads. IDs are kept in ascending order,
+    /// which is also the order of the data's age.
+    fn register_sstable(&self, id: u64) {
+        let mut sstables = self.sstables.lock();
+        let position = sstables.partition_point(|existing| *existing < id);
+        sstables.insert(position, id);
+    }
+
+    /// Force a compaction cycle to occur.
+    ///
+    /// This operates as a full compaction. Taking all data from various sstables
+    /// on disk and merging them into a single L2 file, along with every L2 file
+    /// already written, removing any tombstones values to ensure only the most
+    /// recent data is kept. The files are merged as a stream, so memory use is
+    /// bounded by a block per file rather than the amount of data being
+    /// compacted.
+    ///
+    /// The work performed is returned, and added to the totals reported by
+    /// [`Lsm::compaction_stats`].
+    pub fn force_compaction(&self) -> Result<CompactionStats, ChipmunkError> {
+        self.compact(true)
+    }
+
+    /// Merge the L1 files into a new L2 file, see [`Lsm::force_compaction`].
+    ///
+    /// The L2 files which overlap the L1 files are merged in too and replaced
+    /// by the new one, see [`Lsm::overlapping_l2_files`], or every L2 file with
+    /// `merge_l2`. No L2 file left out of the merge then holds a key which the
+    /// new file may, so tombstones, expired entries and range tombstones have
+    /// nothing older left to shadow and are dropped.
+    fn compact(&self, merge_l2: bool) -> Result<CompactionStats, ChipmunkError> {
+        // There are no SSTables to compact when running in memory only.
+        if self.wal.is_none() {
+            return Ok(CompactionStats::default());
+        }
+        let start = Instant::now();
+        let now = unix_millis();
+        let mut stats = CompactionStats {
+            compactions: 1,
+            output_files: 1,
+            ..Default::default()
+        };
+        let mut insert_count = 0;
+        let mut skip_count = 0;
+        let comparator = self.comparator();
+        let value_transform = self.value_transform();
+        let compaction_filter = self.compaction_filter.read().clone();
+        let mut throttle = Throttle::new(self.compaction_config().max_bytes_per_sec);
+        {
+            let mut sstables = self.sstables.lock();
+            let l2_inputs = match merge_l2 {
+                true => self.l2_files.lock().clone(),
+                false => self.overlapping_l2_files(&sstables, &*comparator),
+            };
+            info!(
+                sstable_count = sstables.len(),
+                l2_count = l2_inputs.len(),
+                "Running compaction cycle"
+            );
+            stats.input_files = (sstables.len() + l2_inputs.len()) as u64;
+            stats.bytes_read =
+                self.level_size(LEVEL_1, &sstables)? + self.level_size(LEVEL_2, &l2_inputs)?;
+            // L2 files hold older data than L1 files, and IDs within a level
+            // ascend with the age of the data, so the inputs are oldest first.
+            let inputs = l2_inputs
+                .iter()
+                .map(|id| (LEVEL_2, *id))
+                .chain(sstables.iter().map(|id| (LEVEL_1, *id)))
+                .map(|(level, id)| {
+                    let file = self.working_directory.join(manifest::file_name(level, id));
+                    info!(file = %file.display(), level, "Compacting file");
+                    Sstable::open(&file).map(|table| table.with_comparator(Arc::clone(&comparator)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let l2_id = self
+                .l2_id
+                .fetch_add(1, std::sync::atomic::Ordering::Acquire);
+            let flush_path = self
+                .working_directory
+                .join(manifest::file_name(LEVEL_2, l2_id));
+            let mut builder = SstableBuilder::with_config(&flush_path, &self.sstable_config)?
+                .with_comparator(Arc::clone(&comparator));
+            // Range tombstones are applied by the merge, and have nothing
+            // older left to shadow.
+            skip_count += inputs
+                .iter()
+                .map(|table| table.metadata().range_tombstones.len() as u64)
+                .sum::<u64>();
+
+            // The inputs are oldest first, so the newest entry of each key
+            // wins the merge.
+            let merge = MergingIter::new(
+                without_range_deletions(
+                    inputs
+                        .iter()
+                        .map(|table| {
+                            let source: ScanSource = Box::new(table.iter());
+                            (source, table.metadata().range_tombstones.clone())
+                        })
+                        .collect(),
+                    &comparator,
+                ),
+                Arc::clone(&comparator),
+            )?;
+            for result in merge {
+                let (k, mut entry) = result?;
+                if let Some(filter) = &compaction_filter {
+                    if let Some(value) = self.compaction_value(&entry, now)? {
+                        let decision = match &value_transform {
+                            Some(transform) => {
+                                filter.filter(&k, &transform.decode(value.to_vec())?)
+                            }
+                            None => filter.filter(&k, &value),
+                        };
+                        match decision {
+                            CompactionDecision::Keep => {}
+                            CompactionDecision::Remove => {
+                                stats.filtered_entries += 1;
+                                entry.value = None;
+                            }
+                            CompactionDecision::ChangeValue(value) => {
+                                stats.filtered_entries += 1;
+                                let value = match &value_transform {
+                                    Some(transform) => transform.encode(value),
+                                    None => value,
+                                };
+                                entry.value = Some(value.into());
+                                entry.separated = false;
+                            }
+                        }
+                    }
+                }
+                if entry.live_value(now).is_some() {
+                    insert_count += 1;
+                    debug!(key = %String::from_utf8_lossy(&k), "Inserting for L2");
+                    builder.add(&k, &entry)?;
+                    let size = k.len() + entry.value.as_ref().map_or(0, Bytes::len);
+                    stats.throttled += throttle.consume(size as u64);
+                } else {
+                    // Nothing older remains for a tombstone or expired entry
+                    // to shadow, so it can be dropped.
+                    skip_count += 1;
+                }
+            }
+            let metadata = builder.finish()?;
+            stats.bytes_written = std::fs::metadata(&flush_path)
+                .map_err(ChipmunkError::SstableRead)?
+                .len();
+
+            // The input files are only removed once the manifest no longer
+            // references them, a crash before then leaves them in place.
+            let removed: Vec<_> = sstables
+                .iter()
+                .map(|&id| (LEVEL_1, id))
+                .chain(l2_inputs.iter().map(|&id| (LEVEL_2, id)))
+                .collect();
+            let mut edits: Vec<_> = removed
+                .iter()
+                .map(|&(level, id)| VersionEdit::DeleteFile { level, id })
+                .collect();
+            edits.push(VersionEdit::AddFile {
+                level: LEVEL_2,
+                id: l2_id,
+                metadata,
+            });
+            self.manifest.lock().apply(&edits)?;
+            {
+                let mut l2_files = self.l2_files.lock();
+                l2_files.retain(|id| !l2_inputs.contains(id));
+                l2_files.push(l2_id);
+            }
+            sstables.clear();
+
+            for (level, id) in removed {
+                let file = self.working_directory.join(manifest::file_name(level, id));
+                info!(file = %file.display(), level, "Deleting compacted file");
+                self.table_cache.evict(level, id);
+                std::fs::remove_file(file)
+                    .expect("Can always remove existing SSTable after compaction");
+            }
+            fs::sync_dir(&self.working_directory).map_err(ChipmunkError::SstableWrite)?;
+        }
+        stats.dropped_tombstones = skip_count;
+        stats.duration = start.elapsed();
+        self.compaction_stats.lock().merge(&stats);
+        self.metrics.compactions.record(stats.duration);
+        self.metrics.bytes_compacted.add(stats.bytes_written);
+        info!(
+            insert_count,
+            skip_count,
+            bytes_read = stats.bytes_read,
+            bytes_written = stats.bytes_written,
+            duration = ?stats.duration,
+            throttled = ?stats.throttled,
+            "Compaction complete"
+        );
+        Ok(stats)
+    }
+
+    /// The live value of an entry being compacted, as passed to the
+    /// [`CompactionFilter`].
+    ///
+    /// Separated values are read from the value log, unless their file has
+    /// been garbage collected. The entry is then shadowed by newer data, so it
+    /// is carried over as it is.
+    fn compaction_value(&self, entry: &Entry, now: u64) -> Result<Option<Bytes>, ChipmunkError> {
+        let Some(value) = entry.live_value(now) else {
+            return Ok(None);
+        };
+        if !entry.separated {
+            return Ok(Some(value.clone()));
+        }
+        self.value_log.read(&self.value_pointer(value)?)
+    }
+
+    /// Decode the [`ValuePointer`] which a separated entry holds.
+    fn value_pointer(&self, value: &[u8]) -> Result<ValuePointer, ChipmunkError> {
+        ValuePointer::decode(value).map_err(|reason| ChipmunkError::ValueLogCorrupt {
+            path: self.working_directory.clone(),
+            reason: reason.to_string(),
+        })
+    }
+
+    /// Remove the value log files which hold no live values, and those whose
+    /// proportion of garbage has reached the configured `value_log_gc_ratio`,
+    /// returning how many were removed. This is run by the background worker
+    /// or, without one, after compaction.
+    ///
+    /// A value is live while the newest entry of its key still points to it.
+    /// Files are chosen without holding off writes, so nothing waits on a
+    /// tree without enough garbage to collect. Before a chosen file is
+    /// removed, its live values are written again as though they were new,
+    /// with a new [`WriteStamp`], then flushed so that they are held
+    /// elsewhere. Writes are held off from then on, and the values checked
+    /// again, so that no newer write to a key can be replaced by its relocated
+    /// value. Files written by a memtable which is still being flushed are
+    /// left alone, as their SSTable may not be readable yet.
+    pub fn collect_value_log_garbage(&self) -> Result<usize, ChipmunkError> {
+        let now = unix_millis();
+        let flushing: Vec<u64> = {
+            let active = self.memtable.read();
+            let immutable = self.immutable_memtables.read();
+            immutable
+                .iter()
+                .map(|frozen| frozen.memtable.id())
+                .chain([active.id()])
+                .collect()
+        };
+
+        let mut chosen = Vec::new();
+        for id in self.value_log.file_ids() {
+            if flushing.contains(&id) {
+                continue;
+            }
+            let records = self.value_log.records(id)?;
+            let total: u64 = records.iter().map(|(_, pointer)| pointer.len as u64).sum();
+            let live = self.live_records(records, now)?;
+            let live_bytes: u64 = live.iter().map(|(_, pointer, _)| pointer.len as u64).sum();
+            let garbage = 1.0 - live_bytes as f64 / total.max(1) as f64;
+            if !live.is_empty() && garbage < self.sstable_config.value_log_gc_ratio {
+                continue;
+            }
+            debug!(id, live = live.len(), garbage, "Collecting value log file");
+            chosen.push((id, live));
+        }
+        if chosen.is_empty() {
+            return Ok(0);
+        }
+
+        let mut sequence = self.sequence.lock();
+        let now = unix_millis();
+        let existing = self.value_log.file_ids();
+        let mut removable = Vec::new();
+        let mut relocated = 0;
+        for (id, live) in chosen {
+            // Another collection may have removed the file since it was chosen.
+            if !existing.contains(&id) {
+                continue;
+            }
+            // Writes since the file was chosen may have replaced its values.
+            let records = live.into_iter().map(|(key, pointer, _)| (key, pointer));
+            for (key, pointer, expires_at) in self.live_records(records.collect(), now)? {
+                let value = self.value_log.read(&pointer)?.ok_or_else(|| {
+                    ChipmunkError::ValueLogCorrupt {
+                        path: self.working_directory.join(value_log::file_name(id)),
+                        reason: "file was removed while its values were relocated".to_string(),
+                    }
+                })?;
+                self.apply_put(&mut sequence, key.to_vec(), value.to_vec(), expires_at)?;
+                relocated += 1;
+            }
+            removable.push(id);
+        }
+
+        if relocated > 0 {
+            self.freeze_memtable();
+            self.flush_immutable_memtables(0)?;
+        }
+        for id in &removable {
+            self.value_log.remove(*id)?;
+        }
+        if !removable.is_empty() {
+            info!(
+                files = removable.len(),
+                relocated, "Collected value log garbage"
+            );
+        }
+        Ok(removable.len())
+    }
+
+    /// The records of a value log file which are still live, alongside the
+    /// expiry of the entry pointing to each.
+    fn live_records(
+        &self,
+        records: Vec<(Bytes, ValuePointer)>,
+        now: u64,
+    ) -> Result<Vec<(Bytes, ValuePointer, Option<u64>)>, ChipmunkError> {
+        let mut live = Vec::new();
+        for (key, pointer) in records {
+            let Some(entry) = self.newest_entry(&key)? else {
+                continue;
+            };
+            let points_here = entry.separated
+                && entry
+                    .live_value(now)
+                    .is_some_and(|value| *value == pointer.encode());
+            if points_here {
+                live.push((key, pointer, entry.expires_at));
+            }
+        }
+        Ok(live)
+    }
+
+    /// The current shape of the tree: its memtables, the files of each level
+    /// and the size of the WAL and value log, alongside the approximate
+    /// number of keys and bytes they hold.
+    pub fn tree_stats(&self) -> TreeStats {
+        let (immutable_memtables, immutable_memtable_bytes) = {
+            let immutable = self.immutable_memtables.read();
+            let bytes = immutable.iter().map(|frozen| frozen.memtable.size()).sum();
+            (immutable.len(), bytes)
+        };
+        TreeStats {
+            memtable_bytes: self.memtable.read().size(),
+            immutable_memtables,
+            immutable_memtable_bytes,
+            levels: self.level_stats(),
+            wal_bytes: self.wal.as_ref().map_or(0, |wal| wal.lock().disk_size()),
+            value_log_bytes: self.value_log.disk_size(),
+            directory_bytes: self.directory_size(),
+            approximate_keys: self.approximate_count(),
+            approximate_bytes: self.approximate_size(),
+        }
+    }
+
+    /// Approximate number of live keys, counted without reading any of them.
+    ///
+    /// Every entry of the memtables and SSTables other than a tombstone is
+    /// counted, so a key written again, or deleted, after it was flushed is
+    /// counted more than once until compaction merges its entries. Range
+    /// deletions and expiry are not accounted for.
+    pub fn approximate_count(&self) -> u64 {
+        let memtables = {
+            let active = self.memtable.read();
+            let immutable = self.immutable_memtables.read();
+            active.len()
+                + immutable
+                    .iter()
+                    .map(|frozen| frozen.memtable.len())
+                    .sum::<u64>()
+        };
+
+        let l1_files = self.sstables.lock();
+        let l2_files = self.l2_files.lock().clone();
+        let manifest = self.manifest.lock();
+        let version = manifest.version();
+        let mut tables = 0;
+        for (level, ids) in [(LEVEL_1, &*l1_files), (LEVEL_2, &l2_files)] {
+            // Files removed by a concurrent compaction count as empty.
+            tables += ids
+                .iter()
+                .filter_map(|id| version.metadata(level, *id))
+                .map(|metadata| metadata.entries - metadata.tombstones)
+                .sum::<u64>();
+        }
+        memtables + tables
+    }
+
+    /// Approximate size, in bytes, of the data held by the tree: the size of
+    /// its memtables, SSTables and value log files. The WAL, which only
+    /// repeats what the memtables hold, is left out.
+    pub fn approximate_size(&self) -> u64 {
+        let memtables = {
+            let active = self.memtable.read();
+            let immutable = self.immutable_memtables.read();
+            active.size()
+                + immutable
+                    .iter()
+                    .map(|frozen| frozen.memtable.size())
+                    .sum::<u64>()
+        };
+        let tables: u64 = self.level_stats().iter().map(|level| level.bytes).sum();
+        memtables + tables + self.value_log.disk_size()
+    }
+
+    /// The files of each level persisted to SSTables, in order.
+    ///
+    /// The space which compaction would reclaim is estimated from the
+    /// metadata of each file, without reading it: every tombstone is taken to
+    /// free an entry of the file's average size, standing for the tombstone
+    /// and the older entry it shadows. Keys which were overwritten, and
+    /// entries which have expired, are not known until they are compacted
+    /// away, so the estimate errs low.
+    fn level_stats(&self) -> Vec<LevelStats> {
+        let level = |level, ids: Vec<u64>| {
+            let mut stats = LevelStats {
+                level,
+                files: ids.len(),
+                ..Default::default()
+            };
+            for id in ids {
+                // Files removed by a concurrent compaction count as empty.
+                let Ok(file) =
+                    std::fs::metadata(self.working_directory.join(manifest::file_name(level, id)))
+                else {
+                    continue;
+                };
+                stats.bytes += file.len();
+                if let Some(metadata) = self.manifest.lock().version().metadata(level, id) {
+                    if metadata.entries > 0 {
+                        stats.reclaimable_bytes +=
+                            (file.len() as u128 * u128::from(metadata.tombstones)
+                                / u128::from(metadata.entries)) as u64;
+                    }
+                }
+            }
+            stats
+        };
+        vec![
+            level(LEVEL_1, self.sstables.lock().clone()),
+            level(LEVEL_2, self.l2_files.lock().clone()),
+        ]
+    }
+
+    /// Size of every file within the working directory, zero when the tree
+    /// is kept in memory.
+    fn directory_size(&self) -> u64 {
+        if self.memtable_config.in_memory.is_some() {
+            return 0;
+        }
+        fs::directory_size(&self.working_directory).unwrap_or_else(|e| {
+            warn!("Cannot measure the size of the working directory: {e}");
+            0
+        })
+    }
+
+    /// Work performed by every compaction cycle since the engine started.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_stats.lock().clone()
+    }
+
+    /// Work performed by the engine since it started, or since
+    /// [`Lsm::reset_statistics`] was last called.
+    pub fn statistics(&self) -> Statistics {
+        let (block_cache_hits, block_cache_misses) = self.block_cache.hits_and_misses();
+        Statistics {
+            block_cache_hits,
+            block_cache_misses,
+            ..self.metrics.statistics()
+        }
+    }
+
+    /// Write the metrics of every part of the engine to the exposition,
+    /// alongside gauges describing the memtables and block cache.
+    pub fn expose_metrics(&self, exposition: &mut Exposition) {
+        self.expose_metrics_with(&self.tree_stats(), exposition);
+    }
+
+    /// Write the metrics of every part of the engine to the exposition, see
+    /// [`Lsm::expose_metrics`], describing the shape of the tree given, such
+    /// as that of every shard together.
+    pub(crate) fn expose_metrics_with(&self, tree: &TreeStats, exposition: &mut Exposition) {
+        self.metrics.expose(exposition);
+        let (hits, misses) = self.block_cache.hits_and_misses();
+        exposition.counter(
+            "block_cache_hits_total",
+            "Data block reads served from the block cache",
+            hits,
+        );
+        exposition.counter(
+            "block_cache_misses_total",
+            "Data block reads which went to disk",
+            misses,
+        );
+        exposition.gauge(
+            "block_cache_bytes",
+            "Size of the data blocks held by the block cache",
+            self.block_cache.size(),
+        );
+        exposition.gauge(
+            "block_cache_capacity_bytes",
+            "Capacity of the block cache",
+            self.block_cache.capacity(),
+        );
+        exposition.gauge(
+            "memtable_bytes",
+            "Approximate size of the active memtable",
+            tree.memtable_bytes,
+        );
+        exposition.gauge(
+            "immutable_memtables",
+            "Frozen memtables awaiting a flush",
+            tree.immutable_memtables,
+        );
+        exposition.gauge(
+            "immutable_memtable_bytes",
+            "Approximate size of the frozen memtables",
+            tree.immutable_memtable_bytes,
+        );
+        exposition.gauge(
+            "approximate_keys",
+            "Approximate number of live keys",
+            tree.approximate_keys,
+        );
+
+        let levels: Vec<[(&str, String); 1]> = tree
+            .levels
+            .iter()
+            .map(|level| [("level", level.level.to_string())])
+            .collect();
+        let per_level = |value: fn(&LevelStats) -> u64| {
+            levels
+                .iter()
+                .zip(&tree.levels)
+                .map(move |(labels, level)| (&labels[..], value(level)))
+        };
+        exposition.family(
+            "level_files",
+            "gauge",
+            "SSTables within each level",
+            per_level(|level| level.files as u64),
+        );
+        exposition.family(
+            "level_bytes",
+            "gauge",
+            "Size on disk of the SSTables within each level",
+            per_level(|level| level.bytes),
+        );
+        exposition.family(
+            "level_reclaimable_bytes",
+            "gauge",
+            "Estimated size which compaction would free within each level",
+            per_level(|level| level.reclaimable_bytes),
+        );
+
+        let sstable_bytes = tree.levels.iter().map(|level| level.bytes).sum();
+        let components: [([(&str, String); 1], u64); 3] = [
+            ([("component", "wal".to_string())], tree.wal_bytes),
+            ([("component", "sstables".to_string())], sstable_bytes),
+            (
+                [("component", "value_log".to_string())],
+                tree.value_log_bytes,
+            ),
+        ];
+        exposition.family(
+            "disk_bytes",
+            "gauge",
+            "Size on disk of each component of the store",
+            components
+                .iter()
+                .map(|(labels, value)| (&labels[..], value)),
+        );
+        exposition.gauge(
+            "directory_bytes",
+            "Size of every file within the working directory",
+            tree.directory_bytes,
+        );
+    }
+
+    /// Set every counter of [`Lsm::statistics`] back to zero.
+    pub fn reset_statistics(&self) {
+        self.metrics.reset();
+        self.block_cache.reset_hits_and_misses();
+    }
+
+    /// Get a value from the LSM-tree.
+    ///
+    /// The [`Memtable`]s are consulted first, then the persisted SSTables from
+    /// newest to oldest. SSTables whose key range or bloom filter rules out
+    /// the key are skipped without reading any of their blocks.
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, ChipmunkError> {
+        self.metrics.get_latency.time(|| {
+            let value = self.get_encoded(key)?;
+            value.map(|value| self.decode_value(value)).transpose()
+        })
+    }
+
+    /// Get a value from the LSM-tree alongside its expiry and the write which
+    /// produced it, see [`Lsm::get`].
+    pub fn get_stamped(&self, key: Vec<u8>) -> Result<Option<StampedValue>, ChipmunkError> {
+        debug!(key=?String::from_utf8_lossy(&key), "Getting stamped key");
+        self.metrics.get_latency.time(|| {
+            self.metrics.keys_read.add(1);
+            let Some(entry) = self.newest_entry(&key)? else {
+                return Ok(None);
+            };
+            let Some(value) = self.live_value(&key, &entry, unix_millis())? else {
+                return Ok(None);
+            };
+            Ok(Some(StampedValue {
+                value: self.decode_value(value)?,
+                expires_at: entry.expires_at,
+                stamp: entry.stamp,
+            }))
+        })
+    }
+
+    /// Get the values of several keys at once, returned in the same order as
+    /// the keys.
+    ///
+    /// The keys are looked up in key order so that each SSTable is
+    /// probed once for every key it may hold, and keys which share an index
+    /// partition or data block share a single read of it.
+    pub fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, ChipmunkError> {
+        debug!(keys = keys.len(), "Getting keys");
+        let start = Instant::now();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        let comparator = self.comparator();
+        order.sort_by(|a, b| comparator.compare(&keys[*a], &keys[*b]));
+        let mut pending = order.clone();
+        self.metrics.keys_read.add(keys.len() as u64);
+        pending.dedup_by(|a, b| keys[*a] == keys[*b]);
+
+        let mut results: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+        let searched = pending.len();
+        {
+            let active = self.memtable.read();
+            let immutable = self.immutable_memtables.read();
+            pending.retain(|i| {
+                let found = std::iter::once(&*active)
+                    .chain(immutable.iter().rev().map(|frozen| &frozen.memtable))
+                    .find_map(|memtable| memtable.get_entry(&keys[*i]));
+                match found {
+                    Some(entry) => {
+                        results[*i] = entry.map(|v| v.to_vec());
+                        false
+                    }
+                    None => true,
+                }
+            });
+        }
+        self.metrics
+            .memtable_hits
+            .add((searched - pending.len()) as u64);
+        self.metrics.memtable_misses.add(pending.len() as u64);
+
+        if !pending.is_empty() {
+            debug!(keys = pending.len(), "Searching SSTables");
+            let now = unix_millis();
+            let mut resolved = vec![false; keys.len()];
+            let l1_files = self.sstables.lock();
+            let l2_files = self.l2_files.lock().clone();
+            for (level, ids) in [(LEVEL_1, &*l1_files), (LEVEL_2, &l2_files)] {
+                for id in ids.iter().rev() {
+                    let mut filtered = Vec::new();
+                    let probes: Vec<usize> = pending
+                        .iter()
+                        .copied()
+                        .filter(|i| {
+                            let (may_contain, bloom_check) = self.probe(level, *id, &keys[*i]);
+                            if bloom_check == Some(true) {
+                                filtered.push(*i);
+                            }
+                            may_contain
+                        })
+                        .collect();
+                    if probes.is_empty() {
+                        continue;
+                    }
+                    self.metrics.sstable_probes.add(probes.len() as u64);
+                    let probe_keys: Vec<&[u8]> =
+                        probes.iter().map(|i| keys[*i].as_slice()).collect();
+                    let found = self
+                        .table_cache
+                        .get(level, *id)
+                        .and_then(|table| table.multi_get(&probe_keys))?;
+                    for (i, entry) in probes.into_iter().zip(found) {
+                        if let Some(entry) = entry {
+                            // A tombstone or expired entry shadows any older
+                            // value
+                            results[i] = self.live_value(&keys[i], &entry, now)?;
+                            resolved[i] = true;
+                        }
+                    }
+                    self.metrics
+                        .bloom_false_positives
+                        .add(filtered.iter().filter(|i| !resolved[**i]).count() as u64);
+                    pending.retain(|i| !resolved[*i]);
+                }
+            }
+        }
+
+        // Repeated keys were only looked up once.
+        for pair in order.windows(2) {
+            if keys[pair[0]] == keys[pair[1]] {
+                results[pair[1]] = results[pair[0]].clone();
+            }
+        }
+
+        let results = match self.value_transform() {
+            Some(transform) => results
+                .into_iter()
+                .map(|value| value.map(|v| transform.decode(v)).transpose())
+                .collect::<Result<_, _>>()?,
+            None => results,
+        };
+        self.metrics.get_latency.record(start.elapsed());
+        Ok(results)
+    }
+
+    /// Get a value in the form it was persisted, prior to any
+    /// [`ValueTransform`] being reversed.
+    fn get_encoded(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, ChipmunkError> {
+        debug!(key=?String::from_utf8_lossy(&key), "Getting key");
+        self.metrics.keys_read.add(1);
+        match self.newest_entry(&key)? {
+            Some(entry) => self.live_value(&key, &entry, unix_millis()),
+            None => Ok(None),
+        }
+    }
+
+    /// The newest entry for a key, which may be a tombstone or have expired.
+    ///
+    /// Entries of memtables are never separated.
+    fn newest_entry(&self, key: &[u8]) -> Result<Option<Entry>, ChipmunkError> {
+        if let Some(entry) = self.memtable.read().entry(key) {
+            self.metrics.memtable_hits.add(1);
+            return Ok(Some(entry));
+        }
+
+        debug!("Searching frozen memtables");
+        for frozen in self.immutable_memtables.read().iter().rev() {
+            if let Some(entry) = frozen.memtable.entry(key) {
+                self.metrics.memtable_hits.add(1);
+                return Ok(Some(entry));
+            }
+        }
+        self.metrics.memtable_misses.add(1);
+
+        debug!("Searching SSTables");
+        // Compaction moves data from L1 to L2 while holding the L1 lock, so
+        // holding it here gives a consistent view of both.
+        let l1_files = self.sstables.lock();
+        let l2_files = self.l2_files.lock().clone();
+        let levels = [(LEVEL_1, &*l1_files), (LEVEL_2, &l2_files)];
+        for (level, ids) in levels {
+            // Within a level, higher IDs hold newer data.
+            for id in ids.iter().rev() {
+                let (may_contain, bloom_check) = self.probe(level, *id, key);
+                if !may_contain {
+                    // The file's key range or bloom filter rules out the key
+                    // without needing to read any of its blocks.
+                    continue;
+                }
+                self.metrics.sstable_probes.add(1);
+                let found = self
+                    .table_cache
+                    .get(level, *id)
+                    .and_then(|table| table.get(key))?;
+                match found {
+                    // A tombstone or expired entry shadows any older value
+                    Some(entry) => return Ok(Some(entry)),
+                    None if bloom_check == Some(true) => {
+                        self.metrics.bloom_false_positives.add(1);
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The live value of a key's newest entry, read from the value log if it
+    /// has been separated.
+    ///
+    /// Should garbage collection have moved the value and removed its file
+    /// since the entry was found, the key is looked up again.
+    fn live_value(
+        &self,
+        key: &[u8],
+        entry: &Entry,
+        now: u64,
+    ) -> Result<Option<Vec<u8>>, ChipmunkError> {
+        let Some(value) = entry.live_value(now) else {
+            return Ok(None);
+        };
+        if !entry.separated {
+            return Ok(Some(value.to_vec()));
+        }
+        match self.value_log.read(&self.value_pointer(value)?)? {
+            Some(value) => Ok(Some(value.to_vec())),
+            None => match self.newest_entry(key)? {
+                Some(entry) => self.live_value(key, &entry, now),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Whether an SSTable may hold an entry for, or delete, the key, alongside
+    /// the outcome of its bloom filter if it was consulted, see
+    /// [`SstableMetadata::bloom_check`](crate::sstable::SstableMetadata::bloom_check).
+    ///
+    /// Only the table's metadata is read, which the manifest holds in memory.
+    fn probe(&self, level: u8, id: u64, key: &[u8]) -> (bool, Option<bool>) {
+        let manifest = self.manifest.lock();
+        let Some(metadata) = manifest.version().metadata(level, id) else {
+            return (true, None);
+        };
+        let bloom_check = metadata.bloom_check(key, &*self.comparator());
+        if let Some(passed) = bloom_check {
+            self.metrics.bloom_checks.add(1);
+            if !passed {
+                self.metrics.bloom_negatives.add(1);
+            }
+        }
+        (metadata.may_contain(key, &*self.comparator()), bloom_check)
+    }
+
+    /// Iterate over the live entries whose keys fall within the given range,
+    /// in the order of the [`Comparator`].
+    ///
+    /// Every memtable and SSTable level is merged, the newest entry for a key
+    /// shadowing any older ones, and tombstones and expired entries are
+    /// skipped. The memtables are copied as the scan starts, while SSTables
+    /// are read a block at a time as it advances. Files which are compacted
+    /// away during the scan remain readable until it is dropped.
+    ///
+    /// A file which cannot be read yields an error, after which the scan
+    /// ends.
+    pub fn scan<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let start = Instant::now();
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let now = unix_millis();
+        // Captured first, so that any value log file removed beforehand has had
+        // its live values moved to where the scan will find them.
+        let value_log = self.value_log.reader();
+
+        // Memtables are captured before SSTables, so data which is flushed in
+        // between is seen twice rather than not at all.
+        let mut memtables: Vec<(ScanSource, Vec<RangeTombstone>)> = Vec::new();
+        {
+            let active = self.memtable.read();
+            let immutable = self.immutable_memtables.read();
+            for memtable in immutable
+                .iter()
+                .map(|frozen| &frozen.memtable)
+                .chain([&*active])
+            {
+                let entries: Vec<_> = memtable
+                    .range(bounds.clone())
+                    .map(|(key, value)| {
+                        Ok((
+                            key,
+                            Entry {
+                                value,
+                                expires_at: None,
+                                separated: false,
+                                stamp: None,
+                            },
+                        ))
+                    })
+                    .collect();
+                memtables.push((Box::new(entries.into_iter()), memtable.range_tombstones()));
+            }
+        }
+
+        // Sources are merged oldest first: L2, then L1, then the memtables.
+        let mut sources: Vec<(ScanSource, Vec<RangeTombstone>)> = Vec::new();
+        {
+            // As with point lookups, holding the L1 lock gives a consistent
+            // view of both levels.
+            let l1_files = self.sstables.lock();
+            let l2_files = self.l2_files.lock().clone();
+            for (level, ids) in [(LEVEL_2, &l2_files), (LEVEL_1, &*l1_files)] {
+                for id in ids {
+                    let overlaps = self
+                        .manifest
+                        .lock()
+                        .version()
+                        .metadata(level, *id)
+                        .is_none_or(|metadata| metadata.overlaps(&bounds, &*self.comparator()));
+                    if !overlaps {
+                        continue;
+                    }
+                    match self.table_cache.get(level, *id) {
+                        Ok(table) => sources.push((
+                            Box::new(table.shared_range(bounds.clone())),
+                            table.metadata().range_tombstones.clone(),
+                        )),
+                        // Reported as the merge of the sources starts.
+                        Err(e) => sources.push((Box::new(std::iter::once(Err(e))), Vec::new())),
+                    }
+                }
+            }
+        }
+        sources.extend(memtables);
+
+        TimedScan {
+            entries: merge_live(
+                sources,
+                now,
+                value_log,
+                self.value_transform(),
+                self.comparator(),
+            ),
+            latency: &self.metrics.scan_latency,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    /// Iterate over the live entries whose keys start with the given prefix,
+    /// in the order of the [`Comparator`].
+    ///
+    /// This is a [`Lsm::scan`] over the range of keys sharing the prefix, so
+    /// only the files whose key range overlaps it are read. Every key is
+    /// scanned if the comparator does not keep such keys together, see
+    /// [`Comparator::prefix_range`].
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let prefix = Bytes::copy_from_slice(prefix);
+        self.scan(self.prefix_bounds(&prefix))
+            .filter(move |result| !matches!(result, Ok((key, _)) if !key.starts_with(&prefix)))
+    }
+
+    /// The range scanned for keys starting with the prefix, see
+    /// [`Lsm::scan_prefix`].
+    pub(crate) fn prefix_bounds(&self, prefix: &[u8]) -> (Bound<Bytes>, Bound<Bytes>) {
+        prefix_bounds(&*self.comparator(), prefix)
+    }
+
+    /// Sequence number of the most recent write. Sequence numbers count every
+    /// write made to the tree, carrying on across restarts.
+    pub fn sequence(&self) -> u64 {
+        *self.sequence.lock()
+    }
+
+    /// Take a [`Snapshot`] of the tree as of the most recent write.
+    ///
+    /// Reads through the snapshot are unaffected by later writes, flushes and
+    /// compactions. The memtables and SSTables which make up the tree are
+    /// shared with the snapshot, other than the active memtable which is
+    /// copied, so SSTables which are compacted away stay open until every
+    /// snapshot referring to them has been dropped.
+    pub fn snapshot(&self) -> Result<Snapshot, ChipmunkError> {
+        // Holding the sequence lock keeps writes out while the tree is
+        // captured.
+        let sequence = self.sequence.lock();
+        let (active, frozen) = {
+            let active = self.memtable.read();
+            let immutable = self.immutable_memtables.read();
+            (
+                active.snapshot(),
+                immutable
+                    .iter()
+                    .map(|frozen| Arc::clone(&frozen.memtable))
+                    .collect(),
+            )
+        };
+
+        let mut tables = Vec::new();
+        {
+            let l1_files = self.sstables.lock();
+            let l2_files = self.l2_files.lock().clone();
+            for (level, ids) in [(LEVEL_2, &l2_files), (LEVEL_1, &*l1_files)] {
+                for id in ids {
+                    tables.push(self.table_cache.get(level, *id)?);
+                }
+            }
+        }
+        debug!(sequence = *sequence, tables = tables.len(), "Took snapshot");
+
+        Ok(Snapshot::new(
+            *sequence,
+            active,
+            frozen,
+            tables,
+            self.value_log.reader(),
+            self.value_transform(),
+            self.comparator(),
+        ))
+    }
+
+    /// Delete a key, returning the sequence number assigned to the write.
+    pub fn delete(&self, key: Vec<u8>) -> Result<u64, ChipmunkError> {
+        debug!(key=?String::from_utf8_lossy(&key), "Deleting key");
+        self.metrics.delete_latency.time(|| {
+            self.throttle_write()?;
+            let stamp = self.apply_delete(&mut self.sequence.lock(), key)?;
+            self.maybe_rotate_memtable()?;
+
+            Ok(stamp.sequence)
+        })
+    }
+
+    /// Delete every key from `start`, inclusive, up to `end`, exclusive.
+    ///
+    /// A single range tombstone is written rather than a tombstone for each
+    /// key, which shadows older data until compaction drops it. Nothing is
+    /// deleted when `start` is not before `end` in the order of the
+    /// [`Comparator`].
+    pub fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> Result<(), ChipmunkError> {
+        debug!(
+            start = ?String::from_utf8_lossy(&start),
+            end = ?String::from_utf8_lossy(&end),
+            "Deleting range"
+        );
+        if self.comparator().compare(&start, &end).is_ge() {
+            return Ok(());
+        }
+        self.metrics.delete_latency.time(|| {
+            self.throttle_write()?;
+            self.apply_delete_range(&mut self.sequence.lock(), start, end)?;
+            self.maybe_rotate_memtable()?;
+
+            Ok(())
+        })
+    }
+
+    /// Atomically replace the value of a key if it currently holds the
+    /// expected value, returning the sequence number assigned to the swap,
+    /// or `None` if it did not take place.
+    ///
+    /// An `expected` value of `None` requires the key to be absent, and a
+    /// `new` value of `None` deletes the key. No other write can take place
+    /// between the comparison and the swap.
+    pub fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Option<u64>, ChipmunkError> {
+        self.swap(key, expected, new, None)
+    }
+
+    /// Atomically replace the value of a key as [`Lsm::compare_and_swap`]
+    /// does, with a new value which expires once the given time-to-live has
+    /// elapsed.
+    pub fn compare_and_swap_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<Option<u64>, ChipmunkError> {
+        self.swap(key, expected, Some(new), Some(expiry_after(ttl)))
+    }
+
+    /// Shared path for compare-and-swap, with an optional expiry of the new
+    /// value as a unix timestamp in milliseconds.
+    fn swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Result<Option<u64>, ChipmunkError> {
+        debug!(key=?String::from_utf8_lossy(&key), "Comparing and swapping key");
+        if let Some(value) = &new {
+            self.check_value_size(value)?;
+        }
+        self.throttle_write()?;
+        let stamp = {
+            let mut sequence = self.sequence.lock();
+            if self.get(key.clone())? != expected {
+                return Ok(None);
+            }
+            match new {
+                Some(value) => {
+                    let value = self.encode_value(value);
+                    self.apply_put(&mut sequence, key, value, expires_at)?
+                }
+                None => self.apply_delete(&mut sequence, key)?,
+            }
+        };
+        self.maybe_rotate_memtable()?;
+
+        Ok(Some(stamp.sequence))
+    }
+
+    /// Apply the writes in order, returning the sequence number assigned to
+    /// the last of them.
+    ///
+    /// No other write can take place between those of the batch, and none of
+    /// them are applied if any value is too large. The batch is not atomic
+    /// across a crash, which may leave only the writes before it durable.
+    pub fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<u64, ChipmunkError> {
+        debug!(writes = writes.len(), "Writing batch");
+        for write in &writes {
+            if let BatchWrite::Put { value, .. } = write {
+                self.check_value_size(value)?;
+            }
+        }
+        self.throttle_write()?;
+        let last = {
+            let mut sequence = self.sequence.lock();
+            for write in writes {
+                match write {
+                    BatchWrite::Put { key, value, ttl } => {
+                        let expires_at = ttl.map(expiry_after);
+                        let value = self.encode_value(value);
+                        self.apply_put(&mut sequence, key, value, expires_at)?;
+                    }
+                    BatchWrite::Delete { key } => {
+                        self.apply_delete(&mut sequence, key)?;
+                    }
+                }
+            }
+            *sequence
+        };
+        self.maybe_rotate_memtable()?;
+
+        Ok(last)
+    }
+
+    pub fn memtable_id(&self) -> u64 {
+        self.memtable.read().id()
+    }
+
+    /// Restore the LSM-tree by recovering the internal [`Memtable`] and
+    /// SSTables.
+    ///
+    /// This works by restoring the WAL and building the memtable from there,
+    /// then registering the SSTables which the manifest records for each level.
+    /// Each SSTable's bloom filter is persisted within its metadata, so none
+    /// need to be rebuilt. A directory written before the manifest existed has
+    /// its SSTables recorded from the files on disk first.
+    ///
+    /// The memtable's ID is raised past every existing SSTable and value log
+    /// file, so that flushing it never overwrites them.
+    ///
+    /// # Panics
+    /// When a restore operation is conducted when the components are not started
+    /// from scratch - partial restore is not supported.
+    pub fn restore(&self) -> Result<(), ChipmunkError> {
+        if self.wal.is_none() {
+            info!("Nothing to restore when running in memory only");
+            return Ok(());
+        }
+        // Writes arriving while the tree is restored wait for it to finish,
+        // as they take the sequence number first.
+        let mut current_sequence = self.sequence.lock();
+        let removed = fs::remove_temp_files(&self.working_directory)
+            .map_err(ChipmunkError::WalRestoreDirectory)?;
+        if removed > 0 {
+            warn!(removed, "Removed files left by interrupted writes");
+        }
+        self.record_unmanaged_sstables()?;
+        let memtable_id = self.memtable_id().max(self.next_memtable_id());
+        {
+            let mut active = self.memtable.write();
+            if active.is_empty() && active.id() != memtable_id {
+                debug!(from = active.id(), to = memtable_id, "Raising memtable ID");
+                *active = Arc::new(Memtable::with_comparator(
+                    memtable_id,
+                    self.memtable_config.max_size,
+                    self.comparator(),
+                ));
+            }
+        }
+
+        {
+            let mut wal = self.wal.as_ref().expect("Checked above").lock();
+            // The restore operation implies that there is currently nothing
+            // in any of the components. A partial restore is not supported at
+            // the moment.
+            let memtable = self.memtable.read();
+            if wal.size() != 0 || !memtable.is_empty() || memtable.size() != 0 {
+                return Err(ChipmunkError::RestoreNotEmpty);
+            }
+
+            let (checkpoint, mut sequence) = {
+                let manifest = self.manifest.lock();
+                let version = manifest.version();
+                (version.wal_checkpoint(), version.sequence())
+            };
+            wal.restore(checkpoint)?;
+            info!("Restoring Memtable");
+            for entry in wal.entries()? {
+                let (stamp, entry) = match entry {
+                    WalEntry::Stamped { stamp, entry } => (Some(stamp), *entry),
+                    entry => (None, entry),
+                };
+                if let Some(stamp) = stamp {
+                    memtable.record_sequence(stamp.sequence);
+                    sequence = sequence.max(stamp.sequence);
+                }
+                match entry {
+                    WalEntry::Put { key, value } => {
+                        memtable.insert_with_stamp(key, value, None, stamp);
+                    }
+                    WalEntry::Delete { key } => {
+                        memtable.delete(key);
+                    }
+                    WalEntry::PutWithExpiry {
+                        key,
+                        value,
+                        expires_at,
+                    } => {
+                        memtable.insert_with_stamp(key, value, Some(expires_at), stamp);
+                    }
+                    WalEntry::DeleteRange { start, end } => {
+                        memtable.delete_range(start, end);
+                    }
+                    WalEntry::Stamped { .. } => unreachable!("WAL stamps are never nested"),
+                }
+            }
+            *current_sequence = sequence;
+            debug!(sequence, "Restored sequence number");
+        }
+
+        info!("Restoring SSTables from the manifest");
+        {
+            let manifest = self.manifest.lock();
+            let version = manifest.version();
+            *self.sstables.lock() = version.files(LEVEL_1);
+            let l2_files = version.files(LEVEL_2);
+            if let Some(last) = l2_files.last() {
+                self.l2_id
+                    .store(last + 1, std::sync::atomic::Ordering::Release);
+            }
+            *self.l2_files.lock() = l2_files;
+        }
+
+        Ok(())
+    }
+
+    /// Get the configured working directory.
+    pub fn working_directory(&self) -> &Path {
+        &self.working_directory
+    }
+}
+
+/// The stamp of the write following the given sequence number.
+fn next_stamp(sequence: u64) -> WriteStamp {
+    WriteStamp {
+        sequence: sequence + 1,
+        written_at: unix_millis(),
+    }
+}
+
+/// A scan which records the time spent within it, from its start until it
+/// is dropped, in the scan latency of the [`Metrics`].
+struct TimedScan<'a, I> {
+    entries: I,
+    latency: &'a Histogram,
+    elapsed: Duration,
+}
+
+impl<I: Iterator> Iterator for TimedScan<'_, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let next = self.entries.next();
+        self.elapsed += start.elapsed();
+        next
+    }
+}
+
+impl<I> Drop for TimedScan<'_, I> {
+    fn drop(&mut self) {
+        self.latency.record(self.elapsed);
+    }
+}
+
+/// Merge sources of entries, given oldest first alongside their range
+/// tombstones, into the live entries they hold with their values read from the
+/// value log and decoded, see [`Lsm::scan`]. The first error reading any
+/// source is yielded in place of the entries which follow it.
+pub(crate) fn merge_live<'a>(
+    sources: Vec<(ScanSource<'a>, Vec<RangeTombstone>)>,
+    now: u64,
+    value_log: ValueLogReader,
+    value_transform: Option<Arc<dyn ValueTransform>>,
+    comparator: Arc<dyn Comparator>,
+) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + 'a {
+    let (merged, failed) =
+        match MergingIter::new(without_range_deletions(sources, &comparator), comparator) {
+            Ok(merged) => (Some(merged), None),
+            Err(e) => (None, Some(Err(e))),
+        };
+    failed
+        .into_iter()
+        .chain(merged.into_iter().flatten().filter_map(move |result| {
+            let (key, entry) = match result {
+                Ok(found) => found,
+                Err(e) => return Some(Err(e)),
+            };
+            let value = match value_log.live_value(&entry, now) {
+                Ok(Some(value)) => value.to_vec(),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            let value = match &value_transform {
+                Some(transform) => match transform.decode(value) {
+                    Ok(value) => value,
+                    Err(e) => return Some(Err(e)),
+                },
+                None => value,
+            };
+            Some(Ok((key, Bytes::from(value))))
+        }))
+}
+
+/// Drop the entries of each source, given oldest first, which a range
+/// tombstone of a newer source covers, so that merging the sources honours
+/// range deletions.
+///
+/// Entries within the same source as a range tombstone are newer than it, so
+/// are kept.
+fn without_range_deletions<'a>(
+    sources: Vec<(ScanSource<'a>, Vec<RangeTombstone>)>,
+    comparator: &Arc<dyn Comparator>,
+) -> Vec<ScanSource<'a>> {
+    let mut newer: Vec<RangeTombstone> = Vec::new();
+    let mut filtered: Vec<ScanSource<'a>> = Vec::with_capacity(sources.len());
+    for (source, range_tombstones) in sources.into_iter().rev() {
+        if newer.is_empty() {
+            filtered.push(source);
+        } else {
+            let covering = newer.clone();
+            let comparator = Arc::clone(comparator);
+            filtered.push(Box::new(source.filter(move |result| {
+                result.as_ref().map_or(true, |(key, _)| {
+                    !covering
+                        .iter()
+                        .any(|tombstone| tombstone.covers(key, &*comparator))
+                })
+            })));
+        }
+        newer.extend(range_tombstones);
+    }
+    filtered.reverse();
+    filtered
+}
+
+/// The range of keys which start with the prefix.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Bytes>, Bound<Bytes>) {
+    let end = match prefix_successor(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(Bytes::copy_from_slice(prefix)), end)
+}
+
+/// The range to scan for keys which start with the prefix, every key when the
+/// comparator does not hold them together.
+pub(crate) fn prefix_bounds(
+    comparator: &dyn Comparator,
+    prefix: &[u8],
+) -> (Bound<Bytes>, Bound<Bytes>) {
+    comparator
+        .prefix_range(prefix)
+        .unwrap_or((Bound::Unbounded, Bound::Unbounded))
+}
+
+/// The smallest key which is greater than every key starting with the
+/// prefix, or `None` when no such key exists.
+fn prefix_successor(prefix: &[u8]) -> Option<Bytes> {
+    let last = prefix.iter().rposition(|byte| *byte != u8::MAX)?;
+    let mut successor = prefix[..=last].to_vec();
+    successor[last] += 1;
+    Some(successor.into())
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        if let Some(wal) = &self.wal {
+            wal.lock().flush_buffer().expect("Flushing buffer on drop");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Bound;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tempdir::TempDir;
+    use walkdir::WalkDir;
+
+    use crate::{
+        compaction::{CompactionDecision, CompactionFilter},
+        comparator::TimeSeries,
+        config::{
+            ChipmunkConfig, CompactionConfig, InMemory, Options, DEFAULT_L1_FILE_TRIGGER,
+            DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+        },
+        lsm::{MemtableConfig, SstableConfig, WalConfig, WriteStallConfig},
+        manifest::{self, LEVEL_1, LEVEL_2},
+        memtable::{Entry, Memtable, WriteStamp, MEMTABLE_MAX_SIZE_BYTES},
+        sstable::{Sstable, SstableBuilder},
+        transform::ValueTransform,
+        value_log,
+        wal::WAL_MAX_SEGMENT_SIZE_BYTES,
+        ChipmunkError,
+    };
+
+    use super::{
+        prefix_successor, BatchWrite, Change, ChangeKind, Exposition, FrozenMemtable, Lsm,
+        Statistics, WriteStall, CHANGE_BUFFER,
+    };
+
+    // Helper for creating an [`Lsm`] store within a test directory
+    fn create_lsm(wal_id: u64, dir: &TempDir, wal_max_size: u64, memtable_max_size: u64) -> Lsm {
+        let w = WalConfig {
+            id: wal_id,
+            max_size: wal_max_size,
+            log_directory: dir.path().to_path_buf(),
+            buffer_size: None,
+            retention: None,
+        };
+        let m = MemtableConfig {
+            id: 0,
+            max_size: memtable_max_size,
+            max_immutable_memtables: DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+            in_memory: None,
+            max_value_size: None,
+            flush_interval: None,
+        };
+        Lsm::new(
+            w,
+            m,
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn crud() {
+        let dir = TempDir::new("crud").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+        assert_eq!(lsm.memtable_id(), 0);
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), Some(b"bar".to_vec()));
+        assert_ne!(lsm.wal.as_ref().unwrap().lock().size(), 0);
+        let wal_size_after_put = lsm.wal.as_ref().unwrap().lock().size();
+
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        assert_eq!(
+            lsm.memtable_id(),
+            1,
+            "Engine should have a new memtable after flush"
+        );
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            Some(b"bar".to_vec()),
+            "Value should be found in sstable on disk"
+        );
+
+        lsm.delete(b"foo".to_vec()).unwrap();
+        assert!(
+            lsm.wal.as_ref().unwrap().lock().size() > wal_size_after_put,
+            "Deletion should append to the WAL"
+        );
+    }
+
+    #[test]
+    fn missing_directory() {
+        let dir = TempDir::new("missing_directory").unwrap();
+        let lsm = Lsm::new(
+            WalConfig::new(
+                0,
+                WAL_MAX_SEGMENT_SIZE_BYTES,
+                dir.path().join("missing"),
+                None,
+            ),
+            MemtableConfig::new(0, MEMTABLE_MAX_SIZE_BYTES),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        );
+        assert!(lsm.is_err(), "The directory is not created by Lsm::new");
+    }
+
+    #[test]
+    fn restore_not_empty() {
+        let dir = TempDir::new("restore_not_empty").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+        assert!(matches!(lsm.restore(), Err(ChipmunkError::RestoreNotEmpty)));
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn unreadable_sstable() {
+        let dir = TempDir::new("unreadable_sstable").unwrap();
+        {
+            let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+            lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+            lsm.close().unwrap();
+        }
+        std::fs::write(dir.path().join(manifest::file_name(LEVEL_1, 0)), b"garbage").unwrap();
+
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert!(matches!(
+            lsm.get(b"foo".to_vec()),
+            Err(ChipmunkError::SstableCorrupt { .. })
+        ));
+        assert!(lsm.multi_get(&[b"foo".to_vec()]).is_err());
+        assert!(lsm.snapshot().is_err());
+        let mut scan = lsm.scan(..);
+        assert!(scan.next().is_some_and(|entry| entry.is_err()));
+        assert!(scan.next().is_none(), "The scan ends after an error");
+    }
+
+    /// Stores values with their bytes reversed.
+    struct Reverse;
+
+    impl ValueTransform for Reverse {
+        fn encode(&self, mut value: Vec<u8>) -> Vec<u8> {
+            value.reverse();
+            value
+        }
+        fn decode(&self, mut value: Vec<u8>) -> Result<Vec<u8>, ChipmunkError> {
+            value.reverse();
+            Ok(value)
+        }
+    }
+
+    #[test]
+    fn value_transform() {
+        let dir = TempDir::new("value_transform").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_value_transform(Arc::new(Reverse));
+
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+        assert_eq!(
+            lsm.memtable.read().get(b"foo"),
+            Some(b"rab".to_vec()),
+            "Values should be stored in their encoded form"
+        );
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            Some(b"bar".to_vec()),
+            "Values should be decoded on read"
+        );
+
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            Some(b"bar".to_vec()),
+            "Values read from an sstable should be decoded"
+        );
+    }
+
+    /// Stores values as they are, but cannot read them back.
+    struct Undecodable;
+
+    impl ValueTransform for Undecodable {
+        fn encode(&self, value: Vec<u8>) -> Vec<u8> {
+            value
+        }
+        fn decode(&self, _: Vec<u8>) -> Result<Vec<u8>, ChipmunkError> {
+            Err(ChipmunkError::ValueDecode("key unavailable".to_string()))
+        }
+    }
+
+    #[test]
+    fn undecodable_value() {
+        let dir = TempDir::new("undecodable_value").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_value_transform(Arc::new(Undecodable));
+
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+        assert!(matches!(
+            lsm.get(b"foo".to_vec()),
+            Err(ChipmunkError::ValueDecode(_))
+        ));
+        assert!(lsm.scan(..).next().is_some_and(|entry| entry.is_err()));
+
+        let _changes = lsm.subscribe();
+        assert!(matches!(
+            lsm.insert(b"foo".to_vec(), b"baz".to_vec()),
+            Err(ChipmunkError::ValueDecode(_))
+        ));
+        assert_eq!(
+            lsm.sequence(),
+            1,
+            "Writes which cannot be published are refused"
+        );
+    }
+
+    #[test]
+    fn ttl() {
+        let dir = TempDir::new("ttl").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+
+        lsm.insert_with_ttl(b"foo".to_vec(), b"bar".to_vec(), Duration::from_millis(50))
+            .unwrap();
+        lsm.insert_with_ttl(b"baz".to_vec(), b"qux".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), Some(b"bar".to_vec()));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            None,
+            "Expired keys should not be returned"
+        );
+
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), None);
+        assert_eq!(
+            lsm.get(b"baz".to_vec()).unwrap(),
+            Some(b"qux".to_vec()),
+            "Unexpired keys should survive a flush"
+        );
+    }
+
+    #[test]
+    fn huge_ttl() {
+        let dir = TempDir::new("huge_ttl").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+
+        lsm.insert_with_ttl(b"foo".to_vec(), b"bar".to_vec(), Duration::MAX)
+            .unwrap();
+        lsm.write_batch(vec![BatchWrite::Put {
+            key: b"baz".to_vec(),
+            value: b"qux".to_vec(),
+            ttl: Some(Duration::MAX),
+        }])
+        .unwrap();
+        for key in [b"foo", b"baz"] {
+            let stamped = lsm.get_stamped(key.to_vec()).unwrap().unwrap();
+            assert_eq!(
+                stamped.expires_at,
+                Some(u64::MAX),
+                "A time-to-live beyond u64 milliseconds never expires"
+            );
+        }
+    }
+
+    #[test]
+    fn frozen_memtable_reads() {
+        let dir = TempDir::new("frozen_memtable_reads").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+
+        // Emulate the window in which a rotated memtable has not yet been
+        // flushed and registered as an SSTable.
+        let frozen = std::mem::replace(
+            &mut *lsm.memtable.write(),
+            Arc::new(Memtable::new(1, MEMTABLE_MAX_SIZE_BYTES)),
+        );
+        lsm.immutable_memtables.write().push(FrozenMemtable {
+            memtable: frozen,
+            wal_segment: 0,
+        });
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            Some(b"bar".to_vec()),
+            "Frozen memtables should be readable"
+        );
+
+        lsm.delete(b"foo".to_vec()).unwrap();
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            None,
+            "A tombstone in the active memtable should shadow frozen data"
+        );
+    }
+
+    #[test]
+    fn immutable_memtables() {
+        let dir = TempDir::new("immutable_memtables").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+
+        for i in 0..=DEFAULT_MAX_IMMUTABLE_MEMTABLES {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+            lsm.rotate_memtable().unwrap();
+        }
+        assert_eq!(
+            lsm.immutable_memtables.read().len(),
+            DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+            "Frozen memtables beyond the limit should be flushed"
+        );
+        assert_eq!(lsm.sstables.lock().len(), 1);
+
+        for i in 0..=DEFAULT_MAX_IMMUTABLE_MEMTABLES {
+            assert_eq!(
+                lsm.get(format!("key{i}").into_bytes()).unwrap(),
+                Some(b"value".to_vec()),
+                "Keys should be readable from both frozen memtables and sstables"
+            );
+        }
+
+        lsm.flush_immutable_memtables(0).unwrap();
+        assert!(lsm.immutable_memtables.read().is_empty());
+    }
+
+    #[test]
+    fn immutable_memtable_limit() {
+        let dir = TempDir::new("immutable_memtable_limit").unwrap();
+        let lsm = Lsm::new(
+            WalConfig::new(
+                0,
+                WAL_MAX_SEGMENT_SIZE_BYTES,
+                dir.path().to_path_buf(),
+                None,
+            ),
+            MemtableConfig::new(0, MEMTABLE_MAX_SIZE_BYTES).with_max_immutable_memtables(3),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+
+        for i in 0..3 {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+            lsm.rotate_memtable().unwrap();
+            assert_eq!(lsm.immutable_memtables.read().len(), i + 1);
+            assert!(
+                lsm.sstables.lock().is_empty(),
+                "Frozen memtables within the limit should not be flushed"
+            );
+        }
+        lsm.insert(b"key3".to_vec(), b"value".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        assert_eq!(lsm.immutable_memtables.read().len(), 3);
+        assert_eq!(
+            lsm.sstables.lock().len(),
+            1,
+            "Only the oldest frozen memtable should be flushed"
+        );
+    }
+
+    #[test]
+    fn block_cache() {
+        let dir = TempDir::new("block_cache").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), Some(b"bar".to_vec()));
+        }
+        assert_eq!(
+            lsm.block_cache.hits_and_misses(),
+            (2, 1),
+            "Only the first read should go to disk"
+        );
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = TempDir::new("compaction").unwrap();
+        let lsm = create_lsm(0, &dir, 1024, 1024);
+        // Every file is left in L1 until compaction is forced
+        lsm.set_compaction_config(CompactionConfig {
+            l1_file_trigger: None,
+            max_l2_files: None,
+            ..CompactionConfig::default()
+        });
+
+        let dir_size = || {
+            let entries = WalkDir::new(dir.path()).into_iter();
+            let len: walkdir::Result<u64> = entries
+                .map(|res| {
+                    res.and_then(|entry| entry.metadata())
+                        .map(|metadata| metadata.len())
+                })
+                .sum();
+            len.expect("fail to get directory size")
+        };
+
+        let mut current_size = dir_size();
+
+        for i in 1..=3 {
+            for j in 0..=1000 {
+                // The same key value is used, but overwritten on different
+                // iterations to force entries which should be discarded.
+                let key = format!("key{j}").as_bytes().to_vec();
+                let value = format!("value{i}-{j}").as_bytes().to_vec();
+                lsm.insert(key.clone(), value).unwrap();
+
+                // Delete every 10th key to accumulate tombstones
+                if j % 10 == 0 {
+                    lsm.delete(key).unwrap();
+                }
+
+                current_size = dir_size();
+            }
+        }
+
+        let final_size = current_size;
+        let l1_files = lsm.sstables.lock().len() as u64;
+        let stats = lsm.force_compaction().unwrap();
+        let post_compaction_size = dir_size();
+        assert_eq!(stats.input_files, l1_files);
+        assert_eq!(stats.output_files, 1);
+        assert!(stats.dropped_tombstones > 0);
+        assert!(stats.bytes_written < stats.bytes_read);
+        assert_eq!(lsm.compaction_stats(), stats);
+
+        assert!(post_compaction_size < final_size);
+        assert_ne!(
+            lsm.memtable_id(),
+            0,
+            "Memtable rotation should increment the ID"
+        );
+        assert_eq!(
+            lsm.sstables.lock().len(),
+            0,
+            "L1 SSTables on disk should be 0 after a full compaction cycle"
+        );
+    }
+
+    #[test]
+    fn scan() {
+        let dir = TempDir::new("scan").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let flush = || {
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+        let scan = |range: (Bound<Bytes>, Bound<Bytes>)| -> Vec<(Bytes, Bytes)> {
+            lsm.scan(range).map(Result::unwrap).collect()
+        };
+
+        // Spread versions of the keys across L2, L1 and the memtable
+        for i in 0..6 {
+            lsm.insert(format!("key{i}").into_bytes(), b"l2".to_vec())
+                .unwrap();
+        }
+        flush();
+        lsm.force_compaction().unwrap();
+        lsm.insert(b"key1".to_vec(), b"l1".to_vec()).unwrap();
+        lsm.delete(b"key2".to_vec()).unwrap();
+        flush();
+        lsm.insert(b"key3".to_vec(), b"memtable".to_vec()).unwrap();
+        lsm.delete(b"key4".to_vec()).unwrap();
+        lsm.insert(b"key6".to_vec(), b"memtable".to_vec()).unwrap();
+
+        let expected = |pairs: &[(&'static str, &'static str)]| -> Vec<(Bytes, Bytes)> {
+            pairs
+                .iter()
+                .map(|(k, v)| (Bytes::from(*k), Bytes::from(*v)))
+                .collect()
+        };
+        assert_eq!(
+            scan((Bound::Unbounded, Bound::Unbounded)),
+            expected(&[
+                ("key0", "l2"),
+                ("key1", "l1"),
+                ("key3", "memtable"),
+                ("key5", "l2"),
+                ("key6", "memtable"),
+            ])
+        );
+        assert_eq!(
+            scan((
+                Bound::Included(Bytes::from("key1")),
+                Bound::Excluded(Bytes::from("key5"))
+            )),
+            expected(&[("key1", "l1"), ("key3", "memtable")])
+        );
+        assert!(scan((Bound::Included(Bytes::from("zzz")), Bound::Unbounded)).is_empty());
+    }
+
+    #[test]
+    fn scan_prefix() {
+        let dir = TempDir::new("scan_prefix").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        for key in [
+            "user:1:name",
+            "user:12:name",
+            "user:2:name",
+            "users",
+            "user;",
+        ] {
+            lsm.insert(key.as_bytes().to_vec(), b"value".to_vec())
+                .unwrap();
+        }
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.insert(b"user:1:email".to_vec(), b"value".to_vec())
+            .unwrap();
+
+        let keys = |prefix: &[u8]| -> Vec<Bytes> {
+            lsm.scan_prefix(prefix)
+                .map(Result::unwrap)
+                .map(|(k, _)| k)
+                .collect()
+        };
+        assert_eq!(
+            keys(b"user:1"),
+            vec!["user:12:name", "user:1:email", "user:1:name"]
+        );
+        assert_eq!(keys(b"user:1:"), vec!["user:1:email", "user:1:name"]);
+        assert_eq!(keys(b"user:").len(), 4);
+        assert_eq!(keys(b"").len(), 6);
+        assert!(keys(b"other").is_empty());
+
+        assert_eq!(prefix_successor(b"ab"), Some(Bytes::from("ac")));
+        assert_eq!(prefix_successor(&[b'a', 0xff]), Some(Bytes::from("b")));
+        assert_eq!(prefix_successor(&[0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn open() {
+        let dir = TempDir::new("open").unwrap();
+        {
+            let lsm = Lsm::open(dir.path(), Options::default()).unwrap();
+            assert!(matches!(
+                Lsm::open(dir.path(), Options::default()),
+                Err(ChipmunkError::DirectoryLocked { .. })
+            ));
+            lsm.insert(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+            lsm.insert(b"unflushed".to_vec(), b"value".to_vec())
+                .unwrap();
+        }
+
+        let lsm = Lsm::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(
+            lsm.get(b"flushed".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            lsm.get(b"unflushed".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        // An SSTable left behind without a manifest record is not overwritten.
+        std::fs::copy(
+            dir.path().join(manifest::file_name(LEVEL_1, 0)),
+            dir.path().join(manifest::file_name(LEVEL_1, 7)),
+        )
+        .unwrap();
+        drop(lsm);
+
+        let lsm = Lsm::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(lsm.memtable_id(), 8);
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        assert_eq!(
+            lsm.get(b"flushed".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            lsm.get(b"unflushed".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[test]
+    fn comparator() {
+        let dir = TempDir::new("comparator").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_comparator(Arc::new(TimeSeries { prefix_len: 2 }));
+        let key = |series: &str, time: u8| [series.as_bytes(), &[time]].concat();
+        let keys = |entries: Vec<(Bytes, Bytes)>| -> Vec<Vec<u8>> {
+            entries.into_iter().map(|(k, _)| k.to_vec()).collect()
+        };
+
+        for time in [1, 3, 5] {
+            lsm.insert(key("s1", time), vec![time]).unwrap();
+            lsm.insert(key("s2", time), vec![time]).unwrap();
+        }
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        for time in [2, 4] {
+            lsm.insert(key("s1", time), vec![time]).unwrap();
+        }
+
+        assert_eq!(
+            keys(lsm.scan_prefix(b"s1").map(Result::unwrap).collect()),
+            (1..=5)
+                .rev()
+                .map(|time| key("s1", time))
+                .collect::<Vec<_>>(),
+            "Each series should be read newest first"
+        );
+        assert_eq!(lsm.get(key("s2", 3)).unwrap(), Some(vec![3]));
+        assert_eq!(
+            lsm.multi_get(&[key("s2", 5), key("s1", 2), key("s1", 6)])
+                .unwrap(),
+            vec![Some(vec![5]), Some(vec![2]), None]
+        );
+
+        // The range runs from the newest time to the oldest.
+        lsm.delete_range(key("s1", 4), key("s1", 1)).unwrap();
+        lsm.delete_range(key("s2", 1), key("s2", 5)).unwrap();
+        lsm.force_compaction().unwrap();
+        assert_eq!(
+            keys(lsm.scan(..).map(Result::unwrap).collect()),
+            vec![
+                key("s1", 5),
+                key("s1", 1),
+                key("s2", 5),
+                key("s2", 3),
+                key("s2", 1)
+            ]
+        );
+        assert_eq!(lsm.get(key("s1", 3)).unwrap(), None);
+    }
+
+    #[test]
+    fn snapshot() {
+        let dir = TempDir::new("snapshot").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let flush = || {
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+
+        lsm.insert(b"flushed".to_vec(), b"old".to_vec()).unwrap();
+        flush();
+        lsm.insert(b"frozen".to_vec(), b"old".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.insert(b"active".to_vec(), b"old".to_vec()).unwrap();
+        assert_eq!(lsm.sequence(), 3);
+
+        let snapshot = lsm.snapshot().unwrap();
+        assert_eq!(snapshot.sequence(), 3);
+
+        lsm.insert(b"flushed".to_vec(), b"new".to_vec()).unwrap();
+        lsm.delete(b"frozen".to_vec()).unwrap();
+        lsm.insert(b"active".to_vec(), b"new".to_vec()).unwrap();
+        lsm.insert(b"later".to_vec(), b"new".to_vec()).unwrap();
+        // Move everything, including the snapshot's files, into L2
+        lsm.flush_immutable_memtables(0).unwrap();
+        flush();
+        lsm.force_compaction().unwrap();
+        assert_eq!(lsm.sequence(), 7);
+
+        assert_eq!(lsm.get(b"flushed".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.get(b"frozen".to_vec()).unwrap(), None);
+        for key in ["active", "flushed", "frozen"] {
+            assert_eq!(
+                snapshot.get(key.as_bytes()).unwrap(),
+                Some(b"old".to_vec()),
+                "{key} should be unchanged through the snapshot"
+            );
+        }
+        assert_eq!(snapshot.get(b"later").unwrap(), None);
+
+        let scanned: Vec<_> = snapshot
+            .scan(..)
+            .map(Result::unwrap)
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(
+            scanned,
+            vec![
+                Bytes::from("active"),
+                Bytes::from("flushed"),
+                Bytes::from("frozen")
+            ]
+        );
+        assert_eq!(snapshot.scan_prefix(b"fr").map(Result::unwrap).count(), 1);
+        assert_eq!(lsm.scan(..).map(Result::unwrap).count(), 3);
+    }
+
+    #[test]
+    fn multi_get() {
+        let dir = TempDir::new("multi_get").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        for i in 0..50 {
+            lsm.insert(format!("key{i:02}").into_bytes(), b"l2".to_vec())
+                .unwrap();
+        }
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.force_compaction().unwrap();
+        lsm.insert(b"key10".to_vec(), b"l1".to_vec()).unwrap();
+        lsm.delete(b"key20".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.insert(b"key30".to_vec(), b"frozen".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.insert(b"key40".to_vec(), b"memtable".to_vec()).unwrap();
+        lsm.delete(b"key41".to_vec()).unwrap();
+
+        let keys: Vec<Vec<u8>> = [
+            "key41", "key40", "missing", "key30", "key20", "key10", "key00", "key10",
+        ]
+        .iter()
+        .map(|k| k.as_bytes().to_vec())
+        .collect();
+        let expected: Vec<_> = keys.iter().map(|k| lsm.get(k.clone()).unwrap()).collect();
+        assert_eq!(lsm.multi_get(&keys).unwrap(), expected);
+        assert_eq!(
+            expected,
+            vec![
+                None,
+                Some(b"memtable".to_vec()),
+                None,
+                Some(b"frozen".to_vec()),
+                None,
+                Some(b"l1".to_vec()),
+                Some(b"l2".to_vec()),
+                Some(b"l1".to_vec()),
+            ]
+        );
+        assert!(lsm.multi_get(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn max_value_size() {
+        let dir = TempDir::new("max_value_size").unwrap();
+        let lsm = Lsm::new(
+            WalConfig::new(
+                0,
+                WAL_MAX_SEGMENT_SIZE_BYTES,
+                dir.path().to_path_buf(),
+                None,
+            ),
+            MemtableConfig::new(0, MEMTABLE_MAX_SIZE_BYTES).with_max_value_size(4),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+
+        lsm.insert(b"key".to_vec(), b"1234".to_vec()).unwrap();
+        assert!(matches!(
+            lsm.insert(b"key".to_vec(), b"12345".to_vec()),
+            Err(ChipmunkError::ValueTooLarge { size: 5, max: 4 })
+        ));
+        assert!(matches!(
+            lsm.compare_and_swap(b"key".to_vec(), Some(b"1234".to_vec()), Some(vec![0; 8])),
+            Err(ChipmunkError::ValueTooLarge { .. })
+        ));
+        assert_eq!(lsm.get(b"key".to_vec()).unwrap(), Some(b"1234".to_vec()));
+        assert_eq!(lsm.sequence(), 1, "Rejected values are not written");
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let dir = TempDir::new("compare_and_swap").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let key = || b"counter".to_vec();
+
+        assert_eq!(
+            lsm.compare_and_swap(key(), None, Some(b"0".to_vec()))
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            lsm.compare_and_swap(key(), None, Some(b"1".to_vec()))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            lsm.compare_and_swap(key(), Some(b"5".to_vec()), Some(b"6".to_vec()))
+                .unwrap(),
+            None
+        );
+        assert_eq!(lsm.get(key()).unwrap(), Some(b"0".to_vec()));
+        assert_eq!(lsm.sequence(), 1, "Failed swaps are not written");
+
+        // Concurrent increments never lose an update
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        loop {
+                            let current = lsm.get(key()).unwrap();
+                            let next = String::from_utf8(current.clone().unwrap())
+                                .unwrap()
+                                .parse::<u64>()
+                                .unwrap()
+                                + 1;
+                            let swapped = lsm
+                                .compare_and_swap(
+                                    key(),
+                                    current,
+                                    Some(next.to_string().into_bytes()),
+                                )
+                                .unwrap();
+                            if swapped.is_some() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(lsm.get(key()).unwrap(), Some(b"100".to_vec()));
+
+        assert_eq!(
+            lsm.compare_and_swap(key(), Some(b"100".to_vec()), None)
+                .unwrap(),
+            Some(102)
+        );
+        assert_eq!(lsm.get(key()).unwrap(), None);
+
+        // A swapped value may expire, after which the key is absent again
+        assert_eq!(
+            lsm.compare_and_swap_with_ttl(key(), None, b"0".to_vec(), Duration::from_millis(50))
+                .unwrap(),
+            Some(103)
+        );
+        assert_eq!(lsm.get(key()).unwrap(), Some(b"0".to_vec()));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            lsm.compare_and_swap(key(), None, Some(b"1".to_vec()))
+                .unwrap(),
+            Some(104)
+        );
+    }
+
+    #[test]
+    fn concurrent_freeze() {
+        let dir = TempDir::new("concurrent_freeze").unwrap();
+        {
+            // Every write fills a WAL segment, rotating to the next
+            let lsm = create_lsm(0, &dir, 64, MEMTABLE_MAX_SIZE_BYTES);
+            lsm.insert(b"before".to_vec(), b"value".to_vec()).unwrap();
+            let wal = lsm.wal.as_ref().unwrap().lock();
+            std::thread::scope(|s| {
+                // The write waits on the WAL, then the freeze on the write
+                s.spawn(|| lsm.insert(b"key".to_vec(), b"value".to_vec()).unwrap());
+                std::thread::sleep(Duration::from_millis(50));
+                s.spawn(|| lsm.freeze_memtable());
+                std::thread::sleep(Duration::from_millis(50));
+                drop(wal);
+            });
+            lsm.flush_immutable_memtables(0).unwrap();
+            // Dropped without closing, as though the process had crashed
+        }
+
+        let lsm = create_lsm(0, &dir, 64, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert_eq!(
+            lsm.get(b"key".to_vec()).unwrap(),
+            Some(b"value".to_vec()),
+            "The segment holding the write is kept until the write is flushed"
+        );
+    }
+
+    #[test]
+    fn write_batch() {
+        let dir = TempDir::new("write_batch").unwrap();
+        {
+            let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+            lsm.insert(b"old".to_vec(), b"value".to_vec()).unwrap();
+            let sequence = lsm
+                .write_batch(vec![
+                    BatchWrite::Put {
+                        key: b"a".to_vec(),
+                        value: b"1".to_vec(),
+                        ttl: None,
+                    },
+                    BatchWrite::Delete {
+                        key: b"old".to_vec(),
+                    },
+                    BatchWrite::Put {
+                        key: b"a".to_vec(),
+                        value: b"2".to_vec(),
+                        ttl: Some(Duration::from_secs(60)),
+                    },
+                ])
+                .unwrap();
+            assert_eq!(sequence, 4, "Every write of the batch is sequenced");
+            assert_eq!(lsm.write_batch(vec![]).unwrap(), 4);
+        }
+
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert_eq!(lsm.sequence(), 4);
+        assert_eq!(lsm.get(b"old".to_vec()).unwrap(), None);
+        let a = lsm.get_stamped(b"a".to_vec()).unwrap().unwrap();
+        assert_eq!(a.value, b"2", "Writes are applied in order");
+        assert!(a.expires_at.is_some());
+    }
+
+    #[test]
+    fn subscribe() {
+        let dir = TempDir::new("subscribe").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_value_transform(Arc::new(Reverse));
+        lsm.insert(b"unseen".to_vec(), b"value".to_vec()).unwrap();
+
+        let mut changes = lsm.subscribe();
+        lsm.insert_with_ttl(b"a".to_vec(), b"abc".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        lsm.delete(b"a".to_vec()).unwrap();
+        lsm.delete_range(b"b".to_vec(), b"c".to_vec()).unwrap();
+
+        let put = changes.try_recv().unwrap();
+        assert_eq!(put.stamp.sequence, 2, "Only later writes are sent");
+        assert!(
+            matches!(
+                put.kind,
+                ChangeKind::Put { key, value, expires_at: Some(_) } if key == "a" && value == "abc"
+            ),
+            "Values are sent as they were written"
+        );
+        assert_eq!(
+            changes.try_recv().unwrap().kind,
+            ChangeKind::Delete {
+                key: Bytes::from("a")
+            }
+        );
+        let range = changes.try_recv().unwrap();
+        assert_eq!(range.stamp.sequence, 4);
+        assert_eq!(
+            range.kind,
+            ChangeKind::DeleteRange {
+                start: Bytes::from("b"),
+                end: Bytes::from("c")
+            }
+        );
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn subscribe_after() {
+        let dir = TempDir::new("subscribe_after").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"unretained".to_vec(), b"value".to_vec())
+            .unwrap();
+        drop(lsm.subscribe());
+        for i in 0..CHANGE_BUFFER + 2 {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        let latest = lsm.sequence();
+
+        let resumed = lsm.subscribe_after(latest - 2);
+        assert_eq!(resumed.missed, 0);
+        let replayed: Vec<u64> = resumed.replayed.iter().map(|c| c.stamp.sequence).collect();
+        assert_eq!(replayed, vec![latest - 1, latest]);
+
+        let resumed = lsm.subscribe_after(0);
+        assert_eq!(resumed.replayed.len(), CHANGE_BUFFER);
+        assert_eq!(
+            resumed.missed,
+            latest - CHANGE_BUFFER as u64,
+            "Changes past those retained are missed"
+        );
+        assert_eq!(resumed.replayed[0].stamp.sequence, resumed.missed + 1);
+
+        let mut resumed = lsm.subscribe_after(latest);
+        assert!(resumed.replayed.is_empty());
+        lsm.delete(b"key0".to_vec()).unwrap();
+        assert_eq!(
+            resumed.changes.try_recv().unwrap().stamp.sequence,
+            latest + 1
+        );
+        assert_eq!(
+            lsm.subscribe_after(latest + 10).missed,
+            0,
+            "Nothing is ahead"
+        );
+    }
+
+    #[test]
+    fn wal_changes_after() {
+        let dir = TempDir::new("wal_changes_after").unwrap();
+        let lsm = create_lsm(0, &dir, 128, MEMTABLE_MAX_SIZE_BYTES);
+        for i in 0..10 {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        lsm.delete(b"key0".to_vec()).unwrap();
+        lsm.delete_range(b"key1".to_vec(), b"key3".to_vec())
+            .unwrap();
+
+        let mut changes = lsm.wal_changes_after(9).unwrap();
+        assert_eq!(changes.latest, 12);
+        let backlog: Vec<Change> = changes.backlog.by_ref().map(Result::unwrap).collect();
+        let sequences: Vec<u64> = backlog.iter().map(|c| c.stamp.sequence).collect();
+        assert_eq!(sequences, vec![10, 11, 12], "Read back across segments");
+        assert!(
+            matches!(&backlog[0].kind, ChangeKind::Put { key, value, .. } if key == "key9" && value == "value")
+        );
+        assert!(matches!(&backlog[1].kind, ChangeKind::Delete { key } if key == "key0"));
+        assert!(
+            matches!(&backlog[2].kind, ChangeKind::DeleteRange { start, end } if start == "key1" && end == "key3")
+        );
+        lsm.insert(b"later".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(changes.changes.try_recv().unwrap().stamp.sequence, 13);
+
+        let replica_dir = TempDir::new("wal_changes_after_replica").unwrap();
+        let replica = create_lsm(0, &replica_dir, 128, MEMTABLE_MAX_SIZE_BYTES);
+        for change in lsm.wal_changes_after(0).unwrap().backlog {
+            assert!(replica.apply_replicated(change.unwrap()).unwrap());
+        }
+        assert_eq!(replica.sequence(), lsm.sequence());
+        assert_eq!(
+            replica.get(b"key9".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            replica.get(b"later".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        for deleted in ["key0", "key1", "key2"] {
+            assert_eq!(replica.get(deleted.as_bytes().to_vec()).unwrap(), None);
+        }
+        assert_eq!(
+            replica.get(b"key3".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        let mut backlog = lsm.wal_changes_after(0).unwrap().backlog;
+        let first = backlog.next().unwrap().unwrap();
+        assert!(
+            !replica.apply_replicated(first.clone()).unwrap(),
+            "Changes already applied are skipped"
+        );
+        let gap = Change {
+            stamp: WriteStamp {
+                sequence: replica.sequence() + 2,
+                ..first.stamp
+            },
+            ..first
+        };
+        assert!(matches!(
+            replica.apply_replicated(gap),
+            Err(ChipmunkError::ReplicationGap {
+                expected: 14,
+                found: 15
+            })
+        ));
+
+        // Segments are removed once their writes are flushed.
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        let first = lsm.wal_changes_after(0).unwrap().backlog.next();
+        assert!(first.is_none_or(|c| c.unwrap().stamp.sequence > 1));
+    }
+
+    #[test]
+    fn wal_changes_retained() {
+        let dir = TempDir::new("wal_changes_retained").unwrap();
+        let open = || {
+            let lsm = Lsm::new(
+                WalConfig::new(0, 128, dir.path().to_path_buf(), None)
+                    .with_retention(Duration::from_secs(3600)),
+                MemtableConfig::new(0, MEMTABLE_MAX_SIZE_BYTES),
+                SstableConfig::default(),
+                CompactionConfig::default(),
+                WriteStallConfig::default(),
+            )
+            .unwrap();
+            lsm.restore().unwrap();
+            lsm
+        };
+        let lsm = open();
+        for i in 0..10 {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.close().unwrap();
+        drop(lsm);
+
+        // Flushed changes are read back from the archived segments, across
+        // a restart.
+        let lsm = open();
+        let mut changes = lsm.wal_changes_after(0).unwrap();
+        assert_eq!(changes.first_after(0).unwrap().unwrap().stamp.sequence, 1);
+        let sequences: Vec<u64> = changes.backlog.map(|c| c.unwrap().stamp.sequence).collect();
+        assert_eq!(sequences, (2..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn write_batch_value_too_large() {
+        let dir = TempDir::new("write_batch_value_too_large").unwrap();
+        let lsm = Lsm::new(
+            WalConfig::new(
+                0,
+                WAL_MAX_SEGMENT_SIZE_BYTES,
+                dir.path().to_path_buf(),
+                None,
+            ),
+            MemtableConfig::new(0, MEMTABLE_MAX_SIZE_BYTES).with_max_value_size(4),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        let put = |key: &[u8], value: &[u8]| BatchWrite::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl: None,
+        };
+
+        assert!(matches!(
+            lsm.write_batch(vec![put(b"a", b"1"), put(b"b", b"12345")]),
+            Err(ChipmunkError::ValueTooLarge { size: 5, max: 4 })
+        ));
+        assert_eq!(
+            lsm.get(b"a".to_vec()).unwrap(),
+            None,
+            "No write of the batch is made"
+        );
+        assert_eq!(lsm.sequence(), 0);
+    }
+
+    #[test]
+    fn delete_range() {
+        let dir = TempDir::new("delete_range").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let flush = || {
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+        let keys = || -> Vec<Bytes> { lsm.scan(..).map(Result::unwrap).map(|(k, _)| k).collect() };
+
+        for tenant in 1..=3 {
+            for i in 0..3 {
+                lsm.insert(format!("tenant:{tenant}:{i}").into_bytes(), b"l2".to_vec())
+                    .unwrap();
+            }
+        }
+        flush();
+        lsm.force_compaction().unwrap();
+        lsm.insert(b"tenant:2:1".to_vec(), b"l1".to_vec()).unwrap();
+        flush();
+        lsm.insert(b"tenant:2:2".to_vec(), b"memtable".to_vec())
+            .unwrap();
+
+        lsm.delete_range(b"tenant:2:".to_vec(), b"tenant:2;".to_vec())
+            .unwrap();
+        lsm.insert(b"tenant:2:9".to_vec(), b"later".to_vec())
+            .unwrap();
+
+        let expected: Vec<Bytes> = [
+            "tenant:1:0",
+            "tenant:1:1",
+            "tenant:1:2",
+            "tenant:2:9",
+            "tenant:3:0",
+            "tenant:3:1",
+            "tenant:3:2",
+        ]
+        .into_iter()
+        .map(Bytes::from)
+        .collect();
+        let check = |stage: &str| {
+            for i in 0..3 {
+                assert_eq!(
+                    lsm.get(format!("tenant:2:{i}").into_bytes()).unwrap(),
+                    None,
+                    "tenant:2:{i} should be deleted {stage}"
+                );
+            }
+            assert_eq!(
+                lsm.get(b"tenant:2:9".to_vec()).unwrap(),
+                Some(b"later".to_vec())
+            );
+            assert_eq!(
+                lsm.get(b"tenant:3:0".to_vec()).unwrap(),
+                Some(b"l2".to_vec())
+            );
+            assert_eq!(keys(), expected, "Scan {stage}");
+            assert_eq!(
+                lsm.multi_get(&[b"tenant:2:0".to_vec(), b"tenant:2:9".to_vec()])
+                    .unwrap(),
+                vec![None, Some(b"later".to_vec())]
+            );
+        };
+        check("within the memtable");
+        flush();
+        check("within L1");
+        lsm.force_compaction().unwrap();
+        check("after compaction");
+
+        // Empty ranges delete nothing
+        lsm.delete_range(b"tenant:3;".to_vec(), b"tenant:3:".to_vec())
+            .unwrap();
+        assert_eq!(
+            lsm.get(b"tenant:3:0".to_vec()).unwrap(),
+            Some(b"l2".to_vec())
+        );
+    }
+
+    #[test]
+    fn l2_reads() {
+        let dir = TempDir::new("l2_reads").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let flush = || {
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+
+        lsm.insert(b"foo".to_vec(), b"old".to_vec()).unwrap();
+        lsm.insert(b"bar".to_vec(), b"old".to_vec()).unwrap();
+        flush();
+        lsm.force_compaction().unwrap();
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            Some(b"old".to_vec()),
+            "Compacted keys should be read from L2"
+        );
+
+        lsm.insert(b"foo".to_vec(), b"new".to_vec()).unwrap();
+        lsm.delete(b"bar".to_vec()).unwrap();
+        flush();
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), Some(b"new".to_vec()));
+        let stats = lsm.force_compaction().unwrap();
+        assert_eq!(
+            stats.dropped_tombstones, 1,
+            "The older L2 file is merged, leaving nothing for the tombstone to shadow"
+        );
+        assert_eq!(*lsm.l2_files.lock(), vec![1]);
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            Some(b"new".to_vec()),
+            "Newer entries win the merge"
+        );
+        assert_eq!(lsm.get(b"bar".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn compaction_drops_tombstones() {
+        let dir = TempDir::new("compaction_drops_tombstones").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let flush = || {
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+        let l2_keys = || {
+            let mut keys = Vec::new();
+            for id in lsm.l2_files.lock().iter() {
+                let path = dir.path().join(manifest::file_name(LEVEL_2, *id));
+                let table = Sstable::open(&path).unwrap();
+                assert!(table.metadata().range_tombstones.is_empty());
+                for result in table.iter() {
+                    let (key, entry) = result.unwrap();
+                    assert!(entry.value.is_some(), "No tombstone is left in L2");
+                    keys.push(key);
+                }
+            }
+            keys.sort();
+            keys
+        };
+
+        for key in ["a", "b", "c"] {
+            lsm.insert(key.into(), b"value".to_vec()).unwrap();
+        }
+        flush();
+        lsm.compact(false).unwrap();
+        lsm.delete(b"a".to_vec()).unwrap();
+        lsm.delete_range(b"c".to_vec(), b"d".to_vec()).unwrap();
+        flush();
+        let stats = lsm.compact(false).unwrap();
+        assert_eq!(stats.dropped_tombstones, 2);
+        assert_eq!(l2_keys(), vec![Bytes::from("b")]);
+
+        // Only the L2 files which overlap L1 are merged
+        lsm.insert(b"x".to_vec(), b"value".to_vec()).unwrap();
+        flush();
+        lsm.compact(false).unwrap();
+        let untouched = lsm.l2_files.lock()[0];
+        assert_eq!(lsm.l2_files.lock().len(), 2);
+        lsm.delete(b"x".to_vec()).unwrap();
+        flush();
+        let stats = lsm.compact(false).unwrap();
+        assert_eq!(stats.input_files, 2);
+        assert_eq!(stats.dropped_tombstones, 1);
+        assert_eq!(lsm.l2_files.lock()[0], untouched);
+        assert_eq!(l2_keys(), vec![Bytes::from("b")]);
+        assert_eq!(lsm.get(b"b".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn compaction_filter() {
+        struct Redact;
+        impl CompactionFilter for Redact {
+            fn filter(&self, key: &[u8], value: &[u8]) -> CompactionDecision {
+                match (key, value) {
+                    (b"drop", _) => CompactionDecision::Remove,
+                    (_, b"secret") => CompactionDecision::ChangeValue(b"redacted".to_vec()),
+                    _ => CompactionDecision::Keep,
+                }
+            }
+        }
+
+        let dir = TempDir::new("compaction_filter").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_compaction_filter(Arc::new(Redact));
+        lsm.insert(b"drop".to_vec(), b"value".to_vec()).unwrap();
+        lsm.insert(b"keep".to_vec(), b"value".to_vec()).unwrap();
+        lsm.insert(b"password".to_vec(), b"secret".to_vec())
+            .unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+
+        let stats = lsm.force_compaction().unwrap();
+        assert_eq!(stats.filtered_entries, 2);
+        let l2_id = lsm.l2_files.lock()[0];
+        let l2 = Sstable::open(&dir.path().join(manifest::file_name(LEVEL_2, l2_id))).unwrap();
+        let entries: Vec<_> = l2
+            .iter()
+            .map(|r| {
+                let (key, entry) = r.unwrap();
+                (key, entry.value.unwrap())
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Bytes::from("keep"), Bytes::from("value")),
+                (Bytes::from("password"), Bytes::from("redacted")),
+            ]
+        );
+    }
+
+    #[test]
+    fn compaction_triggers() {
+        let dir = TempDir::new("compaction_triggers").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_compaction_config(CompactionConfig::default().with_l1_file_trigger(3));
+
+        // Thresholds are checked as the memtable rotates, before the file
+        // holding its data is flushed.
+        let flush = |lsm: &Lsm, i: usize| {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+        for i in 0..3 {
+            flush(&lsm, i);
+        }
+        assert_eq!(lsm.sstables.lock().len(), 3);
+        flush(&lsm, 3);
+        assert_eq!(lsm.sstables.lock().len(), 1, "Reaching 3 L1 files compacts");
+        assert_eq!(lsm.l2_files.lock().len(), 1);
+
+        // The L1 file is far larger than a tenth of the L2 file
+        lsm.set_compaction_config(CompactionConfig::default().with_level_size_ratio(0.1));
+        flush(&lsm, 4);
+        assert_eq!(lsm.sstables.lock().len(), 1);
+        assert_eq!(lsm.l2_files.lock().len(), 2);
+
+        lsm.set_compaction_config(CompactionConfig::default().with_l1_size_trigger(u64::MAX));
+        flush(&lsm, 5);
+        assert_eq!(lsm.sstables.lock().len(), 2, "No threshold is exceeded");
+
+        // Too many L2 files are merged along with L1, rather than added to
+        lsm.set_compaction_config(CompactionConfig::default().with_max_l2_files(1));
+        flush(&lsm, 6);
+        assert_eq!(lsm.sstables.lock().len(), 1);
+        assert_eq!(lsm.l2_files.lock().len(), 1, "L2 is merged into one file");
+        for i in 0..=6 {
+            assert_eq!(
+                lsm.get(format!("key{i}").into_bytes()).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn default_compaction() {
+        let dir = TempDir::new("default_compaction").unwrap();
+        let config = ChipmunkConfig::default();
+        let lsm = Lsm::new(
+            WalConfig {
+                log_directory: dir.path().to_path_buf(),
+                ..config.wal
+            },
+            config.memtable,
+            config.sstable,
+            config.compaction,
+            config.write_stall,
+        )
+        .unwrap();
+
+        // The most recently rotated memtable is held in memory, so one more
+        // rotation than the trigger is needed to flush enough L1 files.
+        let rotate = |i: usize| {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+            lsm.rotate_memtable().unwrap();
+        };
+        for i in 0..DEFAULT_L1_FILE_TRIGGER {
+            rotate(i);
+        }
+        assert_eq!(lsm.compaction_stats().compactions, 0);
+        rotate(DEFAULT_L1_FILE_TRIGGER);
+        assert_eq!(
+            lsm.compaction_stats().compactions,
+            1,
+            "The default thresholds compact L1"
+        );
+        assert!(lsm.sstables.lock().is_empty());
+        assert_eq!(lsm.l2_files.lock().len(), 1);
+    }
+
+    #[test]
+    fn flush_interval() {
+        let dir = TempDir::new("flush_interval").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(!lsm.flush_if_due().unwrap(), "No interval is set");
+
+        lsm.set_flush_interval(Some(Duration::from_secs(60)));
+        assert!(!lsm.flush_if_due().unwrap(), "The memtable is too young");
+
+        lsm.set_flush_interval(Some(Duration::ZERO));
+        assert!(lsm.flush_if_due().unwrap());
+        assert_eq!(lsm.sstables.lock().len(), 1);
+        assert!(
+            !lsm.flush_if_due().unwrap(),
+            "Empty memtables are not flushed"
+        );
+        assert_eq!(lsm.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn write_stalls() {
+        let dir = TempDir::new("write_stalls").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.set_write_stall_config(
+            WriteStallConfig::default()
+                .with_l1_triggers(2, 3)
+                .with_slowdown_delay(Duration::from_millis(50)),
+        );
+
+        let flush = |lsm: &Lsm, i: usize| {
+            lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                .unwrap();
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+        flush(&lsm, 0);
+        assert_eq!(lsm.write_stall(), WriteStall::Normal);
+        flush(&lsm, 1);
+        assert_eq!(lsm.write_stall(), WriteStall::Slowdown);
+        let start = std::time::Instant::now();
+        lsm.insert(b"slow".to_vec(), b"value".to_vec()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        flush(&lsm, 2);
+        assert_eq!(lsm.write_stall(), WriteStall::Stop);
+        // The write waits on a compaction of L1 before going ahead
+        lsm.insert(b"stopped".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(lsm.write_stall(), WriteStall::Normal);
+        assert!(lsm.sstables.lock().is_empty());
+        assert_eq!(lsm.compaction_stats().compactions, 1);
+        assert_eq!(lsm.get(b"key2".to_vec()).unwrap(), Some(b"value".to_vec()));
+
+        // Limits which cannot be relieved reject writes
+        lsm.set_write_stall_config(WriteStallConfig::default().with_l1_triggers(0, 0));
+        assert!(matches!(
+            lsm.insert(b"rejected".to_vec(), b"value".to_vec()),
+            Err(ChipmunkError::WriteStall)
+        ));
+    }
+
+    #[test]
+    fn bloom() {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .init();
+        let dir = TempDir::new("bloom").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+
+        lsm.insert(b"bar".to_vec(), b"bar".to_vec()).unwrap();
+        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
+        lsm.insert(b"qux".to_vec(), b"bar".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.delete(b"foo".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+
+        assert_eq!(lsm.get(b"bar".to_vec()).unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(
+            lsm.get(b"foo".to_vec()).unwrap(),
+            None,
+            "The tombstone is found through the newer table's filter"
+        );
+        lsm.reset_statistics();
+        assert_eq!(lsm.get(b"baz".to_vec()).unwrap(), None);
+        let stats = lsm.statistics();
+        assert_eq!(
+            stats.bloom_checks, 1,
+            "Only the first table's key range holds 'baz'"
+        );
+        assert_eq!(stats.bloom_negatives, 1);
+        assert_eq!(stats.block_cache_misses, 0, "No blocks were read");
+        lsm.close().unwrap();
+        drop(lsm);
+
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert_eq!(
+            lsm.get(b"bar".to_vec()).unwrap(),
+            Some(b"bar".to_vec()),
+            "Keys held only by SSTables are found after the structure was restored"
+        );
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), None);
+        lsm.force_compaction().unwrap();
+        lsm.reset_statistics();
+        assert_eq!(lsm.get(b"foo".to_vec()).unwrap(), None);
+        assert_eq!(
+            lsm.statistics().bloom_negatives,
+            1,
+            "Compaction drops the deleted key from the output's filter"
+        );
+    }
+
+    #[test]
+    fn in_memory() {
+        let dir = TempDir::new("in_memory").unwrap();
+        let create = |in_memory| {
+            Lsm::new(
+                WalConfig::new(
+                    0,
+                    WAL_MAX_SEGMENT_SIZE_BYTES,
+                    dir.path().to_path_buf(),
+                    None,
+                ),
+                MemtableConfig::new(0, 16).with_in_memory(in_memory),
+                SstableConfig::default(),
+                CompactionConfig::default(),
+                WriteStallConfig::default(),
+            )
+            .unwrap()
+        };
+
+        let lsm = create(InMemory::Retain);
+        for i in 0..10 {
+            lsm.insert(
+                format!("key{i}").into_bytes(),
+                b"a value to fill the memtable".to_vec(),
+            )
+            .unwrap();
+        }
+        lsm.delete_range(b"key8".to_vec(), b"key9".to_vec())
+            .unwrap();
+        lsm.force_compaction().unwrap();
+        lsm.close().unwrap();
+        assert!(
+            lsm.get(b"key0".to_vec()).unwrap().is_some(),
+            "Nothing is evicted"
+        );
+        assert!(lsm.get(b"key8".to_vec()).unwrap().is_none());
+        assert_eq!(lsm.scan(..).map(Result::unwrap).count(), 9);
+        assert!(matches!(
+            lsm.checkpoint(&dir.path().join("checkpoint")),
+            Err(ChipmunkError::InMemory(_))
+        ));
+        assert_eq!(
+            std::fs::read_dir(dir.path()).unwrap().count(),
+            0,
+            "Nothing should be written to the log directory"
+        );
+
+        let lsm = create(InMemory::Evict);
+        for i in 0..10 {
+            lsm.insert(
+                format!("key{i}").into_bytes(),
+                b"a value to fill the memtable".to_vec(),
+            )
+            .unwrap();
         }
+        assert_eq!(lsm.immutable_memtables.read().len(), 1);
+        assert!(
+            lsm.get(b"key0".to_vec()).unwrap().is_none(),
+            "The oldest are evicted"
+        );
+        assert!(lsm.get(b"key9".to_vec()).unwrap().is_some());
     }
 
-    pub fn delete(&self, key: Vec<u8>) -> Result<(), ChipmunkError> {
-        debug!(key=?String::from_utf8_lossy(&key), "Deleting key");
-        self.wal
-            .lock()
-            .append(WalEntry::Delete { key: key.clone() })?;
-        self.memtable.delete(key);
-
-        Ok(())
-    }
+    #[test]
+    fn restore_unmanaged_sstables() {
+        let dir = TempDir::new("restore_unmanaged_sstables").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"compacted".to_vec(), b"old".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.force_compaction().unwrap();
+        lsm.insert(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+        lsm.insert(b"compacted".to_vec(), b"new".to_vec()).unwrap();
+        lsm.close().unwrap();
+        drop(lsm);
+        // As written before the manifest existed.
+        std::fs::remove_file(dir.path().join(manifest::MANIFEST_FILE_NAME)).unwrap();
+        let interrupted = dir.path().join("sstable-5.tmp");
+        std::fs::write(&interrupted, b"truncated").unwrap();
 
-    pub fn memtable_id(&self) -> u64 {
-        self.memtable.id()
-    }
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert!(!interrupted.exists());
+        assert_eq!(
+            lsm.get(b"flushed".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            lsm.get(b"compacted".to_vec()).unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            lsm.memtable_id(),
+            2,
+            "The configured ID would overwrite an existing SSTable"
+        );
 
-    /// Restore the LSM-tree by recovering the internal [`Memtable`] and
-    /// [`BloomFilter`].
-    ///
-    /// This works by restoring the WAL and building the memtable from there.
-    /// After this, the bloom filter can be populated.
-    ///
-    /// # Panics
-    /// When a restore operation is conducted when the components are not started
-    /// from scratch - partial restore is not supported.
-    pub fn restore(&mut self) -> Result<(), ChipmunkError> {
-        {
-            let mut wal = self.wal.lock();
-            // Invariant: The restore operation implies that there is currently
-            // nothing in any of the components. A partial restore is not supported
-            // at the moment.
-            assert_eq!(
-                wal.size(),
-                0,
-                "WAL size should be 0 for a restore operation"
-            );
-            assert_eq!(
-                self.memtable.len(),
-                0,
-                "Memtable can only be restored from scratch"
-            );
+        lsm.insert(b"later".to_vec(), b"value".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        drop(lsm);
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        for key in ["flushed", "later"] {
             assert_eq!(
-                self.memtable.size(),
-                0,
-                "Memtable can only be restored from scratch"
+                lsm.get(key.as_bytes().to_vec()).unwrap(),
+                Some(b"value".to_vec())
             );
-
-            wal.restore()?;
-            info!("Restoring Memtable");
-            for line in wal.lines()? {
-                match line {
-                    Ok(line) => {
-                        let entry: WalEntry = WalEntry::from_bytes(line.as_bytes());
-                        match entry {
-                            WalEntry::Put { key, value } => {
-                                self.memtable.insert(key, value);
-                            }
-                            WalEntry::Delete { key } => {
-                                self.memtable.delete(key);
-                            }
-                        }
-                    }
-                    // Entries which are not valid UTF-8 will be skipped.
-                    Err(e) => error!(error=%e, "Skipping invalid WAL entry"),
-                }
-            }
-        }
-
-        info!("Restoring bloom filter");
-        for (k, v) in &self.memtable {
-            if v.is_none() {
-                continue;
-            } else {
-                self.bloom_insert(k.to_vec());
-            }
         }
-
-        Ok(())
-    }
-
-    /// Internal method for inserting into the [`BloomFilter`].
-    fn bloom_insert(&self, key: Vec<u8>) {
-        self.bloom.lock().insert(key);
+        assert_eq!(
+            lsm.get(b"compacted".to_vec()).unwrap(),
+            Some(b"new".to_vec())
+        );
+        lsm.force_compaction().unwrap();
+        assert_eq!(
+            lsm.get(b"compacted".to_vec()).unwrap(),
+            Some(b"new".to_vec())
+        );
     }
 
-    /// Quickly check whether a key is within the LSM-tree, utilising the internal
-    /// bloom filter.
-    ///
-    /// # Note
-    /// As this is **only** a bloom filter check, this can return false positives
-    /// but not false negatives.
-    fn check(&self, key: Vec<u8>) -> bool {
-        // TODO: this could be a read lock (ideally nothing), but the underlying
-        // filter takes a mutable reference when it should not.
-        self.bloom.lock().check(key)
-    }
+    #[test]
+    fn background_work() {
+        let dir = TempDir::new("background_work").unwrap();
+        let mut lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, 16);
+        let work = lsm.enable_background_work();
+        assert!(!work.wait(Duration::ZERO), "Nothing has been handed off");
 
-    /// Get the configured working directory.
-    pub fn working_directory(&self) -> &Path {
-        &self.working_directory
-    }
-}
+        lsm.insert(b"key1".to_vec(), b"a value to fill the memtable".to_vec())
+            .unwrap();
+        assert_eq!(lsm.immutable_memtables.read().len(), 1);
+        assert!(
+            lsm.sstables.lock().is_empty(),
+            "The write left the flush to the worker"
+        );
+        assert!(work.wait(Duration::ZERO));
+        assert!(!work.wait(Duration::ZERO), "Pending work is taken");
+        lsm.run_background_work().unwrap();
+        assert!(lsm.immutable_memtables.read().is_empty());
+        assert_eq!(lsm.sstables.lock().len(), 1);
+        assert_eq!(
+            lsm.get(b"key1".to_vec()).unwrap(),
+            Some(b"a value to fill the memtable".to_vec())
+        );
 
-impl Drop for Lsm {
-    fn drop(&mut self) {
-        self.wal
-            .lock()
-            .flush_buffer()
-            .expect("Flushing buffer on drop");
+        // A worker which falls behind is caught up by the write path
+        for key in [b"key2", b"key3"] {
+            lsm.insert(key.to_vec(), b"a value to fill the memtable".to_vec())
+                .unwrap();
+        }
+        assert_eq!(
+            lsm.immutable_memtables.read().len(),
+            DEFAULT_MAX_IMMUTABLE_MEMTABLES
+        );
+        assert_eq!(lsm.sstables.lock().len(), 2);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::path::Path;
+    #[test]
+    fn statistics() {
+        let dir = TempDir::new("statistics").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.insert(b"memtable".to_vec(), b"value".to_vec()).unwrap();
+        lsm.delete(b"deleted".to_vec()).unwrap();
 
-    use tempdir::TempDir;
-    use walkdir::WalkDir;
+        let stats = lsm.statistics();
+        assert_eq!(stats.keys_written, 3);
+        assert_eq!(stats.bytes_written, 7 + 5 + 8 + 5 + 7);
+        assert_eq!(stats.flushes, 1);
+        assert!(stats.bytes_flushed > 0);
+        assert_eq!(stats.memtable_rotations, 1);
+        assert_eq!(stats.wal_appends, 3);
+        assert!(stats.wal_bytes > stats.bytes_written);
 
-    use crate::{
-        lsm::{MemtableConfig, WalConfig},
-        memtable::MEMTABLE_MAX_SIZE_BYTES,
-        wal::WAL_MAX_SEGMENT_SIZE_BYTES,
-    };
+        lsm.get(b"memtable".to_vec()).unwrap();
+        lsm.get(b"flushed".to_vec()).unwrap();
+        lsm.get(b"missing".to_vec()).unwrap();
+        lsm.multi_get(&[b"memtable".to_vec(), b"flushed".to_vec()])
+            .unwrap();
+        let stats = lsm.statistics();
+        assert_eq!(stats.keys_read, 5);
+        assert_eq!(
+            stats.bloom_checks, 2,
+            "The missing key falls outside of the SSTable's key range"
+        );
+        assert_eq!(stats.bloom_negatives, 0);
+        assert_eq!(stats.memtable_hits, 2);
+        assert_eq!(stats.memtable_misses, 3);
+        assert_eq!(stats.bloom_false_positives, 0);
+        assert_eq!(stats.sstable_probes, 2);
+        assert!(stats.block_cache_hits + stats.block_cache_misses > 0);
+        assert_eq!(stats.latency.put.count, 2);
+        assert_eq!(stats.latency.delete.count, 1);
+        assert_eq!(
+            stats.latency.get.count, 4,
+            "A batch of lookups is timed as one"
+        );
+        assert_eq!(stats.latency.scan.count, 0);
+        assert_eq!(lsm.scan(..).map(Result::unwrap).count(), 2);
+        let stats = lsm.statistics();
+        assert_eq!(stats.latency.scan.count, 1, "Scans are timed once dropped");
+        assert!(stats.latency.get.p50_us <= stats.latency.get.p99_us);
 
-    use super::Lsm;
+        lsm.force_compaction().unwrap();
+        let stats = lsm.statistics();
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.bytes_compacted, lsm.compaction_stats().bytes_written);
+        let mut exposition = Exposition::default();
+        lsm.expose_metrics(&mut exposition);
+        let text = exposition.finish();
+        assert!(text.contains("chipmunk_compactions_total 1\n"), "{text}");
+        assert!(text.contains("chipmunk_keys_read_total 5\n"), "{text}");
 
-    // Helper for creating an [`Lsm`] store within a test directory
-    fn create_lsm(wal_id: u64, dir: &TempDir, wal_max_size: u64, memtable_max_size: u64) -> Lsm {
-        let w = WalConfig {
-            id: wal_id,
-            max_size: wal_max_size,
-            log_directory: dir.path().to_path_buf(),
-            buffer_size: None,
-        };
-        let m = MemtableConfig {
-            id: 0,
-            max_size: memtable_max_size,
-        };
-        Lsm::new(w, m)
+        lsm.reset_statistics();
+        assert_eq!(lsm.statistics(), Statistics::default());
     }
 
     #[test]
-    fn crud() {
-        let dir = TempDir::new("crud").unwrap();
+    fn approximate_count_and_size() {
+        let dir = TempDir::new("approximate_count_and_size").unwrap();
         let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        assert_eq!(lsm.approximate_count(), 0);
+        assert_eq!(lsm.approximate_size(), 0);
 
-        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
-        assert_eq!(lsm.memtable.id(), 0);
-        assert_eq!(lsm.get(b"foo".to_vec()), Some(b"bar".to_vec()));
-        assert_ne!(lsm.wal.lock().size(), 0);
-        let wal_size_after_put = lsm.wal.lock().size();
+        for key in [b"a", b"b", b"c"] {
+            lsm.insert(key.to_vec(), b"value".to_vec()).unwrap();
+        }
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        let flushed = lsm.approximate_size();
+        assert_eq!(flushed, lsm.tree_stats().levels[0].bytes);
+        lsm.insert(b"d".to_vec(), b"value".to_vec()).unwrap();
+        lsm.delete(b"e".to_vec()).unwrap();
+        assert_eq!(lsm.approximate_count(), 5, "Tombstones in memtables count");
+        assert!(lsm.approximate_size() > flushed);
 
-        lsm.rotate_memtable();
+        lsm.insert(b"a".to_vec(), b"again".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
         assert_eq!(
-            lsm.memtable.id(),
-            1,
-            "Engine should have a new memtable after flush"
+            lsm.approximate_count(),
+            5,
+            "Flushed tombstones are not counted, overwritten keys are until compacted"
         );
+        lsm.force_compaction().unwrap();
+        assert_eq!(lsm.approximate_count(), 4);
+        assert_eq!(lsm.tree_stats().approximate_keys, 4);
+    }
+
+    #[test]
+    fn disk_usage() {
+        let dir = TempDir::new("disk_usage").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        for key in [b"a", b"b", b"c"] {
+            lsm.insert(key.to_vec(), b"value".to_vec()).unwrap();
+        }
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        assert_eq!(lsm.tree_stats().levels[0].reclaimable_bytes, 0);
+
+        lsm.delete(b"a".to_vec()).unwrap();
+        lsm.delete(b"b".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        let stats = lsm.tree_stats();
+        assert_eq!(stats.levels[0].files, 2);
+        assert!(stats.levels[0].reclaimable_bytes > 0);
+        assert!(stats.levels[0].reclaimable_bytes < stats.levels[0].bytes);
+        assert!(stats.directory_bytes >= stats.wal_bytes + stats.levels[0].bytes);
+
+        lsm.force_compaction().unwrap();
+        let stats = lsm.tree_stats();
+        assert_eq!(stats.levels[0].files, 0);
+        assert_eq!(stats.levels[1].files, 1);
         assert_eq!(
-            lsm.get(b"foo".to_vec()),
-            Some(b"bar".to_vec()),
-            "Value should be found in sstable on disk"
+            stats.levels[1].reclaimable_bytes, 0,
+            "Compaction drops the tombstones"
         );
 
-        lsm.delete(b"foo".to_vec()).unwrap();
-        assert!(
-            lsm.wal.lock().size() > wal_size_after_put,
-            "Deletion should append to the WAL"
-        );
+        let mut exposition = Exposition::default();
+        lsm.expose_metrics(&mut exposition);
+        let text = exposition.finish();
+        for sample in [
+            "chipmunk_level_files{level=\"1\"} 0\n".to_string(),
+            "chipmunk_level_files{level=\"2\"} 1\n".to_string(),
+            format!(
+                "chipmunk_level_bytes{{level=\"2\"}} {}\n",
+                stats.levels[1].bytes
+            ),
+            "chipmunk_level_reclaimable_bytes{level=\"2\"} 0\n".to_string(),
+            "chipmunk_disk_bytes{component=\"wal\"}".to_string(),
+            "chipmunk_directory_bytes ".to_string(),
+        ] {
+            assert!(text.contains(&sample), "{sample} in {text}");
+        }
     }
 
     #[test]
-    fn compaction() {
-        let dir = TempDir::new("compaction").unwrap();
-        let lsm = create_lsm(0, &dir, 1024, 1024);
+    fn ingest_sstable() {
+        let dir = TempDir::new("ingest_sstable").unwrap();
+        let external = TempDir::new("ingest_sstable_external").unwrap();
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.insert(b"a".to_vec(), b"old".to_vec()).unwrap();
+        lsm.insert(b"c".to_vec(), b"kept".to_vec()).unwrap();
 
-        let dir_size = || {
-            let entries = WalkDir::new(dir.path()).into_iter();
-            let len: walkdir::Result<u64> = entries
-                .map(|res| {
-                    res.and_then(|entry| entry.metadata())
-                        .map(|metadata| metadata.len())
-                })
-                .sum();
-            len.expect("fail to get directory size")
-        };
+        let path = external.path().join("bulk");
+        let mut builder = SstableBuilder::new(&path).unwrap();
+        for key in [b"a", b"b"] {
+            let entry = Entry {
+                value: Some(Bytes::from_static(b"ingested")),
+                expires_at: None,
+                separated: false,
+                stamp: None,
+            };
+            builder.add(key, &entry).unwrap();
+        }
+        builder.finish().unwrap();
 
-        let mut current_size = dir_size();
+        lsm.ingest_sstable(&path).unwrap();
+        assert_eq!(lsm.get(b"a".to_vec()).unwrap(), Some(b"ingested".to_vec()));
+        assert_eq!(lsm.get(b"b".to_vec()).unwrap(), Some(b"ingested".to_vec()));
+        assert_eq!(lsm.get(b"c".to_vec()).unwrap(), Some(b"kept".to_vec()));
 
-        for i in 1..=3 {
-            for j in 0..=1000 {
-                // The same key value is used, but overwritten on different
-                // iterations to force entries which should be discarded.
-                let key = format!("key{j}").as_bytes().to_vec();
-                let value = format!("value{i}-{j}").as_bytes().to_vec();
-                lsm.insert(key.clone(), value).unwrap();
+        lsm.insert(b"b".to_vec(), b"new".to_vec()).unwrap();
+        assert_eq!(
+            lsm.get(b"b".to_vec()).unwrap(),
+            Some(b"new".to_vec()),
+            "Later writes shadow ingested data"
+        );
+        assert_eq!(lsm.manifest.lock().version().files(LEVEL_1), vec![0, 1]);
 
-                // Delete every 10th key to accumulate tombstones
-                if j % 10 == 0 {
-                    lsm.delete(key).unwrap();
-                }
+        // Damaged files are rejected and never become visible
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+        assert!(matches!(
+            lsm.ingest_sstable(&path),
+            Err(ChipmunkError::SstableCorrupt { .. })
+        ));
+        assert_eq!(lsm.sstables.lock().len(), 2);
+    }
 
-                current_size = dir_size();
+    #[test]
+    fn manifest_restore() {
+        let dir = TempDir::new("manifest_restore").unwrap();
+        {
+            let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+            for i in 0..3 {
+                lsm.insert(format!("key{i}").into_bytes(), b"value".to_vec())
+                    .unwrap();
+                lsm.rotate_memtable().unwrap();
+                lsm.flush_immutable_memtables(0).unwrap();
             }
+            lsm.force_compaction().unwrap();
+            lsm.insert(b"key3".to_vec(), b"value".to_vec()).unwrap();
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
         }
 
-        let final_size = current_size;
-        lsm.force_compaction();
-        let post_compaction_size = dir_size();
-
-        assert!(post_compaction_size < final_size);
-        assert_ne!(
-            lsm.memtable_id(),
-            0,
-            "Memtable rotation should increment the ID"
+        let lsm = create_lsm(
+            100,
+            &dir,
+            WAL_MAX_SEGMENT_SIZE_BYTES,
+            MEMTABLE_MAX_SIZE_BYTES,
         );
+        lsm.restore().unwrap();
+        assert_eq!(*lsm.sstables.lock(), vec![3]);
+        assert_eq!(*lsm.l2_files.lock(), vec![0]);
         assert_eq!(
-            lsm.sstables.lock().len(),
-            0,
-            "L1 SSTables on disk should be 0 after a full compaction cycle"
+            lsm.l2_id.load(std::sync::atomic::Ordering::Acquire),
+            1,
+            "New L2 files should not reuse a restored ID"
         );
     }
 
     #[test]
-    fn bloom() {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::DEBUG)
-            .init();
-        let dir = TempDir::new("bloom").unwrap();
-        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+    fn write_stamps() {
+        let dir = TempDir::new("write_stamps").unwrap();
+        let sequence = |lsm: &Lsm, key: &[u8]| {
+            lsm.get_stamped(key.to_vec())
+                .unwrap()
+                .and_then(|value| value.stamp)
+                .map(|stamp| stamp.sequence)
+        };
+        {
+            let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+            let written = lsm.insert(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+            assert_eq!(written, 1);
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+            let ttl = Duration::from_secs(60);
+            let written = lsm
+                .insert_with_ttl(b"unflushed".to_vec(), b"value".to_vec(), ttl)
+                .unwrap();
+            assert_eq!(written, 2);
+            assert_eq!(lsm.delete(b"missing".to_vec()).unwrap(), 3);
 
-        lsm.insert(b"foo".to_vec(), b"bar".to_vec()).unwrap();
-        assert!(lsm.check(b"foo".to_vec()));
-        assert!(!lsm.check(b"baz".to_vec()));
+            let flushed = lsm.get_stamped(b"flushed".to_vec()).unwrap().unwrap();
+            assert_eq!(flushed.value, b"value");
+            assert_eq!(flushed.expires_at, None);
+            assert_eq!(sequence(&lsm, b"flushed"), Some(1));
+            assert!(lsm.get_stamped(b"missing".to_vec()).unwrap().is_none());
+        }
+
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert_eq!(lsm.sequence(), 3, "Sequence numbers continue from the WAL");
+        assert_eq!(sequence(&lsm, b"flushed"), Some(1));
+        let unflushed = lsm.get_stamped(b"unflushed".to_vec()).unwrap().unwrap();
+        assert_eq!(unflushed.stamp.map(|stamp| stamp.sequence), Some(2));
+        assert!(unflushed.expires_at.is_some());
+        assert_eq!(lsm.insert(b"next".to_vec(), b"value".to_vec()).unwrap(), 4);
+        lsm.close().unwrap();
         drop(lsm);
 
-        let mut lsm = create_lsm(1, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        lsm.restore().unwrap();
+        assert_eq!(
+            lsm.sequence(),
+            4,
+            "Sequence numbers continue from the manifest once the WAL is checkpointed"
+        );
+        assert_eq!(sequence(&lsm, b"next"), Some(4));
+    }
+
+    #[test]
+    fn close() {
+        let dir = TempDir::new("close").unwrap();
+        {
+            let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+            lsm.insert(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+            lsm.insert(b"unflushed".to_vec(), b"value".to_vec())
+                .unwrap();
+            lsm.delete(b"flushed".to_vec()).unwrap();
+            lsm.close().unwrap();
+
+            assert!(lsm.memtable.read().is_empty());
+            assert!(lsm.immutable_memtables.read().is_empty());
+            assert_eq!(lsm.manifest.lock().version().wal_checkpoint(), 1);
+            assert!(
+                !dir.path().join("0.wal").exists(),
+                "Checkpointed segments should be removed"
+            );
+        }
+
+        let lsm = create_lsm(0, &dir, WAL_MAX_SEGMENT_SIZE_BYTES, MEMTABLE_MAX_SIZE_BYTES);
+        assert_eq!(
+            lsm.wal.as_ref().unwrap().lock().id(),
+            2,
+            "The WAL should start after the existing segments"
+        );
         lsm.restore().unwrap();
         assert!(
-            lsm.check(b"foo".to_vec()),
-            "The key 'foo' should exist in the filter after the structure was restored."
+            lsm.memtable.read().is_empty(),
+            "Nothing should be replayed after a clean shutdown"
+        );
+        assert_eq!(
+            lsm.get(b"unflushed".to_vec()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(lsm.get(b"flushed".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let dir = TempDir::new("checkpoint").unwrap();
+        let target = dir.path().join("checkpoint");
+        let source_dir = TempDir::new("checkpoint_source").unwrap();
+        let lsm = create_lsm(
+            0,
+            &source_dir,
+            WAL_MAX_SEGMENT_SIZE_BYTES,
+            MEMTABLE_MAX_SIZE_BYTES,
         );
+        lsm.insert(b"l2".to_vec(), b"value".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.force_compaction().unwrap();
+        lsm.insert(b"l1".to_vec(), b"value".to_vec()).unwrap();
+        lsm.rotate_memtable().unwrap();
+        lsm.flush_immutable_memtables(0).unwrap();
+        lsm.insert(b"memtable".to_vec(), b"value".to_vec()).unwrap();
+
+        lsm.checkpoint(&target).unwrap();
+        lsm.insert(b"later".to_vec(), b"value".to_vec()).unwrap();
+        lsm.delete(b"l2".to_vec()).unwrap();
         assert!(
-            !lsm.check(b"baz".to_vec()),
-            "A value which does not exist should not appear after restore"
+            lsm.checkpoint(&target).is_err(),
+            "An existing checkpoint should not be overwritten"
+        );
+
+        let copy = Lsm::new(
+            WalConfig {
+                id: 0,
+                max_size: WAL_MAX_SEGMENT_SIZE_BYTES,
+                log_directory: target,
+                buffer_size: None,
+                retention: None,
+            },
+            MemtableConfig {
+                id: 100,
+                max_size: MEMTABLE_MAX_SIZE_BYTES,
+                max_immutable_memtables: DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+                in_memory: None,
+                max_value_size: None,
+                flush_interval: None,
+            },
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        copy.restore().unwrap();
+        let keys: Vec<Bytes> = copy.scan(..).map(Result::unwrap).map(|(k, _)| k).collect();
+        assert_eq!(
+            keys,
+            vec![
+                Bytes::from("l1"),
+                Bytes::from("l2"),
+                Bytes::from("memtable")
+            ]
         );
+        assert_eq!(copy.get(b"l2".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(lsm.get(b"l2".to_vec()).unwrap(), None);
     }
 
     #[test]
@@ -458,13 +5138,17 @@ mod test {
             .unwrap();
         }
 
+        // Freezing a memtable records the active WAL segment, so memtables
+        // cannot be rotated while the WAL is locked below.
+        for _ in 1..=5 {
+            lsm.rotate_memtable().unwrap();
+        }
         {
-            let mut lsm_wal = lsm.wal.lock();
+            let mut lsm_wal = lsm.wal.as_ref().unwrap().lock();
             assert_eq!(lsm_wal.id(), 0);
             for _ in 1..=5 {
                 // Force rotations
                 lsm_wal.rotate().unwrap();
-                lsm.rotate_memtable();
             }
             assert_eq!(lsm_wal.id(), 5);
             assert_eq!(lsm_wal.closed_segments().len(), 5);
@@ -481,9 +5165,131 @@ mod test {
             assert!(!Path::new(&format!("{}/{}.wal", lsm.working_directory.display(), i)).exists());
         }
         assert_eq!(
-            lsm.wal.lock().closed_segments().len(),
+            lsm.wal.as_ref().unwrap().lock().closed_segments().len(),
             0,
             "No closed segments remaining after removal",
         );
     }
+
+    #[test]
+    fn value_log() {
+        let dir = TempDir::new("value_log").unwrap();
+        let lsm = Lsm::new(
+            WalConfig {
+                id: 0,
+                max_size: WAL_MAX_SEGMENT_SIZE_BYTES,
+                log_directory: dir.path().to_path_buf(),
+                buffer_size: None,
+                retention: None,
+            },
+            MemtableConfig {
+                id: 0,
+                max_size: MEMTABLE_MAX_SIZE_BYTES,
+                max_immutable_memtables: 0,
+                in_memory: None,
+                max_value_size: None,
+                flush_interval: None,
+            },
+            SstableConfig::default().with_min_separated_value_size(16),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        let flush = |lsm: &Lsm| {
+            lsm.rotate_memtable().unwrap();
+            lsm.flush_immutable_memtables(0).unwrap();
+        };
+        let vlog_exists = |id| dir.path().join(value_log::file_name(id)).exists();
+
+        lsm.insert(b"big1".to_vec(), vec![1; 64]).unwrap();
+        lsm.insert(b"big2".to_vec(), vec![2; 64]).unwrap();
+        lsm.insert(b"small".to_vec(), b"value".to_vec()).unwrap();
+        flush(&lsm);
+        assert!(vlog_exists(0), "Large values are separated on flush");
+        let table = Sstable::open(&dir.path().join(manifest::file_name(LEVEL_1, 0))).unwrap();
+        assert!(table.get(b"big1").unwrap().unwrap().separated);
+        assert!(!table.get(b"small").unwrap().unwrap().separated);
+
+        lsm.force_compaction().unwrap();
+        assert_eq!(lsm.get(b"big1".to_vec()).unwrap(), Some(vec![1; 64]));
+        assert_eq!(
+            lsm.multi_get(&[b"big2".to_vec(), b"small".to_vec()])
+                .unwrap(),
+            vec![Some(vec![2; 64]), Some(b"value".to_vec())]
+        );
+        assert_eq!(
+            lsm.scan(..).map(Result::unwrap).collect::<Vec<_>>(),
+            vec![
+                (Bytes::from("big1"), Bytes::from(vec![1; 64])),
+                (Bytes::from("big2"), Bytes::from(vec![2; 64])),
+                (Bytes::from("small"), Bytes::from("value")),
+            ]
+        );
+
+        let snapshot = lsm.snapshot().unwrap();
+        lsm.insert(b"big1".to_vec(), b"new".to_vec()).unwrap();
+        lsm.delete(b"big2".to_vec()).unwrap();
+        flush(&lsm);
+        assert_eq!(
+            lsm.collect_value_log_garbage().unwrap(),
+            1,
+            "A file without live values is removed"
+        );
+        assert!(!vlog_exists(0));
+        assert_eq!(lsm.get(b"big1".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.get(b"big2".to_vec()).unwrap(), None);
+        assert_eq!(
+            snapshot.get(b"big1").unwrap(),
+            Some(vec![1; 64]),
+            "Snapshots keep removed files readable"
+        );
+
+        // Memtable 2 is the only one left holding large values.
+        lsm.insert(b"big3".to_vec(), vec![3; 64]).unwrap();
+        lsm.insert(b"big4".to_vec(), vec![4; 64]).unwrap();
+        flush(&lsm);
+        lsm.insert(b"big3".to_vec(), b"new".to_vec()).unwrap();
+        flush(&lsm);
+        assert_eq!(lsm.collect_value_log_garbage().unwrap(), 1);
+        assert!(!vlog_exists(2));
+        assert!(vlog_exists(4), "Live values are moved to a new file");
+        assert_eq!(lsm.get(b"big3".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.get(b"big4".to_vec()).unwrap(), Some(vec![4; 64]));
+        assert_eq!(
+            lsm.collect_value_log_garbage().unwrap(),
+            0,
+            "Files below the garbage ratio are kept"
+        );
+    }
+
+    #[test]
+    fn value_log_garbage_after_compaction() {
+        let dir = TempDir::new("value_log_garbage_after_compaction").unwrap();
+        let lsm = Lsm::new(
+            WalConfig::new(
+                0,
+                WAL_MAX_SEGMENT_SIZE_BYTES,
+                dir.path().to_path_buf(),
+                None,
+            ),
+            MemtableConfig::new(0, 1).with_max_immutable_memtables(0),
+            SstableConfig::default().with_min_separated_value_size(16),
+            CompactionConfig::default().with_l1_file_trigger(3),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        let vlog_exists = |id| dir.path().join(value_log::file_name(id)).exists();
+
+        lsm.insert(b"big".to_vec(), vec![1; 64]).unwrap();
+        lsm.insert(b"big".to_vec(), vec![2; 64]).unwrap();
+        assert!(
+            vlog_exists(0),
+            "Garbage is left alone on rotation until compaction runs"
+        );
+        lsm.insert(b"big".to_vec(), vec![3; 64]).unwrap();
+        assert!(lsm.sstables.lock().is_empty(), "Compaction has run");
+        assert!(!vlog_exists(0));
+        assert!(!vlog_exists(1));
+        assert_eq!(lsm.get(b"big".to_vec()).unwrap(), Some(vec![3; 64]));
+    }
 }
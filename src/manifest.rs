@@ -0,0 +1,473 @@
+//! The manifest, an append-only log of the SSTables which make up the tree.
+//!
+//! Every flush and compaction records the files it adds and removes from each
+//! level as a single [`VersionEdit`] batch. Replaying the log on open rebuilds
+//! the live set of files, the [`Version`], so that nothing relies on in-memory
+//! state or filename conventions to survive a restart.
+//!
+//! Each record is framed as:
+//!
+//! ```text
+//! | payload len (u32) | payload crc (u32) | payload |
+//! ```
+//!
+//! The payload holds one or more edits, which are applied atomically:
+//!
+//! ```text
+//! | tag (u8) | level (u8) | file id (u64) | [metadata len (u32) | metadata] |
+//! ```
+//!
+//! A checkpoint edit, which records the WAL segments that no longer need to be
+//! replayed, uses the same layout with an unused level and the ID of the first
+//! segment to replay in place of the file ID. A sequence edit, which records
+//! the sequence number writes had reached once they were flushed, holds that
+//! number in place of the file ID.
+//!
+//! A record which is incomplete or fails its checksum marks the end of the log,
+//! as it can only have been produced by a crash mid-append. It is truncated
+//! away before any further records are written.
+//!
+//! All integers are big endian.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use bytes::Bytes;
+use tracing::{debug, info, warn};
+
+use crate::{fs, sstable::SstableMetadata, ChipmunkError};
+
+/// Name of the manifest file within the working directory.
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// Level which memtables are flushed to.
+pub const LEVEL_1: u8 = 1;
+/// Level which compaction of [`LEVEL_1`] produces.
+pub const LEVEL_2: u8 = 2;
+
+/// Name of the file holding the SSTable with the given ID within a level.
+pub fn file_name(level: u8, id: u64) -> String {
+    match level {
+        LEVEL_1 => format!("sstable-{id}"),
+        _ => format!("l{level}-{id}"),
+    }
+}
+
+/// ID of an SSTable from its file name, `None` if the file does not hold an
+/// SSTable of the level.
+pub fn file_id(level: u8, file_name: &str) -> Option<u64> {
+    let id = match level {
+        LEVEL_1 => file_name.strip_prefix("sstable-")?,
+        _ => file_name.strip_prefix(&format!("l{level}-"))?,
+    };
+    id.parse().ok()
+}
+
+/// Payload length (u32) and checksum (u32) preceding every record.
+const RECORD_HEADER_SIZE: usize = 4 + 4;
+
+const EDIT_ADD_FILE: u8 = 0;
+const EDIT_DELETE_FILE: u8 = 1;
+const EDIT_CHECKPOINT: u8 = 2;
+const EDIT_SEQUENCE: u8 = 3;
+
+/// A single change to the set of live files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionEdit {
+    AddFile {
+        level: u8,
+        id: u64,
+        metadata: SstableMetadata,
+    },
+    DeleteFile {
+        level: u8,
+        id: u64,
+    },
+    /// Every WAL segment with an ID lower than `wal_segment` only holds data
+    /// which has been flushed to an SSTable.
+    Checkpoint {
+        wal_segment: u64,
+    },
+    /// Every write with a sequence number up to `sequence` has been flushed,
+    /// so sequence numbers continue from it once the WAL has been replayed.
+    Sequence {
+        sequence: u64,
+    },
+}
+
+impl VersionEdit {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            VersionEdit::AddFile {
+                level,
+                id,
+                metadata,
+            } => {
+                let metadata = metadata.encode();
+                buf.write_u8(EDIT_ADD_FILE).unwrap();
+                buf.write_u8(*level).unwrap();
+                buf.write_u64::<BigEndian>(*id).unwrap();
+                buf.write_u32::<BigEndian>(metadata.len() as u32).unwrap();
+                buf.write_all(&metadata).unwrap();
+            }
+            VersionEdit::DeleteFile { level, id } => {
+                buf.write_u8(EDIT_DELETE_FILE).unwrap();
+                buf.write_u8(*level).unwrap();
+                buf.write_u64::<BigEndian>(*id).unwrap();
+            }
+            VersionEdit::Checkpoint { wal_segment } => {
+                buf.write_u8(EDIT_CHECKPOINT).unwrap();
+                buf.write_u8(0).unwrap();
+                buf.write_u64::<BigEndian>(*wal_segment).unwrap();
+            }
+            VersionEdit::Sequence { sequence } => {
+                buf.write_u8(EDIT_SEQUENCE).unwrap();
+                buf.write_u8(0).unwrap();
+                buf.write_u64::<BigEndian>(*sequence).unwrap();
+            }
+        }
+    }
+
+    fn decode_all(mut payload: &[u8]) -> Result<Vec<Self>, &'static str> {
+        let mut edits = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < 10 {
+                return Err("truncated edit");
+            }
+            let (tag, level, id) = (payload[0], payload[1], BigEndian::read_u64(&payload[2..10]));
+            payload = &payload[10..];
+            match tag {
+                EDIT_ADD_FILE => {
+                    if payload.len() < 4 {
+                        return Err("truncated edit");
+                    }
+                    let len = BigEndian::read_u32(payload) as usize;
+                    let metadata = payload.get(4..4 + len).ok_or("truncated file metadata")?;
+                    let metadata = SstableMetadata::decode(Bytes::copy_from_slice(metadata))?;
+                    payload = &payload[4 + len..];
+                    edits.push(VersionEdit::AddFile {
+                        level,
+                        id,
+                        metadata,
+                    });
+                }
+                EDIT_DELETE_FILE => edits.push(VersionEdit::DeleteFile { level, id }),
+                EDIT_CHECKPOINT => edits.push(VersionEdit::Checkpoint { wal_segment: id }),
+                EDIT_SEQUENCE => edits.push(VersionEdit::Sequence { sequence: id }),
+                _ => return Err("unknown edit"),
+            }
+        }
+        Ok(edits)
+    }
+}
+
+/// The set of live files, per level, produced by replaying the manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Version {
+    levels: BTreeMap<u8, BTreeMap<u64, SstableMetadata>>,
+    wal_checkpoint: u64,
+    sequence: u64,
+}
+
+impl Version {
+    fn apply(&mut self, edit: &VersionEdit) {
+        match edit {
+            VersionEdit::AddFile {
+                level,
+                id,
+                metadata,
+            } => {
+                self.levels
+                    .entry(*level)
+                    .or_default()
+                    .insert(*id, metadata.clone());
+            }
+            VersionEdit::DeleteFile { level, id } => {
+                if let Some(files) = self.levels.get_mut(level) {
+                    files.remove(id);
+                }
+            }
+            VersionEdit::Checkpoint { wal_segment } => {
+                self.wal_checkpoint = self.wal_checkpoint.max(*wal_segment);
+            }
+            VersionEdit::Sequence { sequence } => {
+                self.sequence = self.sequence.max(*sequence);
+            }
+        }
+    }
+
+    /// IDs of the live files within a level, oldest first.
+    pub fn files(&self, level: u8) -> Vec<u64> {
+        self.levels
+            .get(&level)
+            .map(|files| files.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Metadata of a live file.
+    pub fn metadata(&self, level: u8, id: u64) -> Option<&SstableMetadata> {
+        self.levels.get(&level).and_then(|files| files.get(&id))
+    }
+
+    /// ID of the first WAL segment which needs to be replayed, those before it
+    /// only hold data which has been flushed.
+    pub fn wal_checkpoint(&self) -> u64 {
+        self.wal_checkpoint
+    }
+
+    /// Highest sequence number of the writes which have been flushed.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// Handle to the manifest of a working directory.
+pub struct Manifest {
+    /// The file edits are recorded to, `None` when they are only held in
+    /// memory.
+    file: Option<File>,
+    version: Version,
+}
+
+impl Manifest {
+    /// Open the manifest within the given directory, creating it if it does
+    /// not yet exist, and replay it into the current [`Version`].
+    pub fn open(directory: &Path) -> Result<Self, ChipmunkError> {
+        let path = directory.join(MANIFEST_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .map_err(ChipmunkError::ManifestRead)?;
+        // The manifest may have just been created.
+        fs::sync_dir(directory).map_err(ChipmunkError::ManifestWrite)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(ChipmunkError::ManifestRead)?;
+
+        let mut version = Version::default();
+        let mut offset = 0;
+        let mut records = 0;
+        while offset < data.len() {
+            let Some((size, edits)) = Self::read_record(&data[offset..]) else {
+                warn!(
+                    path = %path.display(),
+                    offset,
+                    "Truncating incomplete manifest record"
+                );
+                file.set_len(offset as u64)
+                    .map_err(ChipmunkError::ManifestWrite)?;
+                break;
+            };
+            for edit in &edits {
+                version.apply(edit);
+            }
+            offset += size;
+            records += 1;
+        }
+        info!(path = %path.display(), records, "Replayed manifest");
+
+        Ok(Self {
+            file: Some(file),
+            version,
+        })
+    }
+
+    /// A manifest which applies edits without recording them anywhere, for a
+    /// tree which keeps everything in memory.
+    pub fn in_memory() -> Self {
+        Self {
+            file: None,
+            version: Version::default(),
+        }
+    }
+
+    /// Read a single record, returning its size alongside the edits it holds
+    /// or `None` if it is incomplete or corrupt.
+    fn read_record(data: &[u8]) -> Option<(usize, Vec<VersionEdit>)> {
+        let header = data.get(..RECORD_HEADER_SIZE)?;
+        let len = BigEndian::read_u32(&header[0..4]) as usize;
+        let crc = BigEndian::read_u32(&header[4..8]);
+        let payload = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+        if crc != crc32c::crc32c(payload) {
+            return None;
+        }
+        let edits = VersionEdit::decode_all(payload).ok()?;
+        Some((RECORD_HEADER_SIZE + len, edits))
+    }
+
+    /// Durably record a batch of edits, which are applied together to the
+    /// current [`Version`] once they have been synced to disk.
+    pub fn apply(&mut self, edits: &[VersionEdit]) -> Result<(), ChipmunkError> {
+        let mut payload = Vec::new();
+        for edit in edits {
+            edit.encode(&mut payload);
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+        record
+            .write_u32::<BigEndian>(crc32c::crc32c(&payload))
+            .unwrap();
+        record.extend_from_slice(&payload);
+
+        if let Some(file) = &mut self.file {
+            file.write_all(&record)
+                .and_then(|_| file.sync_data())
+                .map_err(ChipmunkError::ManifestWrite)?;
+        }
+        debug!(edits = edits.len(), "Recorded manifest edits");
+
+        for edit in edits {
+            self.version.apply(edit);
+        }
+        Ok(())
+    }
+
+    /// The set of live files after every recorded edit.
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn metadata(min: &'static [u8], max: &'static [u8]) -> SstableMetadata {
+        SstableMetadata {
+            min_key: Bytes::from_static(min),
+            max_key: Bytes::from_static(max),
+            entries: 2,
+            tombstones: 0,
+            created_at: 0,
+            range_tombstones: Vec::new(),
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let dir = TempDir::new("manifest").unwrap();
+        {
+            let mut manifest = Manifest::open(dir.path()).unwrap();
+            assert_eq!(manifest.version(), &Version::default());
+
+            for id in 0..3 {
+                manifest
+                    .apply(&[VersionEdit::AddFile {
+                        level: LEVEL_1,
+                        id,
+                        metadata: metadata(b"a", b"z"),
+                    }])
+                    .unwrap();
+            }
+            // A compaction of two of the files
+            manifest
+                .apply(&[
+                    VersionEdit::DeleteFile {
+                        level: LEVEL_1,
+                        id: 0,
+                    },
+                    VersionEdit::DeleteFile {
+                        level: LEVEL_1,
+                        id: 1,
+                    },
+                    VersionEdit::AddFile {
+                        level: LEVEL_2,
+                        id: 0,
+                        metadata: metadata(b"b", b"y"),
+                    },
+                ])
+                .unwrap();
+            assert_eq!(manifest.version().files(LEVEL_1), vec![2]);
+        }
+
+        let manifest = Manifest::open(dir.path()).unwrap();
+        let version = manifest.version();
+        assert_eq!(version.files(LEVEL_1), vec![2]);
+        assert_eq!(version.files(LEVEL_2), vec![0]);
+        assert_eq!(version.metadata(LEVEL_2, 0), Some(&metadata(b"b", b"y")));
+        assert_eq!(version.metadata(LEVEL_1, 0), None);
+        assert_eq!(version.wal_checkpoint(), 0);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let dir = TempDir::new("manifest").unwrap();
+        {
+            let mut manifest = Manifest::open(dir.path()).unwrap();
+            manifest
+                .apply(&[
+                    VersionEdit::AddFile {
+                        level: LEVEL_1,
+                        id: 0,
+                        metadata: metadata(b"a", b"z"),
+                    },
+                    VersionEdit::Checkpoint { wal_segment: 3 },
+                    VersionEdit::Sequence { sequence: 10 },
+                ])
+                .unwrap();
+            manifest
+                .apply(&[
+                    VersionEdit::Checkpoint { wal_segment: 2 },
+                    VersionEdit::Sequence { sequence: 4 },
+                ])
+                .unwrap();
+            assert_eq!(
+                manifest.version().wal_checkpoint(),
+                3,
+                "Checkpoints never move backwards"
+            );
+        }
+
+        let manifest = Manifest::open(dir.path()).unwrap();
+        assert_eq!(manifest.version().wal_checkpoint(), 3);
+        assert_eq!(manifest.version().sequence(), 10);
+        assert_eq!(manifest.version().files(LEVEL_1), vec![0]);
+    }
+
+    #[test]
+    fn truncated_record() {
+        let dir = TempDir::new("manifest").unwrap();
+        {
+            let mut manifest = Manifest::open(dir.path()).unwrap();
+            manifest
+                .apply(&[VersionEdit::AddFile {
+                    level: LEVEL_1,
+                    id: 0,
+                    metadata: metadata(b"a", b"z"),
+                }])
+                .unwrap();
+        }
+
+        // Emulate a crash part way through appending a record
+        let path = dir.path().join(MANIFEST_FILE_NAME);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 100, 1, 2]).unwrap();
+
+        {
+            let mut manifest = Manifest::open(dir.path()).unwrap();
+            assert_eq!(manifest.version().files(LEVEL_1), vec![0]);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+            manifest
+                .apply(&[VersionEdit::AddFile {
+                    level: LEVEL_1,
+                    id: 1,
+                    metadata: metadata(b"a", b"z"),
+                }])
+                .unwrap();
+        }
+
+        let manifest = Manifest::open(dir.path()).unwrap();
+        assert_eq!(manifest.version().files(LEVEL_1), vec![0, 1]);
+    }
+}
@@ -2,36 +2,227 @@
 #![allow(dead_code)]
 
 use std::{
+    cmp,
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
     path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::Bytes;
-use dashmap::DashMap;
-use fxhash::FxHashMap;
+use crossbeam_skiplist::{map, SkipMap};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
+use crate::{
+    comparator::{self, Comparator},
+    config::SstableConfig,
+    sstable::{SstableBuilder, SstableMetadata},
+    value_log::ValueLog,
+    ChipmunkError,
+};
+
 pub const MEMTABLE_MAX_SIZE_BYTES: u64 = 1024 * 1024; // 1 MiB
 
+/// Fixed cost attributed to every entry, live or tombstone, on top of its key
+/// and value bytes. This roughly covers the map's bookkeeping and the
+/// reference-counted [`Bytes`] handles.
+const ENTRY_OVERHEAD_BYTES: u64 = 64;
+
+/// The write which produced an [`Entry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteStamp {
+    /// Sequence number assigned to the write, see
+    /// [`Lsm::sequence`](crate::lsm::Lsm::sequence).
+    pub sequence: u64,
+    /// Unix timestamp, in milliseconds, at which the write was applied.
+    pub written_at: u64,
+}
+
+/// A value held for a key, alongside the time at which it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The stored value, [`None`] represents a tombstone.
+    pub value: Option<Bytes>,
+    /// Unix timestamp, in milliseconds, from which the entry is treated as
+    /// absent. Entries without an expiry live until they are overwritten.
+    pub expires_at: Option<u64>,
+    /// Whether the value is a pointer to where it is held in the value log,
+    /// rather than the value itself. Only entries within SSTables are ever
+    /// separated.
+    pub separated: bool,
+    /// The write which produced the entry. Entries written before stamps
+    /// were recorded have none.
+    pub stamp: Option<WriteStamp>,
+}
+
+impl Entry {
+    fn new(value: Option<Bytes>, expires_at: Option<u64>) -> Self {
+        Self {
+            value,
+            expires_at,
+            separated: false,
+            stamp: None,
+        }
+    }
+
+    /// Whether the entry has expired at the given unix timestamp, in
+    /// milliseconds.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// The value which is visible at the given unix timestamp, in milliseconds.
+    ///
+    /// An expired entry behaves the same as a tombstone.
+    pub fn live_value(&self, now: u64) -> Option<&Bytes> {
+        if self.is_expired(now) {
+            None
+        } else {
+            self.value.as_ref()
+        }
+    }
+}
+
+/// A deletion of every key from `start`, inclusive, up to `end`, exclusive.
+///
+/// A range tombstone shadows the entries of older memtables and SSTables. The
+/// entries held alongside it, within the same memtable or SSTable, are always
+/// newer than it as anything older is deleted individually when it is laid
+/// down.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+}
+
+impl RangeTombstone {
+    /// Whether the key falls within the deleted range.
+    pub fn covers(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        comparator.compare(&self.start, key).is_le() && comparator.compare(key, &self.end).is_lt()
+    }
+
+    /// Whether any key within the given range may be deleted.
+    pub fn overlaps<R: RangeBounds<Bytes>>(&self, range: &R, comparator: &dyn Comparator) -> bool {
+        let after_start = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                comparator.compare(start, &self.end).is_lt()
+            }
+            Bound::Unbounded => true,
+        };
+        after_start && comparator::before_end(comparator, &self.start, range.end_bound())
+    }
+}
+
+/// A key held by a memtable, ordered by the memtable's [`Comparator`].
+#[derive(Debug, Clone)]
+struct OrderedKey {
+    key: Bytes,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.comparator.compare(&self.key, &other.key)
+    }
+}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for OrderedKey {}
+
+/// Wrap the bounds of a range of keys so that they can be looked up in a tree
+/// of [`OrderedKey`]s.
+fn ordered_range<R: RangeBounds<Bytes>>(
+    range: &R,
+    comparator: &Arc<dyn Comparator>,
+) -> (Bound<OrderedKey>, Bound<OrderedKey>) {
+    let ordered = |key: &Bytes| OrderedKey {
+        key: key.clone(),
+        comparator: Arc::clone(comparator),
+    };
+    (
+        range.start_bound().map(ordered),
+        range.end_bound().map(ordered),
+    )
+}
+
+/// The current unix timestamp, in milliseconds, used for entry expiry.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is after the unix epoch")
+        .as_millis() as u64
+}
+
+/// The unix timestamp, in milliseconds, at which an entry written now with
+/// the given time-to-live expires. A time-to-live too long to be represented
+/// never expires.
+pub fn expiry_after(ttl: Duration) -> u64 {
+    expiry_from(unix_millis(), ttl)
+}
+
+/// The unix timestamp, in milliseconds, at which an entry written at `now`
+/// with the given time-to-live expires, see [`expiry_after`].
+pub fn expiry_from(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
 #[derive(Debug)]
 pub struct Memtable {
-    id: AtomicU64,
-    tree: DashMap<Bytes, Option<Bytes>>,
+    id: u64,
+    /// Entries ordered by key, tombstones are held as entries without a value.
+    ///
+    /// Replacing an entry of the skiplist removes it before the new entry is
+    /// linked in, leaving a moment where the key is missing. Entries are
+    /// instead updated in place, so readers always see either value.
+    tree: SkipMap<OrderedKey, RwLock<Entry>>,
+    /// Ordering of the keys held.
+    comparator: Arc<dyn Comparator>,
+    /// Ranges deleted through [`Memtable::delete_range`].
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
 
-    /// Approximate size of the [`Memtable`]. This is an approximation as it simply
-    /// uses the values to increment size - simply because for most cases the values
-    /// are going to be far larger than identifying keys.
+    /// Approximate size of the [`Memtable`]. Each entry contributes its key and
+    /// value bytes plus a fixed overhead, tombstones included, so that key-heavy
+    /// and delete-heavy workloads still work towards the `max_size` trigger.
     approximate_size: AtomicU64,
     max_size: u64,
+    /// Highest sequence number of the writes applied, see
+    /// [`Memtable::record_sequence`].
+    max_sequence: AtomicU64,
 }
 
 impl Memtable {
     pub fn new(id: u64, max_size: u64) -> Self {
+        Self::with_comparator(id, max_size, comparator::bytewise())
+    }
+
+    /// Create a [`Memtable`] which orders its keys with the given
+    /// [`Comparator`].
+    pub fn with_comparator(id: u64, max_size: u64, comparator: Arc<dyn Comparator>) -> Self {
         Self {
-            id: AtomicU64::new(id),
-            tree: DashMap::new(),
+            id,
+            tree: SkipMap::new(),
+            comparator,
+            range_tombstones: RwLock::new(Vec::new()),
             approximate_size: AtomicU64::new(0),
             max_size,
+            max_sequence: AtomicU64::new(0),
         }
     }
 
@@ -39,53 +230,289 @@ impl Memtable {
     pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) {
         debug!(
             key = %String::from_utf8_lossy(&key),
-            value = %String::from_utf8_lossy(&value),
+            key_size = key.len(),
+            value_size = value.len(),
             "Memtable insertion",
         );
         let key = Bytes::from(key);
-        let value = Bytes::from(value);
-        self.approximate_size
-            .fetch_add(value.len() as u64, Ordering::Acquire);
-        self.tree.insert(key, Some(value));
+        let value = Some(Bytes::from(value));
+        self.put_entry(key, Entry::new(value, None));
+    }
+
+    /// Put a key-value pair into the [`Memtable`] which expires once the given
+    /// time-to-live has elapsed.
+    pub fn insert_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        self.insert_with_expiry(key, value, expiry_after(ttl));
+    }
+
+    /// Put a key-value pair into the [`Memtable`] which expires at the given
+    /// unix timestamp, in milliseconds.
+    pub fn insert_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: u64) {
+        debug!(
+            key = %String::from_utf8_lossy(&key),
+            key_size = key.len(),
+            value_size = value.len(),
+            expires_at,
+            "Memtable insertion with expiry",
+        );
+        let key = Bytes::from(key);
+        let value = Some(Bytes::from(value));
+        self.put_entry(key, Entry::new(value, Some(expires_at)));
+    }
+
+    /// Put a key-value pair into the [`Memtable`] alongside the write which
+    /// produced it, if known, expiring at the given unix timestamp in
+    /// milliseconds.
+    pub fn insert_with_stamp(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        stamp: Option<WriteStamp>,
+    ) {
+        debug!(
+            key = %String::from_utf8_lossy(&key),
+            key_size = key.len(),
+            value_size = value.len(),
+            ?expires_at,
+            sequence = stamp.map(|stamp| stamp.sequence),
+            "Memtable insertion with stamp",
+        );
+        let entry = Entry {
+            stamp,
+            ..Entry::new(Some(Bytes::from(value)), expires_at)
+        };
+        self.put_entry(Bytes::from(key), entry);
+    }
+
+    /// Note that a write with the given sequence number has been applied, so
+    /// that flushing the [`Memtable`] can record how far writes have reached.
+    pub fn record_sequence(&self, sequence: u64) {
+        self.max_sequence.fetch_max(sequence, Ordering::AcqRel);
+    }
+
+    /// Highest sequence number recorded through [`Memtable::record_sequence`].
+    pub fn max_sequence(&self) -> u64 {
+        self.max_sequence.load(Ordering::Acquire)
     }
 
     /// Get a value pair from the [`Memtable`].
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.tree.get(key) {
-            Some(v) if v.value().is_none() => None,
-            Some(v) => Some(v.value().as_ref().unwrap().to_vec()),
-            None => None,
+        self.get_entry(key).flatten().map(|v| v.to_vec())
+    }
+
+    /// Get the raw entry for a key from the [`Memtable`].
+    ///
+    /// Unlike [`Memtable::get`], this distinguishes a key which is absent,
+    /// [`None`], from a key which has been deleted, `Some(None)`. Readers
+    /// consulting several memtables need this to stop at a tombstone. Expired
+    /// entries, and keys within a deleted range, are reported as tombstones.
+    pub fn get_entry(&self, key: &[u8]) -> Option<Option<Bytes>> {
+        let now = unix_millis();
+        self.entry(key).map(|entry| entry.live_value(now).cloned())
+    }
+
+    /// The entry held for a key as it was written, expired or not. A key
+    /// within a deleted range is reported as a tombstone.
+    pub fn entry(&self, key: &[u8]) -> Option<Entry> {
+        if let Some(entry) = self.tree.get(&self.ordered(Bytes::copy_from_slice(key))) {
+            return Some(entry.value().read().clone());
+        }
+        range_deleted(&self.range_tombstones.read(), key, &*self.comparator)
+            .map(|_| Entry::new(None, None))
+    }
+
+    fn ordered(&self, key: Bytes) -> OrderedKey {
+        OrderedKey {
+            key,
+            comparator: Arc::clone(&self.comparator),
         }
     }
 
     /// Delete a key-value pair from the [`Memtable`].
     pub fn delete(&self, key: Vec<u8>) {
-        debug!(key=%String::from_utf8_lossy(&key), "Memtable deletion");
-        self.tree.insert(key.into(), None);
+        debug!(
+            key = %String::from_utf8_lossy(&key),
+            key_size = key.len(),
+            "Memtable deletion"
+        );
+        self.put_entry(key.into(), Entry::new(None, None));
+    }
+
+    /// Delete every key from `start`, inclusive, up to `end`, exclusive.
+    ///
+    /// Keys already held are deleted individually, so that later writes to the
+    /// range are unaffected, and a [`RangeTombstone`] is recorded to shadow the
+    /// keys held by older memtables and SSTables.
+    pub fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) {
+        debug!(
+            start = %String::from_utf8_lossy(&start),
+            end = %String::from_utf8_lossy(&end),
+            "Memtable range deletion"
+        );
+        let tombstone = RangeTombstone {
+            start: start.into(),
+            end: end.into(),
+        };
+        let keys: Vec<Bytes> = self
+            .tree
+            .range(self.ordered(tombstone.start.clone())..self.ordered(tombstone.end.clone()))
+            .map(|entry| entry.key().key.clone())
+            .collect();
+        for key in keys {
+            self.put_entry(key, Entry::new(None, None));
+        }
+        let size = (tombstone.start.len() + tombstone.end.len()) as u64 + ENTRY_OVERHEAD_BYTES;
+        self.approximate_size.fetch_add(size, Ordering::AcqRel);
+        self.range_tombstones.write().push(tombstone);
+    }
+
+    /// Ranges which have been deleted, in the order they were deleted.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().clone()
+    }
+
+    /// Place an entry into the tree, accounting for any entry that it replaces.
+    fn put_entry(&self, key: Bytes, entry: Entry) {
+        let added = entry_size(&key, &entry);
+        self.approximate_size.fetch_add(added, Ordering::AcqRel);
+
+        let mut inserted = false;
+        let existing = self.tree.get_or_insert_with(self.ordered(key.clone()), || {
+            inserted = true;
+            RwLock::new(entry.clone())
+        });
+        if !inserted {
+            let previous = std::mem::replace(&mut *existing.value().write(), entry);
+            let replaced = entry_size(&key, &previous);
+            self.approximate_size.fetch_sub(replaced, Ordering::AcqRel);
+        }
+    }
+
+    /// Iterate over the entries of the [`Memtable`] whose keys fall within the
+    /// given range, in ascending key order.
+    ///
+    /// Tombstones, and expired entries, are included as [`None`] values so that
+    /// callers merging this with other sorted sources can shadow older entries.
+    pub fn range<'a, R: RangeBounds<Bytes> + 'a>(
+        &'a self,
+        range: R,
+    ) -> impl Iterator<Item = (Bytes, Option<Bytes>)> + 'a {
+        let now = unix_millis();
+        self.tree
+            .range(ordered_range(&range, &self.comparator))
+            .map(move |entry| {
+                (
+                    entry.key().key.clone(),
+                    entry.value().read().live_value(now).cloned(),
+                )
+            })
+    }
+
+    /// Iterate over every entry of the [`Memtable`] in ascending key order.
+    ///
+    /// This borrows the underlying tree rather than copying it, entries are
+    /// cheaply cloned reference-counted [`Bytes`] as they are yielded.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.tree.iter(),
+            now: unix_millis(),
+        }
+    }
+
+    /// Take a point-in-time [`Snapshot`] of the [`Memtable`].
+    ///
+    /// The snapshot is a frozen copy of the entries, which are cheaply cloned
+    /// reference-counted [`Bytes`]. Taking it walks the skiplist without
+    /// locking, so writers are not blocked and later writes are not visible
+    /// through the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let tree = self
+            .tree
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().read().clone()))
+            .collect();
+        Snapshot {
+            id: self.id,
+            tree,
+            comparator: Arc::clone(&self.comparator),
+            range_tombstones: self.range_tombstones(),
+        }
     }
 
     /// Write the [`Memtable`] to disk, this then becomes a Sorted String Table
     /// (SSTable) and is immutable.
-    pub fn flush(&self, flush_dir: PathBuf) {
-        let data = bincode::serialize(&self.tree).unwrap();
-        // Flushing should happen after a put, therefore the "happens-before"
-        // relationship is maintained here. So we can use Relaxed.
-        self.approximate_size.store(0, Ordering::Relaxed);
+    ///
+    /// The in-memory data is left untouched so that the [`Memtable`] can keep
+    /// serving reads until the caller has registered the new SSTable. Entries
+    /// which have already expired are written as tombstones, dropping their
+    /// values while still shadowing older data.
+    ///
+    /// Values of at least the configured `min_separated_value_size` are
+    /// written to a file of the [`ValueLog`], with the SSTable holding
+    /// pointers to them.
+    pub fn flush(
+        &self,
+        flush_dir: PathBuf,
+        config: &SstableConfig,
+        value_log: &ValueLog,
+    ) -> Result<SstableMetadata, ChipmunkError> {
+        let start = Instant::now();
+        let now = unix_millis();
+        let tombstone = Entry::new(None, None);
 
-        let flush_path = format!(
-            "{}/sstable-{}",
-            flush_dir.display(),
-            self.id.load(Ordering::Acquire)
-        );
-        debug!(path = flush_path, "Flushing memtable");
+        let flush_path = flush_dir.join(format!("sstable-{}", self.id));
+        let mut builder = SstableBuilder::with_config(&flush_path, config)?
+            .with_comparator(Arc::clone(&self.comparator));
+        let mut separated = None;
+        for entry in self.tree.iter() {
+            let (key, value) = (&entry.key().key, &*entry.value().read());
+            match &value.value {
+                _ if value.is_expired(now) => builder.add(key, &tombstone)?,
+                Some(v)
+                    if config
+                        .min_separated_value_size
+                        .is_some_and(|min| v.len() >= min) =>
+                {
+                    let writer = match &mut separated {
+                        Some(writer) => writer,
+                        None => separated.insert(value_log.create(self.id)?),
+                    };
+                    let pointer = writer.append(key, v)?;
+                    builder.add(
+                        key,
+                        &Entry {
+                            value: Some(pointer.encode()),
+                            expires_at: value.expires_at,
+                            separated: true,
+                            stamp: value.stamp,
+                        },
+                    )?;
+                }
+                _ => builder.add(key, value)?,
+            }
+        }
+        for tombstone in self.range_tombstones.read().iter() {
+            builder.delete_range(&tombstone.start, &tombstone.end);
+        }
+        // Values are synced before the SSTable which points to them can be
+        // registered.
+        if let Some(writer) = separated {
+            value_log.seal(writer)?;
+        }
+        let metadata = builder.finish()?;
 
-        std::fs::write(flush_path, data).unwrap();
-        self.tree.clear();
-        self.id.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            path = %flush_path.display(),
+            entries = metadata.entries,
+            duration = ?start.elapsed(),
+            "Flushed memtable"
+        );
+        Ok(metadata)
     }
 
     pub fn id(&self) -> u64 {
-        self.id.load(Ordering::Acquire)
+        self.id
     }
 
     pub fn size(&self) -> u64 {
@@ -101,31 +528,149 @@ impl Memtable {
         self.tree.len() as u64
     }
 
-    /// Load a [`Memtable`]'s contained data by providing its path.
-    pub fn load(path: PathBuf) -> FxHashMap<Bytes, Option<Bytes>> {
-        debug!(path = %path.display(), "Loading memtable");
-        let data = std::fs::read(&path).unwrap();
-        bincode::deserialize(&data).unwrap()
+    /// Whether the [`Memtable`] holds neither keys nor range tombstones.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty() && self.range_tombstones.read().is_empty()
+    }
+}
+
+/// Report a key within one of the deleted ranges as a tombstone.
+fn range_deleted(
+    range_tombstones: &[RangeTombstone],
+    key: &[u8],
+    comparator: &dyn Comparator,
+) -> Option<Option<Bytes>> {
+    range_tombstones
+        .iter()
+        .any(|tombstone| tombstone.covers(key, comparator))
+        .then_some(None)
+}
+
+/// Approximate in-memory footprint of a single entry.
+fn entry_size(key: &[u8], entry: &Entry) -> u64 {
+    key.len() as u64 + entry.value.as_ref().map_or(0, |v| v.len() as u64) + ENTRY_OVERHEAD_BYTES
+}
+
+/// An immutable, point-in-time view of a [`Memtable`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    id: u64,
+    tree: BTreeMap<OrderedKey, Entry>,
+    comparator: Arc<dyn Comparator>,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl Snapshot {
+    /// ID of the [`Memtable`] the snapshot was taken from.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get a value from the snapshot.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_entry(key).flatten().map(|v| v.to_vec())
+    }
+
+    /// Get the raw entry for a key from the snapshot, see
+    /// [`Memtable::get_entry`].
+    pub fn get_entry(&self, key: &[u8]) -> Option<Option<Bytes>> {
+        let now = unix_millis();
+        let key = OrderedKey {
+            key: Bytes::copy_from_slice(key),
+            comparator: Arc::clone(&self.comparator),
+        };
+        if let Some(entry) = self.tree.get(&key) {
+            return Some(entry.live_value(now).cloned());
+        }
+        range_deleted(&self.range_tombstones, &key.key, &*self.comparator)
+    }
+
+    /// Ranges which had been deleted when the snapshot was taken.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Iterate over the entries of the snapshot whose keys fall within the
+    /// given range, in ascending key order, see [`Memtable::range`].
+    pub fn range<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (Bytes, Option<Bytes>)> + '_ {
+        let now = unix_millis();
+        self.tree
+            .range(ordered_range(&range, &self.comparator))
+            .map(move |(k, entry)| (k.key.clone(), entry.live_value(now).cloned()))
+    }
+
+    /// Iterate over every entry of the snapshot in ascending key order.
+    pub fn iter(&self) -> impl Iterator<Item = (Bytes, Option<Bytes>)> + '_ {
+        self.range(..)
+    }
+
+    /// Number of elements (keys) within the snapshot.
+    pub fn len(&self) -> u64 {
+        self.tree.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+/// Ordered iterator over the entries of a [`Memtable`].
+///
+/// Expired entries are yielded as tombstones.
+pub struct Iter<'a> {
+    inner: map::Iter<'a, OrderedKey, RwLock<Entry>>,
+    now: u64,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (Bytes, Option<Bytes>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|entry| {
+            (
+                entry.key().key.clone(),
+                entry.value().read().live_value(self.now).cloned(),
+            )
+        })
     }
 }
 
-impl IntoIterator for &Memtable {
+impl<'a> IntoIterator for &'a Memtable {
     type Item = (Bytes, Option<Bytes>);
-    type IntoIter = dashmap::iter::OwningIter<Bytes, Option<Bytes>>;
+    type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.tree.clone().into_iter()
+        self.iter()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use bytes::Bytes;
     use tempdir::TempDir;
 
-    use super::{Memtable, MEMTABLE_MAX_SIZE_BYTES};
+    use super::{unix_millis, Entry, Memtable, ENTRY_OVERHEAD_BYTES, MEMTABLE_MAX_SIZE_BYTES};
+    use crate::{config::SstableConfig, sstable::Sstable, value_log::ValueLog};
 
     const TINY_MEMTABLE_BYTES: u64 = 10;
 
+    // Helper for reading back the entries of a flushed memtable.
+    fn load(path: PathBuf) -> HashMap<Bytes, Entry> {
+        Sstable::open(&path)
+            .unwrap()
+            .entries()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
     #[test]
     fn crud_operations() {
         let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
@@ -141,6 +686,187 @@ mod test {
         assert!(m.get(b"foo").is_none());
     }
 
+    #[test]
+    fn range() {
+        let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        for key in ["d", "a", "c", "e", "b"] {
+            m.insert(key.as_bytes().to_vec(), b"value".to_vec());
+        }
+        m.delete(b"c".to_vec());
+
+        let entries: Vec<_> = m
+            .range(Bytes::from_static(b"b")..Bytes::from_static(b"e"))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Bytes::from_static(b"b"), Some(Bytes::from_static(b"value"))),
+                (Bytes::from_static(b"c"), None),
+                (Bytes::from_static(b"d"), Some(Bytes::from_static(b"value"))),
+            ],
+            "Range should be ordered, exclusive of the end and include tombstones"
+        );
+
+        assert_eq!(
+            m.range(..).count(),
+            5,
+            "Unbounded range should include every entry"
+        );
+    }
+
+    #[test]
+    fn delete_range() {
+        let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        for key in ["a", "b", "c", "d"] {
+            m.insert(key.as_bytes().to_vec(), b"value".to_vec());
+        }
+        m.delete_range(b"b".to_vec(), b"d".to_vec());
+        m.insert(b"c".to_vec(), b"later".to_vec());
+
+        assert_eq!(m.get_entry(b"a"), Some(Some(Bytes::from_static(b"value"))));
+        assert_eq!(m.get_entry(b"b"), Some(None));
+        assert_eq!(
+            m.get(b"c"),
+            Some(b"later".to_vec()),
+            "Writes after a range deletion are unaffected by it"
+        );
+        assert_eq!(m.get_entry(b"d"), Some(Some(Bytes::from_static(b"value"))));
+        assert_eq!(
+            m.get_entry(b"bb"),
+            Some(None),
+            "Keys which are not held are still covered by the range"
+        );
+        assert_eq!(m.snapshot().get_entry(b"bb"), Some(None));
+        assert_eq!(m.range_tombstones().len(), 1);
+    }
+
+    #[test]
+    fn overwrites_stay_visible() {
+        let memtable = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        memtable.insert(b"key".to_vec(), b"0".to_vec());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1..10_000 {
+                    memtable.insert(b"key".to_vec(), i.to_string().into_bytes());
+                }
+            });
+            for _ in 0..10_000 {
+                assert!(
+                    memtable.get(b"key").is_some(),
+                    "A replaced key is never missing"
+                );
+            }
+        });
+        assert_eq!(memtable.get(b"key"), Some(b"9999".to_vec()));
+    }
+
+    #[test]
+    fn size_accounting() {
+        let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        m.insert(b"foo".to_vec(), b"bar".to_vec());
+        let single = m.size();
+
+        m.insert(b"foo".to_vec(), b"barbaz".to_vec());
+        assert_eq!(
+            m.size(),
+            single + 3,
+            "Overwrites should only account for the difference in value size"
+        );
+
+        m.delete(b"foo".to_vec());
+        assert_eq!(
+            m.size(),
+            b"foo".len() as u64 + ENTRY_OVERHEAD_BYTES,
+            "A tombstone should replace the live entry's size with its own"
+        );
+
+        m.delete(b"other".to_vec());
+        assert!(
+            m.size() > b"foo".len() as u64 + ENTRY_OVERHEAD_BYTES,
+            "Tombstones for unseen keys should still grow the memtable"
+        );
+    }
+
+    #[test]
+    fn ordered_iteration() {
+        let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        for key in ["c", "a", "b"] {
+            m.insert(key.as_bytes().to_vec(), b"value".to_vec());
+        }
+
+        let keys: Vec<Bytes> = m.iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            keys,
+            vec![
+                Bytes::from_static(b"a"),
+                Bytes::from_static(b"b"),
+                Bytes::from_static(b"c")
+            ],
+            "Iteration should be in ascending key order"
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        m.insert_with_ttl(b"live".to_vec(), b"value".to_vec(), Duration::from_secs(60));
+        m.insert_with_expiry(b"expired".to_vec(), b"value".to_vec(), unix_millis() - 1);
+
+        assert_eq!(m.get(b"live"), Some(b"value".to_vec()));
+        assert!(m.get(b"expired").is_none(), "Expired keys should be absent");
+        assert_eq!(
+            m.get_entry(b"expired"),
+            Some(None),
+            "Expired keys should behave as tombstones"
+        );
+
+        let flush_dir = TempDir::new("expiry").unwrap();
+        m.flush(
+            flush_dir.path().to_path_buf(),
+            &SstableConfig::default(),
+            &ValueLog::open(flush_dir.path()).unwrap(),
+        )
+        .unwrap();
+        let data = load(flush_dir.path().join("sstable-0"));
+        assert!(
+            data.get(b"live".as_ref()).unwrap().expires_at.is_some(),
+            "Expiry should be persisted on flush"
+        );
+        assert_eq!(
+            data.get(b"expired".as_ref()).unwrap().value,
+            None,
+            "Expired values should be dropped on flush"
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
+        m.insert(b"foo".to_vec(), b"bar".to_vec());
+        m.insert(b"baz".to_vec(), b"qux".to_vec());
+
+        let snapshot = m.snapshot();
+        m.insert(b"foo".to_vec(), b"updated".to_vec());
+        m.delete(b"baz".to_vec());
+        m.insert(b"new".to_vec(), b"value".to_vec());
+
+        assert_eq!(
+            snapshot.get(b"foo"),
+            Some(b"bar".to_vec()),
+            "Snapshot should not observe later writes"
+        );
+        assert_eq!(snapshot.get(b"baz"), Some(b"qux".to_vec()));
+        assert!(snapshot.get(b"new").is_none());
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(m.get(b"foo"), Some(b"updated".to_vec()));
+
+        let keys: Vec<Bytes> = snapshot.iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            keys,
+            vec![Bytes::from_static(b"baz"), Bytes::from_static(b"foo")]
+        );
+    }
+
     #[test]
     fn flush_to_sstable() {
         let m = Memtable::new(0, MEMTABLE_MAX_SIZE_BYTES);
@@ -148,16 +874,24 @@ mod test {
         m.insert(b"foo".to_vec(), b"bar".to_vec());
         assert_eq!(
             m.size(),
-            b"bar".len() as u64,
-            "Size should be approximated based on values"
+            (b"foo".len() + b"bar".len()) as u64 + ENTRY_OVERHEAD_BYTES,
+            "Size should be approximated based on keys, values and overhead"
+        );
+        m.flush(
+            flush_dir.path().to_path_buf(),
+            &SstableConfig::default(),
+            &ValueLog::open(flush_dir.path()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            m.get(b"foo"),
+            Some(b"bar".to_vec()),
+            "Flushed memtable should remain readable"
         );
-        m.flush(flush_dir.path().to_path_buf());
-        assert_eq!(m.size(), 0, "New memtable should have size of 0");
-        assert!(m.tree.is_empty(), "New memtable should be empty");
 
-        let data = Memtable::load(flush_dir.path().join("sstable-0"));
+        let data = load(flush_dir.path().join("sstable-0"));
         assert_eq!(
-            *data.get(b"foo".as_ref()).unwrap(),
+            data.get(b"foo".as_ref()).unwrap().value,
             Some(bytes::Bytes::from_static(b"bar"))
         );
     }
@@ -0,0 +1,621 @@
+//! Metrics describing the work of every part of the engine, from the WAL
+//! through the memtables to flushes, compaction and the read path.
+//!
+//! A single [`Metrics`] registry is shared by the components of the engine,
+//! each updating its own counters with relaxed atomics as operations happen,
+//! so recording them never blocks the read or write paths. It is the one
+//! source behind both the [`Statistics`] of the stats API and the Prometheus
+//! exposition served at `/metrics`, see [`Exposition`].
+//!
+//! The latency of reads and writes is kept in a [`Histogram`] for each kind
+//! of operation, timed within the engine so that embedded stores report the
+//! same latencies as those served over the network.
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::statistics::{Latencies, LatencySummary, Statistics};
+
+/// Prefix of the name of every metric which is exposed.
+const METRIC_PREFIX: &str = "chipmunk_";
+
+/// A count which only goes up, until it is reset.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The number of times an operation ran and the total time it took.
+#[derive(Debug, Default)]
+pub struct Timer {
+    count: Counter,
+    nanos: Counter,
+}
+
+impl Timer {
+    /// Count an operation which took the given time.
+    pub fn record(&self, duration: Duration) {
+        self.count.add(1);
+        self.nanos
+            .add(u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX));
+    }
+
+    /// Number of operations recorded.
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    /// Time taken by every operation recorded.
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.nanos.get())
+    }
+
+    fn reset(&self) {
+        self.count.reset();
+        self.nanos.reset();
+    }
+}
+
+/// Number of buckets of a [`Histogram`] with an upper bound. The bounds are
+/// powers of two microseconds, from 1µs up to roughly 67s.
+const HISTOGRAM_BUCKETS: usize = 27;
+
+/// Counts of durations within exponentially growing buckets, from which
+/// quantiles can be estimated without holding every duration.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Durations no longer than the upper bound of each bucket, other than
+    /// those counted by an earlier bucket, with a final bucket for the rest.
+    buckets: [Counter; HISTOGRAM_BUCKETS + 1],
+    nanos: Counter,
+}
+
+impl Histogram {
+    /// Count an operation which took the given time.
+    pub fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        // The smallest power of two at least as large as the duration.
+        let bucket = (u64::BITS - micros.saturating_sub(1).leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS)].add(1);
+        self.nanos
+            .add(u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX));
+    }
+
+    /// Run an operation, recording the time it took.
+    pub fn time<T>(&self, operation: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = operation();
+        self.record(start.elapsed());
+        result
+    }
+
+    /// Number of operations recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(Counter::get).sum()
+    }
+
+    /// Time taken by every operation recorded.
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.nanos.get())
+    }
+
+    /// Estimate the duration which the given proportion of operations, such
+    /// as `0.99`, took no longer than. Durations are assumed to be spread
+    /// evenly within their bucket, and those beyond the last bucket to take
+    /// as long as its upper bound. Zero when nothing has been recorded.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let counts: Vec<u64> = self.buckets.iter().map(Counter::get).collect();
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * count as f64).max(1.0);
+        let mut below = 0;
+        for (bucket, &n) in counts.iter().enumerate().take(HISTOGRAM_BUCKETS) {
+            if n > 0 && (below + n) as f64 >= rank {
+                let upper = bucket_bound(bucket);
+                let lower = if bucket == 0 { 0.0 } else { upper / 2.0 };
+                let within = (rank - below as f64) / n as f64;
+                return Duration::from_secs_f64(lower + (upper - lower) * within);
+            }
+            below += n;
+        }
+        Duration::from_secs_f64(bucket_bound(HISTOGRAM_BUCKETS - 1))
+    }
+
+    /// The 50th, 95th and 99th percentiles, in microseconds.
+    pub fn summary(&self) -> LatencySummary {
+        let micros =
+            |quantile| u64::try_from(self.quantile(quantile).as_micros()).unwrap_or(u64::MAX);
+        LatencySummary {
+            count: self.count(),
+            p50_us: micros(0.5),
+            p95_us: micros(0.95),
+            p99_us: micros(0.99),
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.reset();
+        }
+        self.nanos.reset();
+    }
+}
+
+/// Upper bound, in seconds, of a bucket of a [`Histogram`].
+fn bucket_bound(bucket: usize) -> f64 {
+    (1u64 << bucket) as f64 / 1_000_000.0
+}
+
+/// Quantiles of the [`Histogram`]s which are reported.
+const QUANTILES: [(f64, &str); 3] = [(0.5, "0.5"), (0.95, "0.95"), (0.99, "0.99")];
+
+/// Every metric recorded by the engine, shared between its components.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Keys looked up, by single or batched point lookups.
+    pub keys_read: Counter,
+    /// Keys inserted or deleted, a range deletion counting as one.
+    pub keys_written: Counter,
+    /// Size, in bytes, of the keys and values written.
+    pub bytes_written: Counter,
+
+    /// Entries appended to the WAL.
+    pub wal_appends: Counter,
+    /// Size, in bytes, of the entries appended to the WAL.
+    pub wal_bytes: Counter,
+    /// Times a WAL segment was fsynced.
+    pub wal_syncs: Counter,
+    /// Times the active WAL segment was closed and a new one started.
+    pub wal_rotations: Counter,
+
+    /// Times the active memtable was frozen and replaced with an empty one.
+    pub memtable_rotations: Counter,
+    /// Lookups which were answered by a memtable, including by a tombstone.
+    pub memtable_hits: Counter,
+    /// Lookups which had to search the SSTables.
+    pub memtable_misses: Counter,
+
+    /// SSTables searched for a key, once their key range and bloom filter
+    /// did not rule it out.
+    pub sstable_probes: Counter,
+    /// Lookups of an SSTable which were checked against its bloom filter.
+    pub bloom_checks: Counter,
+    /// Lookups of an SSTable which its bloom filter ruled out.
+    pub bloom_negatives: Counter,
+    /// Lookups of an SSTable which passed its bloom filter, yet the table held
+    /// no entry for the key.
+    pub bloom_false_positives: Counter,
+
+    /// Memtables flushed to SSTables, and the time taken.
+    pub flushes: Timer,
+    /// Size, in bytes, of the SSTables written by flushes.
+    pub bytes_flushed: Counter,
+    /// Compaction cycles which ran, and the time taken.
+    pub compactions: Timer,
+    /// Size, in bytes, of the SSTables written by compaction.
+    pub bytes_compacted: Counter,
+
+    /// Latency of point lookups, a batch of them counting as one.
+    pub get_latency: Histogram,
+    /// Latency of insertions.
+    pub put_latency: Histogram,
+    /// Latency of deletions, of single keys or of ranges.
+    pub delete_latency: Histogram,
+    /// Time spent within the engine by each scan, from its start until it is
+    /// dropped, excluding the time spent by the caller between entries.
+    pub scan_latency: Histogram,
+}
+
+impl Metrics {
+    /// Copy the current value of every metric kept for the stats API. The
+    /// block cache tracks its own hits and misses, so they are left at zero.
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            keys_read: self.keys_read.get(),
+            keys_written: self.keys_written.get(),
+            bytes_written: self.bytes_written.get(),
+            wal_appends: self.wal_appends.get(),
+            wal_bytes: self.wal_bytes.get(),
+            wal_syncs: self.wal_syncs.get(),
+            memtable_rotations: self.memtable_rotations.get(),
+            sstable_probes: self.sstable_probes.get(),
+            bloom_checks: self.bloom_checks.get(),
+            bloom_negatives: self.bloom_negatives.get(),
+            bloom_false_positives: self.bloom_false_positives.get(),
+            memtable_hits: self.memtable_hits.get(),
+            memtable_misses: self.memtable_misses.get(),
+            block_cache_hits: 0,
+            block_cache_misses: 0,
+            flushes: self.flushes.count(),
+            flush_time_ms: millis(self.flushes.total()),
+            bytes_flushed: self.bytes_flushed.get(),
+            compactions: self.compactions.count(),
+            compaction_time_ms: millis(self.compactions.total()),
+            bytes_compacted: self.bytes_compacted.get(),
+            latency: Latencies {
+                get: self.get_latency.summary(),
+                put: self.put_latency.summary(),
+                delete: self.delete_latency.summary(),
+                scan: self.scan_latency.summary(),
+            },
+        }
+    }
+
+    /// Set every metric back to zero.
+    pub fn reset(&self) {
+        for (_, counter, _) in self.counters() {
+            counter.reset();
+        }
+        for (_, timer, _, _) in self.timers() {
+            timer.reset();
+        }
+        for (_, histogram) in self.latencies() {
+            histogram.reset();
+        }
+    }
+
+    /// Write every metric to the exposition.
+    pub fn expose(&self, exposition: &mut Exposition) {
+        for (name, counter, help) in self.counters() {
+            exposition.counter(name, help, counter.get());
+        }
+        for (name, timer, count_help, time_help) in self.timers() {
+            exposition.counter(&format!("{name}_total"), count_help, timer.count());
+            exposition.counter(
+                &format!("{name}_seconds_total"),
+                time_help,
+                timer.total().as_secs_f64(),
+            );
+        }
+
+        let latencies = self.latencies();
+        let labels: Vec<[(&str, String); 1]> = latencies
+            .iter()
+            .map(|(operation, _)| [("operation", operation.to_string())])
+            .collect();
+        exposition.histogram(
+            "operation_duration_seconds",
+            "Time taken by operations within the engine",
+            labels
+                .iter()
+                .zip(latencies)
+                .map(|(labels, (_, histogram))| (&labels[..], histogram)),
+        );
+        let quantiles: Vec<([(&str, String); 2], f64)> = latencies
+            .iter()
+            .flat_map(|(operation, histogram)| {
+                QUANTILES.map(|(quantile, name)| {
+                    (
+                        [
+                            ("operation", operation.to_string()),
+                            ("quantile", name.to_string()),
+                        ],
+                        histogram.quantile(quantile).as_secs_f64(),
+                    )
+                })
+            })
+            .collect();
+        exposition.family(
+            "operation_latency_seconds",
+            "gauge",
+            "Estimated quantiles of the time taken by operations within the engine",
+            quantiles.iter().map(|(labels, value)| (&labels[..], value)),
+        );
+    }
+
+    /// The latency of each kind of operation, with the label it is exposed
+    /// with.
+    fn latencies(&self) -> [(&'static str, &Histogram); 4] {
+        [
+            ("get", &self.get_latency),
+            ("put", &self.put_latency),
+            ("delete", &self.delete_latency),
+            ("scan", &self.scan_latency),
+        ]
+    }
+
+    /// Every counter, with the name and description it is exposed with.
+    fn counters(&self) -> [(&'static str, &Counter, &'static str); 16] {
+        [
+            ("keys_read_total", &self.keys_read, "Keys looked up"),
+            (
+                "keys_written_total",
+                &self.keys_written,
+                "Keys inserted or deleted",
+            ),
+            (
+                "written_bytes_total",
+                &self.bytes_written,
+                "Size of the keys and values written",
+            ),
+            (
+                "wal_appends_total",
+                &self.wal_appends,
+                "Entries appended to the WAL",
+            ),
+            (
+                "wal_bytes_total",
+                &self.wal_bytes,
+                "Size of the entries appended to the WAL",
+            ),
+            (
+                "wal_syncs_total",
+                &self.wal_syncs,
+                "Times a WAL segment was fsynced",
+            ),
+            (
+                "wal_rotations_total",
+                &self.wal_rotations,
+                "WAL segments closed",
+            ),
+            (
+                "memtable_rotations_total",
+                &self.memtable_rotations,
+                "Memtables frozen to be flushed",
+            ),
+            (
+                "memtable_hits_total",
+                &self.memtable_hits,
+                "Lookups answered by a memtable",
+            ),
+            (
+                "memtable_misses_total",
+                &self.memtable_misses,
+                "Lookups which searched the SSTables",
+            ),
+            (
+                "sstable_probes_total",
+                &self.sstable_probes,
+                "SSTables searched for a key",
+            ),
+            (
+                "bloom_checks_total",
+                &self.bloom_checks,
+                "SSTable lookups checked against a bloom filter",
+            ),
+            (
+                "bloom_negatives_total",
+                &self.bloom_negatives,
+                "SSTable lookups ruled out by a bloom filter",
+            ),
+            (
+                "bloom_false_positives_total",
+                &self.bloom_false_positives,
+                "SSTable lookups which passed a bloom filter yet found nothing",
+            ),
+            (
+                "flushed_bytes_total",
+                &self.bytes_flushed,
+                "Size of the SSTables written by flushes",
+            ),
+            (
+                "compacted_bytes_total",
+                &self.bytes_compacted,
+                "Size of the SSTables written by compaction",
+            ),
+        ]
+    }
+
+    /// Every timer, with the name it is exposed with and descriptions of its
+    /// count and total time.
+    fn timers(&self) -> [(&'static str, &Timer, &'static str, &'static str); 2] {
+        [
+            (
+                "flushes",
+                &self.flushes,
+                "Memtables flushed to SSTables",
+                "Time spent flushing memtables",
+            ),
+            (
+                "compactions",
+                &self.compactions,
+                "Compaction cycles",
+                "Time spent compacting",
+            ),
+        ]
+    }
+}
+
+/// Whole milliseconds within a duration.
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Metrics written in the Prometheus text exposition format, each named
+/// with the `chipmunk_` prefix.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+/// Content type of an [`Exposition`].
+pub const EXPOSITION_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+impl Exposition {
+    /// Write a metric which only goes up.
+    pub fn counter(&mut self, name: &str, help: &str, value: impl fmt::Display) {
+        self.family(name, "counter", help, [(&[][..], value)]);
+    }
+
+    /// Write a metric which may go up and down.
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl fmt::Display) {
+        self.family(name, "gauge", help, [(&[][..], value)]);
+    }
+
+    /// Write a metric with a sample for each set of labels, such as one per
+    /// level of the tree.
+    pub fn family<'a, V: fmt::Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (&'a [(&'a str, String)], V)>,
+    ) {
+        self.header(name, kind, help);
+        for (labels, value) in samples {
+            self.sample(name, labels, &[], value);
+        }
+    }
+
+    /// Write a histogram with a series for each set of labels, giving the
+    /// cumulative count of each bucket, alongside the total time and count.
+    pub fn histogram<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        series: impl IntoIterator<Item = (&'a [(&'a str, String)], &'a Histogram)>,
+    ) {
+        self.header(name, "histogram", help);
+        for (labels, histogram) in series {
+            let bucket_name = format!("{name}_bucket");
+            let mut cumulative = 0;
+            for (bucket, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count.get();
+                let bound = match bucket {
+                    HISTOGRAM_BUCKETS => "+Inf".to_string(),
+                    bucket => bucket_bound(bucket).to_string(),
+                };
+                self.sample(&bucket_name, labels, &[("le", bound)], cumulative);
+            }
+            let sum = histogram.total().as_secs_f64();
+            self.sample(&format!("{name}_sum"), labels, &[], sum);
+            self.sample(&format!("{name}_count"), labels, &[], cumulative);
+        }
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {METRIC_PREFIX}{name} {help}.");
+        let _ = writeln!(self.text, "# TYPE {METRIC_PREFIX}{name} {kind}");
+    }
+
+    fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, String)],
+        extra: &[(&str, String)],
+        value: impl fmt::Display,
+    ) {
+        let _ = write!(self.text, "{METRIC_PREFIX}{name}");
+        if !labels.is_empty() || !extra.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .chain(extra)
+                .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {value}");
+    }
+
+    /// The metrics written so far.
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+/// Escape a label value as the exposition format requires.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn statistics() {
+        let metrics = Metrics::default();
+        metrics.keys_read.add(2);
+        metrics.flushes.record(Duration::from_millis(1500));
+        let statistics = metrics.statistics();
+        assert_eq!(statistics.keys_read, 2);
+        assert_eq!(statistics.flushes, 1);
+        assert_eq!(statistics.flush_time_ms, 1500);
+
+        metrics.reset();
+        assert_eq!(metrics.statistics(), Statistics::default());
+    }
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.99), Duration::ZERO);
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.total(), Duration::from_micros(5050));
+        // Quantiles are estimated within buckets which double in size
+        let p50 = histogram.quantile(0.5);
+        assert!(p50 >= Duration::from_micros(32) && p50 <= Duration::from_micros(64));
+        let p99 = histogram.quantile(0.99);
+        assert!(p99 > Duration::from_micros(64) && p99 <= Duration::from_micros(128));
+        assert!(histogram.quantile(1.0) <= Duration::from_micros(128));
+
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.buckets[HISTOGRAM_BUCKETS].get(), 1);
+        assert_eq!(
+            histogram.quantile(1.0),
+            Duration::from_secs_f64(bucket_bound(HISTOGRAM_BUCKETS - 1)),
+            "Durations beyond the last bucket are capped"
+        );
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 101);
+        assert!(summary.p50_us <= summary.p95_us && summary.p95_us <= summary.p99_us);
+    }
+
+    #[test]
+    fn exposition() {
+        let metrics = Metrics::default();
+        metrics.wal_syncs.add(3);
+        metrics.compactions.record(Duration::from_millis(250));
+        metrics.get_latency.record(Duration::from_micros(3));
+        let mut exposition = Exposition::default();
+        metrics.expose(&mut exposition);
+        exposition.family(
+            "level_files",
+            "gauge",
+            "Files within each level",
+            [(&[("level", "1".to_string())][..], 4)],
+        );
+        let text = exposition.finish();
+
+        assert!(
+            text.contains("# TYPE chipmunk_wal_syncs_total counter\nchipmunk_wal_syncs_total 3\n")
+        );
+        assert!(text.contains("chipmunk_compactions_total 1\n"));
+        assert!(text.contains("chipmunk_compactions_seconds_total 0.25\n"));
+        assert!(text.contains("chipmunk_level_files{level=\"1\"} 4\n"));
+        assert!(text.contains(
+            "chipmunk_operation_duration_seconds_bucket{operation=\"get\",le=\"0.000002\"} 0\n"
+        ));
+        assert!(text.contains(
+            "chipmunk_operation_duration_seconds_bucket{operation=\"get\",le=\"0.000004\"} 1\n"
+        ));
+        assert!(text.contains(
+            "chipmunk_operation_duration_seconds_bucket{operation=\"get\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(text.contains("chipmunk_operation_duration_seconds_count{operation=\"put\"} 0\n"));
+        assert!(text
+            .contains("chipmunk_operation_latency_seconds{operation=\"get\",quantile=\"0.99\"}"));
+        assert_eq!(escape_label("a\"b\\"), "a\\\"b\\\\");
+    }
+}
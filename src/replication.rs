@@ -0,0 +1,940 @@
+//! Replication of a store to others by shipping the records of its WAL.
+//!
+//! A primary serves the changes after a sequence number at
+//! `/replication/wal`: those its WAL still holds are read back first, then
+//! each write is sent as it is applied. A replica asks for the changes after
+//! the most recent write it holds and applies each under the same sequence
+//! number, so that it can carry on from wherever it reached should the
+//! stream break, see [`Chipmunk::follow`](crate::server::Chipmunk::follow).
+//!
+//! Every change is sent as a frame: the length of the record as a big-endian
+//! `u32`, the stamped record as it is written to the WAL, then a CRC32C
+//! checksum of the record. Values are sent as they were written, before any
+//! [`ValueTransform`](crate::transform::ValueTransform) of either store.
+//!
+//! A replica whose changes are no longer held by the WAL of the primary,
+//! having been flushed to SSTables and archived for no longer than the
+//! [`WalConfig::retention`](crate::config::WalConfig::retention), is refused
+//! with `410 Gone`. A new
+//! replica is instead [`bootstrap`]ped from a checkpoint of the primary,
+//! served at `/replication/checkpoint`, then carries on from the sequence
+//! number the checkpoint reached. Each file of the checkpoint is sent as
+//! its name, prefixed by its length as a big-endian `u16`, its size as a
+//! big-endian `u64`, its contents, then a CRC32C checksum of the contents.
+//! A name of length zero ends the checkpoint.
+//!
+//! Replicas acknowledge the most recent change they have applied to
+//! `/replication/ack`, under the ID they stream changes with. In
+//! [`AckMode::SemiSync`] the primary holds back its answer to each write
+//! until enough replicas have acknowledged it, see [`Acknowledgments`].
+//! Only replicas streaming changes are counted, so one which disconnects is
+//! forgotten until it reconnects.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+use crate::config::{AckMode, ReplicationConfig};
+use crate::lsm::{Change, ChangeKind, WalChanges};
+use crate::manifest::Manifest;
+use crate::metrics::{Counter, Exposition};
+use crate::server::{ErrorCode, ErrorResponse};
+use crate::shard::ShardedLsm;
+use crate::wal::WalEntry;
+use crate::{fs, ChipmunkError};
+
+/// Content type of the frames served at `/replication/wal`.
+pub const REPLICATION_CONTENT_TYPE: &str = "application/x-chipmunk-wal";
+
+/// Time a replica waits before reconnecting to its primary once the stream
+/// of changes ends or fails.
+pub const REPLICATION_RETRY: Duration = Duration::from_secs(1);
+
+/// Number of changes read back from the WAL ahead of the replica receiving
+/// them.
+const SHIP_BUFFER: usize = 64;
+
+/// Size, in bytes, of the length and checksum around each record.
+const FRAME_OVERHEAD: usize = 8;
+
+/// Content type of the files served at `/replication/checkpoint`.
+pub const CHECKPOINT_CONTENT_TYPE: &str = "application/x-chipmunk-checkpoint";
+
+/// Directory, within the working directory, holding the checkpoints being
+/// sent to replicas.
+const CHECKPOINT_DIRECTORY: &str = "replication-checkpoints";
+
+/// Size, in bytes, of the chunks which the contents of each file of a
+/// checkpoint are sent in.
+const CHECKPOINT_CHUNK: usize = 64 * 1024;
+
+/// The body sent to `/replication/ack` by a replica once it has applied
+/// every change up to the sequence number.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ReplicationAck {
+    /// Chosen by the replica when it starts, telling replicas apart.
+    pub replica: String,
+    pub sequence: u64,
+}
+
+/// The sequence numbers which replicas have acknowledged applying, which
+/// writes to a primary wait on in [`AckMode::SemiSync`].
+///
+/// A write which is not acknowledged by enough replicas within the timeout
+/// is answered anyway, and later writes are answered without waiting until
+/// enough replicas have caught up with the most recent write.
+#[derive(Debug)]
+pub(crate) struct Acknowledgments {
+    config: ReplicationConfig,
+    /// The replicas streaming changes, by their ID.
+    applied: watch::Sender<HashMap<String, Replica>>,
+    /// Cleared while writes are answered without waiting, having timed out.
+    synchronous: AtomicBool,
+    /// Number of writes which timed out waiting for replicas.
+    timeouts: Counter,
+}
+
+/// A replica streaming changes, see [`Acknowledgments`].
+#[derive(Debug, Default)]
+struct Replica {
+    /// The most recent sequence number the replica has applied.
+    sequence: u64,
+    /// Number of streams of changes open to the replica, more than one while
+    /// it reconnects before the primary notices the last went away.
+    streams: usize,
+}
+
+impl Acknowledgments {
+    pub(crate) fn new(config: ReplicationConfig) -> Self {
+        Self {
+            config,
+            applied: watch::Sender::new(HashMap::new()),
+            synchronous: AtomicBool::new(true),
+            timeouts: Counter::default(),
+        }
+    }
+
+    /// Whether writes currently wait for replicas.
+    fn is_waiting(&self) -> bool {
+        self.config.ack_mode == AckMode::SemiSync && self.synchronous.load(Ordering::Acquire)
+    }
+
+    /// Whether enough replicas have applied the change with the sequence
+    /// number.
+    fn replicated(&self, applied: &HashMap<String, Replica>, sequence: u64) -> bool {
+        let replicas = applied.values().filter(|r| r.sequence >= sequence).count();
+        replicas >= self.config.min_replicas
+    }
+
+    /// Count the replica, which holds every change up to `after`, until the
+    /// returned [`Connection`] is dropped as its stream of changes ends.
+    pub(crate) fn connect(self: &Arc<Self>, replica: String, after: u64) -> Connection {
+        self.applied.send_modify(|applied| {
+            let connected = applied.entry(replica.clone()).or_default();
+            connected.sequence = after.max(connected.sequence);
+            connected.streams += 1;
+        });
+        Connection {
+            acknowledgments: Arc::clone(self),
+            replica,
+        }
+    }
+
+    /// Record that the replica has applied every change up to the sequence
+    /// number, where `latest` is that of the most recent write. Replicas
+    /// which are not streaming changes are ignored.
+    pub(crate) fn acknowledge(&self, ack: ReplicationAck, latest: u64) {
+        self.applied
+            .send_if_modified(|applied| match applied.get_mut(&ack.replica) {
+                Some(replica) if ack.sequence > replica.sequence => {
+                    replica.sequence = ack.sequence;
+                    true
+                }
+                _ => false,
+            });
+        if self.config.ack_mode == AckMode::SemiSync
+            && !self.synchronous.load(Ordering::Acquire)
+            && self.replicated(&self.applied.borrow(), latest)
+            && !self.synchronous.swap(true, Ordering::AcqRel)
+        {
+            info!("Replicas caught up, writes wait for them again");
+        }
+    }
+
+    /// Wait until enough replicas have applied the change with the sequence
+    /// number, or the timeout passes, in [`AckMode::SemiSync`].
+    pub(crate) async fn wait(&self, sequence: u64) {
+        if !self.is_waiting() {
+            return;
+        }
+        let mut applied = self.applied.subscribe();
+        let replicated = applied.wait_for(|applied| self.replicated(applied, sequence));
+        if tokio::time::timeout(self.config.ack_timeout, replicated)
+            .await
+            .is_err()
+        {
+            self.timeouts.add(1);
+            if self.synchronous.swap(false, Ordering::AcqRel) {
+                warn!(
+                    sequence,
+                    min_replicas = self.config.min_replicas,
+                    timeout = ?self.config.ack_timeout,
+                    "Replicas did not acknowledge a write in time, no longer waiting for them"
+                );
+            }
+        }
+    }
+
+    pub(crate) fn expose(&self, exposition: &mut Exposition) {
+        exposition.gauge(
+            "replication_replicas",
+            "Replicas streaming changes",
+            self.applied.borrow().len(),
+        );
+        exposition.gauge(
+            "replication_semi_sync",
+            "Whether writes wait for replicas to apply them",
+            u8::from(self.is_waiting()),
+        );
+        exposition.counter(
+            "replication_ack_timeouts_total",
+            "Writes which timed out waiting for replicas, then answered anyway",
+            self.timeouts.get(),
+        );
+    }
+}
+
+/// A replica streaming changes, counted by [`Acknowledgments`] until this is
+/// dropped.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    acknowledgments: Arc<Acknowledgments>,
+    replica: String,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.acknowledgments.applied.send_modify(|applied| {
+            if let Some(replica) = applied.get_mut(&self.replica) {
+                replica.streams -= 1;
+                if replica.streams == 0 {
+                    applied.remove(&self.replica);
+                }
+            }
+        });
+    }
+}
+
+/// ID a replica streams and acknowledges changes under, random for each process.
+pub(crate) fn replica_id() -> String {
+    format!("{:016x}", fastrand::u64(..))
+}
+
+/// Base URL of the primary at the address, which is given with or without
+/// its scheme, plain HTTP being assumed without.
+pub(crate) fn primary_url(address: &str) -> String {
+    let address = address.trim_end_matches('/');
+    match address.contains("://") {
+        true => address.to_string(),
+        false => format!("http://{address}"),
+    }
+}
+
+/// The frame sending a change to a replica.
+pub(crate) fn encode(change: Change) -> Bytes {
+    let record = WalEntry::Stamped {
+        stamp: change.stamp,
+        entry: Box::new(change.kind.into_entry()),
+    }
+    .as_bytes();
+    let mut frame = BytesMut::with_capacity(record.len() + FRAME_OVERHEAD);
+    frame.put_u32(record.len() as u32);
+    frame.put_slice(&record);
+    frame.put_u32(crc32c::crc32c(&record));
+    frame.freeze()
+}
+
+/// Reassembles the changes sent by a primary from the chunks of the stream,
+/// which need not line up with the frames.
+#[derive(Debug, Default)]
+pub(crate) struct Decoder {
+    buffer: BytesMut,
+}
+
+impl Decoder {
+    /// Add a chunk of the stream.
+    pub(crate) fn extend(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// The next change, once every byte of its frame has been received.
+    pub(crate) fn next_change(&mut self) -> Result<Option<Change>, ChipmunkError> {
+        if self.buffer.len() < FRAME_OVERHEAD {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u32(&self.buffer) as usize;
+        if self.buffer.len() < len + FRAME_OVERHEAD {
+            return Ok(None);
+        }
+        self.buffer.advance(4);
+        let record = self.buffer.split_to(len);
+        let checksum = self.buffer.get_u32();
+        if crc32c::crc32c(&record) != checksum {
+            return Err(ChipmunkError::Replication(
+                "received a change which fails its checksum".to_string(),
+            ));
+        }
+        let entry = WalEntry::from_reader(&mut &record[..])
+            .map_err(|e| ChipmunkError::Replication(format!("received an invalid change: {e}")))?;
+        let WalEntry::Stamped { stamp, entry } = entry else {
+            return Err(ChipmunkError::Replication(
+                "received a change without a sequence number".to_string(),
+            ));
+        };
+        let kind = ChangeKind::from_entry(*entry).ok_or_else(|| {
+            ChipmunkError::Replication("received a change stamped twice".to_string())
+        })?;
+        Ok(Some(Change { stamp, kind }))
+    }
+}
+
+/// The frames of every change after a sequence number, for a replica to
+/// apply, read back from the WAL and then sent as each write is applied.
+///
+/// The stream fails, so that the replica reconnects from where it reached,
+/// should it fall too far behind the writes being applied.
+pub(crate) async fn ship(
+    store: Arc<ShardedLsm>,
+    after: u64,
+) -> Result<impl Stream<Item = Result<Bytes, io::Error>>, ChipmunkError> {
+    let (changes, first) = tokio::task::spawn_blocking(move || {
+        let mut changes = store.wal_changes_after(after)?;
+        let first = changes.first_after(after)?;
+        Ok::<_, ChipmunkError>((changes, first))
+    })
+    .await
+    .expect("WAL can be read")?;
+    let WalChanges {
+        backlog,
+        latest,
+        changes,
+    } = changes;
+    debug!(after, latest, "Shipping WAL");
+
+    // The WAL is read on a blocking thread, which stops once the replica
+    // goes away.
+    let (sender, receiver) = mpsc::channel(SHIP_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        for change in first.map(Ok).into_iter().chain(backlog) {
+            let failed = change.is_err();
+            if sender.blocking_send(change).is_err() || failed {
+                return;
+            }
+        }
+    });
+    let applied = BroadcastStream::new(changes).map(|change| {
+        change.map_err(|BroadcastStreamRecvError::Lagged(missed)| {
+            io::Error::other(format!("replica fell {missed} changes behind"))
+        })
+    });
+
+    // Changes applied while the WAL is read are received twice.
+    let mut last = after;
+    Ok(ReceiverStream::new(receiver)
+        .map(|change| change.map_err(io::Error::other))
+        .chain(applied)
+        .filter_map(move |change| match change {
+            Ok(change) if change.stamp.sequence <= last => None,
+            Ok(change) if change.stamp.sequence > last + 1 => Some(Err(io::Error::other(format!(
+                "changes after {last} are missing from the WAL"
+            )))),
+            Ok(change) => {
+                last = change.stamp.sequence;
+                Some(Ok(encode(change)))
+            }
+            Err(e) => Some(Err(e)),
+        }))
+}
+
+/// The error a primary answered with, where `after` is the sequence number
+/// which changes were asked for after.
+async fn refused(response: reqwest::Response, after: u64) -> ChipmunkError {
+    let status = response.status();
+    let body = response.bytes().await.unwrap_or_default();
+    match serde_json::from_slice::<ErrorResponse>(&body) {
+        Ok(error) if error.code == ErrorCode::Gone => ChipmunkError::ChangesNotRetained { after },
+        Ok(error) => ChipmunkError::Replication(error.message),
+        Err(_) => ChipmunkError::Replication(format!("primary answered {status}")),
+    }
+}
+
+/// Apply the changes shipped by the primary at the given base URL, after
+/// the most recent write the store holds, until the stream ends, returning
+/// the number of changes applied. Each batch of changes applied is
+/// acknowledged under the ID of the replica.
+pub(crate) async fn replicate(
+    store: &Arc<ShardedLsm>,
+    client: &reqwest::Client,
+    primary: &str,
+    replica: &str,
+) -> Result<u64, ChipmunkError> {
+    let after = store.sequence();
+    let primary = primary.trim_end_matches('/');
+    let url = format!("{primary}/replication/wal");
+    let failed = |e: reqwest::Error| ChipmunkError::Replication(e.to_string());
+    let mut response = client
+        .get(url)
+        .query(&[("after", after)])
+        .query(&[("replica", replica)])
+        .send()
+        .await
+        .map_err(failed)?;
+    if !response.status().is_success() {
+        return Err(refused(response, after).await);
+    }
+    debug!(primary, after, "Replicating");
+
+    let mut decoder = Decoder::default();
+    let mut applied = 0;
+    while let Some(chunk) = response.chunk().await.map_err(failed)? {
+        decoder.extend(&chunk);
+        let mut changes = Vec::new();
+        while let Some(change) = decoder.next_change()? {
+            changes.push(change);
+        }
+        if changes.is_empty() {
+            continue;
+        }
+        // Writes block while the engine stalls.
+        let applying = Arc::clone(store);
+        applied += tokio::task::spawn_blocking(move || {
+            let mut applied = 0;
+            for change in changes {
+                applied += u64::from(applying.apply_replicated(change)?);
+            }
+            Ok::<_, ChipmunkError>(applied)
+        })
+        .await
+        .expect("Replicated changes can be applied")?;
+
+        // The primary may predate acknowledgments, which are not needed
+        // unless it waits for them.
+        let ack = ReplicationAck {
+            replica: replica.to_string(),
+            sequence: store.sequence(),
+        };
+        let acknowledged = client
+            .post(format!("{primary}/replication/ack"))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&ack).expect("Acknowledgments serialize"))
+            .send()
+            .await;
+        if let Err(e) = acknowledged.and_then(reqwest::Response::error_for_status) {
+            debug!(sequence = ack.sequence, "Cannot acknowledge changes: {e}");
+        }
+    }
+    Ok(applied)
+}
+
+/// The files of a fresh checkpoint of the store, for a replica to
+/// [`bootstrap`] from.
+///
+/// The checkpoint is created within the working directory, so that its
+/// files are linked rather than copied, and removed once it has been sent
+/// or should creating it fail.
+pub(crate) async fn ship_checkpoint(
+    store: Arc<ShardedLsm>,
+) -> Result<impl Stream<Item = Result<Bytes, io::Error>>, ChipmunkError> {
+    let parent = store.working_directory().join(CHECKPOINT_DIRECTORY);
+    let directory = parent.join(replica_id());
+    let failed = |source| ChipmunkError::Checkpoint {
+        source,
+        path: directory.clone(),
+    };
+    let created = directory.clone();
+    let checkpoint = tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(parent).map_err(|source| ChipmunkError::Checkpoint {
+            source,
+            path: created.clone(),
+        })?;
+        store.checkpoint(&created)
+    })
+    .await
+    .map_err(|e| failed(io::Error::other(e)))
+    .and_then(|checkpoint| checkpoint);
+    if let Err(e) = checkpoint {
+        if let Err(e) = std::fs::remove_dir_all(&directory) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!(directory = %directory.display(), "Cannot remove failed checkpoint: {e}");
+            }
+        }
+        return Err(e);
+    }
+    debug!(directory = %directory.display(), "Shipping checkpoint");
+
+    let (sender, receiver) = mpsc::channel(SHIP_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = send_checkpoint(&directory, &sender) {
+            let _ = sender.blocking_send(Err(e));
+        }
+        if let Err(e) = std::fs::remove_dir_all(&directory) {
+            warn!(directory = %directory.display(), "Cannot remove shipped checkpoint: {e}");
+        }
+    });
+    Ok(ReceiverStream::new(receiver))
+}
+
+/// Send every file of the checkpoint within the directory, failing once
+/// the replica has gone away.
+fn send_checkpoint(
+    directory: &Path,
+    sender: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> io::Result<()> {
+    let send = |chunk| {
+        sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::other("replica went away"))
+    };
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort_unstable();
+
+    for name in names {
+        let mut file = File::open(directory.join(&name))?;
+        let mut remaining = file.metadata()?.len();
+        let mut header = BytesMut::with_capacity(name.len() + 10);
+        header.put_u16(name.len() as u16);
+        header.put_slice(name.as_bytes());
+        header.put_u64(remaining);
+        send(header.freeze())?;
+        let mut checksum = 0;
+        while remaining > 0 {
+            let mut chunk = vec![0; remaining.min(CHECKPOINT_CHUNK as u64) as usize];
+            file.read_exact(&mut chunk)?;
+            checksum = crc32c::crc32c_append(checksum, &chunk);
+            remaining -= chunk.len() as u64;
+            send(Bytes::from(chunk))?;
+        }
+        send(Bytes::copy_from_slice(&checksum.to_be_bytes()))?;
+    }
+    send(Bytes::from_static(&[0, 0]))
+}
+
+/// Remove the checkpoints left within the working directory by sends which
+/// were interrupted, such as by a crash.
+pub(crate) fn remove_checkpoints(working_directory: &Path) -> io::Result<()> {
+    match std::fs::remove_dir_all(working_directory.join(CHECKPOINT_DIRECTORY)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// A part of a checkpoint sent by a primary, see [`CheckpointDecoder`].
+#[derive(Debug, PartialEq, Eq)]
+enum CheckpointPart {
+    /// The start of a file with the name.
+    File(String),
+    /// The next of the contents of the file.
+    Contents(Bytes),
+    /// The file is complete, its checksum having matched.
+    FileEnd,
+    /// Every file has been sent.
+    End,
+}
+
+/// Splits the files of a checkpoint sent by a primary back out of the
+/// chunks of the stream, which need not line up with them.
+#[derive(Debug, Default)]
+struct CheckpointDecoder {
+    buffer: BytesMut,
+    /// Bytes of the contents of the current file yet to be received, and
+    /// the checksum of those which have been.
+    file: Option<(u64, u32)>,
+}
+
+impl CheckpointDecoder {
+    /// Add a chunk of the stream.
+    fn extend(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// The next part of the checkpoint, once it has been received.
+    fn next_part(&mut self) -> Result<Option<CheckpointPart>, ChipmunkError> {
+        match &mut self.file {
+            Some((0, checksum)) => {
+                if self.buffer.len() < 4 {
+                    return Ok(None);
+                }
+                if self.buffer.get_u32() != *checksum {
+                    return Err(ChipmunkError::Replication(
+                        "received a checkpoint file which fails its checksum".to_string(),
+                    ));
+                }
+                self.file = None;
+                Ok(Some(CheckpointPart::FileEnd))
+            }
+            Some((remaining, checksum)) => {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let len = (*remaining).min(self.buffer.len() as u64) as usize;
+                let contents = self.buffer.split_to(len).freeze();
+                *remaining -= len as u64;
+                *checksum = crc32c::crc32c_append(*checksum, &contents);
+                Ok(Some(CheckpointPart::Contents(contents)))
+            }
+            None => {
+                if self.buffer.len() < 2 {
+                    return Ok(None);
+                }
+                let name_len = BigEndian::read_u16(&self.buffer) as usize;
+                if name_len == 0 {
+                    self.buffer.advance(2);
+                    return Ok(Some(CheckpointPart::End));
+                }
+                if self.buffer.len() < name_len + 10 {
+                    return Ok(None);
+                }
+                self.buffer.advance(2);
+                let name = self.buffer.split_to(name_len);
+                let size = self.buffer.get_u64();
+                // Files are only ever written within the directory.
+                let name = String::from_utf8(name.to_vec())
+                    .ok()
+                    .filter(|name| name != ".." && name != "." && !name.contains(['/', '\\']))
+                    .ok_or_else(|| {
+                        ChipmunkError::Replication(
+                            "received a checkpoint file with an invalid name".to_string(),
+                        )
+                    })?;
+                self.file = Some((size, 0));
+                Ok(Some(CheckpointPart::File(name)))
+            }
+        }
+    }
+}
+
+/// Fill the directory with a checkpoint of the primary at the given
+/// address, with or without its scheme, when the directory does not yet
+/// hold a store. Returns the sequence number the checkpoint reached, which
+/// replication carries on from, or `None` when the directory already holds
+/// a store.
+///
+/// This lets a new replica catch up with a primary whose WAL no longer
+/// holds every change, and must be done before the store is opened. The
+/// checkpoint is written alongside the directory, then moved into place
+/// once complete, so a bootstrap which is interrupted starts over.
+pub async fn bootstrap(directory: &Path, primary: &str) -> Result<Option<u64>, ChipmunkError> {
+    let failed = |source| ChipmunkError::Checkpoint {
+        source,
+        path: directory.to_path_buf(),
+    };
+    let holds_store = match std::fs::read_dir(directory) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(failed(e)),
+    };
+    if holds_store {
+        return Ok(None);
+    }
+    let primary = primary_url(primary);
+    // Without any trailing separator, which would put it within the
+    // directory.
+    let staging = fs::temp_path(&directory.components().collect::<PathBuf>());
+    match std::fs::remove_dir_all(&staging) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(failed(e)),
+        _ => std::fs::create_dir_all(&staging).map_err(failed)?,
+    }
+    info!(primary, directory = %directory.display(), "Bootstrapping from a checkpoint");
+
+    let response = reqwest::Client::new()
+        .get(format!("{primary}/replication/checkpoint"))
+        .send()
+        .await
+        .map_err(|e| ChipmunkError::Replication(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(refused(response, 0).await);
+    }
+    let files = receive_checkpoint(response, &staging).await?;
+
+    let sequence = Manifest::open(&staging)?.version().sequence();
+    if directory.exists() {
+        std::fs::remove_dir(directory).map_err(failed)?;
+    }
+    std::fs::rename(&staging, directory)
+        .and_then(|_| fs::sync_parent(directory))
+        .map_err(failed)?;
+    info!(files, sequence, "Bootstrapped from a checkpoint");
+    Ok(Some(sequence))
+}
+
+/// Write the files of the checkpoint being received into the directory,
+/// returning how many there were.
+async fn receive_checkpoint(
+    mut response: reqwest::Response,
+    directory: &Path,
+) -> Result<usize, ChipmunkError> {
+    let failed = |path: PathBuf| move |source| ChipmunkError::Checkpoint { source, path };
+    let mut decoder = CheckpointDecoder::default();
+    let mut file: Option<(tokio::fs::File, PathBuf)> = None;
+    let mut files = 0;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| ChipmunkError::Replication(e.to_string()))?
+    {
+        decoder.extend(&chunk);
+        while let Some(part) = decoder.next_part()? {
+            match (part, &mut file) {
+                (CheckpointPart::File(name), None) => {
+                    let path = directory.join(name);
+                    let created = tokio::fs::File::create(&path)
+                        .await
+                        .map_err(failed(path.clone()))?;
+                    file = Some((created, path));
+                }
+                (CheckpointPart::Contents(contents), Some((file, path))) => {
+                    file.write_all(&contents)
+                        .await
+                        .map_err(failed(path.clone()))?;
+                }
+                (CheckpointPart::FileEnd, Some((written, path))) => {
+                    written.sync_all().await.map_err(failed(path.clone()))?;
+                    file = None;
+                    files += 1;
+                }
+                (CheckpointPart::End, None) => {
+                    fs::sync_dir(directory).map_err(failed(directory.to_path_buf()))?;
+                    return Ok(files);
+                }
+                (part, _) => unreachable!("Checkpoint parts arrive in order, not {part:?}"),
+            }
+        }
+    }
+    Err(ChipmunkError::Replication(
+        "the checkpoint ended part way through".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{
+        CompactionConfig, MemtableConfig, SstableConfig, WalConfig, WriteStallConfig,
+    };
+    use crate::memtable::WriteStamp;
+    use tempdir::TempDir;
+
+    #[test]
+    fn frames() {
+        let changes = vec![
+            Change {
+                stamp: WriteStamp {
+                    sequence: 1,
+                    written_at: 10,
+                },
+                kind: ChangeKind::Put {
+                    key: Bytes::from_static(b"key"),
+                    value: Bytes::from_static(b"value"),
+                    expires_at: Some(20),
+                },
+            },
+            Change {
+                stamp: WriteStamp {
+                    sequence: 2,
+                    written_at: 11,
+                },
+                kind: ChangeKind::DeleteRange {
+                    start: Bytes::from_static(b"a"),
+                    end: Bytes::from_static(b"b"),
+                },
+            },
+        ];
+        let stream: Vec<u8> = changes
+            .iter()
+            .cloned()
+            .flat_map(|change| encode(change).to_vec())
+            .collect();
+
+        // Frames are reassembled however the stream is split.
+        let mut decoder = Decoder::default();
+        let mut decoded = Vec::new();
+        for chunk in stream.chunks(3) {
+            decoder.extend(chunk);
+            while let Some(change) = decoder.next_change().unwrap() {
+                decoded.push(change);
+            }
+        }
+        assert_eq!(decoded, changes);
+
+        let mut corrupt = stream.clone();
+        corrupt[6] ^= 1;
+        let mut decoder = Decoder::default();
+        decoder.extend(&corrupt);
+        assert!(decoder.next_change().is_err());
+    }
+
+    #[test]
+    fn checkpoint_files() {
+        let dir = TempDir::new("checkpoint_files").unwrap();
+        let files = vec![
+            ("MANIFEST".to_string(), vec![1; 10]),
+            ("empty".to_string(), vec![]),
+            (
+                "sstable-1".to_string(),
+                (0..=255u8).cycle().take(CHECKPOINT_CHUNK + 7).collect(),
+            ),
+        ];
+        for (name, contents) in &files {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+        let (sender, mut receiver) = mpsc::channel(SHIP_BUFFER);
+        let sending = std::thread::spawn({
+            let directory = dir.path().to_path_buf();
+            move || send_checkpoint(&directory, &sender)
+        });
+        let mut stream = Vec::new();
+        while let Some(chunk) = receiver.blocking_recv() {
+            stream.extend_from_slice(&chunk.unwrap());
+        }
+        sending.join().unwrap().unwrap();
+
+        let decode = |stream: &[u8]| -> Result<Vec<(String, Vec<u8>)>, ChipmunkError> {
+            let mut decoder = CheckpointDecoder::default();
+            let mut decoded: Vec<(String, Vec<u8>)> = Vec::new();
+            for chunk in stream.chunks(1000) {
+                decoder.extend(chunk);
+                while let Some(part) = decoder.next_part()? {
+                    match part {
+                        CheckpointPart::File(name) => decoded.push((name, Vec::new())),
+                        CheckpointPart::Contents(contents) => {
+                            decoded.last_mut().unwrap().1.extend_from_slice(&contents)
+                        }
+                        CheckpointPart::FileEnd => {}
+                        CheckpointPart::End => return Ok(decoded),
+                    }
+                }
+            }
+            Err(ChipmunkError::Replication("unfinished".to_string()))
+        };
+        assert_eq!(decode(&stream).unwrap(), files);
+        assert!(decode(&stream[..stream.len() - 1]).is_err());
+
+        // The contents of the manifest follow its name and size.
+        let mut corrupt = stream.clone();
+        corrupt[20] ^= 1;
+        assert!(decode(&corrupt).is_err());
+        let mut escaping = BytesMut::new();
+        escaping.put_u16(5);
+        escaping.put_slice(b"../up");
+        escaping.put_u64(0);
+        assert!(
+            decode(&escaping).is_err(),
+            "Files stay within the directory"
+        );
+    }
+
+    #[tokio::test]
+    async fn checkpoint_failure() {
+        let dir = TempDir::new("checkpoint_failure").unwrap();
+        let store = Arc::new(
+            ShardedLsm::new(
+                1,
+                WalConfig {
+                    log_directory: dir.path().to_path_buf(),
+                    ..WalConfig::default()
+                },
+                MemtableConfig::default(),
+                SstableConfig::default(),
+                CompactionConfig::default(),
+                WriteStallConfig::default(),
+            )
+            .unwrap(),
+        );
+        std::fs::write(dir.path().join(CHECKPOINT_DIRECTORY), b"").unwrap();
+        assert!(matches!(
+            ship_checkpoint(store).await.err(),
+            Some(ChipmunkError::Checkpoint { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn acknowledgments() {
+        let ack = |replica: &str, sequence| ReplicationAck {
+            replica: replica.to_string(),
+            sequence,
+        };
+        let timeout = Duration::from_millis(50);
+        let acks = Arc::new(Acknowledgments::new(ReplicationConfig::semi_sync(
+            2, timeout,
+        )));
+        let a = acks.connect("a".to_string(), 0);
+        acks.acknowledge(ack("b", 1), 1);
+        assert!(
+            !acks.applied.borrow().contains_key("b"),
+            "Only streaming replicas are counted"
+        );
+        let b = acks.connect("b".to_string(), 0);
+        acks.acknowledge(ack("a", 1), 1);
+        acks.wait(1).await;
+        assert_eq!(acks.timeouts.get(), 1, "Only one replica applied the write");
+        assert!(!acks.is_waiting());
+        let started = std::time::Instant::now();
+        acks.wait(2).await;
+        assert!(started.elapsed() < timeout, "Writes no longer wait");
+
+        acks.acknowledge(ack("a", 2), 2);
+        assert!(!acks.is_waiting(), "Too few replicas have caught up");
+        acks.acknowledge(ack("b", 2), 2);
+        assert!(acks.is_waiting());
+        let waiting = tokio::spawn({
+            let acks = Arc::clone(&acks);
+            async move { acks.wait(3).await }
+        });
+        acks.acknowledge(ack("a", 3), 3);
+        acks.acknowledge(ack("b", 1), 3);
+        assert!(
+            acks.applied.borrow()["b"].sequence == 2,
+            "Acknowledgments only advance"
+        );
+        acks.acknowledge(ack("b", 3), 3);
+        waiting.await.unwrap();
+        assert_eq!(acks.timeouts.get(), 1);
+
+        // A replica which reconnects before its last stream is dropped is
+        // only forgotten once both are.
+        let reconnected = acks.connect("b".to_string(), 3);
+        drop(b);
+        assert_eq!(acks.applied.borrow()["b"].sequence, 3);
+        drop(reconnected);
+        drop(a);
+        assert!(acks.applied.borrow().is_empty(), "Replicas are forgotten");
+        acks.wait(4).await;
+        assert_eq!(acks.timeouts.get(), 2);
+
+        let acks = Acknowledgments::new(ReplicationConfig::default());
+        acks.wait(1).await;
+        assert!(!acks.is_waiting(), "Writes never wait asynchronously");
+        assert_eq!(acks.timeouts.get(), 0);
+    }
+}
@@ -0,0 +1,702 @@
+//! A listener speaking the Redis protocol (RESP2), see [`serve_resp`].
+//!
+//! Existing Redis clients and tooling can read and write the store through
+//! the commands which map onto it: `GET`, `SET` with an optional `EX` or `PX`
+//! expiry, `DEL`, `EXISTS`, `SCAN` with `MATCH` and `COUNT`, and `TTL`,
+//! alongside `PING` and `QUIT`. Commands are sent as arrays of bulk strings,
+//! or inline as a line of words as typed into a terminal, and may be
+//! pipelined. Keys and values are arbitrary bytes. Bulk strings beyond the
+//! largest request body the server accepts are a protocol error, which closes
+//! the connection.
+//!
+//! `SCAN` cursors are the number of keys passed over so far, so they can be
+//! resumed from any connection. Keys written or deleted during a scan may
+//! shift the cursor, in which case keys can be returned twice or skipped.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::lsm::BatchWrite;
+use crate::memtable::unix_millis;
+use crate::server::{Chipmunk, ErrorResponse};
+use crate::shard::ShardedLsm;
+use crate::ChipmunkError;
+
+/// Longest line accepted for an inline command, or the header of a bulk
+/// string or array.
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+
+/// Most arguments accepted within a single command.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+
+/// Number of keys a `SCAN` passes over when no `COUNT` is given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply to a command, encoded as RESP2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string for `None`.
+    Bulk(Option<Bytes>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{status}\r\n").as_bytes()),
+            Reply::Error(message) => {
+                // Messages must fit on a single line.
+                let message = message.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{message}\r\n").as_bytes());
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{n}\r\n").as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(replies) => {
+                out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    reply.encode(out);
+                }
+            }
+        }
+    }
+
+    fn wrong_arguments(command: &str) -> Self {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{command}' command"
+        ))
+    }
+
+    fn syntax_error() -> Self {
+        Reply::Error("ERR syntax error".into())
+    }
+}
+
+impl From<ChipmunkError> for Reply {
+    fn from(e: ChipmunkError) -> Self {
+        Reply::Error(format!("ERR {}", ErrorResponse::from(e).message))
+    }
+}
+
+/// Position just past the `\r\n` ending the line which starts at `from`, and
+/// the line itself, if it has been received.
+fn line(buf: &[u8], from: usize) -> Option<(&[u8], usize)> {
+    let end = from + buf.get(from..)?.windows(2).position(|w| w == b"\r\n")?;
+    Some((&buf[from..end], end + 2))
+}
+
+/// Parse the length following the type byte of a header line.
+fn length(header: &[u8], kind: &str) -> Result<usize, String> {
+    std::str::from_utf8(&header[1..])
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| format!("invalid {kind} length"))
+}
+
+/// Take the next complete command from the buffer, returning `None` until
+/// all of it has been received. Errors describe a protocol violation, after
+/// which the connection cannot be read further.
+fn parse_command(buf: &mut BytesMut, max_bulk: usize) -> Result<Option<Vec<Bytes>>, String> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+            if buf.len() > MAX_INLINE_SIZE {
+                return Err("too big inline request".into());
+            }
+            return Ok(None);
+        };
+        let line = buf.split_to(end + 1).freeze();
+        return Ok(Some(
+            line.split(u8::is_ascii_whitespace)
+                .filter(|word| !word.is_empty())
+                .map(|word| line.slice_ref(word))
+                .collect(),
+        ));
+    }
+
+    let incomplete = |buf: &BytesMut| {
+        if buf.len() > MAX_INLINE_SIZE {
+            Err("too big header".to_string())
+        } else {
+            Ok(None)
+        }
+    };
+    let Some((header, mut pos)) = line(buf, 0) else {
+        return incomplete(buf);
+    };
+    let count = length(header, "multibulk")?;
+    if count > MAX_ARGUMENTS {
+        return Err("invalid multibulk length".into());
+    }
+    let mut spans = Vec::with_capacity(count);
+    for _ in 0..count {
+        let Some((header, start)) = line(buf, pos) else {
+            return incomplete(buf);
+        };
+        if header.first() != Some(&b'$') {
+            return Err(format!(
+                "expected '$', got '{}'",
+                header.first().map_or(' ', |&b| b as char)
+            ));
+        }
+        let len = length(header, "bulk")?;
+        if len > max_bulk {
+            return Err("invalid bulk length".into());
+        }
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err("bulk string is not terminated".into());
+        }
+        spans.push((start, end));
+        pos = end + 2;
+    }
+    let command = buf.split_to(pos).freeze();
+    Ok(Some(
+        spans
+            .into_iter()
+            .map(|(start, end)| command.slice(start..end))
+            .collect(),
+    ))
+}
+
+/// Whether the key matches a Redis glob pattern, supporting `*`, `?`,
+/// `[...]` classes with ranges and `^` negation, and `\` escapes.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, key_rest)) = key.split_first() else {
+                return false;
+            };
+            let (negated, class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() && class[i] != b']' {
+                if class[i] == b'\\' && i + 1 < class.len() {
+                    matched |= class[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < class.len() && class[i + 1] == b'-' {
+                    let (low, high) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+                    matched |= (low..=high).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            // An unterminated class runs to the end of the pattern.
+            let rest = class.get(i + 1..).unwrap_or_default();
+            matched != negated && glob_match(rest, key_rest)
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            key.first() == Some(escaped) && glob_match(rest, &key[1..])
+        }
+        Some((&literal, rest)) => key.first() == Some(&literal) && glob_match(rest, &key[1..]),
+    }
+}
+
+/// The start of a pattern which every matching key begins with.
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+fn parse_u64(arg: &[u8]) -> Option<u64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Run a single command against the store, returning whether the connection
+/// should be closed after replying.
+async fn execute(chipmunk: &Chipmunk, args: Vec<Bytes>) -> (Reply, bool) {
+    let Some(name) = args.first() else {
+        return (Reply::Error("ERR empty command".into()), false);
+    };
+    let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+    let args = &args[1..];
+    match name.as_str() {
+        "ping" => match args {
+            [] => (Reply::Status("PONG"), false),
+            [message] => (Reply::Bulk(Some(message.clone())), false),
+            _ => (Reply::wrong_arguments(&name), false),
+        },
+        "quit" => (Reply::Status("OK"), true),
+        _ if !chipmunk.open.load(Ordering::Acquire) => {
+            (Reply::Error("ERR chipmunk is shut down".into()), false)
+        }
+        _ if !chipmunk.ready.load(Ordering::Acquire) => (
+            Reply::Error("LOADING chipmunk is restoring the dataset".into()),
+            false,
+        ),
+        "set" | "del" if chipmunk.replica_of.is_some() => (
+            Reply::Error("READONLY You can't write against a read only replica.".into()),
+            false,
+        ),
+        _ => {
+            let command = (name, args.to_vec());
+            let (reply, written) = chipmunk
+                .blocking(move |store| {
+                    let mut written = None;
+                    let reply = run(store, &command.0, &command.1, &mut written);
+                    (reply, written)
+                })
+                .await;
+            if let Some(sequence) = written {
+                chipmunk.replicated(sequence).await;
+            }
+            (reply, false)
+        }
+    }
+}
+
+/// Run a command which reads or writes the store, setting `written` to the
+/// sequence number of any write it applies.
+fn run(store: &ShardedLsm, name: &str, args: &[Bytes], written: &mut Option<u64>) -> Reply {
+    match (name, args) {
+        ("get", [key]) => match store.get(key.to_vec()) {
+            Ok(value) => Reply::Bulk(value.map(Bytes::from)),
+            Err(e) => {
+                warn!(key = ?String::from_utf8_lossy(key), "Cannot get: {e}");
+                e.into()
+            }
+        },
+        ("set", [key, value, options @ ..]) => {
+            let ttl = match options {
+                [] => None,
+                [unit, amount] => {
+                    let millis = match unit.to_ascii_lowercase().as_slice() {
+                        b"ex" => parse_u64(amount).and_then(|secs| secs.checked_mul(1000)),
+                        b"px" => parse_u64(amount),
+                        _ => return Reply::syntax_error(),
+                    };
+                    match millis {
+                        Some(millis) if millis > 0 => Some(Duration::from_millis(millis)),
+                        _ => {
+                            return Reply::Error("ERR invalid expire time in 'set' command".into())
+                        }
+                    }
+                }
+                _ => return Reply::syntax_error(),
+            };
+            let inserted = match ttl {
+                Some(ttl) => store.insert_with_ttl(key.to_vec(), value.to_vec(), ttl),
+                None => store.insert(key.to_vec(), value.to_vec()),
+            };
+            match inserted {
+                Ok(sequence) => {
+                    *written = Some(sequence);
+                    Reply::Status("OK")
+                }
+                Err(e) => {
+                    warn!(key = ?String::from_utf8_lossy(key), "Cannot insert: {e}");
+                    e.into()
+                }
+            }
+        }
+        ("del", keys) if !keys.is_empty() => {
+            // Only keys which exist are counted, so only those are deleted.
+            let mut seen = HashSet::new();
+            let mut deletes = Vec::new();
+            for key in keys.iter().filter(|key| seen.insert(*key)) {
+                match store.get(key.to_vec()) {
+                    Ok(Some(_)) => deletes.push(BatchWrite::Delete { key: key.to_vec() }),
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!(key = ?String::from_utf8_lossy(key), "Cannot get: {e}");
+                        return e.into();
+                    }
+                }
+            }
+            let deleted = deletes.len() as i64;
+            if deletes.is_empty() {
+                return Reply::Integer(0);
+            }
+            match store.write_batch(deletes) {
+                Ok(sequence) => {
+                    *written = Some(sequence);
+                    Reply::Integer(deleted)
+                }
+                Err(e) => {
+                    warn!("Cannot delete keys: {e}");
+                    e.into()
+                }
+            }
+        }
+        ("exists", keys) if !keys.is_empty() => {
+            match keys
+                .iter()
+                .map(|key| store.get(key.to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(values) => Reply::Integer(values.iter().flatten().count() as i64),
+                Err(e) => {
+                    warn!("Cannot get keys: {e}");
+                    e.into()
+                }
+            }
+        }
+        ("ttl", [key]) => match store.get_stamped(key.to_vec()) {
+            Ok(None) => Reply::Integer(-2),
+            Ok(Some(stamped)) => match stamped.expires_at {
+                None => Reply::Integer(-1),
+                Some(expires_at) => {
+                    Reply::Integer(expires_at.saturating_sub(unix_millis()).div_ceil(1000) as i64)
+                }
+            },
+            Err(e) => {
+                warn!(key = ?String::from_utf8_lossy(key), "Cannot get: {e}");
+                e.into()
+            }
+        },
+        ("scan", [cursor, options @ ..]) => {
+            let Some(cursor) = parse_u64(cursor).and_then(|c| usize::try_from(c).ok()) else {
+                return Reply::Error("ERR invalid cursor".into());
+            };
+            let mut pattern = None;
+            let mut count = DEFAULT_SCAN_COUNT;
+            for option in options.chunks(2) {
+                match option {
+                    [name, value] if name.eq_ignore_ascii_case(b"match") => {
+                        pattern = Some(value.clone());
+                    }
+                    [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                        match parse_u64(value).and_then(|n| usize::try_from(n).ok()) {
+                            Some(n) if n > 0 => count = n,
+                            _ => return Reply::syntax_error(),
+                        }
+                    }
+                    _ => return Reply::syntax_error(),
+                }
+            }
+            scan(store, cursor, pattern.as_deref(), count)
+        }
+        ("get" | "set" | "del" | "exists" | "ttl" | "scan", _) => Reply::wrong_arguments(name),
+        _ => Reply::Error(format!("ERR unknown command '{name}'")),
+    }
+}
+
+/// Pass over `count` keys from the cursor, replying with the next cursor,
+/// zero once every key has been passed over, and those keys which match the
+/// pattern.
+fn scan(store: &ShardedLsm, cursor: usize, pattern: Option<&[u8]>, count: usize) -> Reply {
+    // Only the keys sharing the literal start of the pattern are passed over,
+    // so a cursor must be resumed with the same pattern.
+    let prefix = pattern.map(literal_prefix).unwrap_or_default();
+    let passed: Result<Vec<Bytes>, _> = store
+        .scan_prefix(prefix)
+        .skip(cursor)
+        .take(count)
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect();
+    let passed = match passed {
+        Ok(passed) => passed,
+        Err(e) => {
+            warn!("Cannot scan keys: {e}");
+            return e.into();
+        }
+    };
+    let next = if passed.len() < count {
+        0
+    } else {
+        cursor + count
+    };
+    let matched = passed
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Reply::Array(vec![
+        Reply::Bulk(Some(Bytes::from(next.to_string()))),
+        Reply::Array(matched),
+    ])
+}
+
+/// Answer the commands of a connection until it is closed, or the listener
+/// is shut down, in which case commands already received are answered first.
+async fn serve_connection(
+    mut stream: TcpStream,
+    chipmunk: Chipmunk,
+    mut stopping: watch::Receiver<()>,
+) -> io::Result<()> {
+    let max_bulk = chipmunk.max_request_body;
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut out = Vec::new();
+    loop {
+        let mut closing = false;
+        while !closing {
+            match parse_command(&mut buf, max_bulk) {
+                Ok(Some(args)) if args.is_empty() => {}
+                Ok(Some(args)) => {
+                    let (reply, quit) = execute(&chipmunk, args).await;
+                    reply.encode(&mut out);
+                    closing = quit;
+                }
+                Ok(None) => break,
+                Err(message) => {
+                    Reply::Error(format!("ERR Protocol error: {message}")).encode(&mut out);
+                    buf.clear();
+                    closing = true;
+                }
+            }
+        }
+        if !out.is_empty() {
+            stream.write_all(&out).await?;
+            out.clear();
+        }
+        if closing {
+            return stream.shutdown().await;
+        }
+        tokio::select! {
+            read = stream.read_buf(&mut buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+            _ = stopping.changed() => return Ok(()),
+        }
+    }
+}
+
+/// Serve the Redis protocol on the listener until `shutdown` resolves, after
+/// which connections are closed once they have answered the commands they
+/// have received.
+///
+/// Commands are rejected with a `LOADING` error until the store has been
+/// restored.
+pub async fn serve_resp<F>(
+    listener: TcpListener,
+    store: Chipmunk,
+    shutdown: F,
+) -> Result<(), ChipmunkError>
+where
+    F: Future<Output = ()> + Send,
+{
+    let (stop, stopping) = watch::channel(());
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        while connections.try_join_next().is_some() {}
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Cannot accept connection: {e}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let store = store.clone();
+        let stopping = stopping.clone();
+        connections.spawn(async move {
+            if let Err(e) = serve_connection(stream, store, stopping).await {
+                debug!(%peer, "Connection closed with error: {e}");
+            }
+        });
+    }
+
+    drop(listener);
+    let _ = stop.send(());
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use redis::Commands;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::config::{
+        ChipmunkConfig, CompactionConfig, HttpConfig, MemtableConfig, ReplicationConfig,
+        SstableConfig, WalConfig, WriteStallConfig,
+    };
+
+    fn words(command: &[&str]) -> Vec<Bytes> {
+        command
+            .iter()
+            .map(|word| Bytes::copy_from_slice(word.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_commands() {
+        let mut buf =
+            BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\nPING hi \r\n*1\r\n$4\r\nPI"[..]);
+        assert_eq!(
+            parse_command(&mut buf, 16),
+            Ok(Some(words(&["GET", "key"])))
+        );
+        assert_eq!(
+            parse_command(&mut buf, 16),
+            Ok(Some(words(&["PING", "hi"])))
+        );
+        assert_eq!(parse_command(&mut buf, 16), Ok(None), "Incomplete");
+        buf.extend_from_slice(b"NG\r\n");
+        assert_eq!(parse_command(&mut buf, 16), Ok(Some(words(&["PING"]))));
+        assert!(buf.is_empty());
+
+        let mut binary = BytesMut::from(&b"*1\r\n$4\r\n\r\n\x00\xff\r\n"[..]);
+        assert_eq!(
+            parse_command(&mut binary, 16),
+            Ok(Some(vec![Bytes::from_static(b"\r\n\x00\xff")])),
+            "Bulk strings are binary safe"
+        );
+
+        for invalid in [&b"*1\r\n$17\r\n"[..], b"*x\r\n", b"*1\r\n:1\r\n"] {
+            assert!(parse_command(&mut BytesMut::from(invalid), 16).is_err());
+        }
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let matches = |pattern: &str, key: &str| glob_match(pattern.as_bytes(), key.as_bytes());
+        assert!(matches("*", ""));
+        assert!(matches("user:*", "user:1"));
+        assert!(!matches("user:*", "users"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("*:*:end", "a:b:c:end"));
+        assert_eq!(literal_prefix(b"user:[0-9]*"), b"user:");
+    }
+
+    #[tokio::test]
+    async fn redis_client() {
+        let dir = TempDir::new("redis_client").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024).with_max_value_size(16),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let chipmunk = Chipmunk::new(conf).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = {
+            let chipmunk = chipmunk.clone();
+            tokio::spawn(async move {
+                chipmunk
+                    .serve_resp(listener, async {
+                        let _ = stopped.await;
+                    })
+                    .await
+            })
+        };
+
+        let client = redis::Client::open(format!("redis://{addr}")).unwrap();
+        // The client blocks, so it is kept off the runtime serving it.
+        let mut conn = tokio::task::spawn_blocking({
+            let client = client.clone();
+            move || {
+                let mut conn = client.get_connection().unwrap();
+                let loading: redis::RedisResult<Option<String>> = conn.get("key");
+                assert_eq!(
+                    loading.unwrap_err().kind(),
+                    redis::ErrorKind::BusyLoadingError,
+                    "Commands are held back until the store is restored"
+                );
+                conn
+            }
+        })
+        .await
+        .unwrap();
+        chipmunk.restore().await.unwrap();
+
+        tokio::task::spawn_blocking(move || {
+            let _: () = redis::cmd("PING").query(&mut conn).unwrap();
+            let _: () = conn.set("a", "1").unwrap();
+            let _: () = redis::cmd("SET")
+                .arg("b")
+                .arg("2")
+                .arg("EX")
+                .arg(60)
+                .query(&mut conn)
+                .unwrap();
+            let _: () = conn.set(b"\x00c", b"\xff").unwrap();
+            assert_eq!(
+                conn.get::<_, Option<String>>("a").unwrap(),
+                Some("1".into())
+            );
+            assert_eq!(conn.get::<_, Vec<u8>>(b"\x00c").unwrap(), b"\xff");
+            assert_eq!(conn.get::<_, Option<String>>("missing").unwrap(), None);
+
+            assert_eq!(conn.ttl::<_, i64>("a").unwrap(), -1);
+            assert_eq!(conn.ttl::<_, i64>("b").unwrap(), 60);
+            assert_eq!(conn.ttl::<_, i64>("missing").unwrap(), -2);
+            assert_eq!(
+                conn.exists::<_, i64>(&["a", "b", "missing", "a"]).unwrap(),
+                3
+            );
+
+            for i in 0..25 {
+                let _: () = conn.set(format!("user:{i:02}"), i).unwrap();
+            }
+            let mut users: Vec<String> = conn.scan_match("user:1*").unwrap().collect();
+            users.sort();
+            assert_eq!(users.len(), 10);
+            assert_eq!(users[0], "user:10");
+            let all: Vec<Vec<u8>> = conn.scan().unwrap().collect();
+            assert_eq!(all.len(), 28);
+
+            assert_eq!(conn.del::<_, i64>(&["a", "a", "missing", "b"]).unwrap(), 2);
+            assert_eq!(conn.exists::<_, i64>("a").unwrap(), 0);
+            let unknown: redis::RedisResult<()> = redis::cmd("FLUSHALL").query(&mut conn);
+            assert!(unknown.is_err());
+            let _: () = redis::cmd("QUIT").query(&mut conn).unwrap();
+
+            // Values beyond the maximum size are not read, closing the
+            // connection.
+            let mut conn = client.get_connection().unwrap();
+            let too_large: redis::RedisResult<()> = conn.set("d", [0u8; 17].as_slice());
+            assert!(too_large.is_err());
+            assert!(conn.get::<_, Option<String>>("d").is_err());
+        })
+        .await
+        .unwrap();
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -1,146 +1,5024 @@
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::routing::{delete, get, post};
-use axum::Router;
+use axum::async_trait;
+use axum::body::{Body, HttpBody};
+use axum::extract::rejection::{BytesRejection, JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{DefaultBodyLimit, FromRequestParts, Path, Query, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderName, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::alphabet;
+use base64::engine::general_purpose::STANDARD as VALUE_BASE64;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use bytes::Bytes;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::warn;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tower_http::compression::predicate::{
+    DefaultPredicate, NotForContentType, Predicate, SizeAbove,
+};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, field, info, info_span, warn, Span};
+use tracing_subscriber::{reload, Registry};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
-use crate::config::ChipmunkConfig;
-use crate::lsm::Lsm;
+use crate::build_info::BuildInfo;
+use crate::compaction::{CompactionFilter, CompactionStats};
+use crate::comparator::Comparator;
+use crate::config::{
+    AckMode, ChipmunkConfig, CompactionConfig, CorsConfig, HttpConfig, LogLevel, TlsConfig,
+    Tunables, WriteStallConfig, DEFAULT_MAX_REQUEST_BODY,
+};
+use crate::grpc;
+use crate::lsm::{BatchWrite, Change, ChangeKind, WriteStall};
+use crate::memtable::unix_millis;
+use crate::metrics::{Exposition, EXPOSITION_CONTENT_TYPE};
+use crate::replication::{
+    self, Acknowledgments, ReplicationAck, CHECKPOINT_CONTENT_TYPE, REPLICATION_CONTENT_TYPE,
+    REPLICATION_RETRY,
+};
+use crate::resp;
+use crate::sessions;
+use crate::shard::ShardedLsm;
+use crate::statistics::{Statistics, TreeStats};
+use crate::tls::serve_tls;
+use crate::transform::ValueTransform;
+use crate::units::{ByteSize, Millis};
 use crate::ChipmunkError;
 
 pub fn new_app(store: Chipmunk) -> Router {
+    let max_request_body = store.max_request_body;
+    let request_timeout = store.http.request_timeout;
+    let cors = store.cors.as_ref().map(cors_layer);
+    let grpc = grpc::routes(store.clone());
     let store = Arc::new(store);
+    // Keys are a single path segment under `/api/v1`, so the endpoints over
+    // many keys sit a segment deeper where they cannot shadow one of them.
+    let routes = Router::new()
+        .route(
+            "/api/v1/ops/keys",
+            get(list_keys_handler).delete(delete_keys_handler),
+        )
+        .route("/api/v1/ops/watch", get(watch_handler))
+        .route("/api/v1/ops/changes", get(changes_handler))
+        .route("/api/v1/ops/export", get(export_handler))
+        .route("/replication/wal", get(replication_handler))
+        .route("/replication/checkpoint", get(checkpoint_handler))
+        // Imports are read a line at a time rather than buffered, so are not
+        // limited in size.
+        .route(
+            "/api/v1/ops/import",
+            post(import_handler).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/api/v1/ops/sessions", post(create_session_handler))
+        .route(
+            "/api/v1/ops/sessions/:id",
+            get(get_session_handler).delete(delete_session_handler),
+        )
+        .route(
+            "/api/v1/ops/sessions/:id/touch",
+            post(touch_session_handler),
+        )
+        .route(
+            "/api/v1/:key",
+            get(get_key_handler)
+                .put(put_key_handler)
+                .delete(delete_key_handler),
+        )
+        .route(
+            "/api/v2/:key",
+            get(get_key_v2_handler)
+                .put(put_key_v2_handler)
+                .delete(delete_key_v2_handler)
+                // Values are sent as base64, which grows them by a third,
+                // alongside the rest of the document.
+                .layer(DefaultBodyLimit::max(
+                    max_request_body.div_ceil(3) * 4 + V2_BODY_OVERHEAD,
+                )),
+        )
+        // The gRPC API refuses writes itself, as every method is a POST.
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&store),
+            require_primary,
+        ))
+        .merge(grpc)
+        // Replicas of replicas acknowledge the changes they apply too.
+        .route("/replication/ack", post(replication_ack_handler))
+        // Every route above reads or writes keys, which is refused while the
+        // store is shut down.
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&store),
+            require_open,
+        ))
+        .route("/health", get(liveness_handler))
+        .route("/healthz", get(liveness_handler))
+        .route("/readyz", get(readiness_handler))
+        .route("/version", get(version_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/admin/flush", post(flush_handler))
+        .route("/admin/compact", post(compact_handler))
+        .route("/admin/stats", get(stats_handler))
+        .route("/admin/shutdown", post(shutdown_handler))
+        .route("/admin/reopen", post(reopen_handler))
+        .route(
+            "/admin/config",
+            get(config_handler).put(update_config_handler),
+        )
+        .route("/admin/config/reload", post(reload_config_handler))
+        .route("/api/openapi.json", get(openapi_handler))
+        .merge(swagger_ui())
+        .fallback(|| async { ErrorResponse::new(ErrorCode::NotFound, "No such route") });
+    let routes = match request_timeout {
+        Some(timeout) => routes.layer(middleware::from_fn_with_state(timeout, time_out)),
+        None => routes,
+    };
+    let app = routes
+        // Larger bodies are answered with `413 Payload Too Large` before
+        // they are buffered.
+        .layer(DefaultBodyLimit::max(max_request_body))
+        // Within compression, so that the sizes logged are those of the
+        // bodies the handlers returned.
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(log_response)
+                .on_failure(()),
+        )
+        // Responses are compressed as negotiated through `Accept-Encoding`,
+        // unless they are too small to benefit.
+        .layer(
+            CompressionLayer::new().gzip(true).zstd(true).compress_when(
+                DefaultPredicate::new()
+                    .and(SizeAbove::new(MIN_COMPRESSED_SIZE))
+                    // Compressing would hold changes back until enough
+                    // had been written.
+                    .and(NotForContentType::const_new(REPLICATION_CONTENT_TYPE)),
+            ),
+        )
+        .with_state(store);
+    // Preflights are answered before reaching any route, so the layer is
+    // only added when configured.
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+    let request_id = HeaderName::from_static(REQUEST_ID_HEADER);
+    app.layer(PropagateRequestIdLayer::new(request_id.clone()))
+        .layer(SetRequestIdLayer::new(request_id, MakeRequestUuid))
+}
+
+/// The span every request is handled within, identified by its
+/// [`REQUEST_ID_HEADER`] so that its logs can be told apart from those of
+/// concurrent requests.
+///
+/// The size of the key is recorded once it is extracted, see [`Key`], and
+/// that of the body when the client declares it through `Content-Length`.
+fn request_span(request: &Request) -> Span {
+    let headers = request.headers();
+    let id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    let span = info_span!(
+        "request",
+        id,
+        method = %request.method(),
+        path = request.uri().path(),
+        key_bytes = field::Empty,
+        request_bytes = field::Empty,
+    );
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if let Some(length) = length {
+        span.record("request_bytes", length);
+    }
+    span
+}
+
+/// Log the outcome of a request, within its [`request_span`]. The size is
+/// that of the body before compression, and is not known for streams.
+fn log_response(response: &Response<Body>, latency: Duration, _span: &Span) {
+    info!(
+        status = response.status().as_u16(),
+        latency_us = latency.as_micros() as u64,
+        bytes = response.body().size_hint().exact(),
+        "Served request"
+    );
+}
+
+/// Refuse requests while the store is shut down through `/admin/shutdown`.
+async fn require_open(
+    State(state): State<Arc<Chipmunk>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.open.load(Ordering::Acquire) {
+        next.run(request).await
+    } else {
+        ErrorResponse::new(ErrorCode::Unavailable, "Shut down").into_response()
+    }
+}
+
+/// Refuse writes while the store is a replica, which only applies the
+/// writes of its primary.
+async fn require_primary(
+    State(state): State<Arc<Chipmunk>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match state.require_primary() {
+        Err(e) if !matches!(*request.method(), Method::GET | Method::HEAD) => {
+            ErrorResponse::from(e).into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+/// Answer requests which are not answered within the timeout with an
+/// [`ErrorCode::Timeout`], rather than leaving clients waiting on a stalled
+/// engine. Only producing the response is timed, so streamed bodies such as
+/// those of `/api/v1/ops/watch` are unaffected.
+///
+/// Handlers make their calls into the engine on the blocking pool, see
+/// [`Chipmunk::blocking`], which are left to finish in the background once
+/// timed out. A timed out write may therefore still be applied.
+async fn time_out(State(timeout): State<Duration>, request: Request, next: Next) -> Response {
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(?timeout, "Request timed out");
+            ErrorResponse::new(ErrorCode::Timeout, "Request timed out").into_response()
+        }
+    }
+}
+
+/// Answer preflights and tag responses as configured, exposing the headers
+/// which clients of the API read.
+fn cors_layer(config: &CorsConfig) -> CorsLayer {
+    let origins = match &config.allowed_origins {
+        Some(origins) => AllowOrigin::list(origins.iter().cloned()),
+        None => AllowOrigin::any(),
+    };
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(config.allowed_methods.clone())
+        .allow_headers(config.allowed_headers.clone())
+        .expose_headers([
+            header::ETAG,
+            header::RETRY_AFTER,
+            HeaderName::from_static(CONTINUATION_HEADER),
+        ])
+        .max_age(config.max_age)
+}
+
+/// The Swagger UI for [`ApiDoc`], served at `/api/docs`.
+#[cfg(feature = "swagger-ui")]
+fn swagger_ui<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    // The document itself is served by `openapi_handler`, so the UI is only
+    // pointed at it.
+    let config = utoipa_swagger_ui::Config::new(["/api/openapi.json"]);
+    utoipa_swagger_ui::SwaggerUi::new("/api/docs")
+        .config(config)
+        .into()
+}
+
+#[cfg(not(feature = "swagger-ui"))]
+fn swagger_ui<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
     Router::new()
-        .route("/health", get(|| async move { "OK" }))
-        .route("/api/v1/:key", get(get_key_handler))
-        .route("/api/v1", post(add_kv_handler))
-        .route("/api/v1/:key", delete(delete_key_handler))
-        .with_state(store)
 }
 
-async fn get_key_handler(
-    Path(key): Path<String>,
+/// The OpenAPI document describing the HTTP API, served at
+/// `/api/openapi.json`. The gRPC API is described by its protobuf definition
+/// instead.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Chipmunk",
+        description = "A key-value store built on a log-structured merge tree."
+    ),
+    paths(
+        get_key_handler,
+        put_key_handler,
+        delete_key_handler,
+        create_session_handler,
+        get_session_handler,
+        touch_session_handler,
+        delete_session_handler,
+        list_keys_handler,
+        delete_keys_handler,
+        watch_handler,
+        changes_handler,
+        export_handler,
+        import_handler,
+        replication_handler,
+        checkpoint_handler,
+        replication_ack_handler,
+        get_key_v2_handler,
+        put_key_v2_handler,
+        delete_key_v2_handler,
+        liveness_handler,
+        readiness_handler,
+        version_handler,
+        metrics_handler,
+        flush_handler,
+        compact_handler,
+        stats_handler,
+        shutdown_handler,
+        reopen_handler,
+        config_handler,
+        update_config_handler,
+        reload_config_handler,
+    ),
+    tags(
+        (name = "v1", description = "Keys and values as raw bytes"),
+        (name = "v2", description = "Keys and values as JSON documents"),
+        (name = "sessions", description = "Sessions which expire once left untouched"),
+        (name = "health", description = "Probes for orchestrators and load balancers"),
+        (name = "info", description = "The build being served"),
+        (name = "metrics", description = "Metrics for Prometheus to scrape"),
+        (name = "replication", description = "Shipping the WAL to replicas"),
+        (name = "admin", description = "Operating the storage engine"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Serve the [`ApiDoc`].
+async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Room, in bytes, allowed for the fields of a v2 write other than its value.
+const V2_BODY_OVERHEAD: usize = 1024;
+
+/// Size, in bytes, from which response bodies are compressed.
+pub const MIN_COMPRESSED_SIZE: u16 = 1024;
+
+/// Header selecting how the key within the request path is encoded, as an
+/// alternative to the `key_encoding` query parameter. See [`KeyEncoding`].
+pub const KEY_ENCODING_HEADER: &str = "x-chipmunk-key-encoding";
+
+/// Header identifying a request within the access logs, returned on every
+/// response. Requests without one are assigned a random UUID.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header returned by [`list_keys_handler`] when keys remain past the limit,
+/// holding the `cursor` which lists the next page.
+pub const CONTINUATION_HEADER: &str = "x-chipmunk-continuation";
+
+/// Header of a reconnecting server-sent events client, holding the ID of the
+/// last event it received. See [`watch_handler`].
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// Header giving the time-to-live of a written key in seconds, as an
+/// alternative to the `ttl` query parameter. See [`Ttl`].
+pub const TTL_HEADER: &str = "x-chipmunk-ttl";
+
+/// A stable identifier of why a request failed, returned within every
+/// [`ErrorResponse`] so that clients need not match on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A query parameter, header or body of the request is malformed.
+    InvalidRequest,
+    /// The key could not be decoded in the requested [`KeyEncoding`].
+    InvalidKey,
+    /// The request lacks the admin token the route requires.
+    Unauthorized,
+    /// The key, or the route, does not exist.
+    NotFound,
+    /// The operation does not apply to how the store is running, such as
+    /// flushing a store which only runs in memory.
+    Conflict,
+    /// The `If-Match` or `If-None-Match` preconditions do not hold.
+    PreconditionFailed,
+    /// The value or request body exceeds the configured maximum.
+    PayloadTooLarge,
+    /// Writes are stalled until flushes and compaction catch up, and may be
+    /// retried after the `Retry-After` of the response.
+    WriteStall,
+    /// The store cannot serve yet, such as while it is being restored.
+    Unavailable,
+    /// The request was not answered within the configured timeout, and may
+    /// be retried after the `Retry-After` of the response.
+    Timeout,
+    /// What was asked for is no longer retained, such as changes whose WAL
+    /// segments were removed once flushed.
+    Gone,
+    /// Writes are refused by a replica, and are to be sent to its primary.
+    ReadOnly,
+    /// Anything else, the details of which are only logged.
+    Internal,
+}
+
+impl ErrorCode {
+    /// Status of the responses reporting this code.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidKey => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::WriteStall => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unavailable | ErrorCode::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::ReadOnly => StatusCode::FORBIDDEN,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Seconds after which requests rejected by a [`ErrorCode::WriteStall`] or
+/// [`ErrorCode::Timeout`] should be retried, sent as the `Retry-After` header.
+pub const WRITE_STALL_RETRY_AFTER: u64 = 1;
+
+/// The JSON body of every error response, sent with the status of its
+/// [`ErrorCode`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// Human readable description, which may change between releases.
+    pub message: String,
+    /// The key the request addressed, in the requested [`KeyEncoding`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            key: None,
+        }
+    }
+
+    pub fn with_key(mut self, key: String) -> Self {
+        self.key = Some(key);
+        self
+    }
+}
+
+impl From<ChipmunkError> for ErrorResponse {
+    fn from(e: ChipmunkError) -> Self {
+        let code = e.error_code();
+        let message = match code {
+            // The internal error should be masked. We do not want to leak
+            // errors relating to underlying k-v operations over the outward
+            // facing HTTP API.
+            ErrorCode::Internal => "Internal error".to_string(),
+            _ => e.to_string(),
+        };
+        ErrorResponse::new(code, message)
+    }
+}
+
+impl ErrorResponse {
+    /// A request body which could not be extracted.
+    fn rejected_body(status: StatusCode, message: String) -> Self {
+        let code = match status {
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            _ => ErrorCode::InvalidRequest,
+        };
+        ErrorResponse::new(code, message)
+    }
+}
+
+impl From<BytesRejection> for ErrorResponse {
+    fn from(rejection: BytesRejection) -> Self {
+        ErrorResponse::rejected_body(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for ErrorResponse {
+    fn from(rejection: JsonRejection) -> Self {
+        ErrorResponse::rejected_body(rejection.status(), rejection.body_text())
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        let status = self.code.status();
+        let code = self.code;
+        let mut response = (status, Json(self)).into_response();
+        match code {
+            ErrorCode::WriteStall | ErrorCode::Timeout => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, WRITE_STALL_RETRY_AFTER.into());
+            }
+            ErrorCode::Unauthorized => {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Bearer"),
+                );
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// Base64 used for keys, URL-safe so that keys need no further escaping and
+/// accepting keys with or without padding.
+pub(crate) const KEY_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// How the key within a request path is encoded, once it has been
+/// percent-decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyEncoding {
+    /// The percent-decoded bytes are the key.
+    #[default]
+    Raw,
+    /// The percent-decoded segment is the URL-safe base64 encoding of the key.
+    Base64,
+}
+
+impl KeyEncoding {
+    /// Encode a key for use as a path segment.
+    pub fn encode(&self, key: &[u8]) -> String {
+        match self {
+            KeyEncoding::Raw => {
+                percent_encoding::percent_encode(key, percent_encoding::NON_ALPHANUMERIC)
+                    .to_string()
+            }
+            KeyEncoding::Base64 => KEY_BASE64.encode(key),
+        }
+    }
+
+    /// Decode bytes which were sent in this encoding, once any
+    /// percent-encoding has been removed.
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, ErrorResponse> {
+        match self {
+            KeyEncoding::Raw => Ok(encoded.to_vec()),
+            KeyEncoding::Base64 => KEY_BASE64.decode(encoded).map_err(|e| {
+                ErrorResponse::new(ErrorCode::InvalidKey, format!("Not valid base64: {e}"))
+                    .with_key(String::from_utf8_lossy(encoded).into_owned())
+            }),
+        }
+    }
+
+    /// Represent bytes as text within a response body.
+    fn display(&self, bytes: &[u8]) -> String {
+        match self {
+            KeyEncoding::Raw => String::from_utf8_lossy(bytes).into_owned(),
+            KeyEncoding::Base64 => KEY_BASE64.encode(bytes),
+        }
+    }
+}
+
+impl FromStr for KeyEncoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" => Ok(KeyEncoding::Raw),
+            "base64" => Ok(KeyEncoding::Base64),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct KeyParams {
+    /// How the key is encoded, which may also be given through the
+    /// `x-chipmunk-key-encoding` header.
+    key_encoding: Option<KeyEncoding>,
+}
+
+/// The key addressed by the final segment of the request path.
+///
+/// The segment is percent-decoded into bytes, so keys holding `/`, spaces or
+/// bytes which are not UTF-8 can be addressed once escaped. Clients may send
+/// keys as base64 instead, through the `key_encoding=base64` query parameter
+/// or the [`KEY_ENCODING_HEADER`]. The size of the decoded key is recorded
+/// on the [`request_span`].
+struct Key {
+    bytes: Vec<u8>,
+    encoding: KeyEncoding,
+}
+
+impl Key {
+    /// The key as reported within responses, in the encoding it was sent in.
+    fn encoded(&self) -> String {
+        self.encoding.display(&self.bytes)
+    }
+
+    /// An error about this key.
+    fn error(&self, code: ErrorCode, message: impl Into<String>) -> ErrorResponse {
+        ErrorResponse::new(code, message).with_key(self.encoded())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.bytes))
+    }
+}
+
+/// The encoding is taken from the `key_encoding` query parameter, then the
+/// [`KEY_ENCODING_HEADER`], defaulting to [`KeyEncoding::Raw`].
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for KeyEncoding {
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let params = Query::<KeyParams>::try_from_uri(&parts.uri)
+            .map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+        if let Some(encoding) = params.key_encoding {
+            return Ok(encoding);
+        }
+        match parts.headers.get(KEY_ENCODING_HEADER) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    ErrorResponse::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Unknown {KEY_ENCODING_HEADER}"),
+                    )
+                }),
+            None => Ok(KeyEncoding::Raw),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Key {
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // The raw path is used as path extractors require keys to be UTF-8.
+        let segment = parts
+            .uri
+            .path()
+            .rsplit_once('/')
+            .map(|(_, segment)| segment)
+            .unwrap_or_default();
+        let key: Vec<u8> = percent_decode_str(segment).collect();
+        let encoding = KeyEncoding::from_request_parts(parts, state).await?;
+        let bytes = encoding.decode(&key)?;
+        Span::current().record("key_bytes", bytes.len());
+        Ok(Key { bytes, encoding })
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TtlParams {
+    /// Seconds until the key expires, which may also be given through the
+    /// `x-chipmunk-ttl` header.
+    ttl: Option<u64>,
+}
+
+/// How long a written key lives for before it expires, taken in seconds from
+/// the `ttl` query parameter, then the [`TTL_HEADER`]. Keys without either
+/// never expire.
+#[derive(Debug, Clone, Copy)]
+struct Ttl(Option<Duration>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Ttl {
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let params = Query::<TtlParams>::try_from_uri(&parts.uri)
+            .map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+        let seconds = match (params.ttl, parts.headers.get(TTL_HEADER)) {
+            (Some(seconds), _) => Some(seconds),
+            (None, Some(value)) => Some(
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok())
+                    .ok_or_else(|| {
+                        ErrorResponse::new(
+                            ErrorCode::InvalidRequest,
+                            format!("{TTL_HEADER} must be a whole number of seconds"),
+                        )
+                    })?,
+            ),
+            (None, None) => None,
+        };
+        Ok(Ttl(seconds.map(Duration::from_secs)))
+    }
+}
+
+/// The entity tag of a value, returned as the `ETag` of reads and writes.
+///
+/// Tags are derived from the value alone, so they are stable across restarts
+/// and identical values share a tag.
+pub fn etag(value: &[u8]) -> String {
+    format!("\"{:x}-{:08x}\"", value.len(), crc32c::crc32c(value))
+}
+
+/// The entity tags listed by an `If-Match` or `If-None-Match` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EntityTags {
+    /// `*`, matching any current value.
+    Any,
+    Tags(Vec<String>),
+}
+
+impl EntityTags {
+    /// Parse every instance of a header, if it was given.
+    fn from_headers(parts: &Parts, name: header::HeaderName) -> Result<Option<Self>, String> {
+        let mut tags = Vec::new();
+        for value in parts.headers.get_all(&name) {
+            let value = value
+                .to_str()
+                .map_err(|_| format!("{name} must hold entity tags"))?;
+            for tag in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                if tag == "*" {
+                    return Ok(Some(EntityTags::Any));
+                }
+                tags.push(tag.to_string());
+            }
+        }
+        Ok((!tags.is_empty()).then_some(EntityTags::Tags(tags)))
+    }
+
+    /// Whether the current value matches. Weak tags are only compared if
+    /// `weak` is set, as `If-Match` requires a strong comparison.
+    fn matches(&self, current: Option<&[u8]>, weak: bool) -> bool {
+        let Some(current) = current else {
+            return false;
+        };
+        match self {
+            EntityTags::Any => true,
+            EntityTags::Tags(tags) => {
+                let current = etag(current);
+                tags.iter().any(|tag| {
+                    let tag = match tag.strip_prefix("W/") {
+                        Some(tag) if weak => tag,
+                        _ => tag,
+                    };
+                    *tag == current
+                })
+            }
+        }
+    }
+}
+
+/// The `If-Match` and `If-None-Match` preconditions of a request, which
+/// make writes conditional on the current value of the key.
+#[derive(Debug, Default)]
+struct Preconditions {
+    if_match: Option<EntityTags>,
+    if_none_match: Option<EntityTags>,
+}
+
+impl Preconditions {
+    fn is_empty(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+
+    /// Whether the preconditions hold for the current value of the key.
+    fn hold(&self, current: Option<&[u8]>) -> bool {
+        let if_match = self
+            .if_match
+            .as_ref()
+            .is_none_or(|tags| tags.matches(current, false));
+        let if_none_match = self
+            .if_none_match
+            .as_ref()
+            .is_none_or(|tags| !tags.matches(current, true));
+        if_match && if_none_match
+    }
+
+    /// Write, or delete when `new` is `None`, the key if the preconditions
+    /// hold, returning the sequence number assigned to the write, or `None`
+    /// if it was not written.
+    ///
+    /// The value the preconditions were checked against is swapped out through
+    /// [`Lsm::compare_and_swap`], so a concurrent write between the check and
+    /// the swap fails the precondition rather than being overwritten.
+    fn write(
+        &self,
+        store: &ShardedLsm,
+        key: Vec<u8>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Option<u64>, ChipmunkError> {
+        let current = store.get(key.clone())?;
+        if !self.hold(current.as_deref()) {
+            return Ok(None);
+        }
+        store.compare_and_swap(key, current, new)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Preconditions {
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let parse = |name| {
+            EntityTags::from_headers(parts, name)
+                .map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e))
+        };
+        Ok(Preconditions {
+            if_match: parse(header::IF_MATCH)?,
+            if_none_match: parse(header::IF_NONE_MATCH)?,
+        })
+    }
+}
+
+/// Proof that a request carries the configured [`HttpConfig::admin_token`]
+/// as an `Authorization: Bearer` token, required by the routes which take the
+/// store out of service, flush or compact it, or change its settings. Every
+/// such request is refused when no token is configured.
+struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<Arc<Chipmunk>> for AdminAuth {
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<Chipmunk>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = &state.http.admin_token else {
+            return Err(ErrorResponse::new(
+                ErrorCode::Unauthorized,
+                "No admin token is configured",
+            ));
+        };
+        let given = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if constant_time_eq(given.trim().as_bytes(), expected.as_bytes()) => {
+                Ok(AdminAuth)
+            }
+            _ => Err(ErrorResponse::new(
+                ErrorCode::Unauthorized,
+                "Missing or incorrect admin token",
+            )),
+        }
+    }
+}
+
+/// Compare secrets without exiting early, so that the time taken does not
+/// reveal how much of a guess was correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Options of the listing endpoint, see [`list_keys_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListParams {
+    /// Prefix which every listed key starts with, in the requested
+    /// [`KeyEncoding`].
+    #[serde(default)]
+    prefix: String,
+    /// First key to list, in the requested [`KeyEncoding`].
+    start: Option<String>,
+    /// Key to stop listing at, in the requested [`KeyEncoding`].
+    end: Option<String>,
+    /// Whether to list the `end` key too.
+    #[serde(default)]
+    end_inclusive: bool,
+    /// The [`CONTINUATION_HEADER`] of the previous page, to list the keys
+    /// after it. Takes the place of `start`.
+    cursor: Option<String>,
+    /// Maximum number of keys to list.
+    limit: Option<usize>,
+    /// Whether to include the value of each key.
+    #[serde(default)]
+    values: bool,
+}
+
+/// A key, and optionally its value, listed by [`list_keys_handler`], both in
+/// the requested [`KeyEncoding`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct ListedKey {
+    pub(crate) key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<String>,
+}
+
+/// Maximum number of keys listed by a single request when no limit is given.
+pub const DEFAULT_LIST_LIMIT: usize = 1000;
+
+/// List the keys starting with a prefix, within a range, in key order, as a
+/// JSON array.
+///
+/// When keys remain past the limit, the [`CONTINUATION_HEADER`] is returned
+/// for use as the `cursor` of the next request. Cursors are the base64 of the
+/// last key listed, but should be treated as opaque.
+///
+/// Keys and values are returned as UTF-8, with invalid bytes replaced, unless
+/// base64 is requested through the `key_encoding` query parameter or the
+/// [`KEY_ENCODING_HEADER`], which also applies to the given prefix.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ops/keys",
+    tag = "v1",
+    params(ListParams, KeyParams),
+    responses(
+        (
+            status = 200,
+            description = "Keys in key order",
+            body = Vec<ListedKey>,
+            headers(("x-chipmunk-continuation" = String, description = "Cursor listing the next page, when keys remain"))
+        ),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+    )
+)]
+async fn list_keys_handler(
+    encoding: KeyEncoding,
+    params: Result<Query<ListParams>, QueryRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Response, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let prefix = encoding.decode(params.prefix.as_bytes())?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT);
+
+    let (mut start, mut end) = state.store.prefix_bounds(&prefix);
+    if let Some(first) = params.start {
+        start = Bound::Included(encoding.decode(first.as_bytes())?.into());
+    }
+    if let Some(cursor) = params.cursor {
+        let last = KEY_BASE64
+            .decode(&cursor)
+            .map_err(|_| ErrorResponse::new(ErrorCode::InvalidRequest, "Not a valid cursor"))?;
+        start = Bound::Excluded(last.into());
+    }
+    if let Some(last) = params.end {
+        let last = Bytes::from(encoding.decode(last.as_bytes())?);
+        end = match params.end_inclusive {
+            true => Bound::Included(last),
+            false => Bound::Excluded(last),
+        };
+    }
+
+    // One more key than the limit is read, to tell whether another page
+    // follows.
+    let listed = prefix.clone();
+    let mut entries: Vec<(Bytes, Bytes)> = state
+        .blocking(move |store| {
+            store
+                .scan((start, end))
+                .filter(|result| !matches!(result, Ok((key, _)) if !key.starts_with(&listed)))
+                .take(limit.saturating_add(1))
+                .collect::<Result<_, _>>()
+        })
+        .await
+        .map_err(|e| {
+            warn!("Cannot list keys: {e}");
+            ErrorResponse::from(e)
+        })?;
+    let cursor = match entries.len() > limit {
+        true => {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| KEY_BASE64.encode(key))
+        }
+        false => None,
+    };
+    let keys: Vec<ListedKey> = entries
+        .into_iter()
+        .map(|(key, value)| ListedKey {
+            key: encoding.display(&key),
+            value: params.values.then(|| encoding.display(&value)),
+        })
+        .collect();
+    debug!(prefix = ?String::from_utf8_lossy(&prefix), keys = keys.len(), "Listed keys");
+    let mut response = Json(keys).into_response();
+    if let Some(cursor) = cursor {
+        response.headers_mut().insert(
+            CONTINUATION_HEADER,
+            cursor.parse().expect("base64 is a valid header value"),
+        );
+    }
+    Ok(response)
+}
+
+/// Options of the watch endpoint, see [`watch_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WatchParams {
+    /// Prefix which every watched key starts with, in the requested
+    /// [`KeyEncoding`].
+    #[serde(default)]
+    prefix: String,
+}
+
+/// The data of each event streamed by [`watch_handler`], sent as JSON under
+/// the event name of its `type`. Keys and values are in the requested
+/// [`KeyEncoding`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WatchEvent {
+    Put {
+        key: String,
+        value: String,
+        sequence: u64,
+        /// Unix timestamp, in milliseconds, from which the value expires.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Delete {
+        key: String,
+        sequence: u64,
+    },
+    /// Every key from `start`, inclusive, up to `end`, exclusive, was deleted.
+    DeleteRange {
+        start: String,
+        end: String,
+        sequence: u64,
+    },
+    /// The watcher fell behind and missed this many changes, which should be
+    /// caught up on by reading the keys again.
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl WatchEvent {
+    /// The event for a change, if it may affect keys starting with the
+    /// prefix. Range deletions are sent to every watcher.
+    fn from_change(change: Change, prefix: &[u8], encoding: KeyEncoding) -> Option<Self> {
+        let sequence = change.stamp.sequence;
+        match change.kind {
+            ChangeKind::Put {
+                key,
+                value,
+                expires_at,
+            } => key.starts_with(prefix).then(|| WatchEvent::Put {
+                key: encoding.display(&key),
+                value: encoding.display(&value),
+                sequence,
+                expires_at,
+            }),
+            ChangeKind::Delete { key } => key.starts_with(prefix).then(|| WatchEvent::Delete {
+                key: encoding.display(&key),
+                sequence,
+            }),
+            ChangeKind::DeleteRange { start, end } => Some(WatchEvent::DeleteRange {
+                start: encoding.display(&start),
+                end: encoding.display(&end),
+                sequence,
+            }),
+        }
+    }
+
+    fn into_event(self) -> Result<Event, axum::Error> {
+        let (name, id) = match &self {
+            WatchEvent::Put { sequence, .. } => ("put", Some(sequence)),
+            WatchEvent::Delete { sequence, .. } => ("delete", Some(sequence)),
+            WatchEvent::DeleteRange { sequence, .. } => ("delete_range", Some(sequence)),
+            WatchEvent::Lagged { .. } => ("lagged", None),
+        };
+        let event = Event::default().event(name);
+        let event = match id {
+            Some(sequence) => event.id(sequence.to_string()),
+            None => event,
+        };
+        event.json_data(self)
+    }
+}
+
+/// Stream the puts and deletes of keys starting with a prefix as server-sent
+/// events, in the order they are written, from when the request is made.
+///
+/// Each event carries a [`WatchEvent`], with the sequence number of the write
+/// as its ID. A client reconnecting with `Last-Event-ID` resumes after that
+/// write, from the recent writes the store retains. Writes which were not
+/// retained are reported by a `lagged` event first, after which the keys
+/// should be read again. The prefix is given as for [`list_keys_handler`].
+#[utoipa::path(
+    get,
+    path = "/api/v1/ops/watch",
+    tag = "v1",
+    params(
+        WatchParams,
+        KeyParams,
+        ("Last-Event-ID" = Option<u64>, Header, description = "Sequence number of the last write seen, to resume after"),
+    ),
+    responses(
+        (status = 200, description = "A stream of server-sent events", body = WatchEvent, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+    )
+)]
+async fn watch_handler(
+    encoding: KeyEncoding,
+    params: Result<Query<WatchParams>, QueryRejection>,
+    headers: HeaderMap,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let prefix = encoding.decode(params.prefix.as_bytes())?;
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .map(|id| {
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse::<u64>().ok())
+                .ok_or_else(|| {
+                    ErrorResponse::new(
+                        ErrorCode::InvalidRequest,
+                        "Last-Event-ID is not a sequence number",
+                    )
+                })
+        })
+        .transpose()?;
+    debug!(prefix = ?String::from_utf8_lossy(&prefix), ?last_event_id, "Watching keys");
+
+    let (resumed, changes) =
+        match last_event_id {
+            Some(sequence) => {
+                let resumed = state.store.subscribe_after(sequence)?;
+                let missed = (resumed.missed > 0).then_some(WatchEvent::Lagged {
+                    missed: resumed.missed,
+                });
+                let replayed: Vec<WatchEvent> =
+                    missed
+                        .into_iter()
+                        .chain(resumed.replayed.into_iter().filter_map(|change| {
+                            WatchEvent::from_change(change, &prefix, encoding)
+                        }))
+                        .collect();
+                (replayed, resumed.changes)
+            }
+            None => (Vec::new(), state.store.subscribe()?),
+        };
+    let changes = BroadcastStream::new(changes).filter_map(move |change| match change {
+        Ok(change) => WatchEvent::from_change(change, &prefix, encoding),
+        Err(BroadcastStreamRecvError::Lagged(missed)) => Some(WatchEvent::Lagged { missed }),
+    });
+    let events = tokio_stream::iter(resumed)
+        .chain(changes)
+        .map(|event| event.into_event());
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Maximum number of changes read by [`changes_handler`] when no limit is
+/// given.
+pub const DEFAULT_CHANGES_LIMIT: usize = 1000;
+
+/// Options of the changefeed, see [`changes_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ChangesParams {
+    /// Sequence number after which changes are read, the `next` of the
+    /// previous page.
+    #[serde(default)]
+    since: u64,
+    /// Prefix which the key of every change starts with, in the requested
+    /// [`KeyEncoding`].
+    #[serde(default)]
+    prefix: String,
+    /// Maximum number of changes to read.
+    limit: Option<usize>,
+}
+
+/// A page of the changefeed read by [`changes_handler`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ChangesResponse {
+    /// Changes in the order they were written, none of which are
+    /// [`WatchEvent::Lagged`].
+    pub changes: Vec<WatchEvent>,
+    /// Sequence number to read the next page after, that of the last change
+    /// passed over.
+    pub next: u64,
+    /// Sequence number of the most recent write, which `next` reaches once
+    /// every change has been read.
+    pub latest: u64,
+}
+
+/// Read the committed changes after a sequence number, in the order they
+/// were written, for consumers which keep their own copy of the keys up to
+/// date.
+///
+/// Unlike [`watch_handler`], nothing is missed: passing the `next` of each
+/// page as `since` reads every change, for as long as the WAL holds them.
+/// Changes are removed from the WAL once flushed to SSTables, or once the
+/// configured [`WalConfig::retention`](crate::config::WalConfig::retention)
+/// passes after, after which reading them is answered with `410 Gone` and
+/// the keys should be read again. Range deletions are read whatever the
+/// prefix.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ops/changes",
+    tag = "v1",
+    params(ChangesParams, KeyParams),
+    responses(
+        (status = 200, description = "The changes after the sequence number", body = ChangesResponse),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+        (status = 409, description = "The store runs in memory only, without a WAL", body = ErrorResponse),
+        (status = 410, description = "The changes are no longer held by the WAL", body = ErrorResponse),
+    )
+)]
+async fn changes_handler(
+    encoding: KeyEncoding,
+    params: Result<Query<ChangesParams>, QueryRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Json<ChangesResponse>, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let prefix = encoding.decode(params.prefix.as_bytes())?;
+    let limit = params.limit.unwrap_or(DEFAULT_CHANGES_LIMIT);
+    let since = params.since;
+    let store = Arc::clone(&state.store);
+    let page = tokio::task::spawn_blocking(move || {
+        let mut wal = store.wal_changes_after(since)?;
+        let first = wal.first_after(since)?;
+        let mut page = ChangesResponse {
+            changes: Vec::new(),
+            next: since,
+            latest: wal.latest,
+        };
+        for change in first.map(Ok).into_iter().chain(wal.backlog) {
+            if page.changes.len() >= limit {
+                break;
+            }
+            let change = change?;
+            page.next = change.stamp.sequence;
+            page.changes
+                .extend(WatchEvent::from_change(change, &prefix, encoding));
+        }
+        Ok::<_, ChipmunkError>(page)
+    })
+    .await
+    .expect("WAL can be read")?;
+    debug!(
+        since,
+        next = page.next,
+        changes = page.changes.len(),
+        "Read changes"
+    );
+    Ok(Json(page))
+}
+
+/// Content type of the newline-delimited JSON read by [`import_handler`] and
+/// written by [`export_handler`].
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Number of exported entries buffered ahead of the client reading them.
+const EXPORT_BUFFER: usize = 64;
+
+/// Number of imported entries written to the store at a time.
+const IMPORT_BATCH: usize = 1000;
+
+/// A key and its value as a line of the NDJSON read by [`import_handler`]
+/// and written by [`export_handler`], both as standard base64.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct BulkEntry {
+    pub key: String,
+    pub value: String,
+}
+
+impl BulkEntry {
+    /// Parse a line of an import, or `None` for a blank line.
+    pub fn parse(line: &[u8]) -> Result<Option<Self>, String> {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_slice(line).map_err(|e| e.to_string())
+    }
+
+    /// The key and value of the entry, decoded from base64.
+    pub fn decode(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let key = VALUE_BASE64
+            .decode(&self.key)
+            .map_err(|e| format!("Key is not valid base64: {e}"))?;
+        let value = VALUE_BASE64
+            .decode(&self.value)
+            .map_err(|e| format!("Value is not valid base64: {e}"))?;
+        Ok((key, value))
+    }
+}
+
+/// Options of deleting keys, see [`delete_keys_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteKeysParams {
+    /// Prefix which every deleted key starts with, in the requested
+    /// [`KeyEncoding`], which cannot be empty.
+    prefix: String,
+}
+
+/// Delete every key starting with a prefix, such as to clear a namespace
+/// before restoring it from an export, see [`ShardedLsm::delete_prefix`].
+///
+/// The prefix is given as for [`list_keys_handler`]. An empty prefix is
+/// refused rather than deleting every key.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/ops/keys",
+    tag = "v1",
+    params(DeleteKeysParams, KeyParams),
+    responses(
+        (status = 204, description = "The keys were deleted"),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn delete_keys_handler(
+    encoding: KeyEncoding,
+    params: Result<Query<DeleteKeysParams>, QueryRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let prefix = encoding.decode(params.prefix.as_bytes())?;
+    if prefix.is_empty() {
+        return Err(ErrorResponse::new(
+            ErrorCode::InvalidRequest,
+            "A prefix is needed to delete keys",
+        ));
+    }
+    debug!(prefix = ?String::from_utf8_lossy(&prefix), "Deleting keys");
+    let sequence = state
+        .blocking(move |store| store.delete_prefix(&prefix))
+        .await
+        .map_err(|e| {
+            warn!("Cannot delete keys: {e}");
+            ErrorResponse::from(e)
+        })?;
+    state.replicated(sequence).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Options of the export endpoint, see [`export_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportParams {
+    /// Prefix which every exported key starts with, in the requested
+    /// [`KeyEncoding`].
+    #[serde(default)]
+    prefix: String,
+    /// Key, in the requested [`KeyEncoding`], after which the export starts,
+    /// so that one which was interrupted can carry on from the last key it
+    /// wrote.
+    after: Option<String>,
+}
+
+/// Options of the import endpoint, see [`import_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImportParams {
+    /// Prefix which every imported key must start with, in the requested
+    /// [`KeyEncoding`], so that restoring one namespace cannot write into
+    /// another.
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Response to an import, see [`import_handler`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ImportResponse {
+    /// Number of entries written.
+    pub imported: u64,
+    /// Sequence number assigned to the last write, if any were made.
+    pub sequence: Option<u64>,
+}
+
+/// Stream every key starting with a prefix, in key order, as a [`BulkEntry`]
+/// per line, from a snapshot taken when the request is made.
+///
+/// The prefix is given as for [`list_keys_handler`]. Time-to-live is not
+/// exported, so keys imported from the export do not expire.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ops/export",
+    tag = "v1",
+    params(ExportParams, KeyParams),
+    responses(
+        (status = 200, description = "A line of JSON for each key", body = BulkEntry, content_type = "application/x-ndjson"),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+    )
+)]
+async fn export_handler(
+    encoding: KeyEncoding,
+    params: Result<Query<ExportParams>, QueryRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Response, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let prefix = encoding.decode(params.prefix.as_bytes())?;
+    let after = params
+        .after
+        .map(|after| encoding.decode(after.as_bytes()))
+        .transpose()?;
+    debug!(prefix = ?String::from_utf8_lossy(&prefix), "Exporting keys");
+
+    // Entries are read on a blocking thread, which stops once the client
+    // goes away. An entry which cannot be read ends the body early, so the
+    // client sees the export fail rather than a truncated one succeed.
+    let snapshot = state
+        .blocking(|store| store.snapshot())
+        .await
+        .map_err(|e| {
+            warn!("Cannot export keys: {e}");
+            ErrorResponse::from(e)
+        })?;
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, ChipmunkError>>(EXPORT_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let entries: Box<dyn Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>>> = match &after
+        {
+            Some(after) => Box::new(snapshot.scan_prefix_after(&prefix, after)),
+            None => Box::new(snapshot.scan_prefix(&prefix)),
+        };
+        for entry in entries {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Cannot export keys: {e}");
+                    let _ = sender.blocking_send(Err(e));
+                    return;
+                }
+            };
+            let entry = BulkEntry {
+                key: VALUE_BASE64.encode(&key),
+                value: VALUE_BASE64.encode(&value),
+            };
+            let mut line = serde_json::to_vec(&entry).expect("entries serialize to JSON");
+            line.push(b'\n');
+            if sender.blocking_send(Ok(line.into())).is_err() {
+                return;
+            }
+        }
+    });
+    Ok((
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+/// Write the entries of an import, a line at a time, in batches of
+/// [`IMPORT_BATCH`].
+///
+/// Each line of the body is a [`BulkEntry`], as written by
+/// [`export_handler`], and blank lines are skipped. Lines are limited to the
+/// size of a v2 write, rather than the body as a whole. Imports are not
+/// atomic: the batches written before an invalid line are kept, the error
+/// giving the number of that line. A line whose key does not start with the
+/// given prefix is invalid.
+#[utoipa::path(
+    post,
+    path = "/api/v1/ops/import",
+    tag = "v1",
+    params(ImportParams, KeyParams),
+    request_body(content = BulkEntry, content_type = "application/x-ndjson", description = "A line of JSON for each key"),
+    responses(
+        (status = 200, description = "Every entry was written", body = ImportResponse),
+        (status = 400, description = "Invalid line", body = ErrorResponse),
+        (status = 413, description = "A line or value is too large", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn import_handler(
+    encoding: KeyEncoding,
+    params: Result<Query<ImportParams>, QueryRejection>,
     State(state): State<Arc<Chipmunk>>,
-) -> impl IntoResponse {
-    match state.store.read().await.get(key.into_bytes()) {
-        Some(value) => (value).into_response(),
-        None => StatusCode::NOT_FOUND.into_response(),
+    body: Body,
+) -> Result<Json<ImportResponse>, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let prefix = encoding.decode(params.prefix.as_bytes())?;
+    let max_line = state.max_request_body.div_ceil(3) * 4 + V2_BODY_OVERHEAD;
+    let mut body = body.into_data_stream();
+    let mut imported = ImportResponse {
+        imported: 0,
+        sequence: None,
+    };
+    let mut batch = Vec::new();
+    // Bytes received past the last full line.
+    let mut pending = Vec::new();
+    let mut line_number = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| {
+            ErrorResponse::new(ErrorCode::InvalidRequest, format!("Cannot read body: {e}"))
+        })?;
+        pending.extend_from_slice(&chunk);
+        let mut start = 0;
+        while let Some(end) = pending[start..].iter().position(|&b| b == b'\n') {
+            line_number += 1;
+            batch.extend(bulk_write(
+                &pending[start..start + end],
+                line_number,
+                &prefix,
+            )?);
+            start += end + 1;
+            if batch.len() >= IMPORT_BATCH {
+                import_batch(&state, &mut batch, &mut imported).await?;
+            }
+        }
+        pending.drain(..start);
+        if pending.len() > max_line {
+            return Err(ErrorResponse::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Line {} is longer than {max_line} bytes", line_number + 1),
+            ));
+        }
+    }
+    // The last line need not be terminated.
+    batch.extend(bulk_write(&pending, line_number + 1, &prefix)?);
+    import_batch(&state, &mut batch, &mut imported).await?;
+    debug!(imported = imported.imported, "Imported keys");
+    Ok(Json(imported))
+}
+
+/// The write of a line of an import, or none for a blank line.
+fn bulk_write(
+    line: &[u8],
+    line_number: u64,
+    prefix: &[u8],
+) -> Result<Option<BatchWrite>, ErrorResponse> {
+    let invalid = |reason: String| {
+        ErrorResponse::new(
+            ErrorCode::InvalidRequest,
+            format!("Line {line_number}: {reason}"),
+        )
+    };
+    let Some(entry) = BulkEntry::parse(line).map_err(invalid)? else {
+        return Ok(None);
+    };
+    let (key, value) = entry.decode().map_err(invalid)?;
+    if !key.starts_with(prefix) {
+        return Err(invalid("Key does not start with the prefix".to_string()));
+    }
+    Ok(Some(BatchWrite::Put {
+        key,
+        value,
+        ttl: None,
+    }))
+}
+
+/// Write and clear a batch of an import, counting it towards the response.
+async fn import_batch(
+    state: &Chipmunk,
+    batch: &mut Vec<BatchWrite>,
+    imported: &mut ImportResponse,
+) -> Result<(), ErrorResponse> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let writes = std::mem::take(batch);
+    let count = writes.len() as u64;
+    let sequence = state
+        .blocking(move |store| store.write_batch(writes))
+        .await
+        .map_err(|e| {
+            warn!(imported = imported.imported, "Cannot import batch: {e}");
+            ErrorResponse::from(e)
+        })?;
+    state.replicated(sequence).await;
+    imported.imported += count;
+    imported.sequence = Some(sequence);
+    Ok(())
+}
+
+/// Options of the replication endpoint, see [`replication_handler`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReplicationParams {
+    /// Sequence number of the most recent change the replica holds, after
+    /// which changes are sent.
+    #[serde(default)]
+    after: u64,
+    /// ID the replica acknowledges changes under, counted towards
+    /// [`AckMode::SemiSync`] writes while the stream is open.
+    replica: Option<String>,
+}
+
+/// Stream the changes after a sequence number for a replica to apply, read
+/// back from the WAL and then sent as each write is applied, as described
+/// by [`crate::replication`].
+///
+/// Changes which the WAL no longer holds, as they were flushed, are answered
+/// with `410 Gone`. The stream ends with an error should the replica fall
+/// too far behind, after which it reconnects from where it reached.
+#[utoipa::path(
+    get,
+    path = "/replication/wal",
+    tag = "replication",
+    params(ReplicationParams),
+    responses(
+        (status = 200, description = "A frame for each change", body = Vec<u8>, content_type = "application/x-chipmunk-wal"),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+        (status = 409, description = "The store runs in memory only, without a WAL", body = ErrorResponse),
+        (status = 410, description = "The changes are no longer held by the WAL", body = ErrorResponse),
+    )
+)]
+async fn replication_handler(
+    params: Result<Query<ReplicationParams>, QueryRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Response, ErrorResponse> {
+    let Query(params) =
+        params.map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))?;
+    let frames = replication::ship(Arc::clone(&state.store), params.after).await?;
+    // The replica is forgotten once the stream is dropped as it goes away.
+    let connection = params
+        .replica
+        .map(|replica| state.acknowledgments.connect(replica, params.after));
+    let frames = frames.map(move |frame| {
+        let _connected = &connection;
+        frame
+    });
+    Ok((
+        [(header::CONTENT_TYPE, REPLICATION_CONTENT_TYPE)],
+        Body::from_stream(frames),
+    )
+        .into_response())
+}
+
+/// Send a fresh checkpoint of the store, for a new replica to bootstrap
+/// from before replicating the changes after it, as described by
+/// [`crate::replication`].
+#[utoipa::path(
+    get,
+    path = "/replication/checkpoint",
+    tag = "replication",
+    responses(
+        (status = 200, description = "Every file of the checkpoint", body = Vec<u8>, content_type = "application/x-chipmunk-checkpoint"),
+        (status = 409, description = "The store runs in memory only", body = ErrorResponse),
+    )
+)]
+async fn checkpoint_handler(State(state): State<Arc<Chipmunk>>) -> Result<Response, ErrorResponse> {
+    let files = replication::ship_checkpoint(Arc::clone(&state.store)).await?;
+    Ok((
+        [(header::CONTENT_TYPE, CHECKPOINT_CONTENT_TYPE)],
+        Body::from_stream(files),
+    )
+        .into_response())
+}
+
+/// Record that a replica has applied every change up to a sequence number,
+/// which writes wait on in [`AckMode::SemiSync`].
+#[utoipa::path(
+    post,
+    path = "/replication/ack",
+    tag = "replication",
+    request_body = ReplicationAck,
+    responses(
+        (status = 204, description = "The acknowledgment was recorded"),
+        (status = 400, description = "Invalid body", body = ErrorResponse),
+    )
+)]
+async fn replication_ack_handler(
+    State(state): State<Arc<Chipmunk>>,
+    ack: Result<Json<ReplicationAck>, JsonRejection>,
+) -> Result<StatusCode, ErrorResponse> {
+    let Json(ack) = ack?;
+    state
+        .acknowledgments
+        .acknowledge(ack, state.store.sequence());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Read the value of a key along with its [`etag`]. A matching
+/// `If-None-Match` is answered with `304 Not Modified` and no body.
+#[utoipa::path(
+    get,
+    path = "/api/v1/{key}",
+    tag = "v1",
+    params(
+        ("key" = String, Path, description = "The key, percent-encoded or in the requested encoding"),
+        KeyParams,
+        ("If-None-Match" = Option<String>, Header, description = "Entity tags of values the client already holds"),
+    ),
+    responses(
+        (status = 200, description = "The value", body = Vec<u8>, content_type = "application/octet-stream",
+            headers(("ETag" = String, description = "Entity tag of the value"))),
+        (status = 304, description = "The value matches `If-None-Match`"),
+        (status = 404, description = "No such key", body = ErrorResponse),
+    )
+)]
+async fn get_key_handler(
+    key: Key,
+    preconditions: Preconditions,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Response, ErrorResponse> {
+    let lookup = key.bytes.clone();
+    let value = state
+        .blocking(move |store| store.get(lookup))
+        .await
+        .map_err(|e| {
+            warn!("Cannot get '{key}': {e}");
+            ErrorResponse::from(e).with_key(key.encoded())
+        })?;
+    let Some(value) = value else {
+        return Err(key.error(ErrorCode::NotFound, "Key not found"));
+    };
+    let etag = etag(&value);
+    if preconditions
+        .if_none_match
+        .is_some_and(|tags| tags.matches(Some(&value), true))
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::ETAG, etag),
+        ],
+        value,
+    )
+        .into_response())
+}
+
+/// Delete a key, answering `412 Precondition Failed` if the request's
+/// [`Preconditions`] do not hold.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/{key}",
+    tag = "v1",
+    params(
+        ("key" = String, Path, description = "The key, percent-encoded or in the requested encoding"),
+        KeyParams,
+        ("If-Match" = Option<String>, Header, description = "Entity tags the current value must match"),
+        ("If-None-Match" = Option<String>, Header, description = "Entity tags the current value must not match"),
+    ),
+    responses(
+        (status = 204, description = "The key was deleted"),
+        (status = 412, description = "The preconditions do not hold", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn delete_key_handler(
+    key: Key,
+    preconditions: Preconditions,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    let deleting = key.bytes.clone();
+    let deleted = state
+        .blocking(move |store| {
+            if preconditions.is_empty() {
+                store.delete(deleting).map(Some)
+            } else {
+                preconditions.write(store, deleting, None)
+            }
+        })
+        .await;
+    match deleted {
+        Ok(Some(sequence)) => {
+            state.replicated(sequence).await;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(None) => Err(key.error(ErrorCode::PreconditionFailed, "Precondition failed")),
+        Err(e) => {
+            warn!("Cannot delete '{key}': {e}");
+            Err(ErrorResponse::from(e).with_key(key.encoded()))
+        }
+    }
+}
+
+/// Store the raw request body as the value of the key, so values of any
+/// bytes round-trip unchanged. The key expires if a [`Ttl`] is given.
+///
+/// Writes with `If-Match` or `If-None-Match` are only applied if the
+/// [`Preconditions`] hold, answering `412 Precondition Failed` otherwise, and
+/// cannot be combined with a [`Ttl`]. The [`etag`] of the written value is
+/// returned.
+#[utoipa::path(
+    put,
+    path = "/api/v1/{key}",
+    tag = "v1",
+    params(
+        ("key" = String, Path, description = "The key, percent-encoded or in the requested encoding"),
+        KeyParams,
+        TtlParams,
+        ("If-Match" = Option<String>, Header, description = "Entity tags the current value must match"),
+        ("If-None-Match" = Option<String>, Header, description = "Entity tags the current value must not match"),
+    ),
+    request_body(content = Vec<u8>, description = "The value", content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "The value was written",
+            headers(("ETag" = String, description = "Entity tag of the value"))),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+        (status = 412, description = "The preconditions do not hold", body = ErrorResponse),
+        (status = 413, description = "The value is too large", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn put_key_handler(
+    key: Key,
+    Ttl(ttl): Ttl,
+    preconditions: Preconditions,
+    State(state): State<Arc<Chipmunk>>,
+    value: Result<Bytes, BytesRejection>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let value = value.map_err(|e| ErrorResponse::from(e).with_key(key.encoded()))?;
+    let etag = etag(&value);
+    if ttl.is_some() && !preconditions.is_empty() {
+        return Err(key.error(
+            ErrorCode::InvalidRequest,
+            "A TTL cannot be given with If-Match or If-None-Match",
+        ));
+    }
+    let writing = key.bytes.clone();
+    let inserted = state
+        .blocking(move |store| match ttl {
+            Some(ttl) => store
+                .insert_with_ttl(writing, value.to_vec(), ttl)
+                .map(Some),
+            None if preconditions.is_empty() => store.insert(writing, value.to_vec()).map(Some),
+            None => preconditions.write(store, writing, Some(value.to_vec())),
+        })
+        .await;
+    match inserted {
+        Ok(Some(sequence)) => {
+            state.replicated(sequence).await;
+            Ok((StatusCode::NO_CONTENT, [(header::ETAG, etag)]))
+        }
+        Ok(None) => Err(key.error(ErrorCode::PreconditionFailed, "Precondition failed")),
+        Err(e) => {
+            warn!("Cannot insert '{key}': {e}");
+            Err(ErrorResponse::from(e).with_key(key.encoded()))
+        }
+    }
+}
+
+/// Shortest TTL a session can be created with, see
+/// [`create_session_handler`].
+const MIN_SESSION_TTL: Duration = Duration::from_secs(1);
+
+/// A session created through [`create_session_handler`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SessionResponse {
+    /// Opaque id of the session, with which it is read, touched and deleted.
+    pub id: String,
+}
+
+/// The id of a session within the request path.
+fn session_id(id: Result<Path<String>, PathRejection>) -> Result<String, ErrorResponse> {
+    id.map(|Path(id)| id)
+        .map_err(|e| ErrorResponse::new(ErrorCode::InvalidRequest, e.body_text()))
+}
+
+/// Create a session holding the raw request body, which expires once it has
+/// gone untouched for its [`Ttl`], see [`crate::sessions`]. Sessions must be
+/// given a TTL of at least a second.
+#[utoipa::path(
+    post,
+    path = "/api/v1/ops/sessions",
+    tag = "sessions",
+    params(TtlParams),
+    request_body(content = Vec<u8>, description = "Data of the session", content_type = "application/octet-stream"),
+    responses(
+        (status = 201, description = "The session was created", body = SessionResponse,
+            headers(("Location" = String, description = "Path of the session"))),
+        (status = 400, description = "Invalid parameters, or no TTL", body = ErrorResponse),
+        (status = 413, description = "The data is too large", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn create_session_handler(
+    Ttl(ttl): Ttl,
+    State(state): State<Arc<Chipmunk>>,
+    data: Result<Bytes, BytesRejection>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let data = data?;
+    let Some(window) = ttl.filter(|ttl| *ttl >= MIN_SESSION_TTL) else {
+        return Err(ErrorResponse::new(
+            ErrorCode::InvalidRequest,
+            "Sessions need a TTL of at least one second",
+        ));
+    };
+    let created = state
+        .blocking(move |store| sessions::create(store, data.to_vec(), window))
+        .await;
+    match created {
+        Ok((id, sequence)) => {
+            state.replicated(sequence).await;
+            Ok((
+                StatusCode::CREATED,
+                [(header::LOCATION, format!("/api/v1/ops/sessions/{id}"))],
+                Json(SessionResponse { id }),
+            ))
+        }
+        Err(e) => {
+            warn!("Cannot create session: {e}");
+            Err(ErrorResponse::from(e))
+        }
+    }
+}
+
+/// Read the data of a session. Reading does not touch the session, so does
+/// not keep it from expiring.
+#[utoipa::path(
+    get,
+    path = "/api/v1/ops/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path, description = "The id of the session")),
+    responses(
+        (status = 200, description = "Data of the session", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "No such session, or it has expired", body = ErrorResponse),
+    )
+)]
+async fn get_session_handler(
+    id: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Response, ErrorResponse> {
+    let id = session_id(id)?;
+    let session = state
+        .blocking(move |store| sessions::get(store, &id))
+        .await
+        .map_err(|e| {
+            warn!("Cannot get session: {e}");
+            ErrorResponse::from(e)
+        })?;
+    let Some(session) = session else {
+        return Err(ErrorResponse::new(ErrorCode::NotFound, "Session not found"));
+    };
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        session.data,
+    )
+        .into_response())
+}
+
+/// Restart the window of a session, so it expires once it has gone untouched
+/// for its TTL from now.
+#[utoipa::path(
+    post,
+    path = "/api/v1/ops/sessions/{id}/touch",
+    tag = "sessions",
+    params(("id" = String, Path, description = "The id of the session")),
+    responses(
+        (status = 204, description = "The session was touched"),
+        (status = 404, description = "No such session, or it has expired", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn touch_session_handler(
+    id: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    let id = session_id(id)?;
+    let touched = state
+        .blocking(move |store| sessions::touch(store, &id))
+        .await;
+    session_written(&state, touched, "touch").await
+}
+
+/// Delete a session before it expires.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/ops/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path, description = "The id of the session")),
+    responses(
+        (status = 204, description = "The session was deleted"),
+        (status = 404, description = "No such session, or it has expired", body = ErrorResponse),
+    )
+)]
+async fn delete_session_handler(
+    id: Result<Path<String>, PathRejection>,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    let id = session_id(id)?;
+    let deleted = state
+        .blocking(move |store| sessions::delete(store, &id))
+        .await;
+    session_written(&state, deleted, "delete").await
+}
+
+/// Answer a write to a session which returns its sequence number, or `None`
+/// if there is no such session.
+async fn session_written(
+    state: &Chipmunk,
+    written: Result<Option<u64>, ChipmunkError>,
+    operation: &str,
+) -> Result<StatusCode, ErrorResponse> {
+    match written {
+        Ok(Some(sequence)) => {
+            state.replicated(sequence).await;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(None) => Err(ErrorResponse::new(ErrorCode::NotFound, "Session not found")),
+        Err(e) => {
+            warn!("Cannot {operation} session: {e}");
+            Err(ErrorResponse::from(e))
+        }
+    }
+}
+
+/// A value read through the v2 API, see [`get_key_v2_handler`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ValueResponse {
+    /// The key, in the requested [`KeyEncoding`].
+    pub key: String,
+    /// The value, as standard base64.
+    pub value: String,
+    /// Seconds until the key expires, `None` if it never does.
+    pub ttl: Option<u64>,
+    /// Sequence number of the write which produced the value.
+    pub sequence: Option<u64>,
+    /// Unix timestamp, in milliseconds, at which the value was written.
+    pub created_at: Option<u64>,
+}
+
+/// Body of a write through the v2 API, see [`put_key_v2_handler`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct WriteRequest {
+    /// The value, as standard base64.
+    pub value: String,
+    /// Seconds until the key expires, if it should.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+/// Response to a write or deletion through the v2 API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct WriteResponse {
+    /// The key, in the requested [`KeyEncoding`].
+    pub key: String,
+    /// Sequence number assigned to the write.
+    pub sequence: u64,
+}
+
+/// Read the value of a key alongside its expiry and the write which produced
+/// it. Keys written before this was recorded have no `sequence` or
+/// `created_at`.
+#[utoipa::path(
+    get,
+    path = "/api/v2/{key}",
+    tag = "v2",
+    params(("key" = String, Path, description = "The key, percent-encoded or in the requested encoding"), KeyParams),
+    responses(
+        (status = 200, description = "The value", body = ValueResponse),
+        (status = 404, description = "No such key", body = ErrorResponse),
+    )
+)]
+async fn get_key_v2_handler(
+    key: Key,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Json<ValueResponse>, ErrorResponse> {
+    let lookup = key.bytes.clone();
+    let stamped = state
+        .blocking(move |store| store.get_stamped(lookup))
+        .await
+        .map_err(|e| {
+            warn!("Cannot get '{key}': {e}");
+            ErrorResponse::from(e).with_key(key.encoded())
+        })?;
+    let Some(stamped) = stamped else {
+        return Err(key.error(ErrorCode::NotFound, "Key not found"));
+    };
+    let now = unix_millis();
+    Ok(Json(ValueResponse {
+        key: key.encoded(),
+        value: VALUE_BASE64.encode(&stamped.value),
+        // Keys are readable until they expire, so this never rounds to zero.
+        ttl: stamped
+            .expires_at
+            .map(|expires_at| expires_at.saturating_sub(now).div_ceil(1000)),
+        sequence: stamped.stamp.map(|stamp| stamp.sequence),
+        created_at: stamped.stamp.map(|stamp| stamp.written_at),
+    }))
+}
+
+/// Store the value of a [`WriteRequest`], returning the sequence number
+/// assigned to the write.
+#[utoipa::path(
+    put,
+    path = "/api/v2/{key}",
+    tag = "v2",
+    params(("key" = String, Path, description = "The key, percent-encoded or in the requested encoding"), KeyParams),
+    request_body = WriteRequest,
+    responses(
+        (status = 200, description = "The value was written", body = WriteResponse),
+        (status = 400, description = "Invalid body", body = ErrorResponse),
+        (status = 413, description = "The value is too large", body = ErrorResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn put_key_v2_handler(
+    key: Key,
+    State(state): State<Arc<Chipmunk>>,
+    request: Result<Json<WriteRequest>, JsonRejection>,
+) -> Result<Json<WriteResponse>, ErrorResponse> {
+    let Json(request) = request.map_err(|e| ErrorResponse::from(e).with_key(key.encoded()))?;
+    let value = VALUE_BASE64.decode(&request.value).map_err(|e| {
+        key.error(
+            ErrorCode::InvalidRequest,
+            format!("Value is not valid base64: {e}"),
+        )
+    })?;
+    let writing = key.bytes.clone();
+    let written = state
+        .blocking(move |store| match request.ttl {
+            Some(ttl) => store.insert_with_ttl(writing, value, Duration::from_secs(ttl)),
+            None => store.insert(writing, value),
+        })
+        .await;
+    match written {
+        Ok(sequence) => {
+            state.replicated(sequence).await;
+            Ok(Json(WriteResponse {
+                key: key.encoded(),
+                sequence,
+            }))
+        }
+        Err(e) => {
+            warn!("Cannot insert '{key}': {e}");
+            Err(ErrorResponse::from(e).with_key(key.encoded()))
+        }
+    }
+}
+
+/// Delete a key, returning the sequence number assigned to the deletion.
+#[utoipa::path(
+    delete,
+    path = "/api/v2/{key}",
+    tag = "v2",
+    params(("key" = String, Path, description = "The key, percent-encoded or in the requested encoding"), KeyParams),
+    responses(
+        (status = 200, description = "The key was deleted", body = WriteResponse),
+        (status = 429, description = "Writes are stalled", body = ErrorResponse),
+    )
+)]
+async fn delete_key_v2_handler(
+    key: Key,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Json<WriteResponse>, ErrorResponse> {
+    let deleting = key.bytes.clone();
+    match state.blocking(move |store| store.delete(deleting)).await {
+        Ok(sequence) => {
+            state.replicated(sequence).await;
+            Ok(Json(WriteResponse {
+                key: key.encoded(),
+                sequence,
+            }))
+        }
+        Err(e) => {
+            warn!("Cannot delete '{key}': {e}");
+            Err(ErrorResponse::from(e).with_key(key.encoded()))
+        }
+    }
+}
+
+/// Whether the process is alive, regardless of whether the store can serve.
+/// Also served at `/health`.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The process is alive", body = String))
+)]
+async fn liveness_handler() -> &'static str {
+    "OK"
+}
+
+/// The build being served, so that what is running can be verified.
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "info",
+    responses((status = 200, description = "The build being served", body = BuildInfo))
+)]
+async fn version_handler() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}
+
+/// Every metric of the store in the Prometheus text format, from the same
+/// source as `/admin/stats`.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "metrics",
+    responses((status = 200, description = "The metrics of the store", body = String, content_type = "text/plain"))
+)]
+async fn metrics_handler(State(state): State<Arc<Chipmunk>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, EXPOSITION_CONTENT_TYPE)],
+        state.metrics(),
+    )
+}
+
+/// Whether the store can serve traffic: it has been restored and writes are
+/// not stopped by a [`WriteStall`]. Answers `503 Service Unavailable`
+/// otherwise, so that load balancers hold traffic back.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "The store can serve", body = String),
+        (status = 503, description = "The store cannot serve yet", body = ErrorResponse),
+    )
+)]
+async fn readiness_handler(
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<&'static str, ErrorResponse> {
+    let unavailable = |message| Err(ErrorResponse::new(ErrorCode::Unavailable, message));
+    if !state.ready.load(Ordering::Acquire) {
+        return unavailable("Restoring");
+    }
+    match state.store.write_stall() {
+        WriteStall::Stop => unavailable("Writes are stopped"),
+        _ => Ok("OK"),
+    }
+}
+
+/// Flush every memtable to SSTables.
+#[utoipa::path(
+    post,
+    path = "/admin/flush",
+    tag = "admin",
+    params(("Authorization" = String, Header, description = "`Bearer` followed by the admin token")),
+    responses(
+        (status = 204, description = "Every memtable was flushed"),
+        (status = 401, description = "Missing or incorrect admin token", body = ErrorResponse),
+        (status = 409, description = "The store only runs in memory", body = ErrorResponse),
+    )
+)]
+async fn flush_handler(
+    _: AdminAuth,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    let store = Arc::clone(&state.store);
+    match tokio::task::spawn_blocking(move || store.flush()).await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT),
+        Ok(Err(e)) => {
+            warn!("Cannot flush: {e}");
+            Err(e.into())
+        }
+        Err(e) => {
+            warn!("Flush did not complete: {e}");
+            Err(ErrorResponse::new(
+                ErrorCode::Internal,
+                "Flush did not complete",
+            ))
+        }
+    }
+}
+
+/// Run a compaction cycle regardless of the configured thresholds,
+/// returning the work it performed.
+#[utoipa::path(
+    post,
+    path = "/admin/compact",
+    tag = "admin",
+    params(("Authorization" = String, Header, description = "`Bearer` followed by the admin token")),
+    responses(
+        (status = 200, description = "The work the cycle performed", body = CompactionStats),
+        (status = 401, description = "Missing or incorrect admin token", body = ErrorResponse),
+        (status = 409, description = "The store only runs in memory", body = ErrorResponse),
+    )
+)]
+async fn compact_handler(
+    _: AdminAuth,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Json<CompactionStats>, ErrorResponse> {
+    let store = Arc::clone(&state.store);
+    match tokio::task::spawn_blocking(move || store.force_compaction()).await {
+        Ok(Ok(stats)) => Ok(Json(stats)),
+        Ok(Err(e)) => {
+            warn!("Cannot compact: {e}");
+            Err(e.into())
+        }
+        Err(e) => {
+            warn!("Compaction did not complete: {e}");
+            Err(ErrorResponse::new(
+                ErrorCode::Internal,
+                "Compaction did not complete",
+            ))
+        }
+    }
+}
+
+/// Close the store as for a clean shutdown, so that its node can be taken out
+/// of service. Keys are then refused with `503 Service Unavailable`, and
+/// `/readyz` reports the store as unavailable, until `/admin/reopen`.
+#[utoipa::path(
+    post,
+    path = "/admin/shutdown",
+    tag = "admin",
+    params(("Authorization" = String, Header, description = "`Bearer` followed by the admin token")),
+    responses(
+        (status = 204, description = "The store was closed"),
+        (status = 401, description = "Missing or incorrect admin token", body = ErrorResponse),
+    )
+)]
+async fn shutdown_handler(
+    _: AdminAuth,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    state.open.store(false, Ordering::Release);
+    state.ready.store(false, Ordering::Release);
+    let store = Arc::clone(&state.store);
+    match tokio::task::spawn_blocking(move || store.close()).await {
+        Ok(Ok(())) => {
+            info!("Shut down through the admin API");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(Err(e)) => {
+            warn!("Cannot shut down: {e}");
+            Err(e.into())
+        }
+        Err(e) => {
+            warn!("Shutdown did not complete: {e}");
+            Err(ErrorResponse::new(
+                ErrorCode::Internal,
+                "Shutdown did not complete",
+            ))
+        }
+    }
+}
+
+/// Restore a store closed by `/admin/shutdown` from its directory and serve
+/// keys again.
+#[utoipa::path(
+    post,
+    path = "/admin/reopen",
+    tag = "admin",
+    params(("Authorization" = String, Header, description = "`Bearer` followed by the admin token")),
+    responses(
+        (status = 204, description = "The store was reopened"),
+        (status = 401, description = "Missing or incorrect admin token", body = ErrorResponse),
+        (status = 409, description = "The store is not shut down", body = ErrorResponse),
+    )
+)]
+async fn reopen_handler(
+    _: AdminAuth,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<StatusCode, ErrorResponse> {
+    if state.open.load(Ordering::Acquire) {
+        return Err(ErrorResponse::new(
+            ErrorCode::Conflict,
+            "The store is not shut down",
+        ));
+    }
+    let store = Arc::clone(&state.store);
+    match tokio::task::spawn_blocking(move || store.restore()).await {
+        Ok(Ok(())) => {
+            state.open.store(true, Ordering::Release);
+            state.ready.store(true, Ordering::Release);
+            info!("Reopened through the admin API");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(Err(e)) => {
+            warn!("Cannot reopen: {e}");
+            Err(e.into())
+        }
+        Err(e) => {
+            warn!("Reopen did not complete: {e}");
+            Err(ErrorResponse::new(
+                ErrorCode::Internal,
+                "Reopen did not complete",
+            ))
+        }
+    }
+}
+
+/// Everything reported by [`config_handler`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EffectiveConfig {
+    pub fixed: FixedSettings,
+    /// Settings in effect which can be changed while the store runs, those
+    /// missing are disabled.
+    pub tunables: Tunables,
+}
+
+/// Settings which are fixed for as long as the store runs, named as the
+/// command line flags which set them.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FixedSettings {
+    #[schema(value_type = String)]
+    pub wal_directory: PathBuf,
+    pub wal_max_size_bytes: u64,
+    pub memtable_max_size_bytes: u64,
+    pub memtable_max_immutable: usize,
+    /// Whether writes are kept in memory only.
+    pub in_memory: bool,
+    pub max_value_size_bytes: Option<usize>,
+    pub sstable_block_size_bytes: usize,
+    pub sstable_max_open_files: usize,
+    pub sstable_bloom_bits_per_key: usize,
+    /// Whether the API is served over TLS.
+    pub tls: bool,
+    /// File the settings are reloaded from, if any.
+    #[schema(value_type = Option<String>)]
+    pub config_file: Option<PathBuf>,
+    /// Base URL of the primary the store follows, if it is a replica.
+    pub replica_of: Option<String>,
+    /// Number of shards the keys are split between.
+    pub shards: usize,
+}
+
+/// Describe the configuration in effect.
+#[utoipa::path(
+    get,
+    path = "/admin/config",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The settings in effect", body = EffectiveConfig),
+    )
+)]
+async fn config_handler(State(state): State<Arc<Chipmunk>>) -> Json<EffectiveConfig> {
+    let mut fixed = state.fixed.clone();
+    fixed.config_file.clone_from(&state.config_file);
+    Json(EffectiveConfig {
+        fixed,
+        tunables: state.tunables(),
+    })
+}
+
+/// Change the settings given in the body, leaving the others as they are,
+/// until the config file is next reloaded.
+///
+/// Each change is recorded in the log.
+#[utoipa::path(
+    put,
+    path = "/admin/config",
+    tag = "admin",
+    params(("Authorization" = String, Header, description = "`Bearer` followed by the admin token")),
+    request_body = Tunables,
+    responses(
+        (status = 200, description = "The settings now in effect", body = Tunables),
+        (status = 401, description = "Missing or incorrect admin token", body = ErrorResponse),
+        (status = 400, description = "The body names an unknown setting or has an invalid value", body = ErrorResponse),
+    )
+)]
+async fn update_config_handler(
+    _: AdminAuth,
+    State(state): State<Arc<Chipmunk>>,
+    request: Result<Json<Tunables>, JsonRejection>,
+) -> Result<Json<Tunables>, ErrorResponse> {
+    let Json(changes) = request?;
+    match state.update(&changes) {
+        Ok(tunables) => Ok(Json(tunables)),
+        Err(e) => {
+            warn!("Cannot change config: {e}");
+            Err(e.into())
+        }
+    }
+}
+
+/// Read the settings which can be changed while the store runs from the
+/// config file it was started with, and apply them, as on `SIGHUP`.
+///
+/// Settings missing from the file return to the values the store was
+/// started with. The settings in place are kept if the file cannot be read.
+#[utoipa::path(
+    post,
+    path = "/admin/config/reload",
+    tag = "admin",
+    params(("Authorization" = String, Header, description = "`Bearer` followed by the admin token")),
+    responses(
+        (status = 200, description = "The settings read from the file, now applied", body = Tunables),
+        (status = 401, description = "Missing or incorrect admin token", body = ErrorResponse),
+        (status = 409, description = "No config file was given, or it is unreadable or invalid", body = ErrorResponse),
+    )
+)]
+async fn reload_config_handler(
+    _: AdminAuth,
+    State(state): State<Arc<Chipmunk>>,
+) -> Result<Json<Tunables>, ErrorResponse> {
+    match state.reload() {
+        Ok(tunables) => Ok(Json(tunables)),
+        Err(e) => {
+            warn!("Cannot reload config: {e}");
+            Err(e.into())
+        }
+    }
+}
+
+/// Everything reported by [`stats_handler`].
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminStats {
+    /// Seconds since the store was created.
+    uptime_seconds: u64,
+    #[serde(flatten)]
+    tree: TreeStats,
+    statistics: Statistics,
+    bloom_false_positive_rate: f64,
+    memtable_hit_ratio: f64,
+    block_cache_hit_ratio: f64,
+    compaction: CompactionStats,
+}
+
+/// Describe the shape of the tree and the work performed by the engine.
+#[utoipa::path(
+    get,
+    path = "/admin/stats",
+    tag = "admin",
+    responses((status = 200, description = "Statistics of the engine", body = AdminStats))
+)]
+async fn stats_handler(State(state): State<Arc<Chipmunk>>) -> impl IntoResponse {
+    let store = &state.store;
+    let statistics = store.statistics();
+    Json(AdminStats {
+        uptime_seconds: state.started_at.elapsed().as_secs(),
+        tree: store.tree_stats(),
+        bloom_false_positive_rate: statistics.bloom_false_positive_rate(),
+        memtable_hit_ratio: statistics.memtable_hit_ratio(),
+        block_cache_hit_ratio: statistics.block_cache_hit_ratio(),
+        statistics,
+        compaction: store.compaction_stats(),
+    })
+}
+
+/// An instance of the [`Chipmunk`] store.
+///
+/// This comprises of the underlying k-v store and server. This utilises the
+/// actor pattern for communication. This is the task portion.
+#[derive(Clone)]
+pub struct Chipmunk {
+    /// Shared by every request, which run concurrently as the tree
+    /// synchronises access itself.
+    pub(crate) store: Arc<ShardedLsm>,
+    /// Set once [`Chipmunk::restore`] has completed, see `/readyz`.
+    pub(crate) ready: Arc<AtomicBool>,
+    /// Cleared while the store is shut down through `/admin/shutdown`.
+    pub(crate) open: Arc<AtomicBool>,
+    /// Certificate to serve the API with, see [`Chipmunk::serve`].
+    tls: Option<TlsConfig>,
+    /// Cross-origin requests allowed by the HTTP API.
+    cors: Option<CorsConfig>,
+    /// Limits on the connections and requests of the HTTP API.
+    http: HttpConfig,
+    /// Size, in bytes, of the largest request body which is accepted, the
+    /// configured maximum value size if there is one.
+    pub(crate) max_request_body: usize,
+    /// When the store was created, from which its uptime is reported.
+    started_at: Instant,
+    /// File the [`Tunables`] are read from by [`Chipmunk::reload`].
+    config_file: Option<PathBuf>,
+    /// Changes the level of the logs written, see [`Tunables::log_level`].
+    log_level: Option<LogLevelHandle>,
+    /// Settings the store was started with, which those missing from the
+    /// config file fall back to.
+    startup: StartupSettings,
+    /// Settings which cannot be changed while the store runs.
+    fixed: FixedSettings,
+    /// Base URL of the primary the store follows, refusing writes of its
+    /// own, see [`Chipmunk::follow`].
+    pub(crate) replica_of: Option<String>,
+    /// ID the store acknowledges the changes it replicates under.
+    replica_id: String,
+    /// Changes which the replicas following the store have applied.
+    acknowledgments: Arc<Acknowledgments>,
+}
+
+/// Handle to the filter deciding the level of the logs written, which
+/// [`Chipmunk::reload`] changes.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// The [`Tunables`] as the store was started with them.
+#[derive(Debug, Clone)]
+struct StartupSettings {
+    log_level: Option<LevelFilter>,
+    wal_buffer_size: Option<usize>,
+    flush_interval: Option<Duration>,
+    block_cache_capacity: usize,
+    compaction: CompactionConfig,
+    write_stall: WriteStallConfig,
+}
+
+/// Record each setting which differs between two sets of [`Tunables`].
+fn log_changes(before: &Tunables, after: &Tunables) {
+    let as_map = |tunables: &Tunables| match serde_json::to_value(tunables) {
+        Ok(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    let (before, after) = (as_map(before), as_map(after));
+    let describe = |value: Option<&serde_json::Value>| match value {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+        None => "unset".to_string(),
+    };
+    let mut settings: Vec<_> = before.keys().chain(after.keys()).collect();
+    settings.sort();
+    settings.dedup();
+    for setting in settings {
+        let (from, to) = (before.get(setting), after.get(setting));
+        if from != to {
+            info!(
+                setting = %setting,
+                from = %describe(from),
+                to = %describe(to),
+                "Changed setting"
+            );
+        }
+    }
+}
+
+impl Chipmunk {
+    /// Create the store, split into the configured number of shards, along
+    /// with a background worker for each which flushes and compacts it so
+    /// that writes do not have to. The workers exit once every handle to the
+    /// store has been dropped.
+    ///
+    /// More than one shard cannot be combined with following a primary or
+    /// with [`AckMode::SemiSync`], which both need every write in one
+    /// order, failing with [`ChipmunkError::Sharded`].
+    pub fn new(config: ChipmunkConfig) -> Result<Self, ChipmunkError> {
+        if config.shards > 1 {
+            if config.replica_of.is_some() {
+                return Err(ChipmunkError::Sharded("following a primary"));
+            }
+            if config.replication.ack_mode == AckMode::SemiSync {
+                return Err(ChipmunkError::Sharded("waiting on replicas"));
+            }
+        }
+        let max_request_body = config
+            .memtable
+            .max_value_size
+            .unwrap_or(DEFAULT_MAX_REQUEST_BODY);
+        let startup = StartupSettings {
+            log_level: None,
+            wal_buffer_size: config.wal.buffer_size,
+            flush_interval: config.memtable.flush_interval,
+            block_cache_capacity: config.sstable.block_cache_capacity,
+            compaction: config.compaction.clone(),
+            write_stall: config.write_stall.clone(),
+        };
+        let replica_of = config.replica_of.as_deref().map(replication::primary_url);
+        let fixed = FixedSettings {
+            wal_directory: config.wal.log_directory.clone(),
+            wal_max_size_bytes: config.wal.max_size,
+            memtable_max_size_bytes: config.memtable.max_size,
+            memtable_max_immutable: config.memtable.max_immutable_memtables,
+            in_memory: config.memtable.in_memory.is_some(),
+            max_value_size_bytes: config.memtable.max_value_size,
+            sstable_block_size_bytes: config.sstable.block_size,
+            sstable_max_open_files: config.sstable.max_open_files,
+            sstable_bloom_bits_per_key: config.sstable.bloom_bits_per_key,
+            tls: config.tls.is_some(),
+            config_file: None,
+            replica_of: replica_of.clone(),
+            shards: config.shards.max(1),
+        };
+        let store = ShardedLsm::new(
+            config.shards,
+            config.wal,
+            config.memtable,
+            config.sstable,
+            config.compaction,
+            config.write_stall,
+        )?;
+        Ok(Self {
+            store: Arc::new(store),
+            ready: Arc::new(AtomicBool::new(false)),
+            open: Arc::new(AtomicBool::new(true)),
+            tls: config.tls,
+            cors: config.cors,
+            http: config.http,
+            max_request_body,
+            started_at: Instant::now(),
+            config_file: None,
+            log_level: None,
+            startup,
+            fixed,
+            replica_of,
+            replica_id: replication::replica_id(),
+            acknowledgments: Arc::new(Acknowledgments::new(config.replication)),
+        })
+    }
+
+    /// Read the [`Tunables`] from the given JSON file on each
+    /// [`Chipmunk::reload`].
+    pub fn with_config_file(mut self, path: PathBuf) -> Self {
+        self.config_file = Some(path);
+        self
+    }
+
+    /// Change the level of the logs written through the given handle on each
+    /// [`Chipmunk::reload`].
+    pub fn with_log_level(mut self, handle: LogLevelHandle) -> Self {
+        self.startup.log_level = handle.clone_current();
+        self.log_level = Some(handle);
+        self
+    }
+
+    /// Read the [`Tunables`] from the config file and apply them, returning
+    /// them, without restarting the store or replaying its WAL.
+    ///
+    /// Settings missing from the file return to the values the store was
+    /// started with. The settings in place are kept if the file cannot be
+    /// read or is invalid.
+    pub fn reload(&self) -> Result<Tunables, ChipmunkError> {
+        let path = self
+            .config_file
+            .as_ref()
+            .ok_or(ChipmunkError::NoConfigFile)?;
+        let tunables = Tunables::load(path)?;
+        let before = self.tunables();
+        let startup = &self.startup;
+        let level = tunables.log_level.map(|level| level.0);
+        if let Some(level) = level.or(startup.log_level) {
+            self.set_log_level(level);
+        }
+        let wal_buffer_size = tunables.wal_buffer_size_bytes.map(ByteSize::as_usize);
+        self.store
+            .set_wal_buffer_size(wal_buffer_size.or(startup.wal_buffer_size))?;
+        let flush_interval = tunables
+            .memtable_flush_interval_ms
+            .map(|interval| interval.0);
+        self.store
+            .set_flush_interval(flush_interval.or(startup.flush_interval));
+        let block_cache_capacity = tunables.sstable_block_cache_bytes.map(ByteSize::as_usize);
+        self.store
+            .set_block_cache_capacity(block_cache_capacity.unwrap_or(startup.block_cache_capacity));
+        self.store
+            .set_compaction_config(tunables.compaction(&startup.compaction));
+        self.store
+            .set_write_stall_config(tunables.write_stall(&startup.write_stall));
+        info!(path = %path.display(), ?tunables, "Reloaded config");
+        log_changes(&before, &self.tunables());
+        Ok(tunables)
+    }
+
+    /// The [`Tunables`] in effect. Those which are disabled, such as
+    /// compaction triggers which never fire, are unset.
+    pub fn tunables(&self) -> Tunables {
+        let compaction = self.store.compaction_config();
+        let write_stall = self.store.write_stall_config();
+        Tunables {
+            log_level: self
+                .log_level
+                .as_ref()
+                .and_then(LogLevelHandle::clone_current)
+                .map(LogLevel),
+            wal_buffer_size_bytes: self
+                .store
+                .wal_buffer_size()
+                .map(|size| ByteSize(size as u64)),
+            memtable_flush_interval_ms: self.store.flush_interval().map(Millis),
+            sstable_block_cache_bytes: Some(ByteSize(self.store.block_cache_capacity() as u64)),
+            compaction_l1_file_trigger: compaction.l1_file_trigger,
+            compaction_l1_size_trigger_bytes: compaction.l1_size_trigger.map(ByteSize),
+            compaction_level_size_ratio: compaction.level_size_ratio,
+            compaction_max_l2_files: compaction.max_l2_files,
+            compaction_max_bytes_per_sec: compaction.max_bytes_per_sec.map(ByteSize),
+            write_stall_l1_slowdown_files: write_stall.l1_slowdown_trigger,
+            write_stall_l1_stop_files: write_stall.l1_stop_trigger,
+            write_stall_immutable_slowdown: write_stall.immutable_slowdown_trigger,
+            write_stall_immutable_stop: write_stall.immutable_stop_trigger,
+            write_stall_slowdown_delay_ms: Some(Millis(write_stall.slowdown_delay)),
+        }
+    }
+
+    /// Apply the settings which are set in `changes`, leaving the others as
+    /// they are, and return the [`Tunables`] now in effect.
+    ///
+    /// Changes last until the next [`Chipmunk::reload`], which applies the
+    /// config file over the settings the store was started with.
+    pub fn update(&self, changes: &Tunables) -> Result<Tunables, ChipmunkError> {
+        let before = self.tunables();
+        if let Some(level) = changes.log_level {
+            self.set_log_level(level.0);
+        }
+        if let Some(size) = changes.wal_buffer_size_bytes {
+            self.store.set_wal_buffer_size(Some(size.as_usize()))?;
+        }
+        if let Some(interval) = changes.memtable_flush_interval_ms {
+            self.store.set_flush_interval(Some(interval.0));
+        }
+        if let Some(capacity) = changes.sstable_block_cache_bytes {
+            self.store.set_block_cache_capacity(capacity.as_usize());
+        }
+        self.store
+            .set_compaction_config(changes.compaction(&self.store.compaction_config()));
+        self.store
+            .set_write_stall_config(changes.write_stall(&self.store.write_stall_config()));
+        let after = self.tunables();
+        log_changes(&before, &after);
+        Ok(after)
+    }
+
+    /// Work performed by the engine, including the latency of each kind of
+    /// operation, as served by `/admin/stats`.
+    pub fn statistics(&self) -> Statistics {
+        self.store.statistics()
+    }
+
+    /// Every metric of the store in the Prometheus text format, see
+    /// [`crate::metrics`].
+    pub fn metrics(&self) -> String {
+        let mut exposition = Exposition::default();
+        exposition.gauge(
+            "uptime_seconds",
+            "Time since the store was created",
+            self.started_at.elapsed().as_secs_f64(),
+        );
+        exposition.gauge(
+            "ready",
+            "Whether the store is ready to serve traffic",
+            u8::from(self.ready.load(Ordering::Acquire)),
+        );
+        self.store.expose_metrics(&mut exposition);
+        self.acknowledgments.expose(&mut exposition);
+        exposition.finish()
+    }
+
+    /// Change the level of the logs written, if the store was given a
+    /// [`LogLevelHandle`].
+    fn set_log_level(&self, level: LevelFilter) {
+        if let Some(handle) = &self.log_level {
+            if let Err(e) = handle.reload(level) {
+                warn!("Cannot change the log level: {e}");
+            }
+        }
+    }
+
+    /// Serve the HTTP API, alongside the gRPC API of [`crate::grpc`], on the
+    /// listener until `shutdown` resolves, draining in-flight requests before
+    /// returning. TLS is terminated if the store
+    /// was configured with a [`TlsConfig`].
+    pub async fn serve<F>(&self, listener: TcpListener, shutdown: F) -> Result<(), ChipmunkError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let app = new_app(self.clone());
+        match &self.tls {
+            Some(tls) => serve_tls(listener, app, tls.clone(), self.http.clone(), shutdown).await,
+            None => {
+                let handshake = |stream, _| async move { Some(stream) };
+                serve_connections(listener, app, &self.http, handshake, shutdown).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Serve the Redis protocol on the listener until `shutdown` resolves, see
+    /// [`crate::resp`].
+    pub async fn serve_resp<F>(
+        &self,
+        listener: TcpListener,
+        shutdown: F,
+    ) -> Result<(), ChipmunkError>
+    where
+        F: Future<Output = ()> + Send,
+    {
+        resp::serve_resp(listener, self.clone(), shutdown).await
+    }
+
+    /// Apply the changes shipped by the primary at the given base URL, such as
+    /// `http://primary:5000`, after the most recent write this store holds,
+    /// until the primary ends the stream. Returns the number of changes
+    /// applied, see [`crate::replication`].
+    ///
+    /// Writes made to this store directly are not sent to the primary, and
+    /// leave the stores apart, so a replica should only be written to through
+    /// replication.
+    pub async fn replicate_from(&self, primary: &str) -> Result<u64, ChipmunkError> {
+        let client = reqwest::Client::new();
+        replication::replicate(&self.store, &client, primary, &self.replica_id).await
+    }
+
+    /// Run a call into the store on the blocking pool, as reads may wait on
+    /// disk and writes on the WAL or a write stall, leaving the runtime's
+    /// threads free to serve other requests. The call runs within the span of
+    /// the caller, and a panic within it is resumed by the caller.
+    pub(crate) async fn blocking<T: Send + 'static>(
+        &self,
+        call: impl FnOnce(&ShardedLsm) -> T + Send + 'static,
+    ) -> T {
+        let store = Arc::clone(&self.store);
+        let span = Span::current();
+        match tokio::task::spawn_blocking(move || span.in_scope(|| call(&store))).await {
+            Ok(value) => value,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Wait until enough replicas have applied the write with the sequence
+    /// number, or the timeout passes, when writes are acknowledged in
+    /// [`AckMode::SemiSync`].
+    pub(crate) async fn replicated(&self, sequence: u64) {
+        self.acknowledgments.wait(sequence).await;
+    }
+
+    /// Base URL of the primary the store was configured to follow, see
+    /// [`ChipmunkConfig::replica_of`].
+    pub fn replica_of(&self) -> Option<&str> {
+        self.replica_of.as_deref()
+    }
+
+    /// Fail with [`ChipmunkError::ReadOnlyReplica`] when the store is a
+    /// replica, which must only be written to through replication.
+    pub(crate) fn require_primary(&self) -> Result<(), ChipmunkError> {
+        match &self.replica_of {
+            Some(primary) => Err(ChipmunkError::ReadOnlyReplica {
+                primary: primary.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Replicate the primary at the given base URL for as long as the store
+    /// runs, reconnecting after [`REPLICATION_RETRY`] whenever the stream of
+    /// changes ends or fails, see [`Chipmunk::replicate_from`].
+    pub async fn follow(&self, primary: &str) {
+        let client = reqwest::Client::new();
+        loop {
+            match replication::replicate(&self.store, &client, primary, &self.replica_id).await {
+                Ok(applied) => debug!(applied, "Replication stream ended"),
+                Err(e @ ChipmunkError::ChangesNotRetained { .. }) => warn!(
+                    primary,
+                    "Cannot replicate: {e}, start from an empty directory to bootstrap from a checkpoint"
+                ),
+                Err(e) => warn!(primary, "Cannot replicate: {e}"),
+            }
+            tokio::time::sleep(REPLICATION_RETRY).await;
+        }
+    }
+
+    /// Set the [`ValueTransform`] applied to values stored by this instance.
+    ///
+    /// This should be called before the store begins to accept writes.
+    pub fn set_value_transform(&self, transform: Arc<dyn ValueTransform>) {
+        self.store.set_value_transform(transform);
+    }
+
+    /// Set the [`Comparator`] which orders the keys of this instance.
+    ///
+    /// This must be called before the store is restored or accepts writes.
+    pub fn set_comparator(&self, comparator: Arc<dyn Comparator>) {
+        self.store.set_comparator(comparator);
+    }
+
+    /// Set the [`CompactionFilter`] which entries are passed through as they
+    /// are compacted.
+    pub fn set_compaction_filter(&self, filter: Arc<dyn CompactionFilter>) {
+        self.store.set_compaction_filter(filter);
+    }
+
+    /// Attempt to perform a restore of the store.
+    ///
+    /// A restore will performed when previous WAL files were found within the
+    /// current working directory for chipmunk. The store reports itself as
+    /// ready through `/readyz` once this has completed. Writes made in the
+    /// meantime wait for the restore, though reads may not yet see everything
+    /// it restores. Checkpoints left part way through being sent to replicas
+    /// are removed.
+    pub async fn restore(&self) -> Result<(), ChipmunkError> {
+        self.store.restore()?;
+        replication::remove_checkpoints(self.store.working_directory())
+            .map_err(ChipmunkError::WalRestoreDirectory)?;
+        self.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Cleanly shut down the store, flushing everything it holds so that the
+    /// next [`Chipmunk::restore`] has no WAL to replay.
+    pub async fn close(&self) -> Result<(), ChipmunkError> {
+        self.store.close()
+    }
+}
+
+/// Serve the app on each connection accepted from the listener, once
+/// `handshake` has turned it into a stream, until `shutdown` resolves. The
+/// open connections are then closed gracefully, finishing their in-flight
+/// requests, before returning.
+///
+/// Connections beyond [`HttpConfig::max_connections`] are left in the
+/// listener's backlog until others close.
+pub(crate) async fn serve_connections<F, H, Fut, I>(
+    listener: TcpListener,
+    app: Router,
+    config: &HttpConfig,
+    handshake: H,
+    shutdown: F,
+) where
+    F: Future<Output = ()> + Send,
+    H: Fn(TcpStream, SocketAddr) -> Fut,
+    Fut: Future<Output = Option<I>> + Send + 'static,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let limit = config
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    let (stop, stopping) = watch::channel(());
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        while connections.try_join_next().is_some() {}
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = Arc::clone(limit).acquire_owned() => {
+                    Some(permit.expect("Connection limit is never closed"))
+                }
+                _ = &mut shutdown => break,
+            },
+            None => None,
+        };
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Cannot accept connection: {e}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let handshake = handshake(stream, peer);
+        let service = TowerToHyperService::new(app.clone());
+        let keep_alive = config.keep_alive;
+        let keep_alive_interval = config.keep_alive_interval;
+        let mut stopping = stopping.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            let Some(stream) = handshake.await else {
+                return;
+            };
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder.http1().keep_alive(keep_alive);
+            builder
+                .http2()
+                .timer(TokioTimer::new())
+                .keep_alive_interval(keep_alive_interval);
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            let served = tokio::select! {
+                served = connection.as_mut() => served,
+                _ = stopping.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = served {
+                debug!(%peer, "Connection closed with error: {e}");
+            }
+        });
+    }
+
+    drop(listener);
+    let _ = stop.send(());
+    while connections.join_next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::config::{
+        ChipmunkConfig, CompactionConfig, MemtableConfig, ReplicationConfig, SstableConfig,
+        WalConfig, WriteStallConfig,
+    };
+
+    fn get_base_uri(addr: SocketAddr) -> String {
+        format!("http://{addr}/api/v1")
+    }
+
+    async fn setup_server(conf: ChipmunkConfig) -> SocketAddr {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let store = Chipmunk::new(conf).unwrap();
+            let app = new_app(store);
+            axum::serve(socket, app).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn chipmunk_binary_values() {
+        let dir = TempDir::new("binary_values").unwrap();
+        let conf = ChipmunkConfig::builder()
+            .wal_dir(dir.path())
+            .wal_max(1024)
+            .memtable_max(1024)
+            .build();
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let value = vec![0, 159, 146, 150, b'=', 255];
+        let response = client
+            .put(format!("{base}/binary"))
+            .body(value.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = client.get(format!("{base}/binary")).send().await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        assert_eq!(response.bytes().await.unwrap(), value);
+    }
+
+    #[tokio::test]
+    async fn chipmunk_replication() {
+        let dir = TempDir::new("replication").unwrap();
+        let conf = ChipmunkConfig::builder()
+            .wal_dir(dir.path())
+            .wal_max(256)
+            .http(HttpConfig::default().with_admin_token("secret"))
+            .build();
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+        let primary = format!("http://{addr}");
+        // Each write fills a WAL segment.
+        let value = "value".repeat(64);
+        for key in ["before", "removed"] {
+            client
+                .put(format!("{base}/{key}"))
+                .body(value.clone())
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let replica_dir = TempDir::new("replication_replica").unwrap();
+        let replica = Chipmunk::new(
+            ChipmunkConfig::builder()
+                .wal_dir(replica_dir.path())
+                .build(),
+        )
+        .unwrap();
+        let following = replica.clone();
+        let followed = primary.clone();
+        tokio::spawn(async move { following.follow(&followed).await });
+        client
+            .put(format!("{base}/after"))
+            .body(value.clone())
+            .send()
+            .await
+            .unwrap();
+        client
+            .delete(format!("{base}/removed"))
+            .send()
+            .await
+            .unwrap();
+
+        for _ in 0..200 {
+            if replica.store.sequence() == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(replica.store.sequence(), 4);
+        for key in ["before", "after"] {
+            assert_eq!(
+                replica.store.get(key.into()).unwrap(),
+                Some(value.clone().into())
+            );
+        }
+        assert_eq!(replica.store.get(b"removed".to_vec()).unwrap(), None);
+
+        // Once flushed, the earliest writes can no longer be shipped.
+        let response = client
+            .post(format!("{primary}/admin/flush"))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = client
+            .get(format!("{primary}/replication/wal?after=0"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+        let fresh_dir = TempDir::new("replication_fresh").unwrap();
+        let fresh =
+            Chipmunk::new(ChipmunkConfig::builder().wal_dir(fresh_dir.path()).build()).unwrap();
+        assert!(matches!(
+            fresh.replicate_from(&primary).await,
+            Err(ChipmunkError::ChangesNotRetained { after: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn chipmunk_replica_bootstrap() {
+        let dir = TempDir::new("bootstrap_primary").unwrap();
+        let conf = ChipmunkConfig::builder()
+            .wal_dir(dir.path())
+            .http(HttpConfig::default().with_admin_token("secret"))
+            .build();
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+        let primary = format!("http://{addr}");
+        for key in ["a", "b", "c"] {
+            client
+                .put(format!("{base}/{key}"))
+                .body(key)
+                .send()
+                .await
+                .unwrap();
+        }
+        client.delete(format!("{base}/b")).send().await.unwrap();
+        // Once flushed, the WAL no longer holds the writes.
+        let response = client
+            .post(format!("{primary}/admin/flush"))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let replica_dir = TempDir::new("bootstrap_replica").unwrap();
+        let directory = replica_dir.path().join("data");
+        let bootstrapped = replication::bootstrap(&directory, &addr.to_string())
+            .await
+            .unwrap();
+        assert_eq!(bootstrapped, Some(4));
+        assert_eq!(
+            replication::bootstrap(&directory, &addr.to_string())
+                .await
+                .unwrap(),
+            None,
+            "Only an empty directory is bootstrapped"
+        );
+        let replica = Chipmunk::new(ChipmunkConfig::builder().wal_dir(&directory).build()).unwrap();
+        replica.restore().await.unwrap();
+        assert_eq!(replica.store.sequence(), 4);
+        assert_eq!(
+            replica.store.get(b"a".to_vec()).unwrap(),
+            Some(b"a".to_vec())
+        );
+        assert_eq!(replica.store.get(b"b".to_vec()).unwrap(), None);
+
+        // Replication carries on from the checkpoint.
+        let following = replica.clone();
+        tokio::spawn(async move { following.follow(&primary).await });
+        client
+            .put(format!("{base}/d"))
+            .body("d")
+            .send()
+            .await
+            .unwrap();
+        for _ in 0..200 {
+            if replica.store.sequence() == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            replica.store.get(b"d".to_vec()).unwrap(),
+            Some(b"d".to_vec())
+        );
+
+        let checkpoints = dir.path().join("replication-checkpoints");
+        for _ in 0..200 {
+            if std::fs::read_dir(&checkpoints).unwrap().next().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(
+            std::fs::read_dir(&checkpoints).unwrap().next().is_none(),
+            "Checkpoints are removed once sent"
+        );
+    }
+
+    #[tokio::test]
+    async fn chipmunk_replica_of() {
+        let dir = TempDir::new("replica_of_primary").unwrap();
+        let conf = ChipmunkConfig::builder()
+            .wal_dir(dir.path())
+            .replication(ReplicationConfig::semi_sync(1, Duration::from_secs(10)))
+            .build();
+        let primary = setup_server(conf).await;
+        let replica_dir = TempDir::new("replica_of").unwrap();
+        let replica = Chipmunk::new(
+            ChipmunkConfig::builder()
+                .wal_dir(replica_dir.path())
+                .replica_of(primary.to_string())
+                .build(),
+        )
+        .unwrap();
+        let followed = format!("http://{primary}");
+        assert_eq!(replica.replica_of(), Some(followed.as_str()));
+        let following = replica.clone();
+        tokio::spawn(async move { following.follow(&followed).await });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, new_app(replica)).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        client
+            .put(format!("{}/key", get_base_uri(primary)))
+            .body("value")
+            .send()
+            .await
+            .unwrap();
+        // The write is only answered once the replica has applied it.
+        let base = get_base_uri(addr);
+        let response = client.get(format!("{base}/key")).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), "value");
+        let response = client
+            .get(format!("http://{primary}/metrics"))
+            .send()
+            .await
+            .unwrap();
+        let text = response.text().await.unwrap();
+        for sample in [
+            "chipmunk_replication_replicas 1\n",
+            "chipmunk_replication_semi_sync 1\n",
+            "chipmunk_replication_ack_timeouts_total 0\n",
+        ] {
+            assert!(text.contains(sample), "{sample} in {text}");
+        }
+
+        for write in [
+            client.put(format!("{base}/key")).body("other"),
+            client.delete(format!("{base}/key")),
+        ] {
+            let response = write.send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let error: ErrorResponse =
+                serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(error.code, ErrorCode::ReadOnly);
+            assert!(error.message.contains(&primary.to_string()));
+        }
+        let response = client.get(format!("{base}/key")).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), "value");
+    }
+
+    #[tokio::test]
+    async fn sharded_replication() {
+        let dir = TempDir::new("sharded_replication").unwrap();
+        let sharded = || ChipmunkConfig::builder().wal_dir(dir.path()).shards(2);
+        for config in [
+            sharded().replica_of("primary:5000").build(),
+            sharded()
+                .replication(ReplicationConfig::semi_sync(1, Duration::from_secs(1)))
+                .build(),
+        ] {
+            assert!(matches!(
+                Chipmunk::new(config).err(),
+                Some(ChipmunkError::Sharded(_))
+            ));
+        }
+        assert!(Chipmunk::new(sharded().build()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn chipmunk_changes() {
+        let dir = TempDir::new("changes").unwrap();
+        let conf = ChipmunkConfig::builder()
+            .wal_dir(dir.path())
+            .wal_max(256)
+            .http(HttpConfig::default().with_admin_token("secret"))
+            .build();
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+        for key in ["user%2Fa", "user%2Fb", "order%2F1"] {
+            client
+                .put(format!("{base}/{key}"))
+                .body("value".repeat(64))
+                .send()
+                .await
+                .unwrap();
+        }
+        client
+            .delete(format!("{base}/user%2Fa"))
+            .send()
+            .await
+            .unwrap();
+
+        let read = |query: &str| {
+            let request = client.get(format!("{base}/ops/changes?{query}")).send();
+            async move {
+                let response = request.await.unwrap();
+                let status = response.status();
+                let body = response.bytes().await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<ChangesResponse>(&body).ok(),
+                )
+            }
+        };
+        let (_, page) = read("prefix=user%2F&limit=2").await;
+        let page = page.unwrap();
+        assert_eq!(page.latest, 4);
+        assert_eq!(page.next, 2);
+        assert_eq!(
+            page.changes,
+            vec![
+                WatchEvent::Put {
+                    key: "user/a".to_string(),
+                    value: "value".repeat(64),
+                    sequence: 1,
+                    expires_at: None,
+                },
+                WatchEvent::Put {
+                    key: "user/b".to_string(),
+                    value: "value".repeat(64),
+                    sequence: 2,
+                    expires_at: None,
+                },
+            ]
+        );
+        let (_, page) = read("prefix=user%2F&since=2").await;
+        let page = page.unwrap();
+        assert_eq!(page.next, 4, "Changes to other keys are passed over");
+        assert_eq!(
+            page.changes,
+            vec![WatchEvent::Delete {
+                key: "user/a".to_string(),
+                sequence: 4,
+            }]
+        );
+        let (_, page) = read("since=4").await;
+        let page = page.unwrap();
+        assert!(page.changes.is_empty());
+        assert_eq!(page.next, 4);
+
+        let response = client
+            .post(format!("http://{addr}/admin/flush"))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let (status, _) = read("since=0").await;
+        assert_eq!(status, StatusCode::GONE, "Flushed changes are not retained");
+    }
+
+    #[tokio::test]
+    async fn chipmunk_metrics() {
+        let dir = TempDir::new("metrics").unwrap();
+        let conf = ChipmunkConfig::builder().wal_dir(dir.path()).build();
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+        client
+            .put(format!("{base}/key"))
+            .body("value")
+            .send()
+            .await
+            .unwrap();
+        client.get(format!("{base}/key")).send().await.unwrap();
+
+        let response = client
+            .get(format!("http://{addr}/metrics"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            EXPOSITION_CONTENT_TYPE
+        );
+        let text = response.text().await.unwrap();
+        for sample in [
+            "chipmunk_keys_written_total 1\n",
+            "chipmunk_keys_read_total 1\n",
+            "chipmunk_wal_appends_total 1\n",
+            "chipmunk_memtable_hits_total 1\n",
+            "# TYPE chipmunk_memtable_bytes gauge\n",
+            "chipmunk_operation_duration_seconds_count{operation=\"put\"} 1\n",
+            "chipmunk_operation_duration_seconds_count{operation=\"get\"} 1\n",
+        ] {
+            assert!(text.contains(sample), "{sample} in {text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chipmunk_value_size_limit() {
+        let dir = TempDir::new("value_size_limit").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024).with_max_value_size(16),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let put = |value: Vec<u8>| client.put(format!("{base}/key")).body(value).send();
+        let response = put(vec![b'a'; 16]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = put(vec![b'b'; 17]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = client.get(format!("{base}/key")).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), vec![b'a'; 16]);
+    }
+
+    #[tokio::test]
+    async fn chipmunk_compression() {
+        let dir = TempDir::new("compression").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024 * 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024 * 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let large = "chipmunk".repeat(1024);
+        for (key, value) in [("small", "value"), ("large", large.as_str())] {
+            client
+                .put(format!("{base}/{key}"))
+                .body(value.to_string())
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let get = |key: &str, encoding: &'static str| {
+            client
+                .get(format!("{base}/{key}"))
+                .header(header::ACCEPT_ENCODING, encoding)
+                .send()
+        };
+        let response = get("large", "zstd").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "zstd");
+        let compressed = response.bytes().await.unwrap();
+        assert!(compressed.len() < large.len());
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), large.as_bytes());
+
+        let response = get("large", "gzip").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let response = get("small", "gzip, zstd").await.unwrap();
+        assert!(
+            !response.headers().contains_key(header::CONTENT_ENCODING),
+            "Small bodies are sent as is"
+        );
+        assert_eq!(response.text().await.unwrap(), "value");
+    }
+
+    #[tokio::test]
+    async fn chipmunk_escaped_keys() {
+        let dir = TempDir::new("escaped_keys").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let key: &[u8] = b"users/1 name\xff";
+        let escaped = KeyEncoding::Raw.encode(key);
+        let response = client
+            .put(format!("{base}/{escaped}"))
+            .body("raw")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let encoded = KeyEncoding::Base64.encode(key);
+        let by_query = client
+            .get(format!("{base}/{encoded}?key_encoding=base64"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(by_query.text().await.unwrap(), "raw");
+
+        let response = client
+            .delete(format!("{base}/{encoded}"))
+            .header(KEY_ENCODING_HEADER, "base64")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = client
+            .get(format!("{base}/{escaped}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = client
+            .get(format!("{base}/not*base64?key_encoding=base64"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn chipmunk_endpoint_named_keys() {
+        let dir = TempDir::new("endpoint_named_keys").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        // Keys sharing a name with an endpoint are still keys
+        for key in [
+            "keys", "watch", "changes", "export", "import", "sessions", "ops",
+        ] {
+            let response = client
+                .put(format!("{base}/{key}"))
+                .body(key)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{key}");
+            let response = client.get(format!("{base}/{key}")).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{key}");
+            assert_eq!(response.text().await.unwrap(), key);
+            let response = client.delete(format!("{base}/{key}")).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{key}");
+            let response = client.get(format!("{base}/{key}")).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chipmunk_error_responses() {
+        let dir = TempDir::new("error_responses").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024).with_max_value_size(4),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let error = |request: reqwest::RequestBuilder| async move {
+            let response = request.send().await.unwrap();
+            let status = response.status();
+            let body: ErrorResponse =
+                serde_json::from_str(&response.text().await.unwrap()).unwrap();
+            assert_eq!(status, body.code.status());
+            body
+        };
+
+        let body = error(client.get(format!("{base}/missing"))).await;
+        assert_eq!(body.code, ErrorCode::NotFound);
+        assert_eq!(body.key.as_deref(), Some("missing"));
+
+        let body = error(client.get(format!("{base}/not*base64?key_encoding=base64"))).await;
+        assert_eq!(body.code, ErrorCode::InvalidKey);
+        assert_eq!(body.key.as_deref(), Some("not*base64"));
+
+        let body = error(client.put(format!("{base}/large")).body("value")).await;
+        assert_eq!(body.code, ErrorCode::PayloadTooLarge);
+        assert_eq!(body.key.as_deref(), Some("large"));
+
+        let body = error(client.get(format!("{base}/ops/keys?limit=many"))).await;
+        assert_eq!(body.code, ErrorCode::InvalidRequest);
+        assert_eq!(body.key, None);
+
+        let body = error(client.get(format!("http://{addr}/missing/route"))).await;
+        assert_eq!(body.code, ErrorCode::NotFound);
+
+        assert_eq!(
+            serde_json::to_string(&ErrorResponse::from(ChipmunkError::WriteStall)).unwrap(),
+            r#"{"code":"write_stall","message":"writes are stopped until flushes and compaction catch up"}"#
+        );
+        let response = ErrorResponse::from(ChipmunkError::WriteStall).into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn chipmunk_v2() {
+        let dir = TempDir::new("v2").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let v1 = get_base_uri(addr);
+        let v2 = format!("http://{addr}/api/v2");
+
+        let write = |key: &str, request: WriteRequest| {
+            let request = client
+                .put(format!("{v2}/{key}"))
+                .body(serde_json::to_string(&request).unwrap())
+                .header(header::CONTENT_TYPE, "application/json")
+                .send();
+            async move {
+                let response = request.await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                serde_json::from_str::<WriteResponse>(&response.text().await.unwrap()).unwrap()
+            }
+        };
+        let read = |key: &str| {
+            let request = client.get(format!("{v2}/{key}")).send();
+            async move {
+                let response = request.await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                serde_json::from_str::<ValueResponse>(&response.text().await.unwrap()).unwrap()
+            }
+        };
+
+        let value = vec![0, 159, 146, 150];
+        let written = write(
+            "binary",
+            WriteRequest {
+                value: VALUE_BASE64.encode(&value),
+                ttl: Some(3600),
+            },
+        )
+        .await;
+        assert_eq!(written.key, "binary");
+        let read_back = read("binary").await;
+        assert_eq!(read_back.value, VALUE_BASE64.encode(&value));
+        assert_eq!(read_back.ttl, Some(3600));
+        assert_eq!(read_back.sequence, Some(written.sequence));
+        assert!(read_back.created_at.is_some());
+
+        // Both surfaces address the same keys, with v1 staying byte oriented.
+        let response = client.get(format!("{v1}/binary")).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), value);
+        client
+            .put(format!("{v1}/plain"))
+            .body("value")
+            .send()
+            .await
+            .unwrap();
+        let read_back = read("plain").await;
+        assert_eq!(read_back.value, VALUE_BASE64.encode("value"));
+        assert_eq!(read_back.ttl, None);
+        assert_eq!(read_back.sequence, Some(written.sequence + 1));
+
+        let response = client.delete(format!("{v2}/plain")).send().await.unwrap();
+        let deleted: WriteResponse = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(deleted.sequence, written.sequence + 2);
+        let response = client.get(format!("{v2}/plain")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = client
+            .put(format!("{v2}/invalid"))
+            .body(r#"{"value":"not base64!"}"#)
+            .header(header::CONTENT_TYPE, "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = client
+            .put(format!("{v2}/invalid"))
+            .body("value")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn chipmunk_list_keys() {
+        let dir = TempDir::new("list_keys").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        for (key, value) in [
+            ("user1", "a"),
+            ("user2", "b"),
+            ("user3", "c"),
+            ("other", "d"),
+        ] {
+            client
+                .put(format!("{base}/{key}"))
+                .body(value)
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let list = |query: &str| {
+            let request = client.get(format!("{base}/ops/keys?{query}")).send();
+            async move { request.await.unwrap().text().await.unwrap() }
+        };
+        assert_eq!(
+            list("prefix=user&limit=2").await,
+            r#"[{"key":"user1"},{"key":"user2"}]"#
+        );
+        assert_eq!(
+            list("prefix=user3&values=true").await,
+            r#"[{"key":"user3","value":"c"}]"#
+        );
+        assert_eq!(
+            list(&format!(
+                "prefix={}&key_encoding=base64",
+                KeyEncoding::Base64.encode(b"oth")
+            ))
+            .await,
+            format!(r#"[{{"key":"{}"}}]"#, KeyEncoding::Base64.encode(b"other"))
+        );
+        assert_eq!(list("prefix=missing").await, "[]");
+        assert_eq!(list("start=user2&end=user3").await, r#"[{"key":"user2"}]"#);
+        assert_eq!(
+            list("prefix=user&start=a&end=user2&end_inclusive=true").await,
+            r#"[{"key":"user1"},{"key":"user2"}]"#
+        );
+
+        let page = |query: String| {
+            let request = client.get(format!("{base}/ops/keys?{query}")).send();
+            async move {
+                let response = request.await.unwrap();
+                let cursor = response
+                    .headers()
+                    .get(CONTINUATION_HEADER)
+                    .map(|cursor| cursor.to_str().unwrap().to_string());
+                (response.text().await.unwrap(), cursor)
+            }
+        };
+        let (first, cursor) = page("prefix=user&limit=2".to_string()).await;
+        assert_eq!(first, r#"[{"key":"user1"},{"key":"user2"}]"#);
+        let (second, cursor) =
+            page(format!("prefix=user&limit=2&cursor={}", cursor.unwrap())).await;
+        assert_eq!(second, r#"[{"key":"user3"}]"#);
+        assert_eq!(cursor, None, "The last page has no continuation");
+        let (_, cursor) = page("prefix=user&limit=3".to_string()).await;
+        assert_eq!(cursor, None, "Nothing remains past an exact limit");
+        let response = client
+            .get(format!("{base}/ops/keys?cursor=%21"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn chipmunk_import_export() {
+        let dir = TempDir::new("import_export").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024).with_max_value_size(16),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let line = |key: &str, value: &str| {
+            format!(
+                r#"{{"key":"{}","value":"{}"}}"#,
+                VALUE_BASE64.encode(key),
+                VALUE_BASE64.encode(value)
+            )
+        };
+        let import = |body: String| {
+            let request = client.post(format!("{base}/ops/import")).body(body).send();
+            async move { request.await.unwrap() }
+        };
+        let body = (0..IMPORT_BATCH + 1)
+            .map(|i| line(&format!("user{i:04}"), &i.to_string()))
+            .chain([String::new(), line("other", "value")])
+            .collect::<Vec<_>>()
+            .join("\n");
+        let response = import(body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let imported: ImportResponse =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(imported.imported, IMPORT_BATCH as u64 + 2);
+        assert_eq!(imported.sequence, Some(IMPORT_BATCH as u64 + 2));
+        let value = client.get(format!("{base}/user0042")).send().await.unwrap();
+        assert_eq!(value.text().await.unwrap(), "42");
+
+        let response = import(format!("{}\nnot json\n", line("a", "1"))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorResponse =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert!(error.message.starts_with("Line 2:"), "{}", error.message);
+        let response = import(line("a", &"x".repeat(17))).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let export = client
+            .get(format!("{base}/ops/export?prefix=user000"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(export.headers()[header::CONTENT_TYPE], NDJSON_CONTENT_TYPE);
+        let exported = export.text().await.unwrap();
+        let expected: String = (0..10)
+            .map(|i| line(&format!("user000{i}"), &i.to_string()) + "\n")
+            .collect();
+        assert_eq!(exported, expected);
+        let resumed = client
+            .get(format!("{base}/ops/export?prefix=user000&after=user0007"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            resumed.text().await.unwrap(),
+            expected
+                .lines()
+                .skip(8)
+                .map(|line| format!("{line}\n"))
+                .collect::<String>(),
+            "Exports resume after the given key"
+        );
+        let before_prefix = client
+            .get(format!("{base}/ops/export?prefix=user000&after=a"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(before_prefix.text().await.unwrap(), expected);
+        let everything = client
+            .get(format!("{base}/ops/export"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            everything.text().await.unwrap().lines().count(),
+            IMPORT_BATCH + 2
+        );
+    }
+
+    #[tokio::test]
+    async fn chipmunk_config_reload() {
+        let dir = TempDir::new("config_reload").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default().with_l1_file_trigger(4),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default().with_admin_token("secret"),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let config_file = dir.path().join("chipmunk.json");
+        let store = Chipmunk::new(conf)
+            .unwrap()
+            .with_config_file(config_file.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = new_app(store.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let reload = || async {
+            client
+                .post(format!("http://{addr}/admin/config/reload"))
+                .bearer_auth("secret")
+                .send()
+                .await
+                .unwrap()
+        };
+
+        let unauthorized = client
+            .post(format!("http://{addr}/admin/config/reload"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        let missing = reload().await;
+        assert_eq!(missing.status(), StatusCode::CONFLICT);
+
+        std::fs::write(
+            &config_file,
+            r#"{"compaction_l1_file_trigger": 8, "write_stall_slowdown_delay_ms": "0.02s"}"#,
+        )
+        .unwrap();
+        let reloaded = reload().await;
+        assert_eq!(reloaded.status(), StatusCode::OK);
+        let tunables: Tunables = serde_json::from_slice(&reloaded.bytes().await.unwrap()).unwrap();
+        assert_eq!(tunables.compaction_l1_file_trigger, Some(8));
+        assert_eq!(store.store.compaction_config().l1_file_trigger, Some(8));
+        assert_eq!(
+            store.store.write_stall_config().slowdown_delay,
+            Duration::from_millis(20)
+        );
+
+        for invalid in [r#"{"unknown": 1}"#, r#"{"log_level": "loud"}"#, "{"] {
+            std::fs::write(&config_file, invalid).unwrap();
+            let response = reload().await;
+            assert_eq!(response.status(), StatusCode::CONFLICT, "{invalid}");
+            assert_eq!(
+                store.store.compaction_config().l1_file_trigger,
+                Some(8),
+                "Settings are kept when the file is invalid"
+            );
+        }
+
+        std::fs::write(&config_file, r#"{"log_level": "debug"}"#).unwrap();
+        assert_eq!(reload().await.status(), StatusCode::OK);
+        assert_eq!(
+            store.store.compaction_config().l1_file_trigger,
+            Some(4),
+            "Settings missing from the file return to those started with"
+        );
+        assert_eq!(
+            store.store.write_stall_config().slowdown_delay,
+            WriteStallConfig::default().slowdown_delay
+        );
+    }
+
+    #[tokio::test]
+    async fn chipmunk_config_update() {
+        let dir = TempDir::new("config_update").unwrap();
+        let conf = ChipmunkConfig::builder()
+            .wal_dir(dir.path())
+            .compaction(CompactionConfig::default().with_l1_file_trigger(4))
+            .http(HttpConfig::default().with_admin_token("secret"))
+            .build();
+        let store = Chipmunk::new(conf).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = new_app(store.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/admin/config");
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let config: EffectiveConfig =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(config.fixed.wal_directory, dir.path());
+        assert_eq!(config.tunables.compaction_l1_file_trigger, Some(4));
+        assert_eq!(config.tunables.compaction_max_bytes_per_sec, None);
+
+        let update = |body: &'static str| {
+            client
+                .put(&url)
+                .bearer_auth("secret")
+                .body(body)
+                .header(header::CONTENT_TYPE, "application/json")
+                .send()
+        };
+        let unauthorized = client.put(&url).body("{}").send().await.unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        let unknown = update(r#"{"wal_max_size_bytes": 1}"#).await.unwrap();
+        assert_eq!(unknown.status(), StatusCode::BAD_REQUEST);
+
+        let response = update(
+            r#"{"compaction_max_bytes_per_sec": "10MiB", "sstable_block_cache_bytes": "1MiB", "memtable_flush_interval_ms": "5s"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let tunables: Tunables = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(
+            tunables.compaction_l1_file_trigger,
+            Some(4),
+            "Other settings are kept"
+        );
+        assert_eq!(
+            store.store.compaction_config().max_bytes_per_sec,
+            Some(10 << 20)
+        );
+        assert_eq!(store.store.block_cache_capacity(), 1 << 20);
+        assert_eq!(store.store.flush_interval(), Some(Duration::from_secs(5)));
+        assert_eq!(store.tunables(), tunables);
+    }
+
+    #[tokio::test]
+    async fn chipmunk_ttl() {
+        let dir = TempDir::new("ttl").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let put = |url: String| client.put(url).body("value");
+        let response = put(format!("{base}/by_query?ttl=0")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        put(format!("{base}/by_header"))
+            .header(TTL_HEADER, "0")
+            .send()
+            .await
+            .unwrap();
+        put(format!("{base}/long_lived"))
+            .header(TTL_HEADER, "3600")
+            .send()
+            .await
+            .unwrap();
+        let response = put(format!("{base}/invalid"))
+            .header(TTL_HEADER, "soon")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        for (key, status) in [
+            ("by_query", StatusCode::NOT_FOUND),
+            ("by_header", StatusCode::NOT_FOUND),
+            ("long_lived", StatusCode::OK),
+            ("invalid", StatusCode::NOT_FOUND),
+        ] {
+            let response = client.get(format!("{base}/{key}")).send().await.unwrap();
+            assert_eq!(response.status(), status, "Reading '{key}'");
+        }
+    }
+
+    #[tokio::test]
+    async fn chipmunk_sessions() {
+        let dir = TempDir::new("sessions").unwrap();
+        let conf = ChipmunkConfig::builder().wal_dir(dir.path()).build();
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        for url in [
+            format!("{base}/ops/sessions"),
+            format!("{base}/ops/sessions?ttl=0"),
+        ] {
+            let response = client.post(url).body("data").send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let response = client
+            .post(format!("{base}/ops/sessions?ttl=60"))
+            .body("data")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let created: SessionResponse =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(location, format!("/api/v1/ops/sessions/{}", created.id));
+        let session = format!("{base}/ops/sessions/{}", created.id);
+
+        let response = client.get(&session).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), "data");
+        let response = client
+            .post(format!("{session}/touch"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = client.delete(&session).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        for response in [
+            client.get(&session).send().await.unwrap(),
+            client
+                .post(format!("{session}/touch"))
+                .send()
+                .await
+                .unwrap(),
+            client.delete(&session).send().await.unwrap(),
+            client
+                .get(format!("{base}/ops/sessions/unknown"))
+                .send()
+                .await
+                .unwrap(),
+        ] {
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        // Keys beside the sessions are unaffected by their routes
+        client
+            .put(format!("{base}/session"))
+            .body("value")
+            .send()
+            .await
+            .unwrap();
+        let response = client.get(format!("{base}/session")).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), "value");
+    }
+
+    #[tokio::test]
+    async fn chipmunk_conditional_writes() {
+        let dir = TempDir::new("conditional_writes").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/key", get_base_uri(addr));
+        let put = |value: &'static str, name, tag: &str| {
+            client.put(&url).header(name, tag).body(value).send()
+        };
+
+        let response = put("v1", header::IF_NONE_MATCH, "*").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let v1 = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(v1, etag(b"v1"));
+        let response = put("v1", header::IF_NONE_MATCH, "*").await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::PRECONDITION_FAILED,
+            "Creating an existing key"
+        );
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.headers()[header::ETAG], v1.as_str());
+        let response = client
+            .get(&url)
+            .header(header::IF_NONE_MATCH, format!("W/{v1}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = put("v2", header::IF_MATCH, &v1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = put("v3", header::IF_MATCH, &v1).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::PRECONDITION_FAILED,
+            "Writing over a stale value"
+        );
+        let response = client
+            .delete(&url)
+            .header(header::IF_MATCH, &v1)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "v2");
+
+        let response = client
+            .delete(&url)
+            .header(header::IF_MATCH, format!("{v1}, {}", etag(b"v2")))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = put("v4", header::IF_MATCH, "*").await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::PRECONDITION_FAILED,
+            "Updating a missing key"
+        );
+        let response = client
+            .put(format!("{url}?ttl=60"))
+            .header(header::IF_MATCH, "*")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
-}
 
-async fn delete_key_handler(
-    Path(key): Path<String>,
-    State(state): State<Arc<Chipmunk>>,
-) -> impl IntoResponse {
-    match state.store.write().await.delete(key.as_bytes().to_vec()) {
-        Ok(_) => StatusCode::NO_CONTENT,
-        Err(e) => {
-            warn!("Cannot delete '{key}': {e}");
-            e.as_status_code()
+    #[tokio::test]
+    async fn chipmunk_admin() {
+        let dir = TempDir::new("admin").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024 * 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024 * 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default().with_admin_token("secret"),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+        let admin = format!("http://{addr}/admin");
+        let stats = || async {
+            let body = client
+                .get(format!("{admin}/stats"))
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+
+        for round in 0..2 {
+            client
+                .put(format!("{base}/key{round}"))
+                .body("value")
+                .send()
+                .await
+                .unwrap();
+            let stats = stats().await;
+            assert!(stats["memtable_bytes"].as_u64().unwrap() > 0);
+            assert!(stats["wal_bytes"].as_u64().unwrap() > 0);
+
+            let response = client
+                .post(format!("{admin}/flush"))
+                .bearer_auth("secret")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
         }
+        let flushed = stats().await;
+        assert_eq!(flushed["memtable_bytes"], 0);
+        assert_eq!(flushed["levels"][0]["files"], 2);
+        assert_eq!(flushed["statistics"]["flushes"], 2);
+
+        let response = client
+            .post(format!("{admin}/compact"))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let compaction: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(compaction["input_files"], 2);
+
+        let compacted = stats().await;
+        assert_eq!(compacted["levels"][0]["files"], 0);
+        assert_eq!(compacted["levels"][1]["files"], 1);
+        assert!(compacted["levels"][1]["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(compacted["compaction"]["compactions"], 1);
+        assert_eq!(compacted["approximate_keys"], 2);
+        assert_eq!(
+            compacted["approximate_bytes"],
+            compacted["levels"][1]["bytes"]
+        );
+
+        let response = client.get(format!("{base}/key1")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "value");
     }
-}
 
-async fn add_kv_handler(State(state): State<Arc<Chipmunk>>, req: String) -> impl IntoResponse {
-    match req.split_once("=") {
-        Some((key, value)) => match state.store.write().await.insert(key.into(), value.into()) {
-            Ok(_) => StatusCode::NO_CONTENT.into_response(),
-            Err(e) => {
-                warn!("Cannot insert '{key}': {e}");
-                let err = format!("Cannot insert '{key}'");
-                (e.as_status_code(), err).into_response()
-            }
-        },
-        None => (StatusCode::BAD_REQUEST, "Must provide key=value format").into_response(),
+    #[tokio::test]
+    async fn chipmunk_probes() {
+        let dir = TempDir::new("probes").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let store = Chipmunk::new(conf).unwrap();
+        let app = new_app(store.clone());
+        tokio::spawn(async move { axum::serve(socket, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let probe = |path: &'static str| client.get(format!("http://{addr}/{path}")).send();
+
+        assert_eq!(probe("healthz").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            probe("readyz").await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Not ready until restored"
+        );
+        store.restore().await.unwrap();
+        assert_eq!(probe("readyz").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(probe("health").await.unwrap().status(), StatusCode::OK);
     }
-}
 
-/// An instance of the [`Chipmunk`] store.
-///
-/// This comprises of the underlying k-v store and server. This utilises the
-/// actor pattern for communication. This is the task portion.
-#[derive(Clone)]
-pub struct Chipmunk {
-    store: Arc<RwLock<Lsm>>,
-}
+    #[tokio::test]
+    async fn chipmunk_cors() {
+        let dir = TempDir::new("cors").unwrap();
+        let conf = |cors| ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let dashboard = "https://dashboard.example.com";
+        let cors = CorsConfig::new(vec![header::HeaderValue::from_static(dashboard)]);
+        let addr = setup_server(conf(Some(cors))).await;
+        let client = reqwest::Client::new();
+        let preflight = |origin: &'static str| {
+            client
+                .request(
+                    axum::http::Method::OPTIONS,
+                    format!("http://{addr}/api/v1/a"),
+                )
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, TTL_HEADER)
+                .send()
+        };
+        let allowed_origin = |response: &reqwest::Response| {
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .cloned()
+        };
 
-impl Chipmunk {
-    pub fn new(config: ChipmunkConfig) -> Self {
-        Self {
-            store: Arc::new(RwLock::new(Lsm::new(config.wal, config.memtable))),
+        let response = preflight(dashboard).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(allowed_origin(&response).unwrap(), dashboard);
+        let methods = response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("PUT"));
+        let headers = response.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(headers.contains(TTL_HEADER));
+        let response = preflight("https://elsewhere.example.com").await.unwrap();
+        assert_eq!(
+            allowed_origin(&response),
+            None,
+            "Other origins are not allowed"
+        );
+
+        client
+            .put(format!("http://{addr}/api/v1/a"))
+            .body("1")
+            .send()
+            .await
+            .unwrap();
+        let response = client
+            .get(format!("http://{addr}/api/v1/a"))
+            .header(header::ORIGIN, dashboard)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(allowed_origin(&response).unwrap(), dashboard);
+        let exposed = response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(exposed.contains("etag"), "{exposed}");
+
+        let addr = setup_server(conf(None)).await;
+        let response = client
+            .get(format!("http://{addr}/healthz"))
+            .header(header::ORIGIN, dashboard)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            allowed_origin(&response),
+            None,
+            "Not allowed unless configured"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn chipmunk_concurrent_writes() {
+        let dir = TempDir::new("concurrent_writes").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+
+        let writes = (0..32).map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let response = client
+                    .put(format!("http://{addr}/api/v2/key{i}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(format!(
+                        r#"{{"value": "{}"}}"#,
+                        VALUE_BASE64.encode(i.to_string())
+                    ))
+                    .send()
+                    .await
+                    .unwrap();
+                let written: WriteResponse =
+                    serde_json::from_str(&response.text().await.unwrap()).unwrap();
+                written.sequence
+            })
+        });
+        let mut sequences = Vec::new();
+        for write in writes.collect::<Vec<_>>() {
+            sequences.push(write.await.unwrap());
         }
+        sequences.sort_unstable();
+        assert_eq!(sequences, (1..=32).collect::<Vec<u64>>());
+        let listed = client
+            .get(format!("http://{addr}/api/v1/ops/keys?prefix=key"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let listed: Vec<serde_json::Value> = serde_json::from_str(&listed).unwrap();
+        assert_eq!(listed.len(), 32);
     }
 
-    /// Attempt to perform a restore of the store.
-    ///
-    /// A restore will performed when previous WAL files were found within the
-    /// current working directory for chipmunk.
-    pub async fn restore(&self) -> Result<(), ChipmunkError> {
-        self.store.write().await.restore()?;
-        Ok(())
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn chipmunk_request_timeout() {
+        let dir = TempDir::new("request_timeout").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            // Every write is slowed down, as if the engine had fallen behind.
+            write_stall: WriteStallConfig::default()
+                .with_l1_triggers(0, usize::MAX)
+                .with_slowdown_delay(Duration::from_secs(1)),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default().with_request_timeout(Duration::from_millis(100)),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+
+        let started = std::time::Instant::now();
+        let response = client
+            .put(format!("http://{addr}/api/v1/a"))
+            .body("1")
+            .send()
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[header::RETRY_AFTER],
+            WRITE_STALL_RETRY_AFTER.to_string()
+        );
+        let error: ErrorResponse = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(error.code, ErrorCode::Timeout);
+
+        let response = client
+            .get(format!("http://{addr}/healthz"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "Reads are answered in time"
+        );
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::net::SocketAddr;
+    #[tokio::test]
+    async fn chipmunk_max_connections() {
+        let dir = TempDir::new("max_connections").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default().with_max_connections(1),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Chipmunk::new(conf).unwrap();
+        tokio::spawn(async move { store.serve(listener, std::future::pending()).await });
+        let healthz = format!("http://{addr}/healthz");
 
-    use tokio::net::TcpListener;
+        // The first client keeps its connection open between requests.
+        let first = reqwest::Client::new();
+        assert_eq!(
+            first.get(&healthz).send().await.unwrap().status(),
+            StatusCode::OK
+        );
+        let second = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        assert!(
+            second.get(&healthz).send().await.is_err(),
+            "Not accepted while the first connection is open"
+        );
 
-    use tempdir::TempDir;
+        drop(first);
+        let response = reqwest::Client::new().get(&healthz).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 
-    use super::*;
-    use crate::config::{ChipmunkConfig, MemtableConfig, WalConfig};
+    #[tokio::test]
+    async fn chipmunk_shutdown_and_reopen() {
+        let dir = TempDir::new("shutdown_and_reopen").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default().with_admin_token("secret"),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Chipmunk::new(conf).unwrap();
+        store.restore().await.unwrap();
+        let app = new_app(store);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let admin = |path: &str, token: Option<&str>| {
+            let request = client.post(format!("http://{addr}/admin/{path}"));
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+            .send()
+        };
+        let status = |path: &'static str| async move {
+            reqwest::get(format!("http://{addr}/{path}"))
+                .await
+                .unwrap()
+                .status()
+        };
 
-    fn get_base_uri(addr: SocketAddr) -> String {
-        format!("http://{addr}/api/v1")
+        client
+            .put(format!("http://{addr}/api/v1/a"))
+            .body("1")
+            .send()
+            .await
+            .unwrap();
+        let response = admin("shutdown", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let response = admin("shutdown", Some("guess")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(status("api/v1/a").await, StatusCode::OK, "Still open");
+        for path in ["flush", "compact"] {
+            let response = admin(path, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+        }
+        let response = admin("flush", Some("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = admin("shutdown", Some("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(status("api/v1/a").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("readyz").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("healthz").await, StatusCode::OK);
+
+        let response = admin("reopen", Some("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(status("readyz").await, StatusCode::OK);
+        let value = reqwest::get(format!("http://{addr}/api/v1/a"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(value, "1", "Closed writes are restored");
+        let response = admin("reopen", Some("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT, "Already open");
     }
 
-    async fn setup_server(conf: ChipmunkConfig) -> SocketAddr {
-        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = socket.local_addr().unwrap();
-        tokio::spawn(async move {
-            let store = Chipmunk::new(conf);
-            let app = new_app(store);
-            axum::serve(socket, app).await.unwrap();
-        });
-        addr
+    #[tokio::test]
+    async fn chipmunk_admin_unconfigured() {
+        let dir = TempDir::new("admin_unconfigured").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/admin/shutdown"))
+            .bearer_auth("")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
 
     #[tokio::test]
-    async fn chipmunk_invalid_add() {
-        let dir = TempDir::new("invalid_post").unwrap();
+    async fn chipmunk_request_id() {
+        let dir = TempDir::new("request_id").unwrap();
         let conf = ChipmunkConfig {
             wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
             memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
         };
         let addr = setup_server(conf).await;
         let client = reqwest::Client::new();
-        let base = get_base_uri(addr);
+        let request_id = |response: &reqwest::Response| {
+            response.headers()[REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
 
-        let response = client.post(&base).body("key1,value1").send().await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = client
+            .get(format!("http://{addr}/api/v1/missing"))
+            .header(REQUEST_ID_HEADER, "client-chosen")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(request_id(&response), "client-chosen", "IDs are propagated");
+
+        let first = client
+            .get(format!("http://{addr}/healthz"))
+            .send()
+            .await
+            .unwrap();
+        let second = client
+            .get(format!("http://{addr}/healthz"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(request_id(&first).len(), 36, "IDs are assigned as UUIDs");
+        assert_ne!(request_id(&first), request_id(&second));
+    }
+
+    #[tokio::test]
+    async fn chipmunk_version() {
+        let dir = TempDir::new("version").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let response = reqwest::get(format!("http://{addr}/version"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let info: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!info["git_sha"].as_str().unwrap().is_empty());
+        let built_at = info["build_timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(built_at).is_ok());
+        let features = info["features"].as_array().unwrap();
         assert_eq!(
-            response.text().await.unwrap(),
-            "Must provide key=value format"
+            features.iter().any(|feature| feature == "swagger-ui"),
+            cfg!(feature = "swagger-ui")
         );
     }
 
+    #[tokio::test]
+    async fn chipmunk_openapi() {
+        let dir = TempDir::new("openapi").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let response = reqwest::get(format!("http://{addr}/api/openapi.json"))
+            .await
+            .unwrap();
+        let document: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+
+        assert_eq!(document["info"]["title"], "Chipmunk");
+        let paths = document["paths"].as_object().unwrap();
+        for path in [
+            "/api/v1/{key}",
+            "/api/v1/ops/keys",
+            "/api/v1/ops/watch",
+            "/api/v1/ops/changes",
+            "/api/v2/{key}",
+            "/healthz",
+            "/readyz",
+            "/version",
+            "/metrics",
+            "/admin/flush",
+            "/admin/compact",
+            "/admin/stats",
+            "/replication/wal",
+            "/replication/checkpoint",
+            "/replication/ack",
+        ] {
+            assert!(paths.contains_key(path), "{path} is documented");
+        }
+        let v1 = &paths["/api/v1/{key}"];
+        assert!(v1["get"].is_object() && v1["put"].is_object() && v1["delete"].is_object());
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        for schema in [
+            "ErrorResponse",
+            "ValueResponse",
+            "WriteRequest",
+            "WatchEvent",
+        ] {
+            assert!(schemas.contains_key(schema), "{schema} is documented");
+        }
+
+        #[cfg(feature = "swagger-ui")]
+        {
+            let ui = reqwest::get(format!("http://{addr}/api/docs/"))
+                .await
+                .unwrap();
+            assert_eq!(ui.status(), StatusCode::OK);
+        }
+    }
+
     #[tokio::test]
     async fn chipmunk_crud() {
         let dir = TempDir::new("write_kv").unwrap();
         let conf = ChipmunkConfig {
             wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
             memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
         };
         let addr = setup_server(conf).await;
         let client = reqwest::Client::new();
         let base = get_base_uri(addr);
 
-        client.post(&base).body("key1=value1").send().await.unwrap();
+        client
+            .put(format!("{base}/key1"))
+            .body("value1")
+            .send()
+            .await
+            .unwrap();
 
         let got = client
             .get(format!("{base}/key1"))
@@ -157,4 +5035,124 @@ mod test {
         let got = client.get(format!("{base}/key1")).send().await.unwrap();
         assert_eq!(got.status(), StatusCode::NOT_FOUND);
     }
+
+    #[tokio::test]
+    async fn chipmunk_watch() {
+        let dir = TempDir::new("watch").unwrap();
+        let conf = ChipmunkConfig {
+            wal: WalConfig::new(0, 1024, dir.path().to_path_buf(), None),
+            memtable: MemtableConfig::new(0, 1024),
+            sstable: SstableConfig::default(),
+            compaction: CompactionConfig::default(),
+            write_stall: WriteStallConfig::default(),
+            tls: None,
+            cors: None,
+            http: HttpConfig::default(),
+            replica_of: None,
+            replication: ReplicationConfig::default(),
+            shards: 1,
+        };
+        let addr = setup_server(conf).await;
+        let client = reqwest::Client::new();
+        let base = get_base_uri(addr);
+
+        let mut watch = client
+            .get(format!("{base}/ops/watch?prefix=app/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            watch.headers()[header::CONTENT_TYPE],
+            "text/event-stream",
+            "The watch is subscribed once the response starts"
+        );
+        client
+            .put(format!("{base}/app%2Fa?ttl=60"))
+            .body("1")
+            .send()
+            .await
+            .unwrap();
+        client
+            .put(format!("{base}/other"))
+            .body("2")
+            .send()
+            .await
+            .unwrap();
+        client
+            .delete(format!("{base}/app%2Fa"))
+            .send()
+            .await
+            .unwrap();
+
+        let mut body = String::new();
+        while body.matches("\n\n").count() < 2 {
+            let chunk = watch.chunk().await.unwrap().unwrap();
+            body.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let events: Vec<(&str, &str, WatchEvent)> = body
+            .split_terminator("\n\n")
+            .map(|event| {
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .unwrap()
+                };
+                (
+                    field("event: "),
+                    field("id: "),
+                    serde_json::from_str(field("data: ")).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(events.len(), 2, "Only keys with the prefix are watched");
+        let (
+            name,
+            id,
+            WatchEvent::Put {
+                key,
+                value,
+                expires_at,
+                ..
+            },
+        ) = &events[0]
+        else {
+            panic!("Expected a put, got {events:?}");
+        };
+        assert_eq!((*name, *id), ("put", "1"));
+        assert_eq!((key.as_str(), value.as_str()), ("app/a", "1"));
+        assert!(expires_at.is_some());
+        assert_eq!(
+            events[1],
+            (
+                "delete",
+                "3",
+                WatchEvent::Delete {
+                    key: "app/a".into(),
+                    sequence: 3
+                }
+            )
+        );
+
+        let mut resumed = client
+            .get(format!("{base}/ops/watch?prefix=app/"))
+            .header(LAST_EVENT_ID_HEADER, "1")
+            .send()
+            .await
+            .unwrap();
+        let chunk = resumed.chunk().await.unwrap().unwrap();
+        assert!(
+            std::str::from_utf8(&chunk)
+                .unwrap()
+                .starts_with("event: delete\nid: 3\n"),
+            "Writes after the last event are replayed"
+        );
+        let response = client
+            .get(format!("{base}/ops/watch"))
+            .header(LAST_EVENT_ID_HEADER, "latest")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 }
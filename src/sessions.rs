@@ -0,0 +1,313 @@
+//! Sessions held within the store, for applications which would otherwise
+//! keep them in a cache alongside it.
+//!
+//! Each session is a key under [`SESSION_PREFIX`] holding its data, written
+//! with a time-to-live of the session's window. Touching a session writes it
+//! again with a fresh time-to-live, so one touched at least once per window
+//! lives on, while one that is not expires like any other key. Touches and
+//! deletions are compare-and-swaps of the value just read, so a session which
+//! expires or is deleted in between is never brought back.
+//!
+//! Ids are 128 bits from the operating system's random number generator in
+//! hex, so cannot be guessed from those handed out before. The window, then
+//! the time the session expires, are kept ahead of the data within the value,
+//! each as milliseconds in 8 big-endian bytes. Reads and touches check the
+//! time the session expires themselves, the key's time-to-live only removing
+//! it once it has.
+//!
+//! Sessions are otherwise keys like any other: they are listed, exported and
+//! replicated with the rest of the store, and keys written under the prefix
+//! directly are not checked to be sessions until they are read as one.
+
+use std::time::Duration;
+
+use crate::memtable::{expiry_from, unix_millis};
+use crate::shard::ShardedLsm;
+use crate::ChipmunkError;
+
+/// Prefix of the keys holding sessions, followed by the session's id.
+pub const SESSION_PREFIX: &[u8] = b"_sessions/";
+
+/// Number of random bytes within a session id, which holds twice as many hex
+/// digits.
+const SESSION_ID_BYTES: usize = 16;
+
+/// Number of bytes ahead of the data of a session, holding its window and
+/// the time it expires.
+const HEADER_BYTES: usize = 16;
+
+/// A session read from the store, see [`get`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// The data the session was created with.
+    pub data: Vec<u8>,
+    /// How long the session lives for without being touched.
+    pub window: Duration,
+    /// Unix timestamp, in milliseconds, at which the session expires unless
+    /// it is touched.
+    pub expires_at: u64,
+}
+
+impl Session {
+    fn encode(&self) -> Vec<u8> {
+        let window = u64::try_from(self.window.as_millis()).unwrap_or(u64::MAX);
+        let mut value = Vec::with_capacity(HEADER_BYTES + self.data.len());
+        value.extend_from_slice(&window.to_be_bytes());
+        value.extend_from_slice(&self.expires_at.to_be_bytes());
+        value.extend_from_slice(&self.data);
+        value
+    }
+
+    fn decode(mut value: Vec<u8>) -> Result<Self, ChipmunkError> {
+        if value.len() < HEADER_BYTES {
+            return Err(ChipmunkError::ValueDecode(format!(
+                "session of {} bytes is too short to hold its window and expiry",
+                value.len()
+            )));
+        }
+        let data = value.split_off(HEADER_BYTES);
+        let (window, expires_at) = value.split_at(HEADER_BYTES / 2);
+        Ok(Session {
+            data,
+            window: Duration::from_millis(u64::from_be_bytes(
+                window.try_into().expect("slice of the window's length"),
+            )),
+            expires_at: u64::from_be_bytes(
+                expires_at.try_into().expect("slice of the expiry's length"),
+            ),
+        })
+    }
+}
+
+/// Key holding the session with the given id, or `None` if the id is not one
+/// which [`create`] hands out.
+fn session_key(id: &str) -> Option<Vec<u8>> {
+    let valid = id.len() == SESSION_ID_BYTES * 2
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    valid.then(|| [SESSION_PREFIX, id.as_bytes()].concat())
+}
+
+/// Generate a new session id.
+fn new_session_id() -> Result<String, ChipmunkError> {
+    let mut bytes = [0; SESSION_ID_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(ChipmunkError::SessionId)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Create a session holding the data, which expires once it has gone
+/// untouched for the window, returning its id and the sequence number of the
+/// write.
+pub fn create(
+    store: &ShardedLsm,
+    data: Vec<u8>,
+    window: Duration,
+) -> Result<(String, u64), ChipmunkError> {
+    create_at(store, data, window, unix_millis())
+}
+
+/// [`create`] a session as of `now`, a unix timestamp in milliseconds.
+fn create_at(
+    store: &ShardedLsm,
+    data: Vec<u8>,
+    window: Duration,
+    now: u64,
+) -> Result<(String, u64), ChipmunkError> {
+    let value = Session {
+        data,
+        window,
+        expires_at: expiry_from(now, window),
+    }
+    .encode();
+    loop {
+        let id = new_session_id()?;
+        let key = [SESSION_PREFIX, id.as_bytes()].concat();
+        // Only a session which already holds the id is not replaced, in
+        // which case another is drawn.
+        let created = store.compare_and_swap_with_ttl(key, None, value.clone(), window)?;
+        if let Some(sequence) = created {
+            return Ok((id, sequence));
+        }
+    }
+}
+
+/// Read the session with the given id, `None` if it does not exist or has
+/// expired. Reading a session does not touch it.
+pub fn get(store: &ShardedLsm, id: &str) -> Result<Option<Session>, ChipmunkError> {
+    get_at(store, id, unix_millis())
+}
+
+/// [`get`] a session as of `now`, a unix timestamp in milliseconds.
+fn get_at(store: &ShardedLsm, id: &str, now: u64) -> Result<Option<Session>, ChipmunkError> {
+    let Some(key) = session_key(id) else {
+        return Ok(None);
+    };
+    Ok(read(store, &key, now)?.map(|(_, session)| session))
+}
+
+/// The value of the session held by the key, and the session it decodes to,
+/// if it has not expired as of `now`.
+fn read(
+    store: &ShardedLsm,
+    key: &[u8],
+    now: u64,
+) -> Result<Option<(Vec<u8>, Session)>, ChipmunkError> {
+    let Some(value) = store.get(key.to_vec())? else {
+        return Ok(None);
+    };
+    let session = Session::decode(value.clone())?;
+    Ok((session.expires_at > now).then_some((value, session)))
+}
+
+/// Restart the window of the session with the given id, returning the
+/// sequence number of the write, or `None` if the session does not exist or
+/// has expired.
+pub fn touch(store: &ShardedLsm, id: &str) -> Result<Option<u64>, ChipmunkError> {
+    touch_at(store, id, unix_millis())
+}
+
+/// [`touch`] a session as of `now`, a unix timestamp in milliseconds.
+fn touch_at(store: &ShardedLsm, id: &str, now: u64) -> Result<Option<u64>, ChipmunkError> {
+    let Some(key) = session_key(id) else {
+        return Ok(None);
+    };
+    loop {
+        let Some((value, session)) = read(store, &key, now)? else {
+            return Ok(None);
+        };
+        let window = session.window;
+        let touched = Session {
+            expires_at: expiry_from(now, window),
+            ..session
+        }
+        .encode();
+        let touched = store.compare_and_swap_with_ttl(key.clone(), Some(value), touched, window)?;
+        if touched.is_some() {
+            return Ok(touched);
+        }
+    }
+}
+
+/// Delete the session with the given id, returning the sequence number of
+/// the deletion, or `None` if the session does not exist or has expired.
+pub fn delete(store: &ShardedLsm, id: &str) -> Result<Option<u64>, ChipmunkError> {
+    delete_at(store, id, unix_millis())
+}
+
+/// [`delete`] a session as of `now`, a unix timestamp in milliseconds.
+fn delete_at(store: &ShardedLsm, id: &str, now: u64) -> Result<Option<u64>, ChipmunkError> {
+    let Some(key) = session_key(id) else {
+        return Ok(None);
+    };
+    loop {
+        let Some((value, _)) = read(store, &key, now)? else {
+            return Ok(None);
+        };
+        let deleted = store.compare_and_swap(key.clone(), Some(value), None)?;
+        if deleted.is_some() {
+            return Ok(deleted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::config::{
+        CompactionConfig, MemtableConfig, SstableConfig, WalConfig, WriteStallConfig,
+    };
+    use crate::memtable::unix_millis;
+    use crate::shard::ShardedLsm;
+
+    use super::{
+        create, create_at, delete, delete_at, get, get_at, touch, touch_at, Session, SESSION_PREFIX,
+    };
+
+    fn create_store(dir: &TempDir) -> ShardedLsm {
+        let store = ShardedLsm::new(
+            1,
+            WalConfig {
+                log_directory: dir.path().to_path_buf(),
+                ..WalConfig::default()
+            },
+            MemtableConfig::default(),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        store.restore().unwrap();
+        store
+    }
+
+    #[test]
+    fn sessions() {
+        let dir = TempDir::new("sessions").unwrap();
+        let store = create_store(&dir);
+        let window = Duration::from_secs(60);
+        let now = unix_millis();
+
+        let (id, sequence) = create_at(&store, b"user=1".to_vec(), window, now).unwrap();
+        assert_eq!(sequence, 1);
+        assert_eq!(id.len(), 32);
+        let (other, _) = create(&store, b"user=2".to_vec(), window).unwrap();
+        assert_ne!(id, other, "Ids are not reused");
+        assert_eq!(
+            get_at(&store, &id, now).unwrap(),
+            Some(Session {
+                data: b"user=1".to_vec(),
+                window,
+                expires_at: now + 60_000,
+            })
+        );
+
+        assert_eq!(touch(&store, &id).unwrap(), Some(3));
+        assert_eq!(delete(&store, &id).unwrap(), Some(4));
+        assert_eq!(get(&store, &id).unwrap(), None);
+        assert_eq!(
+            touch(&store, &id).unwrap(),
+            None,
+            "Deleted sessions stay gone"
+        );
+        assert_eq!(delete(&store, &id).unwrap(), None);
+        assert_eq!(store.sequence(), 4);
+
+        // Only ids handed out are looked up, not arbitrary keys
+        store
+            .insert([SESSION_PREFIX, b"../other"].concat(), b"value".to_vec())
+            .unwrap();
+        assert_eq!(get(&store, "../other").unwrap(), None);
+        assert_eq!(get(&store, &other.to_uppercase()).unwrap(), None);
+    }
+
+    #[test]
+    fn sliding_window() {
+        let dir = TempDir::new("sliding_window").unwrap();
+        let store = create_store(&dir);
+        let start = unix_millis();
+        let (id, _) = create_at(&store, b"data".to_vec(), Duration::from_secs(60), start).unwrap();
+
+        // Touched within each window, the session outlives the first of them
+        for touched in 1..=3 {
+            assert!(touch_at(&store, &id, start + touched * 50_000)
+                .unwrap()
+                .is_some());
+        }
+        let session = get_at(&store, &id, start + 200_000).unwrap().unwrap();
+        assert_eq!(session.expires_at, start + 210_000);
+
+        let expired = start + 210_000;
+        assert_eq!(get_at(&store, &id, expired).unwrap(), None);
+        assert_eq!(
+            touch_at(&store, &id, expired).unwrap(),
+            None,
+            "Expired sessions stay gone"
+        );
+        assert_eq!(delete_at(&store, &id, expired).unwrap(), None);
+        assert_eq!(store.sequence(), 4, "Expired sessions are not written");
+    }
+}
@@ -0,0 +1,817 @@
+//! Partitioning of the store into shards, each an [`Lsm`] with a WAL,
+//! memtable and write lock of its own, so that writes to different shards
+//! do not wait on one another.
+//!
+//! Each key belongs to the shard chosen by the CRC32C checksum of its bytes,
+//! so the number of shards cannot change once the store has been written
+//! to. It is recorded within the directory, in [`SHARDS_FILE_NAME`], and a
+//! store opened with any other number is refused. A single shard is kept
+//! within the directory itself, as stores were before they were sharded,
+//! while more are each kept within a `shard-N` directory of their own.
+//!
+//! Reads of a key go to its shard alone, while scans merge those of every
+//! shard into key order. The shards share a block cache and their metrics.
+//!
+//! Each shard counts its own sequence numbers, so with more than one shard
+//! there is no single order of every write: watching keys, the changefeed,
+//! replication and checkpoints are refused with [`ChipmunkError::Sharded`].
+//! A batch is only applied atomically within each shard.
+
+use std::fs::File;
+use std::io;
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::block_cache::BlockCache;
+use crate::compaction::{CompactionFilter, CompactionStats};
+use crate::comparator::Comparator;
+use crate::config::{CompactionConfig, MemtableConfig, SstableConfig, WalConfig, WriteStallConfig};
+use crate::lsm::{
+    BackgroundWork, BatchWrite, Change, Lsm, Resumed, StampedValue, WalChanges, WriteStall,
+};
+use crate::manifest::MANIFEST_FILE_NAME;
+use crate::metrics::{Exposition, Metrics};
+use crate::snapshot::Snapshot;
+use crate::statistics::{Statistics, TreeStats};
+use crate::transform::ValueTransform;
+use crate::{fs, ChipmunkError};
+
+/// Name of the file, within the directory, recording the number of shards.
+pub const SHARDS_FILE_NAME: &str = "SHARDS";
+
+/// How long each background worker sleeps between checks that the store is
+/// still in use, and that the memtable of its shard is not due a flush, when
+/// no work has been handed to it.
+const BACKGROUND_WORK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Directory holding the shard with the given index, when there is more
+/// than one.
+fn shard_directory(directory: &Path, shard: usize) -> PathBuf {
+    directory.join(format!("shard-{shard}"))
+}
+
+/// Check the number of shards against the one recorded within the
+/// directory, failing with [`ChipmunkError::ShardCountMismatch`] when it was
+/// written with another, or record it when none is.
+fn record_shards(directory: &Path, shards: usize) -> Result<(), ChipmunkError> {
+    let path = directory.join(SHARDS_FILE_NAME);
+    let recorded = match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(contents.trim().parse::<usize>().map_err(|_| {
+            ChipmunkError::WalRestoreDirectory(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' does not hold a number of shards", path.display()),
+            ))
+        })?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(ChipmunkError::WalRestoreDirectory(e)),
+    };
+    // Stores written before the number was recorded hold a single shard,
+    // within the directory itself.
+    let expected = match recorded {
+        Some(recorded) => recorded,
+        None if directory.join(MANIFEST_FILE_NAME).exists() => 1,
+        None => shards,
+    };
+    if expected != shards {
+        return Err(ChipmunkError::ShardCountMismatch {
+            path: directory.to_path_buf(),
+            recorded: expected,
+            configured: shards,
+        });
+    }
+    if recorded.is_none() {
+        std::fs::create_dir_all(directory).map_err(ChipmunkError::WalRestoreDirectory)?;
+        let temp = fs::temp_path(&path);
+        std::fs::write(&temp, format!("{shards}\n"))
+            .and_then(|_| File::open(&temp)?.sync_all())
+            .and_then(|_| fs::publish(&temp, &path))
+            .map_err(ChipmunkError::WalRestoreDirectory)?;
+        info!(shards, "Recorded the number of shards");
+    }
+    Ok(())
+}
+
+/// Index of the shard which holds the key, out of the given number.
+fn shard_index(key: &[u8], shards: usize) -> usize {
+    crc32c::crc32c(key) as usize % shards
+}
+
+/// The store, split into shards by key, see the [module](self) docs.
+pub struct ShardedLsm {
+    shards: Vec<Arc<Lsm>>,
+    /// Directory holding every shard, within which their number is recorded.
+    directory: PathBuf,
+}
+
+impl ShardedLsm {
+    /// Create the given number of shards, at least one, along with a
+    /// background worker for each which flushes and compacts it so that
+    /// writes do not have to. The workers exit once the store is dropped.
+    ///
+    /// The number of shards is checked against, or recorded as, the one
+    /// within the directory before any shard is created, failing with
+    /// [`ChipmunkError::ShardCountMismatch`] when it was written with another.
+    pub fn new(
+        shards: usize,
+        wal_config: WalConfig,
+        memtable_config: MemtableConfig,
+        sstable_config: SstableConfig,
+        compaction_config: CompactionConfig,
+        write_stall_config: WriteStallConfig,
+    ) -> Result<Self, ChipmunkError> {
+        let count = shards.max(1);
+        let directory = wal_config.log_directory.clone();
+        let in_memory = memtable_config.in_memory.is_some();
+        if !in_memory {
+            record_shards(&directory, count)?;
+        }
+        let metrics = Arc::new(Metrics::default());
+        let block_cache = Arc::new(BlockCache::new(sstable_config.block_cache_capacity));
+        let mut shards = Vec::with_capacity(count);
+        for shard in 0..count {
+            let mut wal_config = wal_config.clone();
+            if count > 1 {
+                wal_config.log_directory = shard_directory(&directory, shard);
+                if !in_memory {
+                    std::fs::create_dir_all(&wal_config.log_directory).map_err(|source| {
+                        ChipmunkError::WalDirectoryOpen {
+                            source,
+                            path: wal_config.log_directory.clone(),
+                        }
+                    })?;
+                }
+            }
+            let mut lsm = Lsm::with_shared(
+                wal_config,
+                memtable_config.clone(),
+                sstable_config.clone(),
+                compaction_config.clone(),
+                write_stall_config.clone(),
+                Arc::clone(&metrics),
+                Arc::clone(&block_cache),
+            )?;
+            let work = lsm.enable_background_work();
+            let lsm = Arc::new(lsm);
+            let worker = Arc::downgrade(&lsm);
+            std::thread::Builder::new()
+                .name(format!("chipmunk-background-{shard}"))
+                .spawn(move || run_background_work(worker, work))
+                .map_err(ChipmunkError::BackgroundWorker)?;
+            shards.push(lsm);
+        }
+        Ok(Self { shards, directory })
+    }
+
+    /// The shard which holds the key.
+    fn shard(&self, key: &[u8]) -> &Lsm {
+        &self.shards[shard_index(key, self.shards.len())]
+    }
+
+    /// The only shard, failing with [`ChipmunkError::Sharded`] when there is
+    /// more than one, for the features which need every write in one order.
+    fn only(&self, feature: &'static str) -> Result<&Lsm, ChipmunkError> {
+        match &self.shards[..] {
+            [shard] => Ok(shard),
+            _ => Err(ChipmunkError::Sharded(feature)),
+        }
+    }
+
+    /// Directory holding every shard.
+    pub fn working_directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Ordering of the keys of every shard.
+    fn comparator(&self) -> Arc<dyn Comparator> {
+        self.shards[0].comparator()
+    }
+
+    /// Restore every shard, see [`Lsm::restore`].
+    pub fn restore(&self) -> Result<(), ChipmunkError> {
+        for shard in &self.shards {
+            shard.restore()?;
+        }
+        Ok(())
+    }
+
+    /// Flush and close every shard, see [`Lsm::close`].
+    pub fn close(&self) -> Result<(), ChipmunkError> {
+        for shard in &self.shards {
+            shard.close()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the memtables of every shard, see [`Lsm::flush`].
+    pub fn flush(&self) -> Result<(), ChipmunkError> {
+        for shard in &self.shards {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compact every shard, returning the work performed by them all, see
+    /// [`Lsm::force_compaction`].
+    pub fn force_compaction(&self) -> Result<CompactionStats, ChipmunkError> {
+        let mut stats = CompactionStats::default();
+        for shard in &self.shards {
+            stats.merge(&shard.force_compaction()?);
+        }
+        Ok(stats)
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, ChipmunkError> {
+        self.shard(&key).get(key)
+    }
+
+    pub fn get_stamped(&self, key: Vec<u8>) -> Result<Option<StampedValue>, ChipmunkError> {
+        self.shard(&key).get_stamped(key)
+    }
+
+    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<u64, ChipmunkError> {
+        self.shard(&key).insert(key, value)
+    }
+
+    pub fn insert_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<u64, ChipmunkError> {
+        self.shard(&key).insert_with_ttl(key, value, ttl)
+    }
+
+    pub fn delete(&self, key: Vec<u8>) -> Result<u64, ChipmunkError> {
+        self.shard(&key).delete(key)
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Option<u64>, ChipmunkError> {
+        self.shard(&key).compare_and_swap(key, expected, new)
+    }
+
+    pub fn compare_and_swap_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<Option<u64>, ChipmunkError> {
+        self.shard(&key)
+            .compare_and_swap_with_ttl(key, expected, new, ttl)
+    }
+
+    /// Apply the writes of the batch, see [`Lsm::write_batch`], returning
+    /// the [`ShardedLsm::sequence`] once they are applied. With more than
+    /// one shard, the writes to each shard are applied atomically, but
+    /// those to one shard may be seen before those to another.
+    pub fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<u64, ChipmunkError> {
+        if let [shard] = &self.shards[..] {
+            return shard.write_batch(writes);
+        }
+        // Checked up front, so that no shard applies its part of a batch
+        // which another then refuses.
+        for write in &writes {
+            if let BatchWrite::Put { key, value, .. } = write {
+                self.shard(key).check_value_size(value)?;
+            }
+        }
+        let mut batches: Vec<Vec<BatchWrite>> = self.shards.iter().map(|_| Vec::new()).collect();
+        for write in writes {
+            let (BatchWrite::Put { key, .. } | BatchWrite::Delete { key }) = &write;
+            batches[shard_index(key, self.shards.len())].push(write);
+        }
+        for (shard, writes) in self.shards.iter().zip(batches) {
+            if !writes.is_empty() {
+                shard.write_batch(writes)?;
+            }
+        }
+        Ok(self.sequence())
+    }
+
+    /// Delete every key starting with the prefix, returning the
+    /// [`ShardedLsm::sequence`] once they are deleted.
+    ///
+    /// Each shard writes a single range tombstone, see [`Lsm::delete_range`],
+    /// when the [`Comparator`] holds the keys together from the prefix up to
+    /// a key after them. Otherwise the keys of a snapshot are deleted one at a
+    /// time, so keys written while that happens may be kept.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Result<u64, ChipmunkError> {
+        match self.prefix_bounds(prefix) {
+            (Bound::Included(start), Bound::Excluded(end)) => {
+                for shard in &self.shards {
+                    shard.delete_range(start.to_vec(), end.to_vec())?;
+                }
+            }
+            _ => {
+                let snapshot = self.snapshot()?;
+                for entry in snapshot.scan_prefix(prefix) {
+                    let (key, _) = entry?;
+                    self.delete(key.to_vec())?;
+                }
+            }
+        }
+        Ok(self.sequence())
+    }
+
+    /// Iterate over the live entries whose keys fall within the given range,
+    /// in key order, merging those of every shard, see [`Lsm::scan`].
+    pub fn scan<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        MergedShards::new(
+            self.shards.iter().map(|shard| shard.scan(bounds.clone())),
+            self.comparator(),
+        )
+    }
+
+    /// Iterate over the live entries whose keys start with the prefix, in
+    /// key order, merging those of every shard, see [`Lsm::scan_prefix`].
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        MergedShards::new(
+            self.shards.iter().map(|shard| shard.scan_prefix(prefix)),
+            self.comparator(),
+        )
+    }
+
+    pub(crate) fn prefix_bounds(&self, prefix: &[u8]) -> (Bound<Bytes>, Bound<Bytes>) {
+        self.shards[0].prefix_bounds(prefix)
+    }
+
+    /// Take a [`Snapshot`] of every shard. Each is consistent as of the most
+    /// recent write to that shard, though with more than one shard, writes
+    /// made while they are taken may be seen by some and not others.
+    pub fn snapshot(&self) -> Result<ShardedSnapshot, ChipmunkError> {
+        Ok(ShardedSnapshot {
+            snapshots: self
+                .shards
+                .iter()
+                .map(|shard| shard.snapshot())
+                .collect::<Result<_, _>>()?,
+            comparator: self.comparator(),
+        })
+    }
+
+    /// Sequence number of the most recent write, see [`Lsm::sequence`].
+    /// With more than one shard, the sum of those of every shard, which
+    /// rises with each write but does not stamp any of them.
+    pub fn sequence(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.sequence()).sum()
+    }
+
+    /// See [`Lsm::subscribe`], which needs a single shard.
+    pub fn subscribe(&self) -> Result<broadcast::Receiver<Change>, ChipmunkError> {
+        Ok(self.only("watching keys")?.subscribe())
+    }
+
+    /// See [`Lsm::subscribe_after`], which needs a single shard.
+    pub fn subscribe_after(&self, sequence: u64) -> Result<Resumed, ChipmunkError> {
+        Ok(self.only("watching keys")?.subscribe_after(sequence))
+    }
+
+    /// See [`Lsm::wal_changes_after`], which needs a single shard.
+    pub fn wal_changes_after(&self, sequence: u64) -> Result<WalChanges, ChipmunkError> {
+        self.only("reading changes")?.wal_changes_after(sequence)
+    }
+
+    /// See [`Lsm::apply_replicated`], which needs a single shard.
+    pub fn apply_replicated(&self, change: Change) -> Result<bool, ChipmunkError> {
+        self.only("replication")?.apply_replicated(change)
+    }
+
+    /// See [`Lsm::checkpoint`], which needs a single shard.
+    pub fn checkpoint(&self, target_dir: &Path) -> Result<(), ChipmunkError> {
+        self.only("checkpoint")?.checkpoint(target_dir)
+    }
+
+    pub fn set_value_transform(&self, transform: Arc<dyn ValueTransform>) {
+        for shard in &self.shards {
+            shard.set_value_transform(Arc::clone(&transform));
+        }
+    }
+
+    pub fn set_comparator(&self, comparator: Arc<dyn Comparator>) {
+        for shard in &self.shards {
+            shard.set_comparator(Arc::clone(&comparator));
+        }
+    }
+
+    pub fn set_compaction_filter(&self, filter: Arc<dyn CompactionFilter>) {
+        for shard in &self.shards {
+            shard.set_compaction_filter(Arc::clone(&filter));
+        }
+    }
+
+    pub fn compaction_config(&self) -> CompactionConfig {
+        self.shards[0].compaction_config()
+    }
+
+    pub fn set_compaction_config(&self, config: CompactionConfig) {
+        for shard in &self.shards {
+            shard.set_compaction_config(config.clone());
+        }
+    }
+
+    pub fn write_stall_config(&self) -> WriteStallConfig {
+        self.shards[0].write_stall_config()
+    }
+
+    pub fn set_write_stall_config(&self, config: WriteStallConfig) {
+        for shard in &self.shards {
+            shard.set_write_stall_config(config.clone());
+        }
+    }
+
+    pub fn flush_interval(&self) -> Option<Duration> {
+        self.shards[0].flush_interval()
+    }
+
+    pub fn set_flush_interval(&self, flush_interval: Option<Duration>) {
+        for shard in &self.shards {
+            shard.set_flush_interval(flush_interval);
+        }
+    }
+
+    /// Capacity of the block cache shared by every shard.
+    pub fn block_cache_capacity(&self) -> usize {
+        self.shards[0].block_cache_capacity()
+    }
+
+    /// Resize the block cache shared by every shard.
+    pub fn set_block_cache_capacity(&self, capacity: usize) {
+        self.shards[0].set_block_cache_capacity(capacity);
+    }
+
+    pub fn wal_buffer_size(&self) -> Option<usize> {
+        self.shards[0].wal_buffer_size()
+    }
+
+    pub fn set_wal_buffer_size(&self, buffer_size: Option<usize>) -> Result<(), ChipmunkError> {
+        for shard in &self.shards {
+            shard.set_wal_buffer_size(buffer_size)?;
+        }
+        Ok(())
+    }
+
+    /// The greatest pressure on the write path of any shard.
+    pub fn write_stall(&self) -> WriteStall {
+        self.shards
+            .iter()
+            .map(|shard| shard.write_stall())
+            .max()
+            .unwrap_or(WriteStall::Normal)
+    }
+
+    /// The shape of every shard together, see [`Lsm::tree_stats`].
+    pub fn tree_stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        for shard in &self.shards {
+            stats.merge(&shard.tree_stats());
+        }
+        stats
+    }
+
+    /// Work performed by every compaction cycle of every shard.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        let mut stats = CompactionStats::default();
+        for shard in &self.shards {
+            stats.merge(&shard.compaction_stats());
+        }
+        stats
+    }
+
+    /// Work performed by every shard, whose metrics are shared.
+    pub fn statistics(&self) -> Statistics {
+        self.shards[0].statistics()
+    }
+
+    /// See [`Lsm::expose_metrics`], describing every shard together.
+    pub fn expose_metrics(&self, exposition: &mut Exposition) {
+        self.shards[0].expose_metrics_with(&self.tree_stats(), exposition);
+    }
+}
+
+/// A [`Snapshot`] of each shard, see [`ShardedLsm::snapshot`].
+pub struct ShardedSnapshot {
+    snapshots: Vec<Snapshot>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl ShardedSnapshot {
+    /// See [`Snapshot::scan`].
+    pub fn scan<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        MergedShards::new(
+            self.snapshots
+                .iter()
+                .map(|snapshot| snapshot.scan(bounds.clone())),
+            Arc::clone(&self.comparator),
+        )
+    }
+
+    /// See [`Snapshot::scan_prefix`].
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        MergedShards::new(
+            self.snapshots
+                .iter()
+                .map(|snapshot| snapshot.scan_prefix(prefix)),
+            Arc::clone(&self.comparator),
+        )
+    }
+
+    /// See [`Snapshot::scan_prefix_after`].
+    pub fn scan_prefix_after(
+        &self,
+        prefix: &[u8],
+        after: &[u8],
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        MergedShards::new(
+            self.snapshots
+                .iter()
+                .map(|snapshot| snapshot.scan_prefix_after(prefix, after)),
+            Arc::clone(&self.comparator),
+        )
+    }
+}
+
+/// Merges the entries read from each shard, whose keys never overlap, into
+/// key order.
+struct MergedShards<I: Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>>> {
+    shards: Vec<Peekable<I>>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl<I: Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>>> MergedShards<I> {
+    fn new(shards: impl IntoIterator<Item = I>, comparator: Arc<dyn Comparator>) -> Self {
+        Self {
+            shards: shards.into_iter().map(Iterator::peekable).collect(),
+            comparator,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>>> Iterator for MergedShards<I> {
+    type Item = Result<(Bytes, Bytes), ChipmunkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // An error reading any shard is yielded before the entries of the
+        // others.
+        if let Some(failed) = self
+            .shards
+            .iter_mut()
+            .position(|shard| matches!(shard.peek(), Some(Err(_))))
+        {
+            return self.shards[failed].next();
+        }
+        let comparator = &self.comparator;
+        let (next, _) = self
+            .shards
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, shard)| match shard.peek()? {
+                Ok((key, _)) => Some((index, key.clone())),
+                Err(_) => None,
+            })
+            .min_by(|(_, a), (_, b)| comparator.compare(a, b))?;
+        self.shards[next].next()
+    }
+}
+
+/// Run flushes and compaction as the write path of the shard hands them
+/// off, until the store is dropped.
+fn run_background_work(shard: Weak<Lsm>, work: Arc<BackgroundWork>) {
+    loop {
+        let pending = work.wait(BACKGROUND_WORK_INTERVAL);
+        let Some(shard) = shard.upgrade() else {
+            debug!("Store dropped, stopping background worker");
+            return;
+        };
+        if pending {
+            if let Err(e) = shard.run_background_work() {
+                warn!("Background flush failed: {e}");
+            }
+        }
+        if let Err(e) = shard.flush_if_due() {
+            warn!("Background flush failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tempdir::TempDir;
+
+    use crate::comparator::TimeSeries;
+    use crate::config::{
+        CompactionConfig, MemtableConfig, SstableConfig, WalConfig, WriteStallConfig,
+    };
+    use crate::lsm::BatchWrite;
+    use crate::ChipmunkError;
+
+    use super::{shard_directory, ShardedLsm, SHARDS_FILE_NAME};
+
+    fn create_sharded(shards: usize, dir: &TempDir) -> ShardedLsm {
+        let store = ShardedLsm::new(
+            shards,
+            WalConfig {
+                log_directory: dir.path().to_path_buf(),
+                ..WalConfig::default()
+            },
+            MemtableConfig::default(),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        store.restore().unwrap();
+        store
+    }
+
+    #[test]
+    fn sharded_writes() {
+        let dir = TempDir::new("sharded_writes").unwrap();
+        let store = create_sharded(4, &dir);
+        for i in 0..100u32 {
+            store
+                .insert(format!("key-{i:03}").into_bytes(), i.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        store
+            .write_batch(vec![
+                BatchWrite::Delete {
+                    key: b"key-000".to_vec(),
+                },
+                BatchWrite::Put {
+                    key: b"key-100".to_vec(),
+                    value: b"batched".to_vec(),
+                    ttl: None,
+                },
+            ])
+            .unwrap();
+        assert_eq!(store.sequence(), 102);
+        assert_eq!(store.get(b"key-000".to_vec()).unwrap(), None);
+        assert_eq!(
+            store.get(b"key-100".to_vec()).unwrap(),
+            Some(b"batched".to_vec())
+        );
+        assert_eq!(
+            store.get(b"key-042".to_vec()).unwrap(),
+            Some(42u32.to_be_bytes().to_vec())
+        );
+
+        let keys: Vec<Bytes> = store
+            .scan(..)
+            .map(Result::unwrap)
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<Bytes> = (1..=100)
+            .map(|i| Bytes::from(format!("key-{i:03}")))
+            .collect();
+        assert_eq!(keys, expected, "Scans merge the shards into key order");
+        assert_eq!(
+            store
+                .snapshot()
+                .unwrap()
+                .scan_prefix(b"key-05")
+                .map(Result::unwrap)
+                .count(),
+            10
+        );
+
+        store.flush().unwrap();
+        for shard in 0..4 {
+            assert!(
+                shard_directory(dir.path(), shard)
+                    .read_dir()
+                    .unwrap()
+                    .count()
+                    > 0,
+                "Every shard holds some of the keys"
+            );
+        }
+        assert_eq!(
+            store
+                .tree_stats()
+                .levels
+                .iter()
+                .map(|l| l.files)
+                .sum::<usize>(),
+            4
+        );
+    }
+
+    #[test]
+    fn delete_prefix() {
+        let dir = TempDir::new("delete_prefix").unwrap();
+        let store = create_sharded(4, &dir);
+        for tenant in ["a", "b"] {
+            for i in 0..20 {
+                store
+                    .insert(format!("{tenant}/{i:02}").into_bytes(), b"v".to_vec())
+                    .unwrap();
+            }
+        }
+        store.flush().unwrap();
+        store.insert(b"a/new".to_vec(), b"v".to_vec()).unwrap();
+
+        store.delete_prefix(b"a/").unwrap();
+        assert_eq!(store.scan_prefix(b"a/").count(), 0);
+        assert_eq!(store.scan_prefix(b"b/").count(), 20);
+        assert_eq!(store.get(b"a/00".to_vec()).unwrap(), None);
+
+        // Without a range to tombstone, the keys are deleted one at a time
+        let dir = TempDir::new("delete_prefix_series").unwrap();
+        let store = ShardedLsm::new(
+            1,
+            WalConfig {
+                log_directory: dir.path().to_path_buf(),
+                ..WalConfig::default()
+            },
+            MemtableConfig::default(),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        )
+        .unwrap();
+        store.set_comparator(Arc::new(TimeSeries { prefix_len: 2 }));
+        store.restore().unwrap();
+        for key in ["a/1", "a/2", "b/1"] {
+            store
+                .insert(key.as_bytes().to_vec(), b"v".to_vec())
+                .unwrap();
+        }
+        assert_eq!(store.delete_prefix(b"a/1").unwrap(), 4);
+        let keys: Vec<Bytes> = store.scan(..).map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec![Bytes::from("a/2"), Bytes::from("b/1")]);
+    }
+
+    #[test]
+    fn shard_count_is_fixed() {
+        let dir = TempDir::new("shard_count_is_fixed").unwrap();
+        let store = create_sharded(2, &dir);
+        store.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+        store.close().unwrap();
+        drop(store);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(SHARDS_FILE_NAME)).unwrap(),
+            "2\n"
+        );
+
+        let store = ShardedLsm::new(
+            3,
+            WalConfig {
+                log_directory: dir.path().to_path_buf(),
+                ..WalConfig::default()
+            },
+            MemtableConfig::default(),
+            SstableConfig::default(),
+            CompactionConfig::default(),
+            WriteStallConfig::default(),
+        );
+        assert!(matches!(
+            store.err(),
+            Some(ChipmunkError::ShardCountMismatch {
+                recorded: 2,
+                configured: 3,
+                ..
+            })
+        ));
+        assert!(
+            !shard_directory(dir.path(), 2).exists(),
+            "No shard is created once the count mismatches"
+        );
+
+        let store = create_sharded(2, &dir);
+        assert_eq!(store.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert!(matches!(store.subscribe(), Err(ChipmunkError::Sharded(_))));
+        assert!(matches!(
+            store.checkpoint(&dir.path().join("checkpoint")),
+            Err(ChipmunkError::Sharded(_))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+// TODO: remove once used in other components
+#![allow(dead_code)]
+
+//! Point-in-time views of the tree.
+//!
+//! Every write is assigned the next sequence number as it is applied. A
+//! [`Snapshot`] pins the sequence number it was taken at along with the
+//! memtables and SSTables which held the tree at that point, so reads through
+//! it are unaffected by later writes, flushes and compactions.
+
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    comparator::Comparator,
+    lsm::{merge_live, prefix_bounds, ScanSource},
+    memtable::{self, unix_millis, Entry, Memtable, RangeTombstone},
+    sstable::Sstable,
+    transform::ValueTransform,
+    value_log::ValueLogReader,
+    ChipmunkError,
+};
+
+/// A consistent, read-only view of the tree as of a single sequence number,
+/// see [`Lsm::snapshot`](crate::lsm::Lsm::snapshot).
+pub struct Snapshot {
+    sequence: u64,
+    /// Copy of the memtable which was accepting writes.
+    active: memtable::Snapshot,
+    /// Frozen memtables, oldest first. These no longer accept writes so are
+    /// shared rather than copied.
+    frozen: Vec<Arc<Memtable>>,
+    /// SSTables, oldest first.
+    tables: Vec<Arc<Sstable>>,
+    /// Keeps the value log files which the SSTables point to readable.
+    value_log: ValueLogReader,
+    value_transform: Option<Arc<dyn ValueTransform>>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        sequence: u64,
+        active: memtable::Snapshot,
+        frozen: Vec<Arc<Memtable>>,
+        tables: Vec<Arc<Sstable>>,
+        value_log: ValueLogReader,
+        value_transform: Option<Arc<dyn ValueTransform>>,
+        comparator: Arc<dyn Comparator>,
+    ) -> Self {
+        Self {
+            sequence,
+            active,
+            frozen,
+            tables,
+            value_log,
+            value_transform,
+            comparator,
+        }
+    }
+
+    /// Sequence number of the last write which is visible through the
+    /// snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Get a value as of the snapshot.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ChipmunkError> {
+        let value = self.get_encoded(key)?;
+        value
+            .map(|value| match &self.value_transform {
+                Some(transform) => transform.decode(value),
+                None => Ok(value),
+            })
+            .transpose()
+    }
+
+    fn get_encoded(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ChipmunkError> {
+        if let Some(entry) = self.active.get_entry(key) {
+            return Ok(entry.map(|v| v.to_vec()));
+        }
+        for memtable in self.frozen.iter().rev() {
+            if let Some(entry) = memtable.get_entry(key) {
+                return Ok(entry.map(|v| v.to_vec()));
+            }
+        }
+
+        let now = unix_millis();
+        for table in self.tables.iter().rev() {
+            if !table.metadata().may_contain(key, &*self.comparator) {
+                continue;
+            }
+            if let Some(entry) = table.get(key)? {
+                let value = self.value_log.live_value(&entry, now)?;
+                return Ok(value.map(|v| v.to_vec()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate over the live entries whose keys fall within the given range,
+    /// in key order, as of the snapshot. See
+    /// [`Lsm::scan`](crate::lsm::Lsm::scan).
+    pub fn scan<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let now = unix_millis();
+
+        let mut sources: Vec<(ScanSource, Vec<RangeTombstone>)> = Vec::new();
+        for table in &self.tables {
+            if table.metadata().overlaps(&bounds, &*self.comparator) {
+                sources.push((
+                    Box::new(table.range(bounds.clone())),
+                    table.metadata().range_tombstones.clone(),
+                ));
+            }
+        }
+        for memtable in &self.frozen {
+            sources.push((
+                Box::new(memtable.range(bounds.clone()).map(to_entry)),
+                memtable.range_tombstones(),
+            ));
+        }
+        sources.push((
+            Box::new(self.active.range(bounds).map(to_entry)),
+            self.active.range_tombstones().to_vec(),
+        ));
+
+        merge_live(
+            sources,
+            now,
+            self.value_log.clone(),
+            self.value_transform.clone(),
+            Arc::clone(&self.comparator),
+        )
+    }
+
+    /// Iterate over the live entries whose keys start with the given prefix,
+    /// in key order, as of the snapshot.
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let prefix = Bytes::copy_from_slice(prefix);
+        self.scan(prefix_bounds(&*self.comparator, &prefix))
+            .filter(move |result| !matches!(result, Ok((key, _)) if !key.starts_with(&prefix)))
+    }
+
+    /// Iterate over the live entries whose keys start with the given prefix
+    /// and come after the given key, in key order, as of the snapshot. Used
+    /// to carry on a prefix scan from the last key it read.
+    pub fn scan_prefix_after(
+        &self,
+        prefix: &[u8],
+        after: &[u8],
+    ) -> impl Iterator<Item = Result<(Bytes, Bytes), ChipmunkError>> + '_ {
+        let prefix = Bytes::copy_from_slice(prefix);
+        let after = Bytes::copy_from_slice(after);
+        let (start, end) = prefix_bounds(&*self.comparator, &prefix);
+        let start = match &start {
+            Bound::Included(key) | Bound::Excluded(key)
+                if self.comparator.compare(&after, key).is_lt() =>
+            {
+                start
+            }
+            _ => Bound::Excluded(after),
+        };
+        self.scan((start, end))
+            .filter(move |result| !matches!(result, Ok((key, _)) if !key.starts_with(&prefix)))
+    }
+}
+
+/// Memtables resolve expiry as they are read, so their entries only need
+/// wrapping to be merged with those of SSTables.
+fn to_entry((key, value): (Bytes, Option<Bytes>)) -> Result<(Bytes, Entry), ChipmunkError> {
+    Ok((
+        key,
+        Entry {
+            value,
+            expires_at: None,
+            separated: false,
+            stamp: None,
+        },
+    ))
+}
@@ -0,0 +1,2282 @@
+//! Sorted String Tables (SSTables), the immutable on-disk format which
+//! memtables are flushed to and compaction produces.
+//!
+//! An SSTable is laid out as a series of data blocks holding entries sorted by
+//! key, followed by an index block, a metadata block and a fixed-size footer:
+//!
+//! ```text
+//! +--------------+-----+--------------+-------------+------------+--------+
+//! | data block 0 | ... | data block n | index block | meta block | footer |
+//! +--------------+-----+--------------+-------------+------------+--------+
+//! ```
+//!
+//! Every data block is followed by a trailer recording the compression applied
+//! to it and a CRC32C checksum of the stored block and compression type.
+//! Blocks which do not shrink when compressed are stored as-is, so the
+//! compression can vary from block to block. Once decompressed, each entry
+//! within a data block is encoded as:
+//!
+//! ```text
+//! | key len (u32) | key | flags (u8) | [expires at (u64)] | [sequence (u64) | written at (u64)] | [value len (u32) | value] |
+//! ```
+//!
+//! The sequence number and time of the write which produced an entry, its
+//! [`WriteStamp`], are recorded from version 2 onwards.
+//!
+//! Large values may be held in the value log instead, in which case the value
+//! is an encoded [`ValuePointer`](crate::value_log::ValuePointer) to it and
+//! the entry is flagged as separated.
+//!
+//! The index block starts with a byte recording its kind. A flat index holds
+//! the last key, offset and size of every data block so that a reader can find
+//! the only block which may contain a key. Large tables can instead partition
+//! their index, writing the block handles out as a series of index partitions
+//! after the data blocks, each followed by its CRC32C checksum. The index block
+//! then holds the last key, offset and size of every partition, so only the
+//! small top level and a single partition are needed to find a block. The meta
+//! block describes the table as a whole, see [`SstableMetadata`]:
+//!
+//! ```text
+//! | min key len (u32) | min key | max key len (u32) | max key | entries (u64) | tombstones (u64) | created at (u64) | [range tombstones] | [bloom filter] |
+//! ```
+//!
+//! Tables holding range deletions, see [`RangeTombstone`], follow this with
+//! them. This section is omitted when there are none, unless a bloom filter
+//! follows in which case the count is written as zero:
+//!
+//! ```text
+//! | count (u32) | start len (u32) | start | end len (u32) | end | ... |
+//! ```
+//!
+//! The meta block ends with a [`BloomFilter`] over every key the table holds,
+//! tombstones included, so that lookups can skip tables which do not hold the
+//! key without reading any of their blocks. Tables written without a filter
+//! omit this section:
+//!
+//! ```text
+//! | filter len (u32) | filter |
+//! ```
+//!
+//! The footer records where the index block lives, the size of the meta block
+//! which immediately follows it and the checksums of both, alongside the
+//! format version and a magic number identifying the file. The footer carries
+//! a checksum of its own fields so a damaged footer is never trusted.
+//!
+//! ```text
+//! | index offset (u64) | index size (u64) | index crc (u32) | meta size (u64) | meta crc (u32) | version (u32) | footer crc (u32) | magic (u64) |
+//! ```
+//!
+//! All integers are big endian.
+//!
+//! Tables written before the format was versioned hold nothing but a
+//! serialized map of keys to values, with no footer. These are treated as
+//! version [`LEGACY_SSTABLE_FORMAT_VERSION`] and can still be opened, their
+//! entries being held in memory as they carry no index.
+
+// TODO: remove once used in other components
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Deref, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+pub use crate::filter::BloomFilter;
+pub use crate::memtable::{Entry, RangeTombstone, WriteStamp};
+use crate::{
+    block_cache::{Block, BlockCache},
+    comparator::{self, Comparator},
+    config::{Compression, SstableConfig, DEFAULT_BLOOM_BITS_PER_KEY, DEFAULT_SSTABLE_BLOCK_SIZE},
+    filter, fs,
+    memtable::unix_millis,
+    ChipmunkError,
+};
+
+/// Magic number which ends every SSTable, "chipmunk" in ASCII.
+pub const SSTABLE_MAGIC: u64 = u64::from_be_bytes(*b"chipmunk");
+
+/// Version of the SSTable format which is written.
+pub const SSTABLE_FORMAT_VERSION: u32 = 2;
+
+/// Oldest version of the SSTable format, with a footer, which can be read.
+pub const SSTABLE_MIN_FORMAT_VERSION: u32 = 1;
+
+/// Version of tables written before the format was versioned, which are a
+/// bincode serialized map of keys to optional values.
+pub const LEGACY_SSTABLE_FORMAT_VERSION: u32 = 0;
+
+/// Compression type (u8) and checksum (u32) following every data block.
+const BLOCK_TRAILER_SIZE: u64 = 1 + 4;
+
+/// Checksum (u32) following every index partition.
+const PARTITION_TRAILER_SIZE: u64 = 4;
+
+/// The index block holds the handle of every data block.
+const INDEX_FLAT: u8 = 0;
+/// The index block holds the handles of index partitions.
+const INDEX_PARTITIONED: u8 = 1;
+
+/// Index offset (u64), index size (u64), index checksum (u32), meta size (u64),
+/// meta checksum (u32), format version (u32), footer checksum (u32) and magic
+/// (u64).
+const FOOTER_SIZE: u64 = 8 + 8 + 4 + 8 + 4 + 4 + 4 + 8;
+
+/// The entry holds a value, otherwise it is a tombstone.
+const ENTRY_FLAG_VALUE: u8 = 1;
+/// The entry holds an expiry.
+const ENTRY_FLAG_EXPIRY: u8 = 1 << 1;
+/// The entry's value is a pointer into the value log.
+const ENTRY_FLAG_SEPARATED: u8 = 1 << 2;
+/// The entry holds a [`WriteStamp`].
+const ENTRY_FLAG_STAMP: u8 = 1 << 3;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_LZ4: u8 = 1;
+const COMPRESSION_ZSTD: u8 = 2;
+const COMPRESSION_SNAPPY: u8 = 3;
+
+/// Location of a data block within an SSTable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockHandle {
+    /// Largest key held within the block.
+    last_key: Bytes,
+    offset: u64,
+    /// Size of the, possibly compressed, block excluding its trailer.
+    size: u64,
+}
+
+/// Properties describing an SSTable as a whole, recorded when it is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstableMetadata {
+    /// Smallest key held, empty when the table holds no entries.
+    pub min_key: Bytes,
+    /// Largest key held, empty when the table holds no entries.
+    pub max_key: Bytes,
+    /// Number of entries held, including tombstones.
+    pub entries: u64,
+    /// Number of entries which are tombstones.
+    pub tombstones: u64,
+    /// Unix timestamp, in milliseconds, at which the table was written.
+    pub created_at: u64,
+    /// Ranges deleted by the table, sorted by their start key.
+    pub range_tombstones: Vec<RangeTombstone>,
+    /// Filter over every key held, absent when the table was written without
+    /// one.
+    pub filter: Option<BloomFilter>,
+}
+
+/// Keys are compared by the [`Comparator`] which the table was written with.
+impl SstableMetadata {
+    /// Whether the table holds an entry for, or deletes, the key. A table for
+    /// which this is false can be skipped.
+    pub fn may_contain(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        (self.in_key_range(key, comparator) && self.bloom_check(key, comparator) != Some(false))
+            || self.is_range_deleted(key, comparator)
+    }
+
+    /// Outcome of checking the key against the table's bloom filter, `None`
+    /// when the filter is not consulted as the table has none or its key range
+    /// already rules the key out.
+    pub fn bloom_check(&self, key: &[u8], comparator: &dyn Comparator) -> Option<bool> {
+        if !self.in_key_range(key, comparator) {
+            return None;
+        }
+        self.filter.as_ref().map(|filter| filter.may_contain(key))
+    }
+
+    /// Whether the key falls within the range of keys which the table holds
+    /// entries for.
+    fn in_key_range(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        self.entries > 0
+            && comparator.compare(&self.min_key, key).is_le()
+            && comparator.compare(key, &self.max_key).is_le()
+    }
+
+    /// Whether the key falls within one of the table's range tombstones.
+    pub fn is_range_deleted(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        self.range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key, comparator))
+    }
+
+    /// The smallest and largest keys which the table holds entries for or
+    /// deletes, `None` when it does neither. The end of a range tombstone is
+    /// included, as it bounds the range.
+    pub fn key_span(&self, comparator: &dyn Comparator) -> Option<(Bytes, Bytes)> {
+        let entries = (self.entries > 0).then_some((&self.min_key, &self.max_key));
+        let tombstones = self.range_tombstones.iter().map(|t| (&t.start, &t.end));
+        let mut span: Option<(Bytes, Bytes)> = None;
+        for (start, end) in entries.into_iter().chain(tombstones) {
+            let (min, max) = span.get_or_insert_with(|| (start.clone(), end.clone()));
+            if comparator.compare(start, min).is_lt() {
+                *min = start.clone();
+            }
+            if comparator.compare(end, max).is_gt() {
+                *max = end.clone();
+            }
+        }
+        span
+    }
+
+    /// Whether any key within the given range may be held, or deleted, by the
+    /// table.
+    pub fn overlaps<R: RangeBounds<Bytes>>(&self, range: &R, comparator: &dyn Comparator) -> bool {
+        if self
+            .range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.overlaps(range, comparator))
+        {
+            return true;
+        }
+        self.entries > 0
+            && comparator::after_start(comparator, &self.max_key, range.start_bound())
+            && comparator::before_end(comparator, &self.min_key, range.end_bound())
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(self.min_key.len() as u32)
+            .unwrap();
+        buf.write_all(&self.min_key).unwrap();
+        buf.write_u32::<BigEndian>(self.max_key.len() as u32)
+            .unwrap();
+        buf.write_all(&self.max_key).unwrap();
+        buf.write_u64::<BigEndian>(self.entries).unwrap();
+        buf.write_u64::<BigEndian>(self.tombstones).unwrap();
+        buf.write_u64::<BigEndian>(self.created_at).unwrap();
+        if !self.range_tombstones.is_empty() || self.filter.is_some() {
+            buf.write_u32::<BigEndian>(self.range_tombstones.len() as u32)
+                .unwrap();
+            for tombstone in &self.range_tombstones {
+                for key in [&tombstone.start, &tombstone.end] {
+                    buf.write_u32::<BigEndian>(key.len() as u32).unwrap();
+                    buf.write_all(key).unwrap();
+                }
+            }
+        }
+        if let Some(filter) = &self.filter {
+            buf.write_u32::<BigEndian>(filter.size() as u32).unwrap();
+            filter.encode(&mut buf);
+        }
+        buf
+    }
+
+    pub(crate) fn decode(data: Bytes) -> Result<Self, &'static str> {
+        let mut cursor = Cursor::new(data);
+        let len = cursor.u32()? as usize;
+        let min_key = cursor.take(len)?;
+        let len = cursor.u32()? as usize;
+        let max_key = cursor.take(len)?;
+        let mut metadata = Self {
+            min_key,
+            max_key,
+            entries: cursor.u64()?,
+            tombstones: cursor.u64()?,
+            created_at: cursor.u64()?,
+            range_tombstones: Vec::new(),
+            filter: None,
+        };
+        if !cursor.is_empty() {
+            for _ in 0..cursor.u32()? {
+                let len = cursor.u32()? as usize;
+                let start = cursor.take(len)?;
+                let len = cursor.u32()? as usize;
+                let end = cursor.take(len)?;
+                metadata
+                    .range_tombstones
+                    .push(RangeTombstone { start, end });
+            }
+        }
+        if !cursor.is_empty() {
+            let len = cursor.u32()? as usize;
+            metadata.filter = Some(BloomFilter::decode(cursor.take(len)?)?);
+        }
+        if !cursor.is_empty() {
+            return Err("trailing bytes in meta block");
+        }
+        Ok(metadata)
+    }
+}
+
+/// Writes an SSTable from entries which are provided in ascending key order.
+///
+/// This is used by the engine when flushing and compacting, and can be used
+/// directly to produce files offline, for example to bulk load a dataset.
+///
+/// ```no_run
+/// use std::path::Path;
+/// use chipmunk::sstable::SstableBuilder;
+///
+/// let mut builder = SstableBuilder::new(Path::new("/tmp/bulk")).unwrap();
+/// builder.put(b"apple", b"red").unwrap();
+/// builder.delete(b"banana").unwrap();
+/// builder.put(b"cherry", b"red").unwrap();
+/// builder.finish().unwrap();
+/// ```
+pub struct SstableBuilder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    block_size: usize,
+    compression: Compression,
+    /// Size beyond which the index is split into partitions, if at all.
+    index_partition_size: Option<usize>,
+    /// Bloom filter bits stored for each key, zero writing no filter.
+    bloom_bits_per_key: usize,
+    /// Ordering which keys must be added in.
+    comparator: Arc<dyn Comparator>,
+
+    /// The data block which is currently being filled.
+    block: Vec<u8>,
+    /// Handles of the data blocks which have already been written.
+    index: Vec<BlockHandle>,
+    /// Offset at which the next data block will be written.
+    offset: u64,
+    /// The first key which was added, the smallest in the table.
+    first_key: Option<Bytes>,
+    /// The most recently added key, used to enforce ordering.
+    last_key: Option<Bytes>,
+    entries: u64,
+    tombstones: u64,
+    range_tombstones: Vec<RangeTombstone>,
+    /// Filter hash of every key added, see [`filter::hash`].
+    key_hashes: Vec<u64>,
+}
+
+impl SstableBuilder {
+    /// Create a new SSTable at the given path, replacing any existing file.
+    ///
+    /// The table is written to a temporary file alongside `path`, which only
+    /// replaces it once [`SstableBuilder::finish`] has written it in full.
+    pub fn new(path: &Path) -> Result<Self, ChipmunkError> {
+        let file = File::create(fs::temp_path(path)).map_err(ChipmunkError::SstableWrite)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            block_size: DEFAULT_SSTABLE_BLOCK_SIZE,
+            compression: Compression::None,
+            index_partition_size: None,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            comparator: comparator::bytewise(),
+            block: Vec::with_capacity(DEFAULT_SSTABLE_BLOCK_SIZE),
+            index: Vec::new(),
+            offset: 0,
+            first_key: None,
+            last_key: None,
+            entries: 0,
+            tombstones: 0,
+            range_tombstones: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    /// Create a new SSTable at the given path, using the block size,
+    /// compression, index partitioning and bloom filter size from the provided
+    /// [`SstableConfig`].
+    pub fn with_config(path: &Path, config: &SstableConfig) -> Result<Self, ChipmunkError> {
+        let builder = Self::new(path)?
+            .with_block_size(config.block_size)
+            .with_compression(config.compression)
+            .with_bloom_bits_per_key(config.bloom_bits_per_key);
+        Ok(match config.index_partition_size {
+            Some(size) => builder.with_index_partition_size(size),
+            None => builder,
+        })
+    }
+
+    /// Set the size at which data blocks are closed.
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set the compression applied to data blocks.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Partition the index once it grows beyond the given size, in bytes, with
+    /// each partition being roughly this size.
+    pub fn with_index_partition_size(mut self, index_partition_size: usize) -> Self {
+        self.index_partition_size = Some(index_partition_size);
+        self
+    }
+
+    /// Set the number of bloom filter bits stored for each key, zero writes
+    /// the table without a filter.
+    pub fn with_bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    /// Set the [`Comparator`] which orders the keys of the table, the table
+    /// must then be read with the same one.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    /// Add an entry to the SSTable.
+    ///
+    /// Keys must be added in strictly ascending order.
+    pub fn add(&mut self, key: &[u8], entry: &Entry) -> Result<(), ChipmunkError> {
+        if self
+            .last_key
+            .as_ref()
+            .is_some_and(|last| self.comparator.compare(key, last).is_le())
+        {
+            return Err(ChipmunkError::SstableUnsortedKey {
+                path: self.path.clone(),
+            });
+        }
+
+        encode_entry(&mut self.block, key, entry);
+        if self.bloom_bits_per_key > 0 {
+            self.key_hashes.push(filter::hash(key));
+        }
+        let key = Bytes::copy_from_slice(key);
+        self.first_key.get_or_insert_with(|| key.clone());
+        self.last_key = Some(key);
+        self.entries += 1;
+        if entry.value.is_none() {
+            self.tombstones += 1;
+        }
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Add a key-value pair to the SSTable.
+    ///
+    /// Keys must be added in strictly ascending order.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), ChipmunkError> {
+        self.add(
+            key,
+            &Entry {
+                value: Some(Bytes::copy_from_slice(value)),
+                expires_at: None,
+                separated: false,
+                stamp: None,
+            },
+        )
+    }
+
+    /// Add a key-value pair which expires at the given unix timestamp, in
+    /// milliseconds.
+    ///
+    /// Keys must be added in strictly ascending order.
+    pub fn put_with_expiry(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> Result<(), ChipmunkError> {
+        self.add(
+            key,
+            &Entry {
+                value: Some(Bytes::copy_from_slice(value)),
+                expires_at: Some(expires_at),
+                separated: false,
+                stamp: None,
+            },
+        )
+    }
+
+    /// Add a tombstone, marking the key as deleted.
+    ///
+    /// Keys must be added in strictly ascending order.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), ChipmunkError> {
+        self.add(
+            key,
+            &Entry {
+                value: None,
+                expires_at: None,
+                separated: false,
+                stamp: None,
+            },
+        )
+    }
+
+    /// Add a range tombstone, marking every key from `start`, inclusive, up to
+    /// `end`, exclusive, as deleted within older tables. Entries added to this
+    /// table are unaffected by it.
+    ///
+    /// Range tombstones can be added in any order, empty ranges are ignored.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        if self.comparator.compare(start, end).is_lt() {
+            self.range_tombstones.push(RangeTombstone {
+                start: Bytes::copy_from_slice(start),
+                end: Bytes::copy_from_slice(end),
+            });
+        }
+    }
+
+    /// Number of entries which have been added.
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Write out the data block that is currently being filled.
+    fn finish_block(&mut self) -> Result<(), ChipmunkError> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let (compression, block) = compress(self.compression, &self.block);
+        let crc = crc32c::crc32c_append(crc32c::crc32c(&block), &[compression]);
+        self.file
+            .write_all(&block)
+            .and_then(|_| self.file.write_all(&[compression]))
+            .and_then(|_| self.file.write_u32::<BigEndian>(crc))
+            .map_err(ChipmunkError::SstableWrite)?;
+        let size = block.len() as u64;
+        self.index.push(BlockHandle {
+            last_key: self
+                .last_key
+                .clone()
+                .expect("A non-empty block has a last key"),
+            offset: self.offset,
+            size,
+        });
+        self.offset += size + BLOCK_TRAILER_SIZE;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Complete the SSTable, writing the index block, meta block and footer
+    /// before syncing the file to disk. The metadata which was recorded is
+    /// returned.
+    pub fn finish(mut self) -> Result<SstableMetadata, ChipmunkError> {
+        self.finish_block()?;
+
+        let handles = std::mem::take(&mut self.index);
+        let mut flat = Vec::new();
+        for handle in &handles {
+            encode_handle(&mut flat, handle);
+        }
+        let mut index = Vec::new();
+        match self.index_partition_size {
+            Some(partition_size) if flat.len() > partition_size => {
+                index.push(INDEX_PARTITIONED);
+                let mut partition = Vec::new();
+                for handle in &handles {
+                    encode_handle(&mut partition, handle);
+                    if partition.len() >= partition_size {
+                        let top = self.write_partition(&partition, &handle.last_key)?;
+                        encode_handle(&mut index, &top);
+                        partition.clear();
+                    }
+                }
+                if let Some(last) = handles.last().filter(|_| !partition.is_empty()) {
+                    let top = self.write_partition(&partition, &last.last_key)?;
+                    encode_handle(&mut index, &top);
+                }
+            }
+            _ => {
+                index.push(INDEX_FLAT);
+                index.extend(flat);
+            }
+        }
+
+        let metadata = SstableMetadata {
+            min_key: self.first_key.clone().unwrap_or_default(),
+            max_key: self.last_key.clone().unwrap_or_default(),
+            entries: self.entries,
+            tombstones: self.tombstones,
+            created_at: unix_millis(),
+            range_tombstones: {
+                let mut range_tombstones = std::mem::take(&mut self.range_tombstones);
+                range_tombstones.sort_by(|a, b| {
+                    self.comparator
+                        .compare(&a.start, &b.start)
+                        .then_with(|| self.comparator.compare(&a.end, &b.end))
+                });
+                range_tombstones.dedup();
+                range_tombstones
+            },
+            filter: (self.bloom_bits_per_key > 0)
+                .then(|| BloomFilter::build(&self.key_hashes, self.bloom_bits_per_key)),
+        };
+        let meta = metadata.encode();
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE as usize);
+        footer.write_u64::<BigEndian>(self.offset).unwrap();
+        footer.write_u64::<BigEndian>(index.len() as u64).unwrap();
+        footer
+            .write_u32::<BigEndian>(crc32c::crc32c(&index))
+            .unwrap();
+        footer.write_u64::<BigEndian>(meta.len() as u64).unwrap();
+        footer
+            .write_u32::<BigEndian>(crc32c::crc32c(&meta))
+            .unwrap();
+        footer
+            .write_u32::<BigEndian>(SSTABLE_FORMAT_VERSION)
+            .unwrap();
+        footer
+            .write_u32::<BigEndian>(crc32c::crc32c(&footer))
+            .unwrap();
+        footer.write_u64::<BigEndian>(SSTABLE_MAGIC).unwrap();
+
+        self.file
+            .write_all(&index)
+            .map_err(ChipmunkError::SstableWrite)?;
+        self.file
+            .write_all(&meta)
+            .map_err(ChipmunkError::SstableWrite)?;
+        self.file
+            .write_all(&footer)
+            .map_err(ChipmunkError::SstableWrite)?;
+        let file = self
+            .file
+            .into_inner()
+            .map_err(|e| ChipmunkError::SstableWrite(e.into_error()))?;
+        file.sync_all().map_err(ChipmunkError::SstableWrite)?;
+        fs::publish(&fs::temp_path(&self.path), &self.path).map_err(ChipmunkError::SstableWrite)?;
+
+        Ok(metadata)
+    }
+
+    /// Write out an encoded index partition, returning the handle which the
+    /// top level of the index holds for it.
+    fn write_partition(
+        &mut self,
+        partition: &[u8],
+        last_key: &Bytes,
+    ) -> Result<BlockHandle, ChipmunkError> {
+        self.file
+            .write_all(partition)
+            .and_then(|_| self.file.write_u32::<BigEndian>(crc32c::crc32c(partition)))
+            .map_err(ChipmunkError::SstableWrite)?;
+        let handle = BlockHandle {
+            last_key: last_key.clone(),
+            offset: self.offset,
+            size: partition.len() as u64,
+        };
+        self.offset += handle.size + PARTITION_TRAILER_SIZE;
+        Ok(handle)
+    }
+}
+
+/// The index of an open [`Sstable`].
+#[derive(Debug)]
+enum Index {
+    /// Handles of every data block.
+    Flat(Arc<Vec<BlockHandle>>),
+    /// Handles of the index partitions, which are read as they are needed.
+    Partitioned(Vec<BlockHandle>),
+    /// Every entry of a legacy table, which has no blocks to index.
+    Legacy(Block),
+}
+
+/// Where the index and meta blocks of an [`Sstable`] live, as recorded by
+/// its footer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SstableFooter {
+    pub index_offset: u64,
+    pub index_size: u64,
+    /// Size of the meta block, which immediately follows the index block.
+    pub meta_size: u64,
+}
+
+/// How the index of an [`Sstable`] is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// The index block holds the handle of every data block.
+    Flat,
+    /// The index block holds the handles of index partitions.
+    Partitioned { partitions: usize },
+    /// A legacy table, which has no index.
+    Legacy,
+}
+
+/// A data block of an [`Sstable`], as recorded by its index, see
+/// [`Sstable::data_blocks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataBlockInfo {
+    /// Largest key held within the block.
+    pub last_key: Bytes,
+    pub offset: u64,
+    /// Size of the, possibly compressed, block excluding its trailer.
+    pub size: u64,
+    /// The index partition which refers to the block, if partitioned.
+    pub partition: Option<usize>,
+}
+
+/// A read-only handle to an SSTable on disk.
+///
+/// Only the footer and index block are held in memory, data blocks are read
+/// from disk as they are needed, or served from a shared [`BlockCache`] when
+/// one is attached.
+#[derive(Debug)]
+pub struct Sstable {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Index,
+    metadata: SstableMetadata,
+    version: u32,
+    /// Absent for legacy tables, which have no footer.
+    footer: Option<SstableFooter>,
+    /// Cache of decoded blocks, alongside the ID this file's blocks are cached
+    /// under.
+    cache: Option<(Arc<BlockCache>, u64)>,
+    /// Ordering of the keys held.
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Sstable {
+    /// Open an existing SSTable, validating its footer and reading its index.
+    ///
+    /// Data block checksums are verified as each block is read, use
+    /// [`Sstable::open_verified`] to check every block upfront. Files without
+    /// a footer are opened as legacy tables, see
+    /// [`LEGACY_SSTABLE_FORMAT_VERSION`].
+    pub fn open(path: &Path) -> Result<Self, ChipmunkError> {
+        let mut file = File::open(path).map_err(ChipmunkError::SstableRead)?;
+        let len = file.metadata().map_err(ChipmunkError::SstableRead)?.len();
+        let corrupt = |reason: &str| ChipmunkError::SstableCorrupt {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        };
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        if len >= FOOTER_SIZE {
+            file.seek(SeekFrom::Start(len - FOOTER_SIZE))
+                .and_then(|_| file.read_exact(&mut footer))
+                .map_err(ChipmunkError::SstableRead)?;
+        }
+        if len < FOOTER_SIZE || BigEndian::read_u64(&footer[40..48]) != SSTABLE_MAGIC {
+            return match Self::open_legacy(path, file)? {
+                Some(sstable) => Ok(sstable),
+                None if len < FOOTER_SIZE => Err(corrupt("file is smaller than the footer")),
+                None => Err(corrupt("bad magic number")),
+            };
+        }
+
+        let index_offset = BigEndian::read_u64(&footer[0..8]);
+        let index_size = BigEndian::read_u64(&footer[8..16]);
+        let index_crc = BigEndian::read_u32(&footer[16..20]);
+        let meta_size = BigEndian::read_u64(&footer[20..28]);
+        let meta_crc = BigEndian::read_u32(&footer[28..32]);
+        let version = BigEndian::read_u32(&footer[32..36]);
+        let footer_crc = BigEndian::read_u32(&footer[36..40]);
+        if footer_crc != crc32c::crc32c(&footer[..36]) {
+            return Err(corrupt("footer checksum mismatch"));
+        }
+        if !(SSTABLE_MIN_FORMAT_VERSION..=SSTABLE_FORMAT_VERSION).contains(&version) {
+            return Err(ChipmunkError::UnsupportedFormatVersion {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+        if index_offset
+            .checked_add(index_size)
+            .and_then(|end| end.checked_add(meta_size))
+            .is_none_or(|end| end > len - FOOTER_SIZE)
+        {
+            return Err(corrupt("index or meta block is out of bounds"));
+        }
+
+        let mut index = vec![0; index_size as usize];
+        let mut meta = vec![0; meta_size as usize];
+        file.seek(SeekFrom::Start(index_offset))
+            .and_then(|_| file.read_exact(&mut index))
+            .and_then(|_| file.read_exact(&mut meta))
+            .map_err(ChipmunkError::SstableRead)?;
+        if index_crc != crc32c::crc32c(&index) {
+            return Err(corrupt("index block checksum mismatch"));
+        }
+        if meta_crc != crc32c::crc32c(&meta) {
+            return Err(corrupt("meta block checksum mismatch"));
+        }
+        let index = match index.split_first() {
+            Some((&INDEX_FLAT, handles)) => Index::Flat(Arc::new(
+                decode_index(Bytes::copy_from_slice(handles)).map_err(corrupt)?,
+            )),
+            Some((&INDEX_PARTITIONED, handles)) => {
+                Index::Partitioned(decode_index(Bytes::copy_from_slice(handles)).map_err(corrupt)?)
+            }
+            _ => return Err(corrupt("unknown index kind")),
+        };
+        let metadata = SstableMetadata::decode(Bytes::from(meta)).map_err(corrupt)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            index,
+            metadata,
+            version,
+            footer: Some(SstableFooter {
+                index_offset,
+                index_size,
+                meta_size,
+            }),
+            cache: None,
+            comparator: comparator::bytewise(),
+        })
+    }
+
+    /// Open a table written before the format was versioned, returning `None`
+    /// if the file does not hold one.
+    fn open_legacy(path: &Path, mut file: File) -> Result<Option<Self>, ChipmunkError> {
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_end(&mut data))
+            .map_err(ChipmunkError::SstableRead)?;
+        let Some(mut entries) = decode_legacy(&data) else {
+            return Ok(None);
+        };
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let created_at = file
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_millis() as u64);
+        let metadata = SstableMetadata {
+            min_key: entries.first().map(|(k, _)| k.clone()).unwrap_or_default(),
+            max_key: entries.last().map(|(k, _)| k.clone()).unwrap_or_default(),
+            entries: entries.len() as u64,
+            tombstones: entries.iter().filter(|(_, e)| e.value.is_none()).count() as u64,
+            created_at,
+            range_tombstones: Vec::new(),
+            filter: None,
+        };
+
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            index: Index::Legacy(Arc::new(entries)),
+            metadata,
+            version: LEGACY_SSTABLE_FORMAT_VERSION,
+            footer: None,
+            cache: None,
+            comparator: comparator::bytewise(),
+        }))
+    }
+
+    /// Serve data blocks through the given cache, under the provided file ID.
+    ///
+    /// The ID must be unique amongst the files sharing the cache.
+    pub fn with_block_cache(mut self, cache: Arc<BlockCache>, file_id: u64) -> Self {
+        self.cache = Some((cache, file_id));
+        self
+    }
+
+    /// Read the table with the [`Comparator`] it was written with, rather
+    /// than ordering keys by their bytes.
+    ///
+    /// Legacy tables hold their entries unsorted, so are sorted again.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        if let Index::Legacy(entries) = &mut self.index {
+            let entries = Arc::make_mut(entries);
+            entries.sort_by(|(a, _), (b, _)| comparator.compare(a, b));
+            if let (Some((min, _)), Some((max, _))) = (entries.first(), entries.last()) {
+                self.metadata.min_key = min.clone();
+                self.metadata.max_key = max.clone();
+            }
+        }
+        self.comparator = comparator;
+        self
+    }
+
+    /// Open an existing SSTable, as with [`Sstable::open`], additionally
+    /// verifying the checksum of every data block before returning.
+    pub fn open_verified(path: &Path) -> Result<Self, ChipmunkError> {
+        let sstable = Self::open(path)?;
+        sstable.verify_checksums()?;
+        Ok(sstable)
+    }
+
+    /// Verify the checksum of every data block, without decoding them.
+    pub fn verify_checksums(&self) -> Result<(), ChipmunkError> {
+        for partition in 0..self.partition_count() {
+            let handles = self.read_partition(partition)?.unwrap_or_default();
+            for handle in handles.iter() {
+                self.read_raw_block(handle)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of partitions within the index, a flat index being treated as a
+    /// single partition.
+    fn partition_count(&self) -> usize {
+        match &self.index {
+            Index::Flat(_) => 1,
+            Index::Legacy(_) => 0,
+            Index::Partitioned(partitions) => partitions.len(),
+        }
+    }
+
+    /// Position of the only index partition which may refer to the key.
+    fn find_partition(&self, key: &[u8]) -> usize {
+        match &self.index {
+            Index::Flat(_) | Index::Legacy(_) => 0,
+            Index::Partitioned(partitions) => partitions
+                .partition_point(|handle| self.comparator.compare(&handle.last_key, key).is_lt()),
+        }
+    }
+
+    /// Read the data block handles within a partition of the index, or `None`
+    /// when there is no such partition.
+    fn read_partition(
+        &self,
+        partition: usize,
+    ) -> Result<Option<Arc<Vec<BlockHandle>>>, ChipmunkError> {
+        let handle = match &self.index {
+            Index::Flat(handles) => return Ok((partition == 0).then(|| Arc::clone(handles))),
+            Index::Legacy(_) => return Ok(None),
+            Index::Partitioned(partitions) => match partitions.get(partition) {
+                Some(handle) => handle,
+                None => return Ok(None),
+            },
+        };
+
+        let mut data = vec![0; (handle.size + PARTITION_TRAILER_SIZE) as usize];
+        {
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(handle.offset))
+                .and_then(|_| file.read_exact(&mut data))
+                .map_err(ChipmunkError::SstableRead)?;
+        }
+        let crc = data.split_off(handle.size as usize);
+        let corrupt = |reason: String| ChipmunkError::SstableCorrupt {
+            path: self.path.clone(),
+            reason,
+        };
+        if BigEndian::read_u32(&crc) != crc32c::crc32c(&data) {
+            return Err(corrupt(format!(
+                "checksum mismatch in index partition at offset {}",
+                handle.offset
+            )));
+        }
+        let handles = decode_index(Bytes::from(data)).map_err(|e| corrupt(e.to_string()))?;
+        Ok(Some(Arc::new(handles)))
+    }
+
+    /// Properties of the table which were recorded when it was written.
+    pub fn metadata(&self) -> &SstableMetadata {
+        &self.metadata
+    }
+
+    /// Path of the underlying file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Version of the format which the table was written in.
+    pub fn format_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Location of the index and meta blocks, `None` for legacy tables.
+    pub fn footer(&self) -> Option<&SstableFooter> {
+        self.footer.as_ref()
+    }
+
+    /// How the index is laid out.
+    pub fn index_kind(&self) -> IndexKind {
+        match &self.index {
+            Index::Flat(_) => IndexKind::Flat,
+            Index::Partitioned(partitions) => IndexKind::Partitioned {
+                partitions: partitions.len(),
+            },
+            Index::Legacy(_) => IndexKind::Legacy,
+        }
+    }
+
+    /// Every data block in the order they are laid out, reading each
+    /// partition of a partitioned index. Legacy tables have none.
+    pub fn data_blocks(&self) -> Result<Vec<DataBlockInfo>, ChipmunkError> {
+        let partitioned = matches!(self.index, Index::Partitioned(_));
+        let mut blocks = Vec::new();
+        for partition in 0..self.partition_count() {
+            let handles = self.read_partition(partition)?.unwrap_or_default();
+            blocks.extend(handles.iter().map(|handle| DataBlockInfo {
+                last_key: handle.last_key.clone(),
+                offset: handle.offset,
+                size: handle.size,
+                partition: partitioned.then_some(partition),
+            }));
+        }
+        Ok(blocks)
+    }
+
+    /// Find the entry for a key, which may be a tombstone or have expired.
+    /// Keys which the table holds no entry for, but fall within one of its
+    /// range tombstones, are reported as tombstones.
+    ///
+    /// Keys outside of the table's key range are rejected without touching
+    /// any data block, otherwise the index is binary searched for the only
+    /// block which may hold the key, so at most a single data block, and index
+    /// partition, is read.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>, ChipmunkError> {
+        Ok(self.get_point(key)?.or_else(|| self.range_deletion(key)))
+    }
+
+    /// A tombstone for a key which falls within one of the table's range
+    /// tombstones.
+    fn range_deletion(&self, key: &[u8]) -> Option<Entry> {
+        self.metadata
+            .is_range_deleted(key, &*self.comparator)
+            .then_some(Entry {
+                value: None,
+                expires_at: None,
+                separated: false,
+                stamp: None,
+            })
+    }
+
+    /// Find the entry held for a key, ignoring range tombstones.
+    fn get_point(&self, key: &[u8]) -> Result<Option<Entry>, ChipmunkError> {
+        if !self.metadata.in_key_range(key, &*self.comparator) {
+            return Ok(None);
+        }
+        if let Index::Legacy(entries) = &self.index {
+            return Ok(self.search(entries, key));
+        }
+        let Some(handles) = self.read_partition(self.find_partition(key))? else {
+            return Ok(None);
+        };
+        let Some(handle) = handles.get(self.find_block(&handles, key)) else {
+            return Ok(None);
+        };
+
+        let entries = self.read_block(handle)?;
+        Ok(self.search(&entries, key))
+    }
+
+    /// Position of the only data block which may hold the key.
+    fn find_block(&self, handles: &[BlockHandle], key: &[u8]) -> usize {
+        handles.partition_point(|handle| self.comparator.compare(&handle.last_key, key).is_lt())
+    }
+
+    /// Binary search sorted entries for a key.
+    fn search(&self, entries: &[(Bytes, Entry)], key: &[u8]) -> Option<Entry> {
+        entries
+            .binary_search_by(|(k, _)| self.comparator.compare(k, key))
+            .ok()
+            .map(|i| entries[i].1.clone())
+    }
+
+    /// Find the entries for several keys, as with [`Sstable::get`], which
+    /// must be given in ascending order. Results are returned in the same
+    /// order as the keys.
+    ///
+    /// Neighbouring keys which fall within the same index partition, or data
+    /// block, share a single read of it.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Entry>>, ChipmunkError> {
+        let mut results = Vec::with_capacity(keys.len());
+        let mut partition: Option<(usize, Option<Arc<Vec<BlockHandle>>>)> = None;
+        let mut block: Option<(usize, usize, Block)> = None;
+        for key in keys {
+            if !self.metadata.in_key_range(key, &*self.comparator) {
+                results.push(None);
+                continue;
+            }
+            if let Index::Legacy(entries) = &self.index {
+                results.push(self.search(entries, key));
+                continue;
+            }
+
+            let p = self.find_partition(key);
+            if partition.as_ref().is_none_or(|(cached, _)| *cached != p) {
+                partition = Some((p, self.read_partition(p)?));
+            }
+            let Some((_, Some(handles))) = &partition else {
+                results.push(None);
+                continue;
+            };
+            let b = self.find_block(handles, key);
+            let Some(handle) = handles.get(b) else {
+                results.push(None);
+                continue;
+            };
+            if block
+                .as_ref()
+                .is_none_or(|(cached_p, cached_b, _)| (*cached_p, *cached_b) != (p, b))
+            {
+                block = Some((p, b, self.read_block(handle)?));
+            }
+            let (_, _, entries) = block.as_ref().expect("Block was just read");
+            results.push(self.search(entries, key));
+        }
+        Ok(results
+            .into_iter()
+            .zip(keys)
+            .map(|(found, key)| found.or_else(|| self.range_deletion(key)))
+            .collect())
+    }
+
+    /// Read every entry held within the SSTable, in ascending key order.
+    pub fn entries(&self) -> Result<Vec<(Bytes, Entry)>, ChipmunkError> {
+        self.iter().collect()
+    }
+
+    /// Iterate over every entry in ascending key order.
+    ///
+    /// Data blocks are read one at a time as the iterator advances, so only a
+    /// single block is held in memory at once.
+    pub fn iter(&self) -> Iter<'_> {
+        self.range::<std::ops::RangeFull>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within the given range, in
+    /// ascending key order.
+    ///
+    /// The index is used to skip straight to the first block which may hold
+    /// the start of the range, no blocks past the end of the range are read.
+    pub fn range<R: RangeBounds<Bytes>>(&self, range: R) -> Iter<'_> {
+        Self::range_of(TableRef::Borrowed(self), range)
+    }
+
+    /// Iterate over the entries whose keys fall within the given range, as
+    /// with [`Sstable::range`], holding a reference to the table rather than
+    /// borrowing it so that the iterator can outlive the caller's handle.
+    pub fn shared_range<R: RangeBounds<Bytes>>(self: &Arc<Self>, range: R) -> Iter<'static> {
+        Self::range_of(TableRef::Shared(Arc::clone(self)), range)
+    }
+
+    fn range_of<R: RangeBounds<Bytes>>(table: TableRef<'_>, range: R) -> Iter<'_> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let partition = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => table.find_partition(key),
+            Bound::Unbounded => 0,
+        };
+        let entries = match &table.index {
+            Index::Legacy(entries) => Arc::clone(entries),
+            _ => Arc::default(),
+        };
+        let done = !table
+            .metadata
+            .overlaps(&(start.clone(), end.clone()), &*table.comparator);
+
+        Iter {
+            sstable: table,
+            next_partition: partition,
+            handles: Arc::default(),
+            next_block: 0,
+            entries,
+            position: 0,
+            done,
+            start,
+            end,
+        }
+    }
+
+    /// Read and decode a single data block, going through the block cache if
+    /// there is one.
+    fn read_block(&self, handle: &BlockHandle) -> Result<Block, ChipmunkError> {
+        if let Some((cache, file_id)) = &self.cache {
+            if let Some(block) = cache.get(*file_id, handle.offset) {
+                return Ok(block);
+            }
+        }
+
+        let (compression, block) = self.read_raw_block(handle)?;
+        let block =
+            decompress(compression, block).map_err(|reason| ChipmunkError::SstableCorrupt {
+                path: self.path.clone(),
+                reason: reason.to_string(),
+            })?;
+        let size = block.len();
+        let entries = Arc::new(decode_block(Bytes::from(block)).map_err(|reason| {
+            ChipmunkError::SstableCorrupt {
+                path: self.path.clone(),
+                reason: reason.to_string(),
+            }
+        })?);
+
+        if let Some((cache, file_id)) = &self.cache {
+            cache.insert(*file_id, handle.offset, entries.clone(), size);
+        }
+        Ok(entries)
+    }
+
+    /// Read a single data block as it is stored on disk, verifying its
+    /// checksum. The compression type is returned alongside the block.
+    fn read_raw_block(&self, handle: &BlockHandle) -> Result<(u8, Vec<u8>), ChipmunkError> {
+        let mut block = vec![0; (handle.size + BLOCK_TRAILER_SIZE) as usize];
+        {
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(handle.offset))
+                .and_then(|_| file.read_exact(&mut block))
+                .map_err(ChipmunkError::SstableRead)?;
+        }
+
+        let trailer = block.split_off(handle.size as usize);
+        let compression = trailer[0];
+        let crc = BigEndian::read_u32(&trailer[1..]);
+        if crc != crc32c::crc32c_append(crc32c::crc32c(&block), &[compression]) {
+            return Err(ChipmunkError::SstableCorrupt {
+                path: self.path.clone(),
+                reason: format!("checksum mismatch in block at offset {}", handle.offset),
+            });
+        }
+        Ok((compression, block))
+    }
+}
+
+/// A problem found while verifying an SSTable, see [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationProblem {
+    /// The footer, index or meta block could not be read or is invalid, so
+    /// nothing else could be checked.
+    Unopenable { reason: String },
+    /// A partition of the index could not be read, or failed its checksum.
+    IndexPartitionUnreadable { partition: usize, reason: String },
+    /// A data block does not start where the previous block ended.
+    BlockOffset { offset: u64, expected: u64 },
+    /// A data block could not be read, or failed its checksum.
+    BlockUnreadable { offset: u64, reason: String },
+    /// A data block passed its checksum but could not be decoded.
+    BlockUndecodable { offset: u64, reason: String },
+    /// The last key of a data block differs from the one held by the index.
+    IndexKeyMismatch { offset: u64 },
+    /// A key is not strictly greater than the one before it.
+    KeyOrder { offset: u64, key: Bytes },
+    /// The meta block disagrees with the entries which are held.
+    MetadataMismatch { reason: String },
+}
+
+/// The outcome of verifying an SSTable, see [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationReport {
+    pub path: PathBuf,
+    /// Number of data blocks which were checked.
+    pub blocks: usize,
+    /// Number of entries which could be decoded.
+    pub entries: u64,
+    pub problems: Vec<VerificationProblem>,
+}
+
+impl VerificationReport {
+    /// Whether the SSTable passed every check.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Verify the integrity of the SSTable at the given path, whose keys are
+/// ordered by their bytes, see [`verify_with_comparator`].
+pub fn verify(path: &Path) -> VerificationReport {
+    verify_with_comparator(path, comparator::bytewise())
+}
+
+/// Verify the integrity of the SSTable at the given path, whose keys are
+/// ordered by the given [`Comparator`].
+///
+/// The footer's magic number, format version and checksum are validated along
+/// with the index and meta blocks. Every data block is then read, checking its
+/// checksum, that it is where the index says it is, that its keys are strictly
+/// ascending and that the index and meta block agree with its contents.
+///
+/// Verification carries on past damaged data blocks so that the report covers
+/// the whole file. Legacy tables carry no checksums, so are only checked to
+/// decode.
+pub fn verify_with_comparator(path: &Path, comparator: Arc<dyn Comparator>) -> VerificationReport {
+    let mut report = VerificationReport {
+        path: path.to_path_buf(),
+        blocks: 0,
+        entries: 0,
+        problems: Vec::new(),
+    };
+    let sstable = match Sstable::open(path) {
+        Ok(sstable) => sstable.with_comparator(comparator),
+        Err(e) => {
+            report.problems.push(VerificationProblem::Unopenable {
+                reason: e.to_string(),
+            });
+            return report;
+        }
+    };
+    if let Index::Legacy(entries) = &sstable.index {
+        report.entries = entries.len() as u64;
+        return report;
+    }
+
+    let mut expected_offset = 0;
+    let mut previous_key: Option<Bytes> = None;
+    let mut first_key: Option<Bytes> = None;
+    let mut tombstones = 0;
+    let mut partitions = Vec::new();
+    for partition in 0..sstable.partition_count() {
+        match sstable.read_partition(partition) {
+            Ok(Some(handles)) => partitions.push(handles),
+            Ok(None) => break,
+            Err(e) => report
+                .problems
+                .push(VerificationProblem::IndexPartitionUnreadable {
+                    partition,
+                    reason: e.to_string(),
+                }),
+        }
+    }
+    for handle in partitions.iter().flat_map(|handles| handles.iter()) {
+        report.blocks += 1;
+        if handle.offset != expected_offset {
+            report.problems.push(VerificationProblem::BlockOffset {
+                offset: handle.offset,
+                expected: expected_offset,
+            });
+        }
+        expected_offset = handle.offset + handle.size + BLOCK_TRAILER_SIZE;
+
+        let entries = match sstable.read_raw_block(handle) {
+            Ok((compression, block)) => {
+                decompress(compression, block).and_then(|b| decode_block(Bytes::from(b)))
+            }
+            Err(e) => {
+                report.problems.push(VerificationProblem::BlockUnreadable {
+                    offset: handle.offset,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(reason) => {
+                report.problems.push(VerificationProblem::BlockUndecodable {
+                    offset: handle.offset,
+                    reason: reason.to_string(),
+                });
+                continue;
+            }
+        };
+
+        for (key, entry) in &entries {
+            if previous_key
+                .as_ref()
+                .is_some_and(|previous| sstable.comparator.compare(key, previous).is_le())
+            {
+                report.problems.push(VerificationProblem::KeyOrder {
+                    offset: handle.offset,
+                    key: key.clone(),
+                });
+            }
+            first_key.get_or_insert_with(|| key.clone());
+            previous_key = Some(key.clone());
+            report.entries += 1;
+            if entry.value.is_none() {
+                tombstones += 1;
+            }
+        }
+        if entries.last().map(|(key, _)| key) != Some(&handle.last_key) {
+            report.problems.push(VerificationProblem::IndexKeyMismatch {
+                offset: handle.offset,
+            });
+        }
+    }
+
+    // Only a fully readable file can be compared against its metadata.
+    if report.is_ok() {
+        let metadata = sstable.metadata();
+        let mut mismatch = |reason: String| {
+            report
+                .problems
+                .push(VerificationProblem::MetadataMismatch { reason })
+        };
+        if metadata.entries != report.entries {
+            mismatch(format!(
+                "{} entries recorded, {} held",
+                metadata.entries, report.entries
+            ));
+        }
+        if metadata.tombstones != tombstones {
+            mismatch(format!(
+                "{} tombstones recorded, {tombstones} held",
+                metadata.tombstones
+            ));
+        }
+        if metadata.min_key != first_key.unwrap_or_default() {
+            mismatch("minimum key differs".to_string());
+        }
+        if metadata.max_key != previous_key.unwrap_or_default() {
+            mismatch("maximum key differs".to_string());
+        }
+    }
+
+    report
+}
+
+/// The table which an [`Iter`] reads from.
+enum TableRef<'a> {
+    Borrowed(&'a Sstable),
+    Shared(Arc<Sstable>),
+}
+
+impl Deref for TableRef<'_> {
+    type Target = Sstable;
+
+    fn deref(&self) -> &Sstable {
+        match self {
+            TableRef::Borrowed(table) => table,
+            TableRef::Shared(table) => table,
+        }
+    }
+}
+
+/// Streaming iterator over the entries of an [`Sstable`], see
+/// [`Sstable::iter`] and [`Sstable::range`].
+///
+/// Yields an error, and then stops, if a data block cannot be read.
+pub struct Iter<'a> {
+    sstable: TableRef<'a>,
+    /// Position of the next index partition to be read.
+    next_partition: usize,
+    /// Data block handles of the current index partition, and the position of
+    /// the next block to be read.
+    handles: Arc<Vec<BlockHandle>>,
+    next_block: usize,
+    /// Entries of the current block, and the position of the next of them.
+    entries: Block,
+    position: usize,
+    start: Bound<Bytes>,
+    end: Bound<Bytes>,
+    done: bool,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(Bytes, Entry), ChipmunkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((key, entry)) = self.entries.get(self.position).cloned() {
+                self.position += 1;
+                let comparator = &*self.sstable.comparator;
+                if !comparator::after_start(comparator, &key, self.start.as_ref()) {
+                    continue;
+                }
+                if !comparator::before_end(comparator, &key, self.end.as_ref()) {
+                    self.done = true;
+                    return None;
+                }
+                return Some(Ok((key, entry)));
+            }
+
+            let Some(handle) = self.handles.get(self.next_block).cloned() else {
+                match self.sstable.read_partition(self.next_partition) {
+                    Ok(Some(handles)) => {
+                        // Skip the blocks which precede the range, only the
+                        // first partition read can hold any.
+                        self.next_block = match &self.start {
+                            Bound::Included(key) | Bound::Excluded(key) => {
+                                self.sstable.find_block(&handles, key)
+                            }
+                            Bound::Unbounded => 0,
+                        };
+                        self.handles = handles;
+                        self.next_partition += 1;
+                    }
+                    Ok(None) => self.done = true,
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+                continue;
+            };
+            self.next_block += 1;
+            match self.sstable.read_block(&handle) {
+                Ok(entries) => {
+                    self.entries = entries;
+                    self.position = 0;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Compress a block, returning the compression which was actually applied
+/// alongside the stored bytes. Blocks which do not shrink are left
+/// uncompressed.
+fn compress(compression: Compression, block: &[u8]) -> (u8, Vec<u8>) {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Lz4 => Some((COMPRESSION_LZ4, lz4_flex::compress_prepend_size(block))),
+        Compression::Zstd => zstd::bulk::compress(block, zstd::DEFAULT_COMPRESSION_LEVEL)
+            .ok()
+            .map(|c| (COMPRESSION_ZSTD, c)),
+        Compression::Snappy => snap::raw::Encoder::new()
+            .compress_vec(block)
+            .ok()
+            .map(|c| (COMPRESSION_SNAPPY, c)),
+    };
+
+    match compressed {
+        Some((id, compressed)) if compressed.len() < block.len() => (id, compressed),
+        _ => (COMPRESSION_NONE, block.to_vec()),
+    }
+}
+
+/// Reverse [`compress`] for a block stored with the given compression.
+fn decompress(compression: u8, block: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    match compression {
+        COMPRESSION_NONE => Ok(block),
+        COMPRESSION_LZ4 => {
+            lz4_flex::decompress_size_prepended(&block).map_err(|_| "invalid lz4 block")
+        }
+        COMPRESSION_ZSTD => {
+            zstd::stream::decode_all(block.as_slice()).map_err(|_| "invalid zstd block")
+        }
+        COMPRESSION_SNAPPY => snap::raw::Decoder::new()
+            .decompress_vec(&block)
+            .map_err(|_| "invalid snappy block"),
+        _ => Err("unknown block compression"),
+    }
+}
+
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], entry: &Entry) {
+    let mut flags = 0;
+    if entry.value.is_some() {
+        flags |= ENTRY_FLAG_VALUE;
+    }
+    if entry.expires_at.is_some() {
+        flags |= ENTRY_FLAG_EXPIRY;
+    }
+    if entry.separated {
+        flags |= ENTRY_FLAG_SEPARATED;
+    }
+    if entry.stamp.is_some() {
+        flags |= ENTRY_FLAG_STAMP;
+    }
+
+    buf.write_u32::<BigEndian>(key.len() as u32).unwrap();
+    buf.write_all(key).unwrap();
+    buf.write_u8(flags).unwrap();
+    if let Some(expires_at) = entry.expires_at {
+        buf.write_u64::<BigEndian>(expires_at).unwrap();
+    }
+    if let Some(stamp) = entry.stamp {
+        buf.write_u64::<BigEndian>(stamp.sequence).unwrap();
+        buf.write_u64::<BigEndian>(stamp.written_at).unwrap();
+    }
+    if let Some(value) = &entry.value {
+        buf.write_u32::<BigEndian>(value.len() as u32).unwrap();
+        buf.write_all(value).unwrap();
+    }
+}
+
+/// Bounds-checked reads over an encoded block, slicing the underlying
+/// [`Bytes`] rather than copying.
+struct Cursor {
+    data: Bytes,
+    position: usize,
+}
+
+impl Cursor {
+    fn new(data: Bytes) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<Bytes, &'static str> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("unexpected end of block")?;
+        let bytes = self.data.slice(self.position..end);
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(BigEndian::read_u32(&self.take(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(BigEndian::read_u64(&self.take(8)?))
+    }
+}
+
+fn decode_block(block: Bytes) -> Result<Vec<(Bytes, Entry)>, &'static str> {
+    let mut cursor = Cursor::new(block);
+    let mut entries = Vec::new();
+    while !cursor.is_empty() {
+        let key_len = cursor.u32()? as usize;
+        let key = cursor.take(key_len)?;
+        let flags = cursor.u8()?;
+        let expires_at = if flags & ENTRY_FLAG_EXPIRY != 0 {
+            Some(cursor.u64()?)
+        } else {
+            None
+        };
+        let stamp = if flags & ENTRY_FLAG_STAMP != 0 {
+            Some(WriteStamp {
+                sequence: cursor.u64()?,
+                written_at: cursor.u64()?,
+            })
+        } else {
+            None
+        };
+        let value = if flags & ENTRY_FLAG_VALUE != 0 {
+            let value_len = cursor.u32()? as usize;
+            Some(cursor.take(value_len)?)
+        } else {
+            None
+        };
+        entries.push((
+            key,
+            Entry {
+                value,
+                expires_at,
+                separated: flags & ENTRY_FLAG_SEPARATED != 0,
+                stamp,
+            },
+        ));
+    }
+    Ok(entries)
+}
+
+/// Decode the bincode serialized map of a legacy table, in which every integer
+/// is little endian and lengths are u64s:
+///
+/// ```text
+/// | entries (u64) | key len | key | is some (u8) | [value len | value] | ... |
+/// ```
+///
+/// Returns `None` unless the data holds exactly such a map.
+fn decode_legacy(mut data: &[u8]) -> Option<Vec<(Bytes, Entry)>> {
+    fn bytes(data: &mut &[u8]) -> Option<Bytes> {
+        let len = usize::try_from(data.read_u64::<LittleEndian>().ok()?).ok()?;
+        let value = data.get(..len)?;
+        *data = &data[len..];
+        Some(Bytes::copy_from_slice(value))
+    }
+
+    let count = data.read_u64::<LittleEndian>().ok()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let key = bytes(&mut data)?;
+        let value = match data.read_u8().ok()? {
+            0 => None,
+            1 => Some(bytes(&mut data)?),
+            _ => return None,
+        };
+        entries.push((
+            key,
+            Entry {
+                value,
+                expires_at: None,
+                separated: false,
+                stamp: None,
+            },
+        ));
+    }
+    data.is_empty().then_some(entries)
+}
+
+fn encode_handle(buf: &mut Vec<u8>, handle: &BlockHandle) {
+    buf.write_u32::<BigEndian>(handle.last_key.len() as u32)
+        .unwrap();
+    buf.write_all(&handle.last_key).unwrap();
+    buf.write_u64::<BigEndian>(handle.offset).unwrap();
+    buf.write_u64::<BigEndian>(handle.size).unwrap();
+}
+
+fn decode_index(index: Bytes) -> Result<Vec<BlockHandle>, &'static str> {
+    let mut cursor = Cursor::new(index);
+    let mut handles = Vec::new();
+    while !cursor.is_empty() {
+        let key_len = cursor.u32()? as usize;
+        let last_key = cursor.take(key_len)?;
+        let offset = cursor.u64()?;
+        let size = cursor.u64()?;
+        handles.push(BlockHandle {
+            last_key,
+            offset,
+            size,
+        });
+    }
+    Ok(handles)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::comparator::Bytewise;
+
+    fn entry(value: &'static [u8]) -> Entry {
+        Entry {
+            value: Some(Bytes::from_static(value)),
+            expires_at: None,
+            separated: false,
+            stamp: None,
+        }
+    }
+
+    #[test]
+    fn write_and_read() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        // A tiny block size forces entries to be spread across many blocks.
+        let mut builder = SstableBuilder::new(&path).unwrap().with_block_size(32);
+        let mut expected = Vec::new();
+        for i in 0..100 {
+            let key = Bytes::from(format!("key{i:03}"));
+            let value = match i % 3 {
+                0 => Entry {
+                    value: None,
+                    expires_at: None,
+                    separated: false,
+                    stamp: None,
+                },
+                1 => Entry {
+                    value: Some(Bytes::from(format!("value{i}"))),
+                    expires_at: Some(i),
+                    separated: false,
+                    stamp: Some(WriteStamp {
+                        sequence: i,
+                        written_at: 1_700_000_000_000 + i,
+                    }),
+                },
+                _ => Entry {
+                    value: Some(Bytes::from(format!("value{i}"))),
+                    expires_at: None,
+                    separated: false,
+                    stamp: None,
+                },
+            };
+            builder.add(&key, &value).unwrap();
+            expected.push((key, value));
+        }
+        assert_eq!(builder.finish().unwrap().entries, 100);
+
+        let table = Sstable::open(&path).unwrap();
+        assert!(
+            table.read_partition(0).unwrap().unwrap().len() > 1,
+            "Entries should span several blocks"
+        );
+        assert_eq!(table.entries().unwrap(), expected);
+    }
+
+    #[test]
+    fn partitioned_index() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        let mut builder = SstableBuilder::new(&path)
+            .unwrap()
+            .with_block_size(32)
+            .with_index_partition_size(64);
+        let mut expected = Vec::new();
+        for i in 0..200 {
+            let key = Bytes::from(format!("key{i:03}"));
+            builder.add(&key, &entry(b"value")).unwrap();
+            expected.push((key, entry(b"value")));
+        }
+        builder.finish().unwrap();
+
+        let table = Sstable::open(&path).unwrap();
+        assert!(
+            matches!(&table.index, Index::Partitioned(p) if p.len() > 1),
+            "The index should have been partitioned"
+        );
+        assert!(verify(&path).is_ok());
+        assert_eq!(table.entries().unwrap(), expected);
+        for (key, value) in &expected {
+            assert_eq!(table.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(table.get(b"key0005").unwrap(), None);
+        let keys: Vec<&[u8]> = vec![b"a", b"key000", b"key0005", b"key001", b"key150", b"zzz"];
+        assert_eq!(
+            table.multi_get(&keys).unwrap(),
+            vec![
+                None,
+                Some(entry(b"value")),
+                None,
+                Some(entry(b"value")),
+                Some(entry(b"value")),
+                None
+            ]
+        );
+        let range: Vec<_> = table
+            .range(Bytes::from_static(b"key150")..Bytes::from_static(b"key153"))
+            .map(|e| e.unwrap())
+            .collect();
+        assert_eq!(range, expected[150..153]);
+
+        // Small indexes are never partitioned
+        let path = dir.path().join("sstable-1");
+        let mut builder = SstableBuilder::new(&path)
+            .unwrap()
+            .with_index_partition_size(64);
+        builder.add(b"key", &entry(b"value")).unwrap();
+        builder.finish().unwrap();
+        assert!(matches!(
+            Sstable::open(&path).unwrap().index,
+            Index::Flat(_)
+        ));
+    }
+
+    #[test]
+    fn point_lookups() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        let mut builder = SstableBuilder::new(&path).unwrap().with_block_size(32);
+        for i in (0..100).step_by(2) {
+            builder
+                .add(format!("key{i:03}").as_bytes(), &entry(b"value"))
+                .unwrap();
+        }
+        let tombstone = Entry {
+            value: None,
+            expires_at: None,
+            separated: false,
+            stamp: None,
+        };
+        builder.add(b"tombstone", &tombstone).unwrap();
+        builder.finish().unwrap();
+
+        let table = Sstable::open(&path).unwrap();
+        for i in 0..100 {
+            let found = table.get(format!("key{i:03}").as_bytes()).unwrap();
+            if i % 2 == 0 {
+                assert_eq!(found, Some(entry(b"value")));
+            } else {
+                assert_eq!(found, None, "key{i:03} was never written");
+            }
+        }
+        assert_eq!(table.get(b"a").unwrap(), None);
+        assert_eq!(table.get(b"zzz").unwrap(), None);
+        assert_eq!(table.get(b"tombstone").unwrap(), Some(tombstone));
+    }
+
+    #[test]
+    fn metadata() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        let before = unix_millis();
+        let mut builder = SstableBuilder::new(&path).unwrap();
+        builder.add(b"b", &entry(b"value")).unwrap();
+        let tombstone = Entry {
+            value: None,
+            expires_at: None,
+            separated: false,
+            stamp: None,
+        };
+        builder.add(b"c", &tombstone).unwrap();
+        builder.add(b"d", &entry(b"value")).unwrap();
+        builder.finish().unwrap();
+
+        let table = Sstable::open(&path).unwrap();
+        let metadata = table.metadata();
+        assert_eq!(metadata.min_key, Bytes::from_static(b"b"));
+        assert_eq!(metadata.max_key, Bytes::from_static(b"d"));
+        assert_eq!(metadata.entries, 3);
+        assert_eq!(metadata.tombstones, 1);
+        assert!(metadata.created_at >= before);
+
+        assert!(!metadata.may_contain(b"a", &Bytewise));
+        assert!(metadata.may_contain(b"c", &Bytewise));
+        assert!(!metadata.may_contain(b"e", &Bytewise));
+        assert_eq!(
+            metadata.bloom_check(b"c", &Bytewise),
+            Some(true),
+            "Tombstones are filtered"
+        );
+        assert_eq!(metadata.bloom_check(b"bb", &Bytewise), Some(false));
+        assert!(!metadata.may_contain(b"bb", &Bytewise));
+        assert_eq!(metadata.bloom_check(b"e", &Bytewise), None);
+        let key = |k: &'static [u8]| Bytes::from_static(k);
+        assert!(metadata.overlaps(&(key(b"a")..=key(b"b")), &Bytewise));
+        assert!(!metadata.overlaps(&(key(b"a")..key(b"b")), &Bytewise));
+        assert!(!metadata.overlaps(&(key(b"e")..), &Bytewise));
+        assert!(metadata.overlaps(&(..), &Bytewise));
+
+        // Tables written without a filter fall back to their key range
+        let path = dir.path().join("sstable-2");
+        let mut builder = SstableBuilder::new(&path)
+            .unwrap()
+            .with_bloom_bits_per_key(0);
+        builder.add(b"b", &entry(b"value")).unwrap();
+        builder.add(b"d", &entry(b"value")).unwrap();
+        builder.finish().unwrap();
+        let table = Sstable::open(&path).unwrap();
+        assert_eq!(table.metadata().filter, None);
+        assert!(table.metadata().may_contain(b"c", &Bytewise));
+
+        // An empty table never contains any key
+        let path = dir.path().join("sstable-1");
+        SstableBuilder::new(&path).unwrap().finish().unwrap();
+        let table = Sstable::open(&path).unwrap();
+        assert_eq!(table.metadata().entries, 0);
+        assert!(!table.metadata().may_contain(b"", &Bytewise));
+        assert_eq!(table.iter().count(), 0);
+    }
+
+    #[test]
+    fn range_tombstones() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        let mut builder = SstableBuilder::new(&path).unwrap();
+        builder.add(b"b", &entry(b"value")).unwrap();
+        builder.delete_range(b"x", b"z");
+        builder.delete_range(b"a", b"c");
+        builder.delete_range(b"q", b"q");
+        let written = builder.finish().unwrap();
+
+        let table = Sstable::open(&path).unwrap();
+        let metadata = table.metadata();
+        assert_eq!(metadata, &written);
+        assert_eq!(
+            metadata.range_tombstones,
+            vec![
+                RangeTombstone {
+                    start: Bytes::from_static(b"a"),
+                    end: Bytes::from_static(b"c"),
+                },
+                RangeTombstone {
+                    start: Bytes::from_static(b"x"),
+                    end: Bytes::from_static(b"z"),
+                },
+            ],
+            "Range tombstones should be sorted and empty ranges dropped"
+        );
+        assert_eq!(
+            SstableMetadata::decode(Bytes::from(metadata.encode())).unwrap(),
+            *metadata
+        );
+
+        let tombstone = Entry {
+            value: None,
+            expires_at: None,
+            separated: false,
+            stamp: None,
+        };
+        assert_eq!(
+            table.get(b"b").unwrap(),
+            Some(entry(b"value")),
+            "Entries within the table are newer than its range tombstones"
+        );
+        assert_eq!(table.get(b"a").unwrap(), Some(tombstone.clone()));
+        assert_eq!(table.get(b"y").unwrap(), Some(tombstone.clone()));
+        assert_eq!(table.get(b"z").unwrap(), None);
+        assert_eq!(
+            table.multi_get(&[b"a", b"b", b"c", b"y"]).unwrap(),
+            vec![
+                Some(tombstone.clone()),
+                Some(entry(b"value")),
+                None,
+                Some(tombstone)
+            ]
+        );
+        assert!(metadata.may_contain(b"xx", &Bytewise));
+        assert!(metadata.overlaps(&(Bytes::from_static(b"y")..), &Bytewise));
+        assert!(!metadata.overlaps(
+            &(Bytes::from_static(b"c")..Bytes::from_static(b"x")),
+            &Bytewise
+        ));
+    }
+
+    #[test]
+    fn iterators() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        let mut builder = SstableBuilder::new(&path).unwrap().with_block_size(32);
+        for i in 0..100 {
+            builder
+                .add(format!("key{i:03}").as_bytes(), &entry(b"value"))
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let table = Sstable::open(&path).unwrap();
+        let keys = |iter: Iter<'_>| -> Vec<String> {
+            iter.map(|e| String::from_utf8(e.unwrap().0.to_vec()).unwrap())
+                .collect()
+        };
+        let key = |k: &'static str| Bytes::from_static(k.as_bytes());
+
+        let all = keys(table.iter());
+        assert_eq!(all.len(), 100);
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(
+            keys(table.range(key("key010")..key("key013"))),
+            vec!["key010", "key011", "key012"]
+        );
+        assert_eq!(
+            keys(table.range(key("key010")..=key("key013"))),
+            vec!["key010", "key011", "key012", "key013"]
+        );
+        assert_eq!(
+            keys(table.range((Bound::Excluded(key("key097")), Bound::Unbounded))),
+            vec!["key098", "key099"]
+        );
+        assert_eq!(keys(table.range(..key("key002"))), vec!["key000", "key001"]);
+        assert!(keys(table.range(key("zzz")..)).is_empty());
+        assert!(keys(table.range(key("key050")..key("key050"))).is_empty());
+    }
+
+    #[test]
+    fn compression() {
+        let dir = TempDir::new("sstable").unwrap();
+        for compression in [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Zstd,
+            Compression::Snappy,
+        ] {
+            let path = dir.path().join(format!("sstable-{compression:?}"));
+            let mut builder = SstableBuilder::new(&path)
+                .unwrap()
+                .with_compression(compression);
+            let mut expected = Vec::new();
+            for i in 0..1000 {
+                let key = Bytes::from(format!("key{i:04}"));
+                let value = Entry {
+                    // Highly repetitive values compress well
+                    value: Some(Bytes::from("value".repeat(20))),
+                    expires_at: None,
+                    separated: false,
+                    stamp: None,
+                };
+                builder.add(&key, &value).unwrap();
+                expected.push((key, value));
+            }
+            builder.finish().unwrap();
+
+            let size = std::fs::metadata(&path).unwrap().len();
+            if compression == Compression::None {
+                assert!(size > 100 * 1000);
+            } else {
+                assert!(size < 100 * 1000, "{compression:?} should shrink the file");
+            }
+            assert_eq!(
+                Sstable::open(&path).unwrap().entries().unwrap(),
+                expected,
+                "{compression:?} blocks should be decompressed transparently"
+            );
+        }
+    }
+
+    #[test]
+    fn offline_builder() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("bulk");
+        let mut builder = SstableBuilder::new(&path).unwrap();
+        builder.put(b"a", b"1").unwrap();
+        builder.delete(b"b").unwrap();
+        builder.put_with_expiry(b"c", b"3", 42).unwrap();
+        assert_eq!(builder.len(), 3);
+        assert!(builder.put(b"a", b"again").is_err());
+        assert!(
+            !path.exists(),
+            "Nothing is visible until the table is finished"
+        );
+        let metadata = builder.finish().unwrap();
+        assert!(!fs::temp_path(&path).exists());
+        assert_eq!(metadata.tombstones, 1);
+
+        assert!(verify(&path).is_ok());
+        let table = Sstable::open(&path).unwrap();
+        assert_eq!(table.get(b"a").unwrap(), Some(entry(b"1")));
+        assert_eq!(table.get(b"b").unwrap().unwrap().value, None);
+        assert_eq!(table.get(b"c").unwrap().unwrap().expires_at, Some(42));
+    }
+
+    #[test]
+    fn unsorted_keys() {
+        let dir = TempDir::new("sstable").unwrap();
+        let mut builder = SstableBuilder::new(&dir.path().join("sstable-0")).unwrap();
+        builder.add(b"b", &entry(b"value")).unwrap();
+        assert!(
+            builder.add(b"a", &entry(b"value")).is_err(),
+            "Keys must be added in ascending order"
+        );
+        assert!(
+            builder.add(b"b", &entry(b"value")).is_err(),
+            "Duplicate keys are rejected"
+        );
+    }
+
+    #[test]
+    fn corrupt_footer() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+        let mut builder = SstableBuilder::new(&path).unwrap();
+        builder.add(b"foo", &entry(b"bar")).unwrap();
+        builder.finish().unwrap();
+
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        assert!(matches!(
+            Sstable::open(&path),
+            Err(ChipmunkError::SstableCorrupt { .. })
+        ));
+
+        // Restore the magic and instead flip a bit within the index offset
+        let mut data = std::fs::read(&path).unwrap();
+        data[last] ^= 0xff;
+        let footer_offset = data.len() - FOOTER_SIZE as usize;
+        data[footer_offset + 7] ^= 0x01;
+        std::fs::write(&path, data).unwrap();
+
+        assert!(matches!(
+            Sstable::open(&path),
+            Err(ChipmunkError::SstableCorrupt { reason, .. }) if reason.contains("footer checksum")
+        ));
+    }
+
+    #[test]
+    fn verification() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+        let mut builder = SstableBuilder::new(&path).unwrap().with_block_size(32);
+        for i in 0..10 {
+            builder
+                .add(format!("key{i}").as_bytes(), &entry(b"value"))
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let report = verify(&path);
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.entries, 10);
+        assert!(report.blocks > 1);
+
+        // Damage the first block, the remaining blocks are still checked
+        let mut data = std::fs::read(&path).unwrap();
+        data[10] ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+        let damaged = verify(&path);
+        assert_eq!(damaged.blocks, report.blocks);
+        assert!(matches!(
+            damaged.problems.as_slice(),
+            [VerificationProblem::BlockUnreadable { offset: 0, .. }]
+        ));
+
+        // Damage the footer, nothing else can be checked
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        let unopenable = verify(&path);
+        assert_eq!(unopenable.blocks, 0);
+        assert!(matches!(
+            unopenable.problems.as_slice(),
+            [VerificationProblem::Unopenable { .. }]
+        ));
+    }
+
+    #[test]
+    fn corrupt_block() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+        let mut builder = SstableBuilder::new(&path).unwrap().with_block_size(32);
+        for i in 0..10 {
+            builder
+                .add(format!("key{i}").as_bytes(), &entry(b"value"))
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        // Flip a bit within a value of the first block
+        let mut data = std::fs::read(&path).unwrap();
+        data[10] ^= 0x01;
+        std::fs::write(&path, data).unwrap();
+
+        // The footer and index are intact, so the file can still be opened...
+        let sstable = Sstable::open(&path).unwrap();
+        // ...but the damaged block is caught when it is read
+        assert!(matches!(
+            sstable.entries(),
+            Err(ChipmunkError::SstableCorrupt { reason, .. }) if reason.contains("checksum")
+        ));
+        assert!(sstable.verify_checksums().is_err());
+        assert!(Sstable::open_verified(&path).is_err());
+    }
+
+    #[test]
+    fn legacy_format() {
+        let dir = TempDir::new("sstable").unwrap();
+        let path = dir.path().join("sstable-0");
+
+        // Tables were previously an unsorted, serialized map
+        let mut legacy: HashMap<Bytes, Option<Bytes>> = HashMap::new();
+        for i in 0..10 {
+            let value = (i % 4 != 0).then(|| Bytes::from(format!("value{i}")));
+            legacy.insert(Bytes::from(format!("key{i}")), value);
+        }
+        std::fs::write(&path, bincode::serialize(&legacy).unwrap()).unwrap();
+
+        let table = Sstable::open(&path).unwrap();
+        assert_eq!(table.format_version(), LEGACY_SSTABLE_FORMAT_VERSION);
+        assert_eq!(table.metadata().entries, 10);
+        assert_eq!(table.metadata().tombstones, 3);
+        assert_eq!(table.metadata().min_key, Bytes::from("key0"));
+        assert_eq!(table.metadata().max_key, Bytes::from("key9"));
+        assert_eq!(table.get(b"key1").unwrap(), Some(entry(b"value1")));
+        assert_eq!(table.get(b"key4").unwrap().unwrap().value, None);
+        assert_eq!(table.get(b"missing").unwrap(), None);
+
+        let keys: Vec<_> = table
+            .range(Bytes::from("key3")..Bytes::from("key6"))
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec!["key3", "key4", "key5"]);
+        assert!(verify(&path).is_ok());
+
+        // Tables which are written are stamped with the current version
+        let mut builder = SstableBuilder::new(&path).unwrap();
+        builder.add(b"foo", &entry(b"bar")).unwrap();
+        builder.finish().unwrap();
+        assert_eq!(
+            Sstable::open(&path).unwrap().format_version(),
+            SSTABLE_FORMAT_VERSION
+        );
+
+        // Versions from the future are rejected rather than misread
+        let mut data = std::fs::read(&path).unwrap();
+        let footer = data.len() - FOOTER_SIZE as usize;
+        data[footer + 35] += 1;
+        let crc = crc32c::crc32c(&data[footer..footer + 36]);
+        data[footer + 36..footer + 40].copy_from_slice(&crc.to_be_bytes());
+        std::fs::write(&path, data).unwrap();
+        assert!(matches!(
+            Sstable::open(&path),
+            Err(ChipmunkError::UnsupportedFormatVersion { version, .. })
+                if version == SSTABLE_FORMAT_VERSION + 1
+        ));
+    }
+}
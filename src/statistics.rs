@@ -0,0 +1,229 @@
+//! Counters describing the work the engine has performed, see
+//! [`Statistics`].
+//!
+//! A [`Statistics`] is a copy of the [`Metrics`](crate::metrics::Metrics)
+//! at a single point, though counters which are updated together may be
+//! observed part way through an operation.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Work performed by the engine since it started, or since the statistics
+/// were last reset.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct Statistics {
+    /// Keys looked up, by single or batched point lookups.
+    pub keys_read: u64,
+    /// Keys inserted or deleted, a range deletion counting as one.
+    pub keys_written: u64,
+    /// Size, in bytes, of the keys and values written.
+    pub bytes_written: u64,
+
+    /// Entries appended to the WAL.
+    pub wal_appends: u64,
+    /// Size, in bytes, of the entries appended to the WAL.
+    pub wal_bytes: u64,
+    /// Times a WAL segment was fsynced.
+    pub wal_syncs: u64,
+
+    /// Times the active memtable was frozen to be flushed.
+    pub memtable_rotations: u64,
+
+    /// SSTables searched for a key, once their key range and bloom filter
+    /// did not rule it out.
+    pub sstable_probes: u64,
+
+    /// Lookups of an SSTable which were checked against its bloom filter.
+    pub bloom_checks: u64,
+    /// Lookups of an SSTable which its bloom filter ruled out without reading
+    /// any of its blocks.
+    pub bloom_negatives: u64,
+    /// Lookups of an SSTable which passed its bloom filter, yet the table held
+    /// no entry for the key.
+    pub bloom_false_positives: u64,
+
+    /// Lookups which were answered by a memtable, including by a tombstone.
+    pub memtable_hits: u64,
+    /// Lookups which had to search the SSTables.
+    pub memtable_misses: u64,
+
+    /// Data block reads which were served from the block cache.
+    pub block_cache_hits: u64,
+    /// Data block reads which went to disk.
+    pub block_cache_misses: u64,
+
+    /// Number of memtables flushed to SSTables.
+    pub flushes: u64,
+    /// Time, in milliseconds, spent flushing memtables.
+    pub flush_time_ms: u64,
+    /// Size, in bytes, of the SSTables written by flushes.
+    pub bytes_flushed: u64,
+    /// Number of compaction cycles which ran.
+    pub compactions: u64,
+    /// Time, in milliseconds, spent compacting.
+    pub compaction_time_ms: u64,
+    /// Size, in bytes, of the SSTables written by compaction.
+    pub bytes_compacted: u64,
+
+    /// Time taken within the engine by each kind of operation.
+    pub latency: Latencies,
+}
+
+/// Latency of each kind of operation, timed within the engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct Latencies {
+    /// Point lookups, a batch of them counting as one.
+    pub get: LatencySummary,
+    /// Insertions.
+    pub put: LatencySummary,
+    /// Deletions, of single keys or of ranges.
+    pub delete: LatencySummary,
+    /// Scans, excluding the time spent by the caller between entries.
+    pub scan: LatencySummary,
+}
+
+/// Estimated percentiles of the time taken by a kind of operation, see
+/// [`Histogram`](crate::metrics::Histogram).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct LatencySummary {
+    /// Number of operations timed.
+    pub count: u64,
+    /// Median time, in microseconds.
+    pub p50_us: u64,
+    /// 95th percentile, in microseconds.
+    pub p95_us: u64,
+    /// 99th percentile, in microseconds.
+    pub p99_us: u64,
+}
+
+impl Statistics {
+    /// Proportion of the lookups passing the bloom filter which found nothing.
+    pub fn bloom_false_positive_rate(&self) -> f64 {
+        ratio(
+            self.bloom_false_positives,
+            self.bloom_checks.saturating_sub(self.bloom_negatives),
+        )
+    }
+
+    /// Proportion of the lookups searching the tree which a memtable
+    /// answered.
+    pub fn memtable_hit_ratio(&self) -> f64 {
+        ratio(
+            self.memtable_hits,
+            self.memtable_hits + self.memtable_misses,
+        )
+    }
+
+    /// Proportion of data block reads which were served from the cache.
+    pub fn block_cache_hit_ratio(&self) -> f64 {
+        ratio(
+            self.block_cache_hits,
+            self.block_cache_hits + self.block_cache_misses,
+        )
+    }
+}
+
+/// The files making up a single level of the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LevelStats {
+    pub level: u8,
+    pub files: usize,
+    /// Total size, in bytes, of the files.
+    pub bytes: u64,
+    /// Estimated size, in bytes, which compaction would free, taking each
+    /// tombstone to free an entry of its file's average size. Overwritten and
+    /// expired entries are not known of, so this errs low.
+    pub reclaimable_bytes: u64,
+}
+
+/// The shape of the tree at a single point, as opposed to the work counted
+/// by [`Statistics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TreeStats {
+    /// Approximate size, in bytes, of the active memtable.
+    pub memtable_bytes: u64,
+    /// Number of frozen memtables awaiting a flush.
+    pub immutable_memtables: usize,
+    /// Approximate size, in bytes, of the frozen memtables.
+    pub immutable_memtable_bytes: u64,
+    /// Every level which is persisted to SSTables, in order.
+    pub levels: Vec<LevelStats>,
+    /// Size, in bytes, of the WAL segments on disk.
+    pub wal_bytes: u64,
+    /// Size, in bytes, of the value log files on disk.
+    pub value_log_bytes: u64,
+    /// Size, in bytes, of every file within the working directory, including
+    /// those not accounted for above such as the manifest and checkpoints.
+    pub directory_bytes: u64,
+    /// Approximate number of live keys, see
+    /// [`Lsm::approximate_count`](crate::lsm::Lsm::approximate_count).
+    pub approximate_keys: u64,
+    /// Approximate size, in bytes, of the data held, see
+    /// [`Lsm::approximate_size`](crate::lsm::Lsm::approximate_size).
+    pub approximate_bytes: u64,
+}
+
+impl TreeStats {
+    /// Add the shape of another tree, such as another shard of the store,
+    /// to this one, level by level.
+    pub fn merge(&mut self, other: &TreeStats) {
+        self.memtable_bytes += other.memtable_bytes;
+        self.immutable_memtables += other.immutable_memtables;
+        self.immutable_memtable_bytes += other.immutable_memtable_bytes;
+        for level in &other.levels {
+            match self
+                .levels
+                .iter_mut()
+                .find(|merged| merged.level == level.level)
+            {
+                Some(merged) => {
+                    merged.files += level.files;
+                    merged.bytes += level.bytes;
+                    merged.reclaimable_bytes += level.reclaimable_bytes;
+                }
+                None => self.levels.push(level.clone()),
+            }
+        }
+        self.wal_bytes += other.wal_bytes;
+        self.value_log_bytes += other.value_log_bytes;
+        self.directory_bytes += other.directory_bytes;
+        self.approximate_keys += other.approximate_keys;
+        self.approximate_bytes += other.approximate_bytes;
+    }
+}
+
+/// `part` as a proportion of `whole`, zero when `whole` is.
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ratios() {
+        let stats = Statistics {
+            bloom_checks: 10,
+            bloom_negatives: 6,
+            bloom_false_positives: 1,
+            memtable_hits: 3,
+            memtable_misses: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.bloom_false_positive_rate(), 0.25);
+        assert_eq!(stats.memtable_hit_ratio(), 0.75);
+        assert_eq!(
+            stats.block_cache_hit_ratio(),
+            0.0,
+            "No reads should not divide by zero"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! A bounded pool of open SSTable readers.
+//!
+//! Opening an SSTable reads its footer, index and meta block, so readers are
+//! kept open between lookups. Holding every file open would exhaust file
+//! descriptors once there are many of them, so at most `max_open_files` are
+//! held, the least recently used being closed and reopened on demand.
+
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use lru::LruCache;
+use parking_lot::{Mutex, RwLock};
+use tracing::debug;
+
+use crate::{
+    block_cache::BlockCache,
+    comparator::{self, Comparator},
+    manifest,
+    sstable::Sstable,
+    ChipmunkError,
+};
+
+/// Identifies a table by its level and ID within that level.
+type TableKey = (u8, u64);
+
+pub struct TableCache {
+    directory: PathBuf,
+    block_cache: Arc<BlockCache>,
+    /// ID of this pool's tables within the block cache, see
+    /// [`BlockCache::new_owner`].
+    owner: u64,
+    /// Ordering of the keys of every table.
+    comparator: RwLock<Arc<dyn Comparator>>,
+    tables: Mutex<LruCache<TableKey, Arc<Sstable>>>,
+}
+
+impl TableCache {
+    /// Create a pool of readers over the SSTables within the given directory,
+    /// holding at most `max_open_files` open at once.
+    pub fn new(directory: &Path, max_open_files: usize, block_cache: Arc<BlockCache>) -> Self {
+        let capacity = NonZeroUsize::new(max_open_files).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            directory: directory.to_path_buf(),
+            owner: block_cache.new_owner(),
+            block_cache,
+            comparator: RwLock::new(comparator::bytewise()),
+            tables: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Read tables with the given [`Comparator`], closing any which are open.
+    pub fn set_comparator(&self, comparator: Arc<dyn Comparator>) {
+        *self.comparator.write() = comparator;
+        self.tables.lock().clear();
+    }
+
+    /// Fetch the reader for a table, opening it if it is not already open.
+    pub fn get(&self, level: u8, id: u64) -> Result<Arc<Sstable>, ChipmunkError> {
+        if let Some(table) = self.tables.lock().get(&(level, id)) {
+            return Ok(Arc::clone(table));
+        }
+
+        let path = self.directory.join(manifest::file_name(level, id));
+        debug!(path = %path.display(), "Opening SSTable reader");
+        let table = Arc::new(
+            Sstable::open(&path)?
+                .with_block_cache(
+                    self.block_cache.clone(),
+                    block_file_id(self.owner, level, id),
+                )
+                .with_comparator(Arc::clone(&self.comparator.read())),
+        );
+        self.tables.lock().put((level, id), Arc::clone(&table));
+        Ok(table)
+    }
+
+    /// Close the reader for a table and drop its cached blocks, used once the
+    /// table has been removed.
+    pub fn evict(&self, level: u8, id: u64) {
+        self.tables.lock().pop(&(level, id));
+        self.block_cache
+            .evict_file(block_file_id(self.owner, level, id));
+    }
+}
+
+/// ID which a table's blocks are cached under, unique across every level and
+/// owner, with the owner in the top 16 bits and the level in the 8 below.
+fn block_file_id(owner: u64, level: u8, id: u64) -> u64 {
+    (owner << 48) | ((level as u64) << 40) | id
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{manifest::LEVEL_1, sstable::SstableBuilder};
+
+    #[test]
+    fn max_open_files() {
+        let dir = TempDir::new("table_cache").unwrap();
+        for id in 0..4 {
+            let path = dir.path().join(manifest::file_name(LEVEL_1, id));
+            let mut builder = SstableBuilder::new(&path).unwrap();
+            builder.put(b"key", format!("{id}").as_bytes()).unwrap();
+            builder.finish().unwrap();
+        }
+
+        let cache = TableCache::new(dir.path(), 2, Arc::new(BlockCache::new(1024)));
+        for id in 0..4 {
+            let table = cache.get(LEVEL_1, id).unwrap();
+            let entry = table.get(b"key").unwrap().unwrap();
+            assert_eq!(entry.value.unwrap(), format!("{id}").as_bytes());
+            assert!(cache.tables.lock().len() <= 2);
+        }
+
+        // Evicted readers are reopened on demand
+        let first = cache.get(LEVEL_1, 0).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(LEVEL_1, 0).unwrap()));
+        cache.evict(LEVEL_1, 0);
+        assert!(!Arc::ptr_eq(&first, &cache.get(LEVEL_1, 0).unwrap()));
+        assert!(cache.get(LEVEL_1, 10).is_err());
+    }
+
+    #[test]
+    fn shared_block_cache() {
+        let block_cache = Arc::new(BlockCache::new(1024));
+        let dirs = [
+            TempDir::new("table_cache_a").unwrap(),
+            TempDir::new("table_cache_b").unwrap(),
+        ];
+        let caches: Vec<TableCache> = dirs
+            .iter()
+            .map(|dir| {
+                let path = dir.path().join(manifest::file_name(LEVEL_1, 0));
+                let mut builder = SstableBuilder::new(&path).unwrap();
+                builder
+                    .put(b"key", dir.path().as_os_str().as_encoded_bytes())
+                    .unwrap();
+                builder.finish().unwrap();
+                TableCache::new(dir.path(), 2, Arc::clone(&block_cache))
+            })
+            .collect();
+
+        // Tables with the same ID in different directories do not share blocks
+        for _ in 0..2 {
+            for (dir, cache) in dirs.iter().zip(&caches) {
+                let entry = cache.get(LEVEL_1, 0).unwrap().get(b"key").unwrap().unwrap();
+                assert_eq!(
+                    entry.value.unwrap(),
+                    dir.path().as_os_str().as_encoded_bytes()
+                );
+            }
+        }
+    }
+}
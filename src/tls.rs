@@ -0,0 +1,253 @@
+//! TLS termination for the server, see [`serve_tls`].
+//!
+//! The certificate and key are read from the PEM files of a [`TlsConfig`].
+//! Those files are checked for changes at the configured interval and
+//! reloaded in place, so that renewed certificates are presented to new
+//! connections without restarting the server. A reload which fails, such as
+//! when only one of the files has been replaced so far, keeps the previous
+//! certificate in use.
+
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::Router;
+use parking_lot::{Mutex, RwLock};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+use crate::config::{HttpConfig, TlsConfig};
+use crate::server::serve_connections;
+use crate::ChipmunkError;
+
+/// A certificate which is reloaded from its files when they change.
+#[derive(Debug)]
+pub struct ReloadingCertificate {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Latest modification time of the files when they were last loaded.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl ReloadingCertificate {
+    /// Load the certificate and key of the [`TlsConfig`].
+    pub fn load(config: TlsConfig) -> Result<Self, ChipmunkError> {
+        let modified = modified(&config);
+        let current = load_certified_key(&config)?;
+        Ok(Self {
+            config,
+            current: RwLock::new(Arc::new(current)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload the certificate and key if either file has changed since they
+    /// were last loaded, returning whether they were.
+    pub fn reload_if_changed(&self) -> Result<bool, ChipmunkError> {
+        let modified = modified(&self.config);
+        let mut last = self.modified.lock();
+        if modified == *last {
+            return Ok(false);
+        }
+        let reloaded = load_certified_key(&self.config)?;
+        *self.current.write() = Arc::new(reloaded);
+        *last = modified;
+        Ok(true)
+    }
+
+    /// The certificate presented to new connections.
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        Arc::clone(&self.current.read())
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Latest modification time of the certificate and key files, if they can
+/// both be read.
+fn modified(config: &TlsConfig) -> Option<SystemTime> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some(modified(&config.cert_path)?.max(modified(&config.key_path)?))
+}
+
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey, ChipmunkError> {
+    let error = |path: &Path, reason: String| ChipmunkError::TlsLoad {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| error(path, e.to_string()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| error(&config.cert_path, e.to_string()))?;
+    if certs.is_empty() {
+        return Err(error(&config.cert_path, "no certificates found".into()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key_path)?)
+        .map_err(|e| error(&config.key_path, e.to_string()))?
+        .ok_or_else(|| error(&config.key_path, "no private key found".into()))?;
+    let key =
+        ring::sign::any_supported_type(&key).map_err(|e| error(&config.key_path, e.to_string()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Serve the app over TLS on the listener until `shutdown` resolves, after
+/// which in-flight requests are drained before returning.
+///
+/// HTTP/2 is negotiated through ALPN, falling back to HTTP/1.1. Failed
+/// handshakes only affect their own connection.
+pub async fn serve_tls<F>(
+    listener: TcpListener,
+    app: Router,
+    config: TlsConfig,
+    http: HttpConfig,
+    shutdown: F,
+) -> Result<(), ChipmunkError>
+where
+    F: Future<Output = ()> + Send,
+{
+    let certificate = Arc::new(ReloadingCertificate::load(config.clone())?);
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ChipmunkError::TlsLoad {
+            path: config.cert_path.clone(),
+            reason: e.to_string(),
+        })?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&certificate) as Arc<dyn ResolvesServerCert>);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let reloader = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.reload_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match certificate.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificate"),
+                Ok(false) => {}
+                Err(e) => warn!("Keeping the current TLS certificate: {e}"),
+            }
+        }
+    });
+
+    let handshake = |stream, peer| {
+        let accepting = acceptor.accept(stream);
+        async move {
+            accepting
+                .await
+                .inspect_err(|e| debug!(%peer, "TLS handshake failed: {e}"))
+                .ok()
+        }
+    };
+    serve_connections(listener, app, &http, handshake, shutdown).await;
+    reloader.abort();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn reload_certificate() {
+        let dir = TempDir::new("reload_certificate").unwrap();
+        let config = TlsConfig::new(dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let install = |n: u8| {
+            std::fs::copy(testdata(&format!("cert{n}.pem")), &config.cert_path).unwrap();
+            std::fs::copy(testdata(&format!("key{n}.pem")), &config.key_path).unwrap();
+        };
+
+        install(1);
+        let certificate = ReloadingCertificate::load(config.clone()).unwrap();
+        let first = certificate.current();
+        assert!(!certificate.reload_if_changed().unwrap());
+
+        std::fs::write(&config.key_path, "not a key").unwrap();
+        assert!(
+            certificate.reload_if_changed().is_err(),
+            "A partial replacement fails to load"
+        );
+        assert_eq!(certificate.current().cert, first.cert);
+
+        install(2);
+        // Modification times may be coarse, so make the change observable.
+        let later = SystemTime::now() + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&config.key_path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(certificate.reload_if_changed().unwrap());
+        assert_ne!(certificate.current().cert, first.cert);
+    }
+
+    #[tokio::test]
+    async fn serve_over_tls() {
+        let config = TlsConfig::new(testdata("cert1.pem"), testdata("key1.pem"));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/healthz", axum::routing::get(|| async { "OK" }));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_tls(
+            listener,
+            app,
+            config,
+            HttpConfig::default(),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let body = client
+            .get(format!("https://{addr}/healthz"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "OK");
+        assert!(
+            reqwest::get(format!("http://{addr}/healthz"))
+                .await
+                .is_err(),
+            "Plain HTTP is not served"
+        );
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+//! Hooks for transforming values as they enter and leave the store.
+//!
+//! A [`ValueTransform`] is applied to every value on write, before it reaches
+//! the WAL, and reversed on read. This allows integrations to layer envelope
+//! encryption or a domain-specific encoding over chipmunk without the engine
+//! needing to know about it.
+//!
+//! A store has a single transform, applied to the values of every key, which
+//! is set in code through
+//! [`Chipmunk::set_value_transform`](crate::server::Chipmunk::set_value_transform)
+//! rather than within the [`ChipmunkConfig`](crate::config::ChipmunkConfig).
+//! Transforms are not chosen per namespace of keys; one which needs to treat
+//! values differently records how within the values it encodes.
+
+use crate::ChipmunkError;
+
+/// A reversible transformation applied to values.
+///
+/// Implementations must guarantee that `decode(encode(v)) == Ok(v)` for every
+/// value, as values are persisted in their encoded form and restored from the
+/// WAL or SSTables without being re-encoded.
+pub trait ValueTransform: Send + Sync {
+    /// Transform a value prior to it being written.
+    fn encode(&self, value: Vec<u8>) -> Vec<u8>;
+
+    /// Reverse [`ValueTransform::encode`] for a value being read, failing,
+    /// typically with [`ChipmunkError::ValueDecode`], when it cannot be, such
+    /// as when a key it was encrypted with is unavailable. The read fails
+    /// with the error.
+    fn decode(&self, value: Vec<u8>) -> Result<Vec<u8>, ChipmunkError>;
+}
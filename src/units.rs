@@ -0,0 +1,302 @@
+//! Sizes and durations as they are written by people, such as `64MiB`, `8kb`
+//! or `500ms`, for the command line flags and the config file.
+//!
+//! Sizes take decimal units, where `1kb` is 1000 bytes, or binary units,
+//! where `1KiB` is 1024 bytes, in any case. Durations take `ns`, `us`, `ms`,
+//! `s`, `m`, `h` and `d`, and may combine them, as in `1m30s`. Either may be
+//! fractional, as in `1.5GiB` or `0.5s`, and a bare number is read in the
+//! unit the flag or setting is named after.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("b", 1),
+    ("kb", 1000),
+    ("mb", 1000 * 1000),
+    ("gb", 1000 * 1000 * 1000),
+    ("tb", 1000 * 1000 * 1000 * 1000),
+    ("kib", 1 << 10),
+    ("mib", 1 << 20),
+    ("gib", 1 << 30),
+    ("tib", 1 << 40),
+];
+
+const DURATION_UNITS: &[(&str, Duration)] = &[
+    ("ns", Duration::from_nanos(1)),
+    ("us", Duration::from_micros(1)),
+    ("ms", Duration::from_millis(1)),
+    ("s", Duration::from_secs(1)),
+    ("m", Duration::from_secs(60)),
+    ("h", Duration::from_secs(60 * 60)),
+    ("d", Duration::from_secs(24 * 60 * 60)),
+];
+
+/// Split off the number at the start of the string, returning it and the
+/// rest of the string.
+fn split_number(s: &str) -> Result<(f64, &str), String> {
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '_'))
+        .unwrap_or(s.len());
+    let (number, rest) = s.split_at(end);
+    let number = number.replace('_', "");
+    match number.parse::<f64>() {
+        Ok(number) if number.is_finite() => Ok((number, rest.trim_start())),
+        _ => Err(format!("'{s}' does not start with a number")),
+    }
+}
+
+/// The unit multiplied by the number, to the nearest nanosecond.
+fn scale(unit: Duration, number: f64, s: &str) -> Result<Duration, String> {
+    let nanos = (unit.as_nanos() as f64 * number).round();
+    if nanos > u64::MAX as f64 {
+        return Err(format!("'{s}' is too long"));
+    }
+    Ok(Duration::from_nanos(nanos as u64))
+}
+
+/// Parse a size, such as `64MiB` or `8kb`, into bytes. Bare numbers are
+/// bytes.
+pub fn parse_size<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let (number, unit) = split_number(s.trim())?;
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        SIZE_UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(|| {
+                format!("'{unit}' is not a unit of size, such as B, kB, MB, KiB or MiB")
+            })?
+    };
+    let bytes = (number * multiplier as f64).round();
+    if bytes > u64::MAX as f64 {
+        return Err(format!("'{s}' is too large"));
+    }
+    T::try_from(bytes as u64).map_err(|_| format!("'{s}' is too large"))
+}
+
+/// Parse a duration, such as `500ms` or `1m30s`. Bare numbers are counted
+/// in the given unit.
+pub fn parse_duration(s: &str, unit: Duration) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, rest) = split_number(s)?;
+    if rest.is_empty() {
+        return scale(unit, number, s);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let (number, after_number) = split_number(rest)?;
+        let unit_end = after_number
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after_number.len());
+        let (name, after_unit) = after_number.split_at(unit_end);
+        let unit = DURATION_UNITS
+            .iter()
+            .find(|(unit, _)| unit.eq_ignore_ascii_case(name))
+            .map(|(_, unit)| *unit)
+            .ok_or_else(|| format!("'{name}' is not a unit of time, such as ms, s, m or h"))?;
+        total = total
+            .checked_add(scale(unit, number, s)?)
+            .ok_or_else(|| format!("'{s}' is too long"))?;
+        rest = after_unit.trim_start();
+    }
+    Ok(total)
+}
+
+/// Parse a duration in which bare numbers are milliseconds, see
+/// [`parse_duration`].
+pub fn parse_millis(s: &str) -> Result<Duration, String> {
+    parse_duration(s, Duration::from_millis(1))
+}
+
+/// Parse a duration in which bare numbers are seconds, see
+/// [`parse_duration`].
+pub fn parse_secs(s: &str) -> Result<Duration, String> {
+    parse_duration(s, Duration::from_secs(1))
+}
+
+/// A size in bytes, given in the config file as a number of bytes or as a
+/// string such as `"64MiB"`. Written in the largest binary unit which holds
+/// it exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    /// The size as a `usize`, saturating on targets where it does not fit.
+    pub fn as_usize(self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_size(s).map(ByteSize)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = ["TiB", "GiB", "MiB", "KiB"]
+            .into_iter()
+            .zip([1 << 40, 1 << 30, 1 << 20, 1 << 10])
+            .find(|(_, multiplier)| self.0 != 0 && self.0.is_multiple_of(*multiplier));
+        match unit {
+            Some((unit, multiplier)) => write!(f, "{}{unit}", self.0 / multiplier),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SizeVisitor;
+
+        impl Visitor<'_> for SizeVisitor {
+            type Value = ByteSize;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of bytes or a size such as \"64MiB\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, bytes: u64) -> Result<ByteSize, E> {
+                Ok(ByteSize(bytes))
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<ByteSize, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+/// A duration, given in the config file as a number of milliseconds or as a
+/// string such as `"500ms"` or `"2s"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Millis(pub Duration);
+
+impl FromStr for Millis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_millis(s).map(Millis)
+    }
+}
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self.0.as_nanos();
+        if nanos.is_multiple_of(1_000_000) {
+            write!(f, "{}ms", nanos / 1_000_000)
+        } else if nanos.is_multiple_of(1_000) {
+            write!(f, "{}us", nanos / 1_000)
+        } else {
+            write!(f, "{nanos}ns")
+        }
+    }
+}
+
+impl Serialize for Millis {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Millis {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MillisVisitor;
+
+        impl Visitor<'_> for MillisVisitor {
+            type Value = Millis;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of milliseconds or a duration such as \"500ms\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, millis: u64) -> Result<Millis, E> {
+                Ok(Millis(Duration::from_millis(millis)))
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Millis, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(MillisVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        let size = |s| parse_size::<u64>(s);
+        assert_eq!(size("4096"), Ok(4096));
+        assert_eq!(size("64MiB"), Ok(64 * 1024 * 1024));
+        assert_eq!(size("8kb"), Ok(8000));
+        assert_eq!(size("8 KiB"), Ok(8192));
+        assert_eq!(size("1.5GiB"), Ok(3 * 512 * 1024 * 1024));
+        assert_eq!(size("1_000B"), Ok(1000));
+        assert!(size("64 MiBs").is_err());
+        assert!(size("MiB").is_err());
+        assert!(size("-1").is_err());
+        assert!(
+            parse_size::<u32>("8GiB").is_err(),
+            "Sizes must fit the type"
+        );
+
+        for bytes in [0, 1000, 8192, 64 << 20, 3 << 30] {
+            let size = ByteSize(bytes);
+            assert_eq!(size.to_string().parse(), Ok(size));
+        }
+        assert_eq!(ByteSize(64 << 20).to_string(), "64MiB");
+        let parsed: Vec<ByteSize> = serde_json::from_str(r#"[1024, "1KiB", "1kb"]"#).unwrap();
+        assert_eq!(parsed, vec![ByteSize(1024), ByteSize(1024), ByteSize(1000)]);
+        assert!(serde_json::from_str::<ByteSize>("-1").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_millis("500"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_secs("60"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_secs("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_millis("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_millis("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_millis("1h 5m"), Ok(Duration::from_secs(3900)));
+        assert_eq!(parse_millis("250us"), Ok(Duration::from_micros(250)));
+        assert!(parse_millis("5 fortnights").is_err());
+        assert!(parse_millis("s").is_err());
+
+        let parsed: Vec<Millis> = serde_json::from_str(r#"[20, "20ms", "0.02s"]"#).unwrap();
+        assert!(parsed
+            .iter()
+            .all(|m| *m == Millis(Duration::from_millis(20))));
+        for duration in [
+            Duration::ZERO,
+            Duration::from_secs(2),
+            Duration::from_micros(3),
+        ] {
+            let millis = Millis(duration);
+            assert_eq!(millis.to_string().parse(), Ok(millis));
+        }
+    }
+}
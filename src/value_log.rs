@@ -0,0 +1,376 @@
+//! Storage for large values outside of the LSM-tree, in the style of WiscKey.
+//!
+//! Values which are at least
+//! [`min_separated_value_size`](crate::config::SstableConfig::min_separated_value_size)
+//! are written to a value log file as their memtable is flushed, the SSTable
+//! holding a [`ValuePointer`] to them in their place. Compaction then only has
+//! to rewrite the small pointers, rather than the values themselves.
+//!
+//! Each flush writes its values to a file of its own, named after the
+//! memtable, which is immutable once written. Every record is laid out as:
+//!
+//! ```text
+//! | key len (u32) | key | value len (u32) | value | value crc (u32) |
+//! ```
+//!
+//! All integers are big endian. The key allows the live records of a file to
+//! be found by looking them up in the tree, so that a file which is mostly
+//! garbage can have them moved elsewhere before it is removed.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use parking_lot::RwLock;
+use tracing::debug;
+
+use crate::{fs, memtable::Entry, ChipmunkError};
+
+/// Prefix of the name of every value log file, followed by its ID.
+const VALUE_LOG_PREFIX: &str = "vlog-";
+
+/// File ID (u64), value offset (u64) and value length (u32).
+const POINTER_SIZE: usize = 8 + 8 + 4;
+
+/// Location of a value within the value log, which is stored in an SSTable
+/// in place of the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValuePointer {
+    /// ID of the value log file holding the value.
+    pub file: u64,
+    /// Offset of the value within the file.
+    pub offset: u64,
+    /// Length of the value, in bytes.
+    pub len: u32,
+}
+
+impl ValuePointer {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = Vec::with_capacity(POINTER_SIZE);
+        buf.write_u64::<BigEndian>(self.file).unwrap();
+        buf.write_u64::<BigEndian>(self.offset).unwrap();
+        buf.write_u32::<BigEndian>(self.len).unwrap();
+        buf.into()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() != POINTER_SIZE {
+            return Err("value pointer has the wrong length");
+        }
+        Ok(Self {
+            file: BigEndian::read_u64(&data[0..8]),
+            offset: BigEndian::read_u64(&data[8..16]),
+            len: BigEndian::read_u32(&data[16..20]),
+        })
+    }
+}
+
+/// Name of the value log file with the given ID.
+pub fn file_name(id: u64) -> String {
+    format!("{VALUE_LOG_PREFIX}{id}")
+}
+
+/// ID of a value log file, from its name.
+fn file_id(name: &str) -> Option<u64> {
+    name.strip_prefix(VALUE_LOG_PREFIX)?.parse().ok()
+}
+
+/// Open value log files, keyed by their ID.
+type Files = BTreeMap<u64, Arc<File>>;
+
+/// Open value log files, shared by every read of a separated value.
+pub struct ValueLog {
+    directory: PathBuf,
+    files: Arc<RwLock<Files>>,
+}
+
+impl ValueLog {
+    /// Open every value log file within the directory.
+    pub fn open(directory: &Path) -> Result<Self, ChipmunkError> {
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(directory).map_err(ChipmunkError::ValueLogRead)? {
+            let entry = entry.map_err(ChipmunkError::ValueLogRead)?;
+            if let Some(id) = file_id(&entry.file_name().to_string_lossy()) {
+                let file = File::open(entry.path()).map_err(ChipmunkError::ValueLogRead)?;
+                files.insert(id, Arc::new(file));
+            }
+        }
+        debug!(files = files.len(), "Opened value log");
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            files: Arc::new(RwLock::new(files)),
+        })
+    }
+
+    /// A value log without any files, for a tree which keeps everything in
+    /// memory and so never separates values.
+    pub fn in_memory() -> Self {
+        Self {
+            directory: PathBuf::new(),
+            files: Arc::default(),
+        }
+    }
+
+    /// Start writing a new value log file, which becomes readable once it has
+    /// been passed to [`ValueLog::seal`].
+    pub fn create(&self, id: u64) -> Result<ValueLogWriter, ChipmunkError> {
+        let path = self.directory.join(file_name(id));
+        let file = File::create(&path).map_err(ChipmunkError::ValueLogWrite)?;
+        Ok(ValueLogWriter {
+            id,
+            file: BufWriter::new(file),
+            offset: 0,
+        })
+    }
+
+    /// Sync a written file to disk and make its values readable.
+    pub fn seal(&self, writer: ValueLogWriter) -> Result<(), ChipmunkError> {
+        let file = writer
+            .file
+            .into_inner()
+            .map_err(|e| ChipmunkError::ValueLogWrite(e.into_error()))?;
+        file.sync_all().map_err(ChipmunkError::ValueLogWrite)?;
+        fs::sync_dir(&self.directory).map_err(ChipmunkError::ValueLogWrite)?;
+        let path = self.directory.join(file_name(writer.id));
+        let file = File::open(path).map_err(ChipmunkError::ValueLogRead)?;
+        self.files.write().insert(writer.id, Arc::new(file));
+        Ok(())
+    }
+
+    /// Read the value a pointer refers to, `None` when its file has since
+    /// been removed by garbage collection.
+    pub fn read(&self, pointer: &ValuePointer) -> Result<Option<Bytes>, ChipmunkError> {
+        let Some(file) = self.files.read().get(&pointer.file).cloned() else {
+            return Ok(None);
+        };
+        read_value(&self.directory, &file, pointer).map(Some)
+    }
+
+    /// A reader which keeps the files which exist now readable, even once
+    /// they have been removed, alongside any which are written later.
+    pub fn reader(&self) -> ValueLogReader {
+        ValueLogReader {
+            directory: self.directory.clone(),
+            pinned: self.files.read().clone(),
+            files: Arc::clone(&self.files),
+        }
+    }
+
+    /// IDs of every value log file, in ascending order.
+    pub fn file_ids(&self) -> Vec<u64> {
+        self.files.read().keys().copied().collect()
+    }
+
+    /// Total size, in bytes, of the value log files.
+    pub fn disk_size(&self) -> u64 {
+        self.files
+            .read()
+            .values()
+            .filter_map(|file| file.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Every record of a file, as its key and a pointer to its value, in the
+    /// order they were written.
+    pub fn records(&self, id: u64) -> Result<Vec<(Bytes, ValuePointer)>, ChipmunkError> {
+        let path = self.directory.join(file_name(id));
+        let file = File::open(&path).map_err(ChipmunkError::ValueLogRead)?;
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        let mut offset = 0;
+        loop {
+            let key_len = match reader.read_u32::<BigEndian>() {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(ChipmunkError::ValueLogRead(e)),
+            };
+            let mut key = vec![0; key_len];
+            reader
+                .read_exact(&mut key)
+                .map_err(ChipmunkError::ValueLogRead)?;
+            let len = reader
+                .read_u32::<BigEndian>()
+                .map_err(ChipmunkError::ValueLogRead)?;
+            let pointer = ValuePointer {
+                file: id,
+                offset: offset + 4 + key_len as u64 + 4,
+                len,
+            };
+            // Values are skipped over, they are only read when they are live.
+            std::io::copy(
+                &mut reader.by_ref().take(len as u64 + 4),
+                &mut std::io::sink(),
+            )
+            .map_err(ChipmunkError::ValueLogRead)?;
+            offset = pointer.offset + len as u64 + 4;
+            records.push((key.into(), pointer));
+        }
+        Ok(records)
+    }
+
+    /// Remove a value log file, which reads through an existing
+    /// [`ValueLogReader`] can still reach.
+    pub fn remove(&self, id: u64) -> Result<(), ChipmunkError> {
+        self.files.write().remove(&id);
+        let path = self.directory.join(file_name(id));
+        debug!(path = %path.display(), "Removing value log file");
+        std::fs::remove_file(path)
+            .and_then(|_| fs::sync_dir(&self.directory))
+            .map_err(ChipmunkError::ValueLogWrite)
+    }
+}
+
+/// Writes the separated values of a single flush, see [`ValueLog::create`].
+pub struct ValueLogWriter {
+    id: u64,
+    file: BufWriter<File>,
+    offset: u64,
+}
+
+impl ValueLogWriter {
+    /// Append a value, returning the pointer to store in its place.
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<ValuePointer, ChipmunkError> {
+        let mut record = Vec::with_capacity(4 + key.len() + 4 + value.len() + 4);
+        record.write_u32::<BigEndian>(key.len() as u32).unwrap();
+        record.write_all(key).unwrap();
+        record.write_u32::<BigEndian>(value.len() as u32).unwrap();
+        record.write_all(value).unwrap();
+        record
+            .write_u32::<BigEndian>(crc32c::crc32c(value))
+            .unwrap();
+        self.file
+            .write_all(&record)
+            .map_err(ChipmunkError::ValueLogWrite)?;
+
+        let pointer = ValuePointer {
+            file: self.id,
+            offset: self.offset + 4 + key.len() as u64 + 4,
+            len: value.len() as u32,
+        };
+        self.offset += record.len() as u64;
+        Ok(pointer)
+    }
+}
+
+/// Reads separated values for a scan or snapshot, see [`ValueLog::reader`].
+#[derive(Clone)]
+pub struct ValueLogReader {
+    directory: PathBuf,
+    /// Files which existed as the reader was created.
+    pinned: Files,
+    files: Arc<RwLock<Files>>,
+}
+
+impl ValueLogReader {
+    /// Read the value a pointer refers to.
+    pub fn read(&self, pointer: &ValuePointer) -> Result<Bytes, ChipmunkError> {
+        let file = match self.pinned.get(&pointer.file) {
+            Some(file) => Arc::clone(file),
+            None => self
+                .files
+                .read()
+                .get(&pointer.file)
+                .cloned()
+                .ok_or_else(|| ChipmunkError::ValueLogCorrupt {
+                    path: self.directory.join(file_name(pointer.file)),
+                    reason: "file does not exist".to_string(),
+                })?,
+        };
+        read_value(&self.directory, &file, pointer)
+    }
+
+    /// The live value of an entry, read from the value log if it has been
+    /// separated.
+    pub fn live_value(&self, entry: &Entry, now: u64) -> Result<Option<Bytes>, ChipmunkError> {
+        let Some(value) = entry.live_value(now) else {
+            return Ok(None);
+        };
+        if !entry.separated {
+            return Ok(Some(value.clone()));
+        }
+        let pointer =
+            ValuePointer::decode(value).map_err(|reason| ChipmunkError::ValueLogCorrupt {
+                path: self.directory.clone(),
+                reason: reason.to_string(),
+            })?;
+        self.read(&pointer).map(Some)
+    }
+}
+
+/// Read the value a pointer refers to from its file, verifying its checksum.
+fn read_value(
+    directory: &Path,
+    file: &File,
+    pointer: &ValuePointer,
+) -> Result<Bytes, ChipmunkError> {
+    let mut buf = vec![0; pointer.len as usize + 4];
+    file.read_exact_at(&mut buf, pointer.offset)
+        .map_err(ChipmunkError::ValueLogRead)?;
+    let crc = BigEndian::read_u32(&buf[pointer.len as usize..]);
+    buf.truncate(pointer.len as usize);
+    if crc != crc32c::crc32c(&buf) {
+        return Err(ChipmunkError::ValueLogCorrupt {
+            path: directory.join(file_name(pointer.file)),
+            reason: format!("value checksum mismatch at offset {}", pointer.offset),
+        });
+    }
+    Ok(buf.into())
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn write_and_read() {
+        let dir = TempDir::new("value_log").unwrap();
+        let log = ValueLog::open(dir.path()).unwrap();
+        let mut writer = log.create(3).unwrap();
+        let first = writer.append(b"key1", b"value1").unwrap();
+        let second = writer.append(b"key2", &[7; 1024]).unwrap();
+        log.seal(writer).unwrap();
+
+        assert_eq!(ValuePointer::decode(&first.encode()).unwrap(), first);
+        assert_eq!(
+            log.read(&first).unwrap(),
+            Some(Bytes::from_static(b"value1"))
+        );
+        assert_eq!(log.read(&second).unwrap(), Some(Bytes::from(vec![7; 1024])));
+        assert_eq!(
+            log.records(3).unwrap(),
+            vec![
+                (Bytes::from_static(b"key1"), first),
+                (Bytes::from_static(b"key2"), second)
+            ]
+        );
+        assert_eq!(log.file_ids(), vec![3]);
+        assert_eq!(
+            ValueLog::open(dir.path()).unwrap().file_ids(),
+            vec![3],
+            "Files are found when reopened"
+        );
+
+        let reader = log.reader();
+        log.remove(3).unwrap();
+        assert_eq!(log.read(&first).unwrap(), None);
+        assert_eq!(
+            reader.read(&first).unwrap(),
+            Bytes::from_static(b"value1"),
+            "Existing readers keep removed files readable"
+        );
+
+        let corrupt = ValuePointer { len: 5, ..first };
+        assert!(matches!(
+            reader.read(&corrupt),
+            Err(ChipmunkError::ValueLogCorrupt { .. })
+        ));
+    }
+}
@@ -2,16 +2,18 @@
 #![allow(dead_code)]
 
 use std::fmt::Display;
-use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fs::File, sync::atomic::AtomicU64};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::ChipmunkError;
+use crate::{fs, memtable::WriteStamp, metrics::Metrics, ChipmunkError};
 
 pub const WAL_MAX_SEGMENT_SIZE_BYTES: u64 = 64 * 1024 * 1024; // 64 MiB
 
@@ -21,8 +23,32 @@ const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
 
 const WAL_INSERT_MARKER: u8 = 0;
 const WAL_DELETE_MARKER: u8 = 1;
+const WAL_INSERT_EXPIRY_MARKER: u8 = 2;
+const WAL_DELETE_RANGE_MARKER: u8 = 3;
+const WAL_STAMP_MARKER: u8 = 4;
 
-const WAL_HEADER: &str = "ch1";
+/// Every segment starts with a header line holding this prefix followed by
+/// the version of the format the segment is written in, e.g. `ch2`.
+const WAL_HEADER_PREFIX: &str = "ch";
+
+/// Version of the WAL format which is written.
+///
+/// Entries are self-delimiting through their length prefixes, so version 2
+/// writes them back to back. Version 1 terminated each entry with a newline,
+/// which is skipped over when reading older segments. Version 3 prefixes
+/// entries with the [`WriteStamp`] of the write, see [`WalEntry::Stamped`].
+/// Version 4 follows each entry with a CRC32C checksum of its bytes.
+pub const WAL_FORMAT_VERSION: u32 = 4;
+
+/// Oldest version of the WAL format in which entries are checksummed.
+const WAL_CHECKSUM_VERSION: u32 = 4;
+
+/// Oldest version of the WAL format which can still be read.
+pub const WAL_MIN_FORMAT_VERSION: u32 = 1;
+
+/// Directory, within the log directory, which closed segments are moved to
+/// once flushed while their changes are retained, see [`Wal::with_retention`].
+pub const WAL_ARCHIVE_DIRECTORY: &str = "wal-archive";
 
 /// Wal maintains a write-ahead log (WAL) as an append-only file to provide persistence
 /// across crashes of the system.
@@ -42,28 +68,66 @@ pub struct Wal {
 
     /// Active segment file
     segment: Segment,
+
+    /// Appends, fsyncs and rotations are counted here.
+    metrics: Arc<Metrics>,
+
+    /// Time which flushed segments are archived for rather than removed.
+    retention: Option<Duration>,
 }
 
 impl Wal {
     pub fn new(id: u64, log_directory: &Path, max_size: u64, buffer_size: Option<usize>) -> Self {
+        Self::try_new(id, log_directory, max_size, buffer_size).unwrap()
+    }
+
+    /// Create a [`Wal`] whose active segment has the given ID, failing when
+    /// the segment file cannot be created.
+    pub fn try_new(
+        id: u64,
+        log_directory: &Path,
+        max_size: u64,
+        buffer_size: Option<usize>,
+    ) -> Result<Self, ChipmunkError> {
         let buffer_size = buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
         let buffer = Vec::with_capacity(buffer_size);
 
-        Self {
+        Ok(Self {
             log_directory: log_directory.to_path_buf(),
             current_size: 0,
             max_size,
             buffer,
             buffer_size,
-            segment: Segment::try_new(id, log_directory).unwrap(),
+            segment: Segment::try_new(id, log_directory)?,
             closed_segments: Vec::new(),
-        }
+            metrics: Arc::default(),
+            retention: None,
+        })
+    }
+
+    /// Count appends, fsyncs and rotations within the given [`Metrics`].
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Move closed segments to the [`WAL_ARCHIVE_DIRECTORY`] once flushed,
+    /// rather than removing them, until their last change is older than the
+    /// retention. Archived segments are removed as later segments are,
+    /// so they may outlive it until the next flush. Every archived segment
+    /// is removed then when unset.
+    pub fn with_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
     }
 
     /// Restore the [`Wal`] through reading the segment files which are in the
-    /// provided directory.
-    pub fn restore(&mut self) -> Result<(), ChipmunkError> {
-        info!("Restoring WAL");
+    /// provided directory, in ascending order of their IDs.
+    ///
+    /// Segments with an ID lower than `checkpoint` only hold data which has
+    /// already been flushed, so are removed rather than replayed.
+    pub fn restore(&mut self, checkpoint: u64) -> Result<(), ChipmunkError> {
+        info!(checkpoint, "Restoring WAL");
         let segment_files = std::fs::read_dir(&self.log_directory).map_err(|e| {
             ChipmunkError::WalDirectoryOpen {
                 source: e,
@@ -71,7 +135,7 @@ impl Wal {
             }
         })?;
 
-        let mut segment_count = 0;
+        let mut segments = Vec::new();
         for s in segment_files.into_iter() {
             let segment = s.expect("Valid file within log directory");
             if !segment.file_type().unwrap().is_file() {
@@ -79,34 +143,49 @@ impl Wal {
                 continue;
             }
 
-            if !segment.file_name().to_string_lossy().contains("wal") {
+            let Some(id) = segment_id(&segment.file_name().to_string_lossy()) else {
                 info!(name=?segment.file_name(), "Skipping non-WAL file during restore");
                 continue;
+            };
+
+            // The active segment is where restored entries are written to.
+            if id == self.id() {
+                continue;
+            }
+            segments.push((id, segment));
+        }
+        segments.sort_by_key(|(id, _)| *id);
+
+        let mut segment_count = 0;
+        let mut skipped = 0;
+        for (id, segment) in segments {
+            if id < checkpoint {
+                debug!(name=?segment.file_name(), "Removing checkpointed WAL segment");
+                self.discard(id)?;
+                skipped += 1;
+                continue;
             }
 
             if segment.metadata().unwrap().len() == 0 {
                 info!(name=?segment.file_name(), "Skipping empty WAL segment");
                 continue;
             }
-            let segment_file = File::open(segment.path()).map_err(ChipmunkError::SegmentOpen)?;
+            let reader = SegmentReader::open(&segment.path())?;
 
             // Only include segments which are valid
             segment_count += 1;
 
             let mut bytes_read = 0;
-            let max_bytes = segment_file.metadata().expect("Can read metadata").len();
             info!(
                 name = ?segment.file_name(),
-                segment_size = max_bytes,
+                segment_size = segment.metadata().unwrap().len(),
+                format_version = reader.version(),
                 current_segment_number = segment_count,
                 "Restoring segment"
             );
-            let reader = BufReader::new(segment_file);
 
-            for line in reader.lines().skip(1) {
-                let line = line.expect("WAL contains valid utf8");
-                bytes_read += line.as_bytes().len();
-                self.append(WalEntry::from_bytes(line.as_bytes())).unwrap();
+            for entry in reader {
+                bytes_read += self.append(entry)?;
             }
             info!(
                 bytes_read,
@@ -114,42 +193,68 @@ impl Wal {
                 "Completed segment"
             );
             self.maybe_flush_buffer(true).unwrap();
+            // The entries now live in the active segment too, so the segment
+            // is removed alongside those closed before it.
+            self.closed_segments.push(id);
+        }
+        if skipped > 0 {
+            self.discarded()?;
         }
-        info!(total_segments = segment_count, "Restored segments");
+        info!(
+            total_segments = segment_count,
+            skipped_segments = skipped,
+            "Restored segments"
+        );
 
         Ok(())
     }
 
-    /// Return a [`Lines`] iterator over the active segment file.
-    pub fn lines(&self) -> Result<Lines<BufReader<File>>, ChipmunkError> {
-        let segment_path = format!("{}/{}.wal", self.log_directory.display(), self.id());
-        let segment_file =
-            std::fs::File::open(&segment_path).map_err(ChipmunkError::SegmentOpen)?;
-
-        let mut reader = BufReader::new(segment_file);
-        let mut header = Vec::with_capacity(WAL_HEADER.len() + 1);
-        reader
-            .read_until(b'\n', &mut header)
-            .expect("WAL header exists within written segment file");
-
-        Ok(reader.lines())
+    /// Return an iterator over the entries of the active segment file.
+    pub fn entries(&self) -> Result<SegmentReader, ChipmunkError> {
+        SegmentReader::open(&self.path())
     }
 
     /// Append a [`WalEntry`] to the WAL file.
     pub fn append(&mut self, entry: WalEntry) -> Result<u64, ChipmunkError> {
-        let entry_bytes = entry.as_bytes();
+        let mut entry_bytes = entry.as_bytes();
+        let crc = crc32c::crc32c(&entry_bytes);
+        entry_bytes.write_u32::<BigEndian>(crc).unwrap();
         self.buffer
             .write_all(&entry_bytes)
             .expect("Can write known entry to buffer");
         self.current_size += entry_bytes.len() as u64;
+        self.metrics.wal_appends.add(1);
+        self.metrics.wal_bytes.add(entry_bytes.len() as u64);
         self.maybe_flush_buffer(false).unwrap();
         Ok(entry_bytes.len() as u64)
     }
 
+    /// Size, in bytes, of the buffer appends are held in before being written
+    /// out.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Change the size, in bytes, of the buffer appends are held in, the
+    /// default when `None`, writing out the buffer if it already holds more.
+    pub fn set_buffer_size(&mut self, buffer_size: Option<usize>) -> Result<(), ChipmunkError> {
+        self.buffer_size = buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        self.maybe_flush_buffer(false)
+    }
+
     pub fn flush_buffer(&mut self) -> Result<(), ChipmunkError> {
         self.maybe_flush_buffer(true)
     }
 
+    /// Write out any buffered appends and fsync the active segment, so that
+    /// every append so far is durable.
+    pub fn sync(&mut self) -> Result<(), ChipmunkError> {
+        self.flush_buffer()?;
+        self.segment.flush()?;
+        self.metrics.wal_syncs.add(1);
+        Ok(())
+    }
+
     fn maybe_flush_buffer(&mut self, force: bool) -> Result<(), ChipmunkError> {
         if self.buffer.len() >= self.buffer_size || force {
             self.segment
@@ -181,7 +286,10 @@ impl Wal {
     /// This flushes all operations to the current file before creating a new file.
     pub fn rotate(&mut self) -> Result<(), ChipmunkError> {
         info!("Rotating WAL");
+        self.flush_buffer()?;
         self.segment.flush()?;
+        self.metrics.wal_syncs.add(1);
+        self.metrics.wal_rotations.add(1);
 
         let current_id = self.segment.id();
         self.closed_segments.push(current_id);
@@ -195,11 +303,32 @@ impl Wal {
         self.current_size
     }
 
+    /// Size, in bytes, of the active and closed segment files, excluding
+    /// any appends which are still buffered.
+    pub fn disk_size(&self) -> u64 {
+        self.closed_segments
+            .iter()
+            .chain([&self.segment.id()])
+            .filter_map(|id| std::fs::metadata(self.log_directory.join(format!("{id}.wal"))).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
     /// ID of the active segment file.
     pub fn id(&self) -> u64 {
         self.segment.id()
     }
 
+    /// Paths of the closed segments followed by that of the active segment,
+    /// oldest first.
+    pub fn segment_paths(&self) -> Vec<PathBuf> {
+        self.closed_segments
+            .iter()
+            .chain([&self.segment.id()])
+            .map(|id| self.log_directory.join(format!("{id}.wal")))
+            .collect()
+    }
+
     /// Retrieve all closed segments.
     ///
     /// NOTE: These are safe to remove when the current [`Memtable`] has been
@@ -219,13 +348,109 @@ impl Wal {
         let mut cleared = 0;
 
         for s in segments {
-            let segment_path = format!("{}/{}.wal", self.log_directory.display(), s);
-            std::fs::remove_file(&segment_path).map_err(ChipmunkError::SegmentDelete)?;
+            self.discard(s)?;
             cleared += 1;
         }
+        if cleared > 0 {
+            self.discarded()?;
+        }
         self.clear_segments();
         Ok(cleared)
     }
+
+    /// Remove closed segments with an ID lower than the one given, returning
+    /// the number of segments that were removed.
+    pub fn remove_closed_segments_before(&mut self, id: u64) -> Result<u64, ChipmunkError> {
+        let mut cleared = 0;
+        for s in self.closed_segments.iter().filter(|s| **s < id) {
+            self.discard(*s)?;
+            cleared += 1;
+        }
+        if cleared > 0 {
+            self.discarded()?;
+        }
+        self.closed_segments.retain(|s| *s >= id);
+        Ok(cleared)
+    }
+
+    fn archive_directory(&self) -> PathBuf {
+        self.log_directory.join(WAL_ARCHIVE_DIRECTORY)
+    }
+
+    /// Remove the closed segment with the ID, or archive it while changes
+    /// are retained.
+    fn discard(&self, id: u64) -> Result<(), ChipmunkError> {
+        let path = self.log_directory.join(format!("{id}.wal"));
+        let discarded = match self.retention {
+            Some(_) => {
+                let archive = self.archive_directory();
+                std::fs::create_dir_all(&archive)
+                    .and_then(|_| std::fs::rename(&path, archive.join(format!("{id}.wal"))))
+            }
+            None => std::fs::remove_file(&path),
+        };
+        discarded.map_err(ChipmunkError::SegmentDelete)
+    }
+
+    /// Make the segments discarded so far durable, then remove the archived
+    /// segments which are older than the retention.
+    fn discarded(&self) -> Result<(), ChipmunkError> {
+        fs::sync_dir(&self.log_directory).map_err(ChipmunkError::SegmentDelete)?;
+        let archive = self.archive_directory();
+        let entries = match std::fs::read_dir(&archive) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            entries => entries.map_err(ChipmunkError::SegmentDelete)?,
+        };
+        let retention = self.retention.unwrap_or_default();
+        let mut pruned = 0;
+        for entry in entries {
+            let path = entry.map_err(ChipmunkError::SegmentDelete)?.path();
+            let modified = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .map_err(ChipmunkError::SegmentDelete)?;
+            if modified.elapsed().unwrap_or_default() >= retention {
+                std::fs::remove_file(&path).map_err(ChipmunkError::SegmentDelete)?;
+                pruned += 1;
+            }
+        }
+        debug!(pruned, "Pruned archived segments");
+        fs::sync_dir(&archive).map_err(ChipmunkError::SegmentDelete)
+    }
+
+    /// Paths of the archived segments, oldest first, which hold changes
+    /// before those of [`Wal::segment_paths`].
+    pub fn archived_paths(&self) -> Result<Vec<PathBuf>, ChipmunkError> {
+        let entries = match std::fs::read_dir(self.archive_directory()) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries.map_err(ChipmunkError::SegmentOpen)?,
+        };
+        let mut segments = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(ChipmunkError::SegmentOpen)?;
+            if let Some(id) = segment_id(&entry.file_name().to_string_lossy()) {
+                segments.push((id, entry.path()));
+            }
+        }
+        segments.sort_unstable_by_key(|(id, _)| *id);
+        Ok(segments.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+/// ID of a segment from its file name, `None` if it is not a segment.
+fn segment_id(file_name: &str) -> Option<u64> {
+    file_name.strip_suffix(".wal")?.parse().ok()
+}
+
+/// The lowest segment ID, no lower than `floor`, which follows every segment
+/// already within the directory.
+pub fn next_segment_id(log_directory: &Path, floor: u64) -> u64 {
+    std::fs::read_dir(log_directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| segment_id(&entry.file_name().to_string_lossy()))
+        .map(|id| id + 1)
+        .fold(floor, u64::max)
 }
 
 #[derive(Debug)]
@@ -255,11 +480,12 @@ impl Segment {
             .open(log_file_path)
             .map_err(ChipmunkError::SegmentOpen)?;
 
-        let header = format!("{WAL_HEADER}\n");
+        let header = format!("{WAL_HEADER_PREFIX}{WAL_FORMAT_VERSION}\n");
 
         new_segment
             .write_all(header.as_bytes())
             .expect("Can write header to new segment");
+        fs::sync_dir(path).map_err(ChipmunkError::SegmentOpen)?;
 
         Ok(Self {
             id,
@@ -279,10 +505,204 @@ impl Segment {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Reads the entries of a segment file, in any supported format version.
+///
+/// Iteration stops at the first entry which cannot be read in full, as this
+/// can only be left behind by a crash part way through an append, or which
+/// fails its checksum.
+#[derive(Debug)]
+pub struct SegmentReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+    version: u32,
+    /// Offset within the file of the next record.
+    offset: u64,
+    /// Whether a record could not be read, after which nothing more is.
+    failed: bool,
+}
+
+/// An entry of a segment alongside where it was read from, see
+/// [`SegmentReader::next_record`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct WalRecord {
+    /// Offset of the record within the segment file.
+    pub offset: u64,
+    pub entry: WalEntry,
+    pub checksum: Checksum,
+}
+
+/// Whether a record matched the checksum stored alongside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Valid,
+    Mismatch {
+        stored: u32,
+        computed: u32,
+    },
+    /// The record was written in a format version without checksums.
+    Absent,
+}
+
+/// Reads through to another reader, keeping every byte read.
+struct Recorded<'a, R> {
+    inner: &'a mut R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorded<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl SegmentReader {
+    /// Open a segment, validating its header.
+    pub fn open(path: &Path) -> Result<Self, ChipmunkError> {
+        let file = File::open(path).map_err(ChipmunkError::SegmentOpen)?;
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader
+            .read_line(&mut header)
+            .map_err(ChipmunkError::SegmentOpen)?;
+
+        let version = header
+            .trim_end()
+            .strip_prefix(WAL_HEADER_PREFIX)
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(0);
+        if !(WAL_MIN_FORMAT_VERSION..=WAL_FORMAT_VERSION).contains(&version) {
+            return Err(ChipmunkError::UnsupportedFormatVersion {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader,
+            version,
+            offset: header.len() as u64,
+            failed: false,
+        })
+    }
+
+    /// Version of the format which the segment was written in.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Read the next record, or `None` once the segment has been read. A
+    /// record which cannot be read in full is returned as an error, after
+    /// which nothing more is read. Unlike iterating over the entries, records
+    /// which fail their checksum are returned rather than ending the read.
+    pub fn next_record(&mut self) -> Option<std::io::Result<WalRecord>> {
+        if self.failed {
+            return None;
+        }
+        let record = self.read_record();
+        self.failed = matches!(record, Some(Err(_)));
+        record
+    }
+
+    fn read_record(&mut self) -> Option<std::io::Result<WalRecord>> {
+        match self.reader.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        let mut recorded = Recorded {
+            inner: &mut self.reader,
+            bytes: Vec::new(),
+        };
+        let entry = match WalEntry::from_reader(&mut recorded) {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        let bytes = recorded.bytes;
+        let mut len = bytes.len() as u64;
+
+        let checksum = if self.version >= WAL_CHECKSUM_VERSION {
+            let stored = match self.reader.read_u32::<BigEndian>() {
+                Ok(stored) => stored,
+                Err(e) => return Some(Err(e)),
+            };
+            len += 4;
+            let computed = crc32c::crc32c(&bytes);
+            match stored == computed {
+                true => Checksum::Valid,
+                false => Checksum::Mismatch { stored, computed },
+            }
+        } else {
+            Checksum::Absent
+        };
+        if self.version == 1 {
+            let mut newline = [0];
+            if let Err(e) = self.reader.read_exact(&mut newline) {
+                return Some(Err(e));
+            }
+            len += 1;
+        }
+
+        let offset = self.offset;
+        self.offset += len;
+        Some(Ok(WalRecord {
+            offset,
+            entry,
+            checksum,
+        }))
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = WalEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record()? {
+            Ok(WalRecord {
+                offset,
+                checksum: Checksum::Mismatch { .. },
+                ..
+            }) => {
+                warn!(path = %self.path.display(), offset, "Ignoring WAL entry which fails its checksum");
+                self.failed = true;
+                None
+            }
+            Ok(record) => Some(record.entry),
+            Err(e) => {
+                warn!(path = %self.path.display(), error = %e, "Ignoring incomplete WAL entry");
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum WalEntry {
-    Put { key: Vec<u8>, value: Vec<u8> },
-    Delete { key: Vec<u8> },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// A put which expires at the given unix timestamp, in milliseconds.
+    PutWithExpiry {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    /// A deletion of every key from `start`, inclusive, up to `end`, exclusive.
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    /// Another entry alongside the write which produced it.
+    Stamped {
+        stamp: WriteStamp,
+        entry: Box<WalEntry>,
+    },
 }
 
 impl WalEntry {
@@ -295,64 +715,110 @@ impl WalEntry {
                 buf.write_all(key).unwrap();
                 buf.write_u64::<BigEndian>(value.len() as u64).unwrap();
                 buf.write_all(value).unwrap();
-                buf.write_all(b"\n").unwrap();
             }
             Self::Delete { key } => {
                 buf.write_u8(WAL_DELETE_MARKER).unwrap();
                 buf.write_u64::<BigEndian>(key.len() as u64).unwrap();
                 buf.write_all(key).unwrap();
-                buf.write_all(b"\n").unwrap();
+            }
+            Self::PutWithExpiry {
+                key,
+                value,
+                expires_at,
+            } => {
+                buf.write_u8(WAL_INSERT_EXPIRY_MARKER).unwrap();
+                buf.write_u64::<BigEndian>(key.len() as u64).unwrap();
+                buf.write_all(key).unwrap();
+                buf.write_u64::<BigEndian>(value.len() as u64).unwrap();
+                buf.write_all(value).unwrap();
+                buf.write_u64::<BigEndian>(*expires_at).unwrap();
+            }
+            Self::DeleteRange { start, end } => {
+                buf.write_u8(WAL_DELETE_RANGE_MARKER).unwrap();
+                buf.write_u64::<BigEndian>(start.len() as u64).unwrap();
+                buf.write_all(start).unwrap();
+                buf.write_u64::<BigEndian>(end.len() as u64).unwrap();
+                buf.write_all(end).unwrap();
+            }
+            Self::Stamped { stamp, entry } => {
+                buf.write_u8(WAL_STAMP_MARKER).unwrap();
+                buf.write_u64::<BigEndian>(stamp.sequence).unwrap();
+                buf.write_u64::<BigEndian>(stamp.written_at).unwrap();
+                buf.extend_from_slice(&entry.as_bytes());
             }
         }
         buf.shrink_to_fit();
         buf
     }
 
-    pub fn from_bytes(mut reader: &[u8]) -> WalEntry {
-        let marker = reader.read_u8().unwrap();
+    /// Read a single entry, as written by [`WalEntry::as_bytes`].
+    pub fn from_reader<R: Read>(reader: &mut R) -> std::io::Result<WalEntry> {
+        let marker = reader.read_u8()?;
         match marker {
             WAL_INSERT_MARKER => {
-                let key_sz = reader.read_u64::<BigEndian>().unwrap();
+                let key_sz = reader.read_u64::<BigEndian>()?;
                 let mut key = vec![0; key_sz as usize];
-                reader.read_exact(&mut key).unwrap();
+                reader.read_exact(&mut key)?;
 
-                let value_sz = reader.read_u64::<BigEndian>().unwrap();
+                let value_sz = reader.read_u64::<BigEndian>()?;
                 let mut value = vec![0; value_sz as usize];
-                reader.read_exact(&mut value).unwrap();
+                reader.read_exact(&mut value)?;
 
-                WalEntry::Put { key, value }
+                Ok(WalEntry::Put { key, value })
             }
             WAL_DELETE_MARKER => {
-                let key_sz = reader.read_u64::<BigEndian>().unwrap();
+                let key_sz = reader.read_u64::<BigEndian>()?;
                 let mut key = vec![0; key_sz as usize];
-                reader.read_exact(&mut key).unwrap();
-                WalEntry::Delete { key }
+                reader.read_exact(&mut key)?;
+                Ok(WalEntry::Delete { key })
             }
-            _ => panic!("Unknown marker encountered"),
-        }
-    }
-
-    pub fn from_reader<R: Read>(reader: &mut R) -> WalEntry {
-        let marker = reader.read_u8().unwrap();
-        match marker {
-            WAL_INSERT_MARKER => {
-                let key_sz = reader.read_u64::<BigEndian>().unwrap();
+            WAL_INSERT_EXPIRY_MARKER => {
+                let key_sz = reader.read_u64::<BigEndian>()?;
                 let mut key = vec![0; key_sz as usize];
-                reader.read_exact(&mut key).unwrap();
+                reader.read_exact(&mut key)?;
 
-                let value_sz = reader.read_u64::<BigEndian>().unwrap();
+                let value_sz = reader.read_u64::<BigEndian>()?;
                 let mut value = vec![0; value_sz as usize];
-                reader.read_exact(&mut value).unwrap();
+                reader.read_exact(&mut value)?;
 
-                WalEntry::Put { key, value }
+                let expires_at = reader.read_u64::<BigEndian>()?;
+
+                Ok(WalEntry::PutWithExpiry {
+                    key,
+                    value,
+                    expires_at,
+                })
             }
-            WAL_DELETE_MARKER => {
-                let key_sz = reader.read_u64::<BigEndian>().unwrap();
-                let mut key = vec![0; key_sz as usize];
-                reader.read_exact(&mut key).unwrap();
-                WalEntry::Delete { key }
+            WAL_DELETE_RANGE_MARKER => {
+                let start_sz = reader.read_u64::<BigEndian>()?;
+                let mut start = vec![0; start_sz as usize];
+                reader.read_exact(&mut start)?;
+
+                let end_sz = reader.read_u64::<BigEndian>()?;
+                let mut end = vec![0; end_sz as usize];
+                reader.read_exact(&mut end)?;
+
+                Ok(WalEntry::DeleteRange { start, end })
+            }
+            WAL_STAMP_MARKER => {
+                let stamp = WriteStamp {
+                    sequence: reader.read_u64::<BigEndian>()?,
+                    written_at: reader.read_u64::<BigEndian>()?,
+                };
+                match WalEntry::from_reader(reader)? {
+                    WalEntry::Stamped { .. } => {
+                        Err(std::io::Error::new(ErrorKind::InvalidData, "nested stamp"))
+                    }
+                    entry => Ok(WalEntry::Stamped {
+                        stamp,
+                        entry: Box::new(entry),
+                    }),
+                }
             }
-            _ => panic!("Unknown marker encountered"),
+            marker => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown marker {marker}"),
+            )),
         }
     }
 }
@@ -369,6 +835,25 @@ impl Display for WalEntry {
                 )
             }
             Self::Delete { key } => write!(f, "DELETE {}", String::from_utf8_lossy(key)),
+            Self::PutWithExpiry {
+                key,
+                value,
+                expires_at,
+            } => {
+                write!(
+                    f,
+                    "PUT {}={} EXPIRES {expires_at}",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(value)
+                )
+            }
+            Self::DeleteRange { start, end } => write!(
+                f,
+                "DELETE RANGE {}..{}",
+                String::from_utf8_lossy(start),
+                String::from_utf8_lossy(end)
+            ),
+            Self::Stamped { stamp, entry } => write!(f, "{entry} AT {}", stamp.sequence),
         }
     }
 }
@@ -411,10 +896,55 @@ mod test {
 
         // Reset the cursor after writing
         buf.seek(std::io::SeekFrom::Start(0)).unwrap();
-        let read_entry = WalEntry::from_reader(&mut buf);
+        let read_entry = WalEntry::from_reader(&mut buf).unwrap();
         assert_eq!(entry, read_entry);
     }
 
+    #[test]
+    fn wal_entry_expiry_bytes() {
+        let mut buf = Cursor::new(Vec::new());
+        let entry = WalEntry::PutWithExpiry {
+            key: b"hello".to_vec(),
+            value: b"world".to_vec(),
+            expires_at: 1_700_000_000_000,
+        };
+        buf.write_all(&entry.as_bytes()).unwrap();
+
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        assert_eq!(entry, WalEntry::from_reader(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn wal_entry_stamped_bytes() {
+        let mut buf = Cursor::new(Vec::new());
+        let entry = WalEntry::Stamped {
+            stamp: WriteStamp {
+                sequence: 7,
+                written_at: 1_700_000_000_000,
+            },
+            entry: Box::new(WalEntry::Delete {
+                key: b"hello".to_vec(),
+            }),
+        };
+        buf.write_all(&entry.as_bytes()).unwrap();
+
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        assert_eq!(entry, WalEntry::from_reader(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn wal_entry_delete_range_bytes() {
+        let mut buf = Cursor::new(Vec::new());
+        let entry = WalEntry::DeleteRange {
+            start: b"tenant:1:".to_vec(),
+            end: b"tenant:1;".to_vec(),
+        };
+        buf.write_all(&entry.as_bytes()).unwrap();
+
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        assert_eq!(entry, WalEntry::from_reader(&mut buf).unwrap());
+    }
+
     #[test]
     fn write_to_wal() {
         let temp_dir = TempDir::new("write_wal").unwrap();
@@ -429,15 +959,16 @@ mod test {
 
         let mut file = std::fs::File::open(wal.path()).unwrap();
         // Skip over the WAL header for the entry read
-        file.seek(std::io::SeekFrom::Start((WAL_HEADER.len() + 1) as u64))
+        let header = format!("{WAL_HEADER_PREFIX}{WAL_FORMAT_VERSION}\n");
+        file.seek(std::io::SeekFrom::Start(header.len() as u64))
             .unwrap();
-        let entry: WalEntry = WalEntry::from_reader(&mut file);
+        let entry: WalEntry = WalEntry::from_reader(&mut file).unwrap();
         match entry {
             WalEntry::Put { key, value } => {
                 assert_eq!(&String::from_utf8_lossy(&key), "foo");
                 assert_eq!(&String::from_utf8_lossy(&value), "bar");
             }
-            WalEntry::Delete { key: _ } => panic!("Expected put operation, got delete!"),
+            other => panic!("Expected put operation, got {other}!"),
         }
 
         let mut wal = Wal::new(1, temp_dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, None);
@@ -449,6 +980,22 @@ mod test {
         assert_eq!(wal.current_size, wrote);
     }
 
+    #[test]
+    fn sync() {
+        let temp_dir = TempDir::new("sync_wal").unwrap();
+        let mut wal = Wal::new(0, temp_dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, Some(4096));
+        let header = format!("{WAL_HEADER_PREFIX}{WAL_FORMAT_VERSION}\n");
+        let on_disk = |wal: &Wal| std::fs::metadata(wal.path()).unwrap().len();
+
+        let mut wrote = 0;
+        for entry in put_entries() {
+            wrote += wal.append(entry).unwrap();
+        }
+        assert_eq!(on_disk(&wal), header.len() as u64, "Appends are buffered");
+        wal.sync().unwrap();
+        assert_eq!(on_disk(&wal), header.len() as u64 + wrote);
+    }
+
     #[test]
     fn wal_replay() {
         let temp_dir = TempDir::new("write_wal").unwrap();
@@ -483,13 +1030,110 @@ mod test {
         drop(wal);
 
         let mut wal = Wal::new(1, temp_dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, None);
-        wal.restore().unwrap();
+        wal.restore(0).unwrap();
         assert_eq!(
             wal.current_size, wrote,
             "WAL size should be the same prior to dropping"
         );
     }
 
+    #[test]
+    fn legacy_segments() {
+        let temp_dir = TempDir::new("write_wal").unwrap();
+
+        // Version 1 segments terminated every entry with a newline
+        let mut legacy = b"ch1\n".to_vec();
+        let entries = vec![
+            WalEntry::Put {
+                // A key whose length contains a newline byte
+                key: vec![b'k'; b'\n' as usize],
+                value: b"bar".to_vec(),
+            },
+            WalEntry::Delete {
+                key: b"baz".to_vec(),
+            },
+        ];
+        for entry in &entries {
+            legacy.extend(entry.as_bytes());
+            legacy.push(b'\n');
+        }
+        std::fs::write(temp_dir.path().join("0.wal"), legacy).unwrap();
+
+        let reader = SegmentReader::open(&temp_dir.path().join("0.wal")).unwrap();
+        assert_eq!(reader.version(), 1);
+        assert_eq!(reader.collect::<Vec<_>>(), entries);
+
+        // Restored entries are rewritten in the current format
+        let mut wal = Wal::new(1, temp_dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, None);
+        wal.restore(0).unwrap();
+        let reader = wal.entries().unwrap();
+        assert_eq!(reader.version(), WAL_FORMAT_VERSION);
+        assert_eq!(reader.collect::<Vec<_>>(), entries);
+
+        // A crash part way through an append leaves an incomplete entry
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(wal.path())
+            .unwrap();
+        file.write_all(&entries[0].as_bytes()[..10]).unwrap();
+        assert_eq!(wal.entries().unwrap().count(), entries.len());
+
+        std::fs::write(temp_dir.path().join("2.wal"), b"ch9\n").unwrap();
+        assert!(matches!(
+            SegmentReader::open(&temp_dir.path().join("2.wal")),
+            Err(ChipmunkError::UnsupportedFormatVersion { version: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn checksums() {
+        let temp_dir = TempDir::new("wal_checksums").unwrap();
+        let mut wal = Wal::new(0, temp_dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, None);
+        for entry in put_entries() {
+            wal.append(entry).unwrap();
+        }
+        wal.sync().unwrap();
+
+        let mut reader = wal.entries().unwrap();
+        let header = format!("{WAL_HEADER_PREFIX}{WAL_FORMAT_VERSION}\n").len() as u64;
+        let first = reader.next_record().unwrap().unwrap();
+        assert_eq!(first.offset, header);
+        assert_eq!(first.checksum, Checksum::Valid);
+        let second = reader.next_record().unwrap().unwrap();
+        assert_eq!(
+            second.offset,
+            header + put_entries()[0].as_bytes().len() as u64 + 4
+        );
+        assert!(reader.next_record().is_none());
+
+        // Flip the last byte of the first value
+        let mut bytes = std::fs::read(wal.path()).unwrap();
+        let value_end = second.offset as usize - 5;
+        bytes[value_end] ^= 0xff;
+        std::fs::write(wal.path(), &bytes).unwrap();
+
+        let mut reader = wal.entries().unwrap();
+        let corrupt = reader.next_record().unwrap().unwrap();
+        assert!(matches!(corrupt.checksum, Checksum::Mismatch { .. }));
+        assert_eq!(
+            reader.next_record().unwrap().unwrap().checksum,
+            Checksum::Valid,
+            "Records after a mismatch can still be read"
+        );
+        assert_eq!(
+            wal.entries().unwrap().count(),
+            0,
+            "Replay stops at the first mismatch"
+        );
+
+        // A truncated record is an error, after which nothing more is read
+        std::fs::write(wal.path(), &bytes[..bytes.len() - 2]).unwrap();
+        let mut reader = wal.entries().unwrap();
+        assert!(reader.next_record().unwrap().is_ok());
+        assert!(reader.next_record().unwrap().is_err());
+        assert!(reader.next_record().is_none());
+    }
+
     #[test]
     fn id() {
         let temp_dir = TempDir::new("write_wal").unwrap();
@@ -554,14 +1198,54 @@ mod test {
         assert_eq!(wal.closed_segments.len(), 5);
         assert_eq!(wal.segment.id(), 5, "Current active segment ID should be 5");
 
+        let removed = wal
+            .remove_closed_segments_before(2)
+            .expect("Can remove segments in test");
+        assert_eq!(removed, 2, "Only segments before the given ID are removed");
+        assert_eq!(wal.closed_segments(), vec![2, 3, 4]);
+
         let removed = wal
             .remove_closed_segments()
             .expect("Can remove segments in test");
-        assert_eq!(removed, segment_count);
+        assert_eq!(removed, segment_count - 2);
         assert_eq!(
             wal.closed_segments().len(),
             0,
             "There should be no closed segments remaining after removal"
         );
     }
+
+    #[test]
+    fn segment_archival() {
+        let temp_dir = TempDir::new("archive_segments").unwrap();
+        let mut wal = Wal::new(0, temp_dir.path(), WAL_MAX_SEGMENT_SIZE_BYTES, None)
+            .with_retention(Some(Duration::from_secs(3600)));
+        for _ in 0..3 {
+            wal.append(WalEntry::Put {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            })
+            .unwrap();
+            wal.rotate().unwrap();
+        }
+
+        assert_eq!(wal.remove_closed_segments_before(2).unwrap(), 2);
+        let archive = temp_dir.path().join(WAL_ARCHIVE_DIRECTORY);
+        assert_eq!(
+            wal.archived_paths().unwrap(),
+            vec![archive.join("0.wal"), archive.join("1.wal")]
+        );
+        assert!(!temp_dir.path().join("0.wal").exists());
+        assert_eq!(
+            SegmentReader::open(&archive.join("1.wal")).unwrap().count(),
+            1,
+            "Archived segments can still be read"
+        );
+
+        // Segments are pruned once the retention passes, or is unset.
+        let mut wal = wal.with_retention(None);
+        wal.remove_closed_segments().unwrap();
+        assert!(wal.archived_paths().unwrap().is_empty());
+        assert!(!temp_dir.path().join("2.wal").exists());
+    }
 }